@@ -0,0 +1,429 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use chrono::{NaiveDate, Utc};
+use log::warn;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::auth::scopes::authenticate;
+use crate::context::RequestContext;
+use crate::error::{AppError, AppResult};
+use crate::histogram::{HistogramSnapshot, LatencyHistogram};
+use crate::state::KeyValueStore;
+use crate::users::{User, UserRepository};
+
+/// How long daily usage aggregates are kept
+const USAGE_RETENTION: Duration = Duration::from_secs(90 * 24 * 3600);
+
+/// One request as seen by the analytics pipeline
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UsageEvent {
+    pub day: NaiveDate,
+    /// `METHOD /route/{pattern}`, or `METHOD <unmatched>` for unknown paths
+    pub route: String,
+    pub status: u16,
+    /// Authenticated user, if any
+    pub user_id: Option<String>,
+}
+
+/// Destination of usage events that passed the opt-out checks
+pub trait AnalyticsSink: Send + Sync {
+    /// Records one request
+    fn record(&self, event: &UsageEvent) -> AppResult<()>;
+}
+
+/// Requests per route for one day
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteUsage {
+    pub route: String,
+    pub requests: u64,
+}
+
+/// Aggregated usage of one day, as exported to product analytics
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UsageExport {
+    pub day: NaiveDate,
+    pub routes: Vec<RouteUsage>,
+    /// Distinct authenticated users
+    pub active_users: u64,
+}
+
+/// [`AnalyticsSink`] aggregating daily counts in a [`KeyValueStore`]
+///
+/// Only counters are kept, never individual requests. Keys (expiring after
+/// 90 days): `count:{day}:{route}`, `routes:{day}` (JSON array of routes
+/// seen), `seen:{day}:{user_id}` and `users:{day}`.
+pub struct UsageAggregator {
+    store: Arc<dyn KeyValueStore>,
+}
+
+impl UsageAggregator {
+    /// Creates an aggregator persisting to `store`
+    pub fn new(store: Arc<dyn KeyValueStore>) -> Self {
+        Self { store }
+    }
+
+    /// Returns the aggregates of `day`
+    pub fn export(&self, day: NaiveDate) -> AppResult<UsageExport> {
+        let mut routes = Vec::new();
+        for route in self.routes(day)? {
+            let requests = self.counter(&format!("count:{}:{}", day, route))?;
+            routes.push(RouteUsage { route, requests });
+        }
+        routes.sort_by_key(|usage| std::cmp::Reverse(usage.requests));
+        Ok(UsageExport {
+            day,
+            routes,
+            active_users: self.counter(&format!("users:{}", day))?,
+        })
+    }
+
+    fn routes(&self, day: NaiveDate) -> AppResult<Vec<String>> {
+        self.store
+            .get(&format!("routes:{}", day))?
+            .map(|raw| {
+                serde_json::from_str(&raw).map_err(|e| AppError::internal(format!("Corrupt usage index: {}", e)))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn counter(&self, key: &str) -> AppResult<u64> {
+        match self.store.get(key)? {
+            Some(raw) => raw
+                .parse()
+                .map_err(|e| AppError::internal(format!("Corrupt usage counter {}: {}", key, e))),
+            None => Ok(0),
+        }
+    }
+}
+
+impl AnalyticsSink for UsageAggregator {
+    fn record(&self, event: &UsageEvent) -> AppResult<()> {
+        let ttl = Some(USAGE_RETENTION);
+        if self.store.increment(&format!("count:{}:{}", event.day, event.route), ttl)? == 1 {
+            let mut routes = self.routes(event.day)?;
+            if !routes.contains(&event.route) {
+                routes.push(event.route.clone());
+                let encoded = serde_json::to_string(&routes)
+                    .map_err(|e| AppError::internal(format!("Failed to encode usage index: {}", e)))?;
+                self.store.set(&format!("routes:{}", event.day), &encoded, ttl)?;
+            }
+        }
+        if let Some(user_id) = &event.user_id {
+            if self.store.set_if_absent(&format!("seen:{}:{}", event.day, user_id), "1", ttl)? {
+                self.store.increment(&format!("users:{}", event.day), ttl)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Operational request counters, unaffected by analytics opt-outs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct OperationalMetrics {
+    pub requests_total: u64,
+    pub server_errors: u64,
+    /// Requests kept out of analytics because of an opt-out
+    pub analytics_excluded: u64,
+}
+
+/// Feeds requests to analytics sinks, honouring tracking opt-outs
+///
+/// A request is excluded from analytics when it carries `DNT: 1` or
+/// `Sec-GPC: 1`, or its bearer credential belongs to a user who opted out
+/// (see [`set_opt_out`](Self::set_opt_out)). Excluded requests never reach
+/// the sinks; every request still counts in the [`OperationalMetrics`].
+pub struct AnalyticsPipeline {
+    sinks: Vec<Arc<dyn AnalyticsSink>>,
+    repository: Option<UserRepository>,
+    requests_total: AtomicU64,
+    server_errors: AtomicU64,
+    analytics_excluded: AtomicU64,
+    latency: LatencyHistogram,
+}
+
+impl AnalyticsPipeline {
+    /// Creates a pipeline without sinks
+    ///
+    /// # Arguments
+    /// * `repository` - User storage holding per-user opt-outs; without it
+    ///   only the request headers are honoured
+    pub fn new(repository: Option<UserRepository>) -> Self {
+        Self {
+            sinks: Vec::new(),
+            repository,
+            requests_total: AtomicU64::new(0),
+            server_errors: AtomicU64::new(0),
+            analytics_excluded: AtomicU64::new(0),
+            latency: LatencyHistogram::new(),
+        }
+    }
+
+    /// Adds a sink receiving usage events
+    pub fn with_sink(mut self, sink: Arc<dyn AnalyticsSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Returns the operational counters
+    pub fn operational(&self) -> OperationalMetrics {
+        OperationalMetrics {
+            requests_total: self.requests_total.load(Ordering::Relaxed),
+            server_errors: self.server_errors.load(Ordering::Relaxed),
+            analytics_excluded: self.analytics_excluded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the request latency histogram, opted-out requests included
+    pub fn latency(&self) -> HistogramSnapshot {
+        self.latency.snapshot()
+    }
+
+    /// Records how long a request took; `trace_id` becomes its bucket's exemplar
+    pub fn observe_latency(&self, latency: Duration, trace_id: Option<&str>) {
+        self.latency.observe(latency, trace_id);
+    }
+
+    /// Records `user_id`'s analytics preference
+    ///
+    /// # Errors
+    /// Not found for unknown users, internal error without a user repository
+    pub fn set_opt_out(&self, user_id: &str, opt_out: bool) -> AppResult<User> {
+        let repository = self
+            .repository
+            .as_ref()
+            .ok_or_else(|| AppError::internal("analytics pipeline has no user repository"))?;
+        let mut user = repository
+            .get(user_id)?
+            .ok_or_else(|| AppError::not_found("user not found"))?;
+        if user.analytics_opt_out != opt_out {
+            user.analytics_opt_out = opt_out;
+            user.updated_at = Utc::now();
+            repository.save(&user)?;
+            repository.record_event(&user.id, "user.analytics_preference_changed", json!({ "opt_out": opt_out }))?;
+        }
+        Ok(user)
+    }
+
+    /// Returns whether the user opted out of analytics
+    ///
+    /// Lookup failures count as an opt-out, erring on the side of privacy.
+    fn user_opted_out(&self, user_id: &str) -> bool {
+        let Some(repository) = &self.repository else {
+            return false;
+        };
+        repository
+            .get(user_id)
+            .map(|user| user.is_some_and(|user| user.analytics_opt_out))
+            .unwrap_or(true)
+    }
+
+    /// Counts a finished request and forwards it to the sinks unless excluded
+    ///
+    /// Sink failures are logged only, so analytics never fail a request.
+    pub fn observe(&self, event: UsageEvent, opted_out: bool) {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+        if event.status >= 500 {
+            self.server_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if opted_out {
+            self.analytics_excluded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        for sink in &self.sinks {
+            if let Err(e) = sink.record(&event) {
+                warn!("Failed to record usage of {}: {}", event.route, e);
+            }
+        }
+    }
+}
+
+/// Returns whether the request asks not to be tracked (`DNT: 1` or `Sec-GPC: 1`)
+pub fn tracking_declined(headers: &HeaderMap) -> bool {
+    ["DNT", "Sec-GPC"].iter().any(|name| {
+        headers
+            .get(*name)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim() == "1")
+    })
+}
+
+/// Reports every request to the registered [`AnalyticsPipeline`]
+///
+/// The route is the matched pattern rather than the raw path, so ids in
+/// paths never reach analytics. Requests failing in inner middleware are
+/// reported with their error status. The latency of every request feeds the
+/// histogram of `/metrics`, with the trace id as exemplar when the client
+/// propagated a `traceparent`.
+pub async fn track_usage(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(pipeline) = req.app_data::<web::Data<AnalyticsPipeline>>().cloned() else {
+        return next.call(req).await;
+    };
+    let user_id = authenticate(&req).ok().map(|claims| claims.sub);
+    let opted_out =
+        tracking_declined(req.headers()) || user_id.as_deref().is_some_and(|id| pipeline.user_opted_out(id));
+    let method = req.method().to_string();
+    let pattern = req.match_pattern();
+    let trace_id = req
+        .extensions()
+        .get::<RequestContext>()
+        .filter(|context| context.traced)
+        .map(|context| context.trace_id.clone());
+    let started = Instant::now();
+
+    let result = next.call(req).await;
+    pipeline.observe_latency(started.elapsed(), trace_id.as_deref());
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    let event = UsageEvent {
+        day: Utc::now().date_naive(),
+        route: format!("{} {}", method, pattern.as_deref().unwrap_or("<unmatched>")),
+        status: status.as_u16(),
+        user_id,
+    };
+    pipeline.observe(event, opted_out);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenService;
+    use crate::state::InMemoryStore;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{middleware::from_fn, App, HttpResponse};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<UsageEvent>>);
+
+    impl AnalyticsSink for RecordingSink {
+        fn record(&self, event: &UsageEvent) -> AppResult<()> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tracking_declined() {
+        let declined = |name: &str, value: &str| {
+            tracking_declined(TestRequest::default().insert_header((name, value)).to_http_request().headers())
+        };
+        assert!(declined("DNT", "1"));
+        assert!(declined("Sec-GPC", "1"));
+        assert!(!declined("DNT", "0"));
+        assert!(!tracking_declined(TestRequest::default().to_http_request().headers()));
+    }
+
+    #[test]
+    fn test_usage_aggregation() {
+        let aggregator = UsageAggregator::new(Arc::new(InMemoryStore::new()));
+        let day = Utc::now().date_naive();
+        let event = |route: &str, user: Option<&str>| UsageEvent {
+            day,
+            route: route.to_string(),
+            status: 200,
+            user_id: user.map(str::to_string),
+        };
+        aggregator.record(&event("GET /public", None)).unwrap();
+        aggregator.record(&event("GET /me/sessions", Some("ann"))).unwrap();
+        aggregator.record(&event("GET /me/sessions", Some("ann"))).unwrap();
+        aggregator.record(&event("GET /me/sessions", Some("bob"))).unwrap();
+
+        let export = aggregator.export(day).unwrap();
+        assert_eq!(export.active_users, 2);
+        assert_eq!(
+            export.routes,
+            vec![
+                RouteUsage { route: "GET /me/sessions".to_string(), requests: 3 },
+                RouteUsage { route: "GET /public".to_string(), requests: 1 },
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_opted_out_requests_never_reach_sinks() {
+        let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+        let ann = repository.create("ann@example.com", String::new()).unwrap();
+        let bob = repository.create("bob@example.com", String::new()).unwrap();
+        let sink = Arc::new(RecordingSink::default());
+        let pipeline = web::Data::new(AnalyticsPipeline::new(Some(repository)).with_sink(sink.clone()));
+        pipeline.set_opt_out(&bob.id, true).unwrap();
+
+        let tokens = TokenService::new(b"analytics-test-key-analytics-000", "test");
+        let bearer = |user: &str| format!("Bearer {}", tokens.issue(user, &[], Duration::from_secs(60)).unwrap());
+        let (ann_bearer, bob_bearer) = (bearer(&ann.id), bearer(&bob.id));
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(tokens))
+                .app_data(pipeline.clone())
+                .wrap(from_fn(track_usage))
+                .route("/items/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let requests = [
+            TestRequest::get().uri("/items/1").insert_header(("Authorization", ann_bearer.clone())),
+            TestRequest::get().uri("/items/2").insert_header(("DNT", "1")),
+            TestRequest::get().uri("/items/3").insert_header(("Sec-GPC", "1")),
+            TestRequest::get().uri("/items/4").insert_header(("Authorization", bob_bearer)),
+            TestRequest::get().uri("/missing"),
+        ];
+        for request in requests {
+            call_service(&app, request.to_request()).await;
+        }
+
+        let recorded = sink.0.lock().unwrap().clone();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].route, "GET /items/{id}");
+        assert_eq!(recorded[0].user_id.as_deref(), Some(ann.id.as_str()));
+        assert_eq!(recorded[1].route, "GET <unmatched>");
+        assert_eq!(recorded[1].status, 404);
+        assert!(recorded.iter().all(|event| event.user_id.as_deref() != Some(bob.id.as_str())));
+
+        let metrics = pipeline.operational();
+        assert_eq!(metrics.requests_total, 5);
+        assert_eq!(metrics.analytics_excluded, 3);
+    }
+
+    #[actix_web::test]
+    async fn test_latency_exemplars_come_from_propagated_traces() {
+        let pipeline = web::Data::new(AnalyticsPipeline::new(None));
+        let app = init_service(
+            App::new()
+                .app_data(pipeline.clone())
+                .wrap(from_fn(track_usage))
+                .wrap(from_fn(crate::context::attach_context))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        call_service(&app, TestRequest::get().uri("/").insert_header(("traceparent", traceparent)).to_request()).await;
+        call_service(&app, TestRequest::get().uri("/").to_request()).await;
+
+        let latency = pipeline.latency();
+        assert_eq!(latency.count, 2);
+        // Only the request with a trace a tracer knows about is an exemplar
+        let exemplars: Vec<&str> = latency
+            .buckets
+            .iter()
+            .filter_map(|bucket| bucket.exemplar.as_ref())
+            .map(|exemplar| exemplar.trace_id.as_str())
+            .collect();
+        assert_eq!(exemplars, ["4bf92f3577b34da6a3ce929d0e0e4736"]);
+    }
+}
@@ -0,0 +1,201 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::auth::SessionRegistry;
+use crate::config::Config;
+use crate::error::AppResult;
+use crate::events::CloudEvent;
+use crate::pii::{scrub_json, tagged_fields};
+use crate::supervisor::Supervisor;
+use crate::users::UserRepository;
+
+/// Scope allowing a caller to run the anonymization job on demand
+pub const DATA_ADMIN_SCOPE: &str = "admin:data";
+
+/// How often the scheduled anonymization runs
+pub const ANONYMIZATION_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// Outcome of an anonymization run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AnonymizationReport {
+    /// Whether records were only counted, not changed
+    pub dry_run: bool,
+    /// Records older than this were considered
+    pub cutoff: DateTime<Utc>,
+    pub users_scanned: usize,
+    pub events_anonymized: usize,
+    pub sessions_anonymized: usize,
+}
+
+/// Scrubs personal data from records older than the retention window
+///
+/// Audit trail entries keep their action and timestamp, so counts per action
+/// and period stay available; only tagged PII fields (see
+/// [`crate::pii::PiiFields`]) and email addresses in their data are replaced
+/// with [`crate::pii::REDACTED`]. Sessions keep their timestamps and lose
+/// their IP address and device name. Each run, dry or not, is logged as a
+/// `com.simple-api-demo.data.anonymized` audit event.
+pub struct AnonymizationJob {
+    repository: UserRepository,
+    sessions: Arc<SessionRegistry>,
+    retention: Duration,
+    dry_run: bool,
+}
+
+impl AnonymizationJob {
+    /// Creates the job
+    ///
+    /// # Arguments
+    /// * `repository` - User storage, whose audit trails are scrubbed
+    /// * `sessions` - Sessions to scrub
+    /// * `retention` - Age after which records are anonymized
+    /// * `dry_run` - Whether scheduled runs only report affected counts
+    pub fn new(repository: UserRepository, sessions: Arc<SessionRegistry>, retention: Duration, dry_run: bool) -> Self {
+        Self {
+            repository,
+            sessions,
+            retention,
+            dry_run,
+        }
+    }
+
+    /// Builds the job from `DATA_RETENTION_DAYS`/`ANONYMIZATION_DRY_RUN`
+    pub fn from_config(config: &Config, repository: UserRepository, sessions: Arc<SessionRegistry>) -> Self {
+        Self::new(
+            repository,
+            sessions,
+            Duration::from_secs(u64::from(config.data_retention_days) * 24 * 3600),
+            config.anonymization_dry_run,
+        )
+    }
+
+    /// Anonymizes records older than the retention window
+    ///
+    /// With `dry_run` nothing is written and the report holds the counts a
+    /// real run would change. Records already anonymized are not counted
+    /// again.
+    pub fn run(&self, dry_run: bool) -> AppResult<AnonymizationReport> {
+        let cutoff = Utc::now() - chrono::Duration::seconds(self.retention.as_secs() as i64);
+        let fields = tagged_fields();
+        let mut report = AnonymizationReport {
+            dry_run,
+            cutoff,
+            users_scanned: 0,
+            events_anonymized: 0,
+            sessions_anonymized: 0,
+        };
+
+        for user_id in self.repository.ids()? {
+            report.users_scanned += 1;
+            let mut events = self.repository.events(&user_id)?;
+            let mut changed = 0;
+            for event in events.iter_mut().filter(|event| event.at < cutoff) {
+                if scrub_json(&mut event.data, &fields) {
+                    changed += 1;
+                }
+            }
+            if changed > 0 && !dry_run {
+                self.repository.save_events(&user_id, &events)?;
+            }
+            report.events_anonymized += changed;
+            report.sessions_anonymized += self.sessions.anonymize_before(&user_id, cutoff, dry_run)?;
+        }
+
+        let event = CloudEvent::new("com.simple-api-demo.data.anonymized", json!(report));
+        info!(target: "audit", "{}", event.to_json()?);
+        Ok(report)
+    }
+
+    /// Runs the job every `every` under `supervisor`, honouring the
+    /// configured dry-run mode
+    pub fn spawn_scheduler(job: Arc<Self>, every: Duration, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("anonymization", move || {
+            let job = job.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(every);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = job.run(job.dry_run) {
+                        error!("Data anonymization failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenDenylist;
+    use crate::pii::REDACTED;
+    use crate::state::InMemoryStore;
+    use crate::users::UserEvent;
+
+    fn job(retention: Duration) -> (AnonymizationJob, UserRepository, Arc<SessionRegistry>) {
+        let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+        let sessions = Arc::new(SessionRegistry::new(
+            Arc::new(InMemoryStore::new()),
+            TokenDenylist::new(Arc::new(InMemoryStore::new())),
+            Duration::from_secs(3600),
+        ));
+        let job = AnonymizationJob::new(repository.clone(), sessions.clone(), retention, false);
+        (job, repository, sessions)
+    }
+
+    #[test]
+    fn test_old_records_are_scrubbed_and_aggregates_kept() {
+        let (job, repository, _) = job(Duration::from_secs(30 * 24 * 3600));
+        let user = repository.create("ann@example.com", String::new()).unwrap();
+        let old = UserEvent {
+            id: "e1".to_string(),
+            user_id: user.id.clone(),
+            action: "user.email_changed".to_string(),
+            at: Utc::now() - chrono::Duration::days(90),
+            data: json!({ "email": "ann@example.com", "attempts": 2 }),
+        };
+        let recent = UserEvent {
+            id: "e2".to_string(),
+            at: Utc::now(),
+            ..old.clone()
+        };
+        repository.save_events(&user.id, &[old, recent]).unwrap();
+
+        let report = job.run(true).unwrap();
+        assert_eq!(report.users_scanned, 1);
+        assert_eq!(report.events_anonymized, 1);
+        assert_eq!(repository.events(&user.id).unwrap()[0].data["email"], "ann@example.com");
+
+        let report = job.run(false).unwrap();
+        assert!(!report.dry_run);
+        assert_eq!(report.events_anonymized, 1);
+        let events = repository.events(&user.id).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].action, "user.email_changed");
+        assert_eq!(events[0].data, json!({ "email": REDACTED, "attempts": 2 }));
+        assert_eq!(events[1].data["email"], "ann@example.com");
+
+        // Already anonymized records are not counted again
+        assert_eq!(job.run(false).unwrap().events_anonymized, 0);
+    }
+
+    #[test]
+    fn test_idle_sessions_are_scrubbed() {
+        let (job, repository, sessions) = job(Duration::ZERO);
+        let user = repository.create("ann@example.com", String::new()).unwrap();
+        let session = sessions.create(&user.id, "Ann's phone", "192.0.2.1".parse().unwrap()).unwrap();
+
+        assert_eq!(job.run(true).unwrap().sessions_anonymized, 1);
+        assert_eq!(sessions.get(&session.id).unwrap().unwrap().ip, "192.0.2.1");
+
+        assert_eq!(job.run(false).unwrap().sessions_anonymized, 1);
+        let session = sessions.get(&session.id).unwrap().unwrap();
+        assert_eq!(session.ip, REDACTED);
+        assert_eq!(session.device_name, REDACTED);
+    }
+}
@@ -0,0 +1,384 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::error::AppError;
+
+/// A static file compiled into the binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Asset {
+    /// Request path the asset is served at
+    pub path: &'static str,
+    /// File name inside `assets/` and the override directory
+    pub file: &'static str,
+    pub content_type: &'static str,
+    pub bytes: &'static [u8],
+}
+
+/// Every bundled asset
+pub const EMBEDDED: &[Asset] = &[
+    Asset {
+        path: "/console",
+        file: "console.html",
+        content_type: "text/html; charset=utf-8",
+        bytes: include_bytes!("../assets/console.html"),
+    },
+    Asset {
+        path: "/docs",
+        file: "swagger.html",
+        content_type: "text/html; charset=utf-8",
+        bytes: include_bytes!("../assets/swagger.html"),
+    },
+    Asset {
+        path: "/dashboard",
+        file: "dashboard.html",
+        content_type: "text/html; charset=utf-8",
+        bytes: include_bytes!("../assets/dashboard.html"),
+    },
+    Asset {
+        path: "/favicon.ico",
+        file: "favicon.ico",
+        content_type: "image/x-icon",
+        bytes: include_bytes!("../assets/favicon.ico"),
+    },
+];
+
+/// Content codings assets can be served with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Token used in `Accept-Encoding` and `Content-Encoding`
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Suffix of pre-compressed files, e.g. `console.html.br`
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    /// Encodings an `Accept-Encoding` value allows, most preferred first
+    ///
+    /// Higher q-values win and Brotli wins ties; `*` stands for the
+    /// encodings not listed explicitly, and `q=0` refuses one.
+    pub fn negotiate(accept_encoding: &str) -> Vec<Encoding> {
+        let mut explicit: Vec<(Option<Encoding>, f32)> = Vec::new();
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';');
+            let coding = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let encoding = match coding.as_str() {
+                "br" => Some(Encoding::Brotli),
+                "gzip" | "x-gzip" => Some(Encoding::Gzip),
+                "*" => None,
+                _ => continue,
+            };
+            explicit.push((encoding, q));
+        }
+        let wildcard = explicit.iter().find(|(encoding, _)| encoding.is_none()).map(|(_, q)| *q);
+        let mut accepted: Vec<(Encoding, f32)> = [Encoding::Brotli, Encoding::Gzip]
+            .into_iter()
+            .filter_map(|encoding| {
+                let q = explicit
+                    .iter()
+                    .find(|(listed, _)| *listed == Some(encoding))
+                    .map(|(_, q)| *q)
+                    .or(wildcard)?;
+                (q > 0.0).then_some((encoding, q))
+            })
+            .collect();
+        // Stable sort keeps Brotli ahead of gzip on equal q-values
+        accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+        accepted.into_iter().map(|(encoding, _)| encoding).collect()
+    }
+
+    fn compress(self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+                writer.write_all(bytes)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Body of an asset in the encoding picked for a request
+#[derive(Debug, Clone)]
+pub struct AssetVariant {
+    pub asset: Asset,
+    /// `None` for the uncompressed content
+    pub encoding: Option<Encoding>,
+    pub bytes: Bytes,
+}
+
+/// Compressed copy of an asset and the digest of the content it was made from
+struct CompressedCopy {
+    source: [u8; 32],
+    /// `None` when compressing did not make the asset smaller
+    bytes: Option<Bytes>,
+}
+
+/// Serves the embedded assets, preferring files from an override directory
+///
+/// Only the file names listed in [`EMBEDDED`] are looked up in the override
+/// directory, so it cannot be used to serve arbitrary files. Clients
+/// accepting Brotli or gzip get a compressed copy: a pre-compressed
+/// `<file>.br`/`<file>.gz` from the override directory when there is one,
+/// otherwise one compressed on first request and cached until the content
+/// changes.
+pub struct AssetStore {
+    override_dir: Option<PathBuf>,
+    compressed: Mutex<HashMap<(&'static str, Encoding), CompressedCopy>>,
+}
+
+impl AssetStore {
+    /// Creates a store; `override_dir` holds customized copies of assets
+    pub fn new(override_dir: Option<PathBuf>) -> Self {
+        Self {
+            override_dir,
+            compressed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Builds the store from `ASSETS_DIR`
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.assets_dir.as_ref().map(PathBuf::from))
+    }
+
+    /// Returns the asset served at `path` and its content
+    ///
+    /// An unreadable override falls back to the embedded copy.
+    pub fn get(&self, path: &str) -> Option<(Asset, Cow<'static, [u8]>)> {
+        let asset = *EMBEDDED.iter().find(|asset| asset.path == path)?;
+        let Some(dir) = &self.override_dir else {
+            return Some((asset, Cow::Borrowed(asset.bytes)));
+        };
+        let file = dir.join(asset.file);
+        match std::fs::read(&file) {
+            Ok(bytes) => Some((asset, Cow::Owned(bytes))),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to read asset override {}: {}", file.display(), e);
+                }
+                Some((asset, Cow::Borrowed(asset.bytes)))
+            }
+        }
+    }
+
+    /// Returns the asset served at `path` in the first of the `accepted`
+    /// encodings that makes it smaller, uncompressed otherwise
+    pub fn variant(&self, path: &str, accepted: &[Encoding]) -> Option<AssetVariant> {
+        let (asset, bytes) = self.get(path)?;
+        for &encoding in accepted {
+            let compressed = self
+                .precompressed(asset, encoding)
+                .or_else(|| self.compressed(asset, encoding, &bytes));
+            if let Some(compressed) = compressed {
+                return Some(AssetVariant {
+                    asset,
+                    encoding: Some(encoding),
+                    bytes: compressed,
+                });
+            }
+        }
+        Some(AssetVariant {
+            asset,
+            encoding: None,
+            bytes: Bytes::from(bytes.into_owned()),
+        })
+    }
+
+    /// Whether a copy of the asset at `path` compressed with `encoding` is cached
+    pub fn is_cached(&self, path: &str, encoding: Encoding) -> bool {
+        self.compressed
+            .lock()
+            .map(|compressed| compressed.keys().any(|(cached, with)| *cached == path && *with == encoding))
+            .unwrap_or(false)
+    }
+
+    /// Reads a pre-compressed copy from the override directory
+    fn precompressed(&self, asset: Asset, encoding: Encoding) -> Option<Bytes> {
+        let file = self.override_dir.as_ref()?.join(format!("{}.{}", asset.file, encoding.extension()));
+        match std::fs::read(&file) {
+            Ok(bytes) => Some(Bytes::from(bytes)),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to read pre-compressed asset {}: {}", file.display(), e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Returns the cached compressed copy of `bytes`, compressing them when
+    /// the cache has none for this content
+    fn compressed(&self, asset: Asset, encoding: Encoding, bytes: &[u8]) -> Option<Bytes> {
+        let source: [u8; 32] = Sha256::digest(bytes).into();
+        let key = (asset.path, encoding);
+        if let Some(copy) = self.compressed.lock().ok()?.get(&key).filter(|copy| copy.source == source) {
+            return copy.bytes.clone();
+        }
+        let compressed = match encoding.compress(bytes) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                warn!("Failed to compress asset {} with {}: {}", asset.file, encoding.name(), e);
+                return None;
+            }
+        };
+        debug!("Compressed {} with {}: {} -> {} bytes", asset.file, encoding.name(), bytes.len(), compressed.len());
+        let compressed = (compressed.len() < bytes.len()).then(|| Bytes::from(compressed));
+        self.compressed.lock().ok()?.insert(
+            key,
+            CompressedCopy {
+                source,
+                bytes: compressed.clone(),
+            },
+        );
+        compressed
+    }
+}
+
+/// Serves the asset registered for the request path
+///
+/// The body is compressed according to `Accept-Encoding`, and responses
+/// vary on it so shared caches keep the encodings apart.
+pub async fn serve(req: HttpRequest, assets: web::Data<AssetStore>) -> Result<HttpResponse, AppError> {
+    let accepted = req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(Encoding::negotiate)
+        .unwrap_or_default();
+    let variant = assets
+        .variant(req.path(), &accepted)
+        .ok_or_else(|| AppError::not_found("asset not found"))?;
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(variant.asset.content_type)
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .insert_header((header::VARY, "Accept-Encoding"));
+    if let Some(encoding) = variant.encoding {
+        response.insert_header((header::CONTENT_ENCODING, encoding.name()));
+    }
+    Ok(response.body(variant.bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_assets() {
+        let store = AssetStore::new(None);
+        let (asset, bytes) = store.get("/docs").unwrap();
+        assert_eq!(asset.content_type, "text/html; charset=utf-8");
+        assert!(String::from_utf8_lossy(&bytes).contains("/openapi.json"));
+        assert_eq!(&store.get("/favicon.ico").unwrap().1[..4], &[0, 0, 1, 0]);
+        assert!(store.get("/console.html").is_none());
+    }
+
+    #[test]
+    fn test_override_directory() {
+        let dir = std::env::temp_dir().join(format!("assets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("console.html"), "<h1>Custom</h1>").unwrap();
+
+        let store = AssetStore::new(Some(dir.clone()));
+        assert_eq!(&store.get("/console").unwrap().1[..], b"<h1>Custom</h1>");
+        // Assets without an override keep the embedded copy
+        assert_eq!(&store.get("/dashboard").unwrap().1[..], EMBEDDED[2].bytes);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_encoding_negotiation() {
+        assert_eq!(Encoding::negotiate("gzip, deflate, br"), vec![Encoding::Brotli, Encoding::Gzip]);
+        assert_eq!(Encoding::negotiate("br;q=0.5, gzip"), vec![Encoding::Gzip, Encoding::Brotli]);
+        assert_eq!(Encoding::negotiate("gzip, br;q=0"), vec![Encoding::Gzip]);
+        assert_eq!(Encoding::negotiate("*;q=0.3, gzip"), vec![Encoding::Gzip, Encoding::Brotli]);
+        assert_eq!(Encoding::negotiate("identity"), vec![]);
+        assert_eq!(Encoding::negotiate("*;q=0"), vec![]);
+    }
+
+    #[test]
+    fn test_compressed_variants_are_cached() {
+        let store = AssetStore::new(None);
+        let plain = store.variant("/docs", &[]).unwrap();
+        assert_eq!((plain.encoding, &plain.bytes[..]), (None, EMBEDDED[1].bytes));
+
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            let variant = store.variant("/docs", &[encoding]).unwrap();
+            assert_eq!(variant.encoding, Some(encoding));
+            assert!(variant.bytes.len() < plain.bytes.len());
+            let mut decoded = Vec::new();
+            match encoding {
+                Encoding::Brotli => {
+                    brotli::BrotliDecompress(&mut &variant.bytes[..], &mut decoded).unwrap();
+                }
+                Encoding::Gzip => {
+                    std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&variant.bytes[..]), &mut decoded)
+                        .unwrap();
+                }
+            }
+            assert_eq!(decoded, EMBEDDED[1].bytes);
+            // The second request is served from the cache
+            assert_eq!(store.variant("/docs", &[encoding]).unwrap().bytes.as_ptr(), variant.bytes.as_ptr());
+        }
+    }
+
+    #[test]
+    fn test_precompressed_and_incompressible_overrides() {
+        let dir = std::env::temp_dir().join(format!("assets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("console.html.gz"), b"prebuilt").unwrap();
+        std::fs::write(dir.join("dashboard.html"), b"<p>").unwrap();
+
+        let store = AssetStore::new(Some(dir.clone()));
+        let prebuilt = store.variant("/console", &[Encoding::Brotli, Encoding::Gzip]).unwrap();
+        assert_eq!(prebuilt.encoding, Some(Encoding::Brotli));
+        let prebuilt = store.variant("/console", &[Encoding::Gzip]).unwrap();
+        assert_eq!((prebuilt.encoding, &prebuilt.bytes[..]), (Some(Encoding::Gzip), &b"prebuilt"[..]));
+
+        // Compressing three bytes only makes them larger
+        let tiny = store.variant("/dashboard", &[Encoding::Gzip]).unwrap();
+        assert_eq!((tiny.encoding, &tiny.bytes[..]), (None, &b"<p>"[..]));
+
+        // Changing the override replaces the cached copy
+        let compressed = store.variant("/dashboard", &[Encoding::Brotli]).unwrap();
+        std::fs::write(dir.join("dashboard.html"), "<p>changed</p>".repeat(50)).unwrap();
+        let changed = store.variant("/dashboard", &[Encoding::Brotli]).unwrap();
+        assert_eq!(changed.encoding, Some(Encoding::Brotli));
+        assert_ne!(changed.bytes, compressed.bytes);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+
+use crate::client_info::client_ip;
+use crate::config::Config;
+use crate::context::{AuthPrincipal, PrincipalSlot, RequestContext};
+use crate::error::{AppError, AppResult};
+
+/// One request as recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    pub trace_id: String,
+    /// User id, key name, Basic user or signing client; `None` for anonymous requests
+    pub principal: Option<String>,
+    /// How the principal authenticated: `user`, `service`, `basic` or `signed`
+    pub auth: Option<&'static str>,
+    /// Client address, resolved through trusted proxies
+    pub client_ip: Option<IpAddr>,
+    pub method: String,
+    /// Route pattern (`/hooks/{provider}`), `<unmatched>` for unknown paths
+    pub route: String,
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: f64,
+}
+
+/// Destination of audit records
+///
+/// Implement it to ship records elsewhere (a SIEM, a database) and register
+/// it with [`ServerManagerBuilder::audit_sink`](crate::server::ServerManagerBuilder::audit_sink).
+pub trait AuditSink: Send + Sync {
+    /// Records one request
+    fn record(&self, record: &AuditRecord) -> AppResult<()>;
+}
+
+/// Where `AUDIT_LOG` sends records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditTarget {
+    /// One JSON object per line on stdout
+    Stdout,
+    /// One JSON object per line appended to a file
+    File(PathBuf),
+}
+
+impl FromStr for AuditTarget {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "" => Err(AppError::validation("AUDIT_LOG must be 'stdout' or a file path")),
+            "stdout" | "-" => Ok(AuditTarget::Stdout),
+            path => Ok(AuditTarget::File(PathBuf::from(path))),
+        }
+    }
+}
+
+/// [`AuditSink`] writing JSON lines to stdout
+#[derive(Debug, Default)]
+pub struct StdoutAuditSink;
+
+impl AuditSink for StdoutAuditSink {
+    fn record(&self, record: &AuditRecord) -> AppResult<()> {
+        let line = serde_json::to_string(record).map_err(|e| AppError::internal(e.to_string()))?;
+        writeln!(std::io::stdout().lock(), "{}", line)
+            .map_err(|e| AppError::internal(format!("failed to write audit record: {}", e)))
+    }
+}
+
+/// [`AuditSink`] appending JSON lines to a file
+#[derive(Debug)]
+pub struct FileAuditSink {
+    file: Mutex<File>,
+}
+
+impl FileAuditSink {
+    /// Opens `path` for appending, creating it (mode 0640) if needed
+    ///
+    /// # Errors
+    /// Returns a config error when the file cannot be opened
+    pub fn open(path: &std::path::Path) -> AppResult<Self> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o640);
+        let file = options
+            .open(path)
+            .map_err(|e| AppError::config(format!("Failed to open AUDIT_LOG {}: {}", path.display(), e)))?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&self, record: &AuditRecord) -> AppResult<()> {
+        let mut line = serde_json::to_vec(record).map_err(|e| AppError::internal(e.to_string()))?;
+        line.push(b'\n');
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| AppError::internal("audit log lock poisoned"))?;
+        // One write per record, so concurrent writers never interleave lines
+        file.write_all(&line)
+            .map_err(|e| AppError::internal(format!("failed to write audit record: {}", e)))
+    }
+}
+
+/// Builds the sink selected by `AUDIT_LOG`; `None` when auditing is off
+///
+/// # Errors
+/// Returns a config error when the audit file cannot be opened
+pub fn sink_from_config(config: &Config) -> AppResult<Option<Arc<dyn AuditSink>>> {
+    Ok(match &config.audit_log {
+        None => None,
+        Some(AuditTarget::Stdout) => Some(Arc::new(StdoutAuditSink)),
+        Some(AuditTarget::File(path)) => Some(Arc::new(FileAuditSink::open(path)?)),
+    })
+}
+
+/// Middleware recording every request in the registered [`AuditSink`], for use with `from_fn`
+///
+/// Runs inside `attach_context` so the record carries the request and trace
+/// ids, and collects the principal the authentication middleware records
+/// through a [`PrincipalSlot`], so requests rejected after authentication
+/// (quota, scopes) are audited with both the caller and the status they got. A failing sink is logged, never
+/// surfaced to the client. Does nothing without a `web::Data<dyn AuditSink>`.
+pub async fn audit_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(sink) = req.app_data::<web::Data<dyn AuditSink>>().cloned() else {
+        return next.call(req).await;
+    };
+    let started = Instant::now();
+    let slot = PrincipalSlot::default();
+    req.extensions_mut().insert(slot.clone());
+    let (request_id, trace_id) = req
+        .extensions()
+        .get::<RequestContext>()
+        .map(|context| (context.request_id.clone(), context.trace_id.clone()))
+        .unwrap_or_default();
+    let client_ip = client_ip(req.request());
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let route = req.match_pattern().unwrap_or_else(|| "<unmatched>".to_string());
+
+    let result = next.call(req).await;
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    let principal = slot.get();
+    let record = AuditRecord {
+        timestamp: Utc::now(),
+        request_id,
+        trace_id,
+        principal: principal.as_ref().map(|principal| principal.subject().to_string()),
+        auth: principal.as_ref().map(AuthPrincipal::kind),
+        client_ip,
+        method,
+        route,
+        path,
+        status: status.as_u16(),
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+    };
+    if let Err(e) = sink.record(&record) {
+        warn!("Failed to record audit entry for {} {}: {}", record.method, record.path, e);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::scopes::require_scopes;
+    use crate::auth::TokenService;
+    use crate::context::attach_context;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<AuditRecord>>);
+
+    impl AuditSink for RecordingSink {
+        fn record(&self, record: &AuditRecord) -> AppResult<()> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_target_parsing() {
+        assert_eq!("stdout".parse::<AuditTarget>().unwrap(), AuditTarget::Stdout);
+        assert_eq!(
+            "/var/log/audit.jsonl".parse::<AuditTarget>().unwrap(),
+            AuditTarget::File(PathBuf::from("/var/log/audit.jsonl"))
+        );
+        assert!(" ".parse::<AuditTarget>().is_err());
+    }
+
+    #[test]
+    fn test_file_sink_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let config = Config {
+            audit_log: Some(AuditTarget::File(path.clone())),
+            ..Config::default()
+        };
+        let sink = sink_from_config(&config).unwrap().unwrap();
+        let record = AuditRecord {
+            timestamp: Utc::now(),
+            request_id: "req-1".to_string(),
+            trace_id: "trace".to_string(),
+            principal: Some("ann".to_string()),
+            auth: Some("user"),
+            client_ip: Some("10.0.0.1".parse().unwrap()),
+            method: "GET".to_string(),
+            route: "/private".to_string(),
+            path: "/private".to_string(),
+            status: 200,
+            latency_ms: 1.5,
+        };
+        sink.record(&record).unwrap();
+        sink.record(&AuditRecord { status: 403, ..record }).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["principal"], "ann");
+        assert_eq!(lines[1]["status"], 403);
+        assert!(sink_from_config(&Config::default()).unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_audit_requests() {
+        let tokens = TokenService::new(b"audit-test-signing-key-0123456789", "simple-api-demo");
+        let token = tokens.issue("ann", &["read:private"], Duration::from_secs(60)).unwrap();
+        let sink = Arc::new(RecordingSink::default());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(tokens))
+                .app_data(web::Data::from(sink.clone() as Arc<dyn AuditSink>))
+                .wrap(from_fn(audit_requests))
+                .wrap(from_fn(attach_context))
+                .route(
+                    "/private/{item}",
+                    web::get()
+                        .to(HttpResponse::Ok)
+                        // Rejects item 9 after authentication, like a quota would
+                        .wrap(from_fn(|req: ServiceRequest, next: Next<_>| async move {
+                            if req.match_info().get("item") == Some("9") {
+                                return Err(AppError::rate_limited("quota exhausted", 60).into());
+                            }
+                            next.call(req).await
+                        }))
+                        .wrap(from_fn(|req, next| require_scopes(Rc::new(vec!["read:private".to_string()]), req, next))),
+                ),
+        )
+        .await;
+        let authorized = |uri: &str| {
+            TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .insert_header(("X-Request-Id", "req-42"))
+                .to_request()
+        };
+
+        assert_eq!(call_service(&app, authorized("/private/7")).await.status(), 200);
+        let err = try_call_service(&app, TestRequest::get().uri("/private/8").to_request()).await.err().unwrap();
+        assert_eq!(err.error_response().status(), 401);
+        let err = try_call_service(&app, authorized("/private/9")).await.err().unwrap();
+        assert_eq!(err.error_response().status(), 429);
+        call_service(&app, TestRequest::get().uri("/nope").to_request()).await;
+
+        let records = sink.0.lock().unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].request_id, "req-42");
+        assert_eq!((records[0].principal.as_deref(), records[0].auth), (Some("ann"), Some("user")));
+        assert_eq!((records[0].route.as_str(), records[0].path.as_str()), ("/private/{item}", "/private/7"));
+        assert_eq!((records[1].principal.as_deref(), records[1].status), (None, 401));
+        assert_eq!((records[2].principal.as_deref(), records[2].status), (Some("ann"), 429));
+        assert_eq!((records[3].route.as_str(), records[3].status), ("<unmatched>", 404));
+    }
+}
@@ -0,0 +1,383 @@
+//! Self-service API keys users create for themselves and present as bearer
+//! credentials, with the scopes chosen at creation.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
+use sha2::{Digest, Sha256};
+
+use super::tokens::Claims;
+use crate::error::{AppError, AppResult};
+use crate::integrity::{IntegrityIssue, IssueKind};
+use crate::state::KeyValueStore;
+use crate::users::ACCOUNT_SCOPE;
+
+/// Prefix identifying API keys among bearer credentials
+pub const API_KEY_PREFIX: &str = "sak_";
+
+/// Characters of the key kept in listings so users can recognise it
+const DISPLAY_PREFIX_LEN: usize = 12;
+
+/// Maximum key name length
+const MAX_NAME_LENGTH: usize = 100;
+
+/// Minimum interval between `last_used_at` updates, to avoid a write per request
+const TOUCH_INTERVAL_SECS: i64 = 60;
+
+/// A user-created API key; the secret itself is never stored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    /// Leading characters of the key, e.g. `sak_1a2b3c4d`
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Claims representing a request authenticated with this key
+    ///
+    /// `jti` is the key id, so denylisting it revokes the key, and the
+    /// `api_key` claim tells handlers the caller used a key.
+    pub fn claims(&self, issuer: &str) -> Claims {
+        let mut extra = Map::new();
+        extra.insert("api_key".to_string(), json!(self.id));
+        Claims {
+            sub: self.user_id.clone(),
+            iss: issuer.to_string(),
+            exp: self.expires_at.map_or(i64::MAX, |at| at.timestamp()),
+            iat: self.created_at.timestamp(),
+            jti: self.id.clone(),
+            scope: Some(self.scopes.join(" ")),
+            sid: None,
+            act: None,
+            extra,
+        }
+    }
+}
+
+/// Self-service API keys
+///
+/// Keys are random `sak_`-prefixed strings shown once at creation and stored
+/// as SHA-256 hashes (they carry 256 random bits, so a slow hash adds
+/// nothing). Keys: `key:{id}` (JSON), `hash:{sha256}` (id) and `user:{user_id}`
+/// (JSON array of key ids, pruned on read). Expiring keys disappear from the
+/// store when they expire.
+pub struct ApiKeyService {
+    store: Arc<dyn KeyValueStore>,
+}
+
+impl ApiKeyService {
+    /// Creates the service on `store`
+    pub fn new(store: Arc<dyn KeyValueStore>) -> Self {
+        Self { store }
+    }
+
+    /// Creates a key for `user_id` and returns it with its secret
+    ///
+    /// Without `scopes` the key gets every scope in `granted` except
+    /// [`ACCOUNT_SCOPE`], which keys can never hold so they cannot manage
+    /// the account (or mint further keys).
+    ///
+    /// # Errors
+    /// Validation error for a bad name, expiry or scope list; forbidden (listing
+    /// them) when asking for scopes outside `granted`.
+    pub fn create(
+        &self,
+        user_id: &str,
+        name: &str,
+        scopes: &[String],
+        granted: &[&str],
+        expires_in: Option<Duration>,
+    ) -> AppResult<(ApiKey, String)> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(AppError::validation(format!(
+                "name must be between 1 and {} characters",
+                MAX_NAME_LENGTH
+            )));
+        }
+        if expires_in.is_some_and(|ttl| ttl.is_zero()) {
+            return Err(AppError::validation("expiry must be in the future"));
+        }
+
+        let scopes: Vec<String> = if scopes.is_empty() {
+            granted
+                .iter()
+                .filter(|scope| **scope != ACCOUNT_SCOPE)
+                .map(|scope| scope.to_string())
+                .collect()
+        } else {
+            if scopes.iter().any(|scope| scope == ACCOUNT_SCOPE) {
+                return Err(AppError::validation("the account scope cannot be granted to API keys"));
+            }
+            let missing: Vec<String> = scopes
+                .iter()
+                .filter(|scope| !granted.contains(&scope.as_str()))
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                return Err(AppError::missing_scopes(missing));
+            }
+            let mut unique: Vec<String> = Vec::new();
+            for scope in scopes {
+                if !unique.contains(scope) {
+                    unique.push(scope.clone());
+                }
+            }
+            unique
+        };
+        if scopes.is_empty() {
+            return Err(AppError::validation("no scopes to grant"));
+        }
+
+        let mut bytes = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let secret = format!("{}{}", API_KEY_PREFIX, hex::encode(bytes));
+        let now = Utc::now();
+        let key = ApiKey {
+            id: uuid::Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            name: name.to_string(),
+            prefix: secret[..DISPLAY_PREFIX_LEN].to_string(),
+            scopes,
+            created_at: now,
+            expires_at: expires_in.map(|ttl| now + chrono::Duration::seconds(ttl.as_secs() as i64)),
+            last_used_at: None,
+        };
+
+        self.save(&key)?;
+        self.store
+            .set(&format!("hash:{}", hash_key(&secret)), &key.id, ttl_until(key.expires_at))?;
+        let mut ids = self.key_ids(user_id)?;
+        ids.push(key.id.clone());
+        self.save_key_ids(user_id, &ids)?;
+        Ok((key, secret))
+    }
+
+    /// Lists the user's keys, newest first
+    pub fn list(&self, user_id: &str) -> AppResult<Vec<ApiKey>> {
+        let ids = self.key_ids(user_id)?;
+        let mut keys = Vec::new();
+        for id in &ids {
+            if let Some(key) = self.get(id)? {
+                keys.push(key);
+            }
+        }
+        if keys.len() != ids.len() {
+            let live: Vec<String> = keys.iter().map(|key| key.id.clone()).collect();
+            self.save_key_ids(user_id, &live)?;
+        }
+        keys.sort_by_key(|key| std::cmp::Reverse(key.created_at));
+        Ok(keys)
+    }
+
+    /// Revokes one of `user_id`'s keys
+    ///
+    /// # Errors
+    /// Returns not found if the key does not exist or belongs to someone else
+    pub fn revoke(&self, user_id: &str, id: &str) -> AppResult<()> {
+        let key = self
+            .get(id)?
+            .filter(|key| key.user_id == user_id)
+            .ok_or_else(|| AppError::not_found("API key not found"))?;
+
+        // The hash index entry is found through the secret, which is gone;
+        // removing the key record is enough for `authenticate` to fail.
+        self.store.delete(&format!("key:{}", key.id))?;
+        let ids: Vec<String> = self
+            .key_ids(user_id)?
+            .into_iter()
+            .filter(|other| *other != key.id)
+            .collect();
+        self.save_key_ids(user_id, &ids)
+    }
+
+    /// Revokes every key of `user_id`; returns how many there were
+    pub fn revoke_all(&self, user_id: &str) -> AppResult<usize> {
+        let keys = self.list(user_id)?;
+        for key in &keys {
+            self.revoke(user_id, &key.id)?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Looks up the key for a presented secret and records its use
+    ///
+    /// # Errors
+    /// Unauthorized for unknown, revoked or expired keys
+    pub fn authenticate(&self, secret: &str) -> AppResult<ApiKey> {
+        let invalid = || AppError::unauthorized("invalid API key");
+        let id = self
+            .store
+            .get(&format!("hash:{}", hash_key(secret)))?
+            .ok_or_else(invalid)?;
+        let mut key = self.get(&id)?.ok_or_else(invalid)?;
+        let now = Utc::now();
+        if key.expires_at.is_some_and(|at| at <= now) {
+            return Err(invalid());
+        }
+
+        if key
+            .last_used_at
+            .is_none_or(|at| (now - at).num_seconds() >= TOUCH_INTERVAL_SECS)
+        {
+            key.last_used_at = Some(now);
+            if let Err(e) = self.save(&key) {
+                warn!("Failed to record use of API key {}: {}", key.id, e);
+            }
+        }
+        Ok(key)
+    }
+
+    /// Checks the keys indexed under `user_id`, timestamps after `horizon`
+    /// counting as in the future
+    ///
+    /// Ids listed twice and keys of another user are reported and, with
+    /// `repair`, dropped from the index; inconsistent timestamps are only
+    /// reported.
+    pub fn check_integrity(&self, user_id: &str, horizon: DateTime<Utc>, repair: bool) -> AppResult<Vec<IntegrityIssue>> {
+        let index = format!("user:{}", user_id);
+        let listed = self.key_ids(user_id)?;
+        let mut ids: Vec<String> = Vec::with_capacity(listed.len());
+        let mut issues = Vec::new();
+        for id in listed {
+            if ids.contains(&id) {
+                let detail = format!("API key {} is listed more than once", id);
+                issues.push(IntegrityIssue::repairable(IssueKind::DuplicateKey, &index, detail, repair));
+                continue;
+            }
+            if let Some(key) = self.get(&id)? {
+                if key.user_id != user_id {
+                    let detail = format!("API key {} belongs to user {}", id, key.user_id);
+                    issues.push(IntegrityIssue::repairable(IssueKind::OrphanedReference, &index, detail, repair));
+                    continue;
+                }
+                let record = format!("key:{}", id);
+                let before_creation = |at: Option<DateTime<Utc>>| at.is_some_and(|at| at < key.created_at);
+                if before_creation(key.expires_at) || before_creation(key.last_used_at) {
+                    let detail = "expires or was last used before it was created";
+                    issues.push(IntegrityIssue::new(IssueKind::InvalidTimestamp, &record, detail));
+                }
+                if key.created_at > horizon || key.last_used_at.is_some_and(|at| at > horizon) {
+                    let detail = "created or last used in the future";
+                    issues.push(IntegrityIssue::new(IssueKind::InvalidTimestamp, &record, detail));
+                }
+            }
+            ids.push(id);
+        }
+        if repair && issues.iter().any(|issue| issue.repaired) {
+            self.save_key_ids(user_id, &ids)?;
+        }
+        Ok(issues)
+    }
+
+    fn get(&self, id: &str) -> AppResult<Option<ApiKey>> {
+        self.store
+            .get(&format!("key:{}", id))?
+            .map(|raw| {
+                serde_json::from_str(&raw).map_err(|e| AppError::internal(format!("Corrupt API key {}: {}", id, e)))
+            })
+            .transpose()
+    }
+
+    fn save(&self, key: &ApiKey) -> AppResult<()> {
+        let encoded =
+            serde_json::to_string(key).map_err(|e| AppError::internal(format!("Failed to encode API key: {}", e)))?;
+        self.store
+            .set(&format!("key:{}", key.id), &encoded, ttl_until(key.expires_at))
+    }
+
+    fn key_ids(&self, user_id: &str) -> AppResult<Vec<String>> {
+        self.store
+            .get(&format!("user:{}", user_id))?
+            .map(|raw| {
+                serde_json::from_str(&raw)
+                    .map_err(|e| AppError::internal(format!("Corrupt API key index for {}: {}", user_id, e)))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn save_key_ids(&self, user_id: &str, ids: &[String]) -> AppResult<()> {
+        let encoded = serde_json::to_string(ids)
+            .map_err(|e| AppError::internal(format!("Failed to encode API key index: {}", e)))?;
+        self.store.set(&format!("user:{}", user_id), &encoded, None)
+    }
+}
+
+fn hash_key(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn ttl_until(expires_at: Option<DateTime<Utc>>) -> Option<Duration> {
+    expires_at.map(|at| Duration::from_secs((at - Utc::now()).num_seconds().max(1) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    const GRANTED: &[&str] = &[ACCOUNT_SCOPE, "read:private", "write:items"];
+
+    #[test]
+    fn test_create_authenticate_and_revoke() {
+        let service = ApiKeyService::new(Arc::new(InMemoryStore::new()));
+        let (key, secret) = service.create("ann", "CI", &[], GRANTED, None).unwrap();
+        assert!(secret.starts_with(API_KEY_PREFIX));
+        assert!(secret.starts_with(&key.prefix));
+        assert_eq!(key.scopes, vec!["read:private", "write:items"]);
+
+        let used = service.authenticate(&secret).unwrap();
+        assert!(used.last_used_at.is_some());
+        assert_eq!(service.list("ann").unwrap()[0].last_used_at, used.last_used_at);
+        let claims = used.claims("test");
+        assert_eq!(claims.sub, "ann");
+        assert!(claims.has_scope("write:items") && !claims.has_scope(ACCOUNT_SCOPE));
+
+        assert!(matches!(service.revoke("bob", &key.id), Err(AppError::NotFound { .. })));
+        service.revoke("ann", &key.id).unwrap();
+        assert!(service.list("ann").unwrap().is_empty());
+        assert!(service.authenticate(&secret).is_err());
+        assert!(service.authenticate("sak_unknown").is_err());
+    }
+
+    #[test]
+    fn test_scopes_are_limited_to_the_caller() {
+        let service = ApiKeyService::new(Arc::new(InMemoryStore::new()));
+        let narrow = vec!["read:private".to_string()];
+        let (key, _) = service.create("ann", "reader", &narrow, GRANTED, None).unwrap();
+        assert_eq!(key.scopes, narrow);
+
+        let admin = vec!["admin:impersonate".to_string()];
+        assert!(matches!(
+            service.create("ann", "admin", &admin, GRANTED, None),
+            Err(AppError::Forbidden { .. })
+        ));
+        let account = vec![ACCOUNT_SCOPE.to_string()];
+        assert!(service.create("ann", "account", &account, GRANTED, None).is_err());
+        assert!(service.create("ann", " ", &[], GRANTED, None).is_err());
+        assert!(service.create("ann", "none", &[], &[ACCOUNT_SCOPE], None).is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let service = ApiKeyService::new(Arc::new(InMemoryStore::new()));
+        let (key, _) = service
+            .create("ann", "temp", &[], GRANTED, Some(Duration::from_secs(3600)))
+            .unwrap();
+        let expires_at = key.expires_at.unwrap();
+        assert!(expires_at > Utc::now() && key.claims("test").exp == expires_at.timestamp());
+        assert!(service.create("ann", "now", &[], GRANTED, Some(Duration::ZERO)).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! HTTP Basic credentials for routes marked with `RouteSpec::require_basic_auth`
+//! or listed in `BASIC_AUTH_ROUTES`; failures carry a `WWW-Authenticate` challenge.
+
+use std::collections::HashMap;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::auth::clients::basic_credentials;
+use crate::config::Config;
+use crate::context::{AuthPrincipal, RequestContext};
+use crate::error::{AppError, AppResult};
+
+/// User authenticated with HTTP Basic credentials
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicAuthUser {
+    pub name: String,
+}
+
+/// Checks `Authorization: Basic` credentials on routes marked with
+/// [`RouteSpec::require_basic_auth`](crate::routes::RouteSpec::require_basic_auth)
+///
+/// Only SHA-256 digests of the passwords are kept, and they are compared in
+/// constant time.
+#[derive(Debug, Clone, Default)]
+pub struct BasicAuthenticator {
+    realm: String,
+    users: HashMap<String, [u8; 32]>,
+}
+
+impl BasicAuthenticator {
+    /// Creates an authenticator without users for `realm`
+    pub fn new(realm: &str) -> Self {
+        Self {
+            realm: realm.to_string(),
+            users: HashMap::new(),
+        }
+    }
+
+    /// Loads the users of `BASIC_AUTH_USERS` and `BASIC_AUTH_FILE`
+    ///
+    /// The file holds one `user:password` per line; blank lines and lines
+    /// starting with `#` are ignored. Users in the file override those set
+    /// in the environment.
+    ///
+    /// # Errors
+    /// Returns a config error when the file cannot be read or has a malformed line
+    pub fn from_config(config: &Config) -> AppResult<Self> {
+        let mut authenticator = Self::new(&config.basic_auth_realm);
+        for (name, password) in &config.basic_auth_users {
+            authenticator.insert(name, password);
+        }
+        if let Some(path) = &config.basic_auth_file {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| AppError::config(format!("Failed to read BASIC_AUTH_FILE {}: {}", path, e)))?;
+            let lines = content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'));
+            for (number, line) in lines.enumerate() {
+                let (name, password) = line.split_once(':').ok_or_else(|| {
+                    AppError::config(format!("BASIC_AUTH_FILE entry {} must look like 'user:password'", number + 1))
+                })?;
+                authenticator.insert(name.trim(), password);
+            }
+        }
+        Ok(authenticator)
+    }
+
+    /// Accepts `password` for `name`, replacing any previous password
+    pub fn insert(&mut self, name: &str, password: &str) {
+        self.users.insert(name.to_string(), Sha256::digest(password.as_bytes()).into());
+    }
+
+    /// Returns whether no users are configured
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Value of the `WWW-Authenticate` header sent with failures
+    pub fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm.replace('"', "'"))
+    }
+
+    /// Returns the user named in valid `Authorization: Basic` credentials
+    ///
+    /// # Errors
+    /// Unauthorized, carrying [`challenge`](Self::challenge), for missing or
+    /// invalid credentials
+    pub fn authenticate(&self, headers: &HeaderMap) -> AppResult<BasicAuthUser> {
+        let (name, password) = basic_credentials(headers)
+            .ok_or_else(|| AppError::unauthorized_with_challenge("basic authentication required", self.challenge()))?;
+        let digest: [u8; 32] = Sha256::digest(password.as_bytes()).into();
+        let valid = self
+            .users
+            .get(&name)
+            .is_some_and(|expected| bool::from(expected.ct_eq(&digest)));
+        if !valid {
+            return Err(AppError::unauthorized_with_challenge("invalid credentials", self.challenge()));
+        }
+        Ok(BasicAuthUser { name })
+    }
+}
+
+/// Middleware requiring valid Basic credentials, for use with `from_fn`
+///
+/// On success the user is recorded as the [`RequestContext`] principal.
+///
+/// # Errors
+/// Unauthorized with a `WWW-Authenticate` header for missing or invalid
+/// credentials, internal error when no `web::Data<BasicAuthenticator>` is
+/// registered
+pub async fn require_basic_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let authenticator = req
+        .app_data::<web::Data<BasicAuthenticator>>()
+        .ok_or_else(|| AppError::internal("basic authentication not configured"))?;
+    let span = RequestContext::span(&req, "auth");
+    let user = authenticator.authenticate(req.headers())?;
+    RequestContext::set_principal(&req, AuthPrincipal::Basic { name: user.name }, None);
+    drop(span);
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    fn basic(user: &str, password: &str) -> (actix_web::http::header::HeaderName, String) {
+        (AUTHORIZATION, format!("Basic {}", STANDARD.encode(format!("{}:{}", user, password))))
+    }
+
+    #[test]
+    fn test_from_config_reads_users_and_file() {
+        let path = std::env::temp_dir().join(format!("basic-auth-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "# operators\nops:file,pass:word\n\nadmin:from-file\n").unwrap();
+        let config = Config {
+            basic_auth_users: vec![("admin".to_string(), "from-env".to_string())],
+            basic_auth_file: Some(path.display().to_string()),
+            ..Config::default()
+        };
+        let authenticator = BasicAuthenticator::from_config(&config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let headers = |user: &str, password: &str| {
+            let mut headers = HeaderMap::new();
+            let (name, value) = basic(user, password);
+            headers.insert(name, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(authenticator.authenticate(&headers("ops", "file,pass:word")).unwrap().name, "ops");
+        assert!(authenticator.authenticate(&headers("admin", "from-file")).is_ok());
+        assert!(authenticator.authenticate(&headers("admin", "from-env")).is_err());
+
+        let missing = Config {
+            basic_auth_file: Some("/nonexistent/basic-auth".to_string()),
+            ..Config::default()
+        };
+        assert!(matches!(BasicAuthenticator::from_config(&missing), Err(AppError::Config { .. })));
+    }
+
+    #[actix_web::test]
+    async fn test_require_basic_auth() {
+        let mut authenticator = BasicAuthenticator::new("ops");
+        authenticator.insert("admin", "s3cret");
+        let app = init_service(App::new().app_data(web::Data::new(authenticator)).route(
+            "/internal",
+            web::get()
+                .to(|context: RequestContext| async move {
+                    HttpResponse::Ok().body(context.principal.unwrap().subject().to_string())
+                })
+                .wrap(from_fn(require_basic_auth)),
+        ))
+        .await;
+
+        let res = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/internal")
+                .insert_header(basic("admin", "s3cret"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(read_body(res).await, "admin");
+
+        for request in [
+            TestRequest::get().uri("/internal"),
+            TestRequest::get().uri("/internal").insert_header(basic("admin", "wrong")),
+            TestRequest::get().uri("/internal").insert_header(basic("nobody", "s3cret")),
+        ] {
+            let err = actix_web::test::try_call_service(&app, request.to_request()).await.err().unwrap();
+            let res = err.error_response();
+            assert_eq!(res.status(), 401);
+            assert_eq!(res.headers().get(WWW_AUTHENTICATE).unwrap(), "Basic realm=\"ops\", charset=\"UTF-8\"");
+        }
+    }
+}
@@ -0,0 +1,400 @@
+//! CAPTCHA challenges demanded after repeated failures. The gate is enabled
+//! by the hCaptcha/Turnstile settings or by a custom [`ChallengeProvider`]
+//! injected with `ServerManagerBuilder::challenge_provider`.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::header::HeaderMap;
+use futures::future::BoxFuture;
+use ipnet::IpNet;
+use log::warn;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::state::KeyValueStore;
+
+/// Header carrying the client's challenge (CAPTCHA) response token
+pub const CHALLENGE_HEADER: &str = "X-Challenge-Response";
+
+/// Hosted challenge services with a siteverify-compatible API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeService {
+    HCaptcha,
+    Turnstile,
+}
+
+impl ChallengeService {
+    /// Default verification endpoint of the service
+    pub fn verify_url(self) -> &'static str {
+        match self {
+            ChallengeService::HCaptcha => "https://api.hcaptcha.com/siteverify",
+            ChallengeService::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+        }
+    }
+}
+
+impl FromStr for ChallengeService {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "hcaptcha" => Ok(ChallengeService::HCaptcha),
+            "turnstile" => Ok(ChallengeService::Turnstile),
+            other => Err(AppError::environment(
+                "CHALLENGE_PROVIDER",
+                format!("must be hcaptcha or turnstile, got: {}", other),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ChallengeService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChallengeService::HCaptcha => write!(f, "hcaptcha"),
+            ChallengeService::Turnstile => write!(f, "turnstile"),
+        }
+    }
+}
+
+/// Verifies a challenge response submitted by a client
+///
+/// This is the extension point for pluggable challenge providers: implement
+/// it for any service (or an in-house puzzle) and inject it with
+/// `ServerManagerBuilder::challenge_provider`. [`ChallengeService`] only
+/// selects which hosted service the built-in [`HttpChallengeProvider`] calls.
+pub trait ChallengeProvider: Send + Sync {
+    /// Returns whether `response` is a valid, unused solution for `remote_ip`
+    fn verify<'a>(&'a self, response: &'a str, remote_ip: IpAddr) -> BoxFuture<'a, AppResult<bool>>;
+}
+
+impl<T: ChallengeProvider + ?Sized> ChallengeProvider for Arc<T> {
+    fn verify<'a>(&'a self, response: &'a str, remote_ip: IpAddr) -> BoxFuture<'a, AppResult<bool>> {
+        (**self).verify(response, remote_ip)
+    }
+}
+
+/// Provider calling an hCaptcha/Turnstile style `siteverify` endpoint
+///
+/// Both services accept a form with `secret`, `response` and `remoteip` and
+/// answer `{"success": bool, ...}`.
+pub struct HttpChallengeProvider {
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+}
+
+impl HttpChallengeProvider {
+    /// Creates a provider posting to `url` with the site `secret`
+    pub fn new<U: Into<String>, S: Into<String>>(client: reqwest::Client, url: U, secret: S) -> Self {
+        Self {
+            client,
+            url: url.into(),
+            secret: secret.into(),
+        }
+    }
+
+    /// Creates a provider for a hosted service's default endpoint
+    pub fn for_service<S: Into<String>>(client: reqwest::Client, service: ChallengeService, secret: S) -> Self {
+        Self::new(client, service.verify_url(), secret)
+    }
+}
+
+impl ChallengeProvider for HttpChallengeProvider {
+    fn verify<'a>(&'a self, response: &'a str, remote_ip: IpAddr) -> BoxFuture<'a, AppResult<bool>> {
+        Box::pin(async move {
+            let remote_ip = remote_ip.to_string();
+            let result = self
+                .client
+                .post(&self.url)
+                .form(&[
+                    ("secret", self.secret.as_str()),
+                    ("response", response),
+                    ("remoteip", remote_ip.as_str()),
+                ])
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| AppError::unavailable(format!("challenge verification failed: {}", e)))?
+                .json::<SiteVerifyResponse>()
+                .await
+                .map_err(|e| AppError::unavailable(format!("invalid challenge verification response: {}", e)))?;
+            Ok(result.success)
+        })
+    }
+}
+
+/// Provider accepting one fixed response, for tests and local development
+pub struct FixedChallengeProvider {
+    expected: String,
+}
+
+impl FixedChallengeProvider {
+    /// Creates a provider accepting only `expected`
+    pub fn new<T: Into<String>>(expected: T) -> Self {
+        Self {
+            expected: expected.into(),
+        }
+    }
+}
+
+impl ChallengeProvider for FixedChallengeProvider {
+    fn verify<'a>(&'a self, response: &'a str, _remote_ip: IpAddr) -> BoxFuture<'a, AppResult<bool>> {
+        let valid = bool::from(self.expected.as_bytes().ct_eq(response.as_bytes()));
+        Box::pin(async move { Ok(valid) })
+    }
+}
+
+/// Demands a solved challenge from addresses with too many recent failures
+///
+/// Endpoints report failures (bad credentials, exhausted limits) with
+/// [`ChallengeGate::record_failure`] and call [`ChallengeGate::check`] before
+/// doing any work. Once an address reaches the threshold within the window it
+/// must send a valid response in [`CHALLENGE_HEADER`]; solving one resets its
+/// count. Trusted networks are never challenged, and without a provider the
+/// gate is a no-op.
+pub struct ChallengeGate {
+    provider: Option<Arc<dyn ChallengeProvider>>,
+    after_failures: u64,
+    window: Duration,
+    trusted_networks: Vec<IpNet>,
+    failures: Arc<dyn KeyValueStore>,
+}
+
+impl ChallengeGate {
+    /// Creates a gate without a provider
+    ///
+    /// # Arguments
+    /// * `after_failures` - Failures within `window` before a challenge is required
+    /// * `window` - Period failures are counted over
+    /// * `failures` - Store holding the per-address failure counters
+    pub fn new(after_failures: u64, window: Duration, failures: Arc<dyn KeyValueStore>) -> Self {
+        Self {
+            provider: None,
+            after_failures,
+            window,
+            trusted_networks: Vec::new(),
+            failures,
+        }
+    }
+
+    /// Builds the gate from the `CHALLENGE_*` settings
+    pub fn from_config(config: &Config, failures: Arc<dyn KeyValueStore>) -> AppResult<Self> {
+        let gate = Self::new(
+            config.challenge_after_failures,
+            Duration::from_secs(config.challenge_failure_window_secs),
+            failures,
+        )
+        .with_trusted_networks(config.challenge_trusted_networks.clone());
+
+        let (Some(service), Some(secret)) = (config.challenge_provider, &config.challenge_secret) else {
+            return Ok(gate);
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .map_err(|e| AppError::config(format!("Failed to build HTTP client: {}", e)))?;
+        let provider = match &config.challenge_verify_url {
+            Some(url) => HttpChallengeProvider::new(client, url, secret),
+            None => HttpChallengeProvider::for_service(client, service, secret),
+        };
+        Ok(gate.with_provider(provider))
+    }
+
+    /// Sets the provider, enabling the gate
+    pub fn with_provider(mut self, provider: impl ChallengeProvider + 'static) -> Self {
+        self.provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Exempts addresses in `networks` from challenges
+    pub fn with_trusted_networks(mut self, networks: Vec<IpNet>) -> Self {
+        self.trusted_networks = networks;
+        self
+    }
+
+    /// Returns whether `ip` belongs to a trusted network
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Counts a failed attempt from `ip`
+    pub fn record_failure(&self, ip: IpAddr) {
+        if self.provider.is_none() || self.is_trusted(ip) {
+            return;
+        }
+        if let Err(e) = self.failures.increment(&ip.to_string(), Some(self.window)) {
+            warn!("Challenge failure counter unavailable: {}", e);
+        }
+    }
+
+    /// Returns whether requests from `ip` currently need a solved challenge
+    pub fn requires_challenge(&self, ip: IpAddr) -> bool {
+        if self.provider.is_none() || self.is_trusted(ip) {
+            return false;
+        }
+        match self.failures.get(&ip.to_string()) {
+            Ok(count) => count
+                .and_then(|count| count.parse::<u64>().ok())
+                .is_some_and(|count| count >= self.after_failures),
+            Err(e) => {
+                warn!("Challenge failure counter unavailable: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Lets the request through unless a challenge is required and not solved
+    ///
+    /// # Errors
+    /// Returns a challenge-required error when the response header is missing
+    /// or rejected by the provider.
+    pub async fn check(&self, ip: IpAddr, headers: &HeaderMap) -> AppResult<()> {
+        let Some(provider) = &self.provider else {
+            return Ok(());
+        };
+        if !self.requires_challenge(ip) {
+            return Ok(());
+        }
+
+        let response = headers
+            .get(CHALLENGE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| AppError::challenge_required("too many failed attempts; solve the challenge"))?;
+        if !provider.verify(response, ip).await? {
+            return Err(AppError::challenge_required("challenge response rejected"));
+        }
+
+        if let Err(e) = self.failures.delete(&ip.to_string()) {
+            warn!("Challenge failure counter unavailable: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Parses CIDR blocks or bare addresses (`10.0.0.0/8,127.0.0.1`)
+///
+/// # Errors
+/// Returns an environment error naming `var_name` for invalid entries
+pub fn parse_networks(var_name: &str, entries: &[String]) -> AppResult<Vec<IpNet>> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| AppError::environment(var_name, format!("invalid network: {}", entry)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+    use actix_web::http::header::HeaderValue;
+
+    fn gate() -> ChallengeGate {
+        ChallengeGate::new(2, Duration::from_secs(60), Arc::new(InMemoryStore::new()))
+            .with_provider(FixedChallengeProvider::new("solved"))
+            .with_trusted_networks(parse_networks("TEST", &["10.0.0.0/8".to_string()]).unwrap())
+    }
+
+    fn with_response(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::HeaderName::from_static("x-challenge-response"),
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[actix_web::test]
+    async fn test_challenge_after_threshold() {
+        let gate = gate();
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+
+        gate.record_failure(ip);
+        assert!(gate.check(ip, &HeaderMap::new()).await.is_ok());
+        gate.record_failure(ip);
+        assert!(gate.requires_challenge(ip));
+
+        let err = gate.check(ip, &HeaderMap::new()).await.unwrap_err();
+        assert!(matches!(err, AppError::ChallengeRequired { .. }));
+        assert!(gate.check(ip, &with_response("wrong")).await.is_err());
+
+        // Solving the challenge resets the count
+        assert!(gate.check(ip, &with_response("solved")).await.is_ok());
+        assert!(!gate.requires_challenge(ip));
+    }
+
+    #[actix_web::test]
+    async fn test_trusted_networks_bypass() {
+        let gate = gate();
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        for _ in 0..5 {
+            gate.record_failure(ip);
+        }
+        assert!(!gate.requires_challenge(ip));
+        assert!(gate.check(ip, &HeaderMap::new()).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_without_provider_is_noop() {
+        let gate = ChallengeGate::new(1, Duration::from_secs(60), Arc::new(InMemoryStore::new()));
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+        gate.record_failure(ip);
+        assert!(gate.check(ip, &HeaderMap::new()).await.is_ok());
+    }
+
+    /// Accepts any response once, remembering who asked
+    struct OneShotProvider {
+        asked: std::sync::Mutex<Vec<IpAddr>>,
+    }
+
+    impl ChallengeProvider for OneShotProvider {
+        fn verify<'a>(&'a self, _response: &'a str, remote_ip: IpAddr) -> BoxFuture<'a, AppResult<bool>> {
+            let mut asked = self.asked.lock().unwrap();
+            asked.push(remote_ip);
+            let valid = asked.len() == 1;
+            Box::pin(async move { Ok(valid) })
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_shared_custom_provider() {
+        let provider = Arc::new(OneShotProvider {
+            asked: std::sync::Mutex::new(Vec::new()),
+        });
+        let gate = ChallengeGate::new(1, Duration::from_secs(60), Arc::new(InMemoryStore::new()))
+            .with_provider(provider.clone() as Arc<dyn ChallengeProvider>);
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+
+        gate.record_failure(ip);
+        assert!(gate.check(ip, &with_response("any")).await.is_ok());
+        gate.record_failure(ip);
+        assert!(gate.check(ip, &with_response("any")).await.is_err());
+        assert_eq!(*provider.asked.lock().unwrap(), vec![ip, ip]);
+    }
+
+    #[test]
+    fn test_parse_networks() {
+        let networks = parse_networks("TEST", &["192.168.0.0/16".to_string(), "::1".to_string()]).unwrap();
+        assert!(networks[0].contains(&"192.168.4.5".parse::<IpAddr>().unwrap()));
+        assert!(networks[1].contains(&"::1".parse::<IpAddr>().unwrap()));
+        assert!(parse_networks("TEST", &["nope".to_string()]).is_err());
+        assert_eq!("Turnstile".parse::<ChallengeService>().unwrap(), ChallengeService::Turnstile);
+    }
+}
@@ -0,0 +1,124 @@
+//! OAuth client credentials and the `Authorization` header parsers.
+
+use std::collections::HashMap;
+
+use actix_web::http::header::HeaderMap;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use subtle::ConstantTimeEq;
+
+use crate::error::{AppError, AppResult};
+
+/// Registered OAuth clients allowed to call service-to-service endpoints
+#[derive(Debug, Clone, Default)]
+pub struct ClientRegistry {
+    clients: HashMap<String, String>,
+}
+
+impl ClientRegistry {
+    /// Creates a registry from `(client_id, client_secret)` pairs
+    pub fn new(clients: impl IntoIterator<Item = (String, String)>) -> Self {
+        Self {
+            clients: clients.into_iter().collect(),
+        }
+    }
+
+    /// Parses `id:secret,id:secret` as used by the `*_CLIENTS` settings
+    ///
+    /// # Errors
+    /// Returns a configuration error for entries without a `:`
+    pub fn parse(spec: &str) -> AppResult<Vec<(String, String)>> {
+        spec.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .split_once(':')
+                    .map(|(id, secret)| (id.trim().to_string(), secret.trim().to_string()))
+                    .ok_or_else(|| AppError::config(format!("client entry '{}' must look like 'id:secret'", entry)))
+            })
+            .collect()
+    }
+
+    /// Returns whether no clients are registered
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Checks a client id and secret in constant time
+    pub fn verify(&self, client_id: &str, client_secret: &str) -> bool {
+        self.clients
+            .get(client_id)
+            .is_some_and(|expected| bool::from(expected.as_bytes().ct_eq(client_secret.as_bytes())))
+    }
+
+    /// Authenticates the client from an `Authorization: Basic` header
+    ///
+    /// # Errors
+    /// Returns an unauthorized error for missing or unknown credentials
+    pub fn authenticate(&self, headers: &HeaderMap) -> AppResult<String> {
+        let (client_id, client_secret) = basic_credentials(headers)
+            .ok_or_else(|| AppError::unauthorized("client authentication required"))?;
+        if self.verify(&client_id, &client_secret) {
+            Ok(client_id)
+        } else {
+            Err(AppError::unauthorized("invalid client credentials"))
+        }
+    }
+}
+
+/// Extracts `user:password` from an `Authorization: Basic` header
+pub fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(actix_web::http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    decoded
+        .split_once(':')
+        .map(|(user, password)| (user.to_string(), password.to_string()))
+}
+
+/// Extracts the token from an `Authorization: Bearer` header
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(actix_web::http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderValue, AUTHORIZATION};
+
+    fn with_authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_parse_clients() {
+        let clients = ClientRegistry::parse("svc-a:secret-a, svc-b:secret-b").unwrap();
+        assert_eq!(clients[1], ("svc-b".to_string(), "secret-b".to_string()));
+        assert!(ClientRegistry::parse("no-secret").is_err());
+    }
+
+    #[test]
+    fn test_authenticate_with_basic_header() {
+        let registry = ClientRegistry::new(vec![("svc".to_string(), "s3cret".to_string())]);
+        let header = format!("Basic {}", STANDARD.encode("svc:s3cret"));
+        assert_eq!(registry.authenticate(&with_authorization(&header)).unwrap(), "svc");
+
+        let wrong = format!("Basic {}", STANDARD.encode("svc:nope"));
+        assert!(registry.authenticate(&with_authorization(&wrong)).is_err());
+        assert!(registry.authenticate(&HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_bearer_token_extraction() {
+        assert_eq!(bearer_token(&with_authorization("Bearer abc.def")), Some("abc.def"));
+        assert_eq!(bearer_token(&with_authorization("Basic abc")), None);
+    }
+}
@@ -0,0 +1,286 @@
+//! Browser sessions in AES-256-GCM encrypted cookies, kept behind the
+//! [`SessionStore`] trait ([`InMemorySessionStore`] by default).
+
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::crypto::Cipher;
+use crate::error::{AppError, AppResult};
+
+/// Browser session established by `POST /auth/session/login`
+///
+/// Extracting it in a handler requires a valid session cookie.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CookieSession {
+    /// Session identifier, only ever sent to the browser encrypted
+    #[serde(skip_serializing)]
+    pub id: String,
+    pub user_id: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl CookieSession {
+    /// Returns whether the session has expired
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+/// Storage of cookie sessions
+///
+/// The in-memory default is enough for a single instance; shared
+/// deployments plug in a store reachable from every replica.
+pub trait SessionStore: Send + Sync {
+    /// Returns the session stored under `id`, if any
+    fn load(&self, id: &str) -> AppResult<Option<CookieSession>>;
+    /// Stores `session`, replacing any session with the same id
+    fn save(&self, session: &CookieSession) -> AppResult<()>;
+    /// Forgets the session stored under `id`
+    fn remove(&self, id: &str) -> AppResult<()>;
+}
+
+/// [`SessionStore`] keeping sessions in process memory
+#[derive(Default)]
+pub struct InMemorySessionStore {
+    sessions: RwLock<HashMap<String, CookieSession>>,
+}
+
+impl InMemorySessionStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for InMemorySessionStore {
+    fn load(&self, id: &str) -> AppResult<Option<CookieSession>> {
+        let sessions = self.sessions.read().map_err(|_| poisoned())?;
+        Ok(sessions.get(id).cloned())
+    }
+
+    fn save(&self, session: &CookieSession) -> AppResult<()> {
+        let mut sessions = self.sessions.write().map_err(|_| poisoned())?;
+        // Saving is rare compared to loading, so expired sessions are swept here
+        sessions.retain(|_, stored| !stored.is_expired());
+        sessions.insert(session.id.clone(), session.clone());
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> AppResult<()> {
+        self.sessions.write().map_err(|_| poisoned())?.remove(id);
+        Ok(())
+    }
+}
+
+fn poisoned() -> AppError {
+    AppError::internal("session store lock poisoned")
+}
+
+/// Issues and resolves session cookies
+///
+/// The cookie holds the session id encrypted and authenticated with
+/// AES-256-GCM under `SESSION_COOKIE_SECRET`, so it can be neither read nor
+/// forged; the session itself lives in the [`SessionStore`]. Cookies are
+/// `HttpOnly`, `SameSite=Lax` and `Secure` unless `COOKIE_SECURE=false`.
+pub struct CookieSessionManager {
+    store: Arc<dyn SessionStore>,
+    cipher: Cipher,
+    name: String,
+    ttl: Duration,
+    secure: bool,
+}
+
+impl CookieSessionManager {
+    /// Creates a manager
+    ///
+    /// # Arguments
+    /// * `store` - Where sessions are kept
+    /// * `secret` - Key material for the cookie encryption
+    /// * `name` - Cookie name
+    /// * `ttl` - Session lifetime
+    pub fn new(store: Arc<dyn SessionStore>, secret: &[u8], name: &str, ttl: Duration) -> Self {
+        Self {
+            store,
+            cipher: Cipher::new(secret),
+            name: name.to_string(),
+            ttl,
+            secure: true,
+        }
+    }
+
+    /// Builds the manager from the `SESSION_COOKIE_*` settings with an in-memory store
+    ///
+    /// Without `SESSION_COOKIE_SECRET` an ephemeral key is generated, which
+    /// logs every browser out on restart; a warning says so.
+    pub fn from_config(config: &Config) -> Self {
+        let secret = match &config.session_cookie_secret {
+            Some(secret) => secret.clone(),
+            None => {
+                warn!("SESSION_COOKIE_SECRET is not set; using an ephemeral key (sessions won't survive restarts)");
+                crate::secrets::generate_secret()
+            }
+        };
+        Self::new(
+            Arc::new(InMemorySessionStore::new()),
+            secret.as_bytes(),
+            &config.session_cookie_name,
+            Duration::from_secs(config.session_cookie_ttl_secs),
+        )
+        .with_secure(config.cookie_secure)
+    }
+
+    /// Sets whether cookies carry the `Secure` attribute
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Replaces the session store
+    pub fn with_store(mut self, store: Arc<dyn SessionStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Starts a session for `user_id` and returns it with the cookie to set
+    pub fn create(&self, user_id: &str) -> AppResult<(CookieSession, Cookie<'static>)> {
+        let now = Utc::now();
+        let ttl = chrono::Duration::from_std(self.ttl).map_err(|e| AppError::internal(e.to_string()))?;
+        let session = CookieSession {
+            id: uuid::Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            created_at: now,
+            expires_at: now + ttl,
+        };
+        self.store.save(&session)?;
+        let value = self.cipher.encrypt(session.id.as_bytes())?;
+        let cookie = self
+            .cookie(value)
+            .max_age(time::Duration::seconds(self.ttl.as_secs() as i64))
+            .finish();
+        Ok((session, cookie))
+    }
+
+    /// Returns the live session named by the request's cookie, if any
+    ///
+    /// Missing, undecryptable, unknown and expired cookies all yield `None`.
+    pub fn resolve(&self, req: &HttpRequest) -> AppResult<Option<CookieSession>> {
+        let Some(cookie) = req.cookie(&self.name) else {
+            return Ok(None);
+        };
+        let Some(id) = self
+            .cipher
+            .decrypt(cookie.value())
+            .ok()
+            .and_then(|id| String::from_utf8(id).ok())
+        else {
+            return Ok(None);
+        };
+        Ok(self.store.load(&id)?.filter(|session| !session.is_expired()))
+    }
+
+    /// Ends the request's session, if any, and returns the cookie clearing it
+    pub fn destroy(&self, req: &HttpRequest) -> AppResult<Cookie<'static>> {
+        if let Some(session) = self.resolve(req)? {
+            self.store.remove(&session.id)?;
+        }
+        let mut cookie = self.cookie(String::new()).finish();
+        cookie.make_removal();
+        Ok(cookie)
+    }
+
+    fn cookie(&self, value: String) -> actix_web::cookie::CookieBuilder<'static> {
+        Cookie::build(self.name.clone(), value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+    }
+}
+
+impl FromRequest for CookieSession {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let session = req
+            .app_data::<web::Data<CookieSessionManager>>()
+            .ok_or_else(|| AppError::internal("cookie sessions not configured"))
+            .and_then(|manager| manager.resolve(req))
+            .and_then(|session| session.ok_or_else(|| AppError::unauthorized("session cookie required")));
+        ready(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn manager(ttl: Duration) -> CookieSessionManager {
+        CookieSessionManager::new(
+            Arc::new(InMemorySessionStore::new()),
+            b"cookie-test-key-cookie-test-key-",
+            "session",
+            ttl,
+        )
+    }
+
+    #[test]
+    fn test_cookie_is_encrypted_and_resolves() {
+        let manager = manager(Duration::from_secs(60));
+        let (session, cookie) = manager.create("u1").unwrap();
+        assert_ne!(cookie.value(), session.id);
+        assert!(!cookie.value().contains(&session.id));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+
+        let req = TestRequest::default().cookie(cookie.clone()).to_http_request();
+        assert_eq!(manager.resolve(&req).unwrap(), Some(session));
+
+        // A cookie encrypted under another secret is ignored
+        let (_, foreign) = CookieSessionManager::new(
+            Arc::new(InMemorySessionStore::new()),
+            b"another-key-another-key-another-",
+            "session",
+            Duration::from_secs(60),
+        )
+        .create("u1")
+        .unwrap();
+        let req = TestRequest::default().cookie(foreign).to_http_request();
+        assert_eq!(manager.resolve(&req).unwrap(), None);
+
+        let req = TestRequest::default().cookie(cookie.clone()).to_http_request();
+        let removal = manager.destroy(&req).unwrap();
+        assert_eq!(removal.value(), "");
+        let req = TestRequest::default().cookie(cookie).to_http_request();
+        assert_eq!(manager.resolve(&req).unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_expired_sessions_are_rejected() {
+        let manager = web::Data::new(manager(Duration::ZERO));
+        let (_, cookie) = manager.create("u1").unwrap();
+        let app = init_service(App::new().app_data(manager).route(
+            "/whoami",
+            web::get().to(|session: CookieSession| async move { HttpResponse::Ok().body(session.user_id) }),
+        ))
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/whoami").cookie(cookie).to_request()).await;
+        assert_eq!(res.status(), 401);
+        let res = call_service(&app, TestRequest::get().uri("/whoami").to_request()).await;
+        assert_eq!(res.status(), 401);
+    }
+}
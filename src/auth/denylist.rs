@@ -0,0 +1,89 @@
+//! Revoked token ids and sessions, shared by replicas through the state store.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::tokens::Claims;
+use crate::error::AppResult;
+use crate::state::KeyValueStore;
+
+/// Revoked token ids (`jti`) and session ids (`sid`)
+///
+/// Entries expire with the longest-lived token they can affect, so the list
+/// stays small. Backed by the shared state store, revocations apply on every
+/// replica when state is distributed.
+#[derive(Clone)]
+pub struct TokenDenylist {
+    store: Arc<dyn KeyValueStore>,
+}
+
+impl TokenDenylist {
+    /// Creates a denylist on `store`
+    pub fn new(store: Arc<dyn KeyValueStore>) -> Self {
+        Self { store }
+    }
+
+    /// Revokes a single token by its `jti`
+    pub fn revoke_token(&self, jti: &str, ttl: Duration) -> AppResult<()> {
+        self.store.set(&format!("jti:{}", jti), "revoked", Some(ttl))
+    }
+
+    /// Revokes a single token by its `jti` unless it already was, in one
+    /// atomic step; returns whether this call revoked it
+    ///
+    /// Single-use tokens are claimed this way, so two concurrent redemptions
+    /// cannot both see the token as unused.
+    pub fn claim_token(&self, jti: &str, ttl: Duration) -> AppResult<bool> {
+        self.store.set_if_absent(&format!("jti:{}", jti), "revoked", Some(ttl))
+    }
+
+    /// Revokes every token issued for a session
+    pub fn revoke_session(&self, sid: &str, ttl: Duration) -> AppResult<()> {
+        self.store.set(&format!("sid:{}", sid), "revoked", Some(ttl))
+    }
+
+    /// Returns whether the token or its session has been revoked
+    pub fn is_revoked(&self, claims: &Claims) -> AppResult<bool> {
+        if self.is_token_revoked(&claims.jti)? {
+            return Ok(true);
+        }
+        match &claims.sid {
+            Some(sid) => self.is_session_revoked(sid),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns whether the token with this `jti` has been revoked
+    pub fn is_token_revoked(&self, jti: &str) -> AppResult<bool> {
+        Ok(self.store.get(&format!("jti:{}", jti))?.is_some())
+    }
+
+    /// Returns whether the session has been revoked
+    pub fn is_session_revoked(&self, sid: &str) -> AppResult<bool> {
+        Ok(self.store.get(&format!("sid:{}", sid))?.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenService;
+    use crate::state::InMemoryStore;
+
+    #[test]
+    fn test_revoke_by_token_and_session() {
+        let denylist = TokenDenylist::new(Arc::new(InMemoryStore::new()));
+        let tokens = TokenService::new(b"deny-test-key-deny-test-key-0000", "test");
+        let mut claims = tokens.claims("ann", &[], Duration::from_secs(60));
+        claims.sid = Some("session-1".to_string());
+        assert!(!denylist.is_revoked(&claims).unwrap());
+
+        denylist.revoke_session("session-1", Duration::from_secs(60)).unwrap();
+        assert!(denylist.is_revoked(&claims).unwrap());
+
+        let other = tokens.claims("ann", &[], Duration::from_secs(60));
+        assert!(!denylist.is_revoked(&other).unwrap());
+        denylist.revoke_token(&other.jti, Duration::from_secs(60)).unwrap();
+        assert!(denylist.is_revoked(&other).unwrap());
+    }
+}
@@ -0,0 +1,181 @@
+//! Short-lived anonymous guest tokens, rate-limited per client address.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use serde::Serialize;
+
+use super::tokens::TokenService;
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::reload::LiveConfig;
+use crate::state::KeyValueStore;
+
+/// Claim marking a token as anonymous
+pub const GUEST_CLAIM: &str = "guest";
+
+const WINDOW_SECS: u64 = 3600;
+
+/// Issued guest token, serialized as an OAuth-style token response
+#[derive(Debug, Clone, Serialize)]
+pub struct GuestToken {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: u64,
+    pub scope: String,
+}
+
+/// Issues short-lived anonymous tokens with a restricted scope set
+///
+/// Issuance is limited per client IP in fixed one-hour windows so a demo
+/// frontend can obtain tokens freely while scripted abuse is capped.
+pub struct GuestTokenIssuer {
+    scopes: Vec<String>,
+    ttl: Duration,
+    per_hour: u64,
+    counters: Arc<dyn KeyValueStore>,
+    live: Option<Arc<LiveConfig>>,
+}
+
+impl GuestTokenIssuer {
+    /// Creates an issuer
+    ///
+    /// # Arguments
+    /// * `scopes` - Scopes every guest token carries
+    /// * `ttl` - Token lifetime
+    /// * `per_hour` - Tokens per IP per hour; 0 disables issuance
+    /// * `counters` - Store holding the per-IP counters
+    pub fn new(scopes: Vec<String>, ttl: Duration, per_hour: u64, counters: Arc<dyn KeyValueStore>) -> Self {
+        Self {
+            scopes,
+            ttl,
+            per_hour,
+            counters,
+            live: None,
+        }
+    }
+
+    /// Builds the issuer from the `GUEST_*` settings
+    pub fn from_config(config: &Config, counters: Arc<dyn KeyValueStore>) -> Self {
+        Self::new(
+            config.guest_scopes.clone(),
+            Duration::from_secs(config.guest_token_ttl_secs),
+            config.guest_tokens_per_hour,
+            counters,
+        )
+    }
+
+    /// Reads the hourly allowance from `live`, so reloads of
+    /// `GUEST_TOKENS_PER_HOUR` apply to the next request
+    pub fn with_live_config(mut self, live: Arc<LiveConfig>) -> Self {
+        self.live = Some(live);
+        self
+    }
+
+    fn per_hour(&self) -> u64 {
+        self.live
+            .as_ref()
+            .map_or(self.per_hour, |live| live.load().guest_tokens_per_hour)
+    }
+
+    /// Returns the scopes granted to guests
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+
+    /// Issues a guest token for a caller at `ip`
+    ///
+    /// # Errors
+    /// Not found when guest tokens are disabled, rate limited when `ip` has
+    /// used up its hourly allowance.
+    pub fn issue(&self, tokens: &TokenService, ip: IpAddr) -> AppResult<GuestToken> {
+        if self.per_hour() == 0 {
+            return Err(AppError::not_found("guest tokens are disabled"));
+        }
+        self.acquire(ip)?;
+
+        let scopes: Vec<&str> = self.scopes.iter().map(String::as_str).collect();
+        let mut claims = tokens.claims(&format!("guest:{}", uuid::Uuid::new_v4()), &scopes, self.ttl);
+        claims.extra.insert(GUEST_CLAIM.to_string(), serde_json::Value::Bool(true));
+
+        Ok(GuestToken {
+            access_token: tokens.sign(&claims)?,
+            token_type: "Bearer",
+            expires_in: self.ttl.as_secs(),
+            scope: self.scopes.join(" "),
+        })
+    }
+
+    /// Takes one slot from the IP's current window
+    fn acquire(&self, ip: IpAddr) -> AppResult<()> {
+        let now = chrono::Utc::now().timestamp() as u64;
+        let window = now / WINDOW_SECS;
+        let count = match self
+            .counters
+            .increment(&format!("{}:{}", ip, window), Some(Duration::from_secs(WINDOW_SECS)))
+        {
+            Ok(count) => count,
+            Err(e) => {
+                // Fail closed: anonymous issuance is not worth an open door
+                warn!("Guest token rate limiter unavailable: {}", e);
+                return Err(AppError::unavailable("guest token issuance temporarily unavailable"));
+            }
+        };
+
+        if count > self.per_hour() {
+            let retry_after = WINDOW_SECS - now % WINDOW_SECS;
+            return Err(AppError::rate_limited("guest token limit reached for this address", retry_after));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    fn issuer(per_hour: u64) -> GuestTokenIssuer {
+        GuestTokenIssuer::new(
+            vec!["read:guest".to_string()],
+            Duration::from_secs(60),
+            per_hour,
+            Arc::new(InMemoryStore::new()),
+        )
+    }
+
+    #[test]
+    fn test_guest_token_carries_restricted_scopes() {
+        let tokens = TokenService::new(b"guest-test-key-guest-test-key-00", "test");
+        let token = issuer(5).issue(&tokens, "127.0.0.1".parse().unwrap()).unwrap();
+
+        let claims = tokens.verify(&token.access_token).unwrap();
+        assert!(claims.sub.starts_with("guest:"));
+        assert!(claims.has_scope("read:guest"));
+        assert!(!claims.has_scope("read:private"));
+        assert_eq!(claims.extra[GUEST_CLAIM], serde_json::Value::Bool(true));
+        assert_eq!(token.expires_in, 60);
+    }
+
+    #[test]
+    fn test_rate_limited_per_ip() {
+        let tokens = TokenService::new(b"guest-test-key-guest-test-key-00", "test");
+        let issuer = issuer(2);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert!(issuer.issue(&tokens, a).is_ok());
+        assert!(issuer.issue(&tokens, a).is_ok());
+        assert!(matches!(issuer.issue(&tokens, a), Err(AppError::RateLimited { .. })));
+        assert!(issuer.issue(&tokens, b).is_ok());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let tokens = TokenService::new(b"guest-test-key-guest-test-key-00", "test");
+        let result = issuer(0).issue(&tokens, "127.0.0.1".parse().unwrap());
+        assert!(matches!(result, Err(AppError::NotFound { .. })));
+    }
+}
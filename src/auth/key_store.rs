@@ -0,0 +1,181 @@
+//! Service keys checked in `X-Api-Key` on routes marked with
+//! `RouteSpec::require_api_key`, seeded from `STATIC_API_KEYS` and replaceable
+//! with `ServerManagerBuilder::key_store`.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::context::{AuthPrincipal, RequestContext};
+use crate::error::{AppError, AppResult};
+
+/// Header carrying the key on key-protected routes
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Client identified by its `X-Api-Key`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyClient {
+    /// Name the key was registered under
+    pub name: String,
+}
+
+/// Source of the keys accepted in [`API_KEY_HEADER`]
+///
+/// Unlike the self-service keys of [`ApiKeyService`](super::ApiKeyService),
+/// these identify services rather than users and carry no scopes.
+pub trait KeyStore: Send + Sync {
+    /// Returns the client owning `key`, if any
+    fn lookup(&self, key: &str) -> AppResult<Option<ApiKeyClient>>;
+}
+
+/// [`KeyStore`] holding SHA-256 hashes of the keys in memory
+#[derive(Default)]
+pub struct InMemoryKeyStore {
+    keys: RwLock<HashMap<[u8; 32], String>>,
+}
+
+impl InMemoryKeyStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a store holding the `STATIC_API_KEYS`
+    pub fn from_config(config: &Config) -> Self {
+        let store = Self::new();
+        for (name, key) in &config.static_api_keys {
+            store.insert(name, key);
+        }
+        store
+    }
+
+    /// Accepts `key` for the client `name`
+    pub fn insert(&self, name: &str, key: &str) {
+        if let Ok(mut keys) = self.keys.write() {
+            keys.insert(hash(key), name.to_string());
+        }
+    }
+
+    /// Stops accepting every key of the client `name`; returns how many were removed
+    pub fn remove(&self, name: &str) -> usize {
+        let Ok(mut keys) = self.keys.write() else {
+            return 0;
+        };
+        let before = keys.len();
+        keys.retain(|_, owner| owner != name);
+        before - keys.len()
+    }
+}
+
+impl KeyStore for InMemoryKeyStore {
+    fn lookup(&self, key: &str) -> AppResult<Option<ApiKeyClient>> {
+        let keys = self
+            .keys
+            .read()
+            .map_err(|_| AppError::internal("key store lock poisoned"))?;
+        Ok(keys.get(&hash(key)).map(|name| ApiKeyClient { name: name.clone() }))
+    }
+}
+
+/// Comparing digests rather than keys keeps lookups independent of how much
+/// of a guessed key matches
+fn hash(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// Middleware requiring a known key in [`API_KEY_HEADER`], for use with `from_fn`
+///
+/// On success the client is recorded as the [`RequestContext`] principal.
+///
+/// # Errors
+/// Unauthorized for a missing or unknown key, internal error when no
+/// `web::Data<dyn KeyStore>` is registered
+pub async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let store = req
+        .app_data::<web::Data<dyn KeyStore>>()
+        .ok_or_else(|| AppError::internal("key store not configured"))?;
+    let key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AppError::unauthorized(format!("{} header required", API_KEY_HEADER)))?;
+    let span = RequestContext::span(&req, "auth");
+    let client = store
+        .lookup(key.trim())?
+        .ok_or_else(|| AppError::unauthorized("invalid API key"))?;
+    RequestContext::set_principal(&req, AuthPrincipal::Service { name: client.name }, None);
+    drop(span);
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_in_memory_store() {
+        let config = Config {
+            static_api_keys: vec![("billing".to_string(), "k-billing-0123456789abcdef".to_string())],
+            ..Config::default()
+        };
+        let store = InMemoryKeyStore::from_config(&config);
+        store.insert("reports", "k-reports-0123456789abcdef");
+
+        assert_eq!(
+            store.lookup("k-billing-0123456789abcdef").unwrap(),
+            Some(ApiKeyClient { name: "billing".to_string() })
+        );
+        assert_eq!(store.lookup("k-billing").unwrap(), None);
+        assert_eq!(store.remove("reports"), 1);
+        assert_eq!(store.lookup("k-reports-0123456789abcdef").unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_require_api_key() {
+        let store = InMemoryKeyStore::new();
+        store.insert("billing", "k-billing-0123456789abcdef");
+        let store: Arc<dyn KeyStore> = Arc::new(store);
+        let app = init_service(
+            App::new().app_data(web::Data::from(store)).route(
+                "/internal",
+                web::get()
+                    .to(|context: RequestContext| async move {
+                        HttpResponse::Ok().body(context.principal.unwrap().subject().to_string())
+                    })
+                    .wrap(from_fn(require_api_key)),
+            ),
+        )
+        .await;
+
+        let res = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/internal")
+                .insert_header((API_KEY_HEADER, "k-billing-0123456789abcdef"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(actix_web::test::read_body(res).await, "billing");
+
+        for request in [
+            TestRequest::get().uri("/internal"),
+            TestRequest::get().uri("/internal").insert_header((API_KEY_HEADER, "wrong")),
+        ] {
+            let err = try_call_service(&app, request.to_request()).await.err().unwrap();
+            assert_eq!(err.as_response_error().status_code(), 401);
+        }
+    }
+}
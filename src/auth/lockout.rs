@@ -0,0 +1,219 @@
+//! Temporary lockouts of accounts and addresses after repeated failed logins.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::warn;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::pii::redact_text;
+use crate::state::KeyValueStore;
+
+/// Temporarily locks out accounts and addresses after repeated failed logins
+///
+/// Failures are counted separately for the account (the login name, case
+/// insensitive) and for the client address. Once either reaches the limit
+/// within the window it is locked out for the configured duration: login
+/// attempts are refused before the credentials are even checked, so a
+/// correct password guessed during the lockout is of no use. Unlike the
+/// [`ChallengeGate`](crate::auth::ChallengeGate), a lockout cannot be lifted
+/// by the client. Store failures are logged and let the attempt through.
+pub struct LoginLockout {
+    max_failures: u64,
+    window: Duration,
+    duration: Duration,
+    store: Arc<dyn KeyValueStore>,
+}
+
+impl LoginLockout {
+    /// Creates a lockout policy
+    ///
+    /// # Arguments
+    /// * `max_failures` - Failures within `window` before a lockout, 0 disables lockouts
+    /// * `window` - Period failures are counted over
+    /// * `duration` - How long a lockout lasts
+    /// * `store` - Store holding the failure counters and lockouts
+    pub fn new(max_failures: u64, window: Duration, duration: Duration, store: Arc<dyn KeyValueStore>) -> Self {
+        Self {
+            max_failures,
+            window,
+            duration,
+            store,
+        }
+    }
+
+    /// Builds the policy from the `LOCKOUT_*` settings
+    pub fn from_config(config: &Config, store: Arc<dyn KeyValueStore>) -> Self {
+        Self::new(
+            config.lockout_max_failures,
+            Duration::from_secs(config.lockout_window_secs),
+            Duration::from_secs(config.lockout_duration_secs),
+            store,
+        )
+    }
+
+    /// Returns whether lockouts are enabled
+    pub fn is_enabled(&self) -> bool {
+        self.max_failures > 0
+    }
+
+    /// Returns when the latest lockout of `principal` or `ip` ends, if one is active at `now`
+    pub fn locked_until(&self, principal: &str, ip: IpAddr, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        subjects(principal, ip)
+            .iter()
+            .filter_map(|subject| match self.store.get(&format!("locked:{}", subject)) {
+                Ok(until) => until
+                    .and_then(|until| until.parse::<i64>().ok())
+                    .and_then(|until| Utc.timestamp_opt(until, 0).single()),
+                Err(e) => {
+                    warn!("Login lockout store unavailable: {}", e);
+                    None
+                }
+            })
+            .filter(|until| *until > now)
+            .max()
+    }
+
+    /// Refuses the attempt while `principal` or `ip` is locked out
+    ///
+    /// # Errors
+    /// Returns a locked-out error carrying the end of the lockout
+    pub fn check(&self, principal: &str, ip: IpAddr, now: DateTime<Utc>) -> AppResult<()> {
+        match self.locked_until(principal, ip, now) {
+            Some(until) => Err(AppError::locked_out("too many failed login attempts; try again later", until)),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a failed attempt, locking out the account or address that reaches the limit
+    ///
+    /// Returns the end of the lockout this failure started, if any.
+    pub fn record_failure(&self, principal: &str, ip: IpAddr, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        // Whole seconds, as stored
+        let until = now + chrono::Duration::from_std(self.duration).unwrap_or_default();
+        let until = Utc.timestamp_opt(until.timestamp(), 0).single().unwrap_or(until);
+        let mut locked = None;
+        for subject in subjects(principal, ip) {
+            let failures = match self.store.increment(&format!("failures:{}", subject), Some(self.window)) {
+                Ok(failures) => failures,
+                Err(e) => {
+                    warn!("Login lockout store unavailable: {}", e);
+                    continue;
+                }
+            };
+            if failures < self.max_failures {
+                continue;
+            }
+            let stored = self
+                .store
+                .set(&format!("locked:{}", subject), &until.timestamp().to_string(), Some(self.duration))
+                .and_then(|_| self.store.delete(&format!("failures:{}", subject)));
+            match stored {
+                Ok(_) => {
+                    warn!("Locked out {} until {} after {} failed logins", redact_text(&subject), until, failures);
+                    locked = Some(until);
+                }
+                Err(e) => warn!("Login lockout store unavailable: {}", e),
+            }
+        }
+        locked
+    }
+
+    /// Clears the failures of `principal` after a successful login
+    ///
+    /// The address keeps its count, so one valid account does not reset an
+    /// attacker's budget for guessing others.
+    pub fn record_success(&self, principal: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Err(e) = self.store.delete(&format!("failures:{}", account_key(principal))) {
+            warn!("Login lockout store unavailable: {}", e);
+        }
+    }
+}
+
+/// Keys failures are counted under: the account, then the address
+fn subjects(principal: &str, ip: IpAddr) -> [String; 2] {
+    [account_key(principal), format!("ip:{}", ip)]
+}
+
+fn account_key(principal: &str) -> String {
+    format!("account:{}", principal.trim().to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    fn lockout() -> LoginLockout {
+        LoginLockout::new(3, Duration::from_secs(60), Duration::from_secs(300), Arc::new(InMemoryStore::new()))
+    }
+
+    #[test]
+    fn test_account_lockout() {
+        let lockout = lockout();
+        let now = Utc::now();
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+
+        assert_eq!(lockout.record_failure("ann@example.com", ip, now), None);
+        assert_eq!(lockout.record_failure("ann@example.com", "203.0.113.10".parse().unwrap(), now), None);
+        assert!(lockout.check("ann@example.com", ip, now).is_ok());
+        // The third failure for the account locks it out, whatever the address
+        let until = lockout.record_failure("ANN@example.com ", "198.51.100.1".parse().unwrap(), now).unwrap();
+        assert_eq!(until.timestamp(), now.timestamp() + 300);
+
+        match lockout.check("ann@example.com", "192.0.2.1".parse().unwrap(), now) {
+            Err(AppError::LockedOut { locked_until, .. }) => assert_eq!(locked_until, until),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(lockout.check("bob@example.com", ip, now).is_ok());
+        assert!(lockout.check("ann@example.com", ip, now + chrono::Duration::seconds(301)).is_ok());
+    }
+
+    #[test]
+    fn test_address_lockout_survives_successes() {
+        let lockout = lockout();
+        let now = Utc::now();
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+
+        lockout.record_failure("a@example.com", ip, now);
+        lockout.record_failure("b@example.com", ip, now);
+        lockout.record_success("c@example.com");
+        assert!(lockout.record_failure("c@example.com", ip, now).is_some());
+        assert!(matches!(lockout.check("d@example.com", ip, now), Err(AppError::LockedOut { .. })));
+        assert!(lockout.check("d@example.com", "203.0.113.10".parse().unwrap(), now).is_ok());
+    }
+
+    #[test]
+    fn test_success_resets_account_failures() {
+        let lockout = lockout();
+        let now = Utc::now();
+        let ip = |last: u8| IpAddr::from([203, 0, 113, last]);
+
+        lockout.record_failure("ann@example.com", ip(1), now);
+        lockout.record_failure("ann@example.com", ip(2), now);
+        lockout.record_success("ann@example.com");
+        assert_eq!(lockout.record_failure("ann@example.com", ip(3), now), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let lockout = LoginLockout::new(0, Duration::from_secs(60), Duration::from_secs(300), Arc::new(InMemoryStore::new()));
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+        for _ in 0..20 {
+            assert_eq!(lockout.record_failure("ann@example.com", ip, Utc::now()), None);
+        }
+        assert!(lockout.check("ann@example.com", ip, Utc::now()).is_ok());
+    }
+}
@@ -0,0 +1,220 @@
+//! TOTP two-factor authentication with single-use recovery codes.
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::totp;
+use crate::config::Config;
+use crate::crypto::Cipher;
+use crate::error::{AppError, AppResult};
+use crate::users::{TotpEnrollment, User, UserRepository};
+
+/// Secret and provisioning URL returned when enrollment starts
+#[derive(Debug, Clone, Serialize)]
+pub struct Provisioning {
+    /// Base32 secret for manual entry
+    pub secret: String,
+    /// `otpauth://` URL for QR codes
+    pub otpauth_url: String,
+}
+
+/// TOTP two-factor authentication for user accounts
+///
+/// Secrets are stored encrypted with the data-at-rest [`Cipher`]; recovery
+/// codes are stored hashed and shown only once, when enrollment completes.
+pub struct TwoFactorService {
+    repository: UserRepository,
+    cipher: Cipher,
+    issuer: String,
+    required_roles: Vec<String>,
+}
+
+impl TwoFactorService {
+    /// Creates the service
+    ///
+    /// # Arguments
+    /// * `repository` - User storage
+    /// * `cipher` - Encrypts TOTP secrets at rest
+    /// * `issuer` - Name shown by authenticator apps
+    /// * `required_roles` - Roles that must enroll before getting full access
+    pub fn new(repository: UserRepository, cipher: Cipher, issuer: &str, required_roles: Vec<String>) -> Self {
+        Self {
+            repository,
+            cipher,
+            issuer: issuer.to_string(),
+            required_roles,
+        }
+    }
+
+    /// Builds the service from configuration
+    pub fn from_config(config: &Config, repository: UserRepository) -> Self {
+        Self::new(
+            repository,
+            Cipher::from_config(config),
+            &config.jwt_issuer,
+            config.mfa_required_roles.clone(),
+        )
+    }
+
+    /// Returns whether policy requires `user` to use two-factor authentication
+    pub fn is_required(&self, user: &User) -> bool {
+        user.roles.iter().any(|role| self.required_roles.contains(role))
+    }
+
+    /// Generates and stores a new pending secret for `user_id`
+    ///
+    /// # Errors
+    /// Returns a validation error if two-factor authentication is already enabled
+    pub fn begin_enrollment(&self, user_id: &str) -> AppResult<Provisioning> {
+        let mut user = self.load(user_id)?;
+        if user.two_factor_enabled() {
+            return Err(AppError::validation("two-factor authentication is already enabled"));
+        }
+
+        let secret = totp::generate_secret();
+        user.totp = Some(TotpEnrollment {
+            secret: self.cipher.encrypt(&secret)?,
+            confirmed: false,
+            last_used_step: None,
+            recovery_codes: Vec::new(),
+        });
+        user.updated_at = chrono::Utc::now();
+        self.repository.save(&user)?;
+        self.repository
+            .record_event(&user.id, "user.2fa_enrollment_started", Value::Null)?;
+
+        Ok(Provisioning {
+            secret: totp::encode_secret(&secret),
+            otpauth_url: totp::otpauth_url(&self.issuer, &user.email, &secret),
+        })
+    }
+
+    /// Confirms a pending enrollment with a first code
+    ///
+    /// Returns the recovery codes, which are not retrievable later.
+    ///
+    /// # Errors
+    /// Validation error without a pending enrollment, unauthorized for a wrong code
+    pub fn confirm_enrollment(&self, user_id: &str, code: &str) -> AppResult<Vec<String>> {
+        let mut user = self.load(user_id)?;
+        let enrollment = user
+            .totp
+            .as_mut()
+            .filter(|totp| !totp.confirmed)
+            .ok_or_else(|| AppError::validation("no pending two-factor enrollment"))?;
+
+        let secret = self.cipher.decrypt(&enrollment.secret)?;
+        let step = totp::verify(&secret, code, now(), None)
+            .ok_or_else(|| AppError::unauthorized("invalid one-time password"))?;
+        let recovery_codes = totp::generate_recovery_codes();
+        enrollment.confirmed = true;
+        enrollment.last_used_step = Some(step);
+        enrollment.recovery_codes = recovery_codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+
+        user.updated_at = chrono::Utc::now();
+        self.repository.save(&user)?;
+        self.repository.record_event(&user.id, "user.2fa_enabled", Value::Null)?;
+        Ok(recovery_codes)
+    }
+
+    /// Checks a TOTP code or an unused recovery code for `user`
+    ///
+    /// Accepted codes are burnt: the TOTP step is remembered and recovery
+    /// codes are removed. Returns `false` for wrong codes or when `user` has
+    /// no confirmed enrollment.
+    pub fn verify(&self, user: &mut User, code: &str) -> AppResult<bool> {
+        let Some(enrollment) = user.totp.as_mut().filter(|totp| totp.confirmed) else {
+            return Ok(false);
+        };
+
+        let secret = self.cipher.decrypt(&enrollment.secret)?;
+        let event = if let Some(step) = totp::verify(&secret, code, now(), enrollment.last_used_step) {
+            enrollment.last_used_step = Some(step);
+            None
+        } else {
+            let hash = totp::hash_recovery_code(code);
+            let Some(index) = enrollment.recovery_codes.iter().position(|stored| *stored == hash) else {
+                return Ok(false);
+            };
+            enrollment.recovery_codes.remove(index);
+            Some(json!({ "remaining_recovery_codes": enrollment.recovery_codes.len() }))
+        };
+
+        self.repository.save(user)?;
+        if let Some(data) = event {
+            self.repository
+                .record_event(&user.id, "user.2fa_recovery_code_used", data)?;
+        }
+        Ok(true)
+    }
+
+    fn load(&self, user_id: &str) -> AppResult<User> {
+        self.repository
+            .get(user_id)?
+            .ok_or_else(|| AppError::not_found("user not found"))
+    }
+}
+
+fn now() -> u64 {
+    chrono::Utc::now().timestamp().max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+    use std::sync::Arc;
+
+    fn setup() -> (TwoFactorService, UserRepository, User) {
+        let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+        let user = repository.create("ann@example.com", String::new()).unwrap();
+        let service = TwoFactorService::new(
+            repository.clone(),
+            Cipher::new(b"mfa-test-key-mfa-test-key-mfa-00"),
+            "demo",
+            vec!["admin".to_string()],
+        );
+        (service, repository, user)
+    }
+
+    fn current_code(provisioning: &Provisioning) -> String {
+        let secret = data_encoding::BASE32_NOPAD.decode(provisioning.secret.as_bytes()).unwrap();
+        totp::code_at_step(&secret, totp::step_for(now()))
+    }
+
+    #[test]
+    fn test_enrollment_and_verification() {
+        let (service, repository, user) = setup();
+        let provisioning = service.begin_enrollment(&user.id).unwrap();
+        assert!(provisioning.otpauth_url.contains("issuer=demo"));
+
+        // The stored secret is encrypted
+        let stored = repository.get(&user.id).unwrap().unwrap();
+        assert!(stored.totp.as_ref().unwrap().secret.starts_with("v1:"));
+        assert!(!stored.two_factor_enabled());
+
+        assert!(service.confirm_enrollment(&user.id, "000000x").is_err());
+        let code = current_code(&provisioning);
+        let recovery = service.confirm_enrollment(&user.id, &code).unwrap();
+        assert_eq!(recovery.len(), totp::RECOVERY_CODE_COUNT);
+
+        let mut user = repository.get(&user.id).unwrap().unwrap();
+        assert!(user.two_factor_enabled());
+        // The confirmation code cannot be replayed
+        assert!(!service.verify(&mut user, &code).unwrap());
+
+        // Recovery codes work once
+        assert!(service.verify(&mut user, &recovery[0]).unwrap());
+        assert!(!service.verify(&mut user, &recovery[0]).unwrap());
+        let stored = repository.get(&user.id).unwrap().unwrap();
+        assert_eq!(stored.totp.unwrap().recovery_codes.len(), totp::RECOVERY_CODE_COUNT - 1);
+    }
+
+    #[test]
+    fn test_policy_by_role() {
+        let (service, _, mut user) = setup();
+        assert!(!service.is_required(&user));
+        user.roles.push("admin".to_string());
+        assert!(service.is_required(&user));
+    }
+}
@@ -0,0 +1,45 @@
+//! Authentication and authorization
+//!
+//! Each submodule covers one way of authenticating or guarding callers;
+//! their module docs describe them. The types the HTTP layer uses are
+//! re-exported here.
+
+pub mod api_keys;
+pub mod basic;
+pub mod challenge;
+pub mod clients;
+pub mod cookie_sessions;
+pub mod denylist;
+pub mod guest;
+pub mod impersonation;
+pub mod key_store;
+pub mod lockout;
+pub mod mfa;
+pub mod oidc;
+pub mod quotas;
+pub mod refresh;
+pub mod scopes;
+pub mod sessions;
+pub mod signatures;
+pub mod signing_keys;
+pub mod tokens;
+pub mod totp;
+
+pub use api_keys::ApiKeyService;
+pub use basic::BasicAuthenticator;
+pub use challenge::ChallengeGate;
+pub use clients::ClientRegistry;
+pub use cookie_sessions::{CookieSession, CookieSessionManager, InMemorySessionStore, SessionStore};
+pub use denylist::TokenDenylist;
+pub use guest::GuestTokenIssuer;
+pub use impersonation::ImpersonationService;
+pub use key_store::{InMemoryKeyStore, KeyStore};
+pub use lockout::LoginLockout;
+pub use mfa::TwoFactorService;
+pub use oidc::{OidcClient, OidcUser};
+pub use quotas::{QuotaService, QuotaStore};
+pub use refresh::RefreshTokenService;
+pub use sessions::SessionRegistry;
+pub use signatures::SignatureVerifier;
+pub use signing_keys::SigningKeyRing;
+pub use tokens::{Actor, Claims, TokenService};
@@ -0,0 +1,388 @@
+//! Daily and monthly request quotas per service key, counted behind the
+//! [`QuotaStore`] trait and enforced by [`enforce_quota`].
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::context::{AuthPrincipal, RequestContext};
+use crate::error::{AppError, AppResult};
+use crate::state::KeyValueStore;
+
+/// Response header carrying the requests left in the tightest quota period
+pub const QUOTA_REMAINING_HEADER: &str = "x-quota-remaining";
+
+/// Counters outlive their window by this much so late reads still find them
+const COUNTER_GRACE: Duration = Duration::from_secs(3600);
+
+/// Window a quota counts requests over, aligned on UTC calendar boundaries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaPeriod {
+    /// Every period, shortest first
+    pub const ALL: [QuotaPeriod; 2] = [QuotaPeriod::Daily, QuotaPeriod::Monthly];
+
+    /// Lowercase name used in messages and responses
+    pub fn name(self) -> &'static str {
+        match self {
+            QuotaPeriod::Daily => "daily",
+            QuotaPeriod::Monthly => "monthly",
+        }
+    }
+
+    /// Identifier of the window containing `now`, e.g. `day:2024-01-15`
+    pub fn window(self, now: DateTime<Utc>) -> String {
+        match self {
+            QuotaPeriod::Daily => now.format("day:%Y-%m-%d").to_string(),
+            QuotaPeriod::Monthly => now.format("month:%Y-%m").to_string(),
+        }
+    }
+
+    /// Start of the window following the one containing `now`
+    pub fn resets_at(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.date_naive();
+        let next = match self {
+            QuotaPeriod::Daily => today.succ_opt(),
+            QuotaPeriod::Monthly if today.month() == 12 => NaiveDate::from_ymd_opt(today.year() + 1, 1, 1),
+            QuotaPeriod::Monthly => NaiveDate::from_ymd_opt(today.year(), today.month() + 1, 1),
+        };
+        next.and_then(|day| day.and_hms_opt(0, 0, 0))
+            .map_or(DateTime::<Utc>::MAX_UTC, |midnight| midnight.and_utc())
+    }
+}
+
+/// Request limits of one API key; `None` means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+}
+
+impl QuotaLimits {
+    /// Creates limits from request counts, 0 meaning unlimited
+    pub fn new(daily: u64, monthly: u64) -> Self {
+        Self {
+            daily: (daily > 0).then_some(daily),
+            monthly: (monthly > 0).then_some(monthly),
+        }
+    }
+
+    /// Limit of `period`
+    pub fn limit(&self, period: QuotaPeriod) -> Option<u64> {
+        match period {
+            QuotaPeriod::Daily => self.daily,
+            QuotaPeriod::Monthly => self.monthly,
+        }
+    }
+
+    /// Parses per-key limits: `name:daily/monthly,...` (0 = unlimited)
+    ///
+    /// # Errors
+    /// Returns a configuration error for malformed entries
+    pub fn parse_overrides(spec: &str) -> AppResult<Vec<(String, QuotaLimits)>> {
+        spec.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let invalid = || AppError::config(format!("quota entry '{}' must look like 'name:daily/monthly'", entry));
+                let (name, limits) = entry.split_once(':').ok_or_else(invalid)?;
+                let (daily, monthly) = limits.split_once('/').ok_or_else(invalid)?;
+                let count = |value: &str| value.trim().parse::<u64>().map_err(|_| invalid());
+                Ok((name.trim().to_string(), QuotaLimits::new(count(daily)?, count(monthly)?)))
+            })
+            .collect()
+    }
+}
+
+/// Persistence of per-key request counters
+pub trait QuotaStore: Send + Sync {
+    /// Requests counted for `key` in `window`
+    fn used(&self, key: &str, window: &str) -> AppResult<u64>;
+
+    /// Counts one request for `key` in `window` and returns the new total
+    ///
+    /// `ttl` applies when the counter is created.
+    fn record(&self, key: &str, window: &str, ttl: Duration) -> AppResult<u64>;
+}
+
+/// [`QuotaStore`] keeping counters in a state store, as `{window}:{key}`
+pub struct StateQuotaStore {
+    store: Arc<dyn KeyValueStore>,
+}
+
+impl StateQuotaStore {
+    /// Creates the store on `store`
+    pub fn new(store: Arc<dyn KeyValueStore>) -> Self {
+        Self { store }
+    }
+}
+
+impl QuotaStore for StateQuotaStore {
+    fn used(&self, key: &str, window: &str) -> AppResult<u64> {
+        Ok(self
+            .store
+            .get(&format!("{}:{}", window, key))?
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0))
+    }
+
+    fn record(&self, key: &str, window: &str, ttl: Duration) -> AppResult<u64> {
+        self.store.increment(&format!("{}:{}", window, key), Some(ttl))
+    }
+}
+
+/// Usage of one quota period
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuotaUsage {
+    pub period: QuotaPeriod,
+    pub limit: Option<u64>,
+    pub used: u64,
+    pub remaining: Option<u64>,
+    pub resets_at: DateTime<Utc>,
+}
+
+/// Usage of every quota period of one key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuotaStatus {
+    pub key: String,
+    pub periods: Vec<QuotaUsage>,
+}
+
+impl QuotaStatus {
+    /// Requests left before the tightest limit is hit, `None` when unlimited
+    pub fn remaining(&self) -> Option<u64> {
+        self.periods.iter().filter_map(|usage| usage.remaining).min()
+    }
+}
+
+/// Daily and monthly request quotas of the service keys sent in `X-Api-Key`
+///
+/// Every request on a key-protected route counts against the calling key,
+/// limited or not, so usage can be queried for any key. Limits come from
+/// `API_KEY_DAILY_QUOTA`/`API_KEY_MONTHLY_QUOTA`, overridden per key name by
+/// `API_KEY_QUOTAS`. The limit is checked before counting, so concurrent
+/// requests can overshoot it by the number in flight.
+pub struct QuotaService {
+    store: Arc<dyn QuotaStore>,
+    defaults: QuotaLimits,
+    overrides: HashMap<String, QuotaLimits>,
+}
+
+impl QuotaService {
+    /// Creates a service applying `defaults` to every key
+    pub fn new(store: Arc<dyn QuotaStore>, defaults: QuotaLimits) -> Self {
+        Self {
+            store,
+            defaults,
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// Builds the service from the `API_KEY_*QUOTA*` settings, counting in `store`
+    pub fn from_config(config: &Config, store: Arc<dyn KeyValueStore>) -> Self {
+        let defaults = QuotaLimits::new(config.api_key_daily_quota, config.api_key_monthly_quota);
+        config.api_key_quotas.iter().fold(
+            Self::new(Arc::new(StateQuotaStore::new(store)), defaults),
+            |service, (name, limits)| service.with_limits(name, *limits),
+        )
+    }
+
+    /// Applies `limits` to the key `name` instead of the defaults
+    pub fn with_limits(mut self, name: &str, limits: QuotaLimits) -> Self {
+        self.overrides.insert(name.to_string(), limits);
+        self
+    }
+
+    /// Limits applying to the key `name`
+    pub fn limits(&self, name: &str) -> QuotaLimits {
+        self.overrides.get(name).copied().unwrap_or(self.defaults)
+    }
+
+    /// Usage of the key `name` in the windows containing `now`
+    pub fn status(&self, name: &str, now: DateTime<Utc>) -> AppResult<QuotaStatus> {
+        let limits = self.limits(name);
+        let periods = QuotaPeriod::ALL
+            .into_iter()
+            .map(|period| {
+                let used = self.store.used(name, &period.window(now))?;
+                let limit = limits.limit(period);
+                Ok(QuotaUsage {
+                    period,
+                    limit,
+                    used,
+                    remaining: limit.map(|limit| limit.saturating_sub(used)),
+                    resets_at: period.resets_at(now),
+                })
+            })
+            .collect::<AppResult<_>>()?;
+        Ok(QuotaStatus {
+            key: name.to_string(),
+            periods,
+        })
+    }
+
+    /// Counts one request of the key `name` and returns the usage including it
+    ///
+    /// # Errors
+    /// Rate limited, retryable when the exhausted period resets, once any
+    /// quota of the key is used up; the rejected request is not counted.
+    pub fn consume(&self, name: &str, now: DateTime<Utc>) -> AppResult<QuotaStatus> {
+        let mut status = self.status(name, now)?;
+        if let Some(exhausted) = status.periods.iter().find(|usage| usage.remaining == Some(0)) {
+            let retry_after = (exhausted.resets_at - now).num_seconds().max(1) as u64;
+            return Err(AppError::rate_limited(
+                format!(
+                    "{} quota of {} requests exhausted for API key '{}'",
+                    exhausted.period.name(),
+                    exhausted.limit.unwrap_or_default(),
+                    name
+                ),
+                retry_after,
+            ));
+        }
+        for usage in &mut status.periods {
+            let ttl = (usage.resets_at - now).to_std().unwrap_or_default() + COUNTER_GRACE;
+            usage.used = self.store.record(name, &usage.period.window(now), ttl)?;
+            usage.remaining = usage.limit.map(|limit| limit.saturating_sub(usage.used));
+        }
+        Ok(status)
+    }
+}
+
+/// Name of the service key the request was authenticated with
+pub fn calling_key(req: &impl HttpMessage) -> Option<String> {
+    match req.extensions().get::<RequestContext>()?.principal.as_ref()? {
+        AuthPrincipal::Service { name } => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// Middleware counting requests against the calling key's quotas, for use
+/// with `from_fn` inside [`require_api_key`](super::key_store::require_api_key)
+///
+/// Adds [`QUOTA_REMAINING_HEADER`] when the key is limited. Passes requests
+/// through when no [`QuotaService`] is registered.
+///
+/// # Errors
+/// Rate limited (429 with `Retry-After`) once a quota is used up
+pub async fn enforce_quota(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let status = match (req.app_data::<web::Data<QuotaService>>(), calling_key(&req)) {
+        (Some(quotas), Some(name)) => Some(quotas.consume(&name, Utc::now())?),
+        _ => None,
+    };
+    let mut res = next.call(req).await?;
+    if let Some(remaining) = status.and_then(|status| status.remaining()) {
+        res.headers_mut()
+            .insert(HeaderName::from_static(QUOTA_REMAINING_HEADER), HeaderValue::from(remaining));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::key_store::{require_api_key, InMemoryKeyStore, KeyStore, API_KEY_HEADER};
+    use crate::state::InMemoryStore;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use chrono::TimeZone;
+
+    fn service(defaults: QuotaLimits) -> QuotaService {
+        QuotaService::new(Arc::new(StateQuotaStore::new(Arc::new(InMemoryStore::new()))), defaults)
+    }
+
+    #[test]
+    fn test_periods() {
+        let now = Utc.with_ymd_and_hms(2024, 12, 31, 18, 30, 0).unwrap();
+        assert_eq!(QuotaPeriod::Daily.window(now), "day:2024-12-31");
+        assert_eq!(QuotaPeriod::Monthly.window(now), "month:2024-12");
+        assert_eq!(QuotaPeriod::Daily.resets_at(now), Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap());
+        assert_eq!(QuotaPeriod::Monthly.resets_at(now), Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap());
+        let mid_year = Utc.with_ymd_and_hms(2024, 2, 10, 0, 0, 0).unwrap();
+        assert_eq!(QuotaPeriod::Monthly.resets_at(mid_year), Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        let overrides = QuotaLimits::parse_overrides("billing:1000/20000, reports:0/500").unwrap();
+        assert_eq!(overrides[0], ("billing".to_string(), QuotaLimits::new(1000, 20000)));
+        assert_eq!(overrides[1].1, QuotaLimits { daily: None, monthly: Some(500) });
+        for invalid in ["billing", "billing:1000", "billing:many/10"] {
+            assert!(QuotaLimits::parse_overrides(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_consume_until_exhausted() {
+        let quotas = service(QuotaLimits::new(2, 0)).with_limits("reports", QuotaLimits::new(0, 0));
+        let now = Utc.with_ymd_and_hms(2024, 1, 15, 23, 0, 0).unwrap();
+
+        assert_eq!(quotas.consume("billing", now).unwrap().remaining(), Some(1));
+        assert_eq!(quotas.consume("billing", now).unwrap().remaining(), Some(0));
+        let err = quotas.consume("billing", now).unwrap_err();
+        assert!(matches!(err, AppError::RateLimited { retry_after_secs: 3600, .. }), "{:?}", err);
+        // Rejected requests are not counted
+        assert_eq!(quotas.status("billing", now).unwrap().periods[0].used, 2);
+        let monthly = &quotas.status("billing", now).unwrap().periods[1];
+        assert_eq!((monthly.limit, monthly.used, monthly.remaining), (None, 2, None));
+
+        // The next day starts a new window; other keys have their own counters
+        let tomorrow = now + chrono::Duration::hours(2);
+        assert_eq!(quotas.consume("billing", tomorrow).unwrap().remaining(), Some(1));
+        for _ in 0..5 {
+            assert_eq!(quotas.consume("reports", now).unwrap().remaining(), None);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_limits_service_keys() {
+        let keys = InMemoryKeyStore::new();
+        keys.insert("billing", "k-billing-0123456789abcdef");
+        let keys: Arc<dyn KeyStore> = Arc::new(keys);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::from(keys))
+                .app_data(web::Data::new(service(QuotaLimits::new(1, 0))))
+                .route(
+                    "/internal",
+                    web::get()
+                        .to(HttpResponse::Ok)
+                        .wrap(from_fn(enforce_quota))
+                        .wrap(from_fn(require_api_key)),
+                ),
+        )
+        .await;
+        let request = || {
+            TestRequest::get()
+                .uri("/internal")
+                .insert_header((API_KEY_HEADER, "k-billing-0123456789abcdef"))
+                .to_request()
+        };
+
+        let res = call_service(&app, request()).await;
+        assert!(res.status().is_success());
+        assert_eq!(res.headers().get(QUOTA_REMAINING_HEADER).unwrap(), "0");
+        let err = try_call_service(&app, request()).await.err().unwrap();
+        let res = err.error_response();
+        assert_eq!(res.status(), 429);
+        assert!(res.headers().get("retry-after").is_some());
+    }
+}
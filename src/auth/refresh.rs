@@ -0,0 +1,151 @@
+//! Single-use rotating refresh tokens bound to a session, with reuse detection.
+
+use std::time::Duration;
+
+use log::warn;
+
+use super::denylist::TokenDenylist;
+use super::tokens::{Claims, TokenService};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+
+/// Audience of refresh tokens, so they are never accepted as access tokens
+pub const REFRESH_AUDIENCE: &str = "refresh";
+
+/// Issues and rotates the refresh tokens handed out by `POST /auth/token`
+///
+/// Refresh tokens are JWTs signed with the access token key for the
+/// [`REFRESH_AUDIENCE`] and bound to a session. Each one can be redeemed
+/// once: redeeming it claims its `jti` on the denylist atomically, so
+/// concurrent redemptions of the same token cannot both succeed. Presenting
+/// an already redeemed token means it leaked, so the whole session is
+/// revoked.
+pub struct RefreshTokenService {
+    tokens: TokenService,
+    denylist: TokenDenylist,
+    ttl: Duration,
+}
+
+impl RefreshTokenService {
+    /// Creates a service issuing refresh tokens valid for `ttl`
+    pub fn new(tokens: &TokenService, denylist: TokenDenylist, ttl: Duration) -> Self {
+        Self {
+            tokens: tokens.for_audience(REFRESH_AUDIENCE),
+            denylist,
+            ttl,
+        }
+    }
+
+    /// Builds the service from `REFRESH_TOKEN_TTL_SECS`
+    pub fn from_config(config: &Config, tokens: &TokenService, denylist: TokenDenylist) -> Self {
+        Self::new(tokens, denylist, Duration::from_secs(config.refresh_token_ttl_secs))
+    }
+
+    /// Lifetime of issued refresh tokens
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Issues a refresh token for `user_id`'s session `session_id`
+    pub fn issue(&self, user_id: &str, session_id: &str) -> AppResult<String> {
+        let mut claims = self.tokens.claims(user_id, &[], self.ttl);
+        claims.sid = Some(session_id.to_string());
+        self.tokens.sign(&claims)
+    }
+
+    /// Redeems a refresh token and returns its claims
+    ///
+    /// # Errors
+    /// Unauthorized for invalid, expired or revoked tokens; a token redeemed
+    /// before also revokes its session.
+    pub fn redeem(&self, token: &str) -> AppResult<Claims> {
+        let claims = self.tokens.verify(token)?;
+        let sid = claims
+            .sid
+            .as_deref()
+            .ok_or_else(|| AppError::unauthorized("refresh token has no session"))?;
+        if self.denylist.is_session_revoked(sid)? {
+            return Err(AppError::unauthorized("refresh token has been revoked"));
+        }
+        if !self.denylist.claim_token(&claims.jti, remaining(&claims))? {
+            warn!("Refresh token {} of session {} was reused; revoking the session", claims.jti, sid);
+            self.denylist.revoke_session(sid, self.ttl)?;
+            return Err(AppError::unauthorized("refresh token has already been used"));
+        }
+        Ok(claims)
+    }
+
+    /// Returns the claims of a valid refresh token without redeeming it
+    ///
+    /// # Errors
+    /// Unauthorized for invalid or expired tokens
+    pub fn verify(&self, token: &str) -> AppResult<Claims> {
+        self.tokens.verify(token)
+    }
+}
+
+/// Time until the token expires, at least a second
+fn remaining(claims: &Claims) -> Duration {
+    Duration::from_secs((claims.exp - chrono::Utc::now().timestamp()).max(1) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+    use std::sync::Arc;
+
+    fn service() -> (RefreshTokenService, TokenService) {
+        let tokens = TokenService::new(b"refresh-test-key-refresh-test-ke", "test");
+        let denylist = TokenDenylist::new(Arc::new(InMemoryStore::new()));
+        (RefreshTokenService::new(&tokens, denylist, Duration::from_secs(600)), tokens)
+    }
+
+    #[test]
+    fn test_refresh_tokens_are_single_use() {
+        let (refresh, _) = service();
+        let first = refresh.issue("ann", "s1").unwrap();
+        let claims = refresh.redeem(&first).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.sid.as_deref()), ("ann", Some("s1")));
+
+        // The rotated token works until the old one is replayed, which revokes the session
+        let second = refresh.issue("ann", "s1").unwrap();
+        assert!(refresh.redeem(&first).is_err());
+        let err = refresh.redeem(&second).unwrap_err();
+        assert!(err.to_string().contains("revoked"), "{}", err);
+
+        let other_session = refresh.issue("ann", "s2").unwrap();
+        assert!(refresh.redeem(&other_session).is_ok());
+    }
+
+    #[test]
+    fn test_concurrent_redemptions_only_one_succeeds() {
+        let (refresh, _) = service();
+        let token = refresh.issue("ann", "s1").unwrap();
+        let barrier = std::sync::Barrier::new(8);
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        refresh.redeem(&token)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(refresh.denylist.is_session_revoked("s1").unwrap());
+    }
+
+    #[test]
+    fn test_refresh_and_access_tokens_are_not_interchangeable() {
+        let (refresh, tokens) = service();
+        let access = tokens.issue("ann", &["read:private"], Duration::from_secs(60)).unwrap();
+        assert!(refresh.redeem(&access).is_err());
+
+        let token = refresh.issue("ann", "s1").unwrap();
+        assert!(tokens.verify(&token).is_err());
+        assert_eq!(refresh.verify(&token).unwrap().sub, "ann");
+    }
+}
@@ -0,0 +1,161 @@
+//! The `require_scopes` middleware guarding routes marked with
+//! `RouteSpec::require_scopes` or listed in `ROUTE_SCOPES`.
+
+use std::rc::Rc;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+
+use super::api_keys::{ApiKeyService, API_KEY_PREFIX};
+use super::clients::bearer_token;
+use super::denylist::TokenDenylist;
+use super::sessions::SessionRegistry;
+use super::tokens::{Claims, TokenService};
+use crate::context::{AuthPrincipal, RequestContext};
+use crate::error::{AppError, AppResult};
+
+/// Returns the scopes in `required` that `claims` does not grant
+pub fn missing_scopes<S: AsRef<str>>(claims: &Claims, required: &[S]) -> Vec<String> {
+    required
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|scope| !claims.has_scope(scope))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parses `ROUTE_SCOPES`: `PATH=SCOPE SCOPE,...`
+///
+/// # Errors
+/// Returns a config error for an entry without a path or scopes
+pub fn parse_routes(spec: &str) -> AppResult<Vec<(String, Vec<String>)>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = || AppError::config(format!("route scopes '{}' must look like 'PATH=SCOPE SCOPE'", entry));
+            let (path, scopes) = entry.split_once('=').ok_or_else(invalid)?;
+            let scopes: Vec<String> = scopes.split_whitespace().map(str::to_string).collect();
+            if !path.trim().starts_with('/') || scopes.is_empty() {
+                return Err(invalid());
+            }
+            Ok((path.trim().to_string(), scopes))
+        })
+        .collect()
+}
+
+/// Authenticates the request's bearer credential
+///
+/// Bearer credentials starting with [`API_KEY_PREFIX`] are looked up as API
+/// keys when an [`ApiKeyService`] is registered. Revoked tokens and sessions
+/// are rejected when a [`TokenDenylist`] is registered, and the token's
+/// session is marked as seen.
+///
+/// # Errors
+/// Unauthorized without a valid, unrevoked token
+pub fn authenticate(req: &ServiceRequest) -> Result<Claims, AppError> {
+    let tokens = req
+        .app_data::<web::Data<TokenService>>()
+        .ok_or_else(|| AppError::internal("token service not configured"))?;
+    let token = bearer_token(req.headers())
+        .ok_or_else(|| AppError::unauthorized("bearer token required"))?;
+    let claims = match req.app_data::<web::Data<ApiKeyService>>() {
+        Some(keys) if token.starts_with(API_KEY_PREFIX) => keys.authenticate(token)?.claims(tokens.issuer()),
+        _ => tokens.verify(token)?,
+    };
+    if let Some(denylist) = req.app_data::<web::Data<TokenDenylist>>() {
+        if denylist.is_revoked(&claims)? {
+            return Err(AppError::unauthorized("token has been revoked"));
+        }
+    }
+    if let (Some(sessions), Some(sid)) = (req.app_data::<web::Data<SessionRegistry>>(), &claims.sid) {
+        sessions.touch(sid, crate::client_info::client_ip(req.request()));
+    }
+    Ok(claims)
+}
+
+/// Authenticates the request and checks it grants every `required` scope
+///
+/// On success the verified [`Claims`] are stored in the request extensions
+/// for handlers and later middleware, and the caller is recorded in the
+/// [`RequestContext`](crate::context::RequestContext).
+///
+/// # Errors
+/// Unauthorized as for [`authenticate`], forbidden (listing the missing
+/// scopes) when the token lacks some of them.
+pub fn authorize<S: AsRef<str>>(req: &ServiceRequest, required: &[S]) -> Result<Claims, AppError> {
+    let claims = authenticate(req)?;
+
+    let missing = missing_scopes(&claims, required);
+    if !missing.is_empty() {
+        return Err(AppError::missing_scopes(missing));
+    }
+
+    req.extensions_mut().insert(claims.clone());
+    RequestContext::set_principal(req, AuthPrincipal::from_claims(&claims), Some(&claims));
+    Ok(claims)
+}
+
+/// Middleware requiring the given scopes, for use with `from_fn`
+///
+/// Self-service API keys presented as bearer credentials are checked against
+/// the scopes they were created with.
+pub async fn require_scopes(
+    required: Rc<Vec<String>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let span = RequestContext::span(&req, "auth");
+    authorize(&req, &required)?;
+    drop(span);
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, middleware::from_fn, test, App, HttpResponse};
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_scope_middleware() {
+        let tokens = TokenService::new(b"scope-test-key-scope-test-key-00", "test");
+        let reader = tokens.issue("alice", &["read:private"], Duration::from_secs(60)).unwrap();
+        let nobody = tokens.issue("bob", &[], Duration::from_secs(60)).unwrap();
+
+        let required = Rc::new(vec!["read:private".to_string()]);
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(tokens)).route(
+                "/",
+                web::get()
+                    .to(HttpResponse::Ok)
+                    .wrap(from_fn(move |req, next| require_scopes(required.clone(), req, next))),
+            ),
+        )
+        .await;
+
+        // Middleware errors surface as `Err` from the service; the server
+        // turns them into responses through `ResponseError`
+        let req = test::TestRequest::get().uri("/").to_request();
+        let err = test::try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("Authorization", format!("Bearer {}", nobody)))
+            .to_request();
+        let resp = test::try_call_service(&app, req).await.unwrap_err().error_response();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["missing_scopes"], serde_json::json!(["read:private"]));
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header(("Authorization", format!("Bearer {}", reader)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}
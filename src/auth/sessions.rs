@@ -0,0 +1,297 @@
+//! Per-device sessions, revoked through the token denylist.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::denylist::TokenDenylist;
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::integrity::{IntegrityIssue, IssueKind};
+use crate::pii::{PiiFields, PiiKind, REDACTED};
+use crate::state::KeyValueStore;
+
+/// Minimum interval between `last_seen` updates, to avoid a write per request
+const TOUCH_INTERVAL_SECS: i64 = 60;
+
+/// A signed-in device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    /// Client-supplied name, falling back to the User-Agent
+    pub device_name: String,
+    pub ip: String,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl PiiFields for Session {
+    const PII_FIELDS: &'static [(&'static str, PiiKind)] =
+        &[("ip", PiiKind::IpAddress), ("device_name", PiiKind::Text)];
+}
+
+/// Tracks sessions per user and revokes them through the [`TokenDenylist`]
+///
+/// Keys: `session:{id}` (JSON, expiring with the session) and
+/// `user:{user_id}` (JSON array of session ids, pruned on read).
+pub struct SessionRegistry {
+    store: Arc<dyn KeyValueStore>,
+    denylist: TokenDenylist,
+    ttl: Duration,
+}
+
+impl SessionRegistry {
+    /// Creates a registry whose sessions live for `ttl`
+    pub fn new(store: Arc<dyn KeyValueStore>, denylist: TokenDenylist, ttl: Duration) -> Self {
+        Self { store, denylist, ttl }
+    }
+
+    /// Builds the registry from `SESSION_TTL_SECS`
+    pub fn from_config(config: &Config, store: Arc<dyn KeyValueStore>, denylist: TokenDenylist) -> Self {
+        Self::new(store, denylist, Duration::from_secs(config.session_ttl_secs))
+    }
+
+    /// Returns the denylist revocations are propagated to
+    pub fn denylist(&self) -> &TokenDenylist {
+        &self.denylist
+    }
+
+    /// Starts a session for `user_id`
+    pub fn create(&self, user_id: &str, device_name: &str, ip: IpAddr) -> AppResult<Session> {
+        let now = Utc::now();
+        let session = Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            device_name: device_name.chars().take(200).collect(),
+            ip: ip.to_string(),
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + chrono::Duration::seconds(self.ttl.as_secs() as i64),
+        };
+        self.save(&session)?;
+
+        let mut ids = self.session_ids(user_id)?;
+        ids.push(session.id.clone());
+        self.save_session_ids(user_id, &ids)?;
+        Ok(session)
+    }
+
+    /// Loads a session that has not expired
+    pub fn get(&self, id: &str) -> AppResult<Option<Session>> {
+        self.store
+            .get(&format!("session:{}", id))?
+            .map(|raw| {
+                serde_json::from_str(&raw).map_err(|e| AppError::internal(format!("Corrupt session {}: {}", id, e)))
+            })
+            .transpose()
+    }
+
+    /// Lists the user's active sessions, most recently seen first
+    pub fn list(&self, user_id: &str) -> AppResult<Vec<Session>> {
+        let ids = self.session_ids(user_id)?;
+        let mut sessions = Vec::new();
+        for id in &ids {
+            if let Some(session) = self.get(id)? {
+                sessions.push(session);
+            }
+        }
+        if sessions.len() != ids.len() {
+            let live: Vec<String> = sessions.iter().map(|session| session.id.clone()).collect();
+            self.save_session_ids(user_id, &live)?;
+        }
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen_at));
+        Ok(sessions)
+    }
+
+    /// Records activity on a session
+    ///
+    /// Writes at most once per minute per session; failures are logged only,
+    /// since `last_seen` is informational.
+    pub fn touch(&self, id: &str, ip: Option<IpAddr>) {
+        let result = self.get(id).and_then(|session| {
+            let Some(mut session) = session else {
+                return Ok(());
+            };
+            let now = Utc::now();
+            if (now - session.last_seen_at).num_seconds() < TOUCH_INTERVAL_SECS {
+                return Ok(());
+            }
+            session.last_seen_at = now;
+            if let Some(ip) = ip {
+                session.ip = ip.to_string();
+            }
+            self.save(&session)
+        });
+        if let Err(e) = result {
+            warn!("Failed to update session {}: {}", id, e);
+        }
+    }
+
+    /// Revokes one of `user_id`'s sessions
+    ///
+    /// The session is removed and its id denylisted, so access tokens already
+    /// issued for it stop working immediately.
+    ///
+    /// # Errors
+    /// Returns not found if the session does not exist or belongs to someone else
+    pub fn revoke(&self, user_id: &str, id: &str) -> AppResult<()> {
+        let session = self
+            .get(id)?
+            .filter(|session| session.user_id == user_id)
+            .ok_or_else(|| AppError::not_found("session not found"))?;
+
+        let remaining = (session.expires_at - Utc::now()).num_seconds().max(1) as u64;
+        self.denylist.revoke_session(&session.id, Duration::from_secs(remaining))?;
+        self.store.delete(&format!("session:{}", session.id))?;
+
+        let ids: Vec<String> = self
+            .session_ids(user_id)?
+            .into_iter()
+            .filter(|other| *other != session.id)
+            .collect();
+        self.save_session_ids(user_id, &ids)
+    }
+
+    /// Revokes every session of `user_id`; returns how many there were
+    pub fn revoke_all(&self, user_id: &str) -> AppResult<usize> {
+        let sessions = self.list(user_id)?;
+        for session in &sessions {
+            self.revoke(user_id, &session.id)?;
+        }
+        Ok(sessions.len())
+    }
+
+    /// Scrubs the IP address and device name of `user_id`'s sessions last seen
+    /// before `cutoff`; returns how many sessions were (or, for a dry run,
+    /// would be) changed
+    pub fn anonymize_before(&self, user_id: &str, cutoff: DateTime<Utc>, dry_run: bool) -> AppResult<usize> {
+        let mut anonymized = 0;
+        for mut session in self.list(user_id)? {
+            let scrubbed = session.ip == REDACTED && session.device_name == REDACTED;
+            if session.last_seen_at >= cutoff || scrubbed {
+                continue;
+            }
+            anonymized += 1;
+            if !dry_run {
+                session.ip = REDACTED.to_string();
+                session.device_name = REDACTED.to_string();
+                self.save(&session)?;
+            }
+        }
+        Ok(anonymized)
+    }
+
+    /// Checks the sessions indexed under `user_id`, timestamps after
+    /// `horizon` counting as in the future
+    ///
+    /// Ids listed twice and sessions of another user are reported and, with
+    /// `repair`, dropped from the index; inconsistent timestamps are only
+    /// reported. Expired sessions are left to the next listing.
+    pub fn check_integrity(&self, user_id: &str, horizon: DateTime<Utc>, repair: bool) -> AppResult<Vec<IntegrityIssue>> {
+        let key = format!("user:{}", user_id);
+        let listed = self.session_ids(user_id)?;
+        let mut ids: Vec<String> = Vec::with_capacity(listed.len());
+        let mut issues = Vec::new();
+        for id in listed {
+            if ids.contains(&id) {
+                let detail = format!("session {} is listed more than once", id);
+                issues.push(IntegrityIssue::repairable(IssueKind::DuplicateKey, &key, detail, repair));
+                continue;
+            }
+            if let Some(session) = self.get(&id)? {
+                if session.user_id != user_id {
+                    let detail = format!("session {} belongs to user {}", id, session.user_id);
+                    issues.push(IntegrityIssue::repairable(IssueKind::OrphanedReference, &key, detail, repair));
+                    continue;
+                }
+                let session_key = format!("session:{}", id);
+                if session.expires_at < session.created_at || session.last_seen_at < session.created_at {
+                    let detail = "expires or was last seen before it started";
+                    issues.push(IntegrityIssue::new(IssueKind::InvalidTimestamp, &session_key, detail));
+                }
+                if session.created_at > horizon || session.last_seen_at > horizon {
+                    let detail = "started or was last seen in the future";
+                    issues.push(IntegrityIssue::new(IssueKind::InvalidTimestamp, &session_key, detail));
+                }
+            }
+            ids.push(id);
+        }
+        if repair && issues.iter().any(|issue| issue.repaired) {
+            self.save_session_ids(user_id, &ids)?;
+        }
+        Ok(issues)
+    }
+
+    fn save(&self, session: &Session) -> AppResult<()> {
+        let remaining = (session.expires_at - Utc::now()).num_seconds().max(1) as u64;
+        let encoded = serde_json::to_string(session)
+            .map_err(|e| AppError::internal(format!("Failed to encode session: {}", e)))?;
+        self.store
+            .set(&format!("session:{}", session.id), &encoded, Some(Duration::from_secs(remaining)))
+    }
+
+    fn session_ids(&self, user_id: &str) -> AppResult<Vec<String>> {
+        self.store
+            .get(&format!("user:{}", user_id))?
+            .map(|raw| {
+                serde_json::from_str(&raw)
+                    .map_err(|e| AppError::internal(format!("Corrupt session index for {}: {}", user_id, e)))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn save_session_ids(&self, user_id: &str, ids: &[String]) -> AppResult<()> {
+        let encoded = serde_json::to_string(ids)
+            .map_err(|e| AppError::internal(format!("Failed to encode session index: {}", e)))?;
+        self.store.set(&format!("user:{}", user_id), &encoded, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenService;
+    use crate::state::InMemoryStore;
+
+    fn registry() -> SessionRegistry {
+        SessionRegistry::new(
+            Arc::new(InMemoryStore::new()),
+            TokenDenylist::new(Arc::new(InMemoryStore::new())),
+            Duration::from_secs(3600),
+        )
+    }
+
+    #[test]
+    fn test_create_list_and_revoke() {
+        let registry = registry();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let laptop = registry.create("ann", "Laptop", ip).unwrap();
+        let phone = registry.create("ann", "Phone", ip).unwrap();
+        registry.create("bob", "Desktop", ip).unwrap();
+
+        assert_eq!(registry.list("ann").unwrap().len(), 2);
+
+        // Users cannot revoke each other's sessions
+        assert!(matches!(registry.revoke("bob", &laptop.id), Err(AppError::NotFound { .. })));
+
+        registry.revoke("ann", &laptop.id).unwrap();
+        let remaining = registry.list("ann").unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, phone.id);
+
+        // Tokens issued for the revoked session are denylisted
+        let tokens = TokenService::new(b"sess-test-key-sess-test-key-0000", "test");
+        let mut claims = tokens.claims("ann", &[], Duration::from_secs(60));
+        claims.sid = Some(laptop.id.clone());
+        assert!(registry.denylist().is_revoked(&claims).unwrap());
+        claims.sid = Some(phone.id);
+        assert!(!registry.denylist().is_revoked(&claims).unwrap());
+    }
+}
@@ -0,0 +1,272 @@
+//! HMAC request signatures for routes marked with `RouteSpec::require_signature`
+//! or listed in `SIGNED_ROUTES`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use subtle::ConstantTimeEq;
+
+use crate::config::Config;
+use crate::context::{AuthPrincipal, RequestContext};
+use crate::error::{AppError, AppResult};
+use crate::webhooks::sign;
+
+/// Header carrying `client=<id>,t=<unix ts>,v1=<hex hmac>`
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Verifies `X-Signature` headers on routes marked with
+/// [`RouteSpec::require_signature`](crate::routes::RouteSpec::require_signature)
+///
+/// Each client shares a secret with the service and signs
+/// `"<ts>\n<METHOD>\n<path?query>\n<body>"` with HMAC-SHA256. Signatures
+/// whose timestamp is further than the tolerance from now are rejected, so a
+/// captured request cannot be replayed later.
+#[derive(Debug, Clone, Default)]
+pub struct SignatureVerifier {
+    clients: HashMap<String, String>,
+    tolerance: Duration,
+}
+
+impl SignatureVerifier {
+    /// Creates a verifier without clients accepting timestamps within `tolerance` of now
+    pub fn new(tolerance: Duration) -> Self {
+        Self {
+            clients: HashMap::new(),
+            tolerance,
+        }
+    }
+
+    /// Loads the clients of `SIGNED_REQUEST_CLIENTS` with `SIGNATURE_TOLERANCE_SECS`
+    pub fn from_config(config: &Config) -> Self {
+        let mut verifier = Self::new(Duration::from_secs(config.signature_tolerance_secs));
+        for (client, secret) in &config.signed_request_clients {
+            verifier.insert(client, secret);
+        }
+        verifier
+    }
+
+    /// Shares `secret` with `client`, replacing any previous secret
+    pub fn insert(&mut self, client: &str, secret: &str) {
+        self.clients.insert(client.to_string(), secret.to_string());
+    }
+
+    /// Returns whether no clients are configured
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Returns the client whose signature of the request is valid at `now`
+    ///
+    /// # Errors
+    /// Unauthorized for a missing or malformed header, an unknown client, a
+    /// timestamp outside the tolerance or a signature mismatch
+    pub fn verify(&self, headers: &HeaderMap, method: &str, path: &str, body: &[u8], now: i64) -> AppResult<String> {
+        let header = headers
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| AppError::unauthorized("request signature required"))?;
+        let signature = ParsedSignature::parse(header)?;
+        let secret = self
+            .clients
+            .get(&signature.client)
+            .ok_or_else(|| AppError::unauthorized("unknown signing client"))?;
+        if now.abs_diff(signature.timestamp) > self.tolerance.as_secs() {
+            return Err(AppError::unauthorized("request signature timestamp outside the tolerance window"));
+        }
+        let expected = sign(secret, &signed_message(signature.timestamp, method, path, body));
+        if !bool::from(expected.as_bytes().ct_eq(signature.value.to_ascii_lowercase().as_bytes())) {
+            return Err(AppError::unauthorized("request signature mismatch"));
+        }
+        Ok(signature.client)
+    }
+}
+
+/// Value of an `X-Signature` header signing a request for `client` at `timestamp`
+pub fn signature_header(client: &str, secret: &str, timestamp: i64, method: &str, path: &str, body: &[u8]) -> String {
+    format!(
+        "client={},t={},v1={}",
+        client,
+        timestamp,
+        sign(secret, &signed_message(timestamp, method, path, body))
+    )
+}
+
+/// Bytes covered by the HMAC
+fn signed_message(timestamp: i64, method: &str, path: &str, body: &[u8]) -> Vec<u8> {
+    let mut message = format!("{}\n{}\n{}\n", timestamp, method.to_ascii_uppercase(), path).into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+/// Fields of an `X-Signature` header
+struct ParsedSignature {
+    client: String,
+    timestamp: i64,
+    value: String,
+}
+
+impl ParsedSignature {
+    /// Splits `client=<id>,t=<ts>,v1=<hex>`, in any order
+    fn parse(header: &str) -> AppResult<Self> {
+        let (mut client, mut timestamp, mut value) = (None, None, None);
+        for part in header.split(',') {
+            match part.trim().split_once('=') {
+                Some(("client", v)) => client = Some(v.to_string()),
+                Some(("t", v)) => timestamp = v.parse::<i64>().ok(),
+                Some(("v1", v)) => value = Some(v.to_string()),
+                _ => {}
+            }
+        }
+        match (client, timestamp, value) {
+            (Some(client), Some(timestamp), Some(value)) => Ok(Self {
+                client,
+                timestamp,
+                value,
+            }),
+            _ => Err(AppError::unauthorized(
+                "X-Signature must look like 'client=<id>,t=<unix ts>,v1=<hex hmac>'",
+            )),
+        }
+    }
+}
+
+/// Middleware requiring a valid `X-Signature`, for use with `from_fn`
+///
+/// Buffers the body to verify it and hands it on unchanged. On success the
+/// client is recorded as the [`RequestContext`] principal unless another
+/// authentication already set one.
+///
+/// # Errors
+/// Unauthorized for missing or invalid signatures, internal error when no
+/// `web::Data<SignatureVerifier>` is registered
+pub async fn require_signature(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let verifier = req
+        .app_data::<web::Data<SignatureVerifier>>()
+        .cloned()
+        .ok_or_else(|| AppError::internal("request signatures not configured"))?;
+    let body = req.extract::<web::Bytes>().await?;
+    let span = RequestContext::span(&req, "auth");
+    let path = req.uri().path_and_query().map_or(req.path(), |path| path.as_str()).to_string();
+    let client = verifier.verify(
+        req.headers(),
+        req.method().as_str(),
+        &path,
+        &body,
+        chrono::Utc::now().timestamp(),
+    )?;
+    let authenticated = req
+        .extensions()
+        .get::<RequestContext>()
+        .is_some_and(|context| context.principal.is_some());
+    if !authenticated {
+        RequestContext::set_principal(&req, AuthPrincipal::Signed { client }, None);
+    }
+    drop(span);
+    req.set_payload(body.into());
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn verifier() -> SignatureVerifier {
+        let mut verifier = SignatureVerifier::new(Duration::from_secs(300));
+        verifier.insert("billing", "billing-secret");
+        verifier
+    }
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static(SIGNATURE_HEADER), HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_verify() {
+        let verifier = verifier();
+        let now = 1_700_000_000;
+        let header = signature_header("billing", "billing-secret", now, "POST", "/orders?dry=1", b"{}");
+        assert_eq!(verifier.verify(&headers(&header), "POST", "/orders?dry=1", b"{}", now + 10).unwrap(), "billing");
+
+        // Anything covered by the signature changes the outcome
+        for (method, path, body) in [("PUT", "/orders?dry=1", &b"{}"[..]), ("POST", "/orders", b"{}"), ("POST", "/orders?dry=1", b"[]")] {
+            let err = verifier.verify(&headers(&header), method, path, body, now).unwrap_err();
+            assert!(err.to_string().contains("mismatch"), "{}", err);
+        }
+        let err = verifier.verify(&headers(&header), "POST", "/orders?dry=1", b"{}", now + 301).unwrap_err();
+        assert!(err.to_string().contains("tolerance"), "{}", err);
+
+        let other = signature_header("reports", "billing-secret", now, "POST", "/orders?dry=1", b"{}");
+        let wrong_secret = signature_header("billing", "guessed", now, "POST", "/orders?dry=1", b"{}");
+        for header in [other.as_str(), &wrong_secret, "client=billing,v1=00", "garbage"] {
+            let err = verifier.verify(&headers(header), "POST", "/orders?dry=1", b"{}", now).unwrap_err();
+            assert!(matches!(err, AppError::Unauthorized { .. }), "{}", header);
+        }
+        assert!(verifier.verify(&HeaderMap::new(), "GET", "/", b"", now).is_err());
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = Config {
+            signed_request_clients: vec![("billing".to_string(), "billing-secret".to_string())],
+            signature_tolerance_secs: 30,
+            ..Config::default()
+        };
+        let verifier = SignatureVerifier::from_config(&config);
+        assert!(!verifier.is_empty());
+        let header = signature_header("billing", "billing-secret", 100, "GET", "/", b"");
+        assert!(verifier.verify(&headers(&header), "GET", "/", b"", 130).is_ok());
+        assert!(verifier.verify(&headers(&header), "GET", "/", b"", 131).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_require_signature() {
+        let app = init_service(App::new().app_data(web::Data::new(verifier())).route(
+            "/orders",
+            web::post()
+                .to(|context: RequestContext, body: web::Bytes| async move {
+                    HttpResponse::Ok().body(format!("{}:{}", context.principal.unwrap().subject(), body.len()))
+                })
+                .wrap(from_fn(require_signature)),
+        ))
+        .await;
+
+        let now = chrono::Utc::now().timestamp();
+        let header = signature_header("billing", "billing-secret", now, "POST", "/orders", b"{\"id\":1}");
+        let res = call_service(
+            &app,
+            TestRequest::post()
+                .uri("/orders")
+                .insert_header((SIGNATURE_HEADER, header.clone()))
+                .set_payload("{\"id\":1}")
+                .to_request(),
+        )
+        .await;
+        // The handler still receives the body the middleware read
+        assert_eq!(read_body(res).await, "billing:8");
+
+        for request in [
+            TestRequest::post().uri("/orders").set_payload("{\"id\":1}"),
+            TestRequest::post()
+                .uri("/orders")
+                .insert_header((SIGNATURE_HEADER, header))
+                .set_payload("{\"id\":2}"),
+        ] {
+            let err = try_call_service(&app, request.to_request()).await.err().unwrap();
+            assert_eq!(err.error_response().status(), 401);
+        }
+    }
+}
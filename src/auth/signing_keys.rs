@@ -0,0 +1,488 @@
+//! ES256 signing keys shared by replicas through the state store, rotated on
+//! schedule by the `jwt_key_rotation` task or on demand. Retired keys keep
+//! verifying, and stay in the JWKS, during their grace period.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use log::{error, info};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::crypto::Cipher;
+use crate::error::{AppError, AppResult};
+use crate::state::KeyValueStore;
+use crate::supervisor::Supervisor;
+
+/// Scope required to rotate the signing key through `POST /admin/jwt/rotate`
+pub const KEYS_ADMIN_SCOPE: &str = "admin:keys";
+
+/// How often replicas pick up keys rotated elsewhere and check whether a
+/// scheduled rotation is due
+pub const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// State store entry holding the key ring
+const KEYRING_KEY: &str = "keyring";
+
+/// State store entry held while a replica rotates the key
+const ROTATION_LOCK_KEY: &str = "rotation_lock";
+
+/// Algorithm access and purpose tokens are signed with (`JWT_ALGORITHM`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    /// HMAC with the shared `JWT_SECRET`
+    #[default]
+    Hs256,
+    /// ECDSA P-256 with rotating keys published at `/.well-known/jwks.json`
+    Es256,
+}
+
+impl FromStr for JwtAlgorithm {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_uppercase().as_str() {
+            "HS256" => Ok(JwtAlgorithm::Hs256),
+            "ES256" => Ok(JwtAlgorithm::Es256),
+            other => Err(AppError::config(format!(
+                "unknown JWT algorithm '{}' (expected HS256 or ES256)",
+                other
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for JwtAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            JwtAlgorithm::Hs256 => "HS256",
+            JwtAlgorithm::Es256 => "ES256",
+        })
+    }
+}
+
+/// One ES256 key pair of a [`SigningKeyRing`]
+#[derive(Clone)]
+pub struct SigningKey {
+    kid: String,
+    created_at: i64,
+    retired_at: Option<i64>,
+    pkcs8: Vec<u8>,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Value,
+}
+
+impl SigningKey {
+    /// Generates a new key pair
+    fn generate(now: i64) -> AppResult<Self> {
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+            .map_err(|_| AppError::internal("failed to generate a signing key"))?;
+        Self::from_pkcs8(pkcs8.as_ref().to_vec(), now, None)
+    }
+
+    fn from_pkcs8(pkcs8: Vec<u8>, created_at: i64, retired_at: Option<i64>) -> AppResult<Self> {
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &SystemRandom::new())
+            .map_err(|e| AppError::internal(format!("unusable signing key: {}", e)))?;
+        // Uncompressed point: 0x04 || x || y
+        let public = pair.public_key().as_ref();
+        let (x, y) = (
+            URL_SAFE_NO_PAD.encode(&public[1..33]),
+            URL_SAFE_NO_PAD.encode(&public[33..65]),
+        );
+        // RFC 7638 thumbprint: members in lexicographic order, no whitespace
+        let thumbprint = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        let kid = URL_SAFE_NO_PAD.encode(Sha256::digest(thumbprint.as_bytes()));
+        let decoding = DecodingKey::from_ec_components(&x, &y)
+            .map_err(|e| AppError::internal(format!("unusable signing key: {}", e)))?;
+        Ok(Self {
+            jwk: json!({ "kty": "EC", "crv": "P-256", "x": x, "y": y, "kid": kid, "alg": "ES256", "use": "sig" }),
+            encoding: EncodingKey::from_ec_der(&pkcs8),
+            decoding,
+            kid,
+            created_at,
+            retired_at,
+            pkcs8,
+        })
+    }
+
+    /// Key id written into the `kid` header of the tokens it signs
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// Private key, for signing
+    pub fn encoding(&self) -> &EncodingKey {
+        &self.encoding
+    }
+
+    fn retired(&self, now: i64) -> Self {
+        Self {
+            retired_at: Some(now),
+            ..self.clone()
+        }
+    }
+}
+
+/// Key ring entry as kept in the state store, private key encrypted
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    kid: String,
+    created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retired_at: Option<i64>,
+    private_key: String,
+}
+
+/// ES256 signing keys of the token service, with rotation
+///
+/// The newest key signs; older keys are retired by a rotation but keep
+/// verifying for `JWT_KEY_GRACE_SECS`, so tokens issued just before stay
+/// valid, and are published at `/.well-known/jwks.json` until then. The ring
+/// lives in the state store with its private keys encrypted by the
+/// data-at-rest [`Cipher`], so every replica signs with the same key and a
+/// rotation on one is picked up by the others within
+/// [`ROTATION_CHECK_INTERVAL`] (or as soon as they see a token with the new
+/// `kid`).
+pub struct SigningKeyRing {
+    store: Arc<dyn KeyValueStore>,
+    cipher: Cipher,
+    /// Newest first; the first key signs
+    keys: RwLock<Vec<Arc<SigningKey>>>,
+    rotation: Option<Duration>,
+    grace: Duration,
+}
+
+impl SigningKeyRing {
+    /// Loads the ring from `store`, creating its first key when empty
+    ///
+    /// # Arguments
+    /// * `rotation` - Age at which the signing key is rotated; never when `None`
+    /// * `grace` - How long retired keys keep verifying
+    ///
+    /// # Errors
+    /// Returns an error when the stored ring cannot be read or decrypted
+    pub fn new(
+        store: Arc<dyn KeyValueStore>,
+        cipher: Cipher,
+        rotation: Option<Duration>,
+        grace: Duration,
+    ) -> AppResult<Self> {
+        let ring = Self {
+            store,
+            cipher,
+            keys: RwLock::new(Vec::new()),
+            rotation,
+            grace,
+        };
+        if !ring.reload()? {
+            let first = vec![Arc::new(SigningKey::generate(chrono::Utc::now().timestamp())?)];
+            // Another replica may have created the ring meanwhile; use theirs then
+            if ring.store.set_if_absent(KEYRING_KEY, &ring.serialize(&first)?, None)? {
+                info!("Created token signing key {}", first[0].kid);
+                ring.replace(first);
+            } else {
+                ring.reload()?;
+            }
+        }
+        Ok(ring)
+    }
+
+    /// Builds the ring from the `JWT_*` settings; `None` unless
+    /// `JWT_ALGORITHM=ES256`
+    ///
+    /// # Errors
+    /// Returns an error when the stored ring cannot be read or decrypted
+    pub fn from_config(config: &Config, store: Arc<dyn KeyValueStore>) -> AppResult<Option<Self>> {
+        if config.jwt_algorithm != JwtAlgorithm::Es256 {
+            return Ok(None);
+        }
+        let rotation = (config.jwt_key_rotation_secs > 0).then(|| Duration::from_secs(config.jwt_key_rotation_secs));
+        Self::new(
+            store,
+            Cipher::from_config(config),
+            rotation,
+            Duration::from_secs(config.jwt_key_grace_secs),
+        )
+        .map(Some)
+    }
+
+    /// The key signing new tokens
+    pub fn current(&self) -> AppResult<Arc<SigningKey>> {
+        self.keys
+            .read()
+            .ok()
+            .and_then(|keys| keys.first().cloned())
+            .ok_or_else(|| AppError::internal("no token signing key"))
+    }
+
+    /// Public key of `kid`, for verification
+    ///
+    /// An unknown `kid` reloads the ring once, in case another replica
+    /// rotated the key since.
+    pub fn decoding_key(&self, kid: &str) -> Option<DecodingKey> {
+        let find = || {
+            self.keys
+                .read()
+                .ok()?
+                .iter()
+                .find(|key| key.kid == kid)
+                .map(|key| key.decoding.clone())
+        };
+        find().or_else(|| {
+            self.reload().ok()?;
+            find()
+        })
+    }
+
+    /// The JSON Web Key Set of every key that still verifies
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<Value> = self
+            .keys
+            .read()
+            .map(|keys| keys.iter().map(|key| key.jwk.clone()).collect())
+            .unwrap_or_default();
+        json!({ "keys": keys })
+    }
+
+    /// Makes a new key the signing key and retires the current one
+    ///
+    /// Returns the id of the new key.
+    ///
+    /// # Errors
+    /// Unavailable while another replica rotates, or when the state store
+    /// fails
+    pub fn rotate(&self) -> AppResult<String> {
+        self.locked(|| self.rotate_now(chrono::Utc::now().timestamp()))
+    }
+
+    /// Picks up keys rotated elsewhere, drops keys past their grace period
+    /// and rotates when the signing key is older than the rotation interval
+    ///
+    /// Returns the id of the new key when it rotated.
+    pub fn maintain(&self, now: i64) -> AppResult<Option<String>> {
+        self.reload()?;
+        let due = |ring: &Self| -> AppResult<bool> {
+            let age = now - ring.current()?.created_at;
+            Ok(ring.rotation.is_some_and(|rotation| age >= rotation.as_secs() as i64))
+        };
+        let expired = self
+            .keys
+            .read()
+            .ok()
+            .is_some_and(|keys| keys.iter().any(|key| self.expired(key, now)));
+        if !due(self)? && !expired {
+            return Ok(None);
+        }
+        self.locked(|| {
+            // Re-read under the lock: another replica may have rotated already
+            self.reload()?;
+            if due(self)? {
+                return self.rotate_now(now).map(Some);
+            }
+            let keys = self.keys.read().map(|keys| keys.clone()).unwrap_or_default();
+            self.save(keys.into_iter().filter(|key| !self.expired(key, now)).collect())?;
+            Ok(None)
+        })
+    }
+
+    /// Checks the ring every [`ROTATION_CHECK_INTERVAL`] under `supervisor`
+    pub fn spawn_rotation(ring: Arc<Self>, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("jwt_key_rotation", move || {
+            let ring = ring.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(ROTATION_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    match ring.maintain(chrono::Utc::now().timestamp()) {
+                        Ok(Some(kid)) => info!("Rotated the token signing key on schedule; now signing with {}", kid),
+                        Ok(None) => {}
+                        Err(e) => error!("Token signing key maintenance failed: {}", e),
+                    }
+                }
+            }
+        });
+    }
+
+    fn rotate_now(&self, now: i64) -> AppResult<String> {
+        self.reload()?;
+        let next = Arc::new(SigningKey::generate(now)?);
+        let kid = next.kid.clone();
+        let keys = self.keys.read().map(|keys| keys.clone()).unwrap_or_default();
+        let mut rotated = vec![next];
+        for key in keys {
+            let key = if key.retired_at.is_none() {
+                Arc::new(key.retired(now))
+            } else {
+                key
+            };
+            if !self.expired(&key, now) {
+                rotated.push(key);
+            }
+        }
+        self.save(rotated)?;
+        info!("Rotated the token signing key; now signing with {}", kid);
+        Ok(kid)
+    }
+
+    fn expired(&self, key: &SigningKey, now: i64) -> bool {
+        key.retired_at
+            .is_some_and(|retired_at| now - retired_at > self.grace.as_secs() as i64)
+    }
+
+    /// Runs `op` holding the rotation lock shared by every replica
+    fn locked<T>(&self, op: impl FnOnce() -> AppResult<T>) -> AppResult<T> {
+        if !self
+            .store
+            .set_if_absent(ROTATION_LOCK_KEY, "1", Some(ROTATION_CHECK_INTERVAL))?
+        {
+            return Err(AppError::unavailable("a signing key rotation is already in progress"));
+        }
+        let result = op();
+        let _ = self.store.delete(ROTATION_LOCK_KEY);
+        result
+    }
+
+    /// Replaces the keys with the stored ring; returns whether one was stored
+    fn reload(&self) -> AppResult<bool> {
+        let Some(stored) = self.store.get(KEYRING_KEY)? else {
+            return Ok(false);
+        };
+        let stored: Vec<StoredKey> = serde_json::from_str(&stored)
+            .map_err(|e| AppError::internal(format!("unreadable signing key ring: {}", e)))?;
+        let keys = stored
+            .into_iter()
+            .map(|key| {
+                let pkcs8 = self.cipher.decrypt(&key.private_key).map_err(|_| {
+                    AppError::config("stored token signing keys cannot be decrypted; check DATA_ENCRYPTION_KEY")
+                })?;
+                SigningKey::from_pkcs8(pkcs8, key.created_at, key.retired_at).map(Arc::new)
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+        if keys.is_empty() {
+            return Ok(false);
+        }
+        self.replace(keys);
+        Ok(true)
+    }
+
+    fn save(&self, keys: Vec<Arc<SigningKey>>) -> AppResult<()> {
+        self.store.set(KEYRING_KEY, &self.serialize(&keys)?, None)?;
+        self.replace(keys);
+        Ok(())
+    }
+
+    fn replace(&self, keys: Vec<Arc<SigningKey>>) {
+        if let Ok(mut current) = self.keys.write() {
+            *current = keys;
+        }
+    }
+
+    fn serialize(&self, keys: &[Arc<SigningKey>]) -> AppResult<String> {
+        let stored = keys
+            .iter()
+            .map(|key| {
+                Ok(StoredKey {
+                    kid: key.kid.clone(),
+                    created_at: key.created_at,
+                    retired_at: key.retired_at,
+                    private_key: self.cipher.encrypt(&key.pkcs8)?,
+                })
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+        serde_json::to_string(&stored).map_err(|e| AppError::internal(format!("unwritable signing key ring: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    const HOUR: i64 = 3600;
+
+    fn ring(store: &Arc<InMemoryStore>) -> SigningKeyRing {
+        SigningKeyRing::new(
+            store.clone(),
+            Cipher::new(b"keyring-test-key-keyring-test-ke"),
+            Some(Duration::from_secs(24 * HOUR as u64)),
+            Duration::from_secs(HOUR as u64),
+        )
+        .unwrap()
+    }
+
+    fn kids(ring: &SigningKeyRing) -> Vec<String> {
+        ring.jwks()["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["kid"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_replicas_share_the_ring() {
+        let store = Arc::new(InMemoryStore::new());
+        let (first, second) = (ring(&store), ring(&store));
+        assert_eq!(first.current().unwrap().kid(), second.current().unwrap().kid());
+
+        let jwk = &first.jwks()["keys"][0];
+        assert_eq!((jwk["kty"].as_str(), jwk["alg"].as_str()), (Some("EC"), Some("ES256")));
+        assert!(jwk.get("d").is_none());
+        assert!(!store
+            .get(KEYRING_KEY)
+            .unwrap()
+            .unwrap()
+            .contains(&URL_SAFE_NO_PAD.encode(&first.current().unwrap().pkcs8)));
+
+        // A rotation elsewhere is seen as soon as its kid shows up
+        let kid = first.rotate().unwrap();
+        assert!(second.decoding_key(&kid).is_some());
+        assert_eq!(second.current().unwrap().kid(), kid);
+        assert!(second.decoding_key("unknown").is_none());
+    }
+
+    #[test]
+    fn test_retired_keys_verify_until_the_grace_period_ends() {
+        let store = Arc::new(InMemoryStore::new());
+        let ring = ring(&store);
+        let old = ring.current().unwrap().kid().to_string();
+        let created = ring.current().unwrap().created_at;
+
+        assert_eq!(ring.maintain(created + HOUR).unwrap(), None);
+        let new = ring.maintain(created + 24 * HOUR).unwrap().unwrap();
+        assert_eq!(kids(&ring), vec![new.clone(), old.clone()]);
+
+        assert_eq!(ring.maintain(created + 25 * HOUR).unwrap(), None);
+        assert_eq!(kids(&ring).len(), 2);
+        ring.maintain(created + 25 * HOUR + 1).unwrap();
+        assert_eq!(kids(&ring), vec![new]);
+        assert!(ring.decoding_key(&old).is_none());
+    }
+
+    #[test]
+    fn test_rotation_is_exclusive() {
+        let store = Arc::new(InMemoryStore::new());
+        let ring = ring(&store);
+        store.set(ROTATION_LOCK_KEY, "1", None).unwrap();
+        assert!(matches!(ring.rotate(), Err(AppError::Unavailable { .. })));
+        store.delete(ROTATION_LOCK_KEY).unwrap();
+        assert!(ring.rotate().is_ok());
+    }
+
+    #[test]
+    fn test_algorithm_parsing() {
+        assert_eq!("es256".parse::<JwtAlgorithm>().unwrap(), JwtAlgorithm::Es256);
+        assert_eq!(JwtAlgorithm::default().to_string(), "HS256");
+        assert!("RS256".parse::<JwtAlgorithm>().is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! JWT issuance and verification with a shared HS256 secret or the ES256 keys
+//! of a [`SigningKeyRing`].
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::signing_keys::{JwtAlgorithm, SigningKeyRing};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+
+/// JWT claims used by tokens issued and accepted by this service
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    /// Subject (user or client id)
+    pub sub: String,
+    /// Issuer
+    pub iss: String,
+    /// Expiry, seconds since the epoch
+    pub exp: i64,
+    /// Issued at, seconds since the epoch
+    pub iat: i64,
+    /// Unique token id, used for revocation
+    pub jti: String,
+    /// Space-separated OAuth scopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Session the token was issued for, used for revocation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    /// Party acting on behalf of the subject (RFC 8693), set on impersonation tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    /// Any other claims
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Actor claim identifying who is acting as the subject
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: String,
+}
+
+impl Claims {
+    /// Returns the granted scopes
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scope
+            .as_deref()
+            .map(|scope| scope.split_whitespace().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns whether `scope` was granted
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes().contains(&scope)
+    }
+
+    /// Returns the roles listed in the `roles` claim
+    pub fn roles(&self) -> Vec<String> {
+        self.extra
+            .get("roles")
+            .and_then(Value::as_array)
+            .map(|roles| roles.iter().filter_map(Value::as_str).map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Signs and verifies JSON Web Tokens
+///
+/// Tokens are HS256 with a shared secret, or ES256 with the rotating keys of
+/// a [`SigningKeyRing`] once one is attached.
+pub struct TokenService {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    keys: Option<Arc<SigningKeyRing>>,
+    issuer: String,
+    audience: Option<String>,
+    validation: Validation,
+}
+
+impl TokenService {
+    /// Creates a service signing with `secret` and issuing as `issuer`
+    pub fn new(secret: &[u8], issuer: &str) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+        validation.leeway = 5;
+
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            keys: None,
+            issuer: issuer.to_string(),
+            audience: None,
+            validation,
+        }
+    }
+
+    /// Derives a service for single-purpose tokens (email verification, ...)
+    ///
+    /// Tokens carry `aud = audience` and are only accepted by a service
+    /// derived for the same audience. Plain services reject any token with an
+    /// audience, so purpose tokens can never be used as access tokens.
+    pub fn for_audience(&self, audience: &str) -> Self {
+        let mut validation = self.validation.clone();
+        validation.set_audience(&[audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "sub", "aud"]);
+
+        Self {
+            encoding: self.encoding.clone(),
+            decoding: self.decoding.clone(),
+            keys: self.keys.clone(),
+            issuer: self.issuer.clone(),
+            audience: Some(audience.to_string()),
+            validation,
+        }
+    }
+
+    /// Signs and verifies with the ES256 keys of `keys` instead of the secret
+    pub fn with_signing_keys(mut self, keys: Arc<SigningKeyRing>) -> Self {
+        self.validation.algorithms = vec![Algorithm::ES256];
+        self.keys = Some(keys);
+        self
+    }
+
+    /// Builds the service from `JWT_SECRET`/`JWT_ISSUER`
+    ///
+    /// Without a configured secret an ephemeral one is generated, which means
+    /// tokens stop validating after a restart; a warning says so unless
+    /// `JWT_ALGORITHM=ES256` makes the secret unused.
+    pub fn from_config(config: &Config) -> Self {
+        let secret = match &config.jwt_secret {
+            Some(secret) => secret.clone(),
+            None if config.jwt_algorithm == JwtAlgorithm::Es256 => crate::secrets::generate_secret(),
+            None => {
+                warn!("JWT_SECRET is not set; using an ephemeral signing key (tokens won't survive restarts)");
+                crate::secrets::generate_secret()
+            }
+        };
+        Self::new(secret.as_bytes(), &config.jwt_issuer)
+    }
+
+    /// Returns the issuer written into and required from tokens
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Issues a token for `subject` with the given scopes and lifetime
+    pub fn issue(&self, subject: &str, scopes: &[&str], ttl: Duration) -> AppResult<String> {
+        self.sign(&self.claims(subject, scopes, ttl))
+    }
+
+    /// Builds fresh claims for `subject`, for callers adding extra claims before signing
+    pub fn claims(&self, subject: &str, scopes: &[&str], ttl: Duration) -> Claims {
+        let now = chrono::Utc::now().timestamp();
+        let mut extra = Map::new();
+        if let Some(audience) = &self.audience {
+            extra.insert("aud".to_string(), Value::String(audience.clone()));
+        }
+        Claims {
+            sub: subject.to_string(),
+            iss: self.issuer.clone(),
+            exp: now + ttl.as_secs() as i64,
+            iat: now,
+            jti: uuid::Uuid::new_v4().to_string(),
+            scope: (!scopes.is_empty()).then(|| scopes.join(" ")),
+            sid: None,
+            act: None,
+            extra,
+        }
+    }
+
+    /// Signs arbitrary claims
+    pub fn sign(&self, claims: &Claims) -> AppResult<String> {
+        let signed = match &self.keys {
+            Some(keys) => {
+                let key = keys.current()?;
+                let mut header = Header::new(Algorithm::ES256);
+                header.kid = Some(key.kid().to_string());
+                encode(&header, claims, key.encoding())
+            }
+            None => encode(&Header::new(Algorithm::HS256), claims, &self.encoding),
+        };
+        signed.map_err(|e| AppError::internal(format!("Failed to sign token: {}", e)))
+    }
+
+    /// Verifies signature, issuer and expiry and returns the claims
+    ///
+    /// # Errors
+    /// Returns an unauthorized error describing why the token was rejected
+    pub fn verify(&self, token: &str) -> AppResult<Claims> {
+        let decoding = match &self.keys {
+            Some(keys) => {
+                let kid = decode_header(token)
+                    .map_err(|e| AppError::unauthorized(format!("invalid token: {}", e)))?
+                    .kid
+                    .ok_or_else(|| AppError::unauthorized("invalid token: no key id"))?;
+                keys.decoding_key(&kid)
+                    .ok_or_else(|| AppError::unauthorized("invalid token: unknown or expired signing key"))?
+            }
+            None => self.decoding.clone(),
+        };
+        decode::<Claims>(token, &decoding, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| AppError::unauthorized(format!("invalid token: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> TokenService {
+        TokenService::new(b"0123456789abcdef0123456789abcdef", "test-issuer")
+    }
+
+    #[test]
+    fn test_issue_and_verify_round_trip() {
+        let service = service();
+        let token = service.issue("alice", &["read:private", "write"], Duration::from_secs(60)).unwrap();
+        let claims = service.verify(&token).unwrap();
+
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.iss, "test-issuer");
+        assert!(claims.has_scope("read:private"));
+        assert!(!claims.has_scope("admin"));
+    }
+
+    #[test]
+    fn test_rejects_expired_foreign_and_tampered_tokens() {
+        let service = service();
+        let mut claims = service.verify(&service.issue("bob", &[], Duration::from_secs(60)).unwrap()).unwrap();
+        claims.exp = chrono::Utc::now().timestamp() - 60;
+        assert!(service.verify(&service.sign(&claims).unwrap()).is_err());
+
+        let other = TokenService::new(b"another-secret-another-secret-xx", "test-issuer");
+        let foreign = other.issue("bob", &[], Duration::from_secs(60)).unwrap();
+        assert!(service.verify(&foreign).is_err());
+
+        let other_issuer = TokenService::new(b"0123456789abcdef0123456789abcdef", "someone-else");
+        let token = other_issuer.issue("bob", &[], Duration::from_secs(60)).unwrap();
+        assert!(matches!(service.verify(&token), Err(AppError::Unauthorized { .. })));
+    }
+
+    #[test]
+    fn test_audience_tokens_are_not_access_tokens() {
+        let service = service();
+        let reset = service.for_audience("password_reset");
+        let token = reset.issue("alice", &[], Duration::from_secs(60)).unwrap();
+
+        assert_eq!(reset.verify(&token).unwrap().sub, "alice");
+        assert!(service.verify(&token).is_err());
+        assert!(service.for_audience("email_verify").verify(&token).is_err());
+
+        let access = service.issue("alice", &[], Duration::from_secs(60)).unwrap();
+        assert!(reset.verify(&access).is_err());
+    }
+
+    #[test]
+    fn test_rotating_es256_keys() {
+        let keys = Arc::new(
+            SigningKeyRing::new(
+                Arc::new(crate::state::InMemoryStore::new()),
+                crate::crypto::Cipher::new(b"tokens-test-key-tokens-test-key-"),
+                None,
+                Duration::from_secs(3600),
+            )
+            .unwrap(),
+        );
+        let service = service().with_signing_keys(keys.clone());
+        let before = service.issue("alice", &[], Duration::from_secs(60)).unwrap();
+        assert_eq!(decode_header(&before).unwrap().alg, Algorithm::ES256);
+
+        keys.rotate().unwrap();
+        let after = service.for_audience("password_reset").issue("alice", &[], Duration::from_secs(60)).unwrap();
+        assert_ne!(decode_header(&before).unwrap().kid, decode_header(&after).unwrap().kid);
+        assert_eq!(service.verify(&before).unwrap().sub, "alice");
+        assert_eq!(service.for_audience("password_reset").verify(&after).unwrap().sub, "alice");
+
+        // HS256 tokens signed with the secret are no longer accepted
+        assert!(service.verify(&self::service().issue("alice", &[], Duration::from_secs(60)).unwrap()).is_err());
+    }
+}
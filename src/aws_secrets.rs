@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+use std::env;
+
+use crate::config::SecretProvider;
+use crate::error::{AppError, AppResult};
+
+#[cfg(feature = "aws")]
+pub use self::client::{AwsClient, AwsCredentials};
+
+/// Scheme of values resolved from AWS Secrets Manager
+pub const SECRETS_MANAGER_SCHEME: &str = "aws-sm://";
+
+/// Scheme of values resolved from the SSM Parameter Store
+pub const PARAMETER_STORE_SCHEME: &str = "ssm://";
+
+/// Configuration value stored in AWS instead of the environment
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AwsSecretRef {
+    /// `aws-sm://prod/api` or `aws-sm://prod/api#jwt_secret`: the secret
+    /// string, or one key of a JSON secret
+    SecretsManager { secret_id: String, key: Option<String> },
+    /// `ssm://name` or `ssm:///prod/api/jwt`: a parameter, decrypted when it
+    /// is a `SecureString`
+    Parameter { name: String },
+}
+
+impl AwsSecretRef {
+    /// Parses the value of `var`; `None` for plain values
+    ///
+    /// # Errors
+    /// Returns an environment error for a reference without a name
+    pub fn parse(var: &str, value: &str) -> AppResult<Option<Self>> {
+        let reference = if let Some(location) = value.strip_prefix(SECRETS_MANAGER_SCHEME) {
+            let (secret_id, key) = match location.split_once('#') {
+                Some((secret_id, key)) => (secret_id, Some(key.to_string())),
+                None => (location, None),
+            };
+            if secret_id.is_empty() || key.as_deref() == Some("") {
+                return Err(AppError::environment(var, format!("expected aws-sm://secret-id[#key], got: {}", value)));
+            }
+            Self::SecretsManager {
+                secret_id: secret_id.to_string(),
+                key,
+            }
+        } else if let Some(name) = value.strip_prefix(PARAMETER_STORE_SCHEME) {
+            if name.is_empty() {
+                return Err(AppError::environment(var, format!("expected ssm://parameter-name, got: {}", value)));
+            }
+            Self::Parameter { name: name.to_string() }
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(reference))
+    }
+}
+
+/// [`SecretProvider`] resolving variables whose value is an `aws-sm://` or
+/// `ssm://` reference
+///
+/// [`load`](Self::load) fetches every referenced secret once at startup
+/// (each Secrets Manager secret a single time, however many keys are used),
+/// so the configuration is built from the resolved values and no secret has
+/// to be baked into the environment. Resolving needs the `aws` feature;
+/// without it a reference fails the start instead of being taken as the
+/// literal secret.
+pub struct AwsSecrets {
+    references: Vec<(String, AwsSecretRef)>,
+    values: HashMap<String, String>,
+}
+
+impl AwsSecrets {
+    /// Collects the references among `vars`; `None` when there are none
+    ///
+    /// # Errors
+    /// Same as [`AwsSecretRef::parse`]
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> AppResult<Option<Self>> {
+        let mut references = Vec::new();
+        for (var, value) in vars {
+            if let Some(reference) = AwsSecretRef::parse(&var, &value)? {
+                references.push((var, reference));
+            }
+        }
+        references.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok((!references.is_empty()).then(|| Self {
+            references,
+            values: HashMap::new(),
+        }))
+    }
+
+    /// Collects the references in the process environment
+    ///
+    /// # Errors
+    /// Same as [`AwsSecretRef::parse`]
+    pub fn from_env() -> AppResult<Option<Self>> {
+        Self::from_vars(env::vars())
+    }
+
+    /// Variables holding a reference, with their reference
+    pub fn references(&self) -> &[(String, AwsSecretRef)] {
+        &self.references
+    }
+
+    /// Fetches every referenced secret with the default AWS credentials
+    ///
+    /// # Errors
+    /// Config error without the `aws` feature or credentials, or for a
+    /// missing key; unavailable when AWS refuses or cannot be reached
+    #[cfg(feature = "aws")]
+    pub async fn load(&mut self) -> AppResult<()> {
+        let client = AwsClient::new(AwsCredentials::from_env()?)?;
+        self.load_with(&client).await
+    }
+
+    /// Fails: the binary was built without the `aws` feature
+    #[cfg(not(feature = "aws"))]
+    pub async fn load(&mut self) -> AppResult<()> {
+        Err(AppError::config(format!(
+            "{} references a secret in AWS, which requires building with the `aws` feature",
+            self.references[0].0
+        )))
+    }
+
+    /// Fetches every referenced secret through `client`
+    ///
+    /// # Errors
+    /// Same as [`load`](Self::load)
+    #[cfg(feature = "aws")]
+    pub async fn load_with(&mut self, client: &AwsClient) -> AppResult<()> {
+        let mut secret_strings: HashMap<String, String> = HashMap::new();
+        for (var, reference) in &self.references {
+            let value = match reference {
+                AwsSecretRef::SecretsManager { secret_id, key } => {
+                    if !secret_strings.contains_key(secret_id) {
+                        secret_strings.insert(secret_id.clone(), client.secret_string(secret_id).await?);
+                    }
+                    let secret = &secret_strings[secret_id];
+                    match key {
+                        Some(key) => json_key(secret, key).ok_or_else(|| {
+                            AppError::config(format!("AWS secret {} has no key {} (referenced by {})", secret_id, key, var))
+                        })?,
+                        None => secret.clone(),
+                    }
+                }
+                AwsSecretRef::Parameter { name } => client.parameter(name).await?,
+            };
+            self.values.insert(var.clone(), value);
+        }
+        log::info!("Resolved {} configuration values from AWS", self.values.len());
+        Ok(())
+    }
+}
+
+impl SecretProvider for AwsSecrets {
+    fn secret(&self, name: &str) -> Option<String> {
+        self.values.get(name).cloned()
+    }
+}
+
+/// Value of `key` in a JSON object secret, such as the ones the console creates
+#[cfg(feature = "aws")]
+fn json_key(secret: &str, key: &str) -> Option<String> {
+    match serde_json::from_str::<serde_json::Value>(secret).ok()?.get(key)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+#[cfg(feature = "aws")]
+mod client {
+    use std::env;
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+    use hmac::{Hmac, Mac};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+
+    use crate::error::{AppError, AppResult};
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    /// Timeout of every AWS request
+    const AWS_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Static credentials and region, as exported for the AWS CLI
+    ///
+    /// Deliberately not `Debug`: it holds the secret key.
+    #[derive(Clone, PartialEq, Eq)]
+    pub struct AwsCredentials {
+        pub access_key_id: String,
+        pub secret_access_key: String,
+        /// Set for temporary credentials (assumed roles, ECS/Lambda task roles)
+        pub session_token: Option<String>,
+        pub region: String,
+    }
+
+    impl AwsCredentials {
+        /// Reads `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+        /// `AWS_SESSION_TOKEN` and `AWS_REGION` (or `AWS_DEFAULT_REGION`)
+        ///
+        /// # Errors
+        /// Returns an environment error naming the first missing variable
+        pub fn from_env() -> AppResult<Self> {
+            let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+            let required = |name: &str| {
+                var(name).ok_or_else(|| AppError::environment(name, "required to resolve aws-sm:// and ssm:// values"))
+            };
+            Ok(Self {
+                access_key_id: required("AWS_ACCESS_KEY_ID")?,
+                secret_access_key: required("AWS_SECRET_ACCESS_KEY")?,
+                session_token: var("AWS_SESSION_TOKEN"),
+                region: var("AWS_REGION").map_or_else(|| required("AWS_DEFAULT_REGION"), Ok)?,
+            })
+        }
+    }
+
+    /// Minimal Secrets Manager and SSM client signing requests with SigV4
+    pub struct AwsClient {
+        credentials: AwsCredentials,
+        /// `AWS_ENDPOINT_URL`, e.g. LocalStack; the regional endpoints otherwise
+        endpoint: Option<String>,
+        http: reqwest::Client,
+    }
+
+    impl AwsClient {
+        /// Creates a client, honouring `AWS_ENDPOINT_URL`
+        ///
+        /// # Errors
+        /// Internal error when the HTTP client cannot be built
+        pub fn new(credentials: AwsCredentials) -> AppResult<Self> {
+            let endpoint = env::var("AWS_ENDPOINT_URL").ok().filter(|value| !value.is_empty());
+            Self::with_endpoint(credentials, endpoint)
+        }
+
+        /// Creates a client sending every request to `endpoint` when set
+        ///
+        /// # Errors
+        /// Internal error when the HTTP client cannot be built
+        pub fn with_endpoint(credentials: AwsCredentials, endpoint: Option<String>) -> AppResult<Self> {
+            let http = reqwest::Client::builder()
+                .timeout(AWS_TIMEOUT)
+                .build()
+                .map_err(|e| AppError::internal(format!("Failed to build the AWS client: {}", e)))?;
+            Ok(Self {
+                credentials,
+                endpoint,
+                http,
+            })
+        }
+
+        /// `SecretString` of a Secrets Manager secret (name or ARN)
+        ///
+        /// # Errors
+        /// Unavailable when AWS refuses the call, config error for binary secrets
+        pub async fn secret_string(&self, secret_id: &str) -> AppResult<String> {
+            let response = self
+                .call("secretsmanager", "secretsmanager.GetSecretValue", json!({ "SecretId": secret_id }))
+                .await?;
+            response["SecretString"]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| AppError::config(format!("AWS secret {} is not a string secret", secret_id)))
+        }
+
+        /// Decrypted value of an SSM parameter
+        ///
+        /// # Errors
+        /// Unavailable when AWS refuses the call or answers without a value
+        pub async fn parameter(&self, name: &str) -> AppResult<String> {
+            let response = self
+                .call("ssm", "AmazonSSM.GetParameter", json!({ "Name": name, "WithDecryption": true }))
+                .await?;
+            response["Parameter"]["Value"]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| AppError::unavailable(format!("SSM answered without a value for {}", name)))
+        }
+
+        /// Calls a JSON 1.1 protocol API
+        async fn call(&self, service: &str, target: &str, body: Value) -> AppResult<Value> {
+            let url = self
+                .endpoint
+                .clone()
+                .unwrap_or_else(|| format!("https://{}.{}.amazonaws.com/", service, self.credentials.region));
+            let url = reqwest::Url::parse(&url)
+                .map_err(|e| AppError::config(format!("Invalid AWS endpoint {}: {}", url, e)))?;
+            let host = match url.port() {
+                Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+                None => url.host_str().unwrap_or_default().to_string(),
+            };
+            let payload = body.to_string();
+            let now = Utc::now();
+            let mut headers = vec![
+                ("content-type", "application/x-amz-json-1.1".to_string()),
+                ("host", host),
+                ("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string()),
+                ("x-amz-target", target.to_string()),
+            ];
+            if let Some(token) = &self.credentials.session_token {
+                headers.push(("x-amz-security-token", token.clone()));
+            }
+            let authorization = authorization(&self.credentials, service, "POST", url.path(), &headers, payload.as_bytes(), now);
+
+            let mut request = self.http.post(url.clone()).header("authorization", authorization).body(payload);
+            for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+                request = request.header(name, value);
+            }
+            let response = request
+                .send()
+                .await
+                .map_err(|e| AppError::unavailable(format!("AWS {} unreachable: {}", service, e)))?;
+            let status = response.status();
+            let body: Value = response
+                .json()
+                .await
+                .map_err(|e| AppError::unavailable(format!("Invalid AWS {} response: {}", service, e)))?;
+            if !status.is_success() {
+                let kind = body["__type"].as_str().unwrap_or_default();
+                // `__type` may be qualified: `com.amazonaws...#ResourceNotFoundException`
+                let kind = kind.rsplit('#').next().unwrap_or(kind);
+                let message = body["message"].as_str().or(body["Message"].as_str()).unwrap_or_default();
+                return Err(AppError::unavailable(format!("AWS {} answered {}: {} {}", target, status, kind, message)));
+            }
+            Ok(body)
+        }
+    }
+
+    /// SigV4 `Authorization` header for a request with `headers` (lowercase
+    /// names, including `host` and `x-amz-date`) and no query string
+    pub(super) fn authorization(
+        credentials: &AwsCredentials,
+        service: &str,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        payload: &[u8],
+        now: DateTime<Utc>,
+    ) -> String {
+        let mut headers: Vec<(&str, &str)> = headers.iter().map(|(name, value)| (*name, value.trim())).collect();
+        headers.sort();
+        let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method,
+            path,
+            canonical_headers,
+            signed_headers,
+            hex::encode(Sha256::digest(payload))
+        );
+
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/{}/aws4_request", date, credentials.region, service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            now.format("%Y%m%dT%H%M%SZ"),
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = [date.as_str(), credentials.region.as_str(), service, "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", credentials.secret_access_key).into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id,
+            scope,
+            signed_headers,
+            hex::encode(hmac(&key, string_to_sign.as_bytes()))
+        )
+    }
+
+    fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(AwsSecretRef::parse("JWT_ISSUER", "simple-api-demo").unwrap(), None);
+        assert_eq!(
+            AwsSecretRef::parse("JWT_SECRET", "aws-sm://prod/api#jwt_secret").unwrap(),
+            Some(AwsSecretRef::SecretsManager {
+                secret_id: "prod/api".to_string(),
+                key: Some("jwt_secret".to_string()),
+            })
+        );
+        assert_eq!(
+            AwsSecretRef::parse("REDIS_URL", "ssm:///prod/api/redis-url").unwrap(),
+            Some(AwsSecretRef::Parameter {
+                name: "/prod/api/redis-url".to_string(),
+            })
+        );
+        assert!(AwsSecretRef::parse("JWT_SECRET", "aws-sm://prod/api#").is_err());
+        assert!(AwsSecretRef::parse("JWT_SECRET", "ssm://").is_err());
+
+        assert!(AwsSecrets::from_vars(vars(&[("PORT", "8080")])).unwrap().is_none());
+        let secrets = AwsSecrets::from_vars(vars(&[("PORT", "8080"), ("JWT_SECRET", "aws-sm://prod/api")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(secrets.references().len(), 1);
+        // Nothing is provided before the references are resolved
+        assert_eq!(secrets.secret("JWT_SECRET"), None);
+    }
+
+    #[cfg(not(feature = "aws"))]
+    #[actix_web::test]
+    async fn test_references_require_feature() {
+        let mut secrets = AwsSecrets::from_vars(vars(&[("JWT_SECRET", "aws-sm://prod/api")])).unwrap().unwrap();
+        let err = secrets.load().await.unwrap_err();
+        assert!(err.to_string().contains("`aws` feature"), "{}", err);
+    }
+
+    #[cfg(feature = "aws")]
+    mod resolving {
+        use super::*;
+        use crate::aws_secrets::client::authorization;
+        use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+        use chrono::{TimeZone, Utc};
+        use serde_json::{json, Value};
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        fn credentials(region: &str) -> AwsCredentials {
+            AwsCredentials {
+                access_key_id: "AKIDEXAMPLE".to_string(),
+                secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+                session_token: None,
+                region: region.to_string(),
+            }
+        }
+
+        #[test]
+        fn test_signature_matches_aws_test_suite() {
+            // `get-vanilla` from the AWS SigV4 test suite
+            let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+            let headers = [
+                ("host", "example.amazonaws.com".to_string()),
+                ("x-amz-date", "20150830T123600Z".to_string()),
+            ];
+            assert_eq!(
+                authorization(&credentials("us-east-1"), "service", "GET", "/", &headers, b"", now),
+                "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+                 SignedHeaders=host;x-amz-date, \
+                 Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+            );
+        }
+
+        /// Fake Secrets Manager and SSM endpoint, counting the calls
+        async fn start_aws(calls: Arc<AtomicU64>) -> String {
+            let calls = web::Data::from(calls);
+            let server = HttpServer::new(move || {
+                App::new().app_data(calls.clone()).route(
+                    "/",
+                    web::post().to(|req: HttpRequest, body: web::Bytes, calls: web::Data<AtomicU64>| async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        let signed = req
+                            .headers()
+                            .get("authorization")
+                            .and_then(|value| value.to_str().ok())
+                            .is_some_and(|value| value.contains("Credential=AKIDEXAMPLE/") && value.contains("/eu-west-1/"));
+                        if !signed {
+                            return HttpResponse::Forbidden().json(json!({"__type": "UnrecognizedClientException"}));
+                        }
+                        let body: Value = serde_json::from_slice(&body).unwrap();
+                        let target = req.headers().get("x-amz-target").unwrap().to_str().unwrap();
+                        match (target, body["SecretId"].as_str(), body["Name"].as_str()) {
+                            ("secretsmanager.GetSecretValue", Some("prod/api"), _) => HttpResponse::Ok().json(json!({
+                                "SecretString": r#"{"jwt_secret":"from-secrets-manager","stripe":"whsec_aws"}"#,
+                            })),
+                            ("AmazonSSM.GetParameter", _, Some("/prod/api/issuer")) if body["WithDecryption"] == true => {
+                                HttpResponse::Ok().json(json!({"Parameter": {"Value": "aws-issuer"}}))
+                            }
+                            _ => HttpResponse::BadRequest().json(json!({
+                                "__type": "com.amazonaws#ResourceNotFoundException",
+                                "message": "not found",
+                            })),
+                        }
+                    }),
+                )
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+            let url = format!("http://{}/", server.addrs()[0]);
+            actix_web::rt::spawn(server.run());
+            url
+        }
+
+        #[actix_web::test]
+        async fn test_resolve_references() {
+            let calls = Arc::new(AtomicU64::new(0));
+            let endpoint = start_aws(calls.clone()).await;
+            let client = AwsClient::with_endpoint(credentials("eu-west-1"), Some(endpoint.clone())).unwrap();
+            let mut secrets = AwsSecrets::from_vars(vars(&[
+                ("JWT_SECRET", "aws-sm://prod/api#jwt_secret"),
+                ("WEBHOOK_STRIPE_SECRET", "aws-sm://prod/api#stripe"),
+                ("JWT_ISSUER", "ssm:///prod/api/issuer"),
+            ]))
+            .unwrap()
+            .unwrap();
+            secrets.load_with(&client).await.unwrap();
+            assert_eq!(secrets.secret("JWT_SECRET").as_deref(), Some("from-secrets-manager"));
+            assert_eq!(secrets.secret("WEBHOOK_STRIPE_SECRET").as_deref(), Some("whsec_aws"));
+            assert_eq!(secrets.secret("JWT_ISSUER").as_deref(), Some("aws-issuer"));
+            // Both keys come from a single read of the secret
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+            let mut missing = AwsSecrets::from_vars(vars(&[("JWT_SECRET", "aws-sm://prod/other")])).unwrap().unwrap();
+            let err = missing.load_with(&client).await.unwrap_err();
+            assert!(err.to_string().contains("ResourceNotFoundException"), "{}", err);
+            let mut no_key = AwsSecrets::from_vars(vars(&[("JWT_SECRET", "aws-sm://prod/api#nope")])).unwrap().unwrap();
+            assert!(matches!(no_key.load_with(&client).await, Err(AppError::Config { .. })));
+
+            let client = AwsClient::with_endpoint(credentials("us-east-1"), Some(endpoint)).unwrap();
+            let err = secrets.load_with(&client).await.unwrap_err();
+            assert!(err.to_string().contains("UnrecognizedClientException"), "{}", err);
+        }
+    }
+}
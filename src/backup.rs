@@ -0,0 +1,303 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::state::{InMemoryStore, StateManager};
+use crate::supervisor::Supervisor;
+
+/// Scope required to take a backup through `POST /admin/backup`
+pub const BACKUP_SCOPE: &str = "admin:backup";
+
+/// Snapshot format written by this version; other versions are refused
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// One key of the state store, as saved in a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub key: String,
+    pub value: String,
+    /// Remaining lifetime in milliseconds when the snapshot was taken,
+    /// `None` for keys that never expire
+    pub ttl_ms: Option<u64>,
+}
+
+/// Point-in-time copy of the in-memory state store
+///
+/// Expiring keys keep the lifetime they had left, counted again from the
+/// restore. The checksum covers the entries so a truncated or edited file is
+/// refused instead of half-loaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    /// Hex SHA-256 of the JSON-serialized entries
+    pub checksum: String,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+impl Snapshot {
+    /// Copies `store`, keys sorted
+    ///
+    /// # Errors
+    /// Internal error when the store lock is poisoned
+    pub fn capture(store: &InMemoryStore) -> AppResult<Self> {
+        let mut entries: Vec<SnapshotEntry> = store
+            .dump()?
+            .into_iter()
+            .map(|(key, value, ttl)| SnapshotEntry {
+                key,
+                value,
+                ttl_ms: ttl.map(|ttl| ttl.as_millis().min(u64::MAX as u128) as u64),
+            })
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(Self {
+            version: SNAPSHOT_VERSION,
+            created_at: Utc::now(),
+            checksum: checksum(&entries)?,
+            entries,
+        })
+    }
+
+    /// Reads and validates the snapshot file at `path`
+    ///
+    /// # Errors
+    /// Validation error when the file cannot be read or is not a valid snapshot
+    pub fn read(path: &Path) -> AppResult<Self> {
+        let json = fs::read(path)
+            .map_err(|e| AppError::validation(format!("cannot read snapshot {}: {}", path.display(), e)))?;
+        let snapshot: Self = serde_json::from_slice(&json)
+            .map_err(|e| AppError::validation(format!("snapshot {} is malformed: {}", path.display(), e)))?;
+        snapshot
+            .validate()
+            .map_err(|e| AppError::validation(format!("snapshot {} is invalid: {}", path.display(), e)))?;
+        Ok(snapshot)
+    }
+
+    /// Checks the format version, the checksum and that keys are unique
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(format!("unsupported version {} (expected {})", self.version, SNAPSHOT_VERSION));
+        }
+        if checksum(&self.entries).map_err(|e| e.to_string())? != self.checksum {
+            return Err("checksum mismatch".to_string());
+        }
+        let mut keys: Vec<&str> = self.entries.iter().map(|entry| entry.key.as_str()).collect();
+        keys.sort_unstable();
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(format!("key '{}' appears twice", pair[0]));
+        }
+        Ok(())
+    }
+
+    /// Replaces the content of `store` with the snapshot; returns the number of keys loaded
+    ///
+    /// # Errors
+    /// Validation error for an invalid snapshot, in which case `store` is left untouched
+    pub fn restore_into(&self, store: &InMemoryStore) -> AppResult<usize> {
+        self.validate().map_err(AppError::validation)?;
+        store.load(
+            self.entries
+                .iter()
+                .map(|entry| (entry.key.clone(), entry.value.clone(), entry.ttl_ms.map(Duration::from_millis))),
+        )
+    }
+}
+
+fn checksum(entries: &[SnapshotEntry]) -> AppResult<String> {
+    let json = serde_json::to_vec(entries).map_err(|e| AppError::internal(e.to_string()))?;
+    Ok(hex::encode(Sha256::digest(json)))
+}
+
+/// Snapshot written by [`BackupService::backup`], as answered by `POST /admin/backup`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BackupReport {
+    /// File name inside `BACKUP_DIR`
+    pub file: String,
+    pub created_at: DateTime<Utc>,
+    pub entries: usize,
+    pub bytes: u64,
+    pub checksum: String,
+}
+
+/// Writes snapshots of the in-memory state store to `BACKUP_DIR`
+///
+/// Snapshots are taken on `POST /admin/backup` and every
+/// `BACKUP_INTERVAL_SECS` when set; the newest `BACKUP_RETENTION` are kept.
+/// Only the shared store of `STATE_MODE=local` is covered: Redis has its
+/// own persistence, and stores made with
+/// [`StateManager::local_store`] are rebuilt at startup.
+pub struct BackupService {
+    store: Arc<InMemoryStore>,
+    dir: PathBuf,
+    retention: usize,
+}
+
+impl BackupService {
+    /// Creates the service writing to `dir`, created (mode 0700) if needed
+    ///
+    /// # Errors
+    /// Returns a config error when the directory cannot be created
+    pub fn open(store: Arc<InMemoryStore>, dir: impl Into<PathBuf>, retention: usize) -> AppResult<Self> {
+        let dir = dir.into();
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(&dir)
+            .map_err(|e| AppError::config(format!("Failed to create BACKUP_DIR {}: {}", dir.display(), e)))?;
+        Ok(Self { store, dir, retention })
+    }
+
+    /// Creates the service from `BACKUP_DIR` and `BACKUP_RETENTION`; `None`
+    /// when backups are off or the state store is not in memory
+    ///
+    /// # Errors
+    /// Returns a config error when the directory cannot be created
+    pub fn from_config(config: &Config, state: &StateManager) -> AppResult<Option<Self>> {
+        let Some(dir) = config.backup_dir.as_deref() else {
+            return Ok(None);
+        };
+        match state.embedded_store() {
+            Some(store) => Self::open(store, dir, config.backup_retention).map(Some),
+            None => {
+                warn!("BACKUP_DIR is ignored: only the in-memory state store (STATE_MODE=local) is backed up");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Writes a snapshot and deletes the oldest beyond the retention
+    ///
+    /// # Errors
+    /// Internal error when the snapshot cannot be written
+    pub fn backup(&self) -> AppResult<BackupReport> {
+        let snapshot = Snapshot::capture(&self.store)?;
+        let json = serde_json::to_vec(&snapshot).map_err(|e| AppError::internal(e.to_string()))?;
+        let file = format!("snapshot-{}.json", snapshot.created_at.format("%Y%m%dT%H%M%S%6fZ"));
+        let path = self.dir.join(&file);
+        // Written aside and renamed, so a crash never leaves a partial snapshot
+        let partial = path.with_extension("json.tmp");
+        fs::write(&partial, &json)
+            .and_then(|_| fs::rename(&partial, &path))
+            .map_err(|e| AppError::internal(format!("failed to write snapshot: {}", e)))?;
+
+        let files = self.snapshots()?;
+        for old in files.iter().take(files.len().saturating_sub(self.retention)) {
+            if let Err(e) = fs::remove_file(self.dir.join(old)) {
+                warn!("Failed to delete old snapshot {}: {}", old, e);
+            }
+        }
+        info!("State snapshot {} written ({} keys)", file, snapshot.entries.len());
+        Ok(BackupReport {
+            file,
+            created_at: snapshot.created_at,
+            entries: snapshot.entries.len(),
+            bytes: json.len() as u64,
+            checksum: snapshot.checksum,
+        })
+    }
+
+    /// Snapshot file names, oldest first
+    fn snapshots(&self) -> AppResult<Vec<String>> {
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| AppError::internal(format!("failed to read BACKUP_DIR: {}", e)))?;
+        let mut files: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+            .filter(|name| name.starts_with("snapshot-") && name.ends_with(".json"))
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /// Takes a snapshot every `every` under `supervisor`
+    pub fn spawn_scheduler(service: Arc<Self>, every: Duration, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("backup", move || {
+            let service = service.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(every);
+                // The first tick is immediate; nothing worth saving exists yet
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let service = service.clone();
+                    let result = actix_web::web::block(move || service.backup()).await;
+                    if let Err(e) = result.map_err(|e| AppError::internal(e.to_string())).and_then(|result| result) {
+                        error!("Scheduled backup failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::KeyValueStore;
+
+    #[test]
+    fn test_backup_and_restore_round_trip() {
+        let dir = std::env::temp_dir().join(format!("backup-test-{}", uuid::Uuid::new_v4()));
+        let store = Arc::new(InMemoryStore::new());
+        store.set("sessions:a", "alice", None).unwrap();
+        store.set("lockout:b", "3", Some(Duration::from_secs(600))).unwrap();
+        let service = BackupService::open(store.clone(), &dir, 2).unwrap();
+
+        let report = service.backup().unwrap();
+        assert_eq!(report.entries, 2);
+        for _ in 0..2 {
+            service.backup().unwrap();
+        }
+        assert_eq!(service.snapshots().unwrap().len(), 2);
+        let newest = service.snapshots().unwrap().pop().unwrap();
+
+        let restored = InMemoryStore::new();
+        restored.set("stale", "x", None).unwrap();
+        let snapshot = Snapshot::read(&dir.join(newest)).unwrap();
+        assert_eq!(snapshot.restore_into(&restored).unwrap(), 2);
+        assert_eq!(restored.get("sessions:a").unwrap(), Some("alice".to_string()));
+        assert_eq!(restored.get("stale").unwrap(), None);
+        let ttl = snapshot.entries.iter().find(|entry| entry.key == "lockout:b").unwrap().ttl_ms;
+        assert!(ttl.is_some_and(|ms| ms > 0 && ms <= 600_000));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_snapshots_are_refused() {
+        let store = InMemoryStore::new();
+        store.set("key", "value", None).unwrap();
+        let snapshot = Snapshot::capture(&store).unwrap();
+
+        let mut tampered = snapshot.clone();
+        tampered.entries[0].value = "other".to_string();
+        assert_eq!(tampered.validate(), Err("checksum mismatch".to_string()));
+        let mut future = snapshot.clone();
+        future.version = SNAPSHOT_VERSION + 1;
+        assert!(future.validate().is_err());
+        let mut duplicated = snapshot.clone();
+        duplicated.entries.push(duplicated.entries[0].clone());
+        duplicated.checksum = checksum(&duplicated.entries).unwrap();
+        assert_eq!(duplicated.validate(), Err("key 'key' appears twice".to_string()));
+
+        let target = InMemoryStore::new();
+        target.set("kept", "1", None).unwrap();
+        assert!(matches!(tampered.restore_into(&target), Err(AppError::Validation { .. })));
+        assert_eq!(target.get("kept").unwrap(), Some("1".to_string()));
+
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", uuid::Uuid::new_v4()));
+        fs::write(&path, "{\"version\": 1").unwrap();
+        assert!(matches!(Snapshot::read(&path), Err(AppError::Validation { .. })));
+        fs::remove_file(path).unwrap();
+    }
+}
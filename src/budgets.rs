@@ -0,0 +1,190 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use log::debug;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+
+/// Backend a [`Budget`] applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Reads from the state store
+    DbRead,
+    /// Writes to the state store: counters, sessions, cached entries
+    Cache,
+    /// Outbound notification deliveries (Slack, webhook)
+    Webhook,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::DbRead => "state store read",
+            Backend::Cache => "state store write",
+            Backend::Webhook => "notification delivery",
+        })
+    }
+}
+
+/// Timeout of one call to a backend and how often a failed call is retried
+///
+/// The configuration guarantees `timeout * (retries + 1)` fits in
+/// `REQUEST_DEADLINE_SECS`, so a dependency can never outlast the request
+/// waiting on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    pub backend: Backend,
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl Budget {
+    /// Creates a budget for `backend`
+    pub fn new(backend: Backend, timeout: Duration, retries: u32) -> Self {
+        Self {
+            backend,
+            timeout,
+            retries,
+        }
+    }
+
+    /// The error a call exceeding the budget fails with
+    pub fn timed_out(&self) -> AppError {
+        AppError::unavailable(format!("{} timed out after {}ms", self.backend, self.timeout.as_millis()))
+    }
+
+    /// Runs a blocking call, retrying it on failure
+    ///
+    /// Blocking clients cannot be interrupted, so `op` must enforce the
+    /// timeout itself (a socket timeout for Redis).
+    pub fn retry<T>(&self, mut op: impl FnMut() -> AppResult<T>) -> AppResult<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    debug!("Retrying {} ({}/{}): {}", self.backend, attempt, self.retries, e);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs an async call under the timeout, retrying it on failure
+    pub async fn run<T, F, Fut>(&self, mut op: F) -> AppResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = AppResult<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = tokio::time::timeout(self.timeout, op())
+                .await
+                .unwrap_or_else(|_| Err(self.timed_out()));
+            match result {
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    debug!("Retrying {} ({}/{}): {}", self.backend, attempt, self.retries, e);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// The per-backend budgets from the `*_TIMEOUT_MS` and `*_RETRIES` settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budgets {
+    pub db_read: Budget,
+    pub cache: Budget,
+    pub webhook: Budget,
+}
+
+impl Budgets {
+    /// Builds the budgets from the configuration
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            db_read: Budget::new(
+                Backend::DbRead,
+                Duration::from_millis(config.db_read_timeout_ms),
+                config.db_read_retries,
+            ),
+            cache: Budget::new(Backend::Cache, Duration::from_millis(config.cache_timeout_ms), config.cache_retries),
+            webhook: Budget::new(
+                Backend::Webhook,
+                Duration::from_millis(config.webhook_timeout_ms),
+                config.webhook_retries,
+            ),
+        }
+    }
+}
+
+impl Default for Budgets {
+    fn default() -> Self {
+        Self::from_config(&Config::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_retry_stops_after_the_budgeted_attempts() {
+        let budget = Budget::new(Backend::DbRead, Duration::from_millis(10), 2);
+        let calls = Cell::new(0);
+        let result: AppResult<()> = budget.retry(|| {
+            calls.set(calls.get() + 1);
+            Err(AppError::unavailable("down"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 3);
+
+        calls.set(0);
+        let result = budget.retry(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() < 2 {
+                Err(AppError::unavailable("blip"))
+            } else {
+                Ok(calls.get())
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_run_times_out_each_attempt() {
+        let budget = Budget::new(Backend::Webhook, Duration::from_millis(20), 1);
+        let calls = Cell::new(0);
+        let started = std::time::Instant::now();
+        let result: AppResult<()> = budget
+            .run(|| {
+                calls.set(calls.get() + 1);
+                async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    Ok(())
+                }
+            })
+            .await;
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("notification delivery timed out after 20ms"), "{}", err);
+        assert_eq!(calls.get(), 2);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = Config {
+            db_read_timeout_ms: 300,
+            cache_retries: 2,
+            ..Config::default()
+        };
+        let budgets = Budgets::from_config(&config);
+        assert_eq!(budgets.db_read.timeout, Duration::from_millis(300));
+        assert_eq!(budgets.cache.retries, 2);
+        assert_eq!(budgets.webhook, Budgets::default().webhook);
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::events::CloudEvent;
+
+/// Header carrying the token of a pending dangerous change, to confirm it
+pub const CONFIRMATION_HEADER: &str = "X-Confirm-Change";
+
+/// Header carrying `CONFIG_CHANGE_APPROVAL_TOKEN`
+pub const APPROVAL_HEADER: &str = "X-Change-Approval";
+
+/// One setting changed by a mutation, `None` when absent on that side
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub path: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// Differences between two settings documents, by dotted path
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangeDiff {
+    pub changes: Vec<FieldChange>,
+}
+
+impl ChangeDiff {
+    /// Compares `before` and `after`, descending into objects
+    pub fn between(before: &Value, after: &Value) -> Self {
+        let (mut old, mut new) = (BTreeMap::new(), BTreeMap::new());
+        flatten("", before, &mut old);
+        flatten("", after, &mut new);
+        let mut paths: Vec<&String> = old.keys().chain(new.keys()).collect();
+        paths.sort();
+        paths.dedup();
+        let changes = paths
+            .into_iter()
+            .filter(|path| old.get(*path) != new.get(*path))
+            .map(|path| FieldChange {
+                path: path.clone(),
+                before: old.get(path).cloned(),
+                after: new.get(path).cloned(),
+            })
+            .collect();
+        Self { changes }
+    }
+
+    /// Returns whether nothing changes
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Fingerprint binding a confirmation to exactly this change
+    fn fingerprint(&self, actor: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(actor.as_bytes());
+        hasher.update(serde_json::to_vec(&self.changes).unwrap_or_default());
+        hex::encode(hasher.finalize())
+    }
+}
+
+fn flatten(prefix: &str, value: &Value, into: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(fields) if !fields.is_empty() => {
+            for (key, value) in fields {
+                let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(&path, value, into);
+            }
+        }
+        value => {
+            into.insert(prefix.to_string(), value.clone());
+        }
+    }
+}
+
+/// What a mutation endpoint should do with a reviewed change
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Review {
+    /// Apply the change, then call [`ChangeGuard::record`]
+    Approved { diff: ChangeDiff },
+    /// Answer 202 and let the caller repeat the request with the token in
+    /// [`CONFIRMATION_HEADER`]
+    ConfirmationRequired {
+        token: String,
+        expires_at: DateTime<Utc>,
+        /// Dangerous paths the change touches
+        dangerous: Vec<String>,
+    },
+}
+
+struct PendingChange {
+    fingerprint: String,
+    expires_at: DateTime<Utc>,
+}
+
+type Validator = Box<dyn Fn(&ChangeDiff) -> AppResult<()> + Send + Sync>;
+
+/// Guard for admin endpoints mutating configuration or feature flags
+///
+/// A mutation endpoint hands the settings before and after the requested
+/// change to [`ChangeGuard::review`], which validates the change, limits how
+/// many changes are applied per hour and makes changes touching dangerous
+/// paths (`CONFIG_DANGEROUS_KEYS`, e.g. `auth.*`) take two steps: the first
+/// request gets a token, and only the same caller repeating the same change
+/// with it in [`CONFIRMATION_HEADER`] goes through. With
+/// `CONFIG_CHANGE_APPROVAL_TOKEN` set, dangerous changes also need it in
+/// [`APPROVAL_HEADER`]. Applied changes are audited with their diff through
+/// [`ChangeGuard::record`].
+pub struct ChangeGuard {
+    dangerous: Vec<String>,
+    approval_token: Option<String>,
+    confirmation_ttl: Duration,
+    max_per_hour: usize,
+    validators: Vec<Validator>,
+    pending: Mutex<HashMap<String, PendingChange>>,
+    applied: Mutex<VecDeque<DateTime<Utc>>>,
+}
+
+impl ChangeGuard {
+    /// Creates a guard treating changes under the `dangerous` path patterns
+    /// (`auth.*`, `auth.enabled`) as dangerous
+    pub fn new(dangerous: Vec<String>, confirmation_ttl: Duration, max_per_hour: usize) -> Self {
+        Self {
+            dangerous,
+            approval_token: None,
+            confirmation_ttl,
+            max_per_hour,
+            validators: Vec::new(),
+            pending: Mutex::new(HashMap::new()),
+            applied: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Builds the guard from the `CONFIG_CHANGE_*` and `CONFIG_DANGEROUS_KEYS` settings
+    pub fn from_config(config: &Config) -> Self {
+        let mut guard = Self::new(
+            config.config_dangerous_keys.clone(),
+            Duration::from_secs(config.config_change_confirm_ttl_secs),
+            config.config_change_max_per_hour,
+        );
+        guard.approval_token = config.config_change_approval_token.clone();
+        guard
+    }
+
+    /// Requires `token` in [`APPROVAL_HEADER`] on dangerous changes
+    pub fn with_approval_token(mut self, token: impl Into<String>) -> Self {
+        self.approval_token = Some(token.into());
+        self
+    }
+
+    /// Adds a check every change must pass, failing with a validation error
+    pub fn with_validator<F>(mut self, validator: F) -> Self
+    where
+        F: Fn(&ChangeDiff) -> AppResult<()> + Send + Sync + 'static,
+    {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    /// Reviews the change from `before` to `after` requested by `actor`
+    ///
+    /// # Errors
+    /// Validation error for an empty change, a setting changing type or a
+    /// failed validator; rate limited past `CONFIG_CHANGE_MAX_PER_HOUR`;
+    /// forbidden for a dangerous change without the approval token or with
+    /// an unknown, expired or mismatched confirmation token
+    pub fn review(
+        &self,
+        actor: &str,
+        before: &Value,
+        after: &Value,
+        confirmation: Option<&str>,
+        approval: Option<&str>,
+    ) -> AppResult<Review> {
+        let diff = ChangeDiff::between(before, after);
+        if diff.is_empty() {
+            return Err(AppError::validation("the change does not modify anything"));
+        }
+        for change in &diff.changes {
+            if let (Some(old), Some(new)) = (&change.before, &change.after) {
+                if !old.is_null() && !new.is_null() && std::mem::discriminant(old) != std::mem::discriminant(new) {
+                    return Err(AppError::validation(format!("{} cannot change type", change.path)));
+                }
+            }
+        }
+        for validator in &self.validators {
+            validator(&diff)?;
+        }
+        self.check_rate()?;
+
+        let dangerous: Vec<String> = diff
+            .changes
+            .iter()
+            .map(|change| change.path.clone())
+            .filter(|path| self.is_dangerous(path))
+            .collect();
+        if dangerous.is_empty() {
+            return Ok(Review::Approved { diff });
+        }
+        if let Some(expected) = &self.approval_token {
+            let approved = approval.is_some_and(|given| bool::from(given.as_bytes().ct_eq(expected.as_bytes())));
+            if !approved {
+                return Err(AppError::forbidden(format!("changing {} requires an approval token", dangerous.join(", "))));
+            }
+        }
+
+        let now = Utc::now();
+        let fingerprint = diff.fingerprint(actor);
+        let mut pending = self.pending.lock().map_err(|_| poisoned())?;
+        pending.retain(|_, change| change.expires_at > now);
+        match confirmation {
+            Some(token) => match pending.remove(token) {
+                Some(change) if change.fingerprint == fingerprint => Ok(Review::Approved { diff }),
+                _ => Err(AppError::forbidden("unknown, expired or mismatched change confirmation")),
+            },
+            None => {
+                let token = uuid::Uuid::new_v4().to_string();
+                let ttl = chrono::Duration::from_std(self.confirmation_ttl).map_err(|e| AppError::internal(e.to_string()))?;
+                let expires_at = now + ttl;
+                pending.insert(token.clone(), PendingChange { fingerprint, expires_at });
+                Ok(Review::ConfirmationRequired { token, expires_at, dangerous })
+            }
+        }
+    }
+
+    /// Reviews a change with the confirmation and approval headers of `req`
+    pub fn review_request(&self, req: &HttpRequest, actor: &str, before: &Value, after: &Value) -> AppResult<Review> {
+        let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+        self.review(actor, before, after, header(CONFIRMATION_HEADER), header(APPROVAL_HEADER))
+    }
+
+    /// Audits an applied change with its diff and counts it against the hourly limit
+    pub fn record(&self, actor: &str, target: &str, diff: &ChangeDiff) -> AppResult<()> {
+        self.applied.lock().map_err(|_| poisoned())?.push_back(Utc::now());
+        let event = CloudEvent::new(
+            "com.simple-api-demo.config.changed",
+            json!({ "actor": actor, "target": target, "changes": diff.changes }),
+        )
+        .with_subject(target.to_string());
+        info!(target: "audit", "{}", event.to_json()?);
+        Ok(())
+    }
+
+    fn check_rate(&self) -> AppResult<()> {
+        let now = Utc::now();
+        let window_start = now - chrono::Duration::hours(1);
+        let mut applied = self.applied.lock().map_err(|_| poisoned())?;
+        while applied.front().is_some_and(|at| *at <= window_start) {
+            applied.pop_front();
+        }
+        if applied.len() < self.max_per_hour {
+            return Ok(());
+        }
+        let retry_after = applied
+            .front()
+            .map(|oldest| (*oldest - window_start).num_seconds().max(1) as u64)
+            .unwrap_or(1);
+        Err(AppError::rate_limited(
+            format!("at most {} configuration changes per hour", self.max_per_hour),
+            retry_after,
+        ))
+    }
+
+    fn is_dangerous(&self, path: &str) -> bool {
+        self.dangerous.iter().any(|pattern| match pattern.strip_suffix(".*") {
+            Some(prefix) => path == prefix || path.starts_with(&format!("{}.", prefix)),
+            None => pattern == "*" || pattern == path,
+        })
+    }
+}
+
+fn poisoned() -> AppError {
+    AppError::internal("change guard lock poisoned")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> ChangeGuard {
+        ChangeGuard::new(vec!["auth.*".to_string()], Duration::from_secs(60), 3)
+    }
+
+    #[test]
+    fn test_diff_by_path() {
+        let diff = ChangeDiff::between(
+            &json!({ "auth": { "enabled": true, "ttl": 60 }, "motd": "hi" }),
+            &json!({ "auth": { "enabled": false, "ttl": 60 }, "beta": true }),
+        );
+        let paths: Vec<&str> = diff.changes.iter().map(|change| change.path.as_str()).collect();
+        assert_eq!(paths, vec!["auth.enabled", "beta", "motd"]);
+        assert_eq!(diff.changes[0].before, Some(json!(true)));
+        assert_eq!(diff.changes[2].after, None);
+    }
+
+    #[test]
+    fn test_validation_and_safe_changes() {
+        let guard = guard().with_validator(|diff| {
+            match diff.changes.iter().any(|change| change.path == "motd" && change.after == Some(json!(""))) {
+                true => Err(AppError::validation("motd cannot be empty")),
+                false => Ok(()),
+            }
+        });
+        let before = json!({ "motd": "hi", "limit": 10 });
+        assert!(matches!(guard.review("root", &before, &before, None, None), Err(AppError::Validation { .. })));
+        let retyped = json!({ "motd": "hi", "limit": "10" });
+        assert!(matches!(guard.review("root", &before, &retyped, None, None), Err(AppError::Validation { .. })));
+        let emptied = json!({ "motd": "", "limit": 10 });
+        assert!(guard.review("root", &before, &emptied, None, None).is_err());
+        let raised = json!({ "motd": "hi", "limit": 20 });
+        assert!(matches!(guard.review("root", &before, &raised, None, None), Ok(Review::Approved { .. })));
+    }
+
+    #[test]
+    fn test_dangerous_changes_take_two_steps() {
+        let guard = guard().with_approval_token("let-me-in");
+        let before = json!({ "auth": { "enabled": true } });
+        let after = json!({ "auth": { "enabled": false } });
+        assert!(matches!(
+            guard.review("root", &before, &after, None, None),
+            Err(AppError::Forbidden { .. })
+        ));
+
+        let Review::ConfirmationRequired { token, dangerous, .. } =
+            guard.review("root", &before, &after, None, Some("let-me-in")).unwrap()
+        else {
+            panic!("expected a confirmation step");
+        };
+        assert_eq!(dangerous, vec!["auth.enabled"]);
+        // Another caller, or another change, cannot use the token
+        assert!(guard.review("mallory", &before, &after, Some(&token), Some("let-me-in")).is_err());
+        let Review::ConfirmationRequired { token, .. } =
+            guard.review("root", &before, &after, None, Some("let-me-in")).unwrap()
+        else {
+            panic!("expected a confirmation step");
+        };
+        assert!(matches!(
+            guard.review("root", &before, &after, Some(&token), Some("let-me-in")),
+            Ok(Review::Approved { .. })
+        ));
+        assert!(guard.review("root", &before, &after, Some(&token), Some("let-me-in")).is_err());
+    }
+
+    #[test]
+    fn test_changes_are_rate_limited() {
+        let guard = guard();
+        let (before, after) = (json!({ "limit": 1 }), json!({ "limit": 2 }));
+        for _ in 0..3 {
+            let Review::Approved { diff } = guard.review("root", &before, &after, None, None).unwrap() else {
+                panic!("expected approval");
+            };
+            guard.record("root", "settings", &diff).unwrap();
+        }
+        assert!(matches!(
+            guard.review("root", &before, &after, None, None),
+            Err(AppError::RateLimited { .. })
+        ));
+    }
+}
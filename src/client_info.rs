@@ -0,0 +1,419 @@
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::dev::Payload;
+use actix_web::http::header::{HeaderMap, HeaderName, USER_AGENT};
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use ipnet::IpNet;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::tls::{ClientCertificate, TlsConnection};
+
+/// Proxy header listing the client and the proxies a request went through
+pub const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Geo headers set by CDNs and load balancers, in order of preference
+const COUNTRY_HEADERS: &[&str] = &["cf-ipcountry", "cloudfront-viewer-country", "x-geo-country"];
+const REGION_HEADERS: &[&str] = &["cloudfront-viewer-country-region", "x-geo-region"];
+const CITY_HEADERS: &[&str] = &["cloudfront-viewer-city", "x-geo-city"];
+
+/// Resolves the client behind a request from the peer address and the
+/// headers set by trusted proxies
+///
+/// Forwarding and geo headers are attacker-controlled unless the peer is one
+/// of the `TRUSTED_PROXIES`, so they are ignored for any other peer.
+#[derive(Debug, Clone, Default)]
+pub struct ClientResolver {
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl ClientResolver {
+    /// Creates a resolver trusting the proxies in `trusted_proxies`
+    pub fn new(trusted_proxies: Vec<IpNet>) -> Self {
+        Self { trusted_proxies }
+    }
+
+    /// Builds the resolver from `TRUSTED_PROXIES`
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.trusted_proxies.clone())
+    }
+
+    /// Returns whether `ip` belongs to a trusted proxy network
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Returns the address of the client that sent a request received from `peer`
+    ///
+    /// The forwarding chain (`Forwarded` when present, `X-Forwarded-For`
+    /// otherwise) is walked from the closest hop back, skipping trusted
+    /// proxies; the first untrusted hop is the client. A malformed hop ends
+    /// the walk at the last proxy that could be trusted.
+    pub fn resolve_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in forwarding_chain(headers).iter().rev() {
+            let Some(ip) = parse_hop(hop) else { break };
+            client = ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Assembles the [`ClientInfo`] of `req`
+    pub fn resolve(&self, req: &HttpRequest) -> ClientInfo {
+        let peer_ip = req.peer_addr().map(|addr| addr.ip());
+        let ip = peer_ip.map(|peer| self.resolve_ip(peer, req.headers()));
+        let trusted_peer = peer_ip.is_some_and(|peer| self.is_trusted(peer));
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let tls = req.conn_data::<TlsConnection>().map(|connection| TlsInfo {
+            connection: connection.clone(),
+            client_certificate: req.conn_data::<ClientCertificate>().cloned(),
+        });
+        ClientInfo {
+            ip,
+            peer_ip,
+            via_proxy: ip != peer_ip,
+            client_kind: ClientKind::classify(user_agent.as_deref().unwrap_or_default()),
+            user_agent,
+            geo: if trusted_peer { GeoInfo::from_headers(req.headers()) } else { None },
+            tls,
+        }
+    }
+}
+
+/// Client address of `req`, resolved through trusted proxies when a
+/// [`ClientResolver`] is registered
+///
+/// Use this instead of `peer_addr` anywhere the address identifies the
+/// client: rate limits, failure counters, session tracking and logs.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    if let Some(info) = req.extensions().get::<ClientInfo>() {
+        return info.ip;
+    }
+    let peer = req.peer_addr()?.ip();
+    Some(match req.app_data::<web::Data<ClientResolver>>() {
+        Some(resolver) => resolver.resolve_ip(peer, req.headers()),
+        None => peer,
+    })
+}
+
+/// Hops of the forwarding chain, from the original client to the closest proxy
+fn forwarding_chain(headers: &HeaderMap) -> Vec<String> {
+    let forwarded: Vec<String> = headers
+        .get_all(actix_web::http::header::FORWARDED)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim().eq_ignore_ascii_case("for").then(|| value.trim().to_string())
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all(HeaderName::from_static(FORWARDED_FOR_HEADER))
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().to_string())
+        .collect()
+}
+
+/// Parses one hop: a bare address, `[v6]`, or either with a port, possibly quoted
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    let inner = hop.strip_prefix('[')?.split(']').next()?;
+    inner.parse().ok()
+}
+
+/// Rough category of the software behind a `User-Agent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientKind {
+    /// Desktop web browser
+    Browser,
+    /// Browser or app on a phone or tablet
+    Mobile,
+    /// Crawler, monitor or headless automation
+    Bot,
+    /// Command-line tool or HTTP library
+    Cli,
+    /// Missing or unrecognised user agent
+    Unknown,
+}
+
+impl ClientKind {
+    /// Classifies a `User-Agent` value
+    pub fn classify(user_agent: &str) -> Self {
+        let ua = user_agent.to_ascii_lowercase();
+        let contains_any = |needles: &[&str]| needles.iter().any(|needle| ua.contains(needle));
+        if contains_any(&["bot", "crawl", "spider", "slurp", "headless", "monitor"]) {
+            ClientKind::Bot
+        } else if contains_any(&[
+            "curl/", "wget/", "httpie/", "python-requests", "python-urllib", "go-http-client", "reqwest", "okhttp",
+        ]) {
+            ClientKind::Cli
+        } else if contains_any(&["mobile", "android", "iphone", "ipad"]) {
+            ClientKind::Mobile
+        } else if ua.starts_with("mozilla/") || ua.starts_with("opera/") {
+            ClientKind::Browser
+        } else {
+            ClientKind::Unknown
+        }
+    }
+}
+
+/// Location of the client as reported by a CDN or load balancer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 country code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+}
+
+impl GeoInfo {
+    /// Reads the geo headers, `None` when none of them is set
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let first = |names: &[&str]| {
+            names.iter().find_map(|name| {
+                let value = headers.get(*name)?.to_str().ok()?.trim();
+                (!value.is_empty()).then(|| value.to_string())
+            })
+        };
+        // Cloudflare reports XX for unknown and T1 for Tor exit nodes
+        let country = first(COUNTRY_HEADERS)
+            .map(|country| country.to_ascii_uppercase())
+            .filter(|country| country.len() == 2 && country != "XX" && country != "T1");
+        let geo = Self {
+            country,
+            region: first(REGION_HEADERS),
+            city: first(CITY_HEADERS),
+        };
+        (geo.country.is_some() || geo.region.is_some() || geo.city.is_some()).then_some(geo)
+    }
+}
+
+/// TLS details of the connection a request arrived on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TlsInfo {
+    #[serde(flatten)]
+    pub connection: TlsConnection,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// Who is on the other end of a request
+///
+/// Resolved once per request by the registered [`ClientResolver`] (peer
+/// address only without one) and cached in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientInfo {
+    /// Client address, resolved through trusted proxies
+    pub ip: Option<IpAddr>,
+    /// Address of the TCP peer, the closest proxy when behind one
+    pub peer_ip: Option<IpAddr>,
+    /// Whether `ip` was taken from forwarding headers
+    pub via_proxy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    pub client_kind: ClientKind,
+    /// Location reported by a trusted proxy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geo: Option<GeoInfo>,
+    /// Set for requests received on a TLS listener
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsInfo>,
+}
+
+impl FromRequest for ClientInfo {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(info) = req.extensions().get::<ClientInfo>() {
+            return ready(Ok(info.clone()));
+        }
+        let info = match req.app_data::<web::Data<ClientResolver>>() {
+            Some(resolver) => resolver.resolve(req),
+            None => ClientResolver::default().resolve(req),
+        };
+        req.extensions_mut().insert(info.clone());
+        ready(Ok(info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+    use actix_web::test::TestRequest;
+
+    fn resolver() -> ClientResolver {
+        ClientResolver::new(vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()])
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_forwarding_headers() {
+        let spoofed = headers(&[("x-forwarded-for", "1.2.3.4"), ("forwarded", "for=5.6.7.8")]);
+        assert_eq!(resolver().resolve_ip(ip("203.0.113.9"), &spoofed), ip("203.0.113.9"));
+        assert_eq!(ClientResolver::default().resolve_ip(ip("10.0.0.1"), &spoofed), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_x_forwarded_for_skips_trusted_hops() {
+        let resolver = resolver();
+        // The left-most entry is client-supplied; the walk stops at the first untrusted hop
+        let chain = headers(&[("x-forwarded-for", "6.6.6.6, 198.51.100.7, 10.1.1.1")]);
+        assert_eq!(resolver.resolve_ip(ip("10.0.0.1"), &chain), ip("198.51.100.7"));
+
+        let split = headers(&[("x-forwarded-for", "198.51.100.7"), ("x-forwarded-for", "10.2.2.2")]);
+        assert_eq!(resolver.resolve_ip(ip("10.0.0.1"), &split), ip("198.51.100.7"));
+
+        let internal = headers(&[("x-forwarded-for", "10.3.3.3, 10.2.2.2")]);
+        assert_eq!(resolver.resolve_ip(ip("10.0.0.1"), &internal), ip("10.3.3.3"));
+
+        let garbage = headers(&[("x-forwarded-for", "198.51.100.7, not-an-ip, 10.2.2.2")]);
+        assert_eq!(resolver.resolve_ip(ip("10.0.0.1"), &garbage), ip("10.2.2.2"));
+
+        assert_eq!(resolver.resolve_ip(ip("10.0.0.1"), &HeaderMap::new()), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_forwarded_header_takes_precedence() {
+        let resolver = resolver();
+        let both = headers(&[
+            ("forwarded", "for=192.0.2.60;proto=https, for=\"[fd00::1]:4711\""),
+            ("x-forwarded-for", "6.6.6.6"),
+        ]);
+        assert_eq!(resolver.resolve_ip(ip("10.0.0.1"), &both), ip("192.0.2.60"));
+
+        let v6 = headers(&[("forwarded", "For=\"[2001:db8:cafe::17]:4711\"")]);
+        assert_eq!(resolver.resolve_ip(ip("fd00::2"), &v6), ip("2001:db8:cafe::17"));
+
+        let with_port = headers(&[("x-forwarded-for", "198.51.100.7:51234")]);
+        assert_eq!(resolver.resolve_ip(ip("10.0.0.1"), &with_port), ip("198.51.100.7"));
+
+        // Obfuscated identifiers cannot be resolved past the proxy
+        let hidden = headers(&[("forwarded", "for=_hidden")]);
+        assert_eq!(resolver.resolve_ip(ip("10.0.0.1"), &hidden), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_client_kind_classification() {
+        let cases = [
+            ("Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0", ClientKind::Browser),
+            ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X) Mobile/15E148", ClientKind::Mobile),
+            ("Mozilla/5.0 (Linux; Android 14; Pixel 8) Chrome/126.0 Mobile Safari/537.36", ClientKind::Mobile),
+            ("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)", ClientKind::Bot),
+            ("Mozilla/5.0 HeadlessChrome/126.0", ClientKind::Bot),
+            ("curl/8.5.0", ClientKind::Cli),
+            ("python-requests/2.32.3", ClientKind::Cli),
+            ("", ClientKind::Unknown),
+            ("my-integration/1.0", ClientKind::Unknown),
+        ];
+        for (ua, kind) in cases {
+            assert_eq!(ClientKind::classify(ua), kind, "{}", ua);
+        }
+    }
+
+    #[test]
+    fn test_geo_headers() {
+        let cloudflare = GeoInfo::from_headers(&headers(&[("cf-ipcountry", "fr")])).unwrap();
+        assert_eq!(cloudflare.country.as_deref(), Some("FR"));
+        assert_eq!(cloudflare.city, None);
+
+        let cloudfront = GeoInfo::from_headers(&headers(&[
+            ("cloudfront-viewer-country", "US"),
+            ("cloudfront-viewer-country-region", "WA"),
+            ("x-geo-city", "Seattle"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            (cloudfront.country.as_deref(), cloudfront.region.as_deref(), cloudfront.city.as_deref()),
+            (Some("US"), Some("WA"), Some("Seattle"))
+        );
+
+        assert_eq!(GeoInfo::from_headers(&headers(&[("cf-ipcountry", "XX")])), None);
+        assert_eq!(GeoInfo::from_headers(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_resolve_request() {
+        let resolver = resolver();
+        let proxied = TestRequest::default()
+            .peer_addr("10.0.0.1:443".parse().unwrap())
+            .insert_header(("x-forwarded-for", "198.51.100.7"))
+            .insert_header(("cf-ipcountry", "DE"))
+            .insert_header(("user-agent", "curl/8.5.0"))
+            .to_http_request();
+        let info = resolver.resolve(&proxied);
+        assert_eq!(info.ip, Some(ip("198.51.100.7")));
+        assert_eq!(info.peer_ip, Some(ip("10.0.0.1")));
+        assert!(info.via_proxy);
+        assert_eq!(info.client_kind, ClientKind::Cli);
+        assert_eq!(info.geo.and_then(|geo| geo.country).as_deref(), Some("DE"));
+        assert_eq!(info.tls, None);
+
+        // Geo headers from an untrusted peer are ignored like forwarding headers
+        let direct = TestRequest::default()
+            .peer_addr("203.0.113.9:5000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "198.51.100.7"))
+            .insert_header(("cf-ipcountry", "DE"))
+            .to_http_request();
+        let info = resolver.resolve(&direct);
+        assert_eq!((info.ip, info.via_proxy, info.geo), (Some(ip("203.0.113.9")), false, None));
+        assert_eq!(info.client_kind, ClientKind::Unknown);
+    }
+
+    #[test]
+    fn test_client_ip_uses_registered_resolver() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:443".parse().unwrap())
+            .insert_header(("x-forwarded-for", "198.51.100.7"))
+            .app_data(web::Data::new(resolver()))
+            .to_http_request();
+        assert_eq!(client_ip(&req), Some(ip("198.51.100.7")));
+
+        let unregistered = TestRequest::default()
+            .peer_addr("10.0.0.1:443".parse().unwrap())
+            .insert_header(("x-forwarded-for", "198.51.100.7"))
+            .to_http_request();
+        assert_eq!(client_ip(&unregistered), Some(ip("10.0.0.1")));
+        assert_eq!(client_ip(&TestRequest::default().to_http_request()), None);
+    }
+}
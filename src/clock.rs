@@ -0,0 +1,394 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::rt::net::UdpSocket;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::supervisor::Supervisor;
+
+/// Timeout of one reference request
+const REFERENCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Port of `ntp://` references without one
+const NTP_PORT: u16 = 123;
+
+/// Seconds from the NTP era (1900) to the Unix epoch
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+
+/// Size of an SNTP request or response without extensions
+const NTP_PACKET_LEN: usize = 48;
+
+/// Time source the system clock is compared against, from `CLOCK_REFERENCE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClockReference {
+    /// `http(s)://` URL whose `Date` response header is read
+    Http(String),
+    /// `host:port` of an NTP server, queried over SNTP
+    Ntp(String),
+}
+
+impl FromStr for ClockReference {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let lower = value.to_ascii_lowercase();
+        if lower.starts_with("http://") || lower.starts_with("https://") {
+            reqwest::Url::parse(value)
+                .map_err(|e| AppError::config(format!("invalid clock reference '{}': {}", value, e)))?;
+            return Ok(ClockReference::Http(value.to_string()));
+        }
+        let host = lower
+            .strip_prefix("ntp://")
+            .map(|_| value["ntp://".len()..].trim_end_matches('/'))
+            .filter(|host| !host.is_empty() && !host.contains('/'))
+            .ok_or_else(|| AppError::config(format!("invalid clock reference '{}'", value)))?;
+        // A bare IPv6 address or host name gets the default port
+        let has_port = host.rsplit_once(':').is_some_and(|(address, port)| {
+            port.parse::<u16>().is_ok() && (!address.contains(':') || address.ends_with(']'))
+        });
+        Ok(ClockReference::Ntp(if has_port {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, NTP_PORT)
+        }))
+    }
+}
+
+impl fmt::Display for ClockReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClockReference::Http(url) => f.write_str(url),
+            ClockReference::Ntp(address) => write!(f, "ntp://{}", address),
+        }
+    }
+}
+
+/// Outcome of the last successful clock check
+#[derive(Debug, Clone, Serialize)]
+pub struct ClockReading {
+    /// How far the system clock runs ahead of the reference (negative when behind)
+    pub skew_secs: f64,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// Clock check state reported by `/metrics`
+#[derive(Debug, Clone, Serialize)]
+pub struct ClockStatus {
+    pub reference: String,
+    pub max_skew_secs: f64,
+    pub last: Option<ClockReading>,
+}
+
+/// Periodic comparison of the system clock against `CLOCK_REFERENCE`
+///
+/// Token expiry, refresh and lockout TTLs, webhook timestamps and signed
+/// request windows all trust the local clock, and fail in confusing ways
+/// when it drifts. The check runs at startup and every
+/// `CLOCK_CHECK_INTERVAL_SECS`; a skew above `CLOCK_MAX_SKEW_SECS` is logged
+/// and makes `/ready` answer 503 until a later check finds the clock back in
+/// range. An unreachable reference is only logged: it says nothing about the
+/// local clock.
+pub struct ClockCheck {
+    reference: ClockReference,
+    max_skew: f64,
+    interval: Duration,
+    http: reqwest::Client,
+    last: Mutex<Option<ClockReading>>,
+}
+
+impl ClockCheck {
+    /// Creates a check against `reference`, tolerating `max_skew`
+    pub fn new(reference: ClockReference, max_skew: Duration, interval: Duration) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(REFERENCE_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            reference,
+            max_skew: max_skew.as_secs_f64(),
+            interval,
+            http,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Builds the check from the `CLOCK_*` settings; `None` without
+    /// `CLOCK_REFERENCE`
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config.clock_reference.clone().map(|reference| {
+            Self::new(
+                reference,
+                Duration::from_secs(config.clock_max_skew_secs),
+                Duration::from_secs(config.clock_check_interval_secs),
+            )
+        })
+    }
+
+    /// Measures the skew of the system clock against the reference, in
+    /// seconds, positive when the system clock runs ahead
+    pub async fn measure(&self) -> AppResult<f64> {
+        match &self.reference {
+            ClockReference::Http(url) => {
+                let sent = unix_now();
+                let response = self
+                    .http
+                    .head(url)
+                    .send()
+                    .await
+                    .map_err(|e| AppError::internal(format!("clock reference {} unreachable: {}", url, e)))?;
+                let received = unix_now();
+                let date = response
+                    .headers()
+                    .get(reqwest::header::DATE)
+                    .and_then(|value| value.to_str().ok())
+                    .ok_or_else(|| AppError::internal(format!("clock reference {} sent no Date header", url)))?;
+                http_date_skew(date, sent, received)
+            }
+            ClockReference::Ntp(address) => actix_web::rt::time::timeout(REFERENCE_TIMEOUT, query_ntp(address))
+                .await
+                .map_err(|_| AppError::internal(format!("NTP server {} did not answer", address)))?,
+        }
+    }
+
+    /// Measures and records the skew, warning when it exceeds the limit
+    pub async fn check(&self) -> AppResult<f64> {
+        let skew_secs = self.measure().await?;
+        let was_excessive = self.excessive_skew().is_some();
+        if let Ok(mut last) = self.last.lock() {
+            *last = Some(ClockReading {
+                skew_secs,
+                checked_at: Utc::now(),
+            });
+        }
+        if skew_secs.abs() > self.max_skew {
+            warn!(
+                "System clock is {:+.3}s off {} (limit {}s): token expiry, TTLs and signatures are unreliable",
+                skew_secs, self.reference, self.max_skew
+            );
+        } else if was_excessive {
+            info!(
+                "System clock back within {}s of {} ({:+.3}s)",
+                self.max_skew, self.reference, skew_secs
+            );
+        }
+        Ok(skew_secs)
+    }
+
+    /// Skew of the last check when it exceeded the limit
+    pub fn excessive_skew(&self) -> Option<f64> {
+        let last = self.last.lock().ok()?;
+        last.as_ref()
+            .map(|reading| reading.skew_secs)
+            .filter(|skew| skew.abs() > self.max_skew)
+    }
+
+    /// Reference, limit and last reading
+    pub fn status(&self) -> ClockStatus {
+        ClockStatus {
+            reference: self.reference.to_string(),
+            max_skew_secs: self.max_skew,
+            last: self.last.lock().ok().and_then(|last| last.clone()),
+        }
+    }
+
+    /// Checks the clock now and then every interval, as the supervised
+    /// `clock_check` task
+    pub fn spawn_checks(check: Arc<Self>, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("clock_check", move || {
+            let check = check.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(check.interval);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = check.check().await {
+                        warn!("Clock check failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Skew of the local clock against an HTTP `Date` header read between
+/// `sent` and `received` (Unix seconds)
+///
+/// `Date` has a one second resolution: it is taken as the middle of its
+/// second and compared with the middle of the round trip.
+pub fn http_date_skew(date: &str, sent: f64, received: f64) -> AppResult<f64> {
+    let reference = DateTime::parse_from_rfc2822(date)
+        .map_err(|e| AppError::internal(format!("invalid Date header '{}': {}", date, e)))?
+        .timestamp() as f64
+        + 0.5;
+    Ok((sent + received) / 2.0 - reference)
+}
+
+async fn query_ntp(address: &str) -> AppResult<f64> {
+    let server = tokio::net::lookup_host(address)
+        .await
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .ok_or_else(|| AppError::internal(format!("cannot resolve NTP server {}", address)))?;
+    let local = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(local).await.map_err(AppError::internal)?;
+    socket.connect(server).await.map_err(AppError::internal)?;
+
+    let request = ntp_request(unix_now());
+    socket.send(&request).await.map_err(AppError::internal)?;
+    let mut response = [0u8; NTP_PACKET_LEN];
+    let len = socket.recv(&mut response).await.map_err(AppError::internal)?;
+    ntp_skew(&request, &response[..len], unix_now())
+}
+
+fn ntp_request(now: f64) -> [u8; NTP_PACKET_LEN] {
+    let mut request = [0u8; NTP_PACKET_LEN];
+    // Leap indicator 0, version 4, mode 3 (client)
+    request[0] = 0x23;
+    request[40..48].copy_from_slice(&encode_ntp_timestamp(now));
+    request
+}
+
+/// Skew of the local clock from an SNTP exchange (RFC 4330), `received`
+/// being the local time the response arrived
+pub fn ntp_skew(request: &[u8], response: &[u8], received: f64) -> AppResult<f64> {
+    if response.len() < NTP_PACKET_LEN || response[0] & 0x07 != 4 {
+        return Err(AppError::internal("invalid NTP response"));
+    }
+    if !(1..=15).contains(&response[1]) {
+        return Err(AppError::internal(
+            "NTP server is unsynchronized or refused the request",
+        ));
+    }
+    // The server echoes our transmit timestamp as originate timestamp
+    if response[24..32] != request[40..48] {
+        return Err(AppError::internal("NTP response does not match the request"));
+    }
+    let sent = decode_ntp_timestamp(&request[40..48]);
+    let server_received = decode_ntp_timestamp(&response[32..40]);
+    let server_sent = decode_ntp_timestamp(&response[40..48]);
+    let offset = ((server_received - sent) + (server_sent - received)) / 2.0;
+    Ok(-offset)
+}
+
+fn encode_ntp_timestamp(unix: f64) -> [u8; 8] {
+    let ntp = unix + NTP_UNIX_OFFSET;
+    let seconds = ntp.trunc() as u32;
+    let fraction = (ntp.fract() * 4_294_967_296.0) as u32;
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&seconds.to_be_bytes());
+    bytes[4..].copy_from_slice(&fraction.to_be_bytes());
+    bytes
+}
+
+fn decode_ntp_timestamp(bytes: &[u8]) -> f64 {
+    let seconds = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64;
+    let fraction = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as f64;
+    seconds + fraction / 4_294_967_296.0 - NTP_UNIX_OFFSET
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ntp_response(request: &[u8], server_received: f64, server_sent: f64) -> [u8; NTP_PACKET_LEN] {
+        let mut response = [0u8; NTP_PACKET_LEN];
+        // Version 4, mode 4 (server), stratum 2
+        response[0] = 0x24;
+        response[1] = 2;
+        response[24..32].copy_from_slice(&request[40..48]);
+        response[32..40].copy_from_slice(&encode_ntp_timestamp(server_received));
+        response[40..48].copy_from_slice(&encode_ntp_timestamp(server_sent));
+        response
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(
+            "https://www.google.com".parse::<ClockReference>().unwrap(),
+            ClockReference::Http("https://www.google.com".to_string())
+        );
+        assert_eq!(
+            "ntp://pool.ntp.org".parse::<ClockReference>().unwrap(),
+            ClockReference::Ntp("pool.ntp.org:123".to_string())
+        );
+        assert_eq!(
+            "NTP://10.0.0.1:1123/".parse::<ClockReference>().unwrap(),
+            ClockReference::Ntp("10.0.0.1:1123".to_string())
+        );
+        assert_eq!(
+            "ntp://[::1]".parse::<ClockReference>().unwrap().to_string(),
+            "ntp://[::1]:123"
+        );
+        assert!("pool.ntp.org".parse::<ClockReference>().is_err());
+        assert!("ntp://".parse::<ClockReference>().is_err());
+    }
+
+    #[test]
+    fn test_http_date_skew() {
+        // 08:12:31 GMT is 784887151
+        let date = "Tue, 15 Nov 1994 08:12:31 GMT";
+        let skew = http_date_skew(date, 784_887_181.0, 784_887_182.0).unwrap();
+        assert!((skew - 30.0).abs() < 1e-6, "{}", skew);
+        let skew = http_date_skew(date, 784_887_151.2, 784_887_151.8).unwrap();
+        assert!(skew.abs() < 1e-6, "{}", skew);
+        assert!(http_date_skew("yesterday", 0.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_ntp_skew() {
+        // The server runs 10s ahead; 50ms each way, 10ms to answer
+        let request = ntp_request(1_700_000_000.0);
+        let response = ntp_response(&request, 1_700_000_010.05, 1_700_000_010.06);
+        let skew = ntp_skew(&request, &response, 1_700_000_000.11).unwrap();
+        assert!((skew + 10.0).abs() < 1e-3, "{}", skew);
+
+        let other = ntp_request(1_700_000_001.0);
+        assert!(ntp_skew(&other, &response, 1_700_000_000.11).is_err());
+        let mut unsynchronized = response;
+        unsynchronized[1] = 0;
+        assert!(ntp_skew(&request, &unsynchronized, 1_700_000_000.11).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_check_reports_excessive_skew() {
+        // Fake NTP server running `offset` seconds ahead of this clock
+        async fn serve(offset: f64) -> String {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let address = socket.local_addr().unwrap().to_string();
+            actix_web::rt::spawn(async move {
+                let mut request = [0u8; NTP_PACKET_LEN];
+                while let Ok((_, peer)) = socket.recv_from(&mut request).await {
+                    let now = unix_now() + offset;
+                    let _ = socket.send_to(&ntp_response(&request, now, now), peer).await;
+                }
+            });
+            address
+        }
+
+        let reference = format!("ntp://{}", serve(-120.0).await).parse().unwrap();
+        let check = ClockCheck::new(reference, Duration::from_secs(5), Duration::from_secs(60));
+        assert!(check.excessive_skew().is_none());
+        let skew = check.check().await.unwrap();
+        assert!((skew - 120.0).abs() < 1.0, "{}", skew);
+        assert!(check.excessive_skew().is_some());
+        assert!(check.status().last.is_some());
+
+        let reference = format!("ntp://{}", serve(0.0).await).parse().unwrap();
+        let check = ClockCheck::new(reference, Duration::from_secs(5), Duration::from_secs(60));
+        assert!(check.check().await.unwrap().abs() < 1.0);
+        assert!(check.excessive_skew().is_none());
+    }
+}
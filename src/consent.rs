@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use chrono::Utc;
+use serde_json::{json, Value};
+
+use crate::auth::scopes::authenticate;
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::state::KeyValueStore;
+use crate::users::{User, UserRepository};
+
+/// Scope allowing a caller to publish a new Terms of Service version
+pub const TERMS_ADMIN_SCOPE: &str = "admin:terms";
+
+/// Path describing the current terms, referenced by consent errors
+pub const TERMS_PATH: &str = "/tos";
+
+/// Path prefixes reachable without accepting the terms (signing in, reading
+/// and accepting them, cancelling an account erasure, opting out of analytics)
+const EXEMPT_PREFIXES: &[&str] = &["/auth/", TERMS_PATH, "/me/deletion", "/me/privacy"];
+
+/// Tracks the required Terms of Service version and users' acceptance
+///
+/// The current version is `TOS_VERSION` plus the number of bumps recorded in
+/// the state store, so bumping is atomic and shared by every replica.
+pub struct ConsentService {
+    store: Arc<dyn KeyValueStore>,
+    repository: UserRepository,
+    initial_version: u32,
+    document_url: Option<String>,
+}
+
+impl ConsentService {
+    /// Creates the service
+    ///
+    /// # Arguments
+    /// * `store` - Holds the version bump counter
+    /// * `repository` - User storage, where acceptance is recorded
+    /// * `initial_version` - Version required before any bump
+    /// * `document_url` - Where the terms can be read
+    pub fn new(
+        store: Arc<dyn KeyValueStore>,
+        repository: UserRepository,
+        initial_version: u32,
+        document_url: Option<String>,
+    ) -> Self {
+        Self {
+            store,
+            repository,
+            initial_version,
+            document_url,
+        }
+    }
+
+    /// Builds the service from `TOS_VERSION`/`TOS_URL`
+    pub fn from_config(config: &Config, store: Arc<dyn KeyValueStore>, repository: UserRepository) -> Self {
+        Self::new(store, repository, config.tos_version, config.tos_url.clone())
+    }
+
+    /// Returns the version users must have accepted
+    pub fn current_version(&self) -> AppResult<u32> {
+        let bumps = match self.store.get("bumps")? {
+            Some(raw) => raw
+                .parse::<u32>()
+                .map_err(|e| AppError::internal(format!("Corrupt terms version counter: {}", e)))?,
+            None => 0,
+        };
+        Ok(self.initial_version.saturating_add(bumps))
+    }
+
+    /// Describes the current terms
+    pub fn terms(&self) -> AppResult<Value> {
+        Ok(json!({
+            "version": self.current_version()?,
+            "url": self.document_url,
+        }))
+    }
+
+    /// Returns whether `user` accepted the current version
+    pub fn has_accepted(&self, user: &User) -> AppResult<bool> {
+        let current = self.current_version()?;
+        Ok(user.terms_version.is_some_and(|version| version >= current))
+    }
+
+    /// Records that `user_id` accepted terms `version`
+    ///
+    /// Accepting a version other than the current one is refused, so clients
+    /// cannot accept terms the user was never shown.
+    ///
+    /// # Errors
+    /// Validation error for a stale or unknown version, not found for unknown users
+    pub fn accept(&self, user_id: &str, version: u32) -> AppResult<User> {
+        let current = self.current_version()?;
+        if version != current {
+            return Err(AppError::validation(format!(
+                "terms version {} is not current (current: {})",
+                version, current
+            )));
+        }
+        let mut user = self
+            .repository
+            .get(user_id)?
+            .ok_or_else(|| AppError::not_found("user not found"))?;
+
+        let now = Utc::now();
+        user.terms_version = Some(version);
+        user.terms_accepted_at = Some(now);
+        user.updated_at = now;
+        self.repository.save(&user)?;
+        self.repository
+            .record_event(&user.id, "user.terms_accepted", json!({ "version": version }))?;
+        Ok(user)
+    }
+
+    /// Publishes a new version, which every user has to accept again
+    pub fn bump(&self, actor: &str) -> AppResult<u32> {
+        self.store.increment("bumps", None)?;
+        let version = self.current_version()?;
+        self.repository
+            .record_event(actor, "admin.terms_version_bumped", json!({ "version": version }))?;
+        Ok(version)
+    }
+}
+
+/// Blocks mutating requests from users who have not accepted the current terms
+///
+/// Applies to POST/PUT/PATCH/DELETE requests whose bearer credential belongs
+/// to a registered user; anonymous callers, service clients and guests pass
+/// through, as do paths under `/auth/` and `/tos` and revocations (DELETE)
+/// of `/me` and below, so access and the account can always be withdrawn.
+/// Blocked requests get
+/// `451 Unavailable For Legal Reasons` pointing at `GET /tos`.
+pub async fn require_consent(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let exempt = EXEMPT_PREFIXES.iter().any(|prefix| req.path().starts_with(prefix))
+        || (req.method() == Method::DELETE && (req.path() == "/me" || req.path().starts_with("/me/")));
+    let consent = req.app_data::<web::Data<ConsentService>>().cloned();
+    let (Some(consent), true, false) = (consent, mutating, exempt) else {
+        return next.call(req).await;
+    };
+    let Ok(claims) = authenticate(&req) else {
+        return next.call(req).await;
+    };
+
+    if let Some(user) = consent.repository.get(&claims.sub)? {
+        if !consent.has_accepted(&user)? {
+            let version = consent.current_version()?;
+            return Err(AppError::consent_required(
+                format!("accept the Terms of Service version {} at {}", version, TERMS_PATH),
+                version,
+            )
+            .into());
+        }
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenService;
+    use crate::state::InMemoryStore;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+    use actix_web::{middleware::from_fn, App, HttpResponse};
+    use std::time::Duration;
+
+    fn service() -> (ConsentService, UserRepository) {
+        let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+        let service = ConsentService::new(Arc::new(InMemoryStore::new()), repository.clone(), 3, None);
+        (service, repository)
+    }
+
+    #[test]
+    fn test_accept_and_bump() {
+        let (service, repository) = service();
+        let user = repository.create("ann@example.com", String::new()).unwrap();
+        assert_eq!(service.current_version().unwrap(), 3);
+        assert!(!service.has_accepted(&user).unwrap());
+
+        assert!(service.accept(&user.id, 2).is_err());
+        let user = service.accept(&user.id, 3).unwrap();
+        assert!(service.has_accepted(&user).unwrap());
+
+        assert_eq!(service.bump("admin").unwrap(), 4);
+        assert!(!service.has_accepted(&user).unwrap());
+        assert_eq!(repository.events(&user.id).unwrap()[0].action, "user.terms_accepted");
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_blocks_mutations_until_accepted() {
+        let (service, repository) = service();
+        let user = repository.create("ann@example.com", String::new()).unwrap();
+        let tokens = TokenService::new(b"tos-test-key-tos-test-key-tos-00", "test");
+        let bearer = format!("Bearer {}", tokens.issue(&user.id, &[], Duration::from_secs(60)).unwrap());
+        let service = web::Data::new(service);
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(tokens))
+                .app_data(service.clone())
+                .wrap(from_fn(require_consent))
+                .route("/items", web::get().to(HttpResponse::Ok))
+                .route("/items", web::post().to(HttpResponse::Created))
+                .route("/tos/accept", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |method: Method, uri: &str| {
+            TestRequest::default()
+                .method(method)
+                .uri(uri)
+                .insert_header(("Authorization", bearer.clone()))
+                .to_request()
+        };
+
+        assert_eq!(call_service(&app, request(Method::GET, "/items")).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, request(Method::POST, "/tos/accept")).await.status(), StatusCode::OK);
+        let err = try_call_service(&app, request(Method::POST, "/items")).await.err().unwrap();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS
+        );
+        let anonymous = TestRequest::post().uri("/items").to_request();
+        assert_eq!(call_service(&app, anonymous).await.status(), StatusCode::CREATED);
+
+        service.accept(&user.id, 3).unwrap();
+        assert_eq!(call_service(&app, request(Method::POST, "/items")).await.status(), StatusCode::CREATED);
+    }
+}
@@ -0,0 +1,484 @@
+use std::cell::RefCell;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use serde::Serialize;
+
+use crate::auth::Claims;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::log_context::{self, LogContext};
+use crate::region::{Placement, SERVED_BY_HEADER};
+use crate::server_timing::{ServerTiming, TimingSpan, SERVER_TIMING_HEADER};
+
+/// Header carrying the request id, accepted from clients and echoed on responses
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Header naming the tenant of unauthenticated requests
+pub const TENANT_HEADER: &str = "x-tenant-id";
+/// Time budget of a request when no `Config` is registered
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(30);
+
+/// Who a request was authenticated as
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuthPrincipal {
+    /// Bearer token or self-service API key
+    User {
+        subject: String,
+        scopes: Vec<String>,
+        roles: Vec<String>,
+    },
+    /// Service key sent in `X-Api-Key`
+    Service { name: String },
+    /// HTTP Basic credentials
+    Basic { name: String },
+    /// Client of a valid `X-Signature`
+    Signed { client: String },
+}
+
+impl AuthPrincipal {
+    /// Creates the principal of a verified token
+    pub fn from_claims(claims: &Claims) -> Self {
+        AuthPrincipal::User {
+            subject: claims.sub.clone(),
+            scopes: claims.scopes().into_iter().map(str::to_string).collect(),
+            roles: claims.roles(),
+        }
+    }
+
+    /// User id, service key name, Basic user name or signing client
+    pub fn subject(&self) -> &str {
+        match self {
+            AuthPrincipal::User { subject, .. } => subject,
+            AuthPrincipal::Service { name } | AuthPrincipal::Basic { name } => name,
+            AuthPrincipal::Signed { client } => client,
+        }
+    }
+
+    /// How the principal authenticated: `user`, `service`, `basic` or `signed`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuthPrincipal::User { .. } => "user",
+            AuthPrincipal::Service { .. } => "service",
+            AuthPrincipal::Basic { .. } => "basic",
+            AuthPrincipal::Signed { .. } => "signed",
+        }
+    }
+}
+
+/// Handle receiving the principal [`RequestContext::set_principal`] records
+///
+/// For middleware that needs the caller once the request was handed on and
+/// may never come back, as when an inner middleware rejects it: insert a
+/// clone in the request extensions before calling the next service.
+#[derive(Debug, Clone, Default)]
+pub struct PrincipalSlot(Rc<RefCell<Option<AuthPrincipal>>>);
+
+impl PrincipalSlot {
+    /// The recorded principal, if any
+    pub fn get(&self) -> Option<AuthPrincipal> {
+        self.0.borrow().clone()
+    }
+}
+
+/// Cross-cutting data about the request being handled
+///
+/// Created by the [`attach_context`] middleware wrapping every listener and
+/// completed by the authentication middleware, so handlers get request
+/// correlation, caller, tenant, deadline and locale from one extractor
+/// instead of a per-feature request extension each.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RequestContext {
+    /// The client's `X-Request-Id` when usable, a new UUID otherwise
+    pub request_id: String,
+    /// W3C trace id from `traceparent`, a new one otherwise
+    pub trace_id: String,
+    /// Whether `trace_id` came from `traceparent`, i.e. a tracer records the request
+    #[serde(skip)]
+    pub traced: bool,
+    /// Id of this service's span for the request, a child of the `traceparent` one
+    pub span_id: String,
+    /// Authenticated caller, set once an authentication middleware accepted the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub principal: Option<AuthPrincipal>,
+    /// The token's `tenant` claim, or `X-Tenant-Id` for other requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Point in time the response is due by
+    #[serde(skip)]
+    pub deadline: Instant,
+    /// Preferred language from `Accept-Language`, e.g. `fr-CA`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// Phase durations reported in `Server-Timing` when `SERVER_TIMING_ENABLED` is set
+    #[serde(skip)]
+    pub timing: ServerTiming,
+}
+
+impl RequestContext {
+    /// Builds the context of a request from its headers
+    pub fn new(headers: &HeaderMap, budget: Duration) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let propagated = header("traceparent").and_then(parse_traceparent);
+        Self {
+            request_id: header(REQUEST_ID_HEADER)
+                .filter(|id| valid_token(id, 128))
+                .map(str::to_string)
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            traced: propagated.is_some(),
+            trace_id: propagated.unwrap_or_else(|| hex::encode(rand::random::<[u8; 16]>())),
+            span_id: hex::encode(rand::random::<[u8; 8]>()),
+            principal: None,
+            tenant: header(TENANT_HEADER)
+                .map(str::trim)
+                .filter(|tenant| valid_token(tenant, 64))
+                .map(str::to_string),
+            deadline: Instant::now() + budget,
+            locale: header(ACCEPT_LANGUAGE.as_str()).and_then(preferred_language),
+            timing: ServerTiming::default(),
+        }
+    }
+
+    /// Time left until the deadline, zero once it has passed
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Records the authenticated caller of `req`
+    ///
+    /// A `tenant` claim replaces the tenant the client asked for in
+    /// `X-Tenant-Id`.
+    pub fn set_principal(req: &impl HttpMessage, principal: AuthPrincipal, claims: Option<&Claims>) {
+        let tenant = claims
+            .and_then(|claims| claims.extra.get("tenant"))
+            .and_then(|tenant| tenant.as_str())
+            .map(str::to_string);
+        if let Some(slot) = req.extensions().get::<PrincipalSlot>() {
+            slot.0.replace(Some(principal.clone()));
+        }
+        Self::update(req, |context| {
+            context.principal = Some(principal);
+            if tenant.is_some() {
+                context.tenant = tenant;
+            }
+        });
+    }
+
+    /// Applies `change` to the context of `req`, creating it if no middleware did
+    pub fn update(req: &impl HttpMessage, change: impl FnOnce(&mut RequestContext)) {
+        let mut extensions = req.extensions_mut();
+        if !extensions.contains::<RequestContext>() {
+            let context = RequestContext::new(req.headers(), DEFAULT_DEADLINE);
+            extensions.insert(context);
+        }
+        if let Some(context) = extensions.get_mut::<RequestContext>() {
+            change(context);
+        }
+    }
+
+    /// Starts a span of the phase `name` in the context of `req`
+    ///
+    /// For middleware, which has no extracted context to call
+    /// [`ServerTiming::span`] on; records nothing without a context.
+    pub fn span(req: &impl HttpMessage, name: &'static str) -> TimingSpan {
+        req.extensions()
+            .get::<RequestContext>()
+            .map(|context| context.timing.clone())
+            .unwrap_or_default()
+            .span(name)
+    }
+
+    fn budget(req: &HttpRequest) -> Duration {
+        req.app_data::<web::Data<Config>>()
+            .map(|config| Duration::from_secs(config.request_deadline_secs))
+            .unwrap_or(DEFAULT_DEADLINE)
+    }
+}
+
+impl FromRequest for RequestContext {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(context) = req.extensions().get::<RequestContext>() {
+            return ready(Ok(context.clone()));
+        }
+        ready(Ok(RequestContext::new(req.headers(), Self::budget(req))))
+    }
+}
+
+/// Middleware creating the [`RequestContext`], for use with `from_fn`
+///
+/// Wraps every listener so the context exists before any other middleware
+/// runs, and makes its ids the current [`LogContext`] while the request is
+/// handled. The request id is echoed in `X-Request-Id` on the response,
+/// including error responses, next to `Server-Timing` when it is enabled and
+/// `X-Served-By` when a `web::Data<Placement>` is registered. The region
+/// affinity check runs in the request's log context.
+pub async fn attach_context(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let budget = RequestContext::budget(req.request());
+    let mut context = RequestContext::new(req.headers(), budget);
+    let config = req.app_data::<web::Data<Config>>();
+    let server_timing = config.is_some_and(|config| config.server_timing_enabled);
+    // Error dumps include the phases even when the header is off
+    let dumps = config.is_some_and(|config| config.error_dump_dir.is_some());
+    context.timing = ServerTiming::new(server_timing || dumps);
+    let timing = context.timing.clone();
+    let request_id = HeaderValue::from_str(&context.request_id).ok();
+    let log_context = LogContext::of(&context);
+    req.extensions_mut().insert(context);
+    let placement = req.app_data::<web::Data<Placement>>().cloned();
+
+    let result = log_context::scope(Some(log_context), async {
+        if let Some(placement) = &placement {
+            placement.check_affinity(req.headers());
+        }
+        next.call(req).await
+    })
+    .await;
+    let mut headers = Vec::new();
+    if let Some(request_id) = request_id {
+        headers.push((HeaderName::from_static(REQUEST_ID_HEADER), request_id));
+    }
+    if let Some(served_by) = placement.and_then(|placement| placement.served_by()) {
+        if let Ok(value) = HeaderValue::from_str(&served_by) {
+            headers.push((HeaderName::from_static(SERVED_BY_HEADER), value));
+        }
+    }
+    if server_timing {
+        if let Ok(value) = HeaderValue::from_str(&timing.header_value(started.elapsed(), budget)) {
+            headers.push((HeaderName::from_static(SERVER_TIMING_HEADER), value));
+        }
+    }
+    match result {
+        Ok(mut res) => {
+            for (name, value) in headers {
+                res.headers_mut().insert(name, value);
+            }
+            Ok(res)
+        }
+        Err(e) => {
+            let mut res = e.error_response();
+            for (name, value) in headers {
+                res.headers_mut().insert(name, value);
+            }
+            Err(actix_web::error::InternalError::from_response(e, res).into())
+        }
+    }
+}
+
+/// Returns whether `value` is a short token safe to log and echo
+fn valid_token(value: &str, max_len: usize) -> bool {
+    !value.is_empty()
+        && value.len() <= max_len
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+/// Extracts the trace id of a W3C `traceparent` header
+fn parse_traceparent(value: &str) -> Option<String> {
+    let mut parts = value.trim().split('-');
+    let (version, trace_id) = (parts.next()?, parts.next()?);
+    let valid = version.len() == 2
+        && version != "ff"
+        && trace_id.len() == 32
+        && trace_id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        && trace_id.bytes().any(|b| b != b'0');
+    valid.then(|| trace_id.to_string())
+}
+
+/// Returns the language with the highest weight in an `Accept-Language` header
+fn preferred_language(value: &str) -> Option<String> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty() && *tag != "*")?;
+            let weight = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            let valid = tag.len() <= 35 && tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+            (valid && weight > 0.0).then_some((tag, weight))
+        })
+        // The first of equally weighted languages wins
+        .fold(None, |best: Option<(&str, f32)>, candidate| match best {
+            Some(best) if best.1 >= candidate.1 => Some(best),
+            _ => Some(candidate),
+        })
+        .map(|(tag, _)| tag.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_context_from_headers() {
+        let context = RequestContext::new(
+            &headers(&[
+                ("x-request-id", "req-42"),
+                ("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+                ("x-tenant-id", "acme"),
+                ("accept-language", "en;q=0.8, fr-CA, de;q=0.9"),
+            ]),
+            Duration::from_secs(10),
+        );
+        assert_eq!(context.request_id, "req-42");
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(context.traced);
+        assert_eq!(context.tenant.as_deref(), Some("acme"));
+        assert_eq!(context.locale.as_deref(), Some("fr-CA"));
+        assert!(context.remaining() > Duration::from_secs(9));
+        assert_eq!(context.principal, None);
+
+        // Unusable values are replaced or dropped rather than echoed
+        let context = RequestContext::new(
+            &headers(&[
+                ("x-request-id", "has spaces"),
+                ("traceparent", "00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+                ("x-tenant-id", "a/b"),
+                ("accept-language", "*, en;q=0"),
+            ]),
+            Duration::ZERO,
+        );
+        assert!(uuid::Uuid::parse_str(&context.request_id).is_ok());
+        assert_eq!(context.trace_id.len(), 32);
+        assert_ne!(context.trace_id, "0".repeat(32));
+        assert!(!context.traced);
+        assert_eq!(context.remaining(), Duration::ZERO);
+        assert_eq!((context.tenant, context.locale), (None, None));
+    }
+
+    #[test]
+    fn test_principal_claims_set_tenant() {
+        let req = TestRequest::default().insert_header(("x-tenant-id", "spoofed")).to_http_request();
+        let mut claims: Claims = serde_json::from_value(serde_json::json!({
+            "sub": "u1", "iss": "test", "exp": 0, "iat": 0, "jti": "t1",
+            "scope": "read:private", "roles": ["admin"], "tenant": "acme"
+        }))
+        .unwrap();
+        RequestContext::set_principal(&req, AuthPrincipal::from_claims(&claims), Some(&claims));
+        let context = req.extensions().get::<RequestContext>().cloned().unwrap();
+        assert_eq!(context.tenant.as_deref(), Some("acme"));
+        assert_eq!(
+            context.principal,
+            Some(AuthPrincipal::User {
+                subject: "u1".to_string(),
+                scopes: vec!["read:private".to_string()],
+                roles: vec!["admin".to_string()],
+            })
+        );
+
+        claims.extra.remove("tenant");
+        let req = TestRequest::default().insert_header(("x-tenant-id", "acme")).to_http_request();
+        RequestContext::set_principal(&req, AuthPrincipal::Service { name: "billing".to_string() }, None);
+        let context = req.extensions().get::<RequestContext>().cloned().unwrap();
+        assert_eq!(context.tenant.as_deref(), Some("acme"));
+        assert_eq!(context.principal.unwrap().subject(), "billing");
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_echoes_request_id() {
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(attach_context))
+                .route(
+                    "/context",
+                    web::get().to(|context: RequestContext| async move { HttpResponse::Ok().json(context) }),
+                )
+                .route(
+                    "/guarded",
+                    web::get().to(HttpResponse::Ok).wrap(from_fn(|_req: ServiceRequest, _next: Next<_>| async {
+                        Err::<ServiceResponse, Error>(AppError::unauthorized("denied").into())
+                    })),
+                )
+                .route(
+                    "/fail",
+                    web::get().to(|| async { Err::<HttpResponse, _>(AppError::validation("bad input")) }),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/context").insert_header(("x-request-id", "abc-1"));
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-1");
+        assert!(!res.headers().contains_key(SERVER_TIMING_HEADER));
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["request_id"], "abc-1");
+        assert_eq!(body["trace_id"].as_str().unwrap().len(), 32);
+        assert!(body.get("principal").is_none());
+
+        let res = call_service(&app, TestRequest::get().uri("/fail").to_request()).await;
+        assert_eq!(res.status(), 400);
+        assert!(res.headers().contains_key(REQUEST_ID_HEADER));
+
+        // Errors raised by inner middleware carry it too
+        let req = TestRequest::get().uri("/guarded").insert_header(("x-request-id", "abc-2"));
+        let err = try_call_service(&app, req.to_request()).await.err().unwrap();
+        let res = err.error_response();
+        assert_eq!(res.status(), 401);
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-2");
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_reports_server_timing() {
+        let config = Config {
+            server_timing_enabled: true,
+            request_deadline_secs: 5,
+            ..Config::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(attach_context))
+                .route(
+                    "/timed",
+                    web::get().to(|context: RequestContext| async move {
+                        context.timing.measure("db", || std::thread::sleep(Duration::from_millis(2)));
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .route(
+                    "/guarded",
+                    web::get().to(HttpResponse::Ok).wrap(from_fn(|req: ServiceRequest, _next: Next<_>| async move {
+                        let _span = RequestContext::span(&req, "auth");
+                        Err::<ServiceResponse, Error>(AppError::unauthorized("denied").into())
+                    })),
+                ),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/timed").to_request()).await;
+        let header = res.headers().get(SERVER_TIMING_HEADER).unwrap().to_str().unwrap().to_string();
+        let phases: Vec<&str> = header.split(", ").map(|entry| entry.split(';').next().unwrap()).collect();
+        assert_eq!(phases, vec!["db", "total", "budget"]);
+        assert!(header.ends_with("budget;dur=5000.0"), "{}", header);
+
+        // Spans of rejecting middleware are reported on the error response
+        let err = try_call_service(&app, TestRequest::get().uri("/guarded").to_request()).await.err().unwrap();
+        let header = err.error_response().headers().get(SERVER_TIMING_HEADER).cloned().unwrap();
+        assert!(header.to_str().unwrap().starts_with("auth;dur="), "{:?}", header);
+    }
+}
@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use actix_web::cookie::{time, Cookie, CookieBuilder, SameSite};
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and verifies signed cookies for lightweight state
+///
+/// Unlike [`CookieSessionManager`](crate::auth::CookieSessionManager) nothing
+/// is stored server side: the cookie carries its value in the clear (base64
+/// encoded), an expiry and an HMAC-SHA256 over both and the cookie name, so
+/// clients can read it but neither alter it, extend it nor move it to
+/// another cookie. Only the first key of `COOKIE_SIGNING_KEYS` signs; the
+/// others still verify, so a key is rotated by prepending its replacement
+/// and dropped once the cookies it signed have expired.
+pub struct CookieSigner {
+    keys: Vec<Vec<u8>>,
+    secure: bool,
+}
+
+impl CookieSigner {
+    /// Creates a signer from keys ordered newest first
+    ///
+    /// # Errors
+    /// Returns a configuration error when `keys` is empty
+    pub fn new<K: AsRef<[u8]>>(keys: &[K]) -> AppResult<Self> {
+        if keys.is_empty() {
+            return Err(AppError::config("at least one cookie signing key is required"));
+        }
+        Ok(Self {
+            keys: keys.iter().map(|key| key.as_ref().to_vec()).collect(),
+            secure: true,
+        })
+    }
+
+    /// Builds the signer from `COOKIE_SIGNING_KEYS` and `COOKIE_SECURE`
+    ///
+    /// Without keys an ephemeral one is generated, which invalidates every
+    /// signed cookie on restart; a warning says so.
+    pub fn from_config(config: &Config) -> Self {
+        let keys = if config.cookie_signing_keys.is_empty() {
+            warn!("COOKIE_SIGNING_KEYS is not set; using an ephemeral key (signed cookies won't survive restarts)");
+            vec![crate::secrets::generate_secret()]
+        } else {
+            config.cookie_signing_keys.clone()
+        };
+        Self {
+            keys: keys.into_iter().map(String::into_bytes).collect(),
+            secure: config.cookie_secure,
+        }
+    }
+
+    /// Sets whether cookies carry the `Secure` attribute
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Returns a cookie named `name` holding `value` for `ttl`
+    pub fn issue(&self, name: &str, value: &str, ttl: Duration) -> Cookie<'static> {
+        let expires = Utc::now().timestamp() + ttl.as_secs() as i64;
+        let payload = format!("{}.{}", URL_SAFE_NO_PAD.encode(value), expires);
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&self.keys[0], name, &payload).finalize().into_bytes());
+        self.cookie(name, format!("{}.{}", payload, signature))
+            .max_age(time::Duration::seconds(ttl.as_secs() as i64))
+            .finish()
+    }
+
+    /// Returns a cookie holding `value` serialized as JSON
+    pub fn issue_json<T: Serialize>(&self, name: &str, value: &T, ttl: Duration) -> AppResult<Cookie<'static>> {
+        let json = serde_json::to_string(value).map_err(|e| AppError::internal(e.to_string()))?;
+        Ok(self.issue(name, &json, ttl))
+    }
+
+    /// Returns the value of the request's `name` cookie if its signature holds
+    ///
+    /// Missing, malformed, forged and expired cookies all yield `None`.
+    pub fn read(&self, req: &HttpRequest, name: &str) -> Option<String> {
+        self.verify(name, req.cookie(name)?.value())
+    }
+
+    /// Returns the request's `name` cookie deserialized from JSON
+    pub fn read_json<T: DeserializeOwned>(&self, req: &HttpRequest, name: &str) -> Option<T> {
+        serde_json::from_str(&self.read(req, name)?).ok()
+    }
+
+    /// Checks a cookie value issued under `name` and returns what it holds
+    pub fn verify(&self, name: &str, cookie_value: &str) -> Option<String> {
+        let (payload, signature) = cookie_value.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        if !self
+            .keys
+            .iter()
+            .any(|key| self.mac(key, name, payload).verify_slice(&signature).is_ok())
+        {
+            return None;
+        }
+        let (value, expires) = payload.split_once('.')?;
+        if expires.parse::<i64>().ok()? <= Utc::now().timestamp() {
+            return None;
+        }
+        String::from_utf8(URL_SAFE_NO_PAD.decode(value).ok()?).ok()
+    }
+
+    /// Returns the cookie clearing `name`
+    pub fn removal(&self, name: &str) -> Cookie<'static> {
+        let mut cookie = self.cookie(name, String::new()).finish();
+        cookie.make_removal();
+        cookie
+    }
+
+    fn mac(&self, key: &[u8], name: &str, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn cookie(&self, name: &str, value: String) -> CookieBuilder<'static> {
+        Cookie::build(name.to_string(), value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn signer(keys: &[&str]) -> CookieSigner {
+        CookieSigner::new(keys).unwrap()
+    }
+
+    #[test]
+    fn test_signed_cookie_round_trip() {
+        let signer = signer(&["cookie-signing-key-0000000000000"]);
+        let cookie = signer.issue("theme", "dark", Duration::from_secs(60));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+
+        let req = TestRequest::default().cookie(cookie.clone()).to_http_request();
+        assert_eq!(signer.read(&req, "theme"), Some("dark".to_string()));
+        assert_eq!(signer.read(&req, "missing"), None);
+
+        let cookie = signer
+            .issue_json("prefs", &serde_json::json!({ "page_size": 50 }), Duration::from_secs(60))
+            .unwrap();
+        let req = TestRequest::default().cookie(cookie).to_http_request();
+        let prefs: serde_json::Value = signer.read_json(&req, "prefs").unwrap();
+        assert_eq!(prefs["page_size"], 50);
+        assert!(CookieSigner::new::<&str>(&[]).is_err());
+    }
+
+    #[test]
+    fn test_tampered_moved_and_expired_cookies_are_rejected() {
+        let signer = signer(&["cookie-signing-key-0000000000000"]);
+        let value = signer.issue("role", "user", Duration::from_secs(60)).value().to_string();
+        assert_eq!(signer.verify("role", &value), Some("user".to_string()));
+
+        let (payload, signature) = value.rsplit_once('.').unwrap();
+        let (_, expires) = payload.split_once('.').unwrap();
+        let forged = format!("{}.{}.{}", URL_SAFE_NO_PAD.encode("admin"), expires, signature);
+        assert_eq!(signer.verify("role", &forged), None);
+        assert_eq!(signer.verify("other", &value), None);
+        assert_eq!(signer.verify("role", "garbage"), None);
+
+        let expired = signer.issue("role", "user", Duration::ZERO);
+        assert_eq!(signer.verify("role", expired.value()), None);
+    }
+
+    #[test]
+    fn test_previous_keys_verify_during_rotation() {
+        let old = signer(&["old-cookie-signing-key-000000000"]);
+        let rotated = signer(&["new-cookie-signing-key-000000000", "old-cookie-signing-key-000000000"]);
+        let retired = signer(&["new-cookie-signing-key-000000000"]);
+
+        let before = old.issue("cart", "3", Duration::from_secs(60));
+        assert_eq!(rotated.verify("cart", before.value()), Some("3".to_string()));
+        assert_eq!(retired.verify("cart", before.value()), None);
+
+        let after = rotated.issue("cart", "4", Duration::from_secs(60));
+        assert_eq!(retired.verify("cart", after.value()), Some("4".to_string()));
+        assert_eq!(old.verify("cart", after.value()), None);
+    }
+}
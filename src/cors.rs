@@ -0,0 +1,614 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_cors::{Cors, CorsMiddleware};
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use actix_web::http::Method;
+use actix_web::Error;
+use futures::future::LocalBoxFuture;
+
+use crate::auth::quotas::QUOTA_REMAINING_HEADER;
+use crate::auth::signatures::SIGNATURE_HEADER;
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::reload::LiveConfig;
+use crate::routes::RouteRegistry;
+use crate::{context, server_timing, simulation, version_skew};
+
+/// What browsers may do cross-origin: the `CORS_*` settings, or one of the
+/// named `CORS_POLICIES`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorsPolicy {
+    /// Allowed origins; `*` allows any
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    /// Request headers allowed besides the ones the API itself reads
+    pub headers: Vec<String>,
+    /// Whether cookies and `Authorization` may be sent along
+    pub credentials: bool,
+    pub max_age_secs: usize,
+}
+
+impl CorsPolicy {
+    /// The default policy, from the `CORS_*` settings
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            origins: config.cors_allowed_origins.clone(),
+            methods: config.cors_allowed_methods.clone(),
+            headers: config.cors_allowed_headers.clone(),
+            credentials: false,
+            max_age_secs: config.cors_max_age_secs,
+        }
+    }
+
+    /// Parses the `CORS_POLICIES` setting
+    ///
+    /// Policies are separated by `;`, each written as `name: origins=A,B
+    /// [methods=GET,POST] [headers=X-A,X-B] [credentials=true]
+    /// [max_age=SECS]`, e.g. `partners: origins=https://app.example.com
+    /// credentials=true`. Methods, headers and max age default to those of
+    /// `base`.
+    ///
+    /// # Errors
+    /// Returns a config error for malformed entries, unknown fields, invalid
+    /// methods or headers, and credentials allowed for any origin
+    pub fn parse_list(spec: &str, base: &CorsPolicy) -> AppResult<Vec<(String, Self)>> {
+        let mut policies: Vec<(String, Self)> = Vec::new();
+        for entry in spec.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (name, policy) = Self::parse(entry, base)?;
+            if policies.iter().any(|(other, _)| *other == name) {
+                return Err(AppError::config(format!("CORS policy '{}' is defined twice", name)));
+            }
+            policies.push((name, policy));
+        }
+        Ok(policies)
+    }
+
+    fn parse(entry: &str, base: &CorsPolicy) -> AppResult<(String, Self)> {
+        let invalid = |reason: &str| AppError::config(format!("CORS policy '{}' {}", entry, reason));
+        let (name, fields) = entry
+            .split_once(':')
+            .ok_or_else(|| invalid("must look like 'name: origins=A,B'"))?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(invalid("needs a name of letters, digits, '-' or '_'"));
+        }
+
+        let list = |value: &str| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        let mut origins = None;
+        let mut policy = Self {
+            credentials: false,
+            ..base.clone()
+        };
+        for field in fields.split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| invalid(&format!("has a field '{}' without '='", field)))?;
+            match key {
+                "origins" => origins = Some(list(value)),
+                "methods" => {
+                    policy.methods = list(value).into_iter().map(|method| method.to_uppercase()).collect();
+                    if let Some(method) = policy
+                        .methods
+                        .iter()
+                        .find(|method| Method::from_bytes(method.as_bytes()).is_err())
+                    {
+                        return Err(invalid(&format!("has an invalid method '{}'", method)));
+                    }
+                }
+                "headers" => {
+                    policy.headers = list(value);
+                    if let Some(header) = policy
+                        .headers
+                        .iter()
+                        .find(|header| HeaderName::from_bytes(header.as_bytes()).is_err())
+                    {
+                        return Err(invalid(&format!("has an invalid header name '{}'", header)));
+                    }
+                }
+                "credentials" => {
+                    policy.credentials = value.parse().map_err(|_| invalid("needs credentials=true or false"))?
+                }
+                "max_age" => policy.max_age_secs = value.parse().map_err(|_| invalid("needs max_age=SECS"))?,
+                other => return Err(invalid(&format!("has an unknown field '{}'", other))),
+            }
+        }
+
+        policy.origins = origins.ok_or_else(|| invalid("needs origins=A,B (or origins=*)"))?;
+        if policy.credentials && policy.allows_any_origin() {
+            return Err(invalid("cannot allow credentials from any origin; list the origins"));
+        }
+        Ok((name.to_string(), policy))
+    }
+
+    fn allows_any_origin(&self) -> bool {
+        self.origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Builds the actix-cors middleware of the policy
+    ///
+    /// The headers the API reads (`Authorization`, `X-Api-Key`, ...) are
+    /// always allowed and the ones it sets always exposed.
+    pub fn build(&self) -> Cors {
+        let cors = if self.allows_any_origin() {
+            Cors::default().allow_any_origin()
+        } else {
+            self.origins
+                .iter()
+                .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        };
+        self.configure(cors)
+    }
+
+    /// Builds the middleware of the policy, checking origins against the
+    /// `CORS_ALLOWED_ORIGINS` in force in `live` instead of the policy's own
+    ///
+    /// The allowed origin is echoed back even when any is allowed.
+    pub fn build_live(&self, live: Arc<LiveConfig>) -> Cors {
+        let cors = Cors::default().allowed_origin_fn(move |origin, _| {
+            live.load()
+                .cors_allowed_origins
+                .iter()
+                .any(|allowed| allowed == "*" || allowed.as_bytes() == origin.as_bytes())
+        });
+        self.configure(cors)
+    }
+
+    fn configure(&self, cors: Cors) -> Cors {
+        let cors = if self.credentials {
+            cors.supports_credentials()
+        } else {
+            cors
+        };
+
+        let headers = [
+            AUTHORIZATION,
+            ACCEPT,
+            CONTENT_TYPE,
+            HeaderName::from_static("x-api-key"),
+            HeaderName::from_static(context::REQUEST_ID_HEADER),
+            HeaderName::from_static(context::TENANT_HEADER),
+            HeaderName::from_static(version_skew::MIN_API_VERSION_HEADER),
+            HeaderName::from_static(SIGNATURE_HEADER),
+            HeaderName::from_static(simulation::SIMULATE_HEADER),
+        ]
+        .into_iter()
+        // Validated when the configuration was loaded
+        .chain(self.headers.iter().filter_map(|header| header.parse().ok()));
+
+        cors.allowed_methods(self.methods.iter().map(String::as_str))
+            .allowed_headers(headers)
+            .expose_headers(vec![
+                HeaderName::from_static(context::REQUEST_ID_HEADER),
+                HeaderName::from_static(server_timing::SERVER_TIMING_HEADER),
+                HeaderName::from_static(version_skew::API_VERSION_HEADER),
+                HeaderName::from_static(QUOTA_REMAINING_HEADER),
+                HeaderName::from_static(simulation::SIMULATED_HEADER),
+            ])
+            .max_age(self.max_age_secs)
+    }
+}
+
+/// Parses the `CORS_ROUTES` setting, `PATH=POLICY,...`
+///
+/// # Errors
+/// Returns a config error for malformed entries
+pub fn parse_routes(spec: &str) -> AppResult<Vec<(String, String)>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = || AppError::config(format!("CORS route '{}' must look like 'PATH=POLICY'", entry));
+            let (path, policy) = entry.split_once('=').ok_or_else(invalid)?;
+            let (path, policy) = (path.trim(), policy.trim());
+            if !path.starts_with('/') || policy.is_empty() {
+                return Err(invalid());
+            }
+            Ok((path.to_string(), policy.to_string()))
+        })
+        .collect()
+}
+
+/// CORS middleware applying the policy attached to the matched route, and
+/// the default policy everywhere else
+///
+/// Policies are per path: a preflight request is answered before its method
+/// selects a route, so every method of a path shares the path's policy.
+#[derive(Debug, Clone, Default)]
+pub struct CorsRouter {
+    default: CorsPolicy,
+    routes: Vec<(String, CorsPolicy)>,
+    live: Option<Arc<LiveConfig>>,
+}
+
+impl CorsRouter {
+    /// Creates a router applying `default` to every route
+    pub fn new(default: CorsPolicy) -> Self {
+        Self {
+            default,
+            routes: Vec::new(),
+            live: None,
+        }
+    }
+
+    /// Applies `policy` to the route with the path pattern `path`
+    pub fn route(mut self, path: impl Into<String>, policy: CorsPolicy) -> Self {
+        self.routes.push((path.into(), policy));
+        self
+    }
+
+    /// Checks origins on routes without a named policy against the
+    /// `CORS_ALLOWED_ORIGINS` in force in `live`, following reloads
+    pub fn with_live_config(mut self, live: Arc<LiveConfig>) -> Self {
+        self.live = Some(live);
+        self
+    }
+
+    /// Builds the router from the `CORS_*` settings and the policies the
+    /// routes of `routes` name
+    ///
+    /// # Errors
+    /// Returns a config error when a route names an undefined policy, or
+    /// routes sharing a path name different ones
+    pub fn from_config(config: &Config, routes: &RouteRegistry) -> AppResult<Self> {
+        let mut router = Self::new(CorsPolicy::from_config(config));
+        for spec in routes.routes() {
+            let Some(name) = &spec.cors_policy else {
+                continue;
+            };
+            let policy = config
+                .cors_policies
+                .iter()
+                .find(|(policy, _)| policy == name)
+                .map(|(_, policy)| policy)
+                .ok_or_else(|| AppError::config(format!("route {} uses undefined CORS policy {}", spec.path, name)))?;
+            match router.routes.iter().find(|(path, _)| path == spec.path) {
+                Some((_, existing)) if existing != policy => {
+                    return Err(AppError::config(format!(
+                        "routes of {} use different CORS policies",
+                        spec.path
+                    )));
+                }
+                Some(_) => {}
+                None => router = router.route(spec.path, policy.clone()),
+            }
+        }
+        Ok(router)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CorsRouter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CorsRouterService<S>;
+    type InitError = ();
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let service = Rc::new(service);
+        let default = match &self.live {
+            Some(live) => self.default.build_live(live.clone()),
+            None => self.default.build(),
+        }
+        .new_transform(SharedService(Rc::clone(&service)));
+        let routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|(path, policy)| {
+                (
+                    path.clone(),
+                    policy.build().new_transform(SharedService(Rc::clone(&service))),
+                )
+            })
+            .collect();
+        Box::pin(async move {
+            let default = default.await?;
+            let mut by_path = HashMap::new();
+            for (path, middleware) in routes {
+                by_path.insert(path, middleware.await?);
+            }
+            Ok(CorsRouterService {
+                default,
+                routes: by_path,
+            })
+        })
+    }
+}
+
+/// The wrapped service, shared by the middleware of every policy
+pub struct SharedService<S>(Rc<S>);
+
+impl<S> Service<ServiceRequest> for SharedService<S>
+where
+    S: Service<ServiceRequest>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        self.0.call(req)
+    }
+}
+
+/// Service produced by [`CorsRouter`]
+pub struct CorsRouterService<S> {
+    default: CorsMiddleware<SharedService<S>>,
+    routes: HashMap<String, CorsMiddleware<SharedService<S>>>,
+}
+
+impl<S, B> Service<ServiceRequest> for CorsRouterService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(default);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let middleware = req
+            .match_pattern()
+            .and_then(|path| self.routes.get(&path))
+            .unwrap_or(&self.default);
+        middleware.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    fn preflight(path: &str, origin: &str, method: &str, headers: &str) -> TestRequest {
+        TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri(path)
+            .insert_header(("Origin", origin.to_string()))
+            .insert_header(("Access-Control-Request-Method", method.to_string()))
+            .insert_header(("Access-Control-Request-Headers", headers.to_string()))
+    }
+
+    #[actix_web::test]
+    async fn test_cors_policy() {
+        let policy = |origins: &[&str]| CorsPolicy {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            headers: vec!["x-client-version".to_string()],
+            max_age_secs: 600,
+            ..CorsPolicy::from_config(&Config::default())
+        };
+
+        let app = init_service(
+            App::new()
+                .wrap(policy(&["https://app.example.com"]).build())
+                .route("/public", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let res = call_service(
+            &app,
+            preflight("/public", "https://app.example.com", "GET", "x-client-version").to_request(),
+        )
+        .await;
+        assert!(res.status().is_success());
+        assert_eq!(res.headers().get("access-control-max-age").unwrap(), "600");
+        let res = call_service(
+            &app,
+            preflight("/public", "https://evil.example.com", "GET", "authorization").to_request(),
+        )
+        .await;
+        assert!(!res.status().is_success());
+        let res = call_service(
+            &app,
+            preflight("/public", "https://app.example.com", "PATCH", "authorization").to_request(),
+        )
+        .await;
+        assert!(!res.status().is_success());
+
+        // No origins: same-origin only
+        let app = init_service(
+            App::new()
+                .wrap(policy(&[]).build())
+                .route("/public", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let res = call_service(
+            &app,
+            preflight("/public", "https://app.example.com", "GET", "authorization").to_request(),
+        )
+        .await;
+        assert!(!res.status().is_success());
+
+        let app = init_service(
+            App::new()
+                .wrap(policy(&["*"]).build())
+                .route("/public", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let res = call_service(
+            &app,
+            preflight("/public", "https://anything.example.com", "DELETE", "x-api-key").to_request(),
+        )
+        .await;
+        assert!(res.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_router_applies_the_policy_of_the_matched_route() {
+        let base = CorsPolicy::from_config(&Config::default());
+        let policies = CorsPolicy::parse_list(
+            "open: origins=*; partners: origins=https://app.example.com methods=GET credentials=true",
+            &base,
+        )
+        .unwrap();
+        let router = CorsRouter::new(CorsPolicy {
+            origins: Vec::new(),
+            ..base
+        })
+        .route("/public", policies[0].1.clone())
+        .route("/items/{id}", policies[1].1.clone());
+        let app = init_service(
+            App::new()
+                .wrap(router)
+                .route("/public", web::get().to(HttpResponse::Ok))
+                .route("/items/{id}", web::get().to(HttpResponse::Ok))
+                .route("/other", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = call_service(
+            &app,
+            preflight("/public", "https://anything.example.com", "POST", "x-api-key").to_request(),
+        )
+        .await;
+        assert!(res.status().is_success());
+        assert!(!res.headers().contains_key("access-control-allow-credentials"));
+
+        let res = call_service(
+            &app,
+            preflight("/items/7", "https://app.example.com", "GET", "authorization").to_request(),
+        )
+        .await;
+        assert!(res.status().is_success());
+        assert_eq!(res.headers().get("access-control-allow-credentials").unwrap(), "true");
+        let res = call_service(
+            &app,
+            preflight("/items/7", "https://anything.example.com", "GET", "authorization").to_request(),
+        )
+        .await;
+        assert!(!res.status().is_success());
+        let res = call_service(
+            &app,
+            preflight("/items/7", "https://app.example.com", "DELETE", "authorization").to_request(),
+        )
+        .await;
+        assert!(!res.status().is_success());
+
+        // Everything else gets the default policy
+        let res = call_service(
+            &app,
+            preflight("/other", "https://app.example.com", "GET", "authorization").to_request(),
+        )
+        .await;
+        assert!(!res.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_live_origins_follow_reloads() {
+        let live = Arc::new(LiveConfig::new(Config {
+            cors_allowed_origins: vec!["https://old.example.com".to_string()],
+            ..Config::default()
+        }));
+        let router = CorsRouter::from_config(&live.load(), &RouteRegistry::new())
+            .unwrap()
+            .with_live_config(live.clone());
+        let app = init_service(App::new().wrap(router).route("/public", web::get().to(HttpResponse::Ok))).await;
+        let allowed = |origin: &'static str| {
+            let app = &app;
+            async move {
+                let res = call_service(app, preflight("/public", origin, "GET", "authorization").to_request()).await;
+                res.status().is_success()
+            }
+        };
+        assert!(allowed("https://old.example.com").await);
+        assert!(!allowed("https://new.example.com").await);
+
+        live.apply(&Config {
+            cors_allowed_origins: vec!["https://new.example.com".to_string()],
+            ..Config::default()
+        });
+        assert!(!allowed("https://old.example.com").await);
+        assert!(allowed("https://new.example.com").await);
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let base = CorsPolicy::from_config(&Config::default());
+        let policies = CorsPolicy::parse_list(
+            "open: origins=*; partners: origins=https://a.example.com,https://b.example.com methods=get headers=x-trace credentials=true max_age=60;",
+            &base,
+        )
+        .unwrap();
+        assert_eq!(
+            policies[0],
+            (
+                "open".to_string(),
+                CorsPolicy {
+                    origins: vec!["*".to_string()],
+                    ..base.clone()
+                }
+            )
+        );
+        let partners = &policies[1].1;
+        assert_eq!(partners.origins, vec!["https://a.example.com", "https://b.example.com"]);
+        assert_eq!(
+            (partners.methods.clone(), partners.headers.clone()),
+            (vec!["GET".to_string()], vec!["x-trace".to_string()])
+        );
+        assert_eq!((partners.credentials, partners.max_age_secs), (true, 60));
+        assert!(CorsPolicy::parse_list("", &base).unwrap().is_empty());
+
+        for spec in [
+            "open origins=*",
+            "open: methods=GET",
+            "open: origins=* credentials=true",
+            "open: origins=* methods=GE(T",
+            "open: origins=* colour=blue",
+            "open: origins=*; open: origins=*",
+        ] {
+            assert!(
+                matches!(CorsPolicy::parse_list(spec, &base), Err(AppError::Config { .. })),
+                "{}",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = Config {
+            cors_policies: CorsPolicy::parse_list("partners: origins=https://app.example.com", &CorsPolicy::default())
+                .unwrap(),
+            ..Config::default()
+        };
+        let routes = RouteRegistry::app_server()
+            .cors_policy_on(&[("/private".to_string(), "partners".to_string())])
+            .unwrap();
+        let router = CorsRouter::from_config(&config, &routes).unwrap();
+        assert_eq!(router.routes.len(), 1);
+        assert_eq!(router.routes[0].0, "/private");
+
+        let routes = RouteRegistry::app_server()
+            .cors_policy_on(&[("/private".to_string(), "nope".to_string())])
+            .unwrap();
+        assert!(CorsRouter::from_config(&config, &routes).is_err());
+        assert_eq!(
+            parse_routes("/public=open, /private=partners").unwrap()[1],
+            ("/private".to_string(), "partners".to_string())
+        );
+        assert!(parse_routes("public=open").is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use std::cell::RefCell;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+
+/// Prefix identifying the envelope format, so the scheme can evolve
+const VERSION_PREFIX: &str = "v1:";
+
+const NONCE_LEN: usize = 12;
+
+/// Prefix of configuration values encrypted with the `CONFIG_MASTER_KEY`
+pub const ENCRYPTED_VALUE_PREFIX: &str = "enc:";
+
+/// Authenticated encryption for data at rest (TOTP secrets, ...)
+///
+/// AES-256-GCM with a random nonce per message. The key is the SHA-256 of
+/// `DATA_ENCRYPTION_KEY`, so any sufficiently strong secret string works.
+/// Ciphertexts are `v1:` followed by base64 of nonce and ciphertext.
+#[derive(Clone)]
+pub struct Cipher {
+    cipher: Aes256Gcm,
+}
+
+impl Cipher {
+    /// Creates a cipher keyed from `secret`
+    pub fn new(secret: &[u8]) -> Self {
+        let key = Sha256::digest(secret);
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        }
+    }
+
+    /// Builds the cipher from `DATA_ENCRYPTION_KEY`
+    ///
+    /// Without a configured key an ephemeral one is generated, which makes
+    /// stored ciphertexts unreadable after a restart; a warning says so.
+    pub fn from_config(config: &Config) -> Self {
+        let secret = match &config.data_encryption_key {
+            Some(secret) => secret.clone(),
+            None => {
+                warn!("DATA_ENCRYPTION_KEY is not set; using an ephemeral key (encrypted data won't survive restarts)");
+                crate::secrets::generate_secret()
+            }
+        };
+        Self::new(secret.as_bytes())
+    }
+
+    /// Encrypts `plaintext`
+    pub fn encrypt(&self, plaintext: &[u8]) -> AppResult<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| AppError::internal("encryption failed"))?;
+
+        let mut envelope = nonce.to_vec();
+        envelope.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}", VERSION_PREFIX, STANDARD.encode(envelope)))
+    }
+
+    /// Decrypts a value produced by [`Cipher::encrypt`]
+    ///
+    /// # Errors
+    /// Returns an internal error for malformed, tampered or foreign ciphertexts
+    pub fn decrypt(&self, encrypted: &str) -> AppResult<Vec<u8>> {
+        let envelope = encrypted
+            .strip_prefix(VERSION_PREFIX)
+            .and_then(|encoded| STANDARD.decode(encoded).ok())
+            .filter(|envelope| envelope.len() > NONCE_LEN)
+            .ok_or_else(|| AppError::internal("malformed ciphertext"))?;
+        let (nonce, ciphertext) = envelope.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| AppError::internal("decryption failed (wrong key or tampered data)"))
+    }
+}
+
+/// Reads the master key of encrypted configuration values from
+/// `CONFIG_MASTER_KEY`, or from the file named by `CONFIG_MASTER_KEY_FILE`
+///
+/// # Errors
+/// Returns a configuration error when the key file cannot be read
+pub fn config_master_key(lookup: &dyn Fn(&str) -> Option<String>) -> AppResult<Option<String>> {
+    if let Some(key) = lookup("CONFIG_MASTER_KEY").filter(|key| !key.is_empty()) {
+        return Ok(Some(key));
+    }
+    let Some(path) = lookup("CONFIG_MASTER_KEY_FILE").filter(|path| !path.is_empty()) else {
+        return Ok(None);
+    };
+    let key = std::fs::read_to_string(&path)
+        .map_err(|e| AppError::config(format!("Failed to read CONFIG_MASTER_KEY_FILE {}: {}", path, e)))?;
+    Ok(Some(key.trim().to_string()))
+}
+
+/// Encrypts `value` for use as an `enc:` configuration value
+pub fn encrypt_config_value(master_key: &str, value: &str) -> AppResult<String> {
+    let encrypted = Cipher::new(master_key.as_bytes()).encrypt(value.as_bytes())?;
+    Ok(format!("{}{}", ENCRYPTED_VALUE_PREFIX, encrypted))
+}
+
+/// Variable lookup decrypting `enc:` values on the way
+///
+/// Values are encrypted with [`encrypt_config_value`], so they can be
+/// committed to configuration files; only the master key must stay secret.
+/// Lookups cannot fail, so the first decryption failure is kept and
+/// reported by [`ConfigDecryptor::finish`], the failing variable reading as
+/// unset meanwhile.
+pub struct ConfigDecryptor<'a> {
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    cipher: Option<Cipher>,
+    error: RefCell<Option<AppError>>,
+}
+
+impl<'a> ConfigDecryptor<'a> {
+    /// Wraps `lookup`, taking the master key from it
+    ///
+    /// # Errors
+    /// Same as [`config_master_key`]
+    pub fn new(lookup: &'a dyn Fn(&str) -> Option<String>) -> AppResult<Self> {
+        Ok(Self {
+            lookup,
+            cipher: config_master_key(lookup)?.map(|key| Cipher::new(key.as_bytes())),
+            error: RefCell::new(None),
+        })
+    }
+
+    /// Returns the variable `name`, decrypted if it is an `enc:` value
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = (self.lookup)(name)?;
+        let Some(encrypted) = value.strip_prefix(ENCRYPTED_VALUE_PREFIX) else {
+            return Some(value);
+        };
+        let decrypted = match &self.cipher {
+            Some(cipher) => cipher
+                .decrypt(encrypted)
+                .ok()
+                .and_then(|plaintext| String::from_utf8(plaintext).ok())
+                .ok_or_else(|| {
+                    AppError::config(format!("{} could not be decrypted (wrong CONFIG_MASTER_KEY or tampered value)", name))
+                }),
+            None => Err(AppError::config(format!("{} is encrypted but CONFIG_MASTER_KEY is not set", name))),
+        };
+        match decrypted {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.error.borrow_mut().get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    /// Reports the first decryption failure
+    ///
+    /// # Errors
+    /// Returns the configuration error of the first value that could not be decrypted
+    pub fn finish(self) -> AppResult<()> {
+        match self.error.into_inner() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_tamper_detection() {
+        let cipher = Cipher::new(b"crypto-test-key-crypto-test-key");
+        let encrypted = cipher.encrypt(b"JBSWY3DPEHPK3PXP").unwrap();
+        assert!(encrypted.starts_with("v1:"));
+        assert_ne!(encrypted, cipher.encrypt(b"JBSWY3DPEHPK3PXP").unwrap());
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), b"JBSWY3DPEHPK3PXP");
+
+        let other = Cipher::new(b"another-key-another-key-another");
+        assert!(other.decrypt(&encrypted).is_err());
+
+        let mut tampered = encrypted.clone();
+        tampered.pop();
+        tampered.push(if encrypted.ends_with('A') { 'B' } else { 'A' });
+        assert!(cipher.decrypt(&tampered).is_err());
+        assert!(cipher.decrypt("plaintext").is_err());
+    }
+
+    #[test]
+    fn test_config_values_are_decrypted_with_the_master_key() {
+        let value = encrypt_config_value("master-key-master-key-master-key", "s3cr3t").unwrap();
+        assert!(value.starts_with("enc:v1:"));
+        let vars = std::collections::HashMap::from([
+            ("CONFIG_MASTER_KEY", "master-key-master-key-master-key".to_string()),
+            ("JWT_SECRET", value.clone()),
+            ("PLAIN", "as-is".to_string()),
+        ]);
+        let lookup = |name: &str| vars.get(name).cloned();
+        let decryptor = ConfigDecryptor::new(&lookup).unwrap();
+        assert_eq!(decryptor.get("JWT_SECRET").as_deref(), Some("s3cr3t"));
+        assert_eq!(decryptor.get("PLAIN").as_deref(), Some("as-is"));
+        assert!(decryptor.finish().is_ok());
+
+        let lookup = |name: &str| (name == "JWT_SECRET").then(|| value.clone());
+        let decryptor = ConfigDecryptor::new(&lookup).unwrap();
+        assert_eq!(decryptor.get("JWT_SECRET"), None);
+        let err = decryptor.finish().unwrap_err();
+        assert!(matches!(&err, AppError::Config { message } if message.contains("CONFIG_MASTER_KEY is not set")), "{}", err);
+    }
+}
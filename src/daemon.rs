@@ -0,0 +1,360 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use tokio::sync::Notify;
+
+use crate::error::{AppError, AppResult};
+
+/// Name of the Windows service the server runs as with `--service`
+pub const SERVICE_NAME: &str = "simple-api-demo";
+
+/// Process management options, from the `--daemon`, `--pidfile`,
+/// `--log-file` and `--service` flags
+///
+/// On Unix, `SIGTERM` drains in-flight requests before exiting while
+/// `SIGINT` and `SIGQUIT` stop immediately. On Windows, `--service` runs the
+/// server under the service control manager, whose Stop and Shutdown
+/// controls drain in-flight requests like `SIGTERM`; outside a service,
+/// Ctrl-C stops the servers immediately.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DaemonOptions {
+    /// Detach from the terminal (`--daemon`, Unix only)
+    pub detach: bool,
+    /// File holding the server's process id while it runs (`--pidfile PATH`)
+    pub pidfile: Option<PathBuf>,
+    /// Where a detached server writes its logs (`--log-file PATH`); without it
+    /// they are discarded
+    pub log_file: Option<PathBuf>,
+    /// Run under the Windows service control manager (`--service`, Windows only)
+    pub service: bool,
+    /// How long a service stop may take, announced to the service control
+    /// manager while the listeners drain
+    pub stop_timeout: Duration,
+}
+
+impl DaemonOptions {
+    /// Detaches the process when `--daemon` was given
+    ///
+    /// Must run before the async runtime starts: only the calling thread
+    /// survives the fork. The working directory is kept, so relative paths in
+    /// the configuration still resolve.
+    ///
+    /// # Errors
+    /// Returns a server error when forking fails, and a config error on
+    /// platforms without daemon support
+    #[cfg(unix)]
+    pub fn detach(&self) -> AppResult<()> {
+        if !self.detach {
+            return Ok(());
+        }
+        let cwd = std::env::current_dir()
+            .map_err(|e| AppError::server(format!("Failed to read the working directory: {}", e)))?;
+        let mut daemon = daemonize::Daemonize::new().working_directory(cwd).umask(0o027);
+        if let Some(path) = &self.log_file {
+            let log = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| AppError::config(format!("Failed to open log file {}: {}", path.display(), e)))?;
+            let stderr = log
+                .try_clone()
+                .map_err(|e| AppError::server(format!("Failed to duplicate log file handle: {}", e)))?;
+            daemon = daemon.stdout(log).stderr(stderr);
+        }
+        daemon
+            .start()
+            .map_err(|e| AppError::server(format!("Failed to daemonize: {}", e)))
+    }
+
+    /// Detaching is only supported on Unix
+    #[cfg(not(unix))]
+    pub fn detach(&self) -> AppResult<()> {
+        if self.detach {
+            return Err(AppError::config(
+                "--daemon is only supported on Unix",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runs `serve`, under the service control manager when `--service` was
+    /// given
+    ///
+    /// A service hands `serve` the trigger its Stop and Shutdown controls
+    /// fire, and reports the service stopped once `serve` returns.
+    ///
+    /// # Errors
+    /// Returns what `serve` returns, and a server error when the service
+    /// control manager cannot be reached, e.g. when started from a console
+    #[cfg(windows)]
+    pub fn run<F>(&self, serve: F) -> AppResult<()>
+    where
+        F: FnOnce(Option<ShutdownTrigger>) -> AppResult<()> + Send + 'static,
+    {
+        match self.service {
+            true => service::run(Box::new(serve), self.stop_timeout),
+            false => serve(None),
+        }
+    }
+
+    /// Running as a service is only supported on Windows
+    #[cfg(not(windows))]
+    pub fn run<F>(&self, serve: F) -> AppResult<()>
+    where
+        F: FnOnce(Option<ShutdownTrigger>) -> AppResult<()> + Send + 'static,
+    {
+        if self.service {
+            return Err(AppError::config("--service is only supported on Windows"));
+        }
+        serve(None)
+    }
+}
+
+/// Asks running servers to stop gracefully, from any thread
+///
+/// Firing it before the servers have started stops them as soon as they do.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownTrigger(Arc<Notify>);
+
+impl ShutdownTrigger {
+    /// Creates a trigger that has not fired
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fires the trigger
+    pub fn trigger(&self) {
+        self.0.notify_one();
+    }
+
+    /// Resolves once the trigger has fired
+    pub async fn triggered(&self) {
+        self.0.notified().await;
+    }
+}
+
+/// The Windows service control manager side of `--service`
+#[cfg(windows)]
+mod service {
+    use std::ffi::OsString;
+    use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+    use std::time::Duration;
+
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    use super::{ShutdownTrigger, SERVICE_NAME};
+    use crate::error::{AppError, AppResult};
+
+    type Serve = Box<dyn FnOnce(Option<ShutdownTrigger>) -> AppResult<()> + Send>;
+
+    /// What the dispatcher's service thread runs, and what it returned; the
+    /// service entry point cannot capture anything
+    static SERVICE: Mutex<Option<(Serve, Duration)>> = Mutex::new(None);
+    static OUTCOME: Mutex<Option<AppResult<()>>> = Mutex::new(None);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Blocks in the service control dispatcher until the service stopped
+    pub(super) fn run(serve: Serve, stop_timeout: Duration) -> AppResult<()> {
+        *SERVICE.lock().unwrap_or_else(PoisonError::into_inner) = Some((serve, stop_timeout));
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .map_err(|e| AppError::server(format!("Failed to connect to the service control manager: {}", e)))?;
+        OUTCOME.lock().unwrap_or_else(PoisonError::into_inner).take().unwrap_or(Ok(()))
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let outcome = serve_as_service();
+        if let Err(e) = &outcome {
+            log::error!("Service {} stopped: {}", SERVICE_NAME, e);
+        }
+        *OUTCOME.lock().unwrap_or_else(PoisonError::into_inner) = Some(outcome);
+    }
+
+    fn serve_as_service() -> AppResult<()> {
+        let (serve, stop_timeout) = SERVICE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            .ok_or_else(|| AppError::internal("the service was started twice"))?;
+        let trigger = ShutdownTrigger::new();
+        let stop = trigger.clone();
+        // The handler runs on the dispatcher thread and may see a control before registration returns
+        let registered: Arc<OnceLock<ServiceStatusHandle>> = Arc::new(OnceLock::new());
+        let handle = registered.clone();
+        let status = service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(status) = handle.get() {
+                    let _ = report(status, ServiceState::StopPending, ServiceExitCode::NO_ERROR, stop_timeout);
+                }
+                stop.trigger();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })
+        .map_err(|e| AppError::server(format!("Failed to register the service control handler: {}", e)))?;
+        let _ = registered.set(status);
+
+        report(&status, ServiceState::Running, ServiceExitCode::NO_ERROR, Duration::ZERO)?;
+        let outcome = serve(Some(trigger));
+        let exit_code = match outcome {
+            Ok(()) => ServiceExitCode::NO_ERROR,
+            Err(_) => ServiceExitCode::ServiceSpecific(1),
+        };
+        report(&status, ServiceState::Stopped, exit_code, Duration::ZERO)?;
+        outcome
+    }
+
+    fn report(status: &ServiceStatusHandle, state: ServiceState, exit_code: ServiceExitCode, wait_hint: Duration) -> AppResult<()> {
+        let controls_accepted = match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        status
+            .set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted,
+                exit_code,
+                checkpoint: 0,
+                wait_hint,
+                process_id: None,
+            })
+            .map_err(|e| AppError::server(format!("Failed to report the service {:?}: {}", state, e)))
+    }
+}
+
+/// A pidfile removed again when dropped
+///
+/// Creation refuses to overwrite the pidfile of a process that is still
+/// running; stale files left by a crash are replaced.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the current process id to `path`
+    ///
+    /// # Errors
+    /// Returns a config error when another live process owns the pidfile or
+    /// it cannot be written
+    pub fn create(path: &Path) -> AppResult<Self> {
+        if let Some(pid) = Self::read(path) {
+            if pid != std::process::id() && process_alive(pid) {
+                return Err(AppError::config(format!(
+                    "{} belongs to running process {}",
+                    path.display(),
+                    pid
+                )));
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| AppError::config(format!("Failed to create pidfile {}: {}", path.display(), e)))?;
+        writeln!(file, "{}", std::process::id())
+            .map_err(|e| AppError::config(format!("Failed to write pidfile {}: {}", path.display(), e)))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Returns the process id recorded in `path`, if readable
+    pub fn read(path: &Path) -> Option<u32> {
+        std::fs::read_to_string(path).ok()?.trim().parse().ok()
+    }
+
+    /// Returns the pidfile location
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Only remove the file if it still names this process
+        if Self::read(&self.path) == Some(std::process::id()) {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!("Failed to remove pidfile {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists and may be signalled
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    // Without a portable liveness check, a leftover pidfile is assumed stale
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pidfile_lifecycle() {
+        let path = std::env::temp_dir().join(format!("demo-{}.pid", uuid::Uuid::new_v4()));
+
+        // A stale pidfile is replaced
+        std::fs::write(&path, "4294967295\n").unwrap();
+        let pidfile = PidFile::create(&path).unwrap();
+        assert_eq!(PidFile::read(pidfile.path()), Some(std::process::id()));
+
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pidfile_of_running_process_is_kept() {
+        let path = std::env::temp_dir().join(format!("demo-{}.pid", uuid::Uuid::new_v4()));
+        let mut child = std::process::Command::new("sleep").arg("5").spawn().unwrap();
+        std::fs::write(&path, format!("{}\n", child.id())).unwrap();
+
+        assert!(matches!(PidFile::create(&path), Err(AppError::Config { .. })));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_shutdown_trigger_fired_before_waiting() {
+        let trigger = ShutdownTrigger::new();
+        trigger.clone().trigger();
+        actix_web::rt::time::timeout(std::time::Duration::from_secs(1), trigger.triggered())
+            .await
+            .expect("an earlier trigger was lost");
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_service_needs_windows() {
+        let options = DaemonOptions {
+            service: true,
+            ..DaemonOptions::default()
+        };
+        assert!(matches!(options.run(|_| Ok(())), Err(AppError::Config { .. })));
+    }
+}
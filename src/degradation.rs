@@ -0,0 +1,312 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, AGE, CONTENT_TYPE, WARNING};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::error_circuit::ERROR_CIRCUIT_HEADER;
+use crate::routes::RouteRegistry;
+use crate::simulation::SIMULATED_HEADER;
+
+/// `Warning` header value of responses served stale (RFC 7234)
+pub const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+
+/// Largest response body kept for stale serving
+const MAX_SNAPSHOT_BYTES: u64 = 256 * 1024;
+
+/// Headers identifying the caller, part of the snapshot key so one
+/// caller's data is never served to another
+const CREDENTIAL_HEADERS: &[&str] = &["authorization", "x-api-key", "cookie"];
+
+/// How often each degradable route was served, as reported by `/metrics`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ServeCounts {
+    /// Answered by the handler
+    pub fresh: u64,
+    /// Answered from the last good response while the handler failed
+    pub stale: u64,
+    /// Failed without a usable last good response
+    pub unavailable: u64,
+}
+
+/// Last good JSON response for one route, path, query and caller
+struct Snapshot {
+    body: serde_json::Map<String, Value>,
+    stored_at: Instant,
+}
+
+/// Degradation policy serving the last good response of selected read
+/// routes while their dependencies fail
+///
+/// Every successful JSON object answered on a `STALE_ROUTES` route is kept
+/// in memory, keyed by path, query and the caller's credentials: the state
+/// store is typically what is down, so it cannot hold them. When the same
+/// request later fails with a 5xx, the kept response is served instead, if
+/// younger than `STALE_MAX_AGE_SECS`, with `stale: true`, a `Warning: 110`
+/// header and its `Age`. Shed and simulated failures are passed through.
+pub struct DegradationPolicy {
+    routes: Vec<String>,
+    max_age: Duration,
+    max_entries: usize,
+    snapshots: Mutex<HashMap<String, Snapshot>>,
+    counts: Mutex<BTreeMap<String, ServeCounts>>,
+}
+
+impl DegradationPolicy {
+    /// Creates a policy for the GET routes `routes`
+    pub fn new(routes: Vec<String>, max_age: Duration, max_entries: usize) -> Self {
+        Self {
+            routes,
+            max_age,
+            max_entries,
+            snapshots: Mutex::new(HashMap::new()),
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Builds the policy from the `STALE_*` settings; `None` without
+    /// `STALE_ROUTES`
+    ///
+    /// # Errors
+    /// Returns a config error when `STALE_ROUTES` names a path no GET route
+    /// of `routes` has
+    pub fn from_config(config: &Config, routes: &RouteRegistry) -> AppResult<Option<Self>> {
+        if config.stale_routes.is_empty() {
+            return Ok(None);
+        }
+        for path in &config.stale_routes {
+            if !routes.routes().iter().any(|spec| spec.path == path && spec.method == Method::GET) {
+                return Err(AppError::config(format!("STALE_ROUTES names unknown GET route {}", path)));
+            }
+        }
+        info!(
+            "Serving {} stale for up to {}s while their dependencies fail",
+            config.stale_routes.join(", "),
+            config.stale_max_age_secs
+        );
+        Ok(Some(Self::new(
+            config.stale_routes.clone(),
+            Duration::from_secs(config.stale_max_age_secs),
+            config.stale_max_entries,
+        )))
+    }
+
+    /// Whether responses of the route `path` may be served stale
+    pub fn covers(&self, path: &str) -> bool {
+        self.routes.iter().any(|route| route == path)
+    }
+
+    /// Returns the serve counters of every route that was requested
+    pub fn stats(&self) -> BTreeMap<String, ServeCounts> {
+        self.counts.lock().map(|counts| counts.clone()).unwrap_or_default()
+    }
+
+    fn count(&self, route: &str, update: impl FnOnce(&mut ServeCounts)) {
+        if let Ok(mut counts) = self.counts.lock() {
+            update(counts.entry(route.to_string()).or_default());
+        }
+    }
+
+    fn store(&self, key: String, body: serde_json::Map<String, Value>, now: Instant) {
+        let Ok(mut snapshots) = self.snapshots.lock() else {
+            return;
+        };
+        if snapshots.len() >= self.max_entries && !snapshots.contains_key(&key) {
+            let oldest = snapshots.iter().min_by_key(|(_, snapshot)| snapshot.stored_at).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                snapshots.remove(&oldest);
+            }
+        }
+        if self.max_entries > 0 {
+            snapshots.insert(key, Snapshot { body, stored_at: now });
+        }
+    }
+
+    /// Returns the kept body for `key` and its age, unless it is too old
+    fn stale(&self, key: &str, now: Instant) -> Option<(serde_json::Map<String, Value>, Duration)> {
+        let snapshots = self.snapshots.lock().ok()?;
+        let snapshot = snapshots.get(key)?;
+        let age = now.saturating_duration_since(snapshot.stored_at);
+        (age <= self.max_age).then(|| (snapshot.body.clone(), age))
+    }
+}
+
+/// Snapshot key: path and query, plus a digest of the caller's credentials
+fn snapshot_key(req: &ServiceRequest) -> String {
+    let mut credentials = Sha256::new();
+    for name in CREDENTIAL_HEADERS {
+        for value in req.headers().get_all(*name) {
+            credentials.update(name.as_bytes());
+            credentials.update(b":");
+            credentials.update(value.as_bytes());
+            credentials.update(b"\n");
+        }
+    }
+    format!("{}?{}#{}", req.path(), req.query_string(), hex::encode(credentials.finalize()))
+}
+
+/// Middleware applying the [`DegradationPolicy`], for use with `from_fn`
+///
+/// Runs outside the simulation and IP filter, inside CORS and the error
+/// circuit. Handler failures are replaced, errors raised by the inner
+/// middleware are not. Does nothing without a `web::Data<DegradationPolicy>`.
+pub async fn serve_stale(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let Some(policy) = req.app_data::<web::Data<DegradationPolicy>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let route = match req.match_pattern() {
+        Some(route) if req.method() == Method::GET && policy.covers(&route) => route,
+        _ => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+    let key = snapshot_key(&req);
+
+    let res = next.call(req).await?;
+    if res.headers().contains_key(ERROR_CIRCUIT_HEADER) || res.headers().contains_key(SIMULATED_HEADER) {
+        return Ok(res.map_into_boxed_body());
+    }
+    let status = res.status();
+    if status.is_server_error() {
+        let Some((mut body, age)) = policy.stale(&key, Instant::now()) else {
+            policy.count(&route, |counts| counts.unavailable += 1);
+            return Ok(res.map_into_boxed_body());
+        };
+        warn!("Serving {} stale ({}s old) after a {}", res.request().path(), age.as_secs(), status);
+        policy.count(&route, |counts| counts.stale += 1);
+        body.insert("stale".to_string(), Value::Bool(true));
+        let response = HttpResponse::Ok()
+            .insert_header((WARNING, HeaderValue::from_static(STALE_WARNING)))
+            .insert_header((AGE, age.as_secs()))
+            .json(body);
+        return Ok(res.into_response(response));
+    }
+
+    if status != StatusCode::OK {
+        return Ok(res.map_into_boxed_body());
+    }
+    policy.count(&route, |counts| counts.fresh += 1);
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let fits = matches!(res.response().body().size(), BodySize::Sized(size) if size <= MAX_SNAPSHOT_BYTES);
+    if !is_json || !fits {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let (http_req, response) = res.into_parts();
+    let (response, payload) = response.into_parts();
+    let bytes = body::to_bytes(payload)
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.into()))?;
+    if let Ok(Value::Object(body)) = serde_json::from_slice(&bytes) {
+        policy.store(key, body, Instant::now());
+    }
+    Ok(ServiceResponse::new(http_req, response.set_body(bytes).map_into_boxed_body()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_failures_are_answered_from_the_last_good_response() {
+        let policy = web::Data::new(DegradationPolicy::new(vec!["/items".to_string()], Duration::from_secs(60), 10));
+        let down = Arc::new(AtomicBool::new(false));
+        let handler_down = down.clone();
+        let app = init_service(
+            App::new()
+                .app_data(policy.clone())
+                .wrap(from_fn(serve_stale))
+                .route(
+                    "/items",
+                    web::get().to(move || {
+                        let down = handler_down.load(Ordering::SeqCst);
+                        async move {
+                            if down {
+                                Err(AppError::unavailable("state store read timed out"))
+                            } else {
+                                Ok(HttpResponse::Ok().json(serde_json::json!({ "items": [1, 2] })))
+                            }
+                        }
+                    }),
+                ),
+        )
+        .await;
+        let get = |uri: &str, token: &str| {
+            TestRequest::get().uri(uri).insert_header(("Authorization", format!("Bearer {}", token))).to_request()
+        };
+
+        let res = call_service(&app, get("/items?page=1", "alice")).await;
+        assert!(!res.headers().contains_key(WARNING));
+        assert_eq!(read_body_json::<Value, _>(res).await["items"], serde_json::json!([1, 2]));
+
+        down.store(true, Ordering::SeqCst);
+        let res = call_service(&app, get("/items?page=1", "alice")).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get(WARNING).unwrap(), STALE_WARNING);
+        assert!(res.headers().contains_key(AGE));
+        let body: Value = read_body_json(res).await;
+        assert_eq!((body["items"].clone(), body["stale"].clone()), (serde_json::json!([1, 2]), Value::Bool(true)));
+
+        // Another caller or another query has nothing to fall back on
+        assert_eq!(call_service(&app, get("/items?page=1", "bob")).await.status(), 503);
+        assert_eq!(call_service(&app, get("/items?page=2", "alice")).await.status(), 503);
+
+        assert_eq!(
+            policy.stats()["/items"],
+            ServeCounts {
+                fresh: 1,
+                stale: 1,
+                unavailable: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_snapshots_expire_and_are_bounded() {
+        let policy = DegradationPolicy::new(vec!["/items".to_string()], Duration::from_secs(60), 2);
+        let now = Instant::now();
+        for key in ["a", "b", "c"] {
+            policy.store(key.to_string(), serde_json::Map::new(), now);
+        }
+        assert_eq!(policy.snapshots.lock().unwrap().len(), 2);
+        assert!(policy.stale("c", now + Duration::from_secs(60)).is_some());
+        assert!(policy.stale("c", now + Duration::from_secs(61)).is_none());
+    }
+
+    #[test]
+    fn test_from_config() {
+        let routes = RouteRegistry::app_server();
+        assert!(DegradationPolicy::from_config(&Config::default(), &routes).unwrap().is_none());
+        let config = Config {
+            stale_routes: vec!["/public".to_string()],
+            ..Config::default()
+        };
+        assert!(DegradationPolicy::from_config(&config, &routes).unwrap().unwrap().covers("/public"));
+        let config = Config {
+            stale_routes: vec!["/hooks/{provider}".to_string()],
+            ..Config::default()
+        };
+        assert!(DegradationPolicy::from_config(&config, &routes).is_err());
+    }
+}
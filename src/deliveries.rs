@@ -0,0 +1,265 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::notifications::{DeliveryResponse, Notification};
+use crate::state::KeyValueStore;
+
+/// Scope required to inspect, retry and replay outbound deliveries
+pub const WEBHOOKS_ADMIN_SCOPE: &str = "admin:webhooks";
+
+/// Characters of a response body kept in a delivery attempt
+pub const RESPONSE_SNIPPET_CHARS: usize = 256;
+
+/// What caused a delivery attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryTrigger {
+    /// The notification was routed to the channel
+    Event,
+    /// `POST /webhooks/deliveries/{id}/retry`
+    Retry,
+    /// `POST /webhooks/deliveries/replay`
+    Replay,
+    /// `POST /webhooks/subscriptions/{id}/test`
+    Test,
+}
+
+/// One attempt at delivering a notification, retries of the budget included
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryAttempt {
+    pub at: DateTime<Utc>,
+    pub trigger: DeliveryTrigger,
+    pub ok: bool,
+    /// HTTP status answered by the destination, for HTTP channels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub latency_ms: u64,
+    /// Start of the response body, personal data masked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DeliveryAttempt {
+    /// Describes an attempt that took `latency` and ended with `result`
+    pub fn new(
+        trigger: DeliveryTrigger,
+        result: &AppResult<()>,
+        response: DeliveryResponse,
+        latency: Duration,
+    ) -> Self {
+        Self {
+            at: Utc::now(),
+            trigger,
+            ok: result.is_ok(),
+            status: response.status,
+            latency_ms: latency.as_millis() as u64,
+            response: response
+                .body
+                .map(|body| crate::pii::redact_text(&body.chars().take(RESPONSE_SNIPPET_CHARS).collect::<String>())),
+            error: result.as_ref().err().map(|e| crate::pii::redact_text(&e.to_string())),
+        }
+    }
+}
+
+/// A notification routed to one channel and every attempt at delivering it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryRecord {
+    pub id: String,
+    pub channel: String,
+    pub event_type: String,
+    pub created_at: DateTime<Utc>,
+    /// Whether some attempt succeeded
+    pub delivered: bool,
+    pub attempts: Vec<DeliveryAttempt>,
+    /// What is sent again on retries and replays
+    pub notification: Notification,
+}
+
+/// History of outbound deliveries, kept for `WEBHOOK_DELIVERY_RETENTION_SECS`
+///
+/// Keys: `delivery:{id}` (JSON, expiring after the retention) and
+/// `day:{YYYY-MM-DD}` (JSON array of the ids created that UTC day, expiring
+/// a day after its last delivery would), which answers time range queries
+/// without scanning the store.
+pub struct DeliveryLog {
+    store: Arc<dyn KeyValueStore>,
+    retention: Duration,
+}
+
+impl DeliveryLog {
+    /// Creates a log keeping deliveries for `retention`
+    pub fn new(store: Arc<dyn KeyValueStore>, retention: Duration) -> Self {
+        Self { store, retention }
+    }
+
+    /// Builds the log from `WEBHOOK_DELIVERY_RETENTION_SECS`; `None` when it is 0
+    pub fn from_config(config: &Config, store: Arc<dyn KeyValueStore>) -> Option<Self> {
+        (config.webhook_delivery_retention_secs > 0)
+            .then(|| Self::new(store, Duration::from_secs(config.webhook_delivery_retention_secs)))
+    }
+
+    /// Records the first attempt at delivering `notification` to `channel`
+    pub fn record(&self, channel: &str, notification: &Notification, attempt: DeliveryAttempt) -> AppResult<DeliveryRecord> {
+        let record = DeliveryRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            channel: channel.to_string(),
+            event_type: notification.event_type.clone(),
+            created_at: attempt.at,
+            delivered: attempt.ok,
+            attempts: vec![attempt],
+            notification: notification.clone(),
+        };
+        self.save(&record)?;
+
+        let day_key = format!("day:{}", record.created_at.date_naive());
+        let mut ids = self.day_ids(&day_key)?;
+        ids.push(record.id.clone());
+        let ids = serde_json::to_string(&ids).map_err(|e| AppError::internal(e.to_string()))?;
+        self.store
+            .set(&day_key, &ids, Some(self.retention + Duration::from_secs(24 * 3600)))?;
+        Ok(record)
+    }
+
+    /// Appends an attempt to the delivery `id`
+    ///
+    /// # Errors
+    /// Not found when the delivery expired or never existed
+    pub fn append(&self, id: &str, attempt: DeliveryAttempt) -> AppResult<DeliveryRecord> {
+        let mut record = self.get(id)?.ok_or_else(|| AppError::not_found("delivery not found"))?;
+        record.delivered |= attempt.ok;
+        record.attempts.push(attempt);
+        self.save(&record)?;
+        Ok(record)
+    }
+
+    /// Loads a delivery that has not expired
+    pub fn get(&self, id: &str) -> AppResult<Option<DeliveryRecord>> {
+        self.store
+            .get(&format!("delivery:{}", id))?
+            .map(|raw| {
+                serde_json::from_str(&raw).map_err(|e| AppError::internal(format!("Corrupt delivery {}: {}", id, e)))
+            })
+            .transpose()
+    }
+
+    /// Lists the deliveries created within `[from, to]`, oldest first
+    ///
+    /// # Errors
+    /// Validation error when `from` is after `to`
+    pub fn list(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> AppResult<Vec<DeliveryRecord>> {
+        if from > to {
+            return Err(AppError::validation("'from' must not be after 'to'"));
+        }
+        // Nothing older than the retention is left to find
+        let oldest = Utc::now() - chrono::Duration::seconds(self.retention.as_secs() as i64);
+        let mut day = from.max(oldest).date_naive();
+        let mut records = Vec::new();
+        while day <= to.date_naive() {
+            for id in self.day_ids(&format!("day:{}", day))? {
+                if let Some(record) = self.get(&id)? {
+                    if record.created_at >= from && record.created_at <= to {
+                        records.push(record);
+                    }
+                }
+            }
+            day = match day.succ_opt() {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        records.sort_by_key(|record| record.created_at);
+        Ok(records)
+    }
+
+    fn save(&self, record: &DeliveryRecord) -> AppResult<()> {
+        let json = serde_json::to_string(record).map_err(|e| AppError::internal(e.to_string()))?;
+        // Appended attempts do not extend the delivery's life
+        let age = (Utc::now() - record.created_at).to_std().unwrap_or_default();
+        let ttl = self.retention.saturating_sub(age).max(Duration::from_secs(1));
+        self.store.set(&format!("delivery:{}", record.id), &json, Some(ttl))
+    }
+
+    fn day_ids(&self, day_key: &str) -> AppResult<Vec<String>> {
+        match self.store.get(day_key)? {
+            Some(raw) => serde_json::from_str(&raw).map_err(|e| AppError::internal(e.to_string())),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Parses a `YYYY-MM-DD` day or an RFC 3339 timestamp; days start at midnight UTC
+pub fn parse_time(value: &str) -> AppResult<DateTime<Utc>> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Ok(at.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|at| at.and_utc())
+        .ok_or_else(|| AppError::validation(format!("'{}' is neither a date nor an RFC 3339 timestamp", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    fn attempt(ok: bool) -> DeliveryAttempt {
+        let result = if ok { Ok(()) } else { Err(AppError::internal("returned 502")) };
+        let response = DeliveryResponse {
+            status: Some(if ok { 200 } else { 502 }),
+            body: Some(format!("{} for ann@example.com", "x".repeat(300))),
+        };
+        DeliveryAttempt::new(DeliveryTrigger::Event, &result, response, Duration::from_millis(12))
+    }
+
+    #[test]
+    fn test_record_append_and_list() {
+        let log = DeliveryLog::new(Arc::new(InMemoryStore::new()), Duration::from_secs(3600));
+        let notification = Notification::new("webhook.github", "Push", "main updated");
+        let failed = log.record("webhook", &notification, attempt(false)).unwrap();
+        assert!(!failed.delivered);
+        assert_eq!(failed.attempts[0].status, Some(502));
+        assert_eq!(failed.attempts[0].latency_ms, 12);
+        assert_eq!(failed.attempts[0].response.as_ref().unwrap().chars().count(), RESPONSE_SNIPPET_CHARS);
+        log.record("slack", &notification, attempt(true)).unwrap();
+
+        let retried = log.append(&failed.id, attempt(true)).unwrap();
+        assert!(retried.delivered);
+        assert_eq!(retried.attempts.len(), 2);
+        assert_eq!(log.get(&failed.id).unwrap(), Some(retried));
+        assert!(matches!(log.append("nope", attempt(true)), Err(AppError::NotFound { .. })));
+
+        let now = Utc::now();
+        let all = log.list(now - chrono::Duration::days(3), now).unwrap();
+        assert_eq!(all.iter().map(|record| record.channel.as_str()).collect::<Vec<_>>(), vec!["webhook", "slack"]);
+        assert!(log.list(now - chrono::Duration::days(3), now - chrono::Duration::days(2)).unwrap().is_empty());
+        assert!(log.list(now, now - chrono::Duration::days(1)).is_err());
+    }
+
+    #[test]
+    fn test_snippets_are_masked() {
+        let response = DeliveryResponse {
+            status: Some(422),
+            body: Some("unknown recipient ann@example.com".to_string()),
+        };
+        let result = Err(AppError::internal("returned 422"));
+        let attempt = DeliveryAttempt::new(DeliveryTrigger::Replay, &result, response, Duration::ZERO);
+        assert!(!attempt.response.unwrap().contains("ann@example.com"));
+        assert!(attempt.error.unwrap().contains("422"));
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("2026-03-01").unwrap().to_rfc3339(), "2026-03-01T00:00:00+00:00");
+        assert_eq!(parse_time("2026-03-01T10:00:00+02:00").unwrap().to_rfc3339(), "2026-03-01T08:00:00+00:00");
+        assert!(parse_time("yesterday").is_err());
+    }
+}
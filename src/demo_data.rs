@@ -0,0 +1,505 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc, Weekday};
+use log::info;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::analytics::{AnalyticsSink, UsageAggregator, UsageEvent};
+use crate::config::{AppEnv, Config};
+use crate::error::{AppError, AppResult};
+use crate::state::{StateManager, StateMode};
+use crate::users::{hash_password, User, UserEvent, UserRepository};
+
+/// Password of every generated account
+pub const DEMO_PASSWORD: &str = "demo-password";
+
+/// Longest history a plan may span, matching the usage aggregates' retention
+pub const MAX_DAYS: u32 = 90;
+
+/// Most accounts one run may create
+pub const MAX_USERS: usize = 10_000;
+
+const FIRST_NAMES: &[&str] = &[
+    "Amelia", "Arjun", "Beatriz", "Chen", "Chloé", "Dmitri", "Elena", "Farah", "Gabriel", "Hana", "Ibrahim",
+    "Ingrid", "Jamal", "Julia", "Kenji", "Lucía", "Malik", "Marta", "Nadia", "Noah", "Olga", "Omar", "Priya",
+    "Quentin", "Rosa", "Sami", "Sofia", "Tariq", "Yuki", "Zoé",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Adeyemi", "Bauer", "Costa", "Dubois", "Eriksen", "Fernández", "García", "Haddad", "Ivanova", "Jensen",
+    "Kowalski", "Lefebvre", "Moreau", "Nakamura", "Novak", "Okafor", "Petrov", "Rossi", "Santos", "Schmidt",
+    "Silva", "Tanaka", "Van Dijk", "Wang", "Yilmaz",
+];
+
+const DOMAINS: &[&str] = &["example.com", "example.org", "example.net"];
+
+const DEVICES: &[&str] = &["Firefox on Linux", "Safari on iPhone", "Chrome on Windows", "curl/8.5.0", "Android app"];
+
+const WORDS: &[&str] = &[
+    "api", "billing", "cache", "dashboard", "deploy", "export", "latency", "metrics", "notes", "project",
+    "release", "report", "search", "team", "webhook",
+];
+
+/// Routes of the generated requests: route, relative weight, whether an
+/// authenticated user makes it
+const ROUTES: &[(&str, u32, bool)] = &[
+    ("GET /", 18, false),
+    ("GET /public", 24, false),
+    ("GET /private", 16, true),
+    ("GET /docs", 8, false),
+    ("GET /openapi.json", 5, false),
+    ("POST /auth/login", 9, false),
+    ("GET /me/sessions", 5, true),
+    ("GET /me/export", 2, true),
+    ("POST /me/api-keys", 2, true),
+    ("POST /hooks/{provider}", 6, false),
+    ("GET <unmatched>", 5, false),
+];
+
+/// Preset sizes of the generated data set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scenario {
+    /// A couple of pages of everything, generated in a blink
+    #[default]
+    Small,
+    /// Enough to page, search and export meaningfully
+    Medium,
+    /// Load-test sized
+    Large,
+}
+
+impl FromStr for Scenario {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "small" => Ok(Scenario::Small),
+            "medium" => Ok(Scenario::Medium),
+            "large" => Ok(Scenario::Large),
+            other => Err(AppError::validation(format!(
+                "scenario must be small, medium or large, got: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Options of one generation run, from the `demo-data` flags or the endpoint's query
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, clap::Parser)]
+pub struct DemoOptions {
+    /// Preset size: small, medium or large
+    #[serde(default)]
+    #[arg(long, value_name = "NAME", default_value = "small")]
+    pub scenario: Scenario,
+    /// Accounts to create instead of the scenario's
+    #[arg(long)]
+    pub users: Option<usize>,
+    /// Days of history instead of the scenario's
+    #[arg(long)]
+    pub days: Option<u32>,
+    /// Seed making the run reproducible; random when absent
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+impl DemoOptions {
+    /// Resolves the scenario and overrides into a plan
+    ///
+    /// # Errors
+    /// Returns a validation error for sizes outside `1..=MAX_USERS` users or
+    /// `1..=MAX_DAYS` days
+    pub fn plan(&self) -> AppResult<DemoPlan> {
+        let mut plan = DemoPlan::for_scenario(self.scenario);
+        plan.users = self.users.unwrap_or(plan.users);
+        plan.days = self.days.unwrap_or(plan.days);
+        plan.seed = self.seed.unwrap_or_else(rand::random);
+        if !(1..=MAX_USERS).contains(&plan.users) {
+            return Err(AppError::validation(format!("users must be between 1 and {}", MAX_USERS)));
+        }
+        if !(1..=MAX_DAYS).contains(&plan.days) {
+            return Err(AppError::validation(format!("days must be between 1 and {}", MAX_DAYS)));
+        }
+        Ok(plan)
+    }
+}
+
+/// What a run generates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DemoPlan {
+    pub users: usize,
+    /// Days of history the sign-ups, audit events and requests spread over
+    pub days: u32,
+    /// Average requests per weekday, fewer on weekends and early on
+    pub requests_per_day: u32,
+    /// Longest audit trail of one account
+    pub max_events_per_user: usize,
+    pub seed: u64,
+}
+
+impl DemoPlan {
+    /// Plan of a scenario, with seed 0
+    pub fn for_scenario(scenario: Scenario) -> Self {
+        let (users, days, requests_per_day, max_events_per_user) = match scenario {
+            Scenario::Small => (25, 14, 150, 8),
+            Scenario::Medium => (250, 60, 1_000, 20),
+            Scenario::Large => (2_500, 90, 4_000, 40),
+        };
+        Self {
+            users,
+            days,
+            requests_per_day,
+            max_events_per_user,
+            seed: 0,
+        }
+    }
+}
+
+/// Outcome of a run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DemoReport {
+    /// Seed reproducing the run
+    pub seed: u64,
+    pub users: usize,
+    pub events: usize,
+    pub requests: u64,
+    pub first_day: NaiveDate,
+    pub last_day: NaiveDate,
+    /// Password of every generated account
+    pub password: &'static str,
+}
+
+/// Fills the user repository and the usage aggregates with realistic fake data
+///
+/// Accounts get names from several locales, `example.*` addresses, sign-up
+/// dates skewed towards recent days and audit trails of very different
+/// lengths and payload sizes; daily request counts follow a growth trend
+/// with weekend dips. Everything is derived from the plan's seed.
+pub struct DemoDataGenerator {
+    users: UserRepository,
+    usage: Arc<dyn AnalyticsSink>,
+}
+
+impl DemoDataGenerator {
+    /// Creates a generator writing to `users` and `usage`
+    pub fn new(users: UserRepository, usage: Arc<dyn AnalyticsSink>) -> Self {
+        Self { users, usage }
+    }
+
+    /// Creates a generator on the stores the server's components use
+    pub fn from_state(state: &StateManager) -> Self {
+        Self::new(
+            UserRepository::new(state.store("users")),
+            Arc::new(UsageAggregator::new(state.store("analytics"))),
+        )
+    }
+
+    /// Generates the data of `plan`, with history ending at `now`
+    ///
+    /// # Errors
+    /// Returns the first storage error
+    pub fn generate(&self, plan: &DemoPlan, now: DateTime<Utc>) -> AppResult<DemoReport> {
+        let mut rng = StdRng::seed_from_u64(plan.seed);
+        let start = now - Duration::days(i64::from(plan.days));
+        let password_hash = hash_password(DEMO_PASSWORD)?;
+
+        let mut accounts = Vec::with_capacity(plan.users);
+        let mut events = 0;
+        for _ in 0..plan.users {
+            let Some(mut user) = self.create_user(&mut rng, &password_hash)? else {
+                continue;
+            };
+            // Sign-ups accelerate: the square root skews them towards recent days
+            let age = (now - start).num_seconds() as f64 * (1.0 - rng.gen::<f64>().sqrt());
+            user.created_at = now - Duration::seconds(age as i64);
+            user.email_verified = rng.gen_bool(0.8);
+            user.analytics_opt_out = rng.gen_bool(0.1);
+            user.roles = match rng.gen_range(0..100) {
+                0..=2 => vec!["admin".to_string()],
+                3..=12 => vec!["editor".to_string()],
+                _ => Vec::new(),
+            };
+            let trail = Self::audit_trail(&mut rng, &user, plan.max_events_per_user, now);
+            user.updated_at = trail.last().map_or(user.created_at, |event| event.at);
+            self.users.save(&user)?;
+            self.users.save_events(&user.id, &trail)?;
+            events += trail.len();
+            accounts.push(user);
+        }
+
+        let mut requests = 0;
+        for day in 0..plan.days {
+            let date = (start + Duration::days(i64::from(day) + 1)).date_naive();
+            requests += self.daily_usage(&mut rng, plan, day, date, &accounts)?;
+        }
+
+        let report = DemoReport {
+            seed: plan.seed,
+            users: accounts.len(),
+            events,
+            requests,
+            first_day: (start + Duration::days(1)).date_naive(),
+            last_day: now.date_naive(),
+            password: DEMO_PASSWORD,
+        };
+        info!(
+            "Generated demo data (seed {}): {} users, {} audit events, {} requests over {} days",
+            report.seed, report.users, report.events, report.requests, plan.days
+        );
+        Ok(report)
+    }
+
+    /// Creates an account with a fresh address, retrying on collisions
+    /// with earlier runs; `None` when every attempt collided
+    fn create_user(&self, rng: &mut StdRng, password_hash: &str) -> AppResult<Option<User>> {
+        let first = pick(rng, FIRST_NAMES);
+        let last = pick(rng, LAST_NAMES);
+        for _ in 0..5 {
+            let email = format!(
+                "{}.{}{}@{}",
+                ascii_slug(first),
+                ascii_slug(last),
+                rng.gen_range(1..10_000),
+                pick(rng, DOMAINS)
+            );
+            match self.users.create(&email, password_hash.to_string()) {
+                Ok(user) => return Ok(Some(user)),
+                Err(AppError::Validation { .. }) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Audit trail between the account's creation and `now`, oldest first
+    ///
+    /// Most accounts have a short trail and a few a long one; profile
+    /// updates carry notes from a few bytes to a couple of kilobytes.
+    fn audit_trail(rng: &mut StdRng, user: &User, max_events: usize, now: DateTime<Utc>) -> Vec<UserEvent> {
+        let mut trail = vec![event(rng, user, "user.registered", user.created_at, json!({}))];
+        if user.email_verified {
+            let at = user.created_at + Duration::minutes(rng.gen_range(1..180));
+            trail.push(event(rng, user, "user.email_verified", at.min(now), json!({})));
+        }
+
+        let extra = (rng.gen::<f64>().powi(3) * max_events.saturating_sub(trail.len()) as f64) as usize;
+        let lifetime = (now - user.created_at).num_seconds().max(1);
+        let mut others: Vec<UserEvent> = (0..extra)
+            .map(|_| {
+                let at = user.created_at + Duration::seconds(rng.gen_range(0..lifetime));
+                let (action, data) = match rng.gen_range(0..10) {
+                    0..=5 => ("user.login", json!({ "device": pick(rng, DEVICES) })),
+                    6..=8 => {
+                        let length = rng.gen_range(0..=400);
+                        let notes = (0..length).map(|_| pick(rng, WORDS)).collect::<Vec<_>>().join(" ");
+                        ("user.profile_updated", json!({ "notes": notes }))
+                    }
+                    _ => ("user.api_key_created", json!({ "name": format!("{} key", pick(rng, WORDS)) })),
+                };
+                event(rng, user, action, at, data)
+            })
+            .collect();
+        others.sort_by_key(|event| event.at);
+        trail.extend(others);
+        trail
+    }
+
+    /// Records the requests of one day; returns how many
+    fn daily_usage(
+        &self,
+        rng: &mut StdRng,
+        plan: &DemoPlan,
+        day: u32,
+        date: NaiveDate,
+        accounts: &[User],
+    ) -> AppResult<u64> {
+        let trend = 0.4 + 0.6 * f64::from(day + 1) / f64::from(plan.days);
+        let weekend = matches!(date.weekday(), Weekday::Sat | Weekday::Sun);
+        let factor = trend * if weekend { 0.45 } else { 1.0 } * rng.gen_range(0.8..1.2);
+        let count = (f64::from(plan.requests_per_day) * factor).round() as u64;
+
+        let active: Vec<&User> = accounts
+            .iter()
+            .filter(|user| user.created_at.date_naive() <= date && !user.analytics_opt_out)
+            .collect();
+        let total_weight: u32 = ROUTES.iter().map(|(_, weight, _)| weight).sum();
+        for _ in 0..count {
+            let mut roll = rng.gen_range(0..total_weight);
+            let &(route, _, authenticated) = ROUTES
+                .iter()
+                .find(|(_, weight, _)| {
+                    let hit = roll < *weight;
+                    roll = roll.saturating_sub(*weight);
+                    hit
+                })
+                .unwrap_or(&ROUTES[0]);
+            let status = match (route, rng.gen_range(0..100)) {
+                ("GET <unmatched>", _) => 404,
+                (_, 0) => 500,
+                (_, 1..=2) => 429,
+                (_, 3..=7) if authenticated => 401,
+                _ => 200,
+            };
+            let user_id = match active.choose(rng) {
+                Some(user) if authenticated && status != 401 => Some(user.id.clone()),
+                _ => None,
+            };
+            self.usage.record(&UsageEvent {
+                day: date,
+                route: route.to_string(),
+                status,
+                user_id,
+            })?;
+        }
+        Ok(count)
+    }
+}
+
+/// Generates demo data into the configured state backend, for the `demo-data` subcommand
+///
+/// # Errors
+/// Returns a config error in production and with process-local state, which
+/// would vanish with the command; the generator's errors otherwise
+pub fn run(config: &Config, options: &DemoOptions) -> AppResult<DemoReport> {
+    if config.app_env == AppEnv::Production {
+        return Err(AppError::config("refusing to generate demo data with APP_ENV=production"));
+    }
+    let state = StateManager::from_config(config)?;
+    if state.mode() == StateMode::Local || config.stub_dependencies {
+        return Err(AppError::config(
+            "demo-data needs a shared state store (STATE_MODE=distributed); \
+             with in-process state use POST /admin/demo-data on the running server",
+        ));
+    }
+    DemoDataGenerator::from_state(&state).generate(&options.plan()?, Utc::now())
+}
+
+fn pick<'a>(rng: &mut StdRng, values: &[&'a str]) -> &'a str {
+    values.choose(rng).copied().unwrap_or_default()
+}
+
+/// Lowercase ASCII form of a name for use in an email address
+fn ascii_slug(name: &str) -> String {
+    name.chars()
+        .filter_map(|c| match c {
+            'á' | 'à' => Some('a'),
+            'é' | 'è' => Some('e'),
+            'í' => Some('i'),
+            'ó' => Some('o'),
+            'ú' => Some('u'),
+            c if c.is_ascii_alphanumeric() => Some(c.to_ascii_lowercase()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Audit event with an id drawn from the seeded generator, so runs are reproducible
+fn event(rng: &mut StdRng, user: &User, action: &str, at: DateTime<Utc>, data: serde_json::Value) -> UserEvent {
+    UserEvent {
+        id: uuid::Uuid::from_u128(rng.gen()).to_string(),
+        user_id: user.id.clone(),
+        action: action.to_string(),
+        at,
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+    use crate::users::verify_password;
+    use chrono::TimeZone;
+
+    fn generator() -> (DemoDataGenerator, UserRepository, Arc<UsageAggregator>) {
+        let users = UserRepository::new(Arc::new(InMemoryStore::new()));
+        let usage = Arc::new(UsageAggregator::new(Arc::new(InMemoryStore::new())));
+        (DemoDataGenerator::new(users.clone(), usage.clone()), users, usage)
+    }
+
+    fn parse(args: &[&str]) -> Result<DemoOptions, clap::Error> {
+        use clap::Parser;
+        DemoOptions::try_parse_from(std::iter::once("demo-data").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_options() {
+        let options = parse(&["--scenario", "medium", "--users", "40", "--seed", "7"]).unwrap();
+        let plan = options.plan().unwrap();
+        assert_eq!((plan.users, plan.days, plan.seed), (40, 60, 7));
+        assert_eq!(plan.requests_per_day, DemoPlan::for_scenario(Scenario::Medium).requests_per_day);
+
+        for invalid in [&["--scenario", "huge"][..], &["--users"], &["--days", "-1"], &["--verbose", "1"]] {
+            assert!(parse(invalid).is_err(), "{:?}", invalid);
+        }
+        assert_eq!(parse(&[]).unwrap(), DemoOptions::default());
+        for (users, days) in [(Some(0), None), (None, Some(0)), (None, Some(MAX_DAYS + 1))] {
+            let options = DemoOptions { users, days, ..DemoOptions::default() };
+            assert!(matches!(options.plan(), Err(AppError::Validation { .. })));
+        }
+    }
+
+    #[test]
+    fn test_generated_data_is_realistic() {
+        let (generator, users, usage) = generator();
+        let now = Utc.with_ymd_and_hms(2024, 6, 30, 12, 0, 0).unwrap();
+        let plan = DemoPlan {
+            seed: 42,
+            ..DemoPlan::for_scenario(Scenario::Small)
+        };
+        let report = generator.generate(&plan, now).unwrap();
+        assert_eq!(report.users, 25);
+        assert_eq!(users.ids().unwrap().len(), 25);
+        assert_eq!((report.first_day, report.last_day), (NaiveDate::from_ymd_opt(2024, 6, 17).unwrap(), now.date_naive()));
+
+        let accounts: Vec<User> = users.ids().unwrap().iter().map(|id| users.get(id).unwrap().unwrap()).collect();
+        assert!(accounts.iter().all(|user| user.created_at >= now - Duration::days(14) && user.created_at <= now));
+        assert!(accounts.iter().all(|user| user.email.ends_with(".com") || user.email.ends_with(".org") || user.email.ends_with(".net")));
+        assert!(verify_password(DEMO_PASSWORD, &accounts[0].password_hash));
+        let trails: Vec<usize> = accounts.iter().map(|user| users.events(&user.id).unwrap().len()).collect();
+        assert_eq!(trails.iter().sum::<usize>(), report.events);
+        assert!(trails.iter().min() < trails.iter().max(), "{:?}", trails);
+
+        // Weekdays late in the period are busier than the first weekend day
+        let busy = usage.export(NaiveDate::from_ymd_opt(2024, 6, 28).unwrap()).unwrap();
+        let quiet = usage.export(NaiveDate::from_ymd_opt(2024, 6, 22).unwrap()).unwrap();
+        let total = |export: &crate::analytics::UsageExport| export.routes.iter().map(|r| r.requests).sum::<u64>();
+        assert!(total(&busy) > total(&quiet), "{} <= {}", total(&busy), total(&quiet));
+        assert!(busy.active_users > 0);
+    }
+
+    #[test]
+    fn test_same_seed_same_data() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 30, 12, 0, 0).unwrap();
+        let plan = DemoPlan {
+            users: 5,
+            seed: 9,
+            ..DemoPlan::for_scenario(Scenario::Small)
+        };
+        let emails = || {
+            let (generator, users, _) = generator();
+            let report = generator.generate(&plan, now).unwrap();
+            let mut emails: Vec<String> =
+                users.ids().unwrap().iter().map(|id| users.get(id).unwrap().unwrap().email).collect();
+            emails.sort();
+            (emails, report.events, report.requests)
+        };
+        assert_eq!(emails(), emails());
+    }
+
+    #[test]
+    fn test_cli_refuses_process_local_state() {
+        let err = run(&Config::default(), &DemoOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("STATE_MODE=distributed"), "{}", err);
+        let production = Config {
+            app_env: AppEnv::Production,
+            ..Config::default()
+        };
+        assert!(run(&production, &DemoOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,549 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderMap, CONTENT_TYPE};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::context::RequestContext;
+use crate::error::{AppError, AppResult};
+use crate::error_circuit::ERROR_CIRCUIT_HEADER;
+use crate::pii::{redact_json, redact_text, tagged_fields, REDACTED};
+use crate::simulation::SIMULATED_HEADER;
+
+/// Scope required to list and read error dumps
+pub const DUMPS_SCOPE: &str = "admin:dumps";
+
+/// Words marking a header, query parameter or JSON field as secret
+const SECRET_WORDS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "password",
+    "secret",
+    "token",
+    "otp",
+    "code",
+    "key",
+    "signature",
+    "credential",
+    "challenge",
+];
+
+/// Sanitized copy of a request answered with a 5xx, for reproducing the bug
+///
+/// Credentials (authorization and cookie headers, tokens, passwords, keys,
+/// signatures) are replaced by `[redacted]` wherever they appear, and
+/// personal data is masked like in logs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestDump {
+    pub id: String,
+    pub captured_at: DateTime<Utc>,
+    pub request_id: String,
+    pub trace_id: String,
+    pub method: String,
+    /// Path and sanitized query string
+    pub path: String,
+    /// Route pattern, `None` for unknown paths
+    pub route: Option<String>,
+    pub status: u16,
+    /// Error raised by a middleware, when the response was not produced by a handler
+    pub error: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    /// The part of the body the handler read, up to the size limit
+    pub body: String,
+    /// Body bytes the handler read
+    pub body_bytes: usize,
+    pub body_truncated: bool,
+    /// Phases recorded through [`ServerTiming`](crate::server_timing::ServerTiming), in milliseconds
+    pub timing: Vec<DumpPhase>,
+    pub total_ms: f64,
+}
+
+/// Duration of one phase of a dumped request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DumpPhase {
+    pub name: String,
+    pub ms: f64,
+}
+
+/// Entry of the dump listing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DumpSummary {
+    pub id: String,
+    pub captured_at: DateTime<Utc>,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+}
+
+impl From<&RequestDump> for DumpSummary {
+    fn from(dump: &RequestDump) -> Self {
+        Self {
+            id: dump.id.clone(),
+            captured_at: dump.captured_at,
+            request_id: dump.request_id.clone(),
+            method: dump.method.clone(),
+            path: dump.path.clone(),
+            status: dump.status,
+        }
+    }
+}
+
+/// Bounded on-disk spool of [`RequestDump`]s, one JSON file each
+///
+/// Dump ids start with their capture time, so file names sort
+/// chronologically; once more than `max_files` dumps are stored the oldest
+/// are deleted.
+#[derive(Debug, Clone)]
+pub struct DumpSpool {
+    dir: PathBuf,
+    max_files: usize,
+    max_body_bytes: usize,
+    sample_rate: f64,
+}
+
+impl DumpSpool {
+    /// Opens the spool in `dir`, creating it (mode 0700) if needed
+    ///
+    /// # Errors
+    /// Returns a config error when the directory cannot be created
+    pub fn open(dir: impl Into<PathBuf>, max_files: usize, max_body_bytes: usize, sample_rate: f64) -> AppResult<Self> {
+        let dir = dir.into();
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(&dir)
+            .map_err(|e| AppError::config(format!("Failed to create ERROR_DUMP_DIR {}: {}", dir.display(), e)))?;
+        Ok(Self {
+            dir,
+            max_files,
+            max_body_bytes,
+            sample_rate,
+        })
+    }
+
+    /// Opens the spool of `ERROR_DUMP_DIR`; `None` when dumps are off
+    ///
+    /// # Errors
+    /// Returns a config error when the directory cannot be created
+    pub fn from_config(config: &Config) -> AppResult<Option<Self>> {
+        config
+            .error_dump_dir
+            .as_deref()
+            .map(|dir| {
+                Self::open(
+                    dir,
+                    config.error_dump_max_files,
+                    config.error_dump_max_body_bytes,
+                    config.error_dump_sample_rate,
+                )
+            })
+            .transpose()
+    }
+
+    /// Body bytes kept per dump
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    /// Draws whether the next failed request is dumped
+    pub fn sample(&self) -> bool {
+        self.sample_rate >= 1.0 || rand::random::<f64>() < self.sample_rate
+    }
+
+    /// Writes `dump` and deletes the oldest dumps beyond the limit
+    ///
+    /// # Errors
+    /// Internal error when the spool cannot be written
+    pub fn store(&self, dump: &RequestDump) -> AppResult<()> {
+        let json = serde_json::to_vec_pretty(dump).map_err(|e| AppError::internal(e.to_string()))?;
+        let path = self.path(&dump.id)?;
+        // Written aside and renamed, so readers never see a partial dump
+        let partial = path.with_extension("json.tmp");
+        fs::write(&partial, json)
+            .and_then(|_| fs::rename(&partial, &path))
+            .map_err(|e| AppError::internal(format!("failed to write error dump: {}", e)))?;
+
+        let ids = self.ids()?;
+        for id in ids.iter().take(ids.len().saturating_sub(self.max_files)) {
+            if let Err(e) = fs::remove_file(self.path(id)?) {
+                warn!("Failed to delete old error dump {}: {}", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the stored dumps, newest first
+    ///
+    /// # Errors
+    /// Internal error when the spool cannot be read
+    pub fn list(&self) -> AppResult<Vec<DumpSummary>> {
+        let mut dumps = Vec::new();
+        for id in self.ids()?.iter().rev() {
+            // A dump pruned since the directory was read is simply skipped
+            if let Some(dump) = self.get(id)? {
+                dumps.push(DumpSummary::from(&dump));
+            }
+        }
+        Ok(dumps)
+    }
+
+    /// Reads the dump `id`
+    ///
+    /// # Errors
+    /// Validation error for malformed ids, internal error for unreadable dumps
+    pub fn get(&self, id: &str) -> AppResult<Option<RequestDump>> {
+        match fs::read(self.path(id)?) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| AppError::internal(format!("corrupt error dump {}: {}", id, e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(AppError::internal(format!("failed to read error dump {}: {}", id, e))),
+        }
+    }
+
+    /// Ids of the stored dumps, oldest first
+    fn ids(&self) -> AppResult<Vec<String>> {
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|e| AppError::internal(format!("failed to read ERROR_DUMP_DIR: {}", e)))?;
+        let mut ids: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()?.strip_suffix(".json").map(str::to_string))
+            .filter(|id| valid_id(id))
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn path(&self, id: &str) -> AppResult<PathBuf> {
+        if !valid_id(id) {
+            return Err(AppError::validation("invalid dump id"));
+        }
+        Ok(self.dir.join(format!("{}.json", id)))
+    }
+}
+
+/// Ids are generated by [`new_id`]; anything else could escape the spool
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// Capture time, then a random suffix: `20240501T101500123456Z-1a2b3c4d`
+fn new_id(captured_at: DateTime<Utc>) -> String {
+    let suffix = uuid::Uuid::new_v4().simple().to_string();
+    format!("{}-{}", captured_at.format("%Y%m%dT%H%M%S%6fZ"), &suffix[..8])
+}
+
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SECRET_WORDS.iter().any(|word| name.contains(word))
+}
+
+/// Headers with secret values redacted and personal data masked
+fn sanitize_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut sanitized = BTreeMap::new();
+    for (name, value) in headers {
+        let value = if is_secret(name.as_str()) {
+            REDACTED.to_string()
+        } else {
+            redact_text(&String::from_utf8_lossy(value.as_bytes()))
+        };
+        sanitized
+            .entry(name.as_str().to_string())
+            .and_modify(|existing: &mut String| {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+    sanitized
+}
+
+/// `a=1&token=xyz` with the values of secret parameters redacted
+fn sanitize_query(query: &str) -> String {
+    let pairs: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if is_secret(name) => format!("{}={}", name, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect();
+    redact_text(&pairs.join("&"))
+}
+
+/// Redacts the values of secret fields anywhere in `value`
+fn redact_secrets(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object.iter_mut() {
+                if is_secret(key) {
+                    *child = Value::String(REDACTED.to_string());
+                } else {
+                    redact_secrets(child);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+/// Readable, sanitized form of the captured body
+fn sanitize_body(body: &[u8], content_type: Option<&str>, truncated: bool) -> String {
+    let content_type = content_type.unwrap_or_default();
+    if body.is_empty() {
+        return String::new();
+    }
+    if let Ok(mut value) = serde_json::from_slice::<Value>(body) {
+        redact_secrets(&mut value);
+        redact_json(&mut value, &tagged_fields());
+        return value.to_string();
+    }
+    if content_type.contains("json") {
+        // Cut off JSON can not be sanitized field by field
+        return format!("<{} bytes of {} JSON omitted>", body.len(), if truncated { "truncated" } else { "invalid" });
+    }
+    match std::str::from_utf8(body) {
+        Ok(text) if content_type.starts_with("application/x-www-form-urlencoded") => sanitize_query(text),
+        Ok(text) => redact_text(text),
+        Err(_) => format!("<{} bytes of binary data>", body.len()),
+    }
+}
+
+/// Body bytes seen while the handler reads the payload
+#[derive(Default)]
+struct CapturedBody {
+    bytes: Vec<u8>,
+    total: usize,
+}
+
+/// Middleware spooling a [`RequestDump`] of sampled requests answered with
+/// a 5xx, for use with `from_fn`
+///
+/// The payload is passed through untouched while its first bytes are
+/// copied, so only the part of the body the handler actually read is
+/// dumped. Runs inside `attach_context` for the request id and the timing
+/// phases. A failing spool is logged, never surfaced to the client. Does
+/// nothing without a `web::Data<DumpSpool>`.
+pub async fn capture_error_dumps(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(spool) = req.app_data::<web::Data<DumpSpool>>().cloned() else {
+        return next.call(req).await;
+    };
+    let started = Instant::now();
+    let captured = Rc::new(RefCell::new(CapturedBody::default()));
+    let sink = captured.clone();
+    let limit = spool.max_body_bytes();
+    let tee = req.take_payload().inspect(move |chunk| {
+        if let Ok(bytes) = chunk {
+            let mut body = sink.borrow_mut();
+            body.total += bytes.len();
+            let room = limit.saturating_sub(body.bytes.len()).min(bytes.len());
+            body.bytes.extend_from_slice(&bytes[..room]);
+        }
+    });
+    let stream: Pin<Box<dyn Stream<Item = Result<web::Bytes, PayloadError>>>> = Box::pin(tee);
+    req.set_payload(Payload::Stream { payload: stream });
+
+    let context = req.extensions().get::<RequestContext>().cloned();
+    let method = req.method().to_string();
+    let path = match req.query_string() {
+        "" => req.path().to_string(),
+        query => format!("{}?{}", req.path(), sanitize_query(query)),
+    };
+    let route = req.match_pattern();
+    let headers = sanitize_headers(req.headers());
+    let content_type = req.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_string);
+
+    let result = next.call(req).await;
+    let (status, error) = match &result {
+        // Shed and simulated responses are not failures worth dumping
+        Ok(res) if res.headers().contains_key(ERROR_CIRCUIT_HEADER) || res.headers().contains_key(SIMULATED_HEADER) => {
+            return result;
+        }
+        Ok(res) => (res.status(), None),
+        Err(e) => (e.as_response_error().status_code(), Some(redact_text(&e.to_string()))),
+    };
+    if !status.is_server_error() || !spool.sample() {
+        return result;
+    }
+
+    let captured_at = Utc::now();
+    let body = captured.borrow();
+    let body_truncated = body.total > body.bytes.len();
+    let dump = RequestDump {
+        id: new_id(captured_at),
+        captured_at,
+        request_id: context.as_ref().map(|context| context.request_id.clone()).unwrap_or_default(),
+        trace_id: context.as_ref().map(|context| context.trace_id.clone()).unwrap_or_default(),
+        method,
+        path,
+        route,
+        status: status.as_u16(),
+        error,
+        headers,
+        body: sanitize_body(&body.bytes, content_type.as_deref(), body_truncated),
+        body_bytes: body.total,
+        body_truncated,
+        timing: context
+            .map(|context| context.timing.phases())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, duration)| DumpPhase {
+                name: name.to_string(),
+                ms: duration.as_secs_f64() * 1000.0,
+            })
+            .collect(),
+        total_ms: started.elapsed().as_secs_f64() * 1000.0,
+    };
+    match spool.store(&dump) {
+        Ok(()) => info!("Captured error dump {} for {} {} ({})", dump.id, dump.method, dump.path, dump.status),
+        Err(e) => warn!("Failed to capture error dump for {} {}: {}", dump.method, dump.path, e),
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::attach_context;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use std::time::Duration;
+
+    fn spool(max_files: usize, max_body_bytes: usize) -> DumpSpool {
+        let dir = std::env::temp_dir().join(format!("dumps-{}", uuid::Uuid::new_v4()));
+        DumpSpool::open(dir, max_files, max_body_bytes, 1.0).unwrap()
+    }
+
+    fn dump(id: &str) -> RequestDump {
+        RequestDump {
+            id: id.to_string(),
+            captured_at: Utc::now(),
+            request_id: "req-1".to_string(),
+            trace_id: "trace".to_string(),
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            route: Some("/".to_string()),
+            status: 500,
+            error: None,
+            headers: BTreeMap::new(),
+            body: String::new(),
+            body_bytes: 0,
+            body_truncated: false,
+            timing: Vec::new(),
+            total_ms: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_spool_keeps_the_newest_dumps() {
+        let spool = spool(2, 1024);
+        for id in ["20240501T100000000000Z-a", "20240501T100001000000Z-b", "20240501T100002000000Z-c"] {
+            spool.store(&dump(id)).unwrap();
+        }
+        let ids: Vec<String> = spool.list().unwrap().into_iter().map(|summary| summary.id).collect();
+        assert_eq!(ids, ["20240501T100002000000Z-c", "20240501T100001000000Z-b"]);
+        assert!(spool.get("20240501T100000000000Z-a").unwrap().is_none());
+        assert_eq!(spool.get("20240501T100002000000Z-c").unwrap().unwrap().request_id, "req-1");
+        assert!(matches!(spool.get("../etc/passwd"), Err(AppError::Validation { .. })));
+        fs::remove_dir_all(&spool.dir).unwrap();
+    }
+
+    #[test]
+    fn test_sanitize_body() {
+        let body = br#"{"email":"ann@example.com","password":"hunter2","items":[{"api_key":"k1","qty":2}]}"#;
+        let sanitized: Value = serde_json::from_str(&sanitize_body(body, Some("application/json"), false)).unwrap();
+        assert_eq!(sanitized["email"], "a***@example.com");
+        assert_eq!(sanitized["password"], REDACTED);
+        assert_eq!(sanitized["items"][0]["api_key"], REDACTED);
+        assert_eq!(sanitized["items"][0]["qty"], 2);
+
+        assert_eq!(sanitize_body(br#"{"password":"hun"#, Some("application/json"), true), "<16 bytes of truncated JSON omitted>");
+        assert_eq!(
+            sanitize_body(b"user=ann&token=abc", Some("application/x-www-form-urlencoded"), false),
+            format!("user=ann&token={}", REDACTED)
+        );
+        assert_eq!(sanitize_body(&[0xff, 0xfe], None, false), "<2 bytes of binary data>");
+    }
+
+    #[actix_web::test]
+    async fn test_capture_error_dumps() {
+        let spool = spool(10, 8);
+        let config = Config {
+            error_dump_dir: Some(spool.dir.display().to_string()),
+            ..Config::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .app_data(web::Data::new(spool.clone()))
+                .wrap(from_fn(capture_error_dumps))
+                .wrap(from_fn(attach_context))
+                .route(
+                    "/orders/{id}",
+                    web::post().to(|body: String, context: RequestContext| async move {
+                        context.timing.record("db", Duration::from_millis(3));
+                        match body.as_str() {
+                            "fine" => HttpResponse::Ok().finish(),
+                            _ => HttpResponse::InternalServerError().finish(),
+                        }
+                    }),
+                ),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::post().uri("/orders/1").set_payload("fine").to_request()).await;
+        assert_eq!(res.status(), 200);
+        assert!(spool.list().unwrap().is_empty());
+
+        let res = call_service(
+            &app,
+            TestRequest::post()
+                .uri("/orders/2?access_token=abc&page=1")
+                .insert_header(("Authorization", "Bearer secret"))
+                .insert_header(("X-Request-Id", "req-9"))
+                .insert_header(("X-Forwarded-For", "203.0.113.9"))
+                .set_payload("some plain text body")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.status(), 500);
+        // Phases are recorded for the dump without turning on the Server-Timing header
+        assert!(!res.headers().contains_key("server-timing"));
+        assert!(read_body(res).await.is_empty());
+
+        let dumps = spool.list().unwrap();
+        assert_eq!(dumps.len(), 1);
+        let dump = spool.get(&dumps[0].id).unwrap().unwrap();
+        assert_eq!((dump.request_id.as_str(), dump.status), ("req-9", 500));
+        assert_eq!(dump.path, format!("/orders/2?access_token={}&page=1", REDACTED));
+        assert_eq!(dump.route.as_deref(), Some("/orders/{id}"));
+        assert_eq!(dump.headers["authorization"], REDACTED);
+        assert_eq!(dump.headers["x-forwarded-for"], "203.0.113.9");
+        // The handler still got the whole body, the dump keeps its first 8 bytes
+        assert_eq!((dump.body.as_str(), dump.body_bytes, dump.body_truncated), ("some pla", 20, true));
+        assert_eq!(dump.timing, [DumpPhase { name: "db".to_string(), ms: 3.0 }]);
+        fs::remove_dir_all(&spool.dir).unwrap();
+    }
+}
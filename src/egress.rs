@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+
+/// Token bucket settings of one destination
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EgressLimits {
+    /// Tokens added per second, 0 for unlimited
+    pub rate_per_sec: f64,
+    /// Bucket capacity, the largest burst sent at once
+    pub burst: u32,
+}
+
+impl EgressLimits {
+    /// Parses per-destination limits: `name:rate/burst,...`
+    ///
+    /// # Errors
+    /// Returns a configuration error for malformed entries
+    pub fn parse_overrides(spec: &str) -> AppResult<Vec<(String, EgressLimits)>> {
+        spec.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let invalid = || AppError::config(format!("egress limit '{}' must look like 'name:rate/burst'", entry));
+                let (name, limits) = entry.split_once(':').ok_or_else(invalid)?;
+                let (rate, burst) = limits.split_once('/').ok_or_else(invalid)?;
+                let rate_per_sec = rate.trim().parse::<f64>().map_err(|_| invalid())?;
+                let burst = burst.trim().parse::<u32>().map_err(|_| invalid())?;
+                if !rate_per_sec.is_finite() || rate_per_sec < 0.0 || burst == 0 {
+                    return Err(invalid());
+                }
+                Ok((name.trim().to_string(), EgressLimits { rate_per_sec, burst }))
+            })
+            .collect()
+    }
+}
+
+/// What a delivery does when its destination's bucket is empty
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpilloverPolicy {
+    /// Wait for a token, unless `EGRESS_MAX_QUEUE` deliveries already wait
+    Defer,
+    /// Discard the delivery
+    Drop,
+}
+
+impl fmt::Display for SpilloverPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SpilloverPolicy::Defer => "defer",
+            SpilloverPolicy::Drop => "drop",
+        })
+    }
+}
+
+impl FromStr for SpilloverPolicy {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "defer" => Ok(SpilloverPolicy::Defer),
+            "drop" => Ok(SpilloverPolicy::Drop),
+            other => Err(AppError::config(format!(
+                "unknown spillover policy '{}' (expected defer or drop)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Deliveries to one destination, as reported by `/metrics`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct EgressCounts {
+    /// Sent at once, a token being available
+    pub sent: u64,
+    /// Sent after waiting for a token
+    pub deferred: u64,
+    /// Discarded for lack of a token
+    pub dropped: u64,
+    /// Currently waiting for a token
+    pub queued: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    counts: EgressCounts,
+}
+
+/// Whether a delivery may go, after how long
+#[derive(Debug, PartialEq)]
+enum Admission {
+    Now,
+    After(Duration),
+    Dropped,
+}
+
+/// Outbound rate limiter keeping one token bucket per destination
+///
+/// Smooths bursts of internal events so they cannot overwhelm a webhook
+/// target: each destination gets `EGRESS_RATE_PER_SEC` tokens per second up
+/// to `EGRESS_BURST`, or its own `EGRESS_LIMITS` entry. A delivery finding
+/// the bucket empty waits for its token under the `defer` policy, holding
+/// back the caller, until `EGRESS_MAX_QUEUE` deliveries wait; then, or
+/// under the `drop` policy, it is discarded. Buckets are per instance.
+pub struct EgressLimiter {
+    defaults: EgressLimits,
+    overrides: HashMap<String, EgressLimits>,
+    policy: SpilloverPolicy,
+    max_queue: usize,
+    buckets: Mutex<BTreeMap<String, Bucket>>,
+}
+
+impl EgressLimiter {
+    /// Creates a limiter applying `defaults` to every destination
+    pub fn new(defaults: EgressLimits, policy: SpilloverPolicy, max_queue: usize) -> Self {
+        Self {
+            defaults,
+            overrides: HashMap::new(),
+            policy,
+            max_queue,
+            buckets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Builds the limiter from the `EGRESS_*` settings
+    pub fn from_config(config: &Config) -> Self {
+        let defaults = EgressLimits {
+            rate_per_sec: config.egress_rate_per_sec,
+            burst: config.egress_burst,
+        };
+        let mut limiter = Self::new(defaults, config.egress_spillover, config.egress_max_queue);
+        for (name, limits) in &config.egress_limits {
+            limiter = limiter.with_limits(name, *limits);
+        }
+        limiter
+    }
+
+    /// Overrides the limits of `destination`
+    pub fn with_limits(mut self, destination: &str, limits: EgressLimits) -> Self {
+        self.overrides.insert(destination.to_string(), limits);
+        self
+    }
+
+    /// Whether some destination is limited
+    pub fn enabled(&self) -> bool {
+        self.defaults.rate_per_sec > 0.0 || self.overrides.values().any(|limits| limits.rate_per_sec > 0.0)
+    }
+
+    /// Limits applying to `destination`
+    pub fn limits(&self, destination: &str) -> EgressLimits {
+        self.overrides.get(destination).copied().unwrap_or(self.defaults)
+    }
+
+    /// Returns the delivery counters of every destination used so far
+    pub fn stats(&self) -> BTreeMap<String, EgressCounts> {
+        self.buckets
+            .lock()
+            .map(|buckets| buckets.iter().map(|(name, bucket)| (name.clone(), bucket.counts.clone())).collect())
+            .unwrap_or_default()
+    }
+
+    /// Takes a token for one delivery to `destination`, waiting for it under
+    /// the `defer` policy
+    ///
+    /// Returns false when the delivery must be discarded.
+    pub async fn admit(&self, destination: &str) -> bool {
+        match self.reserve(destination, Instant::now()) {
+            Admission::Now => true,
+            Admission::Dropped => {
+                debug!("Egress to '{}' over its rate, dropping the delivery", destination);
+                false
+            }
+            Admission::After(wait) => {
+                debug!("Egress to '{}' over its rate, deferring the delivery by {:?}", destination, wait);
+                tokio::time::sleep(wait).await;
+                if let Ok(mut buckets) = self.buckets.lock() {
+                    if let Some(bucket) = buckets.get_mut(destination) {
+                        bucket.counts.queued -= 1;
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    /// Refills the destination's bucket up to `now` and takes a token,
+    /// reserving a future one when none is left and the delivery may wait
+    fn reserve(&self, destination: &str, now: Instant) -> Admission {
+        let limits = self.limits(destination);
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Admission::Now;
+        };
+        let bucket = buckets.entry(destination.to_string()).or_insert_with(|| Bucket {
+            tokens: f64::from(limits.burst),
+            refilled: now,
+            counts: EgressCounts::default(),
+        });
+        if limits.rate_per_sec <= 0.0 {
+            bucket.counts.sent += 1;
+            return Admission::Now;
+        }
+
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limits.rate_per_sec).min(f64::from(limits.burst));
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.counts.sent += 1;
+            return Admission::Now;
+        }
+        if self.policy == SpilloverPolicy::Drop || bucket.counts.queued >= self.max_queue {
+            bucket.counts.dropped += 1;
+            return Admission::Dropped;
+        }
+        // The balance goes negative: later deliveries queue behind this one
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / limits.rate_per_sec);
+        bucket.tokens -= 1.0;
+        bucket.counts.deferred += 1;
+        bucket.counts.queued += 1;
+        Admission::After(wait)
+    }
+}
+
+impl Default for EgressLimiter {
+    fn default() -> Self {
+        Self::new(
+            EgressLimits {
+                rate_per_sec: 0.0,
+                burst: 1,
+            },
+            SpilloverPolicy::Defer,
+            0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(policy: SpilloverPolicy, max_queue: usize) -> EgressLimiter {
+        EgressLimiter::new(
+            EgressLimits {
+                rate_per_sec: 2.0,
+                burst: 2,
+            },
+            policy,
+            max_queue,
+        )
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        let limits = EgressLimits::parse_overrides("slack:0.5/1, webhook:10/20").unwrap();
+        assert_eq!(limits[0], ("slack".to_string(), EgressLimits { rate_per_sec: 0.5, burst: 1 }));
+        assert_eq!(limits[1].1.burst, 20);
+        for invalid in ["slack", "slack:1", "slack:x/1", "slack:1/0", "slack:-1/1"] {
+            assert!(EgressLimits::parse_overrides(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!("DROP".parse::<SpilloverPolicy>().unwrap(), SpilloverPolicy::Drop);
+        assert!("queue".parse::<SpilloverPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_bursts_are_deferred_then_dropped() {
+        let limiter = limiter(SpilloverPolicy::Defer, 2);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve("webhook", now), Admission::Now);
+        assert_eq!(limiter.reserve("webhook", now), Admission::Now);
+        assert_eq!(limiter.reserve("webhook", now), Admission::After(Duration::from_millis(500)));
+        assert_eq!(limiter.reserve("webhook", now), Admission::After(Duration::from_secs(1)));
+        assert_eq!(limiter.reserve("webhook", now), Admission::Dropped);
+
+        let stats = limiter.stats();
+        assert_eq!(
+            stats["webhook"],
+            EgressCounts {
+                sent: 2,
+                deferred: 2,
+                dropped: 1,
+                queued: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_drop_policy_and_refill() {
+        let limiter = limiter(SpilloverPolicy::Drop, 10).with_limits(
+            "email",
+            EgressLimits {
+                rate_per_sec: 0.0,
+                burst: 1,
+            },
+        );
+        let now = Instant::now();
+        limiter.reserve("webhook", now);
+        limiter.reserve("webhook", now);
+        assert_eq!(limiter.reserve("webhook", now), Admission::Dropped);
+        assert_eq!(limiter.reserve("webhook", now + Duration::from_millis(500)), Admission::Now);
+        assert_eq!(limiter.reserve("webhook", now + Duration::from_millis(500)), Admission::Dropped);
+
+        for _ in 0..5 {
+            assert_eq!(limiter.reserve("email", now), Admission::Now);
+        }
+        assert_eq!(limiter.stats()["email"].sent, 5);
+        assert!(limiter.enabled());
+        assert!(!EgressLimiter::default().enabled());
+    }
+
+    #[actix_web::test]
+    async fn test_admit_waits_for_a_token() {
+        let limiter = EgressLimiter::new(
+            EgressLimits {
+                rate_per_sec: 50.0,
+                burst: 1,
+            },
+            SpilloverPolicy::Defer,
+            10,
+        );
+        let started = Instant::now();
+        assert!(limiter.admit("webhook").await);
+        assert!(limiter.admit("webhook").await);
+        assert!(started.elapsed() >= Duration::from_millis(15));
+        assert_eq!(limiter.stats()["webhook"].queued, 0);
+    }
+}
@@ -1,7 +1,51 @@
 use actix_web::{HttpResponse, ResponseError};
 use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::OnceLock;
 use thiserror::Error;
 
+/// How much error responses tell clients, installed once at startup
+static ERROR_DETAIL: OnceLock<ErrorDetail> = OnceLock::new();
+
+/// How much error responses tell clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorDetail {
+    /// Full messages in pretty-printed JSON, for reading in a terminal
+    Verbose,
+    /// Compact JSON; 5xx messages are replaced by the status reason so
+    /// internal details do not reach clients
+    Terse,
+}
+
+impl FromStr for ErrorDetail {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "verbose" => Ok(ErrorDetail::Verbose),
+            "terse" => Ok(ErrorDetail::Terse),
+            other => Err(AppError::environment("ERROR_DETAIL", format!("must be verbose or terse, got: {}", other))),
+        }
+    }
+}
+
+impl Display for ErrorDetail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ErrorDetail::Verbose => "verbose",
+            ErrorDetail::Terse => "terse",
+        })
+    }
+}
+
+/// Sets how much error responses tell clients
+///
+/// Only the first call has an effect; until then responses carry full
+/// messages in compact JSON.
+pub fn install_error_detail(detail: ErrorDetail) {
+    let _ = ERROR_DETAIL.set(detail);
+}
+
 /// Application-specific error types
 /// 
 /// This enum defines all possible errors that can occur in the application,
@@ -27,6 +71,71 @@ pub enum AppError {
     /// Validation errors for request data
     #[error("Validation error: {message}")]
     Validation { message: String },
+
+    /// Well-formed request whose content is refused (e.g. an upload failing a
+    /// content scan); reported like a validation error but with a 422
+    #[error("Validation error: {message}")]
+    Unprocessable { message: String },
+
+    /// Missing or invalid credentials/signatures
+    #[error("Unauthorized: {message}")]
+    Unauthorized {
+        message: String,
+        /// Authentication scheme the caller should use, sent as `WWW-Authenticate`
+        challenge: Option<String>,
+    },
+
+    /// Authenticated caller lacks the required permissions
+    #[error("Forbidden: {message}")]
+    Forbidden {
+        message: String,
+        /// OAuth scopes the caller would additionally need
+        missing_scopes: Vec<String>,
+    },
+
+    /// Requested resource does not exist
+    #[error("Not found: {message}")]
+    NotFound { message: String },
+
+    /// Temporary inability to accept work (e.g. a full queue)
+    #[error("Service unavailable: {message}")]
+    Unavailable { message: String },
+
+    /// Caller must solve a challenge (CAPTCHA) before retrying
+    #[error("Challenge required: {message}")]
+    ChallengeRequired { message: String },
+
+    /// Caller must accept the current Terms of Service first
+    #[error("Consent required: {message}")]
+    ConsentRequired {
+        message: String,
+        /// Terms version the caller has to accept
+        required_version: u32,
+    },
+
+    /// Caller exceeded a rate limit
+    #[error("Too many requests: {message}")]
+    RateLimited {
+        message: String,
+        /// Seconds until the caller may retry, sent as `Retry-After`
+        retry_after_secs: u64,
+    },
+
+    /// Too many failed logins for the account or client address
+    #[error("Locked out: {message}")]
+    LockedOut {
+        message: String,
+        /// End of the lockout, reported in the body and as `Retry-After`
+        locked_until: chrono::DateTime<chrono::Utc>,
+    },
+
+    /// Caller requires a newer API version than this instance serves
+    #[error("Upgrade required: {message}")]
+    UpgradeRequired {
+        message: String,
+        /// API version of this instance, sent as `X-Api-Version`
+        instance_version: String,
+    },
 }
 
 impl AppError {
@@ -65,6 +174,98 @@ impl AppError {
             message: message.to_string(),
         }
     }
+
+    /// Creates an error refusing the content of a well-formed request
+    pub fn unprocessable<T: Display>(message: T) -> Self {
+        Self::Unprocessable {
+            message: message.to_string(),
+        }
+    }
+
+    /// Creates a new unauthorized error
+    pub fn unauthorized<T: Display>(message: T) -> Self {
+        Self::Unauthorized {
+            message: message.to_string(),
+            challenge: None,
+        }
+    }
+
+    /// Creates an unauthorized error telling the caller how to authenticate
+    pub fn unauthorized_with_challenge<T: Display, U: Display>(message: T, challenge: U) -> Self {
+        Self::Unauthorized {
+            message: message.to_string(),
+            challenge: Some(challenge.to_string()),
+        }
+    }
+
+    /// Creates a new forbidden error
+    pub fn forbidden<T: Display>(message: T) -> Self {
+        Self::Forbidden {
+            message: message.to_string(),
+            missing_scopes: Vec::new(),
+        }
+    }
+
+    /// Creates a forbidden error listing the scopes the caller is missing
+    pub fn missing_scopes(missing_scopes: Vec<String>) -> Self {
+        Self::Forbidden {
+            message: format!("missing required scopes: {}", missing_scopes.join(" ")),
+            missing_scopes,
+        }
+    }
+
+    /// Creates a new not found error
+    pub fn not_found<T: Display>(message: T) -> Self {
+        Self::NotFound {
+            message: message.to_string(),
+        }
+    }
+
+    /// Creates a new service unavailable error
+    pub fn unavailable<T: Display>(message: T) -> Self {
+        Self::Unavailable {
+            message: message.to_string(),
+        }
+    }
+
+    /// Creates a new challenge required error
+    pub fn challenge_required<T: Display>(message: T) -> Self {
+        Self::ChallengeRequired {
+            message: message.to_string(),
+        }
+    }
+
+    /// Creates a new rate limit error
+    pub fn rate_limited<T: Display>(message: T, retry_after_secs: u64) -> Self {
+        Self::RateLimited {
+            message: message.to_string(),
+            retry_after_secs,
+        }
+    }
+
+    /// Creates a lockout error lasting until `locked_until`
+    pub fn locked_out<T: Display>(message: T, locked_until: chrono::DateTime<chrono::Utc>) -> Self {
+        Self::LockedOut {
+            message: message.to_string(),
+            locked_until,
+        }
+    }
+
+    /// Creates a new consent required error for terms `required_version`
+    pub fn consent_required<T: Display>(message: T, required_version: u32) -> Self {
+        Self::ConsentRequired {
+            message: message.to_string(),
+            required_version,
+        }
+    }
+
+    /// Creates an upgrade required error for an instance serving `instance_version`
+    pub fn upgrade_required<T: Display, U: Display>(message: T, instance_version: U) -> Self {
+        Self::UpgradeRequired {
+            message: message.to_string(),
+            instance_version: instance_version.to_string(),
+        }
+    }
 }
 
 impl ResponseError for AppError {
@@ -76,24 +277,88 @@ impl ResponseError for AppError {
             AppError::Environment { .. } => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             AppError::Internal { .. } => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             AppError::Validation { .. } => actix_web::http::StatusCode::BAD_REQUEST,
+            AppError::Unprocessable { .. } => actix_web::http::StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Unauthorized { .. } => actix_web::http::StatusCode::UNAUTHORIZED,
+            AppError::Forbidden { .. } => actix_web::http::StatusCode::FORBIDDEN,
+            AppError::NotFound { .. } => actix_web::http::StatusCode::NOT_FOUND,
+            AppError::Unavailable { .. } => actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
+            AppError::ChallengeRequired { .. } => actix_web::http::StatusCode::FORBIDDEN,
+            AppError::ConsentRequired { .. } => actix_web::http::StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            AppError::RateLimited { .. } => actix_web::http::StatusCode::TOO_MANY_REQUESTS,
+            AppError::LockedOut { .. } => actix_web::http::StatusCode::TOO_MANY_REQUESTS,
+            AppError::UpgradeRequired { .. } => actix_web::http::StatusCode::UPGRADE_REQUIRED,
         }
     }
 
     /// Returns a JSON error response for API consumers
+    ///
+    /// Email addresses in the message are masked (see [`crate::pii`]), and
+    /// the installed [`ErrorDetail`] picks the message and layout.
     fn error_response(&self) -> HttpResponse {
-        let error_json = serde_json::json!({
+        let detail = ERROR_DETAIL.get().copied();
+        let mut error_json = serde_json::json!({
             "error": {
                 "type": self.error_type(),
-                "message": self.to_string(),
+                "message": self.client_message(detail),
                 "timestamp": chrono::Utc::now().to_rfc3339()
             }
         });
 
-        HttpResponse::build(self.status_code()).json(error_json)
+        if let AppError::Forbidden { missing_scopes, .. } = self {
+            if !missing_scopes.is_empty() {
+                error_json["error"]["missing_scopes"] = serde_json::json!(missing_scopes);
+            }
+        }
+
+        if let AppError::ConsentRequired { required_version, .. } = self {
+            error_json["error"]["required_version"] = serde_json::json!(required_version);
+            error_json["error"]["terms"] = serde_json::json!(crate::consent::TERMS_PATH);
+        }
+
+        if let AppError::UpgradeRequired { instance_version, .. } = self {
+            error_json["error"]["instance_version"] = serde_json::json!(instance_version);
+        }
+
+        if let AppError::LockedOut { locked_until, .. } = self {
+            error_json["error"]["locked_until"] = serde_json::json!(locked_until.to_rfc3339());
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::RateLimited { retry_after_secs, .. } = self {
+            response.insert_header((actix_web::http::header::RETRY_AFTER, retry_after_secs.to_string()));
+        }
+        if let AppError::LockedOut { locked_until, .. } = self {
+            let retry_after_secs = (*locked_until - chrono::Utc::now()).num_seconds().max(1);
+            response.insert_header((actix_web::http::header::RETRY_AFTER, retry_after_secs.to_string()));
+        }
+        if let AppError::Unauthorized { challenge: Some(challenge), .. } = self {
+            response.insert_header((actix_web::http::header::WWW_AUTHENTICATE, challenge.as_str()));
+        }
+        if let AppError::UpgradeRequired { instance_version, .. } = self {
+            response.insert_header((crate::version_skew::API_VERSION_HEADER, instance_version.as_str()));
+        }
+        match detail {
+            Some(ErrorDetail::Verbose) => response
+                .content_type(actix_web::http::header::ContentType::json())
+                .body(serde_json::to_string_pretty(&error_json).unwrap_or_default()),
+            _ => response.json(error_json),
+        }
     }
 }
 
 impl AppError {
+    /// Message shown to clients, masked and, with [`ErrorDetail::Terse`],
+    /// reduced to the status reason for server errors
+    fn client_message(&self, detail: Option<ErrorDetail>) -> String {
+        let status = self.status_code();
+        match detail {
+            Some(ErrorDetail::Terse) if status.is_server_error() => {
+                status.canonical_reason().unwrap_or("Server error").to_string()
+            }
+            _ => crate::pii::redact_text(&self.to_string()),
+        }
+    }
+
     /// Returns a string identifier for the error type
     fn error_type(&self) -> &'static str {
         match self {
@@ -101,7 +366,16 @@ impl AppError {
             AppError::Server { .. } => "server_error",
             AppError::Environment { .. } => "environment_error",
             AppError::Internal { .. } => "internal_error",
-            AppError::Validation { .. } => "validation_error",
+            AppError::Validation { .. } | AppError::Unprocessable { .. } => "validation_error",
+            AppError::Unauthorized { .. } => "unauthorized",
+            AppError::Forbidden { .. } => "forbidden",
+            AppError::NotFound { .. } => "not_found",
+            AppError::Unavailable { .. } => "service_unavailable",
+            AppError::ChallengeRequired { .. } => "challenge_required",
+            AppError::ConsentRequired { .. } => "consent_required",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::LockedOut { .. } => "locked_out",
+            AppError::UpgradeRequired { .. } => "upgrade_required",
         }
     }
 }
@@ -125,6 +399,18 @@ mod tests {
         assert!(matches!(env_error, AppError::Environment { .. }));
     }
 
+    #[test]
+    fn test_client_message_detail() {
+        let internal = AppError::internal("pool exhausted on db-1");
+        assert_eq!(internal.client_message(Some(ErrorDetail::Terse)), "Internal Server Error");
+        assert!(internal.client_message(Some(ErrorDetail::Verbose)).contains("db-1"));
+        assert!(internal.client_message(None).contains("db-1"));
+        let validation = AppError::validation("name is required");
+        assert!(validation.client_message(Some(ErrorDetail::Terse)).contains("name is required"));
+        assert_eq!(" Terse ".parse::<ErrorDetail>().unwrap(), ErrorDetail::Terse);
+        assert!("chatty".parse::<ErrorDetail>().is_err());
+    }
+
     #[test]
     fn test_error_status_codes() {
         let config_error = AppError::config("test");
@@ -132,6 +418,50 @@ mod tests {
         
         let validation_error = AppError::validation("test");
         assert_eq!(validation_error.status_code(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let unprocessable_error = AppError::unprocessable("test");
+        assert_eq!(unprocessable_error.status_code(), actix_web::http::StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(unprocessable_error.error_type(), "validation_error");
+
+        let unauthorized_error = AppError::unauthorized("test");
+        assert_eq!(unauthorized_error.status_code(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert!(unauthorized_error.error_response().headers().get(actix_web::http::header::WWW_AUTHENTICATE).is_none());
+        let response = AppError::unauthorized_with_challenge("test", "Basic realm=\"api\"").error_response();
+        assert_eq!(response.headers().get(actix_web::http::header::WWW_AUTHENTICATE).unwrap(), "Basic realm=\"api\"");
+
+        let unavailable_error = AppError::unavailable("test");
+        assert_eq!(unavailable_error.status_code(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+
+        let rate_limited_error = AppError::rate_limited("test", 30);
+        assert_eq!(rate_limited_error.status_code(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        let response = rate_limited_error.error_response();
+        assert_eq!(response.headers().get(actix_web::http::header::RETRY_AFTER).unwrap(), "30");
+
+        let locked_out_error = AppError::locked_out("test", chrono::Utc::now() + chrono::Duration::seconds(90));
+        assert_eq!(locked_out_error.status_code(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(locked_out_error.error_type(), "locked_out");
+        let response = locked_out_error.error_response();
+        let retry_after: i64 = response.headers().get(actix_web::http::header::RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+        assert!((89..=90).contains(&retry_after));
+
+        let consent_error = AppError::consent_required("test", 2);
+        assert_eq!(consent_error.status_code(), actix_web::http::StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+        assert_eq!(consent_error.error_type(), "consent_required");
+
+        let upgrade_error = AppError::upgrade_required("test", "1.4.0");
+        assert_eq!(upgrade_error.status_code(), actix_web::http::StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(upgrade_error.error_type(), "upgrade_required");
+        let response = upgrade_error.error_response();
+        assert_eq!(response.headers().get(crate::version_skew::API_VERSION_HEADER).unwrap(), "1.4.0");
+    }
+
+    #[actix_web::test]
+    async fn test_error_messages_are_redacted() {
+        let response = AppError::validation("ann@example.com is already registered").error_response();
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("a***@example.com is already registered"));
+        assert!(!body.contains("ann@example.com"));
     }
 
     #[test]
@@ -0,0 +1,373 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, ResponseError};
+use log::{info, warn};
+use serde_json::json;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::notifications::{Notification, NotificationRouter};
+use crate::routes::RouteRegistry;
+use crate::simulation::SIMULATED_HEADER;
+
+/// Header marking responses shed while the circuit is open
+pub const ERROR_CIRCUIT_HEADER: &str = "x-error-circuit";
+
+/// Notification event sent when the circuit opens
+pub const CIRCUIT_OPENED_EVENT: &str = "error_circuit.opened";
+
+/// Notification event sent when the circuit closes again
+pub const CIRCUIT_CLOSED_EVENT: &str = "error_circuit.closed";
+
+/// Change of the circuit state, with the error rate that caused it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitTransition {
+    Opened { rate: f64, requests: u64 },
+    Closed { rate: f64, requests: u64 },
+}
+
+/// Request and 5xx counts of one second
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    second: u64,
+    requests: u64,
+    errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct CircuitWindow {
+    buckets: VecDeque<Bucket>,
+    /// Since when the error rate has been above the threshold
+    above_since: Option<Instant>,
+    open: bool,
+}
+
+/// Safety valve putting non-critical routes into maintenance while the
+/// instance fails too many requests
+///
+/// The 5xx rate is measured over the last `window`; once it has stayed above
+/// the threshold for a whole window (with at least `min_requests` requests
+/// in it), the circuit opens: the configured routes are answered with 503
+/// and `Retry-After` so the critical ones keep their capacity, and operators
+/// are notified. It closes, with another notification, as soon as the rate
+/// over the window is back under the threshold. Shed and simulated
+/// responses are not counted. State is per instance.
+pub struct ErrorCircuit {
+    threshold: f64,
+    window: Duration,
+    min_requests: u64,
+    routes: Vec<String>,
+    started: Instant,
+    state: Mutex<CircuitWindow>,
+}
+
+impl ErrorCircuit {
+    /// Creates a closed circuit
+    ///
+    /// # Arguments
+    /// * `threshold` - Fraction of 5xx responses above which the circuit opens
+    /// * `window` - Period the rate is measured over and must stay above the threshold
+    /// * `min_requests` - Requests within the window below which the circuit stays closed
+    /// * `routes` - Route paths shed while the circuit is open
+    pub fn new(threshold: f64, window: Duration, min_requests: u64, routes: Vec<String>) -> Self {
+        Self {
+            threshold,
+            window,
+            min_requests,
+            routes,
+            started: Instant::now(),
+            state: Mutex::new(CircuitWindow::default()),
+        }
+    }
+
+    /// Builds the circuit from the `ERROR_CIRCUIT_*` settings; `None` when
+    /// the threshold is 0
+    ///
+    /// # Errors
+    /// Returns a config error when `ERROR_CIRCUIT_ROUTES` names a path no
+    /// route of `routes` has
+    pub fn from_config(config: &Config, routes: &RouteRegistry) -> AppResult<Option<Self>> {
+        if config.error_circuit_threshold <= 0.0 {
+            return Ok(None);
+        }
+        for path in &config.error_circuit_routes {
+            if !routes.routes().iter().any(|spec| spec.path == path) {
+                return Err(AppError::config(format!("ERROR_CIRCUIT_ROUTES names unknown route {}", path)));
+            }
+        }
+        if config.error_circuit_routes.is_empty() {
+            warn!("ERROR_CIRCUIT_ROUTES is empty: the error circuit only notifies, it sheds no route");
+        }
+        Ok(Some(Self::new(
+            config.error_circuit_threshold,
+            Duration::from_secs(config.error_circuit_window_secs),
+            config.error_circuit_min_requests,
+            config.error_circuit_routes.clone(),
+        )))
+    }
+
+    /// Whether the route `path` is shed while the circuit is open
+    pub fn sheds(&self, path: &str) -> bool {
+        self.routes.iter().any(|route| route == path)
+    }
+
+    /// Re-evaluates the circuit at `now`; returns whether it is open and
+    /// the transition this caused, if any
+    pub fn poll(&self, now: Instant) -> (bool, Option<CircuitTransition>) {
+        let Ok(mut state) = self.state.lock() else {
+            return (false, None);
+        };
+        let transition = self.evaluate(&mut state, now);
+        (state.open, transition)
+    }
+
+    /// Counts a response, `server_error` for a 5xx
+    pub fn record(&self, server_error: bool, now: Instant) -> Option<CircuitTransition> {
+        let mut state = self.state.lock().ok()?;
+        let second = self.second(now);
+        match state.buckets.back_mut() {
+            Some(bucket) if bucket.second == second => {
+                bucket.requests += 1;
+                bucket.errors += u64::from(server_error);
+            }
+            _ => state.buckets.push_back(Bucket {
+                second,
+                requests: 1,
+                errors: u64::from(server_error),
+            }),
+        }
+        self.evaluate(&mut state, now)
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs()
+    }
+
+    fn evaluate(&self, state: &mut CircuitWindow, now: Instant) -> Option<CircuitTransition> {
+        let oldest = self.second(now).saturating_sub(self.window.as_secs().saturating_sub(1));
+        while state.buckets.front().is_some_and(|bucket| bucket.second < oldest) {
+            state.buckets.pop_front();
+        }
+        let requests: u64 = state.buckets.iter().map(|bucket| bucket.requests).sum();
+        let errors: u64 = state.buckets.iter().map(|bucket| bucket.errors).sum();
+        let rate = if requests == 0 { 0.0 } else { errors as f64 / requests as f64 };
+
+        if requests >= self.min_requests && rate > self.threshold {
+            let since = *state.above_since.get_or_insert(now);
+            if !state.open && now.saturating_duration_since(since) >= self.window {
+                state.open = true;
+                return Some(CircuitTransition::Opened { rate, requests });
+            }
+        } else {
+            state.above_since = None;
+            if state.open {
+                state.open = false;
+                return Some(CircuitTransition::Closed { rate, requests });
+            }
+        }
+        None
+    }
+
+    /// Logs `transition` and tells the operators through `notifications`
+    fn announce(&self, transition: CircuitTransition, notifications: Option<web::Data<NotificationRouter>>) {
+        let notification = match transition {
+            CircuitTransition::Opened { rate, requests } => {
+                let message = format!(
+                    "{:.0}% of {} requests failed over the last {}s; shedding: {}",
+                    rate * 100.0,
+                    requests,
+                    self.window.as_secs(),
+                    if self.routes.is_empty() { "none".to_string() } else { self.routes.join(", ") }
+                );
+                warn!("Error circuit opened: {}", message);
+                Notification::new(CIRCUIT_OPENED_EVENT, "Error circuit opened", message)
+                    .with_data(json!({ "rate": rate, "requests": requests, "routes": self.routes }))
+            }
+            CircuitTransition::Closed { rate, requests } => {
+                let message = format!(
+                    "Error rate back to {:.0}% of {} requests over the last {}s",
+                    rate * 100.0,
+                    requests,
+                    self.window.as_secs()
+                );
+                info!("Error circuit closed: {}", message);
+                Notification::new(CIRCUIT_CLOSED_EVENT, "Error circuit closed", message)
+                    .with_data(json!({ "rate": rate, "requests": requests }))
+            }
+        };
+        if let Some(router) = notifications {
+            crate::log_context::spawn(async move {
+                router.notify(&notification).await;
+            });
+        }
+    }
+}
+
+/// Middleware counting responses for the [`ErrorCircuit`] and shedding its
+/// routes while it is open, for use with `from_fn`
+///
+/// Runs inside the access log, so shed requests are logged, and outside
+/// everything that can fail a request, so those failures are counted. Does
+/// nothing without a `web::Data<ErrorCircuit>`.
+pub async fn error_circuit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(circuit) = req.app_data::<web::Data<ErrorCircuit>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let notifications = req.app_data::<web::Data<NotificationRouter>>().cloned();
+
+    let (open, transition) = circuit.poll(Instant::now());
+    if let Some(transition) = transition {
+        circuit.announce(transition, notifications.clone());
+    }
+    if open && req.match_pattern().is_some_and(|route| circuit.sheds(&route)) {
+        let mut response = AppError::unavailable("temporarily unavailable while the service recovers from errors")
+            .error_response();
+        let headers = response.headers_mut();
+        headers.insert(RETRY_AFTER, HeaderValue::from(circuit.window.as_secs()));
+        headers.insert(HeaderName::from_static(ERROR_CIRCUIT_HEADER), HeaderValue::from_static("open"));
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    let result = next.call(req).await;
+    let server_error = match &result {
+        Ok(res) => res.status().is_server_error() && !res.headers().contains_key(SIMULATED_HEADER),
+        Err(e) => e.as_response_error().status_code().is_server_error(),
+    };
+    if let Some(transition) = circuit.record(server_error, Instant::now()) {
+        circuit.announce(transition, notifications);
+    }
+    Ok(result?.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn circuit() -> ErrorCircuit {
+        ErrorCircuit::new(0.5, Duration::from_secs(10), 4, vec!["/reports".to_string()])
+    }
+
+    #[test]
+    fn test_opens_after_a_sustained_error_rate_and_recovers() {
+        let circuit = circuit();
+        let start = circuit.started;
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        // Too few requests to judge
+        for _ in 0..3 {
+            assert_eq!(circuit.record(true, at(0)), None);
+        }
+        // Above the threshold, but not for a whole window yet
+        assert_eq!(circuit.record(false, at(1)), None);
+        assert_eq!(circuit.poll(at(5)), (false, None));
+        for _ in 0..4 {
+            circuit.record(true, at(9));
+        }
+        assert_eq!(circuit.poll(at(10)), (false, None));
+        match circuit.poll(at(11)) {
+            (true, Some(CircuitTransition::Opened { requests, rate })) => {
+                // Only the requests of second 9 are still in the window
+                assert_eq!(requests, 4);
+                assert!((rate - 1.0).abs() < 1e-9, "{}", rate);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(circuit.poll(at(12)), (true, None));
+
+        // Successes bring the rate under the threshold
+        for _ in 0..3 {
+            circuit.record(false, at(13));
+        }
+        assert!(matches!(circuit.record(false, at(13)), Some(CircuitTransition::Closed { requests: 8, .. })));
+        assert_eq!(circuit.poll(at(14)), (false, None));
+    }
+
+    #[test]
+    fn test_errors_ageing_out_close_the_circuit() {
+        let circuit = circuit();
+        let start = circuit.started;
+        for secs in 0..=13 {
+            circuit.record(true, start + Duration::from_secs(secs));
+        }
+        assert!(circuit.poll(start + Duration::from_secs(13)).0);
+        // Without traffic (everything shed), the errors leave the window
+        assert!(matches!(
+            circuit.poll(start + Duration::from_secs(30)),
+            (false, Some(CircuitTransition::Closed { requests: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_from_config() {
+        let routes = RouteRegistry::app_server();
+        assert!(ErrorCircuit::from_config(&Config::default(), &routes).unwrap().is_none());
+        let config = Config {
+            error_circuit_threshold: 0.2,
+            error_circuit_routes: vec!["/public".to_string()],
+            ..Config::default()
+        };
+        assert!(ErrorCircuit::from_config(&config, &routes).unwrap().unwrap().sheds("/public"));
+        let config = Config {
+            error_circuit_threshold: 0.2,
+            error_circuit_routes: vec!["/nope".to_string()],
+            ..Config::default()
+        };
+        assert!(matches!(ErrorCircuit::from_config(&config, &routes), Err(AppError::Config { .. })));
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_sheds_non_critical_routes() {
+        let circuit = web::Data::new(circuit());
+        let app = init_service(
+            App::new()
+                .app_data(circuit.clone())
+                .wrap(from_fn(error_circuit))
+                .route("/reports", web::get().to(HttpResponse::Ok))
+                .route("/login", web::get().to(HttpResponse::Ok))
+                .route("/broken", web::get().to(HttpResponse::InternalServerError))
+                .route(
+                    "/simulated",
+                    web::get().to(|| async {
+                        HttpResponse::ServiceUnavailable().insert_header((SIMULATED_HEADER, "maintenance")).finish()
+                    }),
+                ),
+        )
+        .await;
+
+        assert_eq!(call_service(&app, TestRequest::get().uri("/broken").to_request()).await.status(), 500);
+        for _ in 0..2 {
+            call_service(&app, TestRequest::get().uri("/simulated").to_request()).await;
+        }
+        {
+            let state = circuit.state.lock().unwrap();
+            let counts = state.buckets.iter().fold((0, 0), |(r, e), b| (r + b.requests, e + b.errors));
+            // Simulated failures count as served requests
+            assert_eq!(counts, (3, 1));
+        }
+
+        // Keep failing for a whole window
+        for secs in 0..=11 {
+            circuit.record(true, circuit.started + Duration::from_secs(secs));
+        }
+        assert!(circuit.poll(Instant::now()).0);
+
+        let res = call_service(&app, TestRequest::get().uri("/reports").to_request()).await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.headers().get(ERROR_CIRCUIT_HEADER).unwrap(), "open");
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "10");
+        // Critical routes keep serving
+        assert_eq!(call_service(&app, TestRequest::get().uri("/login").to_request()).await.status(), 200);
+    }
+}
@@ -0,0 +1,547 @@
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use futures::Stream;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::config::Config;
+use crate::error::AppError;
+
+/// Default number of events a subscriber can fall behind by
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// Topics published by the service
+pub mod topics {
+    use super::{OverflowPolicy, Topic};
+    use crate::jobs::Job;
+
+    /// Webhook jobs once processed; feeds the webhook notifications
+    pub const WEBHOOK_PROCESSED: Topic<Job> = Topic::new("webhook.processed", OverflowPolicy::DropOldest);
+}
+
+/// What a publisher does when a subscriber's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Discard the subscriber's oldest queued event
+    DropOldest,
+    /// Discard the event being published
+    DropNewest,
+    /// Wait for room; non-blocking publishes drop the event instead
+    Block,
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            OverflowPolicy::DropOldest => "drop-oldest",
+            OverflowPolicy::DropNewest => "drop-newest",
+            OverflowPolicy::Block => "block",
+        })
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "block" => Ok(OverflowPolicy::Block),
+            other => Err(AppError::config(format!(
+                "unknown overflow policy '{}' (expected drop-oldest, drop-newest or block)",
+                other
+            ))),
+        }
+    }
+}
+
+/// A named topic carrying events of type `T`
+///
+/// Topics are declared as constants so publishers and subscribers agree on
+/// the event type at compile time.
+pub struct Topic<T> {
+    name: &'static str,
+    policy: OverflowPolicy,
+    _event: PhantomData<fn() -> T>,
+}
+
+impl<T> Topic<T> {
+    /// Declares a topic
+    pub const fn new(name: &'static str, policy: OverflowPolicy) -> Self {
+        Self {
+            name,
+            policy,
+            _event: PhantomData,
+        }
+    }
+
+    /// Returns the topic name
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the overflow policy of the topic's subscribers
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+}
+
+/// Per-topic counters, as reported by `/metrics`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TopicStats {
+    pub policy: OverflowPolicy,
+    pub subscribers: usize,
+    pub published: u64,
+    /// Events some subscriber never received because its queue was full
+    pub dropped: u64,
+}
+
+/// Bounded queue of one subscriber
+struct Queue<T> {
+    events: Mutex<VecDeque<T>>,
+    capacity: usize,
+    readable: Notify,
+    writable: Notify,
+    closed: AtomicBool,
+}
+
+impl<T> Queue<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            readable: Notify::new(),
+            writable: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+
+    fn len(&self) -> usize {
+        self.events.lock().map(|events| events.len()).unwrap_or(0)
+    }
+}
+
+/// Subscribers and counters of one topic
+struct Channel<T> {
+    policy: OverflowPolicy,
+    subscribers: Mutex<Vec<Weak<Queue<T>>>>,
+    published: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Outcome of offering an event to a full-or-not queue
+enum Offer<T> {
+    Delivered,
+    Dropped,
+    Full(T),
+}
+
+impl<T: Clone> Channel<T> {
+    fn live_subscribers(&self) -> Vec<Arc<Queue<T>>> {
+        let Ok(mut subscribers) = self.subscribers.lock() else {
+            return Vec::new();
+        };
+        subscribers.retain(|queue| queue.strong_count() > 0);
+        subscribers.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    fn offer(&self, queue: &Queue<T>, event: T) -> Offer<T> {
+        if queue.closed.load(Ordering::SeqCst) {
+            return Offer::Dropped;
+        }
+        let Ok(mut events) = queue.events.lock() else {
+            return Offer::Dropped;
+        };
+        if events.len() >= queue.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    events.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Offer::Dropped;
+                }
+                OverflowPolicy::Block => return Offer::Full(event),
+            }
+        }
+        events.push_back(event);
+        drop(events);
+        queue.readable.notify_one();
+        Offer::Delivered
+    }
+}
+
+/// Type-erased view of a channel for stats and shutdown
+trait AnyChannel: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn stats(&self) -> TopicStats;
+    fn close(&self);
+    fn pending(&self) -> usize;
+    fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+}
+
+struct Named<T> {
+    name: &'static str,
+    channel: Channel<T>,
+}
+
+impl<T: Clone + Send + 'static> AnyChannel for Named<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn stats(&self) -> TopicStats {
+        TopicStats {
+            policy: self.channel.policy,
+            subscribers: self.channel.live_subscribers().len(),
+            published: self.channel.published.load(Ordering::Relaxed),
+            dropped: self.channel.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn close(&self) {
+        for queue in self.channel.live_subscribers() {
+            queue.close();
+        }
+    }
+
+    fn pending(&self) -> usize {
+        self.channel.live_subscribers().iter().map(|queue| queue.len()).sum()
+    }
+
+    fn as_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+/// Channels by topic name and event type
+type Channels = HashMap<(&'static str, TypeId), Arc<dyn AnyChannel>>;
+
+/// In-process publish/subscribe bus with typed topics
+///
+/// Every subscriber gets its own bounded queue, so a slow consumer (an SSE
+/// stream, a webhook forwarder, a cache invalidator) only affects itself:
+/// depending on the topic's [`OverflowPolicy`] it loses its oldest events,
+/// misses new ones, or makes publishers wait. Lost events are counted per
+/// topic. After [`shutdown`](Self::shutdown) publishes are refused and
+/// subscribers receive what is still queued, then `None`.
+pub struct EventBus {
+    capacity: usize,
+    channels: Mutex<Channels>,
+    shut_down: AtomicBool,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl EventBus {
+    /// Creates a bus whose subscriber queues hold `capacity` events
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            channels: Mutex::new(HashMap::new()),
+            shut_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Creates a bus sized by `EVENT_BUS_CAPACITY`
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.event_bus_capacity)
+    }
+
+    fn channel<T: Clone + Send + 'static>(&self, topic: &Topic<T>) -> Option<Arc<Named<T>>> {
+        let mut channels = self.channels.lock().ok()?;
+        let channel = channels
+            .entry((topic.name, TypeId::of::<T>()))
+            .or_insert_with(|| -> Arc<dyn AnyChannel> {
+                Arc::new(Named::<T> {
+                    name: topic.name,
+                    channel: Channel {
+                        policy: topic.policy,
+                        subscribers: Mutex::new(Vec::new()),
+                        published: AtomicU64::new(0),
+                        dropped: AtomicU64::new(0),
+                    },
+                })
+            })
+            .clone();
+        channel.as_any().downcast::<Named<T>>().ok()
+    }
+
+    /// Subscribes to `topic`; events published from now on are queued for it
+    ///
+    /// Subscribing after shutdown returns a subscription that is already closed.
+    pub fn subscribe<T: Clone + Send + 'static>(&self, topic: &Topic<T>) -> Subscription<T> {
+        let queue = Arc::new(Queue::new(self.capacity));
+        match self.channel(topic) {
+            Some(named) if !self.is_shut_down() => {
+                if let Ok(mut subscribers) = named.channel.subscribers.lock() {
+                    subscribers.push(Arc::downgrade(&queue));
+                }
+            }
+            _ => queue.close(),
+        }
+        Subscription { queue }
+    }
+
+    /// Publishes `event` without waiting; returns how many subscribers got it
+    ///
+    /// Full queues of [`OverflowPolicy::Block`] topics drop the event.
+    pub fn try_publish<T: Clone + Send + 'static>(&self, topic: &Topic<T>, event: T) -> usize {
+        let Some(named) = self.channel(topic).filter(|_| !self.is_shut_down()) else {
+            return 0;
+        };
+        let channel = &named.channel;
+        channel.published.fetch_add(1, Ordering::Relaxed);
+        let mut delivered = 0;
+        for queue in channel.live_subscribers() {
+            match channel.offer(&queue, event.clone()) {
+                Offer::Delivered => delivered += 1,
+                Offer::Dropped => {}
+                Offer::Full(_) => {
+                    channel.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Publishes `event`, waiting for room on [`OverflowPolicy::Block`] topics
+    ///
+    /// Returns how many subscribers got it.
+    pub async fn publish<T: Clone + Send + 'static>(&self, topic: &Topic<T>, event: T) -> usize {
+        let Some(named) = self.channel(topic).filter(|_| !self.is_shut_down()) else {
+            return 0;
+        };
+        let channel = &named.channel;
+        channel.published.fetch_add(1, Ordering::Relaxed);
+        let mut delivered = 0;
+        for queue in channel.live_subscribers() {
+            let mut pending = event.clone();
+            loop {
+                let writable = queue.writable.notified();
+                match channel.offer(&queue, pending) {
+                    Offer::Delivered => {
+                        delivered += 1;
+                        break;
+                    }
+                    Offer::Dropped => break,
+                    Offer::Full(event) => {
+                        pending = event;
+                        writable.await;
+                    }
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Refuses further publishes and closes every subscription once drained
+    pub fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+        if let Ok(channels) = self.channels.lock() {
+            for channel in channels.values() {
+                channel.close();
+            }
+        }
+    }
+
+    /// Returns whether [`shutdown`](Self::shutdown) was called
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Waits until subscribers consumed every queued event, or `timeout`
+    ///
+    /// Returns the number of events still queued.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let pending = self.pending();
+            if pending == 0 || tokio::time::Instant::now() >= deadline {
+                return pending;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn pending(&self) -> usize {
+        self.channels
+            .lock()
+            .map(|channels| channels.values().map(|channel| channel.pending()).sum())
+            .unwrap_or(0)
+    }
+
+    /// Returns the counters of every topic, by name
+    pub fn stats(&self) -> BTreeMap<String, TopicStats> {
+        let Ok(channels) = self.channels.lock() else {
+            return BTreeMap::new();
+        };
+        channels
+            .values()
+            .map(|channel| (channel.name().to_string(), channel.stats()))
+            .collect()
+    }
+}
+
+/// Receiving end of a topic subscription; dropping it unsubscribes
+pub struct Subscription<T> {
+    queue: Arc<Queue<T>>,
+}
+
+impl<T> Subscription<T> {
+    /// Waits for the next event
+    ///
+    /// Returns `None` once the bus has shut down and the queue is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let readable = self.queue.readable.notified();
+            let next = self.queue.events.lock().ok()?.pop_front();
+            if let Some(event) = next {
+                self.queue.writable.notify_one();
+                return Some(event);
+            }
+            if self.queue.closed.load(Ordering::SeqCst) {
+                return None;
+            }
+            readable.await;
+        }
+    }
+
+    /// Returns the number of queued events
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns whether no event is queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Turns the subscription into a stream, e.g. for server-sent events
+    pub fn into_stream(self) -> impl Stream<Item = T> {
+        futures::stream::unfold(self, |mut subscription| async move {
+            subscription.recv().await.map(|event| (event, subscription))
+        })
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        // Unblocks publishers waiting for room in this queue
+        self.queue.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    const NUMBERS: Topic<u32> = Topic::new("numbers", OverflowPolicy::DropOldest);
+    const LATEST_FIRST: Topic<u32> = Topic::new("latest-first", OverflowPolicy::DropNewest);
+    const BLOCKING: Topic<u32> = Topic::new("blocking", OverflowPolicy::Block);
+
+    #[actix_web::test]
+    async fn test_every_subscriber_gets_every_event() {
+        let bus = EventBus::new(8);
+        let mut first = bus.subscribe(&NUMBERS);
+        let second = bus.subscribe(&NUMBERS);
+        assert_eq!(bus.try_publish(&NUMBERS, 1), 2);
+        assert_eq!(bus.publish(&NUMBERS, 2).await, 2);
+
+        assert_eq!(first.recv().await, Some(1));
+        assert_eq!(first.recv().await, Some(2));
+        drop(second);
+        assert_eq!(bus.try_publish(&NUMBERS, 3), 1);
+        assert_eq!(bus.stats()["numbers"].subscribers, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_overflow_policies_count_drops() {
+        let bus = EventBus::new(2);
+        let mut oldest = bus.subscribe(&NUMBERS);
+        let mut newest = bus.subscribe(&LATEST_FIRST);
+        let mut blocking = bus.subscribe(&BLOCKING);
+        for n in 1..=3 {
+            bus.try_publish(&NUMBERS, n);
+            bus.try_publish(&LATEST_FIRST, n);
+            bus.try_publish(&BLOCKING, n);
+        }
+
+        assert_eq!((oldest.recv().await, oldest.recv().await), (Some(2), Some(3)));
+        assert_eq!((newest.recv().await, newest.recv().await), (Some(1), Some(2)));
+        assert_eq!((blocking.recv().await, blocking.recv().await), (Some(1), Some(2)));
+        let stats = bus.stats();
+        assert_eq!(stats["numbers"].dropped, 1);
+        assert_eq!(stats["latest-first"].dropped, 1);
+        assert_eq!(stats["blocking"].dropped, 1);
+        assert_eq!(stats["blocking"].published, 3);
+    }
+
+    #[actix_web::test]
+    async fn test_blocking_publish_waits_for_room() {
+        let bus = Arc::new(EventBus::new(1));
+        let subscription = bus.subscribe(&BLOCKING);
+        bus.publish(&BLOCKING, 1).await;
+
+        let publisher = bus.clone();
+        let blocked = actix_web::rt::spawn(async move { publisher.publish(&BLOCKING, 2).await });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!blocked.is_finished());
+
+        let mut events = std::pin::pin!(subscription.into_stream());
+        assert_eq!(events.next().await, Some(1));
+        assert_eq!(blocked.await.unwrap(), 1);
+        assert_eq!(events.next().await, Some(2));
+        assert_eq!(bus.stats()["blocking"].dropped, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_shutdown_drains_subscribers() {
+        let bus = Arc::new(EventBus::new(8));
+        let mut subscription = bus.subscribe(&NUMBERS);
+        bus.try_publish(&NUMBERS, 1);
+        bus.try_publish(&NUMBERS, 2);
+        bus.shutdown();
+        assert_eq!(bus.try_publish(&NUMBERS, 3), 0);
+
+        let consumer = actix_web::rt::spawn(async move {
+            let mut seen = Vec::new();
+            while let Some(n) = subscription.recv().await {
+                seen.push(n);
+            }
+            seen
+        });
+        assert_eq!(bus.drain(Duration::from_secs(1)).await, 0);
+        assert_eq!(consumer.await.unwrap(), vec![1, 2]);
+        assert_eq!(bus.subscribe(&NUMBERS).recv().await, None);
+    }
+
+    #[test]
+    fn test_overflow_policy_round_trip() {
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::DropNewest, OverflowPolicy::Block] {
+            assert_eq!(policy.to_string().parse::<OverflowPolicy>().unwrap(), policy);
+        }
+        assert!("lossy".parse::<OverflowPolicy>().is_err());
+    }
+}
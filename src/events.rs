@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::{AppError, AppResult};
+
+/// CloudEvents specification version emitted by this service
+pub const SPEC_VERSION: &str = "1.0";
+
+/// Default `source` attribute for events produced by this service
+pub const DEFAULT_SOURCE: &str = "/simple-api-demo";
+
+/// Media type for structured-mode CloudEvents over HTTP
+pub const STRUCTURED_CONTENT_TYPE: &str = "application/cloudevents+json; charset=utf-8";
+
+/// CloudEvents 1.0 envelope
+///
+/// Every internal or outbound event (webhooks, broker publishes) is wrapped in
+/// this envelope so event routers can handle it without knowing our payloads.
+/// Extension attributes are flattened next to the core attributes, as the
+/// JSON format requires.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CloudEvent {
+    /// Unique identifier of the event within its source
+    pub id: String,
+    /// URI reference identifying the context in which the event happened
+    pub source: String,
+    /// CloudEvents version, always [`SPEC_VERSION`]
+    pub specversion: String,
+    /// Reverse-DNS style event type, e.g. `com.simple-api-demo.user.created`
+    #[serde(rename = "type")]
+    pub event_type: String,
+    /// Time the occurrence happened
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<DateTime<Utc>>,
+    /// Media type of `data`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub datacontenttype: Option<String>,
+    /// Schema that `data` adheres to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dataschema: Option<String>,
+    /// Subject of the event in the context of the source
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// Event payload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    /// Extension context attributes
+    #[serde(flatten)]
+    pub extensions: BTreeMap<String, Value>,
+}
+
+impl CloudEvent {
+    /// Creates an event with a fresh id, the current time and a JSON payload
+    ///
+    /// # Arguments
+    /// * `event_type` - Reverse-DNS event type
+    /// * `data` - JSON payload
+    pub fn new<T: Into<String>>(event_type: T, data: Value) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            source: DEFAULT_SOURCE.to_string(),
+            specversion: SPEC_VERSION.to_string(),
+            event_type: event_type.into(),
+            time: Some(Utc::now()),
+            datacontenttype: Some("application/json".to_string()),
+            dataschema: None,
+            subject: None,
+            data: Some(data),
+            extensions: BTreeMap::new(),
+        }
+    }
+
+    /// Overrides the `source` attribute
+    pub fn with_source<T: Into<String>>(mut self, source: T) -> Self {
+        self.source = source.into();
+        self
+    }
+
+    /// Sets the `subject` attribute
+    pub fn with_subject<T: Into<String>>(mut self, subject: T) -> Self {
+        self.subject = Some(subject.into());
+        self
+    }
+
+    /// Adds an extension attribute
+    pub fn with_extension<K: Into<String>>(mut self, name: K, value: Value) -> Self {
+        self.extensions.insert(name.into(), value);
+        self
+    }
+
+    /// Checks the event against the CloudEvents 1.0 attribute rules
+    ///
+    /// # Errors
+    /// Returns a validation error describing the first violated rule
+    pub fn validate(&self) -> AppResult<()> {
+        if self.specversion != SPEC_VERSION {
+            return Err(AppError::validation(format!(
+                "unsupported specversion '{}'",
+                self.specversion
+            )));
+        }
+        for (name, value) in [
+            ("id", &self.id),
+            ("source", &self.source),
+            ("type", &self.event_type),
+        ] {
+            if value.is_empty() {
+                return Err(AppError::validation(format!(
+                    "CloudEvent attribute '{}' must be a non-empty string",
+                    name
+                )));
+            }
+        }
+        for name in self.extensions.keys() {
+            if !is_valid_attribute_name(name) || RESERVED_ATTRIBUTES.contains(&name.as_str()) {
+                return Err(AppError::validation(format!(
+                    "invalid CloudEvent extension attribute name '{}'",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Serializes the event in structured content mode (JSON format)
+    ///
+    /// # Errors
+    /// Returns a validation error if the event is not conformant
+    pub fn to_json(&self) -> AppResult<String> {
+        self.validate()?;
+        serde_json::to_string(self)
+            .map_err(|e| AppError::internal(format!("Failed to serialize CloudEvent: {}", e)))
+    }
+
+    /// Parses and validates a structured-mode JSON event
+    pub fn from_json(json: &str) -> AppResult<Self> {
+        let event: CloudEvent = serde_json::from_str(json)
+            .map_err(|e| AppError::validation(format!("Invalid CloudEvent JSON: {}", e)))?;
+        event.validate()?;
+        Ok(event)
+    }
+
+    /// Returns the `ce-*` headers for binary content mode over HTTP
+    ///
+    /// In binary mode the payload travels as the request body and the
+    /// attributes travel as headers.
+    pub fn binary_headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![
+            ("ce-id".to_string(), self.id.clone()),
+            ("ce-source".to_string(), self.source.clone()),
+            ("ce-specversion".to_string(), self.specversion.clone()),
+            ("ce-type".to_string(), self.event_type.clone()),
+        ];
+        if let Some(time) = &self.time {
+            headers.push(("ce-time".to_string(), time.to_rfc3339()));
+        }
+        if let Some(schema) = &self.dataschema {
+            headers.push(("ce-dataschema".to_string(), schema.clone()));
+        }
+        if let Some(subject) = &self.subject {
+            headers.push(("ce-subject".to_string(), subject.clone()));
+        }
+        if let Some(content_type) = &self.datacontenttype {
+            headers.push(("content-type".to_string(), content_type.clone()));
+        }
+        for (name, value) in &self.extensions {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            headers.push((format!("ce-{}", name), value));
+        }
+        headers
+    }
+}
+
+/// Attribute names defined by the spec that extensions must not reuse
+const RESERVED_ATTRIBUTES: &[&str] = &[
+    "id",
+    "source",
+    "specversion",
+    "type",
+    "time",
+    "datacontenttype",
+    "dataschema",
+    "subject",
+    "data",
+    "data_base64",
+];
+
+/// Attribute names must be lower-case ASCII letters or digits, at most 20 chars
+fn is_valid_attribute_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 20
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_structured_serialization_has_required_attributes() {
+        let event = CloudEvent::new("com.simple-api-demo.test", json!({"hello": "world"}));
+        let value: Value = serde_json::from_str(&event.to_json().unwrap()).unwrap();
+
+        assert_eq!(value["specversion"], "1.0");
+        assert_eq!(value["type"], "com.simple-api-demo.test");
+        assert_eq!(value["source"], DEFAULT_SOURCE);
+        assert_eq!(value["datacontenttype"], "application/json");
+        assert!(value["id"].as_str().is_some_and(|id| !id.is_empty()));
+        assert!(DateTime::parse_from_rfc3339(value["time"].as_str().unwrap()).is_ok());
+        assert_eq!(value["data"]["hello"], "world");
+    }
+
+    #[test]
+    fn test_extensions_are_flattened_and_round_trip() {
+        let event = CloudEvent::new("com.simple-api-demo.test", json!(1))
+            .with_subject("users/42")
+            .with_extension("traceparent", json!("00-abc-def-01"));
+        let json = event.to_json().unwrap();
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["traceparent"], "00-abc-def-01");
+
+        let parsed = CloudEvent::from_json(&json).unwrap();
+        assert_eq!(parsed, event);
+    }
+
+    #[test]
+    fn test_spec_example_parses() {
+        let json = r#"{
+            "specversion": "1.0",
+            "type": "com.github.pull_request.opened",
+            "source": "https://github.com/cloudevents/spec/pull",
+            "subject": "123",
+            "id": "A234-1234-1234",
+            "time": "2018-04-05T17:31:00Z",
+            "comexampleextension1": "value",
+            "datacontenttype": "text/xml",
+            "data": "<much wow=\"xml\"/>"
+        }"#;
+        let event = CloudEvent::from_json(json).unwrap();
+        assert_eq!(event.event_type, "com.github.pull_request.opened");
+        assert_eq!(event.extensions["comexampleextension1"], "value");
+    }
+
+    #[test]
+    fn test_validation_rejects_non_conformant_events() {
+        let mut event = CloudEvent::new("t", Value::Null);
+        event.specversion = "0.3".to_string();
+        assert!(event.validate().is_err());
+
+        let event = CloudEvent::new("", Value::Null);
+        assert!(event.validate().is_err());
+
+        let event = CloudEvent::new("t", Value::Null).with_extension("Bad-Name", json!(1));
+        assert!(event.validate().is_err());
+
+        let event = CloudEvent::new("t", Value::Null).with_extension("subject", json!(1));
+        assert!(event.validate().is_err());
+    }
+
+    #[test]
+    fn test_binary_mode_headers() {
+        let event = CloudEvent::new("com.simple-api-demo.test", json!({}))
+            .with_extension("partitionkey", json!("p1"));
+        let headers = event.binary_headers();
+        let get = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+
+        assert_eq!(get("ce-specversion"), Some("1.0"));
+        assert_eq!(get("ce-type"), Some("com.simple-api-demo.test"));
+        assert_eq!(get("ce-partitionkey"), Some("p1"));
+        assert_eq!(get("content-type"), Some("application/json"));
+    }
+}
@@ -0,0 +1,425 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::scanning::Upload;
+use crate::state::KeyValueStore;
+use crate::supervisor::Supervisor;
+
+/// Scope required to upload, fetch and delete files
+pub const FILES_SCOPE: &str = "files";
+
+/// Index key listing the hash of every stored blob
+const BLOBS_INDEX: &str = "blobs";
+
+/// A file uploaded by a user, pointing at the blob holding its content
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileRecord {
+    pub id: String,
+    pub owner: String,
+    pub name: String,
+    /// Type declared at upload
+    pub content_type: String,
+    /// Hex SHA-256 of the content, fetched through `GET /files/by-hash/{sha256}`
+    pub sha256: String,
+    pub size: u64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Content stored once on disk, whatever the number of files sharing it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Blob {
+    pub sha256: String,
+    pub size: u64,
+    /// Type declared by the first upload of this content
+    pub content_type: String,
+    /// Files pointing at this blob
+    pub refs: u64,
+    /// When the last file pointing at it was deleted
+    pub orphaned_at: Option<DateTime<Utc>>,
+}
+
+/// Outcome of a garbage collection
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GcReport {
+    pub blobs_deleted: usize,
+    pub bytes_freed: u64,
+}
+
+/// Content-addressable storage for uploaded files
+///
+/// Blobs are written to `dir/{first two hex digits}/{sha256}` and their
+/// metadata kept in the state store, so identical uploads share one copy
+/// whose reference count follows the files pointing at it. A blob left
+/// without references is deleted by [`collect_garbage`](Self::collect_garbage)
+/// once it has been orphaned for the grace period; uploading the same
+/// content again in the meantime revives it.
+///
+/// Reference counts are updated under a process-local lock: instances
+/// sharing `FILES_DIR` and a Redis state store may race on them.
+pub struct FileStore {
+    dir: PathBuf,
+    store: Arc<dyn KeyValueStore>,
+    max_bytes: usize,
+    grace: Duration,
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    /// Opens the store writing blobs to `dir`, created (mode 0700) if needed
+    ///
+    /// # Arguments
+    /// * `max_bytes` - Largest upload accepted
+    /// * `grace` - How long an unreferenced blob is kept before garbage collection
+    ///
+    /// # Errors
+    /// Returns a config error when the directory cannot be created
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        store: Arc<dyn KeyValueStore>,
+        max_bytes: usize,
+        grace: Duration,
+    ) -> AppResult<Self> {
+        let dir = dir.into();
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(&dir)
+            .map_err(|e| AppError::config(format!("Failed to create FILES_DIR {}: {}", dir.display(), e)))?;
+        Ok(Self {
+            dir,
+            store,
+            max_bytes,
+            grace,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Creates the store from the `FILES_*` settings; `None` when
+    /// `FILES_DIR` is not set
+    ///
+    /// # Errors
+    /// Returns a config error when the directory cannot be created
+    pub fn from_config(config: &Config, store: Arc<dyn KeyValueStore>) -> AppResult<Option<Self>> {
+        config
+            .files_dir
+            .as_deref()
+            .map(|dir| {
+                Self::open(
+                    dir,
+                    store,
+                    config.files_max_bytes,
+                    Duration::from_secs(config.files_gc_grace_secs),
+                )
+            })
+            .transpose()
+    }
+
+    /// Largest upload accepted, in bytes
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Stores `upload` for `owner`, returning its record and whether its
+    /// content was already stored
+    ///
+    /// # Errors
+    /// Validation error when the upload is empty or too large, internal
+    /// error when the blob cannot be written
+    pub fn put(&self, owner: &str, upload: &Upload) -> AppResult<(FileRecord, bool)> {
+        if upload.bytes.is_empty() {
+            return Err(AppError::validation("the file is empty"));
+        }
+        if upload.bytes.len() > self.max_bytes {
+            return Err(AppError::validation(format!("files are limited to {} bytes", self.max_bytes)));
+        }
+        let sha256 = hex::encode(Sha256::digest(&upload.bytes));
+        let record = FileRecord {
+            id: uuid::Uuid::new_v4().to_string(),
+            owner: owner.to_string(),
+            name: upload.filename.clone(),
+            content_type: upload.content_type.clone(),
+            sha256: sha256.clone(),
+            size: upload.bytes.len() as u64,
+            created_at: Utc::now(),
+        };
+
+        let _guard = self.lock()?;
+        let deduplicated = match self.load_blob(&sha256)? {
+            Some(mut blob) => {
+                blob.refs += 1;
+                blob.orphaned_at = None;
+                self.save(&blob_key(&sha256), &blob)?;
+                true
+            }
+            None => {
+                self.write_blob(&sha256, &upload.bytes)?;
+                self.save(
+                    &blob_key(&sha256),
+                    &Blob {
+                        sha256: sha256.clone(),
+                        size: record.size,
+                        content_type: record.content_type.clone(),
+                        refs: 1,
+                        orphaned_at: None,
+                    },
+                )?;
+                let mut hashes = self.ids(BLOBS_INDEX)?;
+                hashes.push(sha256.clone());
+                self.save(BLOBS_INDEX, &hashes)?;
+                false
+            }
+        };
+        self.save(&file_key(&record.id), &record)?;
+        let mut ids = self.ids(&owner_key(owner))?;
+        ids.push(record.id.clone());
+        self.save(&owner_key(owner), &ids)?;
+        Ok((record, deduplicated))
+    }
+
+    /// Loads one of `owner`'s files
+    ///
+    /// # Errors
+    /// Not found if the file does not exist or belongs to someone else
+    pub fn get(&self, owner: &str, id: &str) -> AppResult<FileRecord> {
+        self.load::<FileRecord>(&file_key(id))?
+            .filter(|record| record.owner == owner)
+            .ok_or_else(|| AppError::not_found("file not found"))
+    }
+
+    /// Deletes one of `owner`'s files, orphaning its blob when no other
+    /// file points at it
+    ///
+    /// # Errors
+    /// Not found if the file does not exist or belongs to someone else
+    pub fn delete(&self, owner: &str, id: &str) -> AppResult<()> {
+        let _guard = self.lock()?;
+        let record = self.get(owner, id)?;
+        self.store.delete(&file_key(id))?;
+        let ids: Vec<String> = self.ids(&owner_key(owner))?.into_iter().filter(|other| other != id).collect();
+        self.save(&owner_key(owner), &ids)?;
+        if let Some(mut blob) = self.load_blob(&record.sha256)? {
+            blob.refs = blob.refs.saturating_sub(1);
+            if blob.refs == 0 {
+                blob.orphaned_at = Some(Utc::now());
+            }
+            self.save(&blob_key(&record.sha256), &blob)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the blob of `sha256` and its content, provided `owner` has
+    /// a file with that content
+    ///
+    /// Knowing a hash is not enough to read the content, nor to learn
+    /// whether someone else uploaded it.
+    ///
+    /// # Errors
+    /// Not found when `owner` has no such file, internal error when the
+    /// blob cannot be read
+    pub fn get_by_hash(&self, owner: &str, sha256: &str) -> AppResult<(Blob, Vec<u8>)> {
+        let sha256 = sha256.to_ascii_lowercase();
+        let not_found = || AppError::not_found("file not found");
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(not_found());
+        }
+        let mut owned = false;
+        for id in self.ids(&owner_key(owner))? {
+            if self.load::<FileRecord>(&file_key(&id))?.is_some_and(|record| record.sha256 == sha256) {
+                owned = true;
+                break;
+            }
+        }
+        if !owned {
+            return Err(not_found());
+        }
+        let blob = self.load_blob(&sha256)?.ok_or_else(not_found)?;
+        let bytes = fs::read(self.blob_path(&sha256))
+            .map_err(|e| AppError::internal(format!("failed to read blob {}: {}", sha256, e)))?;
+        Ok((blob, bytes))
+    }
+
+    /// Deletes the blobs left without references for longer than the
+    /// grace period as of `now`
+    ///
+    /// # Errors
+    /// Internal error when the metadata cannot be read or written; blobs
+    /// whose file cannot be removed are kept for the next run
+    pub fn collect_garbage(&self, now: DateTime<Utc>) -> AppResult<GcReport> {
+        let grace = chrono::Duration::from_std(self.grace).unwrap_or(chrono::Duration::MAX);
+        let _guard = self.lock()?;
+        let mut report = GcReport::default();
+        let mut kept = Vec::new();
+        for sha256 in self.ids(BLOBS_INDEX)? {
+            let Some(blob) = self.load_blob(&sha256)? else {
+                continue;
+            };
+            let expired = blob.refs == 0 && blob.orphaned_at.is_some_and(|at| at + grace <= now);
+            if !expired {
+                kept.push(sha256);
+                continue;
+            }
+            match fs::remove_file(self.blob_path(&sha256)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("Failed to delete blob {}: {}", sha256, e);
+                    kept.push(sha256);
+                    continue;
+                }
+            }
+            self.store.delete(&blob_key(&sha256))?;
+            report.blobs_deleted += 1;
+            report.bytes_freed += blob.size;
+        }
+        self.save(BLOBS_INDEX, &kept)?;
+        Ok(report)
+    }
+
+    /// Collects garbage every `every` under `supervisor`
+    pub fn spawn_scheduler(files: Arc<Self>, every: Duration, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("files_gc", move || {
+            let files = files.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(every);
+                loop {
+                    interval.tick().await;
+                    let files = files.clone();
+                    let result = actix_web::web::block(move || files.collect_garbage(Utc::now())).await;
+                    match result.map_err(|e| AppError::internal(e.to_string())).and_then(|result| result) {
+                        Ok(report) if report.blobs_deleted > 0 => info!(
+                            "Deleted {} unreferenced blobs ({} bytes)",
+                            report.blobs_deleted, report.bytes_freed
+                        ),
+                        Ok(_) => {}
+                        Err(e) => error!("File garbage collection failed: {}", e),
+                    }
+                }
+            }
+        });
+    }
+
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.dir.join(&sha256[..2]).join(sha256)
+    }
+
+    fn write_blob(&self, sha256: &str, bytes: &[u8]) -> AppResult<()> {
+        let path = self.blob_path(sha256);
+        let write = || {
+            fs::create_dir_all(path.parent().unwrap_or(&self.dir))?;
+            // Written aside and renamed, so a crash never leaves a partial blob
+            let partial = path.with_extension("tmp");
+            fs::write(&partial, bytes)?;
+            fs::rename(&partial, &path)
+        };
+        write().map_err(|e| AppError::internal(format!("failed to write blob {}: {}", sha256, e)))
+    }
+
+    fn lock(&self) -> AppResult<MutexGuard<'_, ()>> {
+        self.lock.lock().map_err(|_| AppError::internal("file store lock poisoned"))
+    }
+
+    fn load_blob(&self, sha256: &str) -> AppResult<Option<Blob>> {
+        self.load(&blob_key(sha256))
+    }
+
+    fn load<T: for<'de> Deserialize<'de>>(&self, key: &str) -> AppResult<Option<T>> {
+        self.store
+            .get(key)?
+            .map(|raw| serde_json::from_str(&raw).map_err(|e| AppError::internal(format!("Corrupt {}: {}", key, e))))
+            .transpose()
+    }
+
+    fn ids(&self, key: &str) -> AppResult<Vec<String>> {
+        Ok(self.load(key)?.unwrap_or_default())
+    }
+
+    fn save<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> AppResult<()> {
+        let json = serde_json::to_string(value).map_err(|e| AppError::internal(e.to_string()))?;
+        self.store.set(key, &json, None)
+    }
+}
+
+fn blob_key(sha256: &str) -> String {
+    format!("blob:{}", sha256)
+}
+
+fn file_key(id: &str) -> String {
+    format!("file:{}", id)
+}
+
+fn owner_key(owner: &str) -> String {
+    format!("owner:{}", owner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    fn store() -> (FileStore, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("files-{}", uuid::Uuid::new_v4()));
+        let files = FileStore::open(&dir, Arc::new(InMemoryStore::new()), 1024, Duration::from_secs(60)).unwrap();
+        (files, dir)
+    }
+
+    #[test]
+    fn test_identical_uploads_share_one_blob() {
+        let (files, dir) = store();
+        let (first, deduplicated) = files.put("alice", &Upload::new("a.txt", "text/plain", "hello")).unwrap();
+        assert!(!deduplicated);
+        let (second, deduplicated) = files.put("bob", &Upload::new("b.txt", "text/plain", "hello")).unwrap();
+        assert!(deduplicated);
+        assert_eq!(first.sha256, second.sha256);
+        assert_eq!(first.sha256, hex::encode(Sha256::digest(b"hello")));
+        assert!(dir.join(&first.sha256[..2]).join(&first.sha256).is_file());
+
+        let (blob, bytes) = files.get_by_hash("bob", &first.sha256.to_uppercase()).unwrap();
+        assert_eq!((blob.refs, bytes.as_slice()), (2, b"hello".as_slice()));
+        // Only owners of the content may fetch it
+        assert!(matches!(files.get_by_hash("carol", &first.sha256), Err(AppError::NotFound { .. })));
+        assert!(matches!(files.get_by_hash("bob", "../etc"), Err(AppError::NotFound { .. })));
+
+        assert!(matches!(files.put("alice", &Upload::new("e", "text/plain", "")), Err(AppError::Validation { .. })));
+        let large = vec![b'x'; 1025];
+        assert!(matches!(files.put("alice", &Upload::new("l", "text/plain", large)), Err(AppError::Validation { .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_garbage_collection_waits_for_the_grace_period() {
+        let (files, dir) = store();
+        let (first, _) = files.put("alice", &Upload::new("a.txt", "text/plain", "hello")).unwrap();
+        let (second, _) = files.put("bob", &Upload::new("b.txt", "text/plain", "hello")).unwrap();
+        let path = dir.join(&first.sha256[..2]).join(&first.sha256);
+
+        assert!(matches!(files.delete("bob", &first.id), Err(AppError::NotFound { .. })));
+        files.delete("alice", &first.id).unwrap();
+        let later = Utc::now() + chrono::Duration::hours(1);
+        assert_eq!(files.collect_garbage(later).unwrap(), GcReport::default());
+        assert!(path.is_file());
+
+        files.delete("bob", &second.id).unwrap();
+        assert_eq!(files.collect_garbage(Utc::now()).unwrap().blobs_deleted, 0);
+        let report = files.collect_garbage(later).unwrap();
+        assert_eq!((report.blobs_deleted, report.bytes_freed), (1, 5));
+        assert!(!path.exists());
+
+        // The content is written again once collected
+        let (third, deduplicated) = files.put("alice", &Upload::new("c.txt", "text/plain", "hello")).unwrap();
+        assert!(!deduplicated);
+        assert_eq!(files.get_by_hash("alice", &third.sha256).unwrap().1, b"hello");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
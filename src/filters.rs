@@ -0,0 +1,449 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::AppError;
+
+/// Longest accepted filter expression
+pub const MAX_FILTER_LENGTH: usize = 1024;
+
+/// Deepest accepted nesting of parentheses and `NOT`
+const MAX_DEPTH: usize = 32;
+
+/// Comparison operators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Substring of a string, element of an array
+    Contains,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Compare { field: Vec<String>, op: Op, value: Value },
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+/// A filter expression evaluated against JSON events
+///
+/// Comparisons name a dotted field path, an operator and a literal:
+/// `data.repository.name == "api"`, `data.attempts >= 3`,
+/// `title contains "deploy"`. Operators are `==`, `!=`, `<`, `<=`, `>`,
+/// `>=` and `contains`; literals are quoted strings, numbers, `true`,
+/// `false` and `null`. Comparisons combine with `AND`, `OR` and `NOT`
+/// (any case), `NOT` binding tightest and `AND` tighter than `OR`;
+/// parentheses group. A missing field equals `null` and is neither smaller
+/// nor larger than anything, and ordering only compares numbers with
+/// numbers and strings with strings.
+///
+/// Expressions are validated when parsed, so a stored filter always
+/// evaluates; it serializes back to its source text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct EventFilter {
+    source: String,
+    expr: Expr,
+}
+
+impl EventFilter {
+    /// Whether `event` passes the filter
+    pub fn matches(&self, event: &Value) -> bool {
+        evaluate(&self.expr, event)
+    }
+
+    /// The expression as written
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+impl FromStr for EventFilter {
+    type Err = AppError;
+
+    /// Parses a filter expression
+    ///
+    /// # Errors
+    /// Validation error naming the position of the first problem
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = s.trim();
+        if source.is_empty() {
+            return Err(AppError::validation("filter must not be empty"));
+        }
+        if source.len() > MAX_FILTER_LENGTH {
+            return Err(AppError::validation(format!(
+                "filter must be at most {} characters",
+                MAX_FILTER_LENGTH
+            )));
+        }
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            next: 0,
+            depth: 0,
+            end: source.len(),
+        };
+        let expr = parser.or()?;
+        if let Some((position, token)) = parser.tokens.get(parser.next) {
+            return Err(error(*position, format!("unexpected {}", token)));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+}
+
+impl TryFrom<String> for EventFilter {
+    type Error = AppError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<EventFilter> for String {
+    fn from(filter: EventFilter) -> Self {
+        filter.source
+    }
+}
+
+impl fmt::Display for EventFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn error(position: usize, message: impl fmt::Display) -> AppError {
+    AppError::validation(format!("invalid filter at position {}: {}", position + 1, message))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Literal(Value),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Literal(value) => write!(f, "{}", value),
+            Token::Op(op) => write!(f, "operator {:?}", op),
+            Token::And => f.write_str("AND"),
+            Token::Or => f.write_str("OR"),
+            Token::Not => f.write_str("NOT"),
+            Token::Open => f.write_str("'('"),
+            Token::Close => f.write_str("')'"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, AppError> {
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (position, c) = chars[i];
+        let next = chars.get(i + 1).map(|(_, c)| *c);
+        let token = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '=' | '!' | '<' | '>' => {
+                let op = match (c, next) {
+                    ('=', Some('=')) => Op::Eq,
+                    ('!', Some('=')) => Op::Ne,
+                    ('<', Some('=')) => Op::Le,
+                    ('>', Some('=')) => Op::Ge,
+                    ('<', _) => Op::Lt,
+                    ('>', _) => Op::Gt,
+                    _ => return Err(error(position, format!("unknown operator '{}'", c))),
+                };
+                i += if matches!(op, Op::Lt | Op::Gt) { 1 } else { 2 };
+                tokens.push((position, Token::Op(op)));
+                continue;
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                let mut j = i + 1;
+                loop {
+                    match chars.get(j) {
+                        None => return Err(error(position, "unterminated string")),
+                        Some((_, '\\')) => {
+                            let (_, escaped) = *chars.get(j + 1).ok_or_else(|| error(position, "unterminated string"))?;
+                            value.push(escaped);
+                            j += 2;
+                        }
+                        Some((_, quote)) if *quote == c => break,
+                        Some((_, other)) => {
+                            value.push(*other);
+                            j += 1;
+                        }
+                    }
+                }
+                i = j + 1;
+                tokens.push((position, Token::Literal(Value::String(value))));
+                continue;
+            }
+            c if c.is_alphanumeric() || matches!(c, '_' | '-' | '.') => {
+                let mut j = i;
+                while chars
+                    .get(j)
+                    .is_some_and(|(_, c)| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '+'))
+                {
+                    j += 1;
+                }
+                let word: String = chars[i..j].iter().map(|(_, c)| c).collect();
+                i = j;
+                let token = match word.to_ascii_lowercase().as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "contains" => Token::Op(Op::Contains),
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => match word.parse::<f64>() {
+                        Ok(number) => Token::Literal(
+                            serde_json::Number::from_f64(number)
+                                .map(Value::Number)
+                                .ok_or_else(|| error(position, format!("invalid number '{}'", word)))?,
+                        ),
+                        Err(_) => Token::Word(word),
+                    },
+                };
+                tokens.push((position, token));
+                continue;
+            }
+            other => return Err(error(position, format!("unexpected character '{}'", other))),
+        };
+        tokens.push((position, token));
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over `or := and (OR and)*`, `and := unary (AND unary)*`,
+/// `unary := NOT unary | '(' or ')' | field op literal`
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    depth: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn or(&mut self) -> Result<Expr, AppError> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, AppError> {
+        let mut expr = self.unary()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, AppError> {
+        let (position, token) = self.take("a comparison")?;
+        match token {
+            Token::Not | Token::Open => {
+                self.depth += 1;
+                if self.depth > MAX_DEPTH {
+                    return Err(error(position, format!("nested deeper than {}", MAX_DEPTH)));
+                }
+                let expr = if token == Token::Not {
+                    Expr::Not(Box::new(self.unary()?))
+                } else {
+                    let inner = self.or()?;
+                    match self.take("')'")? {
+                        (_, Token::Close) => inner,
+                        (position, other) => return Err(error(position, format!("expected ')', found {}", other))),
+                    }
+                };
+                self.depth -= 1;
+                Ok(expr)
+            }
+            Token::Word(field) => {
+                let path: Vec<String> = field.split('.').map(str::to_string).collect();
+                if path.iter().any(String::is_empty) {
+                    return Err(error(position, format!("invalid field '{}'", field)));
+                }
+                let op = match self.take("an operator")? {
+                    (_, Token::Op(op)) => op,
+                    (position, other) => return Err(error(position, format!("expected an operator, found {}", other))),
+                };
+                let value = match self.take("a value")? {
+                    (_, Token::Literal(value)) => value,
+                    (position, other) => return Err(error(position, format!("expected a value, found {}", other))),
+                };
+                Ok(Expr::Compare { field: path, op, value })
+            }
+            other => Err(error(position, format!("expected a comparison, found {}", other))),
+        }
+    }
+
+    fn take(&mut self, expected: &str) -> Result<(usize, Token), AppError> {
+        let token = self
+            .tokens
+            .get(self.next)
+            .cloned()
+            .ok_or_else(|| error(self.end, format!("expected {}", expected)))?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.tokens.get(self.next).is_some_and(|(_, next)| next == token);
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+}
+
+fn evaluate(expr: &Expr, event: &Value) -> bool {
+    match expr {
+        Expr::And(left, right) => evaluate(left, event) && evaluate(right, event),
+        Expr::Or(left, right) => evaluate(left, event) || evaluate(right, event),
+        Expr::Not(inner) => !evaluate(inner, event),
+        Expr::Compare { field, op, value } => {
+            let actual = field.iter().try_fold(event, |value, key| value.get(key)).unwrap_or(&Value::Null);
+            match op {
+                Op::Eq => equals(actual, value),
+                Op::Ne => !equals(actual, value),
+                Op::Contains => match (actual, value) {
+                    (Value::String(haystack), Value::String(needle)) => haystack.contains(needle.as_str()),
+                    (Value::Array(items), _) => items.iter().any(|item| equals(item, value)),
+                    _ => false,
+                },
+                Op::Lt => order(actual, value) == Some(Ordering::Less),
+                Op::Le => matches!(order(actual, value), Some(Ordering::Less | Ordering::Equal)),
+                Op::Gt => order(actual, value) == Some(Ordering::Greater),
+                Op::Ge => matches!(order(actual, value), Some(Ordering::Greater | Ordering::Equal)),
+            }
+        }
+    }
+}
+
+/// JSON equality, with `1` equal to `1.0`
+fn equals(actual: &Value, expected: &Value) -> bool {
+    match (actual.as_f64(), expected.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => actual == expected,
+    }
+}
+
+fn order(actual: &Value, expected: &Value) -> Option<Ordering> {
+    match (actual, expected) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(source: &str) -> EventFilter {
+        source.parse().unwrap()
+    }
+
+    fn event() -> Value {
+        json!({
+            "event_type": "webhook.github",
+            "title": "Push to main",
+            "data": { "repository": { "name": "api", "stars": 42 }, "labels": ["ci", "prod"], "draft": false },
+        })
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let event = event();
+        for (source, expected) in [
+            ("event_type == 'webhook.github'", true),
+            ("data.repository.name != \"api\"", false),
+            ("data.repository.stars >= 42", true),
+            ("data.repository.stars < 42.5", true),
+            ("data.repository.stars > 100", false),
+            ("title contains 'main'", true),
+            ("data.labels contains 'prod'", true),
+            ("data.draft == false", true),
+            ("data.missing == null", true),
+            ("data.missing < 1", false),
+            ("data.repository.name > 1", false),
+        ] {
+            assert_eq!(filter(source).matches(&event), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        // AND binds tighter than OR: true OR (false AND false)
+        let event = json!({ "a": 1, "b": 0, "c": 0 });
+        assert!(filter("a == 1 OR b == 1 AND c == 1").matches(&event));
+        assert!(!filter("(a == 1 OR b == 1) AND c == 1").matches(&event));
+        // (false AND true) OR true
+        assert!(filter("b == 1 and a == 1 or a == 1").matches(&event));
+        // NOT binds tighter than AND
+        assert!(!filter("NOT a == 1 AND b == 0").matches(&event));
+        assert!(filter("NOT (a == 1 AND b == 1)").matches(&event));
+        assert!(filter("not not a == 1").matches(&event));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (source, position) in [
+            ("a ==", "position 5"),
+            ("a = 1", "position 3"),
+            ("(a == 1", "position 8"),
+            ("a == 1)", "position 7"),
+            ("a == 1 AND", "position 11"),
+            ("a == 'open", "position 6"),
+            ("== 1", "position 1"),
+            ("a == b", "position 6"),
+            ("a..b == 1", "position 1"),
+            ("a == 1 # b", "position 8"),
+        ] {
+            let message = source.parse::<EventFilter>().unwrap_err().to_string();
+            assert!(message.contains(position), "{}: {}", source, message);
+        }
+        assert!("".parse::<EventFilter>().is_err());
+        assert!(format!("{}a == 1{}", "(".repeat(40), ")".repeat(40)).parse::<EventFilter>().is_err());
+        assert!("a == 1".repeat(200).parse::<EventFilter>().is_err());
+    }
+
+    #[test]
+    fn test_serializes_as_source() {
+        let parsed = filter(" data.repository.name == 'api' ");
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), json!("data.repository.name == 'api'"));
+        let decoded: EventFilter = serde_json::from_value(json!("a == 1")).unwrap();
+        assert!(decoded.matches(&json!({ "a": 1 })));
+        assert!(serde_json::from_value::<EventFilter>(json!("a ==")).is_err());
+    }
+}
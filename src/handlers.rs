@@ -14,6 +14,145 @@ pub mod main_server {
             .content_type("text/plain; charset=utf-8")
             .body("Hello world!"))
     }
+
+    /// Readiness endpoint
+    /// 
+    /// Answers 200 once the shared state store responds, 503 otherwise, so
+    /// orchestrators only route traffic to instances that can serve it.
+    /// Supervised background tasks that crash repeatedly also answer 503,
+    /// as does an instance whose cache warm-up has not finished or whose
+    /// clock was last found too far off the `CLOCK_REFERENCE`.
+    pub async fn ready(
+        store: actix_web::web::Data<dyn crate::state::KeyValueStore>,
+        supervisor: Option<actix_web::web::Data<crate::supervisor::Supervisor>>,
+        warmup: Option<actix_web::web::Data<crate::warmup::WarmupStatus>>,
+        clock: Option<actix_web::web::Data<crate::clock::ClockCheck>>,
+        context: crate::context::RequestContext,
+    ) -> Result<HttpResponse, crate::error::AppError> {
+        context
+            .timing
+            .measure("db", || store.get("probe"))
+            .map_err(|e| crate::error::AppError::unavailable(format!("state store unavailable: {}", e)))?;
+        let degraded = supervisor.map(|supervisor| supervisor.unhealthy()).unwrap_or_default();
+        if !degraded.is_empty() {
+            return Err(crate::error::AppError::unavailable(format!(
+                "degraded components: {}",
+                degraded.join(", ")
+            )));
+        }
+        if let Some(warmup) = warmup.filter(|warmup| !warmup.is_complete()) {
+            let (done, total) = warmup.progress();
+            return Err(crate::error::AppError::unavailable(format!(
+                "cache warm-up in progress ({}/{})",
+                done, total
+            )));
+        }
+        if let Some(skew) = clock.and_then(|clock| clock.excessive_skew()) {
+            return Err(crate::error::AppError::unavailable(format!("clock skew of {:+.1}s", skew)));
+        }
+        Ok(HttpResponse::Ok().json(json!({ "status": "ready" })))
+    }
+
+    /// Load balancer weight endpoint
+    /// 
+    /// Returns the weight (0 to 100) external load balancers should give the
+    /// instance, from the application requests in flight, the recent 5xx
+    /// rate and the drain state, along with those inputs. `?format=haproxy`
+    /// or `Accept: text/plain` get the `ready 75%`/`drain` line of an
+    /// HAProxy agent instead. Every answer carries the load as an ORCA
+    /// `endpoint-load-metrics` header for Envoy.
+    pub async fn lb_weight(
+        req: actix_web::HttpRequest,
+        monitor: actix_web::web::Data<crate::lb_weight::LoadMonitor>,
+    ) -> ActixResult<HttpResponse> {
+        let report = monitor.report(std::time::Instant::now());
+        let accept = req
+            .headers()
+            .get(actix_web::http::header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let mut response = HttpResponse::Ok();
+        response.insert_header((crate::lb_weight::LOAD_METRICS_HEADER, report.orca()));
+        if req.query_string().split('&').any(|pair| pair == "format=haproxy") || accept.starts_with("text/plain") {
+            return Ok(response.content_type("text/plain; charset=utf-8").body(report.agent_line()));
+        }
+        Ok(response.json(report))
+    }
+
+    /// Operational metrics endpoint
+    /// 
+    /// Returns the request counters of the application server, which count
+    /// every request regardless of analytics opt-outs, the request latency
+    /// histogram, the restart counts of the supervised background tasks and
+    /// the event bus counters, the outbound deliveries sent, deferred and
+    /// dropped per channel, the region and zone of the instance and the
+    /// last clock check.
+    /// Clients accepting `application/openmetrics-text` get the counters and
+    /// histogram in that format instead, labelled with the region and zone,
+    /// with trace ids attached to the buckets as exemplars.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn metrics(
+        req: actix_web::HttpRequest,
+        pipeline: actix_web::web::Data<crate::analytics::AnalyticsPipeline>,
+        supervisor: Option<actix_web::web::Data<crate::supervisor::Supervisor>>,
+        events: Option<actix_web::web::Data<crate::event_bus::EventBus>>,
+        degradation: Option<actix_web::web::Data<crate::degradation::DegradationPolicy>>,
+        egress: Option<actix_web::web::Data<crate::egress::EgressLimiter>>,
+        placement: Option<actix_web::web::Data<crate::region::Placement>>,
+        clock: Option<actix_web::web::Data<crate::clock::ClockCheck>>,
+    ) -> ActixResult<HttpResponse> {
+        let accept = req
+            .headers()
+            .get(actix_web::http::header::ACCEPT)
+            .and_then(|value| value.to_str().ok());
+        if crate::histogram::wants_openmetrics(accept) {
+            return Ok(HttpResponse::Ok()
+                .content_type(crate::histogram::OPENMETRICS_CONTENT_TYPE)
+                .body(crate::histogram::render_openmetrics(
+                    &pipeline.operational(),
+                    &pipeline.latency(),
+                    &placement.map(|placement| placement.labels()).unwrap_or_default(),
+                )));
+        }
+        let mut body = json!(pipeline.operational());
+        body["latency"] = json!(pipeline.latency());
+        if let Some(supervisor) = supervisor {
+            body["tasks"] = json!(supervisor.health());
+        }
+        if let Some(events) = events {
+            body["events"] = json!(events.stats());
+        }
+        if let Some(degradation) = degradation {
+            body["degradation"] = json!(degradation.stats());
+        }
+        if let Some(egress) = egress {
+            body["egress"] = json!(egress.stats());
+        }
+        if let Some(placement) = placement {
+            body["region"] = json!(placement.region);
+            body["zone"] = json!(placement.zone);
+            body["region_affinity_mismatches"] = json!(placement.affinity_mismatches());
+        }
+        if let Some(clock) = clock {
+            body["clock"] = json!(clock.status());
+        }
+        Ok(HttpResponse::Ok().json(body))
+    }
+
+    /// Debug information endpoint
+    /// 
+    /// Reports non-secret runtime settings. Only registered when
+    /// `ENABLE_DEBUG_ENDPOINTS=true`.
+    pub async fn debug_info(config: actix_web::web::Data<crate::config::Config>) -> ActixResult<HttpResponse> {
+        Ok(HttpResponse::Ok().json(json!({
+            "app_env": config.app_env.to_string(),
+            "state_mode": config.state_mode.to_string(),
+            "replica_count": config.replica_count,
+            "cors_allowed_origins": config.cors_allowed_origins,
+            "cookie_secure": config.cookie_secure,
+            "version": env!("CARGO_PKG_VERSION")
+        })))
+    }
 }
 
 /// Application server handlers  
@@ -32,6 +171,22 @@ pub mod app_server {
         })))
     }
 
+    /// Version endpoint
+    /// 
+    /// Reports the build version, the API version and where the serving
+    /// instance runs, so clients can tell which deployment answered.
+    pub async fn version(
+        config: actix_web::web::Data<crate::config::Config>,
+    ) -> ActixResult<HttpResponse> {
+        Ok(HttpResponse::Ok().json(json!({
+            "service": "simple-api-demo",
+            "version": env!("CARGO_PKG_VERSION"),
+            "api_version": config.api_version.to_string(),
+            "region": config.region,
+            "zone": config.zone
+        })))
+    }
+
     /// Public route endpoint
     /// 
     /// Returns a JSON response for publicly accessible content.
@@ -47,17 +202,1500 @@ pub mod app_server {
     /// Private route endpoint
     /// 
     /// Returns a JSON response for protected content.
-    /// In a real application, this would require authentication.
-    pub async fn private_route() -> ActixResult<HttpResponse> {
-        Ok(HttpResponse::Ok().json(json!({
+    /// In a real application, this would require authentication. When the
+    /// caller is authenticated its subject and roles are included.
+    pub async fn private_route(principal: Option<crate::rbac::Principal>) -> ActixResult<HttpResponse> {
+        let mut body = json!({
             "message": "private and protected route",
             "access": "private",
             "timestamp": chrono::Utc::now().to_rfc3339(),
             "warning": "This route should require authentication in production"
+        });
+        if let Some(principal) = principal {
+            body["subject"] = json!(principal.subject);
+            body["roles"] = json!(principal.roles);
+        }
+        Ok(HttpResponse::Ok().json(body))
+    }
+
+    /// Client information endpoint
+    /// 
+    /// Echoes what the server resolved about the caller: client address
+    /// (through trusted proxies), user agent class, geo data and TLS details.
+    pub async fn whoami(
+        client: crate::client_info::ClientInfo,
+        context: crate::context::RequestContext,
+    ) -> ActixResult<HttpResponse> {
+        let mut body = json!(client);
+        body["request_id"] = json!(context.request_id);
+        Ok(HttpResponse::Ok().insert_header((actix_web::http::header::CACHE_CONTROL, "no-store")).json(body))
+    }
+
+    /// Quota endpoint
+    /// 
+    /// Reports the daily and monthly usage, limits and reset times of the
+    /// calling `X-Api-Key` key. Querying does not count against the quota.
+    pub async fn quota(
+        quotas: actix_web::web::Data<crate::auth::QuotaService>,
+        req: actix_web::HttpRequest,
+    ) -> Result<HttpResponse, crate::error::AppError> {
+        let name = crate::auth::quotas::calling_key(&req)
+            .ok_or_else(|| crate::error::AppError::unauthorized("an API key is required"))?;
+        let status = quotas.status(&name, chrono::Utc::now())?;
+        Ok(HttpResponse::Ok().insert_header((actix_web::http::header::CACHE_CONTROL, "no-store")).json(status))
+    }
+
+    /// OpenAPI document endpoint
+    /// 
+    /// Serves the document generated from the route registry at startup.
+    pub async fn openapi(
+        document: actix_web::web::Data<crate::openapi::OpenApiDocument>,
+        context: crate::context::RequestContext,
+    ) -> ActixResult<HttpResponse> {
+        Ok(context.timing.measure("render", || HttpResponse::Ok().json(&document.0)))
+    }
+}
+
+/// Inbound webhook handlers
+pub mod hooks {
+    use super::*;
+    use actix_web::{web, HttpRequest};
+    use serde_json::Value;
+
+    use crate::error::AppError;
+    use crate::jobs::{Job, JobQueue};
+    use crate::webhooks::WebhookVerifier;
+
+    /// Receives a webhook delivery from `{provider}`
+    /// 
+    /// Verifies the provider signature, enqueues the payload for asynchronous
+    /// processing and answers 202 Accepted with the job id without waiting
+    /// for the work to happen.
+    pub async fn receive(
+        provider: web::Path<String>,
+        req: HttpRequest,
+        body: web::Bytes,
+        verifier: web::Data<WebhookVerifier>,
+        jobs: web::Data<JobQueue>,
+    ) -> Result<HttpResponse, AppError> {
+        let provider = provider.into_inner();
+        verifier.verify(&provider, req.headers(), &body)?;
+
+        let payload: Value = serde_json::from_slice(&body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+        let event = req
+            .headers()
+            .get("x-github-event")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| payload.get("type").and_then(Value::as_str).map(str::to_string));
+
+        let job_id = jobs.enqueue(Job::new(
+            format!("webhook.{}", provider),
+            json!({
+                "provider": provider,
+                "event": event,
+                "payload": payload,
+            }),
+        ))?;
+
+        Ok(HttpResponse::Accepted().json(json!({
+            "status": "accepted",
+            "job_id": job_id
         })))
     }
 }
 
+/// Outbound webhook delivery handlers
+pub mod webhooks {
+    use super::*;
+    use actix_web::web;
+    use serde::Deserialize;
+
+    use crate::auth::Claims;
+    use crate::deliveries::parse_time;
+    use crate::error::AppError;
+    use crate::notifications::NotificationRouter;
+    use crate::pagination::{Order, PageQuery};
+    use crate::subscriptions::{SubscriptionRegistry, SubscriptionRequest};
+
+    /// Time range of a delivery listing or replay
+    ///
+    /// Bounds are `YYYY-MM-DD` days or RFC 3339 timestamps; the range
+    /// defaults to the last 24 hours.
+    #[derive(Deserialize)]
+    pub struct TimeRange {
+        from: Option<String>,
+        to: Option<String>,
+    }
+
+    impl TimeRange {
+        fn resolve(&self) -> Result<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>), AppError> {
+            let to = self.to.as_deref().map(parse_time).transpose()?.unwrap_or_else(chrono::Utc::now);
+            let from = match self.from.as_deref() {
+                Some(from) => parse_time(from)?,
+                None => to - chrono::Duration::days(1),
+            };
+            Ok((from, to))
+        }
+    }
+
+    /// Delivery listing endpoint
+    /// 
+    /// Lists the notification deliveries created in the `from`/`to` range,
+    /// oldest first, with every attempt's status, latency and response
+    /// snippet (`admin:webhooks` scope). Paginated through [`PageQuery`].
+    pub async fn deliveries(
+        range: web::Query<TimeRange>,
+        paging: web::Query<PageQuery>,
+        router: web::Data<NotificationRouter>,
+    ) -> Result<HttpResponse, AppError> {
+        let (from, to) = range.resolve()?;
+        let page = paging.paginate(router.deliveries()?.list(from, to)?, Order::OldestFirst, |delivery| {
+            (delivery.created_at, delivery.id.as_str())
+        })?;
+        Ok(HttpResponse::Ok().json(json!({ "deliveries": page.items, "next_cursor": page.next_cursor })))
+    }
+
+    /// Delivery endpoint
+    /// 
+    /// Returns one delivery and its attempts (`admin:webhooks` scope).
+    pub async fn delivery(
+        id: web::Path<String>,
+        router: web::Data<NotificationRouter>,
+    ) -> Result<HttpResponse, AppError> {
+        let delivery = router
+            .deliveries()?
+            .get(&id)?
+            .ok_or_else(|| AppError::not_found("delivery not found"))?;
+        Ok(HttpResponse::Ok().json(delivery))
+    }
+
+    /// Delivery retry endpoint
+    /// 
+    /// Sends a recorded delivery again and returns it with the new attempt
+    /// (`admin:webhooks` scope).
+    pub async fn retry(
+        id: web::Path<String>,
+        router: web::Data<NotificationRouter>,
+    ) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(router.retry(&id).await?))
+    }
+
+    /// Bulk replay request
+    #[derive(Deserialize)]
+    pub struct ReplayRequest {
+        #[serde(flatten)]
+        range: TimeRange,
+        /// Only replay deliveries no attempt succeeded for (default: true)
+        #[serde(default = "failed_only_default")]
+        failed_only: bool,
+    }
+
+    fn failed_only_default() -> bool {
+        true
+    }
+
+    /// Bulk replay endpoint
+    /// 
+    /// Sends again the deliveries created in the `from`/`to` range, by
+    /// default only those that never went through, and reports which
+    /// succeeded (`admin:webhooks` scope).
+    pub async fn replay(
+        body: web::Json<ReplayRequest>,
+        router: web::Data<NotificationRouter>,
+    ) -> Result<HttpResponse, AppError> {
+        let (from, to) = body.range.resolve()?;
+        Ok(HttpResponse::Ok().json(router.replay(from, to, body.failed_only).await?))
+    }
+
+    /// Subscription creation endpoint
+    /// 
+    /// Registers a webhook endpoint for the caller; the signing secret is
+    /// only returned here and by secret rotation (`webhooks` scope).
+    pub async fn create_subscription(
+        claims: web::ReqData<Claims>,
+        body: web::Json<SubscriptionRequest>,
+        registry: web::Data<SubscriptionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        let (subscription, secret) = registry.create(&claims.sub, &body)?;
+        let mut response = json!(subscription);
+        response["secret"] = json!(secret);
+        Ok(HttpResponse::Created()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(response))
+    }
+
+    /// Lists the caller's webhook subscriptions, paginated through [`PageQuery`]
+    pub async fn list_subscriptions(
+        claims: web::ReqData<Claims>,
+        paging: web::Query<PageQuery>,
+        registry: web::Data<SubscriptionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        let page = paging.paginate(registry.list(&claims.sub)?, Order::NewestFirst, |subscription| {
+            (subscription.created_at, subscription.id.as_str())
+        })?;
+        Ok(HttpResponse::Ok().json(json!({ "subscriptions": page.items, "next_cursor": page.next_cursor })))
+    }
+
+    /// Returns one of the caller's webhook subscriptions
+    pub async fn subscription(
+        claims: web::ReqData<Claims>,
+        id: web::Path<String>,
+        registry: web::Data<SubscriptionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(registry.get(&claims.sub, &id)?))
+    }
+
+    /// Deletes one of the caller's webhook subscriptions
+    pub async fn delete_subscription(
+        claims: web::ReqData<Claims>,
+        id: web::Path<String>,
+        registry: web::Data<SubscriptionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        registry.delete(&claims.sub, &id)?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// Resumes deliveries to one of the caller's webhook subscriptions
+    pub async fn enable_subscription(
+        claims: web::ReqData<Claims>,
+        id: web::Path<String>,
+        registry: web::Data<SubscriptionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(registry.set_enabled(&claims.sub, &id, true)?))
+    }
+
+    /// Pauses deliveries to one of the caller's webhook subscriptions
+    pub async fn disable_subscription(
+        claims: web::ReqData<Claims>,
+        id: web::Path<String>,
+        registry: web::Data<SubscriptionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(registry.set_enabled(&claims.sub, &id, false)?))
+    }
+
+    /// Secret rotation endpoint
+    /// 
+    /// Returns the subscription's new secret; deliveries are signed with
+    /// both secrets for `WEBHOOK_SECRET_GRACE_SECS`.
+    pub async fn rotate_subscription_secret(
+        claims: web::ReqData<Claims>,
+        id: web::Path<String>,
+        registry: web::Data<SubscriptionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        let (subscription, secret) = registry.rotate_secret(&claims.sub, &id)?;
+        let mut response = json!(subscription);
+        response["secret"] = json!(secret);
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(response))
+    }
+
+    /// Test delivery endpoint
+    /// 
+    /// Sends a `webhook.test` event to one of the caller's subscriptions,
+    /// even a disabled one, and returns how the attempt went.
+    pub async fn test_subscription(
+        claims: web::ReqData<Claims>,
+        id: web::Path<String>,
+        router: web::Data<NotificationRouter>,
+    ) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(router.send_test(&claims.sub, &id).await?))
+    }
+}
+
+/// Authentication endpoint handlers
+pub mod auth {
+    use super::*;
+    use actix_web::{web, HttpRequest};
+    use serde::Deserialize;
+
+    use crate::auth::{
+        ChallengeGate, Claims, ClientRegistry, CookieSession, CookieSessionManager, GuestTokenIssuer, LoginLockout,
+        OidcClient, OidcUser, RefreshTokenService, SessionRegistry, SigningKeyRing, TokenDenylist, TokenService,
+        TwoFactorService,
+    };
+    use crate::auth::sessions::Session;
+    use crate::client_info::client_ip;
+    use crate::error::AppError;
+    use crate::events::CloudEvent;
+    use crate::tls::ClientCertificate;
+    use crate::users::{User, UserService};
+
+    /// Account registration request
+    #[derive(Debug, Deserialize)]
+    pub struct RegisterRequest {
+        pub email: String,
+        pub password: String,
+    }
+
+    /// Login request; `otp` is a TOTP or recovery code for 2FA accounts
+    #[derive(Debug, Deserialize)]
+    pub struct LoginRequest {
+        pub email: String,
+        pub password: String,
+        #[serde(default)]
+        pub otp: Option<String>,
+        /// Name shown in the session list; defaults to the User-Agent
+        #[serde(default)]
+        pub device_name: Option<String>,
+    }
+
+    /// Refresh token exchange or revocation request
+    #[derive(Debug, Deserialize)]
+    pub struct RefreshRequest {
+        pub refresh_token: String,
+    }
+
+    /// Two-factor enrollment confirmation
+    #[derive(Debug, Deserialize)]
+    pub struct TwoFactorConfirmRequest {
+        pub code: String,
+    }
+
+    /// Email verification request
+    #[derive(Debug, Deserialize)]
+    pub struct VerifyRequest {
+        pub token: String,
+    }
+
+    /// Password reset: request a token by email, or redeem one
+    #[derive(Debug, Deserialize)]
+    #[serde(untagged)]
+    pub enum ResetRequest {
+        Confirm { token: String, password: String },
+        Request { email: String },
+    }
+
+    /// Query of the OpenID Connect callback
+    #[derive(Debug, Deserialize)]
+    pub struct OidcCallback {
+        pub code: String,
+        pub state: String,
+    }
+
+    /// RFC 7662 introspection request (form encoded)
+    #[derive(Debug, Deserialize)]
+    pub struct IntrospectionRequest {
+        pub token: String,
+        #[serde(default)]
+        pub token_type_hint: Option<String>,
+    }
+
+    /// Token introspection endpoint (RFC 7662)
+    /// 
+    /// Callers authenticate with HTTP Basic client credentials. Any token
+    /// that fails verification or has been revoked is reported as
+    /// `{"active": false}` without detail, as the RFC requires.
+    pub async fn introspect(
+        req: HttpRequest,
+        form: web::Form<IntrospectionRequest>,
+        clients: web::Data<ClientRegistry>,
+        tokens: web::Data<TokenService>,
+        denylist: web::Data<TokenDenylist>,
+    ) -> Result<HttpResponse, AppError> {
+        clients.authenticate(req.headers())?;
+
+        let claims = match tokens.verify(&form.token) {
+            Ok(claims) if !denylist.is_revoked(&claims)? => claims,
+            _ => return Ok(HttpResponse::Ok().json(json!({ "active": false }))),
+        };
+
+        let mut body = serde_json::to_value(&claims)
+            .map_err(|e| AppError::internal(format!("Failed to encode claims: {}", e)))?;
+        if let Some(object) = body.as_object_mut() {
+            object.insert("active".to_string(), json!(true));
+            object.insert("token_type".to_string(), json!("Bearer"));
+        }
+        Ok(HttpResponse::Ok().json(body))
+    }
+
+    /// Account registration endpoint
+    /// 
+    /// Creates an account with an unverified email and mails a verification token.
+    pub async fn register(
+        body: web::Json<RegisterRequest>,
+        users: web::Data<UserService>,
+    ) -> Result<HttpResponse, AppError> {
+        let user = users.register(&body.email, &body.password).await?;
+        Ok(HttpResponse::Created().json(user.profile()))
+    }
+
+    /// Login endpoint
+    /// 
+    /// Exchanges email and password (plus a one-time password for accounts
+    /// with 2FA) for an access token. Users whose role requires 2FA but who
+    /// have not enrolled get a token limited to the `account` scope. Failed
+    /// attempts count towards the challenge threshold of the client address.
+    /// Each successful login starts a session listed under `/me/sessions`.
+    pub async fn login(
+        req: HttpRequest,
+        body: web::Json<LoginRequest>,
+        users: web::Data<UserService>,
+        two_factor: web::Data<TwoFactorService>,
+        tokens: web::Data<TokenService>,
+        challenge: web::Data<ChallengeGate>,
+        sessions: web::Data<SessionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        let (token, _) = open_session(&req, &body, &users, &two_factor, &tokens, &challenge, &sessions).await?;
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(token))
+    }
+
+    /// Token endpoint
+    ///
+    /// Same as `/auth/login`, with a refresh token added to the response for
+    /// clients that renew their short-lived access tokens at `/auth/refresh`.
+    /// Users who must enroll in 2FA first get no refresh token.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn token(
+        req: HttpRequest,
+        body: web::Json<LoginRequest>,
+        users: web::Data<UserService>,
+        two_factor: web::Data<TwoFactorService>,
+        tokens: web::Data<TokenService>,
+        challenge: web::Data<ChallengeGate>,
+        sessions: web::Data<SessionRegistry>,
+        refresh: web::Data<RefreshTokenService>,
+    ) -> Result<HttpResponse, AppError> {
+        let (mut token, session) =
+            open_session(&req, &body, &users, &two_factor, &tokens, &challenge, &sessions).await?;
+        if token.get("mfa_enrollment_required").is_none() {
+            token["refresh_token"] = json!(refresh.issue(&session.user_id, &session.id)?);
+            token["refresh_expires_in"] = json!(refresh.ttl().as_secs());
+        }
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(token))
+    }
+
+    /// Refresh endpoint
+    ///
+    /// Exchanges a refresh token for a new access token and a new refresh
+    /// token for the same session. The presented refresh token can not be
+    /// used again; replaying it revokes the session.
+    pub async fn refresh(
+        body: web::Json<RefreshRequest>,
+        users: web::Data<UserService>,
+        two_factor: web::Data<TwoFactorService>,
+        tokens: web::Data<TokenService>,
+        sessions: web::Data<SessionRegistry>,
+        refresh: web::Data<RefreshTokenService>,
+    ) -> Result<HttpResponse, AppError> {
+        let claims = refresh.redeem(&body.refresh_token)?;
+        let session = claims
+            .sid
+            .as_deref()
+            .map(|sid| sessions.get(sid))
+            .transpose()?
+            .flatten()
+            .filter(|session| session.user_id == claims.sub)
+            .ok_or_else(|| AppError::unauthorized("session has ended"))?;
+        let user = users
+            .repository()
+            .get(&claims.sub)?
+            .ok_or_else(|| AppError::unauthorized("account no longer exists"))?;
+        if two_factor.is_required(&user) && !user.two_factor_enabled() {
+            return Err(AppError::unauthorized("two-factor enrollment required, log in again"));
+        }
+        sessions.touch(&session.id, None);
+
+        // Scopes and roles are resolved again, so changes apply from the next refresh
+        let mut token = users.issue_access_token(&tokens, &user, false, Some(&session.id))?;
+        token["session_id"] = json!(session.id);
+        token["refresh_token"] = json!(refresh.issue(&user.id, &session.id)?);
+        token["refresh_expires_in"] = json!(refresh.ttl().as_secs());
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(token))
+    }
+
+    /// Refresh token revocation endpoint
+    ///
+    /// Ends the refresh token's session, so it and every token issued for
+    /// the session stop working. Like RFC 7009, answers 200 for tokens that
+    /// are already invalid.
+    pub async fn revoke(
+        body: web::Json<RefreshRequest>,
+        sessions: web::Data<SessionRegistry>,
+        refresh: web::Data<RefreshTokenService>,
+    ) -> Result<HttpResponse, AppError> {
+        if let Ok(Claims { sub, sid: Some(sid), .. }) = refresh.verify(&body.refresh_token) {
+            match sessions.revoke(&sub, &sid) {
+                Ok(()) | Err(AppError::NotFound { .. }) => {}
+                Err(e) => return Err(e),
+            }
+            sessions.denylist().revoke_session(&sid, refresh.ttl())?;
+        }
+        Ok(HttpResponse::Ok().json(json!({ "revoked": true })))
+    }
+
+    /// Checks the credentials and starts a session with its access token
+    ///
+    /// Users whose role requires 2FA but who have not enrolled get a token
+    /// limited to the `account` scope.
+    async fn open_session(
+        req: &HttpRequest,
+        body: &LoginRequest,
+        users: &UserService,
+        two_factor: &TwoFactorService,
+        tokens: &TokenService,
+        challenge: &ChallengeGate,
+        sessions: &SessionRegistry,
+    ) -> Result<(serde_json::Value, Session), AppError> {
+        let (user, ip) = check_credentials(req, body, users, two_factor, challenge).await?;
+
+        let device_name = body
+            .device_name
+            .as_deref()
+            .or_else(|| {
+                req.headers()
+                    .get(actix_web::http::header::USER_AGENT)
+                    .and_then(|value| value.to_str().ok())
+            })
+            .unwrap_or("unknown");
+        let session = sessions.create(&user.id, device_name, ip)?;
+
+        let enrollment_only = two_factor.is_required(&user) && !user.two_factor_enabled();
+        let mut token = users.issue_access_token(tokens, &user, enrollment_only, Some(&session.id))?;
+        token["session_id"] = json!(session.id);
+        Ok((token, session))
+    }
+
+    /// Checks the login credentials, passing the lockout and challenge gate first
+    ///
+    /// Failed attempts count towards the challenge threshold of the client
+    /// address and, when a [`LoginLockout`] is registered, towards the
+    /// lockout of the account and the address.
+    async fn check_credentials(
+        req: &HttpRequest,
+        body: &LoginRequest,
+        users: &UserService,
+        two_factor: &TwoFactorService,
+        challenge: &ChallengeGate,
+    ) -> Result<(User, std::net::IpAddr), AppError> {
+        let ip = client_ip(req)
+            .ok_or_else(|| AppError::internal("client address unavailable"))?;
+        let lockout = req.app_data::<web::Data<LoginLockout>>();
+        if let Some(lockout) = lockout {
+            lockout.check(&body.email, ip, chrono::Utc::now())?;
+        }
+        challenge.check(ip, req.headers()).await?;
+
+        let result = (|| {
+            let mut user = users.authenticate(&body.email, &body.password)?;
+            if user.two_factor_enabled() {
+                let code = body
+                    .otp
+                    .as_deref()
+                    .ok_or_else(|| AppError::unauthorized("one-time password required"))?;
+                if !two_factor.verify(&mut user, code)? {
+                    return Err(AppError::unauthorized("invalid one-time password"));
+                }
+            }
+            Ok(user)
+        })();
+        let user = result.inspect_err(|e| {
+            if matches!(e, AppError::Unauthorized { .. }) {
+                challenge.record_failure(ip);
+                if let Some(lockout) = lockout {
+                    lockout.record_failure(&body.email, ip, chrono::Utc::now());
+                }
+            }
+        })?;
+        if let Some(lockout) = lockout {
+            lockout.record_success(&body.email);
+        }
+        Ok((user, ip))
+    }
+
+    /// Cookie session login endpoint
+    /// 
+    /// Checks the same credentials as `/auth/login` and answers with an
+    /// encrypted session cookie instead of a token, for browser clients.
+    /// Users who must enroll in 2FA first are refused.
+    pub async fn session_login(
+        req: HttpRequest,
+        body: web::Json<LoginRequest>,
+        users: web::Data<UserService>,
+        two_factor: web::Data<TwoFactorService>,
+        challenge: web::Data<ChallengeGate>,
+        cookie_sessions: web::Data<CookieSessionManager>,
+    ) -> Result<HttpResponse, AppError> {
+        let (user, _) = check_credentials(&req, &body, &users, &two_factor, &challenge).await?;
+        if two_factor.is_required(&user) && !user.two_factor_enabled() {
+            return Err(AppError::forbidden("two-factor enrollment required; log in with /auth/login"));
+        }
+        let (session, cookie) = cookie_sessions.create(&user.id)?;
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .cookie(cookie)
+            .json(session))
+    }
+
+    /// Cookie session logout endpoint
+    /// 
+    /// Ends the session, if any, and clears the cookie.
+    pub async fn session_logout(
+        req: HttpRequest,
+        cookie_sessions: web::Data<CookieSessionManager>,
+    ) -> Result<HttpResponse, AppError> {
+        let cookie = cookie_sessions.destroy(&req)?;
+        Ok(HttpResponse::NoContent().cookie(cookie).finish())
+    }
+
+    /// Returns the current cookie session
+    pub async fn session_info(session: CookieSession) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(session))
+    }
+
+    /// Returns the certificate the client authenticated the TLS connection with
+    ///
+    /// Only answers on listeners with mutual TLS (`APP_TLS_CLIENT_CA_PATH`).
+    pub async fn client_certificate(certificate: ClientCertificate) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(certificate))
+    }
+
+    /// Starts TOTP enrollment for the authenticated user
+    /// 
+    /// Returns the secret and an `otpauth://` URL; 2FA becomes active once
+    /// confirmed with a first code.
+    pub async fn two_factor_setup(
+        claims: web::ReqData<Claims>,
+        two_factor: web::Data<TwoFactorService>,
+    ) -> Result<HttpResponse, AppError> {
+        let provisioning = two_factor.begin_enrollment(&claims.sub)?;
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(provisioning))
+    }
+
+    /// Confirms TOTP enrollment and returns the one-time recovery codes
+    pub async fn two_factor_confirm(
+        claims: web::ReqData<Claims>,
+        body: web::Json<TwoFactorConfirmRequest>,
+        two_factor: web::Data<TwoFactorService>,
+    ) -> Result<HttpResponse, AppError> {
+        let recovery_codes = two_factor.confirm_enrollment(&claims.sub, &body.code)?;
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(json!({ "two_factor_enabled": true, "recovery_codes": recovery_codes })))
+    }
+
+    /// Email verification endpoint
+    /// 
+    /// Redeems a verification token sent by [`register`].
+    pub async fn verify(
+        body: web::Json<VerifyRequest>,
+        users: web::Data<UserService>,
+    ) -> Result<HttpResponse, AppError> {
+        let user = users.verify_email(&body.token).await?;
+        Ok(HttpResponse::Ok().json(user.profile()))
+    }
+
+    /// Password reset endpoint
+    /// 
+    /// With `{"email"}` mails a reset token (always 202, whether or not the
+    /// address is registered); with `{"token", "password"}` sets the password.
+    pub async fn reset(
+        body: web::Json<ResetRequest>,
+        users: web::Data<UserService>,
+    ) -> Result<HttpResponse, AppError> {
+        match body.into_inner() {
+            ResetRequest::Request { email } => {
+                users.request_password_reset(&email).await?;
+                Ok(HttpResponse::Accepted().json(json!({ "status": "reset_requested" })))
+            }
+            ResetRequest::Confirm { token, password } => {
+                let user = users.reset_password(&token, &password).await?;
+                Ok(HttpResponse::Ok().json(user.profile()))
+            }
+        }
+    }
+
+    /// Guest token endpoint
+    /// 
+    /// Issues a short-lived anonymous token with the restricted `GUEST_SCOPES`,
+    /// rate-limited per client IP. Addresses that keep hitting the limit must
+    /// solve a challenge once the configured failure threshold is reached.
+    pub async fn guest(
+        req: HttpRequest,
+        guests: web::Data<GuestTokenIssuer>,
+        tokens: web::Data<TokenService>,
+        challenge: web::Data<ChallengeGate>,
+    ) -> Result<HttpResponse, AppError> {
+        let ip = client_ip(&req)
+            .ok_or_else(|| AppError::internal("client address unavailable"))?;
+        challenge.check(ip, req.headers()).await?;
+
+        let token = guests.issue(&tokens, ip).inspect_err(|e| {
+            if matches!(e, AppError::RateLimited { .. }) {
+                challenge.record_failure(ip);
+            }
+        })?;
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(token))
+    }
+
+    /// Starts an OpenID Connect login by redirecting to the provider
+    pub async fn oidc_login(oidc: Option<web::Data<OidcClient>>) -> Result<HttpResponse, AppError> {
+        let oidc = oidc.ok_or_else(|| AppError::not_found("OpenID Connect is not configured"))?;
+        let request = oidc.authorization_url().await?;
+        Ok(HttpResponse::Found()
+            .insert_header((actix_web::http::header::LOCATION, request.url))
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .finish())
+    }
+
+    /// OpenID Connect callback
+    /// 
+    /// Exchanges the authorization code and returns the validated ID token
+    /// with its claims; the login is recorded in the audit trail.
+    pub async fn oidc_callback(
+        query: web::Query<OidcCallback>,
+        oidc: Option<web::Data<OidcClient>>,
+    ) -> Result<HttpResponse, AppError> {
+        let oidc = oidc.ok_or_else(|| AppError::not_found("OpenID Connect is not configured"))?;
+        let (id_token, claims) = oidc.exchange(&query.code, &query.state).await?;
+
+        let event = CloudEvent::new(
+            "com.simple-api-demo.user.oidc_login",
+            json!({ "issuer": claims.iss, "email_verified": claims.email_verified }),
+        )
+        .with_subject(claims.sub.clone());
+        log::info!(target: "audit", "{}", event.to_json()?);
+
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(json!({ "id_token": id_token, "token_type": "Bearer", "claims": claims })))
+    }
+
+    /// Claims of the OpenID Connect ID token sent as bearer token
+    pub async fn oidc_me(user: OidcUser) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(user.0))
+    }
+
+    /// JSON Web Key Set endpoint
+    /// 
+    /// Publishes the public keys verifying the issued tokens, including
+    /// retired keys still in their grace period. Answers 404 when tokens are
+    /// signed with the shared HS256 secret, which is never published.
+    pub async fn jwks(keys: Option<web::Data<SigningKeyRing>>) -> Result<HttpResponse, AppError> {
+        let keys = keys.ok_or_else(|| AppError::not_found("tokens are signed with a shared secret (JWT_ALGORITHM=HS256)"))?;
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "public, max-age=300"))
+            .json(keys.jwks()))
+    }
+}
+
+/// Terms of Service handlers
+pub mod terms {
+    use super::*;
+    use actix_web::web;
+    use serde::Deserialize;
+
+    use crate::auth::Claims;
+    use crate::consent::ConsentService;
+    use crate::error::AppError;
+
+    /// Terms acceptance request
+    #[derive(Debug, Deserialize)]
+    pub struct AcceptRequest {
+        /// Version being accepted, as returned by `GET /tos`
+        pub version: u32,
+    }
+
+    /// Current Terms of Service version and document
+    pub async fn current(consent: web::Data<ConsentService>) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(consent.terms()?))
+    }
+
+    /// Accepts the current Terms of Service for the authenticated user
+    /// 
+    /// Impersonation tokens cannot accept on a user's behalf.
+    pub async fn accept(
+        claims: web::ReqData<Claims>,
+        body: web::Json<AcceptRequest>,
+        consent: web::Data<ConsentService>,
+    ) -> Result<HttpResponse, AppError> {
+        if claims.act.is_some() {
+            return Err(AppError::forbidden("impersonation tokens cannot accept the terms"));
+        }
+        let user = consent.accept(&claims.sub, body.version)?;
+        Ok(HttpResponse::Ok().json(user.profile()))
+    }
+}
+
+/// Handlers for uploaded files, stored once per content
+pub mod files {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::{web, HttpRequest};
+    use futures::StreamExt;
+    use serde::Deserialize;
+
+    use crate::auth::Claims;
+    use crate::error::AppError;
+    use crate::files::FileStore;
+    use crate::scanning::{ContentScanner, Upload};
+
+    /// Upload query parameters
+    #[derive(Deserialize)]
+    pub struct UploadQuery {
+        /// File name recorded with the upload (default: `upload`)
+        name: Option<String>,
+    }
+
+    fn enabled(files: Option<web::Data<FileStore>>) -> Result<web::Data<FileStore>, AppError> {
+        files.ok_or_else(|| AppError::not_found("file uploads are not enabled (FILES_DIR)"))
+    }
+
+    /// Upload endpoint
+    /// 
+    /// Stores the raw request body as a file of the caller, typed by the
+    /// request's `Content-Type`, once it passes the content scanners
+    /// (`files` scope). Content already stored is not written again; the
+    /// response says whether it was `deduplicated`.
+    pub async fn upload(
+        claims: web::ReqData<Claims>,
+        req: HttpRequest,
+        query: web::Query<UploadQuery>,
+        mut payload: web::Payload,
+        files: Option<web::Data<FileStore>>,
+        scanner: web::Data<dyn ContentScanner>,
+    ) -> Result<HttpResponse, AppError> {
+        let files = enabled(files)?;
+        let mut bytes = web::BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| AppError::validation(format!("failed to read the upload: {}", e)))?;
+            if bytes.len() + chunk.len() > files.max_bytes() {
+                return Err(AppError::validation(format!("files are limited to {} bytes", files.max_bytes())));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/octet-stream");
+        let upload = Upload::new(query.name.as_deref().unwrap_or("upload"), content_type, bytes.freeze());
+        scanner.scan(&upload).await?;
+
+        let (record, deduplicated) = web::block(move || files.put(&claims.sub, &upload))
+            .await
+            .map_err(|e| AppError::internal(format!("upload failed: {}", e)))??;
+        let mut response = json!(record);
+        response["deduplicated"] = json!(deduplicated);
+        Ok(HttpResponse::Created().json(response))
+    }
+
+    /// Content endpoint
+    /// 
+    /// Returns the content hashed to `{sha256}`, provided the caller has a
+    /// file with it (`files` scope). The hash doubles as a strong `ETag`.
+    pub async fn by_hash(
+        claims: web::ReqData<Claims>,
+        sha256: web::Path<String>,
+        req: HttpRequest,
+        files: Option<web::Data<FileStore>>,
+    ) -> Result<HttpResponse, AppError> {
+        let files = enabled(files)?;
+        let sha256 = sha256.into_inner();
+        let (blob, bytes) = web::block(move || files.get_by_hash(&claims.sub, &sha256))
+            .await
+            .map_err(|e| AppError::internal(format!("download failed: {}", e)))??;
+        let etag = format!("\"{}\"", blob.sha256);
+        let cached = req
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+        let mut response = if cached { HttpResponse::NotModified() } else { HttpResponse::Ok() };
+        response
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::CACHE_CONTROL, "private, max-age=31536000, immutable"));
+        if cached {
+            return Ok(response.finish());
+        }
+        Ok(response
+            .insert_header((header::CONTENT_TYPE, blob.content_type))
+            .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .body(bytes))
+    }
+
+    /// Returns one of the caller's files
+    pub async fn file(
+        claims: web::ReqData<Claims>,
+        id: web::Path<String>,
+        files: Option<web::Data<FileStore>>,
+    ) -> Result<HttpResponse, AppError> {
+        Ok(HttpResponse::Ok().json(enabled(files)?.get(&claims.sub, &id)?))
+    }
+
+    /// Deletes one of the caller's files; its content is collected once no
+    /// file points at it
+    pub async fn delete(
+        claims: web::ReqData<Claims>,
+        id: web::Path<String>,
+        files: Option<web::Data<FileStore>>,
+    ) -> Result<HttpResponse, AppError> {
+        enabled(files)?.delete(&claims.sub, &id)?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+/// Handlers for the authenticated user's own account
+pub mod me {
+    use super::*;
+    use actix_web::web;
+    use serde::Deserialize;
+
+    use crate::analytics::AnalyticsPipeline;
+    use crate::auth::{ApiKeyService, Claims, SessionRegistry, TwoFactorService};
+    use crate::error::AppError;
+    use crate::pagination::{Order, PageQuery};
+    use crate::privacy::PrivacyService;
+    use crate::users::{verify_password, UserService};
+
+    /// Account erasure request, re-authenticating the user
+    #[derive(Debug, Deserialize)]
+    pub struct EraseAccountRequest {
+        pub password: String,
+        /// TOTP or recovery code, required when 2FA is enabled
+        #[serde(default)]
+        pub otp: Option<String>,
+    }
+
+    /// API key creation request
+    #[derive(Debug, Deserialize)]
+    pub struct CreateApiKeyRequest {
+        pub name: String,
+        /// Scopes for the key; defaults to those of the calling token
+        #[serde(default)]
+        pub scopes: Vec<String>,
+        /// Days until the key expires; keys without one never expire
+        #[serde(default)]
+        pub expires_in_days: Option<u32>,
+    }
+
+    /// Privacy preferences update
+    #[derive(Debug, Deserialize)]
+    pub struct PrivacyPreferences {
+        pub analytics_opt_out: bool,
+    }
+
+    /// Downloads a ZIP archive of all data stored about the caller
+    /// 
+    /// Impersonation tokens cannot export a user's data.
+    pub async fn export(
+        claims: web::ReqData<Claims>,
+        privacy: web::Data<PrivacyService>,
+    ) -> Result<HttpResponse, AppError> {
+        if claims.act.is_some() {
+            return Err(AppError::forbidden("impersonation tokens cannot export user data"));
+        }
+        let archive = privacy.export(&claims.sub)?;
+        Ok(HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header((
+                actix_web::http::header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"export-{}.zip\"", claims.sub),
+            ))
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .body(archive))
+    }
+
+    /// Requests erasure of the caller's account
+    /// 
+    /// The password (and a one-time password for 2FA accounts) must be
+    /// supplied again. The account is erased once the grace period ends,
+    /// unless cancelled through `POST /me/deletion/cancel`.
+    pub async fn erase(
+        claims: web::ReqData<Claims>,
+        body: web::Json<EraseAccountRequest>,
+        users: web::Data<UserService>,
+        two_factor: web::Data<TwoFactorService>,
+        privacy: web::Data<PrivacyService>,
+    ) -> Result<HttpResponse, AppError> {
+        if claims.act.is_some() {
+            return Err(AppError::forbidden("impersonation tokens cannot erase accounts"));
+        }
+        let mut user = users
+            .repository()
+            .get(&claims.sub)?
+            .ok_or_else(|| AppError::not_found("user not found"))?;
+        if !verify_password(&body.password, &user.password_hash) {
+            return Err(AppError::unauthorized("invalid password"));
+        }
+        if user.two_factor_enabled() {
+            let code = body
+                .otp
+                .as_deref()
+                .ok_or_else(|| AppError::unauthorized("one-time password required"))?;
+            if !two_factor.verify(&mut user, code)? {
+                return Err(AppError::unauthorized("invalid one-time password"));
+            }
+        }
+
+        let user = privacy.schedule_erasure(&user.id)?;
+        Ok(HttpResponse::Accepted().json(json!({
+            "status": "erasure_scheduled",
+            "erase_after": user.erase_after.map(|at| at.to_rfc3339()),
+        })))
+    }
+
+    /// Cancels a pending account erasure
+    pub async fn cancel_erasure(
+        claims: web::ReqData<Claims>,
+        privacy: web::Data<PrivacyService>,
+    ) -> Result<HttpResponse, AppError> {
+        let user = privacy.cancel_erasure(&claims.sub)?;
+        Ok(HttpResponse::Ok().json(user.profile()))
+    }
+
+    /// Sets whether the caller's requests are excluded from analytics
+    /// 
+    /// Applies to every credential of the account, in addition to per-request
+    /// `DNT`/`Sec-GPC` headers.
+    pub async fn update_privacy(
+        claims: web::ReqData<Claims>,
+        body: web::Json<PrivacyPreferences>,
+        analytics: web::Data<AnalyticsPipeline>,
+    ) -> Result<HttpResponse, AppError> {
+        let user = analytics.set_opt_out(&claims.sub, body.analytics_opt_out)?;
+        Ok(HttpResponse::Ok().json(user.profile()))
+    }
+
+    /// Lists the caller's active sessions
+    /// 
+    /// The session the request was made with is flagged `current`. Newest
+    /// first, paginated through [`PageQuery`].
+    pub async fn list_sessions(
+        claims: web::ReqData<Claims>,
+        paging: web::Query<PageQuery>,
+        sessions: web::Data<SessionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        let page = paging.paginate(sessions.list(&claims.sub)?, Order::NewestFirst, |session| {
+            (session.created_at, session.id.as_str())
+        })?;
+        let sessions: Vec<_> = page
+            .items
+            .into_iter()
+            .map(|session| {
+                let current = claims.sid.as_deref() == Some(session.id.as_str());
+                let mut value = json!(session);
+                value["current"] = json!(current);
+                value
+            })
+            .collect();
+        Ok(HttpResponse::Ok().json(json!({ "sessions": sessions, "next_cursor": page.next_cursor })))
+    }
+
+    /// Revokes one of the caller's sessions
+    /// 
+    /// Tokens issued for the session are rejected from then on.
+    pub async fn revoke_session(
+        claims: web::ReqData<Claims>,
+        path: web::Path<String>,
+        sessions: web::Data<SessionRegistry>,
+    ) -> Result<HttpResponse, AppError> {
+        sessions.revoke(&claims.sub, &path.into_inner())?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// Creates an API key
+    /// 
+    /// The key is returned once, in `key`; only a hash is kept. Its scopes
+    /// cannot exceed those of the calling token, and impersonation tokens
+    /// cannot create keys.
+    pub async fn create_api_key(
+        claims: web::ReqData<Claims>,
+        body: web::Json<CreateApiKeyRequest>,
+        keys: web::Data<ApiKeyService>,
+    ) -> Result<HttpResponse, AppError> {
+        if claims.act.is_some() {
+            return Err(AppError::forbidden("impersonation tokens cannot create API keys"));
+        }
+        let expires_in = body
+            .expires_in_days
+            .map(|days| std::time::Duration::from_secs(u64::from(days) * 24 * 3600));
+        let (key, secret) = keys.create(&claims.sub, &body.name, &body.scopes, &claims.scopes(), expires_in)?;
+
+        let mut response = json!(key);
+        response["key"] = json!(secret);
+        Ok(HttpResponse::Created()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(response))
+    }
+
+    /// Lists the caller's API keys with their last use, paginated through [`PageQuery`]
+    pub async fn list_api_keys(
+        claims: web::ReqData<Claims>,
+        paging: web::Query<PageQuery>,
+        keys: web::Data<ApiKeyService>,
+    ) -> Result<HttpResponse, AppError> {
+        let page = paging.paginate(keys.list(&claims.sub)?, Order::NewestFirst, |key| {
+            (key.created_at, key.id.as_str())
+        })?;
+        Ok(HttpResponse::Ok().json(json!({ "api_keys": page.items, "next_cursor": page.next_cursor })))
+    }
+
+    /// Revokes one of the caller's API keys
+    pub async fn revoke_api_key(
+        claims: web::ReqData<Claims>,
+        path: web::Path<String>,
+        keys: web::Data<ApiKeyService>,
+    ) -> Result<HttpResponse, AppError> {
+        keys.revoke(&claims.sub, &path.into_inner())?;
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+/// Administrative handlers
+pub mod admin {
+    use super::*;
+    use actix_web::web;
+    use serde::Deserialize;
+
+    use crate::analytics::{AnalyticsPipeline, UsageAggregator};
+    use crate::anonymization::AnonymizationJob;
+    use crate::auth::{Claims, ImpersonationService, SigningKeyRing, TokenService};
+    use crate::backup::BackupService;
+    use crate::change_guard::{ChangeGuard, Review};
+    use crate::config::{AppEnv, Config};
+    use crate::consent::ConsentService;
+    use crate::demo_data::{DemoDataGenerator, DemoOptions};
+    use crate::dumps::DumpSpool;
+    use crate::error::AppError;
+    use crate::integrity::IntegrityChecker;
+    use crate::lb_weight::LoadMonitor;
+    use crate::metrics_export::MetricsExporter;
+    use crate::pagination::{Order, PageQuery};
+    use crate::policy::Authorizer;
+    use crate::read_only::ReadOnlyMode;
+    use crate::reload::LiveConfig;
+    use crate::slow_queries::SlowQueryLog;
+    use crate::users::UserService;
+
+    /// Impersonation request
+    #[derive(Debug, Deserialize)]
+    pub struct ImpersonateRequest {
+        pub user_id: String,
+        /// Free-text justification recorded in the audit trail
+        #[serde(default)]
+        pub reason: Option<String>,
+    }
+
+    /// Impersonation endpoint
+    /// 
+    /// Mints a short-lived token acting as another user; requires the
+    /// `admin:impersonate` scope and is audited on both accounts.
+    pub async fn impersonate(
+        claims: web::ReqData<Claims>,
+        body: web::Json<ImpersonateRequest>,
+        impersonation: web::Data<ImpersonationService>,
+        users: web::Data<UserService>,
+        tokens: web::Data<TokenService>,
+    ) -> Result<HttpResponse, AppError> {
+        let token = impersonation.impersonate(&users, &tokens, &claims, &body.user_id, body.reason.as_deref())?;
+        Ok(HttpResponse::Ok()
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            .json(token))
+    }
+
+    /// Terms version bump endpoint
+    /// 
+    /// Publishes a new Terms of Service version (`admin:terms` scope); every
+    /// user must accept it before making further changes.
+    pub async fn bump_terms(
+        claims: web::ReqData<Claims>,
+        consent: web::Data<ConsentService>,
+    ) -> Result<HttpResponse, AppError> {
+        let version = consent.bump(&claims.sub)?;
+        Ok(HttpResponse::Ok().json(json!({ "version": version })))
+    }
+
+    /// Signing key rotation endpoint
+    /// 
+    /// Makes a new key sign tokens (`admin:keys` scope); the previous key
+    /// keeps verifying for `JWT_KEY_GRACE_SECS`. Answers 404 unless
+    /// `JWT_ALGORITHM=ES256`.
+    pub async fn rotate_signing_key(
+        claims: web::ReqData<Claims>,
+        keys: Option<web::Data<SigningKeyRing>>,
+    ) -> Result<HttpResponse, AppError> {
+        let keys = keys.ok_or_else(|| AppError::not_found("no signing keys to rotate (JWT_ALGORITHM=HS256)"))?;
+        let kid = keys.rotate()?;
+        log::info!(target: "audit", "Token signing key rotated to {} by {}", kid, claims.sub);
+        Ok(HttpResponse::Ok().json(json!({ "kid": kid })))
+    }
+
+    /// Anonymization query parameters
+    #[derive(Debug, Deserialize)]
+    pub struct AnonymizeQuery {
+        /// Only report affected counts (default: true)
+        #[serde(default = "default_dry_run")]
+        pub dry_run: bool,
+    }
+
+    fn default_dry_run() -> bool {
+        true
+    }
+
+    /// Anonymization endpoint
+    /// 
+    /// Runs the data anonymization job now (`admin:data` scope). Defaults to a
+    /// dry run reporting affected counts; pass `?dry_run=false` to scrub.
+    pub async fn anonymize(
+        query: web::Query<AnonymizeQuery>,
+        job: web::Data<AnonymizationJob>,
+    ) -> Result<HttpResponse, AppError> {
+        let report = job.run(query.dry_run)?;
+        Ok(HttpResponse::Ok().json(report))
+    }
+
+    /// Usage export query parameters
+    #[derive(Debug, Deserialize)]
+    pub struct UsageQuery {
+        /// Day to export (default: today, UTC)
+        #[serde(default)]
+        pub day: Option<chrono::NaiveDate>,
+    }
+
+    /// Usage export endpoint
+    /// 
+    /// Returns the aggregated usage of a day (`admin:data` scope), which
+    /// excludes requests opted out of analytics, alongside this instance's
+    /// operational counters, which include them.
+    pub async fn usage(
+        query: web::Query<UsageQuery>,
+        usage: web::Data<UsageAggregator>,
+        analytics: web::Data<AnalyticsPipeline>,
+    ) -> Result<HttpResponse, AppError> {
+        let day = query.day.unwrap_or_else(|| chrono::Utc::now().date_naive());
+        Ok(HttpResponse::Ok().json(json!({
+            "usage": usage.export(day)?,
+            "operational": analytics.operational(),
+        })))
+    }
+
+    /// Demo data endpoint
+    /// 
+    /// Fills the user repository and usage aggregates with generated accounts,
+    /// audit trails and request history (`admin:data` scope) so pagination,
+    /// search and export have something to show. Takes `?scenario=`,
+    /// `?users=`, `?days=` and `?seed=`; refused in production.
+    pub async fn demo_data(
+        query: web::Query<DemoOptions>,
+        users: web::Data<UserService>,
+        usage: web::Data<UsageAggregator>,
+        config: web::Data<Config>,
+    ) -> Result<HttpResponse, AppError> {
+        if config.app_env == AppEnv::Production {
+            return Err(AppError::forbidden("demo data cannot be generated in production"));
+        }
+        let plan = query.plan()?;
+        let generator = DemoDataGenerator::new(users.repository().clone(), usage.into_inner());
+        let log_context = crate::log_context::LogContext::current();
+        let report = web::block(move || {
+            crate::log_context::sync_scope(log_context, || generator.generate(&plan, chrono::Utc::now()))
+        })
+            .await
+            .map_err(|e| AppError::internal(format!("demo data generation failed: {}", e)))??;
+        Ok(HttpResponse::Created().json(report))
+    }
+
+    /// Policy reload endpoint
+    /// 
+    /// Reads `POLICY_MODEL_FILE` and `POLICY_FILE` again and reports the rules
+    /// now in force; invalid files are refused and the current policy is kept
+    /// (`admin:policy` scope).
+    pub async fn reload_policy(authorizer: web::Data<Authorizer>) -> Result<HttpResponse, AppError> {
+        let summary = authorizer
+            .reload()
+            .map_err(|e| AppError::validation(e.to_string()))?;
+        Ok(HttpResponse::Ok().json(summary))
+    }
+
+    /// Drain switch request
+    #[derive(Debug, Deserialize)]
+    pub struct DrainRequest {
+        pub draining: bool,
+    }
+
+    /// Drain switch endpoint
+    /// 
+    /// Brings the `GET /lb-weight` of this instance to 0, or back to its
+    /// computed value (`admin:maintenance` scope), so load balancers move
+    /// traffic away before a restart. Requests keep being served meanwhile.
+    pub async fn set_drain(
+        claims: web::ReqData<Claims>,
+        body: web::Json<DrainRequest>,
+        monitor: web::Data<LoadMonitor>,
+    ) -> HttpResponse {
+        let report = monitor.set_draining(body.draining);
+        log::info!(target: "audit", "Drain {} by {}", if body.draining { "started" } else { "stopped" }, claims.sub);
+        HttpResponse::Ok().json(report)
+    }
+
+    /// Read-only mode switch request
+    #[derive(Debug, Deserialize)]
+    pub struct ReadOnlyRequest {
+        pub enabled: bool,
+        /// Explanation given to refused callers
+        #[serde(default)]
+        pub reason: Option<String>,
+    }
+
+    /// Read-only mode status endpoint (`admin:maintenance` scope)
+    pub async fn read_only_status(mode: web::Data<ReadOnlyMode>) -> HttpResponse {
+        HttpResponse::Ok().json(mode.status())
+    }
+
+    /// Read-only mode switch endpoint
+    /// 
+    /// Turns the read-only mode on or off (`admin:maintenance` scope). The
+    /// change goes through the [`ChangeGuard`]: it is rate limited, answered
+    /// with 202 and a confirmation token when `read_only.*` is among the
+    /// dangerous keys, and audited with its diff once applied.
+    pub async fn set_read_only(
+        req: actix_web::HttpRequest,
+        claims: web::ReqData<Claims>,
+        body: web::Json<ReadOnlyRequest>,
+        mode: web::Data<ReadOnlyMode>,
+        guard: web::Data<ChangeGuard>,
+    ) -> Result<HttpResponse, AppError> {
+        let current = mode.status();
+        let before = json!({ "read_only": { "enabled": current.enabled, "reason": current.reason } });
+        let after = json!({ "read_only": { "enabled": body.enabled, "reason": body.reason } });
+        match guard.review_request(&req, &claims.sub, &before, &after)? {
+            Review::Approved { diff } => {
+                let status = mode.set(body.enabled, body.reason.clone());
+                guard.record(&claims.sub, "read_only", &diff)?;
+                Ok(HttpResponse::Ok().json(status))
+            }
+            pending => Ok(HttpResponse::Accepted().json(pending)),
+        }
+    }
+
+    /// Backup endpoint
+    /// 
+    /// Writes a consistent snapshot of the in-memory state store to
+    /// `BACKUP_DIR` and describes it (`admin:backup` scope). Restore it with
+    /// the `restore` subcommand.
+    pub async fn backup(
+        claims: web::ReqData<Claims>,
+        backups: Option<web::Data<BackupService>>,
+    ) -> Result<HttpResponse, AppError> {
+        let backups = backups.ok_or_else(|| {
+            AppError::not_found("backups are not enabled (BACKUP_DIR with STATE_MODE=local)")
+        })?;
+        let report = web::block(move || backups.backup())
+            .await
+            .map_err(|e| AppError::internal(format!("backup failed: {}", e)))??;
+        log::info!(target: "audit", "State snapshot {} taken by {}", report.file, claims.sub);
+        Ok(HttpResponse::Created().json(report))
+    }
+
+    /// Integrity report query parameters
+    #[derive(Debug, Deserialize)]
+    pub struct IntegrityQuery {
+        /// Run a check now instead of answering the latest report (default: false)
+        #[serde(default)]
+        pub refresh: bool,
+    }
+
+    /// Integrity report endpoint
+    /// 
+    /// Answers the report of the latest storage integrity check (`admin:data`
+    /// scope), running one first with `?refresh=true` or when none ran yet.
+    /// Checks run from here never repair; see `INTEGRITY_AUTO_REPAIR`.
+    pub async fn integrity(
+        query: web::Query<IntegrityQuery>,
+        checker: web::Data<IntegrityChecker>,
+    ) -> Result<HttpResponse, AppError> {
+        let report = match checker.last_report().filter(|_| !query.refresh) {
+            Some(report) => report,
+            None => web::block(move || checker.run(false))
+                .await
+                .map_err(|e| AppError::internal(format!("integrity check failed: {}", e)))??,
+        };
+        Ok(HttpResponse::Ok().json(report))
+    }
+
+    /// Metrics snapshot endpoint
+    /// 
+    /// Answers every counter, gauge and histogram of `GET /metrics` as one
+    /// structured document (`admin:metrics` scope), the same snapshot
+    /// appended to `METRICS_EXPORT_FILE`.
+    pub async fn metrics_snapshot(exporter: web::Data<MetricsExporter>) -> HttpResponse {
+        HttpResponse::Ok().json(exporter.snapshot())
+    }
+
+    /// Effective configuration endpoint
+    /// 
+    /// Answers every setting of the running configuration, reloads
+    /// included, with the source it came from and its value, secrets
+    /// masked (`admin:config` scope).
+    pub async fn effective_config(live_config: web::Data<LiveConfig>) -> HttpResponse {
+        let config = live_config.load();
+        HttpResponse::Ok().json(serde_json::json!({
+            "app_env": config.app_env.to_string(),
+            "settings": config.redacted_settings(),
+        }))
+    }
+
+    /// Slow query report endpoint
+    /// 
+    /// Answers the query stats of every component store, slowest total
+    /// first, and the last slow queries with their keys redacted
+    /// (`admin:metrics` scope).
+    pub async fn slow_queries(log: Option<web::Data<SlowQueryLog>>) -> Result<HttpResponse, AppError> {
+        let log = log.ok_or_else(|| AppError::not_found("query timing is not enabled"))?;
+        Ok(HttpResponse::Ok().json(log.report()))
+    }
+
+    fn dump_spool(spool: Option<web::Data<DumpSpool>>) -> Result<web::Data<DumpSpool>, AppError> {
+        spool.ok_or_else(|| AppError::not_found("error dumps are not enabled"))
+    }
+
+    /// Error dump listing endpoint
+    /// 
+    /// Lists the captured dumps of requests answered with a 5xx, newest first
+    /// (`admin:dumps` scope). Paginated through [`PageQuery`].
+    pub async fn error_dumps(
+        paging: web::Query<PageQuery>,
+        spool: Option<web::Data<DumpSpool>>,
+    ) -> Result<HttpResponse, AppError> {
+        let spool = dump_spool(spool)?;
+        let dumps = web::block(move || spool.list())
+            .await
+            .map_err(|e| AppError::internal(format!("listing error dumps failed: {}", e)))??;
+        let page = paging.paginate(dumps, Order::NewestFirst, |dump| (dump.captured_at, dump.id.as_str()))?;
+        Ok(HttpResponse::Ok().json(json!({ "dumps": page.items, "next_cursor": page.next_cursor })))
+    }
+
+    /// Error dump endpoint
+    /// 
+    /// Returns one sanitized request dump: headers, body, and timing
+    /// breakdown (`admin:dumps` scope).
+    pub async fn error_dump(
+        id: web::Path<String>,
+        spool: Option<web::Data<DumpSpool>>,
+    ) -> Result<HttpResponse, AppError> {
+        let spool = dump_spool(spool)?;
+        let id = id.into_inner();
+        let dump = web::block(move || spool.get(&id))
+            .await
+            .map_err(|e| AppError::internal(format!("reading error dump failed: {}", e)))??
+            .ok_or_else(|| AppError::not_found("error dump not found"))?;
+        Ok(HttpResponse::Ok().json(dump))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -68,6 +1706,34 @@ mod tests {
         assert_eq!(response.status(), 200);
     }
 
+    #[actix_web::test]
+    async fn test_main_server_ready() {
+        let store: std::sync::Arc<dyn crate::state::KeyValueStore> =
+            std::sync::Arc::new(crate::state::InMemoryStore::new());
+        let store = actix_web::web::Data::from(store);
+        let context = || crate::context::RequestContext::new(&Default::default(), std::time::Duration::from_secs(1));
+        let response = main_server::ready(store.clone(), None, None, None, context()).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let supervisor = std::sync::Arc::new(crate::supervisor::Supervisor::new(crate::supervisor::RestartPolicy {
+            max_restarts: 0,
+            ..Default::default()
+        }));
+        supervisor.spawn("scheduler", || async { Err(crate::error::AppError::internal("boom")) });
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        let err = main_server::ready(store.clone(), Some(actix_web::web::Data::from(supervisor)), None, None, context())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, crate::error::AppError::Unavailable { .. }));
+        assert!(err.to_string().contains("scheduler"));
+
+        let warmup = actix_web::web::Data::new(crate::warmup::WarmupStatus::pending());
+        let err = main_server::ready(store.clone(), None, Some(warmup), None, context()).await.unwrap_err();
+        assert!(err.to_string().contains("cache warm-up in progress"), "{}", err);
+        let warmup = actix_web::web::Data::new(crate::warmup::WarmupStatus::skipped());
+        assert!(main_server::ready(store, None, Some(warmup), None, context()).await.is_ok());
+    }
+
     #[actix_web::test]
     async fn test_app_server_root() {
         let response = app_server::root().await.unwrap();
@@ -82,7 +1748,7 @@ mod tests {
 
     #[actix_web::test]
     async fn test_app_server_private_route() {
-        let response = app_server::private_route().await.unwrap();
+        let response = app_server::private_route(None).await.unwrap();
         assert_eq!(response.status(), 200);
     }
 } 
\ No newline at end of file
@@ -0,0 +1,181 @@
+use log::{error, warn};
+
+use crate::config::{AppEnv, Config};
+use crate::error::{AppError, AppResult};
+
+/// Values that must never be used as secrets outside development
+pub const KNOWN_DEFAULT_SECRETS: &[&str] = &[
+    "changeme",
+    "change-me",
+    "change_me",
+    "secret",
+    "password",
+    "default",
+    "example",
+    "test",
+    "admin",
+    "12345678",
+];
+
+/// A failed production-hardening check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    /// Short identifier of the check
+    pub check: &'static str,
+    /// What is wrong and how to fix it
+    pub message: String,
+}
+
+/// Runs every hardening check against the configuration
+///
+/// The checks are environment-independent; [`enforce`] decides what to do
+/// with the findings.
+pub fn audit(config: &Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    if config.cors_allowed_origins.iter().any(|origin| origin == "*") {
+        findings.push(Finding {
+            check: "cors",
+            message: "CORS allows any origin; set CORS_ALLOWED_ORIGINS to the trusted origins".to_string(),
+        });
+    }
+
+    if !config.cookie_secure {
+        findings.push(Finding {
+            check: "cookies",
+            message: "cookies are issued without the Secure attribute; unset COOKIE_SECURE=false".to_string(),
+        });
+    }
+
+    if config.debug_endpoints {
+        findings.push(Finding {
+            check: "debug_endpoints",
+            message: "debug endpoints are enabled; unset ENABLE_DEBUG_ENDPOINTS".to_string(),
+        });
+    }
+
+    if config.stub_dependencies {
+        findings.push(Finding {
+            check: "stub_dependencies",
+            message: "dependencies are replaced by in-process fakes; unset STUB_DEPENDENCIES".to_string(),
+        });
+    }
+
+    for (name, value) in config.secrets() {
+        if is_default_secret(value) {
+            findings.push(Finding {
+                check: "default_secret",
+                message: format!("{} uses a well-known default value; generate a random secret", name),
+            });
+        }
+    }
+
+    findings
+}
+
+/// Returns whether `value` is one of the well-known default secrets
+pub fn is_default_secret(value: &str) -> bool {
+    let normalized = value.trim().to_ascii_lowercase();
+    KNOWN_DEFAULT_SECRETS.contains(&normalized.as_str())
+}
+
+/// Applies the hardening checks at startup
+///
+/// Outside production the findings are only logged. In production any
+/// finding refuses startup unless `ALLOW_INSECURE_PRODUCTION=true`, in which
+/// case every finding is logged at error level instead.
+///
+/// # Errors
+/// Returns a configuration error listing every finding when startup is refused
+pub fn enforce(config: &Config) -> AppResult<()> {
+    let findings = audit(config);
+    if findings.is_empty() {
+        return Ok(());
+    }
+
+    if config.app_env != AppEnv::Production {
+        for finding in &findings {
+            warn!("Hardening check '{}' would fail in production: {}", finding.check, finding.message);
+        }
+        return Ok(());
+    }
+
+    if config.allow_insecure_production {
+        for finding in &findings {
+            error!(
+                "INSECURE PRODUCTION DEPLOYMENT (ALLOW_INSECURE_PRODUCTION override active) - {}: {}",
+                finding.check, finding.message
+            );
+        }
+        return Ok(());
+    }
+
+    let summary = findings
+        .iter()
+        .map(|finding| format!("[{}] {}", finding.check, finding.message))
+        .collect::<Vec<_>>()
+        .join("; ");
+    Err(AppError::config(format!(
+        "refusing to start with APP_ENV=production: {} (set ALLOW_INSECURE_PRODUCTION=true to override)",
+        summary
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn production() -> Config {
+        Config {
+            app_env: AppEnv::Production,
+            cors_allowed_origins: vec!["https://app.example.com".to_string()],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_hardened_production_config_passes() {
+        assert!(audit(&production()).is_empty());
+        assert!(enforce(&production()).is_ok());
+    }
+
+    #[test]
+    fn test_each_check_reports_a_finding() {
+        let config = Config {
+            cors_allowed_origins: vec!["*".to_string()],
+            cookie_secure: false,
+            debug_endpoints: true,
+            stub_dependencies: true,
+            webhook_github_secret: Some("ChangeMe".to_string()),
+            ..production()
+        };
+
+        let checks: Vec<_> = audit(&config).into_iter().map(|f| f.check).collect();
+        assert_eq!(checks, vec!["cors", "cookies", "debug_endpoints", "stub_dependencies", "default_secret"]);
+    }
+
+    #[test]
+    fn test_production_refuses_unless_overridden() {
+        let config = Config {
+            debug_endpoints: true,
+            ..production()
+        };
+        let err = enforce(&config).unwrap_err();
+        assert!(err.to_string().contains("debug_endpoints"));
+
+        let overridden = Config {
+            allow_insecure_production: true,
+            ..config
+        };
+        assert!(enforce(&overridden).is_ok());
+    }
+
+    #[test]
+    fn test_development_only_warns() {
+        let config = Config {
+            debug_endpoints: true,
+            ..Config::default()
+        };
+        assert!(enforce(&config).is_ok());
+    }
+}
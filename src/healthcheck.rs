@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use crate::error::{AppError, AppResult};
+
+/// Default request timeout of the `healthcheck` subcommand
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Options of the `healthcheck` subcommand
+#[derive(Debug, Clone, PartialEq, clap::Parser)]
+pub struct HealthcheckOptions {
+    /// URL to probe [default: the main server's `/ready` on localhost, on `PORT`]
+    #[arg(long, value_name = "URL")]
+    pub url: Option<String>,
+    /// Seconds to wait for the answer
+    #[arg(long, value_name = "SECS", value_parser = parse_timeout, default_value = "3")]
+    pub timeout: Duration,
+}
+
+impl HealthcheckOptions {
+    /// URL to probe
+    ///
+    /// Defaults to the main server's `/ready` on localhost, using `PORT`
+    /// when set, so `simple-api-demo healthcheck` works unchanged as a
+    /// Docker `HEALTHCHECK`.
+    pub fn url(&self) -> String {
+        self.url.clone().unwrap_or_else(|| {
+            let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+            format!("http://127.0.0.1:{}/ready", port)
+        })
+    }
+}
+
+/// Parses a positive number of seconds, fractions allowed
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    let secs: f64 = value.parse().map_err(|_| "must be a number of seconds".to_string())?;
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+        .ok_or_else(|| "must be positive".to_string())
+}
+
+/// Requests `options.url` and succeeds only on a 2xx response within the timeout
+///
+/// # Errors
+/// Returns an unavailable error describing the failed request or status
+pub async fn check(options: &HealthcheckOptions) -> AppResult<()> {
+    let client = reqwest::Client::builder()
+        .timeout(options.timeout)
+        .build()
+        .map_err(|e| AppError::internal(format!("Failed to build HTTP client: {}", e)))?;
+    let url = options.url();
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| AppError::unavailable(format!("{} is unreachable: {}", url, e)))?;
+    if !response.status().is_success() {
+        return Err(AppError::unavailable(format!("{} answered {}", url, response.status())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use clap::Parser;
+
+    fn parse(args: &[&str]) -> Result<HealthcheckOptions, clap::Error> {
+        HealthcheckOptions::try_parse_from(std::iter::once("healthcheck").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = parse(&["--url", "http://db:8080/ready", "--timeout", "0.5"]).unwrap();
+        assert_eq!(options.url(), "http://db:8080/ready");
+        assert_eq!(options.timeout, Duration::from_millis(500));
+        assert_eq!(parse(&[]).unwrap().timeout, DEFAULT_TIMEOUT);
+
+        assert!(parse(&["--url"]).is_err());
+        assert!(parse(&["--timeout", "0"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_check_follows_status() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/ready", web::get().to(HttpResponse::Ok))
+                .route("/down", web::get().to(HttpResponse::ServiceUnavailable))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let handle = server.run();
+        actix_web::rt::spawn(handle);
+
+        let options = |path: &str| HealthcheckOptions {
+            url: Some(format!("http://{}{}", addr, path)),
+            timeout: Duration::from_secs(2),
+        };
+        assert!(check(&options("/ready")).await.is_ok());
+        assert!(matches!(check(&options("/down")).await, Err(AppError::Unavailable { .. })));
+
+        // Nothing listens on port 9 (discard) locally
+        let unreachable = HealthcheckOptions {
+            url: Some("http://127.0.0.1:9/ready".to_string()),
+            timeout: Duration::from_millis(500),
+        };
+        assert!(check(&unreachable).await.is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::analytics::OperationalMetrics;
+
+/// Media type of the OpenMetrics text exposition
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Upper bounds, in seconds, of the request latency buckets
+pub const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Trace of one observation, linking a bucket to a representative request
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Exemplar {
+    pub trace_id: String,
+    /// Observed latency in seconds
+    pub value: f64,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// One bucket of a [`HistogramSnapshot`]; counts are cumulative
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BucketSnapshot {
+    /// Upper bound in seconds, `None` for `+Inf`
+    pub le: Option<f64>,
+    pub count: u64,
+    /// Most recent traced observation that fell in this bucket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exemplar: Option<Exemplar>,
+}
+
+/// Point-in-time copy of a [`LatencyHistogram`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<BucketSnapshot>,
+    /// Sum of all observations in seconds
+    pub sum: f64,
+    pub count: u64,
+}
+
+#[derive(Debug)]
+struct Recording {
+    /// Per-bucket, non-cumulative counts; the last one is `+Inf`
+    counts: Vec<u64>,
+    exemplars: Vec<Option<Exemplar>>,
+    sum: f64,
+}
+
+/// Request latency histogram keeping an exemplar per bucket
+///
+/// Each bucket remembers the latest observation that carried a trace id, so
+/// an operator looking at a slow bucket can jump straight to a trace of a
+/// request that landed there.
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    recording: Mutex<Recording>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    /// Creates an empty histogram over [`LATENCY_BUCKETS`]
+    pub fn new() -> Self {
+        Self {
+            recording: Mutex::new(Recording {
+                counts: vec![0; LATENCY_BUCKETS.len() + 1],
+                exemplars: vec![None; LATENCY_BUCKETS.len() + 1],
+                sum: 0.0,
+            }),
+        }
+    }
+
+    /// Records one request; `trace_id` becomes the bucket's exemplar
+    pub fn observe(&self, latency: Duration, trace_id: Option<&str>) {
+        let value = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| value <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        let Ok(mut recording) = self.recording.lock() else {
+            return;
+        };
+        recording.counts[bucket] += 1;
+        recording.sum += value;
+        if let Some(trace_id) = trace_id {
+            recording.exemplars[bucket] = Some(Exemplar {
+                trace_id: trace_id.to_string(),
+                value,
+                timestamp: Utc::now(),
+            });
+        }
+    }
+
+    /// Returns the cumulative bucket counts with their exemplars
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let Ok(recording) = self.recording.lock() else {
+            return HistogramSnapshot {
+                buckets: Vec::new(),
+                sum: 0.0,
+                count: 0,
+            };
+        };
+        let mut cumulative = 0;
+        let buckets = recording
+            .counts
+            .iter()
+            .zip(&recording.exemplars)
+            .enumerate()
+            .map(|(index, (count, exemplar))| {
+                cumulative += count;
+                BucketSnapshot {
+                    le: LATENCY_BUCKETS.get(index).copied(),
+                    count: cumulative,
+                    exemplar: exemplar.clone(),
+                }
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            sum: recording.sum,
+            count: cumulative,
+        }
+    }
+}
+
+/// Returns whether an `Accept` header asks for the OpenMetrics text format
+pub fn wants_openmetrics(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| accept.contains("application/openmetrics-text"))
+}
+
+/// Renders the request counters and latency histogram in the OpenMetrics
+/// text format, with bucket exemplars
+///
+/// `labels` (e.g. `region="eu-west-1"`) are attached to every sample.
+pub fn render_openmetrics(operational: &OperationalMetrics, latency: &HistogramSnapshot, labels: &str) -> String {
+    let mut out = String::new();
+    let (set, prefix) = if labels.is_empty() {
+        (String::new(), String::new())
+    } else {
+        (format!("{{{}}}", labels), format!("{},", labels))
+    };
+    let counters = [
+        ("http_requests", "Requests handled", operational.requests_total),
+        ("http_server_errors", "Requests answered with a 5xx status", operational.server_errors),
+        (
+            "analytics_excluded_requests",
+            "Requests kept out of analytics because of an opt-out",
+            operational.analytics_excluded,
+        ),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# TYPE {} counter\n# HELP {} {}\n{}_total{} {}", name, name, help, name, set, value);
+    }
+
+    let name = "http_request_duration_seconds";
+    let _ = writeln!(out, "# TYPE {} histogram\n# UNIT {} seconds", name, name);
+    let _ = writeln!(out, "# HELP {} Request latency, from routing to the response", name);
+    for bucket in &latency.buckets {
+        let le = bucket.le.map_or_else(|| "+Inf".to_string(), |le| format!("{}", le));
+        let _ = write!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, prefix, le, bucket.count);
+        if let Some(exemplar) = &bucket.exemplar {
+            let _ = write!(
+                out,
+                " # {{trace_id=\"{}\"}} {} {:.3}",
+                exemplar.trace_id,
+                exemplar.value,
+                exemplar.timestamp.timestamp_millis() as f64 / 1000.0
+            );
+        }
+        out.push('\n');
+    }
+    let _ = writeln!(out, "{}_sum{} {}\n{}_count{} {}", name, set, latency.sum, name, set, latency.count);
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observations_fill_cumulative_buckets() {
+        let histogram = LatencyHistogram::new();
+        histogram.observe(Duration::from_millis(3), Some("aaaa"));
+        histogram.observe(Duration::from_millis(70), None);
+        histogram.observe(Duration::from_millis(80), Some("bbbb"));
+        histogram.observe(Duration::from_secs(30), Some("cccc"));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.buckets.len(), LATENCY_BUCKETS.len() + 1);
+        let bucket = |le: Option<f64>| snapshot.buckets.iter().find(|bucket| bucket.le == le).unwrap();
+        assert_eq!(bucket(Some(0.005)).count, 1);
+        assert_eq!(bucket(Some(0.05)).count, 1);
+        assert_eq!(bucket(Some(0.1)).count, 3);
+        assert_eq!(bucket(Some(0.1)).exemplar.as_ref().unwrap().trace_id, "bbbb");
+        assert!(bucket(Some(0.25)).exemplar.is_none());
+        assert_eq!((bucket(None).count, bucket(None).exemplar.as_ref().unwrap().trace_id.as_str()), (4, "cccc"));
+        assert!((snapshot.sum - 30.153).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_render_openmetrics() {
+        let histogram = LatencyHistogram::new();
+        histogram.observe(Duration::from_millis(40), Some("4bf92f3577b34da6a3ce929d0e0e4736"));
+        let operational = OperationalMetrics {
+            requests_total: 1,
+            server_errors: 0,
+            analytics_excluded: 0,
+        };
+        let text = render_openmetrics(&operational, &histogram.snapshot(), "");
+
+        assert!(text.contains("# TYPE http_requests counter\n"));
+        assert!(text.contains("http_requests_total 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.025\"} 0\n"));
+        let line = text
+            .lines()
+            .find(|line| line.starts_with("http_request_duration_seconds_bucket{le=\"0.05\"}"))
+            .unwrap();
+        assert!(
+            line.starts_with("http_request_duration_seconds_bucket{le=\"0.05\"} 1 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.04 "),
+            "{}",
+            line
+        );
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_count 1\n"));
+        assert!(text.ends_with("# EOF\n"));
+
+        let text = render_openmetrics(&operational, &histogram.snapshot(), "region=\"eu-west-1\"");
+        assert!(text.contains("http_requests_total{region=\"eu-west-1\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{region=\"eu-west-1\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_count{region=\"eu-west-1\"} 1\n"));
+    }
+
+    #[test]
+    fn test_wants_openmetrics() {
+        assert!(wants_openmetrics(Some("application/openmetrics-text; version=1.0.0,text/plain;q=0.5")));
+        assert!(!wants_openmetrics(Some("application/json")));
+        assert!(!wants_openmetrics(None));
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::config::{AppEnv, Config};
+use crate::error::{AppError, AppResult};
+use crate::hardening;
+use crate::secrets;
+use crate::state::StateMode;
+
+/// Default file written by `init`
+pub const DEFAULT_OUTPUT: &str = "simple-api-demo.env";
+
+/// Default Redis URL proposed for distributed state
+const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379";
+
+/// How users authenticate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    /// Password login issuing JWT access tokens
+    Jwt,
+    /// Like [`AuthMode::Jwt`], with TOTP two-factor authentication required
+    /// for administrators
+    JwtMfa,
+}
+
+impl FromStr for AuthMode {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "jwt" => Ok(AuthMode::Jwt),
+            "jwt-mfa" | "mfa" => Ok(AuthMode::JwtMfa),
+            other => Err(AppError::config(format!("auth mode must be jwt or jwt-mfa, got: {}", other))),
+        }
+    }
+}
+
+impl fmt::Display for AuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthMode::Jwt => write!(f, "jwt"),
+            AuthMode::JwtMfa => write!(f, "jwt-mfa"),
+        }
+    }
+}
+
+/// Choices made on the command line; unset ones are asked for interactively
+#[derive(Debug, Clone, Default, PartialEq, clap::Parser)]
+pub struct InitOptions {
+    /// Environment: production, staging or development
+    #[arg(long = "env", value_name = "ENV")]
+    pub app_env: Option<AppEnv>,
+    /// Whether clients reach the service over HTTPS (terminated by a proxy): on or off
+    #[arg(long, value_name = "ON|OFF", value_parser = parse_switch)]
+    pub tls: Option<bool>,
+    /// Authentication: jwt or jwt-mfa
+    #[arg(long, value_name = "MODE")]
+    pub auth: Option<AuthMode>,
+    /// Storage backend: local or redis
+    #[arg(long = "storage", value_name = "BACKEND")]
+    pub state_mode: Option<StateMode>,
+    /// Redis URL when the storage backend is redis
+    #[arg(long, value_name = "URL")]
+    pub redis_url: Option<String>,
+    /// Allowed CORS origin (repeatable)
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    pub cors_origins: Vec<String>,
+    /// Configuration file to write
+    #[arg(long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+    /// Also write a `.env` file with the same settings
+    #[arg(long, value_name = "FILE", num_args = 0..=1, default_missing_value = ".env")]
+    pub env_file: Option<PathBuf>,
+    /// Replace existing files
+    #[arg(long)]
+    pub force: bool,
+    /// Never prompt; unset choices take their defaults
+    #[arg(long, short = 'y')]
+    pub non_interactive: bool,
+}
+
+impl InitOptions {
+    /// Fills in unset choices, asking on `output` and reading answers from
+    /// `input` unless non-interactive
+    ///
+    /// Empty answers take the default shown in brackets.
+    ///
+    /// # Errors
+    /// Returns a config error for invalid answers or I/O failures
+    pub fn resolve(&self, input: &mut impl BufRead, output: &mut impl Write) -> AppResult<Choices> {
+        let mut ask = |question: &str, default: &str| -> AppResult<String> {
+            if self.non_interactive {
+                return Ok(default.to_string());
+            }
+            write!(output, "{} [{}]: ", question, default)
+                .and_then(|_| output.flush())
+                .map_err(|e| AppError::config(format!("Cannot prompt: {}", e)))?;
+            let mut answer = String::new();
+            input
+                .read_line(&mut answer)
+                .map_err(|e| AppError::config(format!("Cannot read answer: {}", e)))?;
+            let answer = answer.trim();
+            Ok(if answer.is_empty() { default } else { answer }.to_string())
+        };
+
+        let app_env = match self.app_env {
+            Some(app_env) => app_env,
+            None => ask("Environment (development/staging/production)", "development")?.parse()?,
+        };
+        let tls = match self.tls {
+            Some(tls) => tls,
+            None => {
+                let default = if app_env == AppEnv::Development { "off" } else { "on" };
+                parse_switch(&ask("Served over HTTPS (on/off)", default)?)?
+            }
+        };
+        let auth = match self.auth {
+            Some(auth) => auth,
+            None => ask("Authentication (jwt/jwt-mfa)", "jwt")?.parse()?,
+        };
+        let state_mode = match self.state_mode {
+            Some(state_mode) => state_mode,
+            None => ask("Storage backend (local/redis)", "local")?.parse()?,
+        };
+        let redis_url = match (&self.redis_url, state_mode) {
+            (Some(url), _) => Some(url.clone()),
+            (None, StateMode::Distributed) => Some(ask("Redis URL", DEFAULT_REDIS_URL)?),
+            (None, StateMode::Local) => None,
+        };
+        let cors_origins = if !self.cors_origins.is_empty() || app_env == AppEnv::Development {
+            self.cors_origins.clone()
+        } else {
+            ask("Allowed CORS origins (comma-separated)", "")?
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+
+        Ok(Choices {
+            app_env,
+            tls,
+            auth,
+            state_mode,
+            redis_url,
+            cors_origins,
+        })
+    }
+}
+
+/// Resolved feature choices
+#[derive(Debug, Clone, PartialEq)]
+pub struct Choices {
+    pub app_env: AppEnv,
+    pub tls: bool,
+    pub auth: AuthMode,
+    pub state_mode: StateMode,
+    pub redis_url: Option<String>,
+    pub cors_origins: Vec<String>,
+}
+
+/// One generated variable with the comment explaining it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    pub name: &'static str,
+    pub value: String,
+    pub comment: &'static str,
+}
+
+impl Choices {
+    /// Returns the variables implementing the choices, with fresh secrets
+    pub fn settings(&self) -> Vec<Setting> {
+        let mut settings = Vec::new();
+        let mut set = |name, value: String, comment| settings.push(Setting { name, value, comment });
+
+        set("APP_ENV", self.app_env.to_string(), "Deployment environment; production enforces the hardening checks");
+        set("PORT", "8080".to_string(), "Main server port (hello world, /health, /ready)");
+        set("PORT_APP", "4242".to_string(), "Application server port");
+        set("BIND_ADDRESS", "0.0.0.0".to_string(), "Address both servers listen on");
+        set(
+            "COOKIE_SECURE",
+            self.tls.to_string(),
+            "HTTPS is terminated by the reverse proxy (see nginx.conf); cookies are Secure when it is on",
+        );
+        let origins = if self.cors_origins.is_empty() {
+            "*".to_string()
+        } else {
+            self.cors_origins.join(",")
+        };
+        set("CORS_ALLOWED_ORIGINS", origins, "Origins allowed to call the API from a browser");
+        set(
+            "STATE_MODE",
+            self.state_mode.to_string(),
+            "local keeps state in memory; distributed shares it through Redis (build with --features redis)",
+        );
+        if let Some(url) = &self.redis_url {
+            set("REDIS_URL", url.clone(), "Redis instance holding shared state");
+        }
+        set("JWT_SECRET", secrets::generate_secret(), "HS256 signing key for access tokens (generated)");
+        set(
+            "DATA_ENCRYPTION_KEY",
+            secrets::generate_secret(),
+            "Key encrypting secrets at rest, such as TOTP seeds (generated)",
+        );
+        if self.auth == AuthMode::JwtMfa {
+            set("MFA_REQUIRED_ROLES", "admin".to_string(), "Roles that must enroll in TOTP two-factor authentication");
+        }
+        settings
+    }
+}
+
+/// Checks that `settings` load and pass the startup checks
+///
+/// Production settings must pass every hardening check, since the server
+/// would refuse to start otherwise.
+///
+/// # Errors
+/// Returns the configuration error the server would report
+pub fn validate(settings: &[Setting]) -> AppResult<Config> {
+    let vars: HashMap<&str, &str> = settings
+        .iter()
+        .map(|setting| (setting.name, setting.value.as_str()))
+        .collect();
+    let config = Config::from_lookup(|name| vars.get(name).map(|value| value.to_string()))?;
+    secrets::validate(&config)?;
+    if config.app_env == AppEnv::Production {
+        let findings = hardening::audit(&config);
+        if !findings.is_empty() {
+            let summary: Vec<String> = findings.iter().map(|finding| finding.message.clone()).collect();
+            return Err(AppError::config(format!(
+                "production settings would be refused at startup: {}",
+                summary.join("; ")
+            )));
+        }
+    }
+    Ok(config)
+}
+
+/// Renders the commented configuration file
+pub fn render_config(choices: &Choices, settings: &[Setting], path: &Path) -> String {
+    let mut output = format!(
+        "# simple-api-demo configuration generated by `simple-api-demo init`\n\
+         # Features: environment={}, https={}, auth={}, storage={}\n\
+         # Load with `set -a; . {}; set +a` or docker compose `env_file:`.\n\
+         # Contains secrets: keep it out of version control.\n",
+        choices.app_env,
+        if choices.tls { "on" } else { "off" },
+        choices.auth,
+        choices.state_mode,
+        path.display(),
+    );
+    for setting in settings {
+        output.push_str(&format!("\n# {}\n{}={}\n", setting.comment, setting.name, setting.value));
+    }
+    output
+}
+
+/// Renders the same settings as a plain `.env` file
+pub fn render_env(settings: &[Setting]) -> String {
+    settings
+        .iter()
+        .map(|setting| format!("{}={}\n", setting.name, setting.value))
+        .collect()
+}
+
+/// Runs the wizard: resolves choices, validates them and writes the files
+///
+/// Returns the paths written.
+///
+/// # Errors
+/// Returns a config error for invalid choices, settings failing validation,
+/// or files that exist without `--force`
+pub fn run(options: &InitOptions, input: &mut impl BufRead, output: &mut impl Write) -> AppResult<Vec<PathBuf>> {
+    let choices = options.resolve(input, output)?;
+    let settings = choices.settings();
+    validate(&settings)?;
+
+    let config_path = options.output.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_OUTPUT));
+    let contents = render_config(&choices, &settings, &config_path);
+    let mut files = vec![(config_path, contents)];
+    if let Some(path) = &options.env_file {
+        files.push((path.clone(), render_env(&settings)));
+    }
+    // Check every target first so a refusal leaves nothing half-written
+    if !options.force {
+        if let Some((path, _)) = files.iter().find(|(path, _)| path.exists()) {
+            return Err(AppError::config(format!(
+                "{} already exists; pass --force to replace it",
+                path.display()
+            )));
+        }
+    }
+    for (path, contents) in &files {
+        replace_private_file(path, contents)?;
+    }
+    Ok(files.into_iter().map(|(path, _)| path).collect())
+}
+
+fn replace_private_file(path: &Path, contents: &str) -> AppResult<()> {
+    if path.exists() {
+        std::fs::remove_file(path)
+            .map_err(|e| AppError::config(format!("Cannot replace {}: {}", path.display(), e)))?;
+    }
+    secrets::write_private_file(path, contents)
+}
+
+fn parse_switch(value: &str) -> AppResult<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        other => Err(AppError::config(format!("expected on or off, got: {}", other))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Parser;
+
+    fn parse(args: &[&str]) -> Result<InitOptions, clap::Error> {
+        InitOptions::try_parse_from(std::iter::once("init").chain(args.iter().copied()))
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}", uuid::Uuid::new_v4(), name))
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let options = parse(&[
+            "--env", "prod", "--tls", "on", "--auth", "jwt-mfa", "--storage", "redis", "--env-file", "-y",
+        ])
+        .unwrap();
+        assert_eq!(options.app_env, Some(AppEnv::Production));
+        assert_eq!(options.tls, Some(true));
+        assert_eq!(options.auth, Some(AuthMode::JwtMfa));
+        assert_eq!(options.state_mode, Some(StateMode::Distributed));
+        assert_eq!(options.env_file, Some(PathBuf::from(".env")));
+        assert!(options.non_interactive);
+
+        assert!(parse(&["--tls", "maybe"]).is_err());
+        assert!(parse(&["--wat"]).is_err());
+    }
+
+    #[test]
+    fn test_interactive_answers_and_defaults() {
+        let options = parse(&["--auth", "jwt"]).unwrap();
+        let mut input = "production\n\nredis\n\nhttps://app.example.com, https://admin.example.com\n".as_bytes();
+        let mut prompts = Vec::new();
+        let choices = options.resolve(&mut input, &mut prompts).unwrap();
+
+        assert_eq!(choices.app_env, AppEnv::Production);
+        assert!(choices.tls, "HTTPS defaults to on outside development");
+        assert_eq!(choices.auth, AuthMode::Jwt);
+        assert_eq!(choices.redis_url.as_deref(), Some(DEFAULT_REDIS_URL));
+        assert_eq!(choices.cors_origins, vec!["https://app.example.com", "https://admin.example.com"]);
+        let prompts = String::from_utf8(prompts).unwrap();
+        assert!(prompts.contains("Storage backend (local/redis) [local]: "));
+        assert!(!prompts.contains("Authentication"));
+    }
+
+    #[test]
+    fn test_generated_settings_validate() {
+        let options = parse(&["-y", "--auth", "jwt-mfa"]).unwrap();
+        let choices = options.resolve(&mut "".as_bytes(), &mut Vec::new()).unwrap();
+        let settings = choices.settings();
+        let config = validate(&settings).unwrap();
+        assert_eq!(config.mfa_required_roles, vec!["admin"]);
+        assert!(!config.cookie_secure);
+
+        // Production refuses wide-open CORS, as the server would at startup
+        let options = parse(&["-y", "--env", "production"]).unwrap();
+        let choices = options.resolve(&mut "".as_bytes(), &mut Vec::new()).unwrap();
+        assert!(validate(&choices.settings()).is_err());
+    }
+
+    #[test]
+    fn test_run_writes_config_and_env_file() {
+        let (config_path, env_path) = (temp_path("demo.env"), temp_path(".env"));
+        let options = InitOptions {
+            output: Some(config_path.clone()),
+            env_file: Some(env_path.clone()),
+            non_interactive: true,
+            ..InitOptions::default()
+        };
+        let written = run(&options, &mut "".as_bytes(), &mut Vec::new()).unwrap();
+        assert_eq!(written, vec![config_path.clone(), env_path.clone()]);
+
+        let config = std::fs::read_to_string(&config_path).unwrap();
+        assert!(config.contains("# HS256 signing key for access tokens (generated)\nJWT_SECRET="));
+        let env = std::fs::read_to_string(&env_path).unwrap();
+        assert!(!env.contains('#'));
+        let secret = |text: &str| text.lines().find(|line| line.starts_with("JWT_SECRET=")).unwrap().to_string();
+        assert_eq!(secret(&config), secret(&env));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&env_path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // Existing files are kept unless --force is given
+        assert!(run(&options, &mut "".as_bytes(), &mut Vec::new()).is_err());
+        let forced = InitOptions { force: true, ..options };
+        run(&forced, &mut "".as_bytes(), &mut Vec::new()).unwrap();
+        assert_ne!(secret(&std::fs::read_to_string(&config_path).unwrap()), secret(&config));
+
+        std::fs::remove_file(config_path).unwrap();
+        std::fs::remove_file(env_path).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::auth::{ApiKeyService, SessionRegistry};
+use crate::config::Config;
+use crate::error::AppResult;
+use crate::events::CloudEvent;
+use crate::supervisor::Supervisor;
+use crate::users::UserRepository;
+
+/// Margin before a timestamp counts as lying in the future, leaving room for
+/// clock skew between replicas
+pub const FUTURE_TOLERANCE: Duration = Duration::from_secs(300);
+
+/// Class of invariant an [`IntegrityIssue`] breaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// A key points to a record that is missing or belongs to someone else
+    OrphanedReference,
+    /// A timestamp in the future or out of order with a related one
+    InvalidTimestamp,
+    /// The same id listed twice, or a unique value claimed by two records
+    DuplicateKey,
+}
+
+/// An inconsistency found in the storage layer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IntegrityIssue {
+    pub kind: IssueKind,
+    /// Store key holding the inconsistency, e.g. `index` or `user:{id}`
+    pub key: String,
+    pub detail: String,
+    /// Whether the issue can be fixed without losing data
+    pub repairable: bool,
+    /// Whether the run that found the issue fixed it
+    pub repaired: bool,
+}
+
+impl IntegrityIssue {
+    /// An issue that is only reported
+    pub fn new(kind: IssueKind, key: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            kind,
+            key: key.into(),
+            detail: detail.into(),
+            repairable: false,
+            repaired: false,
+        }
+    }
+
+    /// An issue safe to fix automatically, `repaired` telling whether it was
+    pub fn repairable(kind: IssueKind, key: impl Into<String>, detail: impl Into<String>, repaired: bool) -> Self {
+        Self {
+            repairable: true,
+            repaired,
+            ..Self::new(kind, key, detail)
+        }
+    }
+}
+
+/// Outcome of an integrity check, as answered by `GET /admin/integrity`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IntegrityReport {
+    pub checked_at: DateTime<Utc>,
+    /// Whether safe repairs were applied
+    pub repair: bool,
+    pub users_checked: usize,
+    /// Number of issues fixed by this run
+    pub repaired: usize,
+    pub issues: Vec<IntegrityIssue>,
+}
+
+/// Validates the invariants linking users, their audit trails, sessions and
+/// API keys
+///
+/// Records are reached through the user index, as the store cannot list
+/// keys. Ids listed twice or pointing nowhere, missing email reservations and
+/// sessions or keys indexed under the wrong user are repairable: the fix only
+/// drops a dangling reference or restores one derived from the record.
+/// Conflicting email reservations, out-of-order or future timestamps and
+/// events of another user are reported for a human to look at. Each run is
+/// logged as a `com.simple-api-demo.data.integrity_checked` audit event.
+pub struct IntegrityChecker {
+    repository: UserRepository,
+    sessions: Arc<SessionRegistry>,
+    api_keys: Arc<ApiKeyService>,
+    auto_repair: bool,
+    last: RwLock<Option<IntegrityReport>>,
+}
+
+impl IntegrityChecker {
+    /// Creates the checker
+    ///
+    /// # Arguments
+    /// * `repository` - User storage, whose index drives the check
+    /// * `sessions` - Sessions indexed per user
+    /// * `api_keys` - API keys indexed per user
+    /// * `auto_repair` - Whether scheduled runs apply the safe repairs
+    pub fn new(
+        repository: UserRepository,
+        sessions: Arc<SessionRegistry>,
+        api_keys: Arc<ApiKeyService>,
+        auto_repair: bool,
+    ) -> Self {
+        Self {
+            repository,
+            sessions,
+            api_keys,
+            auto_repair,
+            last: RwLock::new(None),
+        }
+    }
+
+    /// Builds the checker from `INTEGRITY_AUTO_REPAIR`
+    pub fn from_config(
+        config: &Config,
+        repository: UserRepository,
+        sessions: Arc<SessionRegistry>,
+        api_keys: Arc<ApiKeyService>,
+    ) -> Self {
+        Self::new(repository, sessions, api_keys, config.integrity_auto_repair)
+    }
+
+    /// Checks every record reachable from the user index, applying the safe
+    /// repairs when `repair` is set
+    pub fn run(&self, repair: bool) -> AppResult<IntegrityReport> {
+        let checked_at = Utc::now();
+        let horizon = checked_at + chrono::Duration::seconds(FUTURE_TOLERANCE.as_secs() as i64);
+        let (ids, mut issues) = self.repository.check_index(repair)?;
+        for user_id in &ids {
+            issues.extend(self.repository.check_user(user_id, horizon, repair)?);
+            issues.extend(self.sessions.check_integrity(user_id, horizon, repair)?);
+            issues.extend(self.api_keys.check_integrity(user_id, horizon, repair)?);
+        }
+        let report = IntegrityReport {
+            checked_at,
+            repair,
+            users_checked: ids.len(),
+            repaired: issues.iter().filter(|issue| issue.repaired).count(),
+            issues,
+        };
+
+        if !report.issues.is_empty() {
+            warn!(
+                "Integrity check found {} issue(s), {} repaired",
+                report.issues.len(),
+                report.repaired
+            );
+        }
+        let event = CloudEvent::new(
+            "com.simple-api-demo.data.integrity_checked",
+            json!({
+                "repair": report.repair,
+                "users_checked": report.users_checked,
+                "issues": report.issues.len(),
+                "repaired": report.repaired,
+            }),
+        );
+        info!(target: "audit", "{}", event.to_json()?);
+        if let Ok(mut last) = self.last.write() {
+            *last = Some(report.clone());
+        }
+        Ok(report)
+    }
+
+    /// Returns the report of the latest run, if any
+    pub fn last_report(&self) -> Option<IntegrityReport> {
+        self.last.read().ok().and_then(|last| last.clone())
+    }
+
+    /// Runs the check every `every` under `supervisor`, repairing when
+    /// `INTEGRITY_AUTO_REPAIR` is set
+    pub fn spawn_scheduler(checker: Arc<Self>, every: Duration, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("integrity", move || {
+            let checker = checker.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(every);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = checker.run(checker.auto_repair) {
+                        error!("Integrity check failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenDenylist;
+    use crate::state::{InMemoryStore, KeyValueStore};
+    use crate::users::UserEvent;
+
+    struct Fixture {
+        checker: IntegrityChecker,
+        users: Arc<InMemoryStore>,
+        sessions: Arc<InMemoryStore>,
+        repository: UserRepository,
+        registry: Arc<SessionRegistry>,
+    }
+
+    fn fixture() -> Fixture {
+        let users = Arc::new(InMemoryStore::new());
+        let sessions = Arc::new(InMemoryStore::new());
+        let repository = UserRepository::new(users.clone());
+        let registry = Arc::new(SessionRegistry::new(
+            sessions.clone(),
+            TokenDenylist::new(Arc::new(InMemoryStore::new())),
+            Duration::from_secs(3600),
+        ));
+        let api_keys = Arc::new(ApiKeyService::new(Arc::new(InMemoryStore::new())));
+        let checker = IntegrityChecker::new(repository.clone(), registry.clone(), api_keys, false);
+        Fixture {
+            checker,
+            users,
+            sessions,
+            repository,
+            registry,
+        }
+    }
+
+    fn kinds(report: &IntegrityReport) -> Vec<(IssueKind, bool)> {
+        report.issues.iter().map(|issue| (issue.kind, issue.repairable)).collect()
+    }
+
+    #[test]
+    fn test_consistent_store_has_no_issues() {
+        let f = fixture();
+        let user = f.repository.create("ann@example.com", String::new()).unwrap();
+        f.repository.record_event(&user.id, "user.registered", json!({})).unwrap();
+        f.registry.create(&user.id, "laptop", "192.0.2.1".parse().unwrap()).unwrap();
+
+        let report = f.checker.run(false).unwrap();
+        assert_eq!(report.users_checked, 1);
+        assert!(report.issues.is_empty());
+        assert_eq!(f.checker.last_report(), Some(report));
+    }
+
+    #[test]
+    fn test_safe_issues_are_repaired() {
+        let f = fixture();
+        let ann = f.repository.create("ann@example.com", String::new()).unwrap();
+        let bob = f.repository.create("bob@example.com", String::new()).unwrap();
+        let index = json!([ann.id, bob.id, ann.id, "gone"]).to_string();
+        f.users.set("index", &index, None).unwrap();
+        f.users.delete("email:bob@example.com").unwrap();
+        let session = f.registry.create(&ann.id, "laptop", "192.0.2.1".parse().unwrap()).unwrap();
+        f.sessions.set(&format!("user:{}", bob.id), &json!([session.id]).to_string(), None).unwrap();
+
+        let report = f.checker.run(false).unwrap();
+        assert_eq!(report.users_checked, 2);
+        assert_eq!(
+            kinds(&report),
+            vec![
+                (IssueKind::DuplicateKey, true),
+                (IssueKind::OrphanedReference, true),
+                (IssueKind::OrphanedReference, true),
+                (IssueKind::OrphanedReference, true),
+            ]
+        );
+        assert_eq!(report.repaired, 0);
+        assert_eq!(f.repository.ids().unwrap().len(), 4);
+
+        let report = f.checker.run(true).unwrap();
+        assert_eq!(report.repaired, 4);
+        assert_eq!(f.repository.ids().unwrap(), vec![ann.id.clone(), bob.id.clone()]);
+        assert_eq!(f.repository.find_by_email("bob@example.com").unwrap().unwrap().id, bob.id);
+        assert!(f.registry.list(&bob.id).unwrap().is_empty());
+        assert_eq!(f.registry.list(&ann.id).unwrap().len(), 1);
+        assert!(f.checker.run(false).unwrap().issues.is_empty());
+    }
+
+    #[test]
+    fn test_unsafe_issues_are_only_reported() {
+        let f = fixture();
+        let mut ann = f.repository.create("ann@example.com", String::new()).unwrap();
+        let bob = f.repository.create("bob@example.com", String::new()).unwrap();
+        f.users.set("email:ann@example.com", &bob.id, None).unwrap();
+        ann.updated_at = ann.created_at - chrono::Duration::days(1);
+        f.repository.save(&ann).unwrap();
+        let event = UserEvent {
+            id: "e1".to_string(),
+            user_id: bob.id.clone(),
+            action: "user.registered".to_string(),
+            at: Utc::now() + chrono::Duration::days(1),
+            data: json!({}),
+        };
+        f.repository.save_events(&ann.id, &[event]).unwrap();
+
+        let report = f.checker.run(true).unwrap();
+        assert_eq!(
+            kinds(&report),
+            vec![
+                (IssueKind::DuplicateKey, false),
+                (IssueKind::InvalidTimestamp, false),
+                (IssueKind::OrphanedReference, false),
+                (IssueKind::InvalidTimestamp, false),
+            ]
+        );
+        assert_eq!(report.repaired, 0);
+        assert!(report.issues.iter().all(|issue| !issue.detail.contains("example.com")));
+        assert_eq!(f.repository.events(&ann.id).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,137 @@
+use std::net::IpAddr;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use ipnet::IpNet;
+use log::debug;
+
+use crate::client_info::client_ip;
+use crate::config::Config;
+use crate::error::AppError;
+
+/// Network allowlist and denylist applied to every request
+///
+/// Denied networks always lose; with a non-empty allowlist only addresses in
+/// it get through. Addresses are the client's as resolved through
+/// `TRUSTED_PROXIES`, so a proxy cannot be used to dodge the lists.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Creates a filter from allowed and denied networks
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Builds the filter from `IP_ALLOWLIST` and `IP_DENYLIST`
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.ip_allowlist.clone(), config.ip_denylist.clone())
+    }
+
+    /// Whether the filter lets every request through
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether requests from `ip` are accepted
+    ///
+    /// An unknown address only passes when no allowlist is configured.
+    pub fn permits(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip else {
+            return self.allow.is_empty();
+        };
+        if self.deny.iter().any(|network| network.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|network| network.contains(&ip))
+    }
+}
+
+/// Middleware rejecting requests from filtered addresses with 403, for use
+/// with `from_fn`
+///
+/// Passes every request when no [`IpFilter`] is registered.
+pub async fn ip_filter(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if let Some(filter) = req.app_data::<web::Data<IpFilter>>().filter(|filter| !filter.is_empty()) {
+        let ip = client_ip(req.request());
+        if !filter.permits(ip) {
+            debug!("Rejected {} {} from filtered address {:?}", req.method(), req.path(), ip);
+            return Err(AppError::forbidden("requests from this address are not allowed").into());
+        }
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_info::ClientResolver;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn networks(entries: &[&str]) -> Vec<IpNet> {
+        entries.iter().map(|entry| entry.parse().unwrap()).collect()
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    #[test]
+    fn test_allow_and_deny_lists() {
+        let open = IpFilter::default();
+        assert!(open.is_empty());
+        assert!(open.permits(ip("203.0.113.9")) && open.permits(None));
+
+        let deny_only = IpFilter::new(Vec::new(), networks(&["203.0.113.0/24"]));
+        assert!(!deny_only.permits(ip("203.0.113.9")));
+        assert!(deny_only.permits(ip("198.51.100.1")));
+        assert!(deny_only.permits(None));
+
+        // Denied networks win over allowed ones; unknown addresses fail closed
+        let both = IpFilter::new(networks(&["10.0.0.0/8", "2001:db8::/32"]), networks(&["10.6.6.0/24"]));
+        assert!(both.permits(ip("10.1.2.3")));
+        assert!(both.permits(ip("2001:db8::1")));
+        assert!(!both.permits(ip("10.6.6.6")));
+        assert!(!both.permits(ip("198.51.100.1")));
+        assert!(!both.permits(None));
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_uses_resolved_client_address() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(IpFilter::new(Vec::new(), networks(&["198.51.100.0/24"]))))
+                .app_data(web::Data::new(ClientResolver::new(networks(&["10.0.0.0/8"]))))
+                .wrap(from_fn(ip_filter))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |peer: &str, forwarded_for: &str| {
+            TestRequest::get()
+                .uri("/")
+                .peer_addr(peer.parse().unwrap())
+                .insert_header(("x-forwarded-for", forwarded_for.to_string()))
+                .to_request()
+        };
+
+        // A denied client behind the trusted proxy is rejected
+        let err = try_call_service(&app, request("10.0.0.1:443", "198.51.100.7")).await.err().unwrap();
+        assert_eq!(err.as_response_error().status_code(), 403);
+        assert!(call_service(&app, request("10.0.0.1:443", "203.0.113.9")).await.status().is_success());
+
+        // An untrusted peer cannot claim another address, nor hide its own
+        let err = try_call_service(&app, request("198.51.100.7:5000", "203.0.113.9")).await.err().unwrap();
+        assert_eq!(err.as_response_error().status_code(), 403);
+        assert!(call_service(&app, request("203.0.113.9:5000", "198.51.100.7")).await.status().is_success());
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use log::{debug, error, warn};
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+use crate::error::{AppError, AppResult};
+use crate::supervisor::Supervisor;
+
+/// Default number of jobs that can wait in the queue
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// A unit of work processed asynchronously, outside the request cycle
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// Unique job identifier, returned to callers for correlation
+    pub id: String,
+    /// Job kind used to pick the handler, e.g. `webhook.github`
+    pub kind: String,
+    /// Job payload
+    pub payload: Value,
+    /// Time the job was accepted
+    pub enqueued_at: DateTime<Utc>,
+}
+
+impl Job {
+    /// Creates a job with a fresh id
+    pub fn new<T: Into<String>>(kind: T, payload: Value) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            kind: kind.into(),
+            payload,
+            enqueued_at: Utc::now(),
+        }
+    }
+}
+
+/// Processes jobs of one or more kinds
+pub trait JobHandler: Send + Sync {
+    /// Handles a single job; errors are logged and the job is dropped
+    fn handle(&self, job: &Job) -> AppResult<()>;
+}
+
+impl<F> JobHandler for F
+where
+    F: Fn(&Job) -> AppResult<()> + Send + Sync,
+{
+    fn handle(&self, job: &Job) -> AppResult<()> {
+        self(job)
+    }
+}
+
+/// Registry mapping job kinds to their handlers
+///
+/// A handler registered for a prefix ending in `.` (e.g. `webhook.`) receives
+/// every job whose kind starts with it; exact kinds take precedence.
+#[derive(Default, Clone)]
+pub struct JobHandlers {
+    handlers: HashMap<String, Arc<dyn JobHandler>>,
+}
+
+impl JobHandlers {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `handler` for `kind`
+    pub fn register<T: Into<String>>(mut self, kind: T, handler: impl JobHandler + 'static) -> Self {
+        self.handlers.insert(kind.into(), Arc::new(handler));
+        self
+    }
+
+    fn find(&self, kind: &str) -> Option<&Arc<dyn JobHandler>> {
+        self.handlers.get(kind).or_else(|| {
+            self.handlers
+                .iter()
+                .filter(|(prefix, _)| prefix.ends_with('.') && kind.starts_with(prefix.as_str()))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, handler)| handler)
+        })
+    }
+
+    fn dispatch(&self, job: &Job) {
+        match self.find(&job.kind) {
+            Some(handler) => {
+                if let Err(e) = handler.handle(job) {
+                    error!("Job {} ({}) failed: {}", job.id, job.kind, e);
+                } else {
+                    debug!("Job {} ({}) processed", job.id, job.kind);
+                }
+            }
+            None => warn!("No handler registered for job kind '{}', dropping job {}", job.kind, job.id),
+        }
+    }
+}
+
+/// Bounded in-process job queue
+///
+/// Cloning the queue clones the sending side only; a single background task
+/// drains the queue and dispatches jobs to their handlers.
+#[derive(Clone)]
+pub struct JobQueue {
+    sender: mpsc::Sender<Job>,
+}
+
+impl JobQueue {
+    /// Creates the queue and runs its worker under `supervisor`
+    ///
+    /// A handler panic crashes the worker, which the supervisor restarts;
+    /// the job being processed is dropped.
+    ///
+    /// # Arguments
+    /// * `capacity` - Maximum number of jobs waiting to be processed
+    /// * `handlers` - Handlers jobs are dispatched to
+    /// * `supervisor` - Supervisor restarting the worker
+    pub fn start(capacity: usize, handlers: JobHandlers, supervisor: &Arc<Supervisor>) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>(capacity.max(1));
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+
+        supervisor.spawn("job_queue", move || {
+            let receiver = receiver.clone();
+            let handlers = handlers.clone();
+            async move {
+                let mut receiver = receiver.lock().await;
+                while let Some(job) = receiver.recv().await {
+                    handlers.dispatch(&job);
+                }
+                debug!("Job queue closed, worker exiting");
+                Ok(())
+            }
+        });
+
+        Self { sender }
+    }
+
+    /// Enqueues a job without waiting
+    ///
+    /// # Errors
+    /// Returns a service unavailable error when the queue is full or stopped,
+    /// so callers can shed load instead of blocking requests.
+    pub fn enqueue(&self, job: Job) -> AppResult<String> {
+        let id = job.id.clone();
+        self.sender.try_send(job).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => AppError::unavailable("job queue is full"),
+            mpsc::error::TrySendError::Closed(_) => AppError::unavailable("job queue is not running"),
+        })?;
+        Ok(id)
+    }
+
+    /// Returns the number of free slots in the queue
+    pub fn remaining_capacity(&self) -> usize {
+        self.sender.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_jobs_are_dispatched_by_kind() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let handlers = JobHandlers::new().register("webhook.", move |job: &Job| {
+            recorder.lock().unwrap().push(job.kind.clone());
+            Ok(())
+        });
+
+        let queue = JobQueue::start(8, handlers, &Arc::new(Supervisor::default()));
+        queue.enqueue(Job::new("webhook.github", Value::Null)).unwrap();
+        queue.enqueue(Job::new("other", Value::Null)).unwrap();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*seen.lock().unwrap(), vec!["webhook.github".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn test_full_queue_is_reported_as_unavailable() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let queue = JobQueue { sender };
+
+        assert!(queue.enqueue(Job::new("a", Value::Null)).is_ok());
+        let err = queue.enqueue(Job::new("b", Value::Null)).unwrap_err();
+        assert!(matches!(err, AppError::Unavailable { .. }));
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::rt::net::TcpListener;
+use actix_web::{web, Error};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::simulation::SIMULATED_HEADER;
+use crate::supervisor::Supervisor;
+
+/// Route switching the drain state
+pub const DRAIN_ROUTE: &str = "/admin/drain";
+
+/// Header carrying the load as an ORCA report, read by Envoy's client-side
+/// weighted round robin
+pub const LOAD_METRICS_HEADER: &str = "endpoint-load-metrics";
+
+/// Requests and 5xx responses of one second
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    second: u64,
+    requests: u64,
+    errors: u64,
+}
+
+/// Weight of the instance and what it was computed from, as answered by
+/// `GET /lb-weight`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WeightReport {
+    /// 0 while draining, otherwise 1 (struggling) to 100 (idle)
+    pub weight: u8,
+    pub draining: bool,
+    /// Application requests being handled
+    pub in_flight: u64,
+    /// In-flight requests at which the load alone brings the weight down to 1
+    pub max_in_flight: u64,
+    /// Requests answered within the window
+    pub requests: u64,
+    /// Fraction of 5xx responses within the window, 0 below the minimum
+    /// number of requests
+    pub error_rate: f64,
+}
+
+impl WeightReport {
+    /// Reply of an HAProxy `agent-check`: `drain`, or `ready` and the weight
+    pub fn agent_line(&self) -> String {
+        match self.draining {
+            true => "drain\n".to_string(),
+            false => format!("ready {}%\n", self.weight),
+        }
+    }
+
+    /// Value of the [`LOAD_METRICS_HEADER`], the utilization being the
+    /// share of weight lost
+    pub fn orca(&self) -> String {
+        format!(
+            "TEXT application_utilization={:.3}, named_metrics.in_flight={}, named_metrics.error_rate={:.3}",
+            1.0 - f64::from(self.weight) / 100.0,
+            self.in_flight,
+            self.error_rate
+        )
+    }
+}
+
+/// Load of the application listeners turned into a weight for external
+/// load balancers
+///
+/// The weight starts at 100 and shrinks in proportion to the requests in
+/// flight against `LB_WEIGHT_MAX_IN_FLIGHT` and to the 5xx rate over the last
+/// `LB_WEIGHT_WINDOW_SECS` against `LB_WEIGHT_MAX_ERROR_RATE`, never below 1
+/// so a struggling instance still gets a trickle to recover on. Draining,
+/// switched through `PUT /admin/drain`, brings it to 0. Simulated responses
+/// are not counted. State is per instance.
+pub struct LoadMonitor {
+    max_in_flight: u64,
+    max_error_rate: f64,
+    window: Duration,
+    min_requests: u64,
+    in_flight: AtomicU64,
+    draining: AtomicBool,
+    started: Instant,
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+/// Counts a request as in flight until dropped
+pub struct InFlight<'a>(&'a LoadMonitor);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadMonitor {
+    /// Creates the monitor of an idle instance
+    ///
+    /// # Arguments
+    /// * `max_in_flight` - Requests in flight at which the load weighs fully
+    /// * `max_error_rate` - 5xx rate at which errors weigh fully
+    /// * `window` - Period the error rate is measured over
+    /// * `min_requests` - Requests within the window below which errors are ignored
+    pub fn new(max_in_flight: u64, max_error_rate: f64, window: Duration, min_requests: u64) -> Self {
+        Self {
+            max_in_flight: max_in_flight.max(1),
+            max_error_rate,
+            window,
+            min_requests,
+            in_flight: AtomicU64::new(0),
+            draining: AtomicBool::new(false),
+            started: Instant::now(),
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Builds the monitor from the `LB_WEIGHT_*` settings
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.lb_weight_max_in_flight,
+            config.lb_weight_max_error_rate,
+            Duration::from_secs(config.lb_weight_window_secs),
+            config.lb_weight_min_requests,
+        )
+    }
+
+    /// Counts a request as in flight until the guard is dropped
+    pub fn begin(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self)
+    }
+
+    /// Counts a response, `server_error` for a 5xx
+    pub fn record(&self, server_error: bool, now: Instant) {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return;
+        };
+        let second = self.second(now);
+        match buckets.back_mut() {
+            Some(bucket) if bucket.second == second => {
+                bucket.requests += 1;
+                bucket.errors += u64::from(server_error);
+            }
+            _ => buckets.push_back(Bucket {
+                second,
+                requests: 1,
+                errors: u64::from(server_error),
+            }),
+        }
+        self.expire(&mut buckets, now);
+    }
+
+    /// Starts or stops draining; returns the new report
+    pub fn set_draining(&self, draining: bool) -> WeightReport {
+        if self.draining.swap(draining, Ordering::Relaxed) != draining {
+            info!("Load balancer weight {}", if draining { "drained to 0" } else { "restored" });
+        }
+        self.report(Instant::now())
+    }
+
+    /// Computes the weight at `now`
+    pub fn report(&self, now: Instant) -> WeightReport {
+        let (requests, errors) = match self.buckets.lock() {
+            Ok(mut buckets) => {
+                self.expire(&mut buckets, now);
+                buckets
+                    .iter()
+                    .fold((0, 0), |(requests, errors), bucket| (requests + bucket.requests, errors + bucket.errors))
+            }
+            Err(_) => (0, 0),
+        };
+        let error_rate = match requests >= self.min_requests.max(1) {
+            true => errors as f64 / requests as f64,
+            false => 0.0,
+        };
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        let draining = self.draining.load(Ordering::Relaxed);
+        let load = (in_flight as f64 / self.max_in_flight as f64).min(1.0);
+        let errors = match self.max_error_rate > 0.0 {
+            true => (error_rate / self.max_error_rate).min(1.0),
+            false => 0.0,
+        };
+        let weight = match draining {
+            true => 0,
+            false => ((100.0 * (1.0 - load) * (1.0 - errors)).round() as u8).max(1),
+        };
+        WeightReport {
+            weight,
+            draining,
+            in_flight,
+            max_in_flight: self.max_in_flight,
+            requests,
+            error_rate,
+        }
+    }
+
+    fn second(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs()
+    }
+
+    fn expire(&self, buckets: &mut VecDeque<Bucket>, now: Instant) {
+        let oldest = self.second(now).saturating_sub(self.window.as_secs().saturating_sub(1));
+        while buckets.front().is_some_and(|bucket| bucket.second < oldest) {
+            buckets.pop_front();
+        }
+    }
+
+    /// Answers HAProxy `agent-check` connections on `address` under
+    /// `supervisor`, one [`WeightReport::agent_line`] per connection
+    pub fn spawn_agent(monitor: Arc<Self>, address: String, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("lb_agent", move || {
+            let (monitor, address) = (monitor.clone(), address.clone());
+            async move {
+                let listener = TcpListener::bind(&address)
+                    .await
+                    .map_err(|e| AppError::internal(format!("cannot listen on LB_AGENT_ADDRESS {}: {}", address, e)))?;
+                info!("Load balancer agent listening on {}", address);
+                loop {
+                    let Ok((mut stream, peer)) = listener.accept().await else {
+                        continue;
+                    };
+                    let line = monitor.report(Instant::now()).agent_line();
+                    if let Err(e) = stream.write_all(line.as_bytes()).await.and(stream.shutdown().await) {
+                        warn!("Load balancer agent reply to {} failed: {}", peer, e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Middleware counting the requests in flight and the 5xx responses for
+/// the registered `web::Data<LoadMonitor>`, for use with `from_fn`
+pub async fn track_load(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(monitor) = req.app_data::<web::Data<LoadMonitor>>().cloned() else {
+        return next.call(req).await;
+    };
+    let in_flight = monitor.begin();
+    let result = next.call(req).await;
+    drop(in_flight);
+    match &result {
+        Ok(res) if res.headers().contains_key(SIMULATED_HEADER) => {}
+        Ok(res) => monitor.record(res.status().is_server_error(), Instant::now()),
+        Err(e) => monitor.record(e.as_response_error().status_code().is_server_error(), Instant::now()),
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    #[test]
+    fn test_weight_follows_load_errors_and_drain() {
+        let monitor = LoadMonitor::new(4, 0.5, Duration::from_secs(10), 4);
+        let now = Instant::now();
+        assert_eq!(monitor.report(now).weight, 100);
+
+        let _first = monitor.begin();
+        let second = monitor.begin();
+        assert_eq!(monitor.report(now).weight, 50);
+        drop(second);
+        assert_eq!(monitor.report(now).in_flight, 1);
+
+        // Errors only count once the window holds enough requests
+        monitor.record(true, now);
+        assert_eq!(monitor.report(now).error_rate, 0.0);
+        for _ in 0..3 {
+            monitor.record(false, now);
+        }
+        let report = monitor.report(now);
+        assert_eq!((report.requests, report.error_rate, report.weight), (4, 0.25, 38));
+        assert_eq!(monitor.report(now + Duration::from_secs(11)).requests, 0);
+
+        let report = monitor.set_draining(true);
+        assert_eq!((report.weight, report.agent_line()), (0, "drain\n".to_string()));
+        let report = monitor.set_draining(false);
+        assert_eq!(report.agent_line(), format!("ready {}%\n", report.weight));
+    }
+
+    #[test]
+    fn test_weight_never_drops_to_zero_unless_draining() {
+        let monitor = LoadMonitor::new(1, 0.5, Duration::from_secs(10), 1);
+        let _busy = (monitor.begin(), monitor.begin());
+        monitor.record(true, Instant::now());
+        let report = monitor.report(Instant::now());
+        assert_eq!(report.weight, 1);
+        assert!(report.orca().starts_with("TEXT application_utilization=0.990"));
+    }
+
+    #[actix_web::test]
+    async fn test_middleware_counts_responses() {
+        let monitor = web::Data::new(LoadMonitor::new(10, 0.5, Duration::from_secs(60), 1));
+        let app = init_service(
+            App::new()
+                .app_data(monitor.clone())
+                .wrap(from_fn(track_load))
+                .route("/ok", web::get().to(HttpResponse::Ok))
+                .route("/fail", web::get().to(HttpResponse::InternalServerError)),
+        )
+        .await;
+        call_service(&app, TestRequest::get().uri("/ok").to_request()).await;
+        call_service(&app, TestRequest::get().uri("/fail").to_request()).await;
+
+        let report = monitor.report(Instant::now());
+        assert_eq!((report.requests, report.error_rate, report.in_flight), (2, 0.5, 0));
+    }
+
+    #[actix_web::test]
+    async fn test_agent_answers_haproxy() {
+        use tokio::io::AsyncReadExt;
+
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let monitor = Arc::new(LoadMonitor::new(10, 0.5, Duration::from_secs(60), 1));
+        monitor.set_draining(true);
+        let supervisor = Arc::new(Supervisor::new(Default::default()));
+        LoadMonitor::spawn_agent(monitor, address.clone(), &supervisor);
+
+        let mut reply = String::new();
+        for _ in 0..50 {
+            if let Ok(mut stream) = actix_web::rt::net::TcpStream::connect(&address).await {
+                stream.read_to_string(&mut reply).await.unwrap();
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(reply, "drain\n");
+    }
+}
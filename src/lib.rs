@@ -2,7 +2,75 @@
 /// 
 /// This library provides the core functionality for the simple API demo application.
 /// It includes configuration management, request handlers, server setup, and error handling.
+pub mod analytics;
+pub mod anonymization;
+pub mod assets;
+pub mod audit;
+pub mod aws_secrets;
+pub mod auth;
+pub mod backup;
+pub mod budgets;
+pub mod change_guard;
+pub mod client_info;
+pub mod clock;
 pub mod config;
+pub mod consent;
+pub mod context;
+pub mod cookies;
+pub mod cors;
+pub mod crypto;
+pub mod daemon;
+pub mod degradation;
+pub mod deliveries;
+pub mod egress;
+pub mod demo_data;
+pub mod dumps;
 pub mod error;
+pub mod error_circuit;
+pub mod event_bus;
+pub mod events;
+pub mod files;
+pub mod filters;
 pub mod handlers;
-pub mod server; 
\ No newline at end of file
+pub mod hardening;
+pub mod healthcheck;
+pub mod histogram;
+pub mod init;
+pub mod integrity;
+pub mod ip_filter;
+pub mod jobs;
+pub mod lb_weight;
+pub mod listeners;
+pub mod log_context;
+pub mod metrics_export;
+pub mod notifications;
+pub mod openapi;
+pub mod pagination;
+pub mod plugins;
+pub mod pii;
+pub mod policy;
+pub mod privacy;
+pub mod rbac;
+pub mod read_only;
+pub mod region;
+pub mod reload;
+pub mod remote_config;
+pub mod routes;
+pub mod scanning;
+pub mod scripting;
+pub mod secrets;
+pub mod server;
+pub mod server_timing;
+pub mod simulation;
+pub mod slow_queries;
+pub mod state;
+pub mod statsd;
+pub mod stubs;
+pub mod subscriptions;
+pub mod supervisor;
+pub mod tls;
+pub mod users;
+pub mod vault;
+pub mod version_skew;
+pub mod warmup;
+pub mod webhooks; 
\ No newline at end of file
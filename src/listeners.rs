@@ -0,0 +1,457 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix_web::web;
+
+use crate::error::{AppError, AppResult};
+use crate::handlers::main_server;
+use crate::routes::RouteRegistry;
+use crate::tls::TlsSettings;
+
+/// Routes served by a listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteProfile {
+    /// `/`, `/health`, `/ready`, `/lb-weight` and, when enabled, `/debug/info`
+    Main,
+    /// Every route of the application [`RouteRegistry`]
+    App,
+    /// `/health`, `/ready` and `/lb-weight` only
+    Health,
+    /// `/metrics` (operational request counters) and `/health`
+    Metrics,
+}
+
+impl RouteProfile {
+    /// Registers the profile's routes
+    ///
+    /// # Arguments
+    /// * `routes` - Application routes, served by [`RouteProfile::App`]
+    /// * `debug_endpoints` - Whether [`RouteProfile::Main`] exposes `/debug/*`
+    pub fn configure(self, cfg: &mut web::ServiceConfig, routes: &RouteRegistry, debug_endpoints: bool) {
+        match self {
+            RouteProfile::Main => {
+                let mut scope = web::scope("")
+                    .route("/", web::get().to(main_server::hello))
+                    .route("/health", web::get().to(main_server::hello)) // Health check endpoint
+                    .route("/ready", web::get().to(main_server::ready))
+                    .route("/lb-weight", web::get().to(main_server::lb_weight));
+                if debug_endpoints {
+                    scope = scope.route("/debug/info", web::get().to(main_server::debug_info));
+                }
+                cfg.service(scope);
+            }
+            RouteProfile::App => routes.configure(cfg),
+            RouteProfile::Health => {
+                cfg.route("/health", web::get().to(main_server::hello))
+                    .route("/ready", web::get().to(main_server::ready))
+                    .route("/lb-weight", web::get().to(main_server::lb_weight));
+            }
+            RouteProfile::Metrics => {
+                cfg.route("/metrics", web::get().to(main_server::metrics))
+                    .route("/health", web::get().to(main_server::hello));
+            }
+        }
+    }
+
+    /// Middleware used when a listener does not name a profile
+    pub fn default_middleware(self) -> MiddlewareProfile {
+        match self {
+            RouteProfile::App => MiddlewareProfile::Full,
+            RouteProfile::Main => MiddlewareProfile::Standard,
+            RouteProfile::Health | RouteProfile::Metrics => MiddlewareProfile::Minimal,
+        }
+    }
+}
+
+impl fmt::Display for RouteProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RouteProfile::Main => "main",
+            RouteProfile::App => "app",
+            RouteProfile::Health => "health",
+            RouteProfile::Metrics => "metrics",
+        })
+    }
+}
+
+impl FromStr for RouteProfile {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "main" => Ok(RouteProfile::Main),
+            "app" => Ok(RouteProfile::App),
+            "health" => Ok(RouteProfile::Health),
+            "metrics" => Ok(RouteProfile::Metrics),
+            other => Err(AppError::config(format!(
+                "unknown routes profile '{}' (expected main, app, health or metrics)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Middleware wrapped around a listener's routes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiddlewareProfile {
+    /// Request scripts, usage analytics, impersonation marking, the consent
+    /// gate and middleware plugins, plus CORS and request logging
+    Full,
+    /// CORS and request logging
+    Standard,
+    /// Request logging only
+    Minimal,
+}
+
+impl fmt::Display for MiddlewareProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MiddlewareProfile::Full => "full",
+            MiddlewareProfile::Standard => "standard",
+            MiddlewareProfile::Minimal => "minimal",
+        })
+    }
+}
+
+impl FromStr for MiddlewareProfile {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "full" => Ok(MiddlewareProfile::Full),
+            "standard" => Ok(MiddlewareProfile::Standard),
+            "minimal" => Ok(MiddlewareProfile::Minimal),
+            other => Err(AppError::config(format!(
+                "unknown middleware profile '{}' (expected full, standard or minimal)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Where a listener's server runs
+///
+/// Every listener always has its own worker threads; a dedicated runtime also
+/// moves its accept loop and control future off the main actix system onto a
+/// thread of its own, so it keeps serving whatever the main system is doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListenerRuntime {
+    /// Driven by the main actix system, alongside the background tasks
+    #[default]
+    Shared,
+    /// Driven by its own actix system on a dedicated thread
+    Dedicated,
+}
+
+impl fmt::Display for ListenerRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ListenerRuntime::Shared => "shared",
+            ListenerRuntime::Dedicated => "dedicated",
+        })
+    }
+}
+
+impl FromStr for ListenerRuntime {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "shared" => Ok(ListenerRuntime::Shared),
+            "dedicated" => Ok(ListenerRuntime::Dedicated),
+            other => Err(AppError::config(format!(
+                "unknown listener runtime '{}' (expected shared or dedicated)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Parses a worker count, which must be at least 1
+pub fn parse_workers(value: &str) -> Option<usize> {
+    value.trim().parse::<usize>().ok().filter(|workers| *workers > 0)
+}
+
+/// Timeouts and connection limits shared by every listener's server
+///
+/// Built by [`Config::server_limits`](crate::config::Config::server_limits)
+/// so the actix defaults are spelled out in configuration rather than
+/// implied by the library version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Idle time before a keep-alive connection is closed; keep-alive is off when `None`
+    pub keep_alive: Option<Duration>,
+    /// Time a client has to send the request head; zero disables the timeout
+    pub client_request_timeout: Duration,
+    /// Grace period for in-flight requests when the server stops
+    pub shutdown_timeout: Duration,
+    /// Concurrent connections accepted by each worker
+    pub max_connections: usize,
+}
+
+/// One named HTTP listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerSpec {
+    /// Unique name used in logs
+    pub name: String,
+    /// Address to bind
+    pub address: String,
+    pub port: u16,
+    pub routes: RouteProfile,
+    pub middleware: MiddlewareProfile,
+    /// Size of the listener's own worker set; actix's default (one per CPU) when unset
+    pub workers: Option<usize>,
+    pub runtime: ListenerRuntime,
+    /// Serves HTTPS with these settings instead of plain HTTP
+    pub tls: Option<TlsSettings>,
+}
+
+impl ListenerSpec {
+    /// Describes a listener using the routes profile's default middleware
+    pub fn new(name: &str, address: &str, port: u16, routes: RouteProfile) -> Self {
+        Self {
+            name: name.to_string(),
+            address: address.to_string(),
+            port,
+            routes,
+            middleware: routes.default_middleware(),
+            workers: None,
+            runtime: ListenerRuntime::Shared,
+            tls: None,
+        }
+    }
+
+    /// Parses the `LISTENERS` setting
+    ///
+    /// Listeners are separated by `;`, each written as
+    /// `name: bind=ADDRESS:PORT routes=PROFILE [middleware=PROFILE] [workers=N]
+    /// [runtime=shared|dedicated]`, e.g.
+    /// `metrics: bind=127.0.0.1:9100 routes=metrics`.
+    ///
+    /// # Errors
+    /// Returns a config error for malformed entries, unknown keys or profiles
+    pub fn parse_list(spec: &str) -> AppResult<Vec<Self>> {
+        spec.split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    fn parse(entry: &str) -> AppResult<Self> {
+        let invalid = |reason: &str| AppError::config(format!("listener '{}' {}", entry, reason));
+        let (name, fields) = entry
+            .split_once(':')
+            .ok_or_else(|| invalid("must look like 'name: bind=ADDRESS:PORT routes=PROFILE'"))?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(invalid("needs a name of letters, digits, '-' or '_'"));
+        }
+
+        let (mut bind, mut routes, mut middleware, mut workers, mut runtime) = (None, None, None, None, None);
+        for field in fields.split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| invalid(&format!("has a field '{}' without '='", field)))?;
+            match key {
+                "bind" => bind = Some(value),
+                "routes" => routes = Some(value.parse::<RouteProfile>()?),
+                "middleware" => middleware = Some(value.parse::<MiddlewareProfile>()?),
+                "workers" => {
+                    workers = Some(parse_workers(value).ok_or_else(|| invalid("needs workers=N with N of at least 1"))?)
+                }
+                "runtime" => runtime = Some(value.parse::<ListenerRuntime>()?),
+                other => return Err(invalid(&format!("has an unknown field '{}'", other))),
+            }
+        }
+
+        let bind = bind.ok_or_else(|| invalid("needs bind=ADDRESS:PORT"))?;
+        let (address, port) = bind
+            .rsplit_once(':')
+            .and_then(|(address, port)| Some((address, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| invalid("needs bind=ADDRESS:PORT"))?;
+        let address = address.trim_start_matches('[').trim_end_matches(']');
+        let routes = routes.ok_or_else(|| invalid("needs routes=PROFILE"))?;
+        let mut listener = Self::new(name, address, port, routes);
+        if let Some(middleware) = middleware {
+            listener.middleware = middleware;
+        }
+        listener.workers = workers;
+        listener.runtime = runtime.unwrap_or_default();
+        Ok(listener)
+    }
+}
+
+impl fmt::Display for ListenerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} on {}:{} (routes: {}, middleware: {}, runtime: {}",
+            self.name, self.address, self.port, self.routes, self.middleware, self.runtime
+        )?;
+        if let Some(workers) = self.workers {
+            write!(f, ", workers: {}", workers)?;
+        }
+        match &self.tls {
+            Some(tls) if tls.client_ca_path.is_some() => write!(f, ", tls: client certificates {})", tls.client_auth),
+            Some(_) => f.write_str(", tls)"),
+            None => f.write_str(")"),
+        }
+    }
+}
+
+/// Checks that listener names and bind addresses are unique and that
+/// addresses are IP addresses or host names
+///
+/// # Errors
+/// Returns a config error listing every problem
+pub fn validate(listeners: &[ListenerSpec]) -> AppResult<()> {
+    match problems(listeners) {
+        problems if problems.is_empty() => Ok(()),
+        problems => Err(AppError::config(problems.join("; "))),
+    }
+}
+
+/// Describes every problem [`validate`] reports
+///
+/// An unspecified address (`0.0.0.0`, `::`) clashes with any address on
+/// the same port; port 0, picked by the system, never clashes.
+pub fn problems(listeners: &[ListenerSpec]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (index, listener) in listeners.iter().enumerate() {
+        if !valid_address(&listener.address) {
+            problems.push(format!(
+                "listener '{}' binds '{}', which is neither an IP address nor a host name",
+                listener.name, listener.address
+            ));
+        }
+        for other in &listeners[..index] {
+            if other.name == listener.name {
+                problems.push(format!("listener name '{}' is used twice", listener.name));
+            } else if other.port == listener.port && listener.port != 0 && overlap(&other.address, &listener.address) {
+                problems.push(format!(
+                    "listeners '{}' and '{}' both bind {}:{}",
+                    other.name, listener.name, listener.address, listener.port
+                ));
+            }
+        }
+    }
+    problems
+}
+
+fn valid_address(address: &str) -> bool {
+    if address.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+    address.len() <= 253
+        && address.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Whether binding both addresses on one port conflicts
+fn overlap(a: &str, b: &str) -> bool {
+    let unspecified = |address: &str| address.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified());
+    a == b || unspecified(a) || unspecified(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analytics::AnalyticsPipeline;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::App;
+
+    #[test]
+    fn test_parse_list() {
+        let listeners = ListenerSpec::parse_list(
+            "metrics: bind=127.0.0.1:9100 routes=metrics; internal: bind=[::1]:9000 routes=app middleware=standard;",
+        )
+        .unwrap();
+        assert_eq!(listeners[0], ListenerSpec::new("metrics", "127.0.0.1", 9100, RouteProfile::Metrics));
+        assert_eq!(listeners[0].middleware, MiddlewareProfile::Minimal);
+        assert_eq!(listeners[1].address, "::1");
+        assert_eq!(listeners[1].routes, RouteProfile::App);
+        assert_eq!(listeners[1].middleware, MiddlewareProfile::Standard);
+        assert_eq!((listeners[1].workers, listeners[1].runtime), (None, ListenerRuntime::Shared));
+        assert!(ListenerSpec::parse_list("").unwrap().is_empty());
+
+        let health = ListenerSpec::parse_list("health: bind=0.0.0.0:8081 routes=health workers=1 runtime=dedicated")
+            .unwrap()
+            .remove(0);
+        assert_eq!(health.workers, Some(1));
+        assert_eq!(health.runtime, ListenerRuntime::Dedicated);
+
+        for invalid in [
+            "metrics bind=127.0.0.1:9100 routes=metrics",
+            "metrics: routes=metrics",
+            "metrics: bind=127.0.0.1 routes=metrics",
+            "metrics: bind=127.0.0.1:9100",
+            "metrics: bind=127.0.0.1:9100 routes=stats",
+            "metrics: bind=127.0.0.1:9100 routes=metrics threads=2",
+            "metrics: bind=127.0.0.1:9100 routes=metrics workers=0",
+            "metrics: bind=127.0.0.1:9100 routes=metrics runtime=isolated",
+            "bad name: bind=127.0.0.1:9100 routes=metrics",
+        ] {
+            assert!(ListenerSpec::parse_list(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_clashes() {
+        let main = ListenerSpec::new("main", "0.0.0.0", 8080, RouteProfile::Main);
+        let app = ListenerSpec::new("app", "0.0.0.0", 4242, RouteProfile::App);
+        assert!(validate(&[main.clone(), app.clone()]).is_ok());
+
+        let same_name = ListenerSpec::new("app", "127.0.0.1", 9000, RouteProfile::Health);
+        assert!(validate(&[main.clone(), app.clone(), same_name]).is_err());
+        let same_port = ListenerSpec::new("health", "0.0.0.0", 8080, RouteProfile::Health);
+        assert!(validate(&[main.clone(), app.clone(), same_port]).is_err());
+        let loopback = ListenerSpec::new("health", "127.0.0.1", 4242, RouteProfile::Health);
+        assert!(validate(&[main.clone(), app.clone(), loopback]).is_err());
+        let elsewhere = ListenerSpec::new("health", "localhost", 9000, RouteProfile::Health);
+        assert!(validate(&[main.clone(), app.clone(), elsewhere]).is_ok());
+
+        let bad_address = ListenerSpec::new("health", "not an address", 8080, RouteProfile::Health);
+        let problems = problems(&[main, app, bad_address]);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("neither an IP address nor a host name"));
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_profile_only_serves_metrics() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(AnalyticsPipeline::new(None)))
+                .configure(|cfg| RouteProfile::Metrics.configure(cfg, &RouteRegistry::app_server(), false)),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(res.status().is_success());
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["requests_total"], 0);
+        assert_eq!(body["latency"]["count"], 0);
+
+        let res = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/metrics")
+                .insert_header(("Accept", "application/openmetrics-text; version=1.0.0"))
+                .to_request(),
+        )
+        .await;
+        assert!(res.headers().get("content-type").unwrap().to_str().unwrap().starts_with("application/openmetrics-text"));
+        let body = read_body(res).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("http_request_duration_seconds_count 0\n"));
+
+        let res = call_service(&app, TestRequest::get().uri("/public").to_request()).await;
+        assert_eq!(res.status(), 404);
+    }
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::future::Future;
+
+use tokio::task::futures::TaskLocalFuture;
+use tokio::task::JoinHandle;
+
+use crate::context::RequestContext;
+
+tokio::task_local! {
+    static CURRENT: Option<LogContext>;
+}
+
+/// Correlation fields appended to every log line emitted on behalf of a request
+///
+/// [`attach_context`](crate::context::attach_context) makes the request's
+/// fields current while the request is handled, and
+/// [`pii::format_log_record`](crate::pii::format_log_record) appends them to
+/// each line, so logs can be joined with traces without every call site
+/// repeating the ids. The context is task-local: work moved to another task
+/// or thread keeps it through [`spawn`], [`WithLogContext::in_current_log_context`]
+/// or [`sync_scope`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub request_id: String,
+}
+
+impl LogContext {
+    /// Fields of the request described by `context`
+    pub fn of(context: &RequestContext) -> Self {
+        Self {
+            trace_id: context.trace_id.clone(),
+            span_id: context.span_id.clone(),
+            request_id: context.request_id.clone(),
+        }
+    }
+
+    /// Returns the context of the request being handled, if any
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok().flatten()
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "trace_id={} span_id={} request_id={}",
+            self.trace_id, self.span_id, self.request_id
+        )
+    }
+}
+
+/// Runs `future` with `context` as the current log context
+pub fn scope<F: Future>(context: Option<LogContext>, future: F) -> TaskLocalFuture<Option<LogContext>, F> {
+    CURRENT.scope(context, future)
+}
+
+/// Runs `f` with `context` as the current log context
+///
+/// For blocking work handed to another thread, e.g. through `web::block`.
+pub fn sync_scope<R>(context: Option<LogContext>, f: impl FnOnce() -> R) -> R {
+    CURRENT.sync_scope(context, f)
+}
+
+/// Carries the current log context into futures run on other tasks
+pub trait WithLogContext: Future + Sized {
+    /// Runs the future in the log context current at the time of the call,
+    /// e.g. `tokio::spawn(task.in_current_log_context())`
+    fn in_current_log_context(self) -> TaskLocalFuture<Option<LogContext>, Self> {
+        scope(LogContext::current(), self)
+    }
+}
+
+impl<F: Future> WithLogContext for F {}
+
+/// Spawns `future` on the current worker like `actix_web::rt::spawn`,
+/// keeping the current log context
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+{
+    actix_web::rt::spawn(future.in_current_log_context())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::attach_context;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    fn context(request_id: &str) -> LogContext {
+        LogContext {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+            span_id: "00f067aa0ba902b7".to_string(),
+            request_id: request_id.to_string(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_context_crosses_spawn_boundaries() {
+        assert_eq!(LogContext::current(), None);
+        let expected = context("req-1");
+
+        let (plain, carried, nested, local) = scope(Some(expected.clone()), async {
+            let plain = tokio::spawn(async { LogContext::current() }).await.unwrap();
+            let carried = tokio::spawn(async { LogContext::current() }.in_current_log_context())
+                .await
+                .unwrap();
+            // A task spawned by a spawned task still belongs to the request
+            let nested = tokio::spawn(
+                async { tokio::spawn(async { LogContext::current() }.in_current_log_context()).await.unwrap() }
+                    .in_current_log_context(),
+            )
+            .await
+            .unwrap();
+            let local = spawn(async { LogContext::current() }).await.unwrap();
+            (plain, carried, nested, local)
+        })
+        .await;
+
+        // tokio::spawn alone starts from an empty task-local context
+        assert_eq!(plain, None);
+        assert_eq!(carried.as_ref(), Some(&expected));
+        assert_eq!(nested.as_ref(), Some(&expected));
+        assert_eq!(local.as_ref(), Some(&expected));
+        assert_eq!(LogContext::current(), None);
+
+        let blocking = scope(Some(expected.clone()), async {
+            let context = LogContext::current();
+            web::block(move || sync_scope(context, LogContext::current)).await.unwrap()
+        })
+        .await;
+        assert_eq!(blocking, Some(expected));
+    }
+
+    #[actix_web::test]
+    async fn test_requests_set_the_context() {
+        let app = init_service(App::new().wrap(from_fn(attach_context)).route(
+            "/",
+            web::get().to(|| async {
+                let spawned = tokio::spawn(async { LogContext::current() }.in_current_log_context())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                HttpResponse::Ok().json(serde_json::json!({
+                    "trace_id": spawned.trace_id,
+                    "span_id": spawned.span_id,
+                    "request_id": spawned.request_id,
+                }))
+            }),
+        ))
+        .await;
+
+        let res = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/")
+                .insert_header(("x-request-id", "req-7"))
+                .insert_header(("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"))
+                .to_request(),
+        )
+        .await;
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["request_id"], "req-7");
+        assert_eq!(body["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        // The request gets its own span under the caller's
+        assert_eq!(body["span_id"].as_str().unwrap().len(), 16);
+        assert_ne!(body["span_id"], "00f067aa0ba902b7");
+    }
+}
@@ -1,30 +1,370 @@
-mod config;
-mod error;
-mod handlers;
-mod server;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use config::Config;
-use error::AppError;
-use server::ServerManager;
+use clap::{Parser, Subcommand};
+use simple_api_demo::aws_secrets::AwsSecrets;
+use simple_api_demo::backup::Snapshot;
+use simple_api_demo::config::{self, ConfigLayers, ConfigSource, SecretProvider};
+use simple_api_demo::crypto;
+use simple_api_demo::daemon::{DaemonOptions, PidFile};
+use simple_api_demo::demo_data::{self, DemoOptions};
+use simple_api_demo::error::AppError;
+use simple_api_demo::healthcheck::{self, HealthcheckOptions};
+use simple_api_demo::init::{self, InitOptions};
+use simple_api_demo::reload;
+use simple_api_demo::remote_config::RemoteConfig;
+use simple_api_demo::secrets;
+use simple_api_demo::server::ServerManager;
+use simple_api_demo::vault::VaultProvider;
+
+/// Simple API demo: a main server and an application server
+///
+/// Flags override the environment variables and the configuration file.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Main server port, overriding `PORT`
+    #[arg(long)]
+    port: Option<u16>,
+    /// Application server port, overriding `PORT_APP`
+    #[arg(long)]
+    app_port: Option<u16>,
+    /// Address the servers bind, overriding `BIND_ADDRESS`
+    #[arg(long)]
+    bind: Option<String>,
+    /// Log filter such as `debug` or `simple_api_demo=trace`, overriding `LOG_LEVEL`
+    #[arg(long)]
+    log_level: Option<String>,
+    /// TOML or YAML file to read the settings from, overriding `CONFIG_FILE`
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Dotenv file loaded into the environment, `.env` when present otherwise
+    #[arg(long)]
+    env_file: Option<PathBuf>,
+    /// Do not load `.env` from the working directory
+    #[arg(long, conflicts_with = "env_file")]
+    no_env_file: bool,
+    /// Detach from the terminal (Unix only)
+    #[arg(long)]
+    daemon: bool,
+    /// File holding the process id while the server runs
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+    /// Where the detached server writes its logs
+    #[arg(long, requires = "daemon")]
+    log_file: Option<PathBuf>,
+    /// Run under the Windows service control manager (Windows only)
+    #[arg(long, conflicts_with = "daemon")]
+    service: bool,
+    /// Print fresh values for every secret variable and exit
+    #[arg(long)]
+    rotate_secrets: bool,
+    /// Write the rotated secrets to this new owner-only file instead
+    #[arg(long, requires = "rotate_secrets")]
+    output: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Probe a running instance's readiness
+    Healthcheck(HealthcheckOptions),
+    /// Generate a validated configuration file
+    Init(InitOptions),
+    /// Fill the state store with generated users, audit events and usage
+    DemoData(DemoOptions),
+    /// Read a value on stdin and print it as an `enc:` value
+    EncryptValue,
+    /// Start the server with the state store loaded from a snapshot taken by `POST /admin/backup`
+    Restore {
+        /// Snapshot file, validated before anything starts
+        snapshot: PathBuf,
+    },
+}
+
+impl Cli {
+    /// Variables set by `--port`, `--app-port`, `--bind` and `--log-level`
+    fn overrides(&self) -> FlagOverrides {
+        let mut values = Vec::new();
+        if let Some(port) = self.port {
+            values.push(("PORT", port.to_string()));
+        }
+        if let Some(port) = self.app_port {
+            values.push(("PORT_APP", port.to_string()));
+        }
+        if let Some(bind) = &self.bind {
+            values.push(("BIND_ADDRESS", bind.clone()));
+        }
+        if let Some(filter) = &self.log_level {
+            values.push(("LOG_LEVEL", filter.clone()));
+        }
+        FlagOverrides(values)
+    }
+}
+
+/// Settings given as flags, the [`ConfigSource::Flag`] layer
+struct FlagOverrides(Vec<(&'static str, String)>);
+
+impl SecretProvider for FlagOverrides {
+    fn secret(&self, name: &str) -> Option<String> {
+        self.0.iter().find(|(flag, _)| *flag == name).map(|(_, value)| value.clone())
+    }
+}
 
 /// Entry point for the simple API demo application.
 /// 
 /// This application starts two HTTP servers:
 /// - Main server: Simple hello world endpoint  
 /// - Application server: Multiple endpoints with JSON responses
-#[actix_web::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+/// 
+/// The async runtime is started by hand rather than with `#[actix_web::main]`
+/// so that `--daemon` can fork before any runtime thread exists.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    // Variables of the dotenv file fill in those not exported, `RUST_LOG`
+    // included, before the logger or any thread starts
+    let env_file = match cli.no_env_file {
+        true => None,
+        false => config::load_env_file(cli.env_file.as_deref())?,
+    };
+
+    // Initialize logging; email addresses are masked in every line, and the
+    // filter follows `LOG_LEVEL` once the configuration is loaded
+    reload::init_logger(cli.log_level.as_deref())?;
+    if let Some(path) = env_file {
+        log::info!("Loaded environment variables from {}", path.display());
+    }
 
-    // Load configuration
-    let config = Config::from_env()
+    match &cli.command {
+        Some(Command::Healthcheck(options)) => return actix_web::rt::System::new().block_on(run_healthcheck(options)),
+        Some(Command::Init(options)) => return run_init(options.clone()),
+        Some(Command::DemoData(options)) => return run_demo_data(&cli, options),
+        Some(Command::EncryptValue) => return encrypt_value(),
+        Some(Command::Restore { .. }) | None => {}
+    }
+    if cli.rotate_secrets {
+        return rotate_secrets(cli.output.as_deref());
+    }
+    let snapshot = match &cli.command {
+        Some(Command::Restore { snapshot }) => Some(Snapshot::read(snapshot)?),
+        _ => None,
+    };
+
+    let Layers {
+        layers,
+        config_file,
+        remote,
+        vault,
+    } = load_layers(&cli)?;
+    let config = layers
+        .resolve()
         .map_err(|e| AppError::config(format!("Failed to load configuration: {}", e)))?;
-    
-    // Create and start server manager
-    let server_manager = ServerManager::new(config);
-    server_manager.start().await
-        .map_err(|e| AppError::server(format!("Failed to start servers: {}", e)))?;
+    reload::set_log_format(config.log_format);
+    reload::set_log_filter(config.log_level.as_deref().unwrap_or(""));
+    for (name, source) in config.sources.iter().filter(|(_, source)| *source != ConfigSource::Default) {
+        log::debug!("{} set by the {}", name, source);
+    }
+    let watch = config.config_watch;
+
+    let daemon = DaemonOptions {
+        detach: cli.daemon,
+        pidfile: cli.pidfile,
+        log_file: cli.log_file,
+        service: cli.service,
+        stop_timeout: Duration::from_secs(config.server_shutdown_timeout_secs + config.event_bus_drain_timeout_secs),
+    };
+    daemon.detach()?;
+    let _pidfile = daemon.pidfile.as_deref().map(PidFile::create).transpose()?;
+
+    // Create and start server manager, stopped by the service control manager under `--service`
+    daemon.run(move |shutdown| {
+        let mut server_manager = ServerManager::builder(config);
+        if let Some(vault) = vault {
+            server_manager = server_manager.vault(vault);
+        }
+        if let Some(snapshot) = snapshot {
+            server_manager = server_manager.restore(snapshot);
+        }
+        if let Some(remote) = remote.filter(|_| watch) {
+            server_manager = server_manager.watch_remote_config(remote, layers.clone());
+        }
+        if watch && config_file.is_some() {
+            server_manager = server_manager.watch_config(layers);
+        }
+        if let Some(trigger) = shutdown {
+            server_manager = server_manager.stop_on(trigger);
+        }
+        let server_manager = server_manager.build();
+        actix_web::rt::System::new()
+            .block_on(server_manager.start())
+            .map_err(|e| AppError::server(format!("Failed to start servers: {}", e)))
+    })?;
+
+    Ok(())
+}
 
+/// Configuration layers with the providers the server keeps using
+struct Layers {
+    layers: ConfigLayers,
+    config_file: Option<PathBuf>,
+    remote: Option<Arc<RemoteConfig>>,
+    vault: Option<Arc<VaultProvider>>,
+}
+
+/// Builds the configuration layers shared by the server and `demo-data`
+///
+/// Layers by precedence: defaults, the TOML or YAML file named by
+/// `--config PATH` or `CONFIG_FILE`, Consul or etcd keys under
+/// `REMOTE_CONFIG_PREFIX`, the environment, secrets mapped in
+/// `VAULT_SECRETS` and `aws-sm://`/`ssm://` values, then flags. The runtime
+/// reading remote keys and secrets, and its threads, are gone again before
+/// `--daemon` forks.
+fn load_layers(cli: &Cli) -> Result<Layers, Box<dyn std::error::Error>> {
+    let config_file = cli.config.clone().or_else(|| std::env::var_os("CONFIG_FILE").map(PathBuf::from));
+    let mut layers = ConfigLayers::new().environment().layer(ConfigSource::Flag, Arc::new(cli.overrides()));
+    if let Some(path) = &config_file {
+        layers = layers.file(path);
+    }
+    let remote = RemoteConfig::from_env()?.map(Arc::new);
+    let vault = VaultProvider::from_env()?.map(Arc::new);
+    let aws = AwsSecrets::from_env()?;
+    if remote.is_some() || vault.is_some() || aws.is_some() {
+        let runtime = actix_web::rt::System::new();
+        if let Some(remote) = &remote {
+            runtime
+                .block_on(remote.load())
+                .map_err(|e| AppError::config(format!("Failed to load remote configuration: {}", e)))?;
+            layers = layers.layer(ConfigSource::Remote, remote.clone());
+        }
+        if let Some(vault) = &vault {
+            runtime
+                .block_on(vault.load())
+                .map_err(|e| AppError::config(format!("Failed to load secrets from Vault: {}", e)))?;
+            layers = layers.layer(ConfigSource::SecretStore, vault.clone());
+        }
+        if let Some(mut aws) = aws {
+            runtime
+                .block_on(aws.load())
+                .map_err(|e| AppError::config(format!("Failed to resolve secrets from AWS: {}", e)))?;
+            layers = layers.layer(ConfigSource::SecretStore, Arc::new(aws));
+        }
+    }
+    Ok(Layers {
+        layers,
+        config_file,
+        remote,
+        vault,
+    })
+}
+
+/// Probes a running instance's readiness and exits 0 (ready) or 1
+/// 
+/// `healthcheck [--url URL] [--timeout SECS]` replaces curl in Docker
+/// `HEALTHCHECK`s and CI gates.
+async fn run_healthcheck(options: &HealthcheckOptions) -> Result<(), Box<dyn std::error::Error>> {
+    if let Err(e) = healthcheck::check(options).await {
+        eprintln!("unhealthy: {}", e);
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+/// Generates a commented configuration file (and optionally a `.env`)
+/// 
+/// Prompts for every feature not chosen with a flag, unless stdin is not a
+/// terminal or `--non-interactive` is given.
+fn run_init(mut options: InitOptions) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::IsTerminal;
+
+    options.non_interactive |= !std::io::stdin().is_terminal();
+    let written = init::run(&options, &mut std::io::stdin().lock(), &mut std::io::stderr())?;
+    for path in written {
+        eprintln!("Wrote {} (mode 0600)", path.display());
+    }
+    Ok(())
+}
+
+/// Generates demo data into the configured state store and prints the report
+/// 
+/// The configuration is resolved from the same layers as the server's, so
+/// the data lands in the store the server reads. Only useful with
+/// `STATE_MODE=distributed`: process-local state would be gone once the
+/// command exits.
+fn run_demo_data(cli: &Cli, options: &DemoOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_layers(cli)?
+        .layers
+        .resolve()
+        .map_err(|e| AppError::config(format!("Failed to load configuration: {}", e)))?;
+    let report = demo_data::run(&config, options)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Encrypts the value read on stdin with the `CONFIG_MASTER_KEY`
+/// 
+/// Prints the `enc:` value to put in the environment or a configuration
+/// file in place of the plaintext; a single trailing newline is dropped.
+fn encrypt_value() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+
+    let master_key = crypto::config_master_key(&|name| std::env::var(name).ok())?
+        .ok_or_else(|| AppError::config("encrypt-value requires CONFIG_MASTER_KEY or CONFIG_MASTER_KEY_FILE"))?;
+    let mut value = String::new();
+    std::io::stdin().read_to_string(&mut value)?;
+    let value = value.strip_suffix('\n').map(|value| value.strip_suffix('\r').unwrap_or(value)).unwrap_or(&value);
+    println!("{}", crypto::encrypt_config_value(&master_key, value)?);
+    Ok(())
+}
+
+/// Generates replacements for every secret variable
+/// 
+/// Without `--output` the values go to stdout in `.env` format (logs go to
+/// stderr, so the output can be redirected safely). With `--output FILE` they
+/// are written to a new owner-only file instead of the terminal.
+fn rotate_secrets(output: Option<&Path>) -> Result<(), Box<dyn std::error::Error>> {
+    match output {
+        Some(path) => {
+            secrets::write_rotated_env(path)?;
+            eprintln!("Wrote rotated secrets to {} (mode 0600)", path.display());
+        }
+        None => print!("{}", secrets::rotated_env()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_flags() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["simple-api-demo", "--port", "9000", "--bind", "127.0.0.1", "--config", "app.toml"]).unwrap();
+        let overrides = cli.overrides();
+        assert_eq!(overrides.secret("PORT"), Some("9000".to_string()));
+        assert_eq!(overrides.secret("PORT_APP"), None);
+        assert_eq!(overrides.secret("BIND_ADDRESS"), Some("127.0.0.1".to_string()));
+        assert_eq!(cli.config, Some(PathBuf::from("app.toml")));
+
+        let cli = Cli::try_parse_from(["simple-api-demo", "healthcheck", "--url", "http://app:8080/ready"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Healthcheck(options)) if options.url() == "http://app:8080/ready"));
+        assert!(Cli::try_parse_from(["simple-api-demo", "healthcheck", "--timeout", "soon"]).is_err());
+        assert!(Cli::try_parse_from(["simple-api-demo", "demo-data", "--scenaro", "large"]).is_err());
+        let cli = Cli::try_parse_from(["simple-api-demo", "demo-data", "--scenario", "large", "--seed", "7"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::DemoData(options)) if options.seed == Some(7)));
+        let cli = Cli::try_parse_from(["simple-api-demo", "--port", "9000", "restore", "backups/snapshot.json"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Restore { snapshot }) if snapshot == Path::new("backups/snapshot.json")));
+        assert!(Cli::try_parse_from(["simple-api-demo", "--log-file", "out.log"]).is_err());
+        assert!(Cli::try_parse_from(["simple-api-demo", "--service", "--daemon"]).is_err());
+        assert!(Cli::try_parse_from(["simple-api-demo", "--env-file", "dev.env", "--no-env-file"]).is_err());
+        let cli = Cli::try_parse_from(["simple-api-demo", "init", "--env-file", "--non-interactive"]).unwrap();
+        assert!(cli.env_file.is_none());
+        assert!(matches!(cli.command, Some(Command::Init(options)) if options.env_file.is_some() && options.non_interactive));
+        assert!(Cli::try_parse_from(["simple-api-demo", "--port", "http"]).is_err());
+    }
+}
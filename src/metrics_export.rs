@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::Serialize;
+
+use crate::analytics::AnalyticsPipeline;
+use crate::clock::ClockCheck;
+use crate::config::Config;
+use crate::degradation::DegradationPolicy;
+use crate::egress::EgressLimiter;
+use crate::error::{AppError, AppResult};
+use crate::event_bus::EventBus;
+use crate::histogram::HistogramSnapshot;
+use crate::region::Placement;
+use crate::supervisor::Supervisor;
+
+/// Scope required to read `GET /admin/metrics.json`
+pub const METRICS_SCOPE: &str = "admin:metrics";
+
+/// One value of a metric, told apart from the others of the same name by its labels
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Sample<T> {
+    pub name: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<&'static str, String>,
+    pub value: T,
+}
+
+impl<T> Sample<T> {
+    fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            labels: BTreeMap::new(),
+            value,
+        }
+    }
+
+    fn label(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.labels.insert(name, value.into());
+        self
+    }
+}
+
+/// Every metric of the instance at one point in time, as answered by
+/// `GET /admin/metrics.json` and appended to `METRICS_EXPORT_FILE`
+///
+/// Names follow the OpenMetrics exposition of `/metrics`, so a snapshot
+/// can be loaded into the same dashboards later.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    pub taken_at: DateTime<Utc>,
+    /// `region` and `zone` of the instance, when set
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<&'static str, String>,
+    /// Totals since the process started
+    pub counters: Vec<Sample<u64>>,
+    /// Values that go up and down
+    pub gauges: Vec<Sample<f64>>,
+    pub histograms: Vec<Sample<HistogramSnapshot>>,
+}
+
+/// Gathers the counters, gauges and histograms `/metrics` reports into a
+/// [`MetricsSnapshot`]
+///
+/// Only the request counters and latency histogram are always there; the
+/// other sources are added when the components exist.
+pub struct MetricsExporter {
+    pipeline: Arc<AnalyticsPipeline>,
+    supervisor: Option<Arc<Supervisor>>,
+    events: Option<Arc<EventBus>>,
+    degradation: Option<Arc<DegradationPolicy>>,
+    egress: Option<Arc<EgressLimiter>>,
+    placement: Option<Arc<Placement>>,
+    clock: Option<Arc<ClockCheck>>,
+}
+
+impl MetricsExporter {
+    /// Creates the exporter of `pipeline`'s request metrics
+    pub fn new(pipeline: Arc<AnalyticsPipeline>) -> Self {
+        Self {
+            pipeline,
+            supervisor: None,
+            events: None,
+            degradation: None,
+            egress: None,
+            placement: None,
+            clock: None,
+        }
+    }
+
+    /// Adds the restarts and failures of the supervised tasks
+    pub fn with_supervisor(mut self, supervisor: Arc<Supervisor>) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    /// Adds the per-topic event bus counters
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Adds how degradable routes were served
+    pub fn with_degradation(mut self, degradation: Option<Arc<DegradationPolicy>>) -> Self {
+        self.degradation = degradation;
+        self
+    }
+
+    /// Adds the outbound deliveries per destination
+    pub fn with_egress(mut self, egress: Option<Arc<EgressLimiter>>) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    /// Adds the region and zone labels and the affinity mismatches
+    pub fn with_placement(mut self, placement: Arc<Placement>) -> Self {
+        self.placement = Some(placement);
+        self
+    }
+
+    /// Adds the clock skew found by the last clock check
+    pub fn with_clock(mut self, clock: Option<Arc<ClockCheck>>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Copies every metric now
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let operational = self.pipeline.operational();
+        let mut counters = vec![
+            Sample::new("http_requests_total", operational.requests_total),
+            Sample::new("http_server_errors_total", operational.server_errors),
+            Sample::new("analytics_excluded_requests_total", operational.analytics_excluded),
+        ];
+        let mut gauges = Vec::new();
+        let mut labels = BTreeMap::new();
+
+        if let Some(supervisor) = &self.supervisor {
+            for (task, health) in supervisor.health() {
+                counters.push(Sample::new("task_restarts_total", health.restarts).label("task", &task));
+                gauges.push(Sample::new("task_consecutive_failures", f64::from(health.consecutive_failures)).label("task", task));
+            }
+        }
+        if let Some(events) = &self.events {
+            for (topic, stats) in events.stats() {
+                counters.push(Sample::new("event_bus_published_total", stats.published).label("topic", &topic));
+                counters.push(Sample::new("event_bus_dropped_total", stats.dropped).label("topic", &topic));
+                gauges.push(Sample::new("event_bus_subscribers", stats.subscribers as f64).label("topic", topic));
+            }
+        }
+        if let Some(degradation) = &self.degradation {
+            for (route, counts) in degradation.stats() {
+                for (outcome, value) in [("fresh", counts.fresh), ("stale", counts.stale), ("unavailable", counts.unavailable)] {
+                    counters.push(
+                        Sample::new("degradable_responses_total", value)
+                            .label("route", &route)
+                            .label("outcome", outcome),
+                    );
+                }
+            }
+        }
+        if let Some(egress) = &self.egress {
+            for (destination, counts) in egress.stats() {
+                for (outcome, value) in [("sent", counts.sent), ("deferred", counts.deferred), ("dropped", counts.dropped)] {
+                    counters.push(
+                        Sample::new("egress_deliveries_total", value)
+                            .label("destination", &destination)
+                            .label("outcome", outcome),
+                    );
+                }
+                gauges.push(Sample::new("egress_queued", counts.queued as f64).label("destination", destination));
+            }
+        }
+        if let Some(placement) = &self.placement {
+            counters.push(Sample::new("region_affinity_mismatches_total", placement.affinity_mismatches()));
+            for (name, value) in [("region", &placement.region), ("zone", &placement.zone)] {
+                if let Some(value) = value {
+                    labels.insert(name, value.clone());
+                }
+            }
+        }
+        if let Some(reading) = self.clock.as_ref().and_then(|clock| clock.status().last) {
+            gauges.push(Sample::new("clock_skew_seconds", reading.skew_secs));
+        }
+
+        MetricsSnapshot {
+            taken_at: Utc::now(),
+            labels,
+            counters,
+            gauges,
+            histograms: vec![Sample::new("http_request_duration_seconds", self.pipeline.latency())],
+        }
+    }
+}
+
+/// Appends a [`MetricsSnapshot`] per line to `METRICS_EXPORT_FILE` every
+/// `METRICS_EXPORT_INTERVAL_SECS`, for environments without a scraper
+///
+/// Once the file would grow past `METRICS_EXPORT_MAX_BYTES` it is renamed
+/// with a `.1` suffix, older files shifting to `.2` and so on, and the
+/// oldest beyond `METRICS_EXPORT_FILES` is deleted.
+pub struct MetricsLog {
+    exporter: Arc<MetricsExporter>,
+    path: PathBuf,
+    max_bytes: u64,
+    files: usize,
+}
+
+impl MetricsLog {
+    /// Creates the log of `exporter`'s snapshots at `path`, keeping `files`
+    /// rotated files of up to `max_bytes`
+    pub fn new(exporter: Arc<MetricsExporter>, path: impl Into<PathBuf>, max_bytes: u64, files: usize) -> Self {
+        Self {
+            exporter,
+            path: path.into(),
+            max_bytes,
+            files,
+        }
+    }
+
+    /// Builds the log from `METRICS_EXPORT_FILE`, `METRICS_EXPORT_MAX_BYTES`
+    /// and `METRICS_EXPORT_FILES`; `None` when no file is set
+    pub fn from_config(config: &Config, exporter: Arc<MetricsExporter>) -> Option<Self> {
+        let path = config.metrics_export_file.as_deref()?;
+        Some(Self::new(exporter, path, config.metrics_export_max_bytes, config.metrics_export_files))
+    }
+
+    /// Appends a snapshot, rotating the file first when it is full
+    ///
+    /// # Errors
+    /// Internal error when the file cannot be rotated or written
+    pub fn append(&self) -> AppResult<()> {
+        let mut line = serde_json::to_vec(&self.exporter.snapshot()).map_err(|e| AppError::internal(e.to_string()))?;
+        line.push(b'\n');
+        let size = fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .map_err(|e| AppError::internal(format!("failed to write {}: {}", self.path.display(), e)))
+    }
+
+    fn rotate(&self) -> AppResult<()> {
+        let failed = |e: std::io::Error| AppError::internal(format!("failed to rotate {}: {}", self.path.display(), e));
+        for index in (1..self.files).rev() {
+            let older = rotated(&self.path, index);
+            if older.exists() {
+                fs::rename(&older, rotated(&self.path, index + 1)).map_err(failed)?;
+            }
+        }
+        match self.files {
+            0 => fs::remove_file(&self.path).map_err(failed),
+            _ => fs::rename(&self.path, rotated(&self.path, 1)).map_err(failed),
+        }
+    }
+
+    /// Appends a snapshot every `every` under `supervisor`
+    pub fn spawn_scheduler(log: Arc<Self>, every: Duration, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("metrics_export", move || {
+            let log = log.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(every);
+                loop {
+                    interval.tick().await;
+                    let log = log.clone();
+                    let result = actix_web::web::block(move || log.append()).await;
+                    if let Err(e) = result.map_err(|e| AppError::internal(e.to_string())).and_then(|result| result) {
+                        error!("Metrics export failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// `path` with `.{index}` appended to its file name
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", index));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exporter() -> (Arc<MetricsExporter>, Arc<AnalyticsPipeline>) {
+        let pipeline = Arc::new(AnalyticsPipeline::new(None));
+        let exporter = MetricsExporter::new(pipeline.clone())
+            .with_placement(Arc::new(Placement::new(Some("eu-west-1".to_string()), None)));
+        (Arc::new(exporter), pipeline)
+    }
+
+    #[test]
+    fn test_snapshot_classifies_metrics() {
+        let (exporter, pipeline) = exporter();
+        pipeline.observe_latency(Duration::from_millis(30), None);
+
+        let snapshot = exporter.snapshot();
+        assert_eq!(snapshot.labels.get("region").map(String::as_str), Some("eu-west-1"));
+        let names: Vec<&str> = snapshot.counters.iter().map(|sample| sample.name).collect();
+        assert!(names.contains(&"http_requests_total"));
+        assert!(names.contains(&"region_affinity_mismatches_total"));
+        assert_eq!(snapshot.histograms[0].name, "http_request_duration_seconds");
+        assert_eq!(snapshot.histograms[0].value.count, 1);
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["counters"][0], serde_json::json!({ "name": "http_requests_total", "value": 0 }));
+    }
+
+    #[test]
+    fn test_log_appends_lines_and_rotates() {
+        let dir = std::env::temp_dir().join(format!("metrics-export-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.ndjson");
+        let (exporter, _) = exporter();
+        let line_len = serde_json::to_vec(&exporter.snapshot()).unwrap().len() as u64 + 1;
+        // Room for two lines per file, give or take the timestamp width
+        let log = MetricsLog::new(exporter, &path, line_len * 2 + 8, 2);
+
+        for _ in 0..2 {
+            log.append().unwrap();
+        }
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        let line: serde_json::Value = serde_json::from_str(content.lines().next().unwrap()).unwrap();
+        assert!(line["taken_at"].is_string());
+
+        for _ in 0..6 {
+            log.append().unwrap();
+        }
+        assert!(rotated(&path, 1).exists() && rotated(&path, 2).exists());
+        assert!(!rotated(&path, 3).exists());
+        assert_eq!(fs::read_to_string(rotated(&path, 1)).unwrap().lines().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
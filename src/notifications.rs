@@ -0,0 +1,1037 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::budgets::{Budget, Budgets};
+use crate::config::Config;
+use crate::deliveries::{DeliveryAttempt, DeliveryLog, DeliveryRecord, DeliveryTrigger};
+use crate::egress::EgressLimiter;
+use crate::error::{AppError, AppResult};
+use crate::events::CloudEvent;
+use crate::pii::{PiiFields, PiiKind};
+use crate::reload::LiveConfig;
+use crate::state::{InMemoryStore, KeyValueStore};
+use crate::stubs::{FaultProfile, StubNotifier, STUBBED_CHANNELS};
+use crate::subscriptions::{SubscriptionNotifier, SubscriptionRegistry, CHANNEL_PREFIX, TEST_EVENT_TYPE};
+use crate::supervisor::Supervisor;
+
+/// A message to deliver to one or more channels
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    /// Event type used for routing, e.g. `webhook.github`
+    pub event_type: String,
+    /// Short human-readable title
+    pub title: String,
+    /// Message body
+    pub message: String,
+    /// Structured details for machine consumers
+    pub data: Value,
+    /// Addressee for personal messages; channels without one use their default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+}
+
+impl PiiFields for Notification {
+    const PII_FIELDS: &'static [(&'static str, PiiKind)] = &[("recipient", PiiKind::Email)];
+}
+
+impl Notification {
+    /// Creates a notification without structured data
+    pub fn new<E: Into<String>, T: Into<String>, M: Into<String>>(
+        event_type: E,
+        title: T,
+        message: M,
+    ) -> Self {
+        Self {
+            event_type: event_type.into(),
+            title: title.into(),
+            message: message.into(),
+            data: Value::Null,
+            recipient: None,
+        }
+    }
+
+    /// Attaches structured data
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = data;
+        self
+    }
+
+    /// Addresses the notification to a specific recipient
+    pub fn with_recipient<T: Into<String>>(mut self, recipient: T) -> Self {
+        self.recipient = Some(recipient.into());
+        self
+    }
+}
+
+/// What a destination answered to one delivery
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeliveryResponse {
+    /// HTTP status, for HTTP channels
+    pub status: Option<u16>,
+    /// Response body
+    pub body: Option<String>,
+}
+
+/// A delivery channel (email, Slack, webhook, ...)
+///
+/// Implementations only deal with delivery; routing and rate limiting are
+/// handled once by [`NotificationRouter`].
+pub trait Notifier: Send + Sync {
+    /// Channel name referenced by routing rules
+    fn name(&self) -> &str;
+
+    /// Delivers a single notification
+    fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, AppResult<()>>;
+
+    /// Delivers a single notification and reports what the destination answered
+    ///
+    /// HTTP channels override it to report the response status and body,
+    /// including when the destination rejected the delivery.
+    fn deliver<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, (AppResult<()>, DeliveryResponse)> {
+        Box::pin(async move { (self.send(notification).await, DeliveryResponse::default()) })
+    }
+}
+
+/// Posts notifications to a Slack incoming-webhook URL
+pub struct SlackNotifier {
+    client: reqwest::Client,
+    webhook_url: String,
+}
+
+impl SlackNotifier {
+    /// Creates a Slack channel posting to `webhook_url`
+    pub fn new<T: Into<String>>(client: reqwest::Client, webhook_url: T) -> Self {
+        Self {
+            client,
+            webhook_url: webhook_url.into(),
+        }
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn name(&self) -> &str {
+        "slack"
+    }
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move { self.deliver(notification).await.0 })
+    }
+
+    fn deliver<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, (AppResult<()>, DeliveryResponse)> {
+        Box::pin(async move {
+            let body = json!({
+                "text": format!("*{}*\n{}", notification.title, notification.message)
+            });
+            post_json(&self.client, &self.webhook_url, &body).await
+        })
+    }
+}
+
+/// Posts notifications as CloudEvents to an HTTP endpoint
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookNotifier {
+    /// Creates a webhook channel posting to `url`
+    pub fn new<T: Into<String>>(client: reqwest::Client, url: T) -> Self {
+        Self {
+            client,
+            url: url.into(),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move { self.deliver(notification).await.0 })
+    }
+
+    fn deliver<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, (AppResult<()>, DeliveryResponse)> {
+        Box::pin(async move {
+            match encode_event(notification) {
+                Ok(body) => post_json(&self.client, &self.url, &body).await,
+                Err(e) => (Err(e), DeliveryResponse::default()),
+            }
+        })
+    }
+}
+
+/// Writes notifications as RFC 5322 messages into a mail pickup directory
+///
+/// Any MTA or relay watching the directory (Postfix pickup, MailHog, a
+/// sidecar) delivers them, which keeps SMTP handling out of the service.
+pub struct EmailNotifier {
+    outbox: PathBuf,
+    from: String,
+    to: String,
+}
+
+impl EmailNotifier {
+    /// Creates an email channel writing to `outbox`
+    pub fn new<P: Into<PathBuf>, F: Into<String>, T: Into<String>>(outbox: P, from: F, to: T) -> Self {
+        Self {
+            outbox: outbox.into(),
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+
+    /// Renders the message written to the pickup directory
+    fn render(&self, notification: &Notification) -> String {
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+            self.from,
+            notification.recipient.as_deref().unwrap_or(&self.to),
+            notification.title,
+            chrono::Utc::now().to_rfc2822(),
+            notification.message
+        )
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            let path = self.outbox.join(format!("{}.eml", uuid::Uuid::new_v4()));
+            let write_error =
+                |e: std::io::Error| AppError::internal(format!("Failed to write email to {}: {}", path.display(), e));
+            tokio::fs::create_dir_all(&self.outbox).await.map_err(write_error)?;
+            tokio::fs::write(&path, self.render(notification))
+                .await
+                .map_err(write_error)
+        })
+    }
+}
+
+/// Channel that records notifications in memory for assertions in tests
+#[derive(Default, Clone)]
+pub struct RecordingNotifier {
+    name: String,
+    sent: Arc<Mutex<Vec<Notification>>>,
+}
+
+impl RecordingNotifier {
+    /// Creates a recording channel registered under `name`
+    pub fn new<T: Into<String>>(name: T) -> Self {
+        Self {
+            name: name.into(),
+            sent: Arc::default(),
+        }
+    }
+
+    /// Returns every notification delivered so far
+    pub fn sent(&self) -> Vec<Notification> {
+        self.sent.lock().map(|sent| sent.clone()).unwrap_or_default()
+    }
+}
+
+impl Notifier for RecordingNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            self.sent
+                .lock()
+                .map_err(|_| AppError::internal("recording notifier lock poisoned"))?
+                .push(notification.clone());
+            Ok(())
+        })
+    }
+}
+
+/// Wraps `notification` in the CloudEvent posted by webhook channels
+pub(crate) fn encode_event(notification: &Notification) -> AppResult<Value> {
+    let data = serde_json::to_value(notification)
+        .map_err(|e| AppError::internal(format!("Failed to encode notification: {}", e)))?;
+    let event = CloudEvent::new(
+        format!("com.simple-api-demo.notification.{}", notification.event_type),
+        data,
+    );
+    serde_json::to_value(&event).map_err(|e| AppError::internal(format!("Failed to encode event: {}", e)))
+}
+
+async fn post_json(client: &reqwest::Client, url: &str, body: &Value) -> (AppResult<()>, DeliveryResponse) {
+    send_request(client.post(url).json(body), url).await
+}
+
+/// Sends a delivery request to `url`, failing on non-2xx answers
+pub(crate) async fn send_request(request: reqwest::RequestBuilder, url: &str) -> (AppResult<()>, DeliveryResponse) {
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            let error = AppError::internal(format!("Notification delivery to {} failed: {}", url, e));
+            return (Err(error), DeliveryResponse::default());
+        }
+    };
+    let status = response.status();
+    let answer = DeliveryResponse {
+        status: Some(status.as_u16()),
+        body: response.text().await.ok().filter(|body| !body.is_empty()),
+    };
+    if !status.is_success() {
+        let error = AppError::internal(format!("Notification delivery to {} returned {}", url, status));
+        return (Err(error), answer);
+    }
+    (Ok(()), answer)
+}
+
+/// Maps an event type pattern to the channels that receive it
+///
+/// Patterns are exact event types, a prefix ending in `*` (`webhook.*`), or
+/// `*` alone for everything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutingRule {
+    pub pattern: String,
+    pub channels: Vec<String>,
+}
+
+impl RoutingRule {
+    fn matches(&self, event_type: &str) -> bool {
+        pattern_matches(&self.pattern, event_type)
+    }
+
+    /// Parses `pattern=channel,channel;pattern=channel`
+    ///
+    /// # Errors
+    /// Returns a configuration error for rules without `=`
+    pub fn parse_rules(spec: &str) -> AppResult<Vec<RoutingRule>> {
+        spec.split(';')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let (pattern, channels) = rule.split_once('=').ok_or_else(|| {
+                    AppError::config(format!("notification route '{}' must look like 'pattern=channel,...'", rule))
+                })?;
+                Ok(RoutingRule {
+                    pattern: pattern.trim().to_string(),
+                    channels: channels
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|c| !c.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Whether `event_type` matches a routing pattern: an exact event type, a
+/// prefix ending in `*` or `*` alone
+pub(crate) fn pattern_matches(pattern: &str, event_type: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => event_type.starts_with(prefix),
+        None => pattern == event_type,
+    }
+}
+
+/// Routes notifications to channels with per-channel rate limiting
+///
+/// Callers only ever call [`NotificationRouter::notify`]; adding a channel
+/// means registering a [`Notifier`] and referencing it in a routing rule.
+/// Every delivery runs within the `WEBHOOK_*` budget, after taking a token
+/// from the channel's [`EgressLimiter`] bucket, and is kept in the
+/// [`DeliveryLog`] when one is set so it can be inspected and sent again.
+/// Notifications also go to the matching enabled webhook subscriptions of
+/// the [`SubscriptionRegistry`], each a channel of its own; subscriptions
+/// asking for batches collect them in memory until the batch is full or
+/// [`NotificationRouter::flush_batches`] finds it due.
+pub struct NotificationRouter {
+    channels: HashMap<String, Arc<dyn Notifier>>,
+    rules: Vec<RoutingRule>,
+    max_per_minute: u64,
+    counters: Arc<dyn KeyValueStore>,
+    live: Option<Arc<LiveConfig>>,
+    budget: Budget,
+    egress: Arc<EgressLimiter>,
+    deliveries: Option<DeliveryLog>,
+    subscriptions: Option<Arc<SubscriptionRegistry>>,
+    batches: Mutex<HashMap<String, PendingBatch>>,
+}
+
+/// Notifications waiting to be delivered together to one subscription
+struct PendingBatch {
+    notifications: Vec<Notification>,
+    due: Instant,
+}
+
+/// Deliveries sent per replay call
+pub const MAX_REPLAY: usize = 100;
+
+/// How often pending subscription batches are checked for being due
+const BATCH_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Outcome of a replay
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ReplayReport {
+    /// Ids of the deliveries that went through
+    pub delivered: Vec<String>,
+    /// Ids of the deliveries that failed again
+    pub failed: Vec<String>,
+    /// Matching deliveries left for a later call
+    pub remaining: usize,
+}
+
+/// Outcome of routing one notification
+#[derive(Debug, Default, PartialEq)]
+pub struct DeliveryReport {
+    pub delivered: Vec<String>,
+    pub rate_limited: Vec<String>,
+    pub failed: Vec<String>,
+    /// Subscriptions holding the notification in a batch not sent yet
+    pub batched: Vec<String>,
+}
+
+impl NotificationRouter {
+    /// Creates a router with no channels
+    ///
+    /// # Arguments
+    /// * `max_per_minute` - Per-channel delivery cap (0 disables limiting)
+    /// * `counters` - Store holding the rate limit counters
+    pub fn new(max_per_minute: u64, counters: Arc<dyn KeyValueStore>) -> Self {
+        Self {
+            channels: HashMap::new(),
+            rules: Vec::new(),
+            max_per_minute,
+            counters,
+            live: None,
+            budget: Budgets::default().webhook,
+            egress: Arc::new(EgressLimiter::default()),
+            deliveries: None,
+            subscriptions: None,
+            batches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Builds the router from the `NOTIFY_*` settings in the configuration
+    pub fn from_config(config: &Config, counters: Arc<dyn KeyValueStore>) -> AppResult<Self> {
+        let budget = Budgets::from_config(config).webhook;
+        let client = reqwest::Client::builder()
+            .timeout(budget.timeout)
+            .build()
+            .map_err(|e| AppError::config(format!("Failed to build HTTP client: {}", e)))?;
+
+        let mut router = Self::new(config.notify_rate_limit_per_minute, counters)
+            .with_budget(budget)
+            .with_egress(Arc::new(EgressLimiter::from_config(config)));
+        if config.stub_dependencies {
+            // Every channel exists, none of them reaches the outside world
+            for name in STUBBED_CHANNELS {
+                router = router.with_channel(StubNotifier::new(*name, FaultProfile::from_config(config)));
+            }
+        } else {
+            if let Some(url) = &config.notify_slack_webhook_url {
+                router = router.with_channel(SlackNotifier::new(client.clone(), url));
+            }
+            if let Some(url) = &config.notify_webhook_url {
+                router = router.with_channel(WebhookNotifier::new(client.clone(), url));
+            }
+            if let (Some(outbox), Some(to)) = (&config.notify_email_outbox, &config.notify_email_to) {
+                router = router.with_channel(EmailNotifier::new(outbox, "simple-api-demo@localhost", to));
+            }
+        }
+
+        // Without explicit rules every configured channel receives everything
+        let rules = match &config.notify_routes {
+            Some(spec) => RoutingRule::parse_rules(spec)?,
+            None => vec![RoutingRule {
+                pattern: "*".to_string(),
+                channels: router.channels.keys().cloned().collect(),
+            }],
+        };
+        Ok(router.with_rules(rules))
+    }
+
+    /// Registers a channel under its own name
+    pub fn with_channel(mut self, notifier: impl Notifier + 'static) -> Self {
+        self.channels.insert(notifier.name().to_string(), Arc::new(notifier));
+        self
+    }
+
+    /// Reads the per-channel cap from `live`, so reloads of
+    /// `NOTIFY_RATE_LIMIT_PER_MINUTE` apply to the next delivery
+    pub fn with_live_config(mut self, live: Arc<LiveConfig>) -> Self {
+        self.live = Some(live);
+        self
+    }
+
+    /// Sets the timeout and retries of each delivery
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Sets the limiter smoothing deliveries per channel
+    pub fn with_egress(mut self, egress: Arc<EgressLimiter>) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    /// Returns the limiter smoothing deliveries per channel
+    pub fn egress(&self) -> &Arc<EgressLimiter> {
+        &self.egress
+    }
+
+    /// Keeps every delivery and its attempts in `log`
+    pub fn with_deliveries(mut self, log: DeliveryLog) -> Self {
+        self.deliveries = Some(log);
+        self
+    }
+
+    /// Returns the delivery history
+    ///
+    /// # Errors
+    /// Not found when `WEBHOOK_DELIVERY_RETENTION_SECS` disabled it
+    pub fn deliveries(&self) -> AppResult<&DeliveryLog> {
+        self.deliveries
+            .as_ref()
+            .ok_or_else(|| AppError::not_found("delivery history is not enabled"))
+    }
+
+    /// Delivers notifications to the subscriptions of `registry` too
+    pub fn with_subscriptions(mut self, registry: Arc<SubscriptionRegistry>) -> Self {
+        self.subscriptions = Some(registry);
+        self
+    }
+
+    /// Appends routing rules
+    pub fn with_rules(mut self, rules: Vec<RoutingRule>) -> Self {
+        self.rules.extend(rules);
+        self
+    }
+
+    /// Returns the channel names matching `event_type`, deduplicated in rule order
+    pub fn channels_for(&self, event_type: &str) -> Vec<String> {
+        let mut channels: Vec<String> = Vec::new();
+        for rule in self.rules.iter().filter(|rule| rule.matches(event_type)) {
+            for channel in &rule.channels {
+                if !channels.contains(channel) {
+                    channels.push(channel.clone());
+                }
+            }
+        }
+        channels
+    }
+
+    /// Delivers `notification` to every routed channel
+    ///
+    /// Delivery failures are logged and reported, never propagated, so a
+    /// broken channel cannot fail the operation that triggered the message.
+    /// Deliveries deferred by the egress limiter hold back the caller;
+    /// those it drops are reported as rate limited.
+    pub async fn notify(&self, notification: &Notification) -> DeliveryReport {
+        let mut report = DeliveryReport::default();
+        let mut targets: Vec<(String, Option<Arc<dyn Notifier>>)> = self
+            .channels_for(&notification.event_type)
+            .into_iter()
+            .map(|name| {
+                let channel = self.channels.get(&name).cloned();
+                (name, channel)
+            })
+            .collect();
+        let mut batched = Vec::new();
+        if let Some(subscriptions) = &self.subscriptions {
+            match subscriptions.matching(notification) {
+                Ok(notifiers) => {
+                    for notifier in notifiers {
+                        if notifier.subscription().batch.is_some() {
+                            batched.push(notifier);
+                        } else {
+                            targets.push((notifier.name().to_string(), Some(Arc::new(notifier) as Arc<dyn Notifier>)));
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to load webhook subscriptions: {}", e),
+            }
+        }
+        for (name, channel) in targets {
+            let Some(channel) = channel else {
+                warn!("Notification route references unknown channel '{}'", name);
+                report.failed.push(name);
+                continue;
+            };
+            if !self.acquire(&name) {
+                debug!("Channel '{}' rate limited, dropping {}", name, notification.event_type);
+                report.rate_limited.push(name);
+                continue;
+            }
+            if !self.egress.admit(&name).await {
+                report.rate_limited.push(name);
+                continue;
+            }
+            let attempt = self.attempt(DeliveryTrigger::Event, || channel.deliver(notification)).await;
+            if let Some(log) = &self.deliveries {
+                if let Err(e) = log.record(&name, notification, attempt.clone()) {
+                    warn!("Failed to record delivery via '{}': {}", name, e);
+                }
+            }
+            if attempt.ok {
+                report.delivered.push(name);
+            } else {
+                warn!("Notification via '{}' failed: {}", name, attempt.error.unwrap_or_default());
+                report.failed.push(name);
+            }
+        }
+        for notifier in batched {
+            let name = notifier.name().to_string();
+            match self.enqueue(&notifier, notification) {
+                Some(batch) => match self.send_batch(&notifier, &batch).await {
+                    Some(true) => report.delivered.push(name),
+                    Some(false) => report.failed.push(name),
+                    None => report.rate_limited.push(name),
+                },
+                None => report.batched.push(name),
+            }
+        }
+        report
+    }
+
+    /// Sends the pending batches that waited long enough, or all of them
+    /// with `force`, and returns how many went out
+    ///
+    /// Batches of subscriptions deleted or disabled meanwhile are discarded.
+    pub async fn flush_batches(&self, force: bool) -> usize {
+        let now = Instant::now();
+        let due: Vec<(String, Vec<Notification>)> = match self.batches.lock() {
+            Ok(mut batches) => {
+                let ids: Vec<String> = batches
+                    .iter()
+                    .filter(|(_, batch)| force || batch.due <= now)
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                ids.into_iter()
+                    .filter_map(|id| batches.remove(&id).map(|batch| (id, batch.notifications)))
+                    .collect()
+            }
+            Err(_) => return 0,
+        };
+        let Some(subscriptions) = &self.subscriptions else {
+            return 0;
+        };
+        let mut sent = 0;
+        for (id, notifications) in due {
+            match subscriptions.notifier(&id) {
+                Ok(Some(notifier)) if notifier.subscription().enabled => {
+                    self.send_batch(&notifier, &notifications).await;
+                    sent += 1;
+                }
+                Ok(_) => debug!("Discarding {} batched notifications of subscription {}", notifications.len(), id),
+                Err(e) => warn!("Failed to load webhook subscription {}: {}", id, e),
+            }
+        }
+        sent
+    }
+
+    /// Runs a supervised task sending the pending batches once they are due
+    pub fn spawn_batch_flusher(router: Arc<Self>, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("webhook_batcher", move || {
+            let router = router.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(BATCH_FLUSH_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    router.flush_batches(false).await;
+                }
+            }
+        });
+    }
+
+    /// Adds `notification` to the subscription's pending batch, returning
+    /// the batch once it is full
+    fn enqueue(&self, notifier: &SubscriptionNotifier, notification: &Notification) -> Option<Vec<Notification>> {
+        let settings = notifier.subscription().batch?;
+        let mut batches = self.batches.lock().ok()?;
+        let id = &notifier.subscription().id;
+        let batch = batches.entry(id.clone()).or_insert_with(|| PendingBatch {
+            notifications: Vec::new(),
+            due: Instant::now() + settings.max_wait(),
+        });
+        batch.notifications.push(notification.clone());
+        if batch.notifications.len() < settings.max_events {
+            return None;
+        }
+        batches.remove(id).map(|batch| batch.notifications)
+    }
+
+    /// Delivers a batch as one request, recording the attempt for each of
+    /// its notifications
+    ///
+    /// Returns whether it went through, `None` when rate limited.
+    async fn send_batch(&self, notifier: &SubscriptionNotifier, notifications: &[Notification]) -> Option<bool> {
+        let name = notifier.name();
+        if !self.acquire(name) || !self.egress.admit(name).await {
+            warn!("Channel '{}' rate limited, dropping a batch of {} notifications", name, notifications.len());
+            return None;
+        }
+        let attempt = self
+            .attempt(DeliveryTrigger::Event, || notifier.deliver_batch(notifications))
+            .await;
+        if let Some(log) = &self.deliveries {
+            for notification in notifications {
+                if let Err(e) = log.record(name, notification, attempt.clone()) {
+                    warn!("Failed to record delivery via '{}': {}", name, e);
+                }
+            }
+        }
+        if !attempt.ok {
+            warn!("Batch via '{}' failed: {}", name, attempt.error.unwrap_or_default());
+        }
+        Some(attempt.ok)
+    }
+
+    /// Sends the recorded delivery `id` again through its channel
+    ///
+    /// The egress limiter applies, the per-minute cap does not: the caller
+    /// asked for this one.
+    ///
+    /// # Errors
+    /// Not found without delivery history, for an unknown or expired
+    /// delivery or a channel no longer configured; rate limited when the
+    /// egress limiter drops the delivery
+    pub async fn retry(&self, id: &str) -> AppResult<DeliveryRecord> {
+        let log = self.deliveries()?;
+        let record = log.get(id)?.ok_or_else(|| AppError::not_found("delivery not found"))?;
+        self.redeliver(log, &record, DeliveryTrigger::Retry).await
+    }
+
+    /// Sends again, oldest first, the deliveries created within `[from, to]`,
+    /// or only those never delivered with `failed_only`
+    ///
+    /// At most [`MAX_REPLAY`] deliveries are sent per call; the report tells
+    /// how many more match.
+    ///
+    /// # Errors
+    /// Not found without delivery history, validation error for an empty range
+    pub async fn replay(&self, from: DateTime<Utc>, to: DateTime<Utc>, failed_only: bool) -> AppResult<ReplayReport> {
+        let log = self.deliveries()?;
+        let records: Vec<DeliveryRecord> = log
+            .list(from, to)?
+            .into_iter()
+            .filter(|record| !failed_only || !record.delivered)
+            .collect();
+        let mut report = ReplayReport {
+            remaining: records.len().saturating_sub(MAX_REPLAY),
+            ..ReplayReport::default()
+        };
+        for record in records.iter().take(MAX_REPLAY) {
+            match self.redeliver(log, record, DeliveryTrigger::Replay).await {
+                Ok(replayed) if replayed.attempts.last().is_some_and(|attempt| attempt.ok) => {
+                    report.delivered.push(record.id.clone())
+                }
+                Ok(_) => report.failed.push(record.id.clone()),
+                Err(e) => {
+                    warn!("Replaying delivery {} failed: {}", record.id, e);
+                    report.failed.push(record.id.clone());
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    async fn redeliver(&self, log: &DeliveryLog, record: &DeliveryRecord, trigger: DeliveryTrigger) -> AppResult<DeliveryRecord> {
+        let channel = self.channel(&record.channel)?;
+        if !self.egress.admit(&record.channel).await {
+            return Err(AppError::rate_limited(format!("channel '{}' is over its egress rate", record.channel), 1));
+        }
+        let attempt = self.attempt(trigger, || channel.deliver(&record.notification)).await;
+        log.append(&record.id, attempt)
+    }
+
+    /// Sends a `webhook.test` notification to one of `owner`'s subscriptions,
+    /// enabled or not, whatever its event types
+    ///
+    /// # Errors
+    /// Not found for an unknown subscription or one owned by someone else;
+    /// rate limited when the egress limiter drops the delivery
+    pub async fn send_test(&self, owner: &str, id: &str) -> AppResult<DeliveryAttempt> {
+        let subscription = self
+            .subscriptions
+            .as_ref()
+            .ok_or_else(|| AppError::not_found("webhook subscriptions are not enabled"))?
+            .notifier(id)?
+            .filter(|notifier| notifier.subscription().owner == owner)
+            .ok_or_else(|| AppError::not_found("webhook subscription not found"))?;
+        let name = subscription.name().to_string();
+        if !self.egress.admit(&name).await {
+            return Err(AppError::rate_limited(format!("channel '{}' is over its egress rate", name), 1));
+        }
+        let notification = Notification::new(TEST_EVENT_TYPE, "Test delivery", "Requested through the subscription API")
+            .with_data(json!({ "subscription_id": id }));
+        let attempt = self
+            .attempt(DeliveryTrigger::Test, || subscription.deliver(&notification))
+            .await;
+        if let Some(log) = &self.deliveries {
+            if let Err(e) = log.record(&name, &notification, attempt.clone()) {
+                warn!("Failed to record delivery via '{}': {}", name, e);
+            }
+        }
+        Ok(attempt)
+    }
+
+    /// Resolves a configured channel or an enabled subscription by name
+    fn channel(&self, name: &str) -> AppResult<Arc<dyn Notifier>> {
+        if let Some(channel) = self.channels.get(name) {
+            return Ok(channel.clone());
+        }
+        if let (Some(id), Some(subscriptions)) = (name.strip_prefix(CHANNEL_PREFIX), &self.subscriptions) {
+            if let Some(notifier) = subscriptions.notifier(id)?.filter(|notifier| notifier.subscription().enabled) {
+                return Ok(Arc::new(notifier));
+            }
+        }
+        Err(AppError::not_found(format!("channel '{}' is not configured", name)))
+    }
+
+    /// Runs `deliver` within the budget and describes how it went
+    async fn attempt<F, Fut>(&self, trigger: DeliveryTrigger, deliver: F) -> DeliveryAttempt
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = (AppResult<()>, DeliveryResponse)>,
+    {
+        // The budget's last try is the one reported
+        let answer = Mutex::new(DeliveryResponse::default());
+        let started = Instant::now();
+        let result = self
+            .budget
+            .run(|| async {
+                let (result, response) = deliver().await;
+                if let Ok(mut answer) = answer.lock() {
+                    *answer = response;
+                }
+                result
+            })
+            .await;
+        let response = answer.into_inner().unwrap_or_default();
+        DeliveryAttempt::new(trigger, &result, response, started.elapsed())
+    }
+
+    /// Takes one slot from the channel's fixed one-minute window
+    fn acquire(&self, channel: &str) -> bool {
+        let max_per_minute = self
+            .live
+            .as_ref()
+            .map_or(self.max_per_minute, |live| live.load().notify_rate_limit_per_minute);
+        if max_per_minute == 0 {
+            return true;
+        }
+        let window = chrono::Utc::now().timestamp() / 60;
+        match self
+            .counters
+            .increment(&format!("{}:{}", channel, window), Some(Duration::from_secs(60)))
+        {
+            Ok(count) => count <= max_per_minute,
+            Err(e) => {
+                // Fail open: losing the limiter must not silence alerts
+                warn!("Notification rate limiter unavailable: {}", e);
+                true
+            }
+        }
+    }
+}
+
+impl Default for NotificationRouter {
+    fn default() -> Self {
+        Self::new(0, Arc::new(InMemoryStore::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(max_per_minute: u64, spec: &str) -> (NotificationRouter, RecordingNotifier, RecordingNotifier) {
+        let slack = RecordingNotifier::new("slack");
+        let email = RecordingNotifier::new("email");
+        let router = NotificationRouter::new(max_per_minute, Arc::new(InMemoryStore::new()))
+            .with_channel(slack.clone())
+            .with_channel(email.clone())
+            .with_rules(RoutingRule::parse_rules(spec).unwrap());
+        (router, slack, email)
+    }
+
+    #[test]
+    fn test_parse_routing_rules() {
+        let rules = RoutingRule::parse_rules("webhook.*=slack,email; *=webhook").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].channels, vec!["slack", "email"]);
+        assert!(RoutingRule::parse_rules("missing-equals").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_routing_by_event_type() {
+        let (router, slack, email) = router(0, "webhook.*=slack;user.created=email,slack");
+
+        let report = router.notify(&Notification::new("webhook.github", "t", "m")).await;
+        assert_eq!(report.delivered, vec!["slack"]);
+
+        router.notify(&Notification::new("user.created", "t", "m")).await;
+        router.notify(&Notification::new("unrouted", "t", "m")).await;
+
+        assert_eq!(slack.sent().len(), 2);
+        assert_eq!(email.sent().len(), 1);
+        assert_eq!(email.sent()[0].event_type, "user.created");
+    }
+
+    #[actix_web::test]
+    async fn test_per_channel_rate_limit() {
+        let (router, slack, _) = router(2, "*=slack");
+        for _ in 0..3 {
+            router.notify(&Notification::new("x", "t", "m")).await;
+        }
+        let report = router.notify(&Notification::new("x", "t", "m")).await;
+
+        assert_eq!(report.rate_limited, vec!["slack"]);
+        assert_eq!(slack.sent().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_stubbed_channels_need_no_backend() {
+        let config = Config {
+            stub_dependencies: true,
+            ..Config::default()
+        };
+        let router = NotificationRouter::from_config(&config, Arc::new(InMemoryStore::new())).unwrap();
+        let report = router.notify(&Notification::new("webhook.github", "Push", "main updated")).await;
+        let mut delivered = report.delivered.clone();
+        delivered.sort();
+        assert_eq!(delivered, vec!["email", "slack", "webhook"]);
+        assert!(report.failed.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_slow_channels_fail_within_the_budget() {
+        let config = Config {
+            stub_dependencies: true,
+            stub_latency_ms: 5_000,
+            webhook_timeout_ms: 20,
+            webhook_retries: 1,
+            notify_routes: Some("*=slack".to_string()),
+            ..Config::default()
+        };
+        let router = NotificationRouter::from_config(&config, Arc::new(InMemoryStore::new())).unwrap();
+        let started = std::time::Instant::now();
+        let report = router.notify(&Notification::new("x", "t", "m")).await;
+        assert_eq!(report.failed, vec!["slack"]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[actix_web::test]
+    async fn test_egress_limiter_drops_bursts() {
+        let egress = EgressLimiter::new(
+            crate::egress::EgressLimits {
+                rate_per_sec: 0.01,
+                burst: 2,
+            },
+            crate::egress::SpilloverPolicy::Drop,
+            0,
+        );
+        let (router, slack, _) = router(0, "*=slack");
+        let router = router.with_egress(Arc::new(egress));
+        for _ in 0..3 {
+            router.notify(&Notification::new("x", "t", "m")).await;
+        }
+
+        assert_eq!(slack.sent().len(), 2);
+        assert_eq!(router.egress().stats()["slack"].dropped, 1);
+    }
+
+    /// Channel rejecting its first `failures` deliveries like an HTTP endpoint would
+    struct FlakyNotifier {
+        failures: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Notifier for FlakyNotifier {
+        fn name(&self) -> &str {
+            "webhook"
+        }
+
+        fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, AppResult<()>> {
+            Box::pin(async move { self.deliver(notification).await.0 })
+        }
+
+        fn deliver<'a>(&'a self, _: &'a Notification) -> BoxFuture<'a, (AppResult<()>, DeliveryResponse)> {
+            Box::pin(async move {
+                use std::sync::atomic::Ordering;
+                let failing = self
+                    .failures
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1))
+                    .is_ok();
+                let (status, body) = if failing { (503, "try later") } else { (200, "ok") };
+                let response = DeliveryResponse {
+                    status: Some(status),
+                    body: Some(body.to_string()),
+                };
+                let result = if failing { Err(AppError::internal("returned 503")) } else { Ok(()) };
+                (result, response)
+            })
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_deliveries_are_recorded_retried_and_replayed() {
+        let router = NotificationRouter::new(0, Arc::new(InMemoryStore::new()))
+            .with_channel(FlakyNotifier {
+                failures: std::sync::atomic::AtomicUsize::new(2),
+            })
+            .with_rules(RoutingRule::parse_rules("*=webhook").unwrap())
+            .with_deliveries(DeliveryLog::new(Arc::new(InMemoryStore::new()), Duration::from_secs(3600)));
+        assert_eq!(router.notify(&Notification::new("a", "t", "m")).await.failed, vec!["webhook"]);
+        assert_eq!(router.notify(&Notification::new("b", "t", "m")).await.failed, vec!["webhook"]);
+        assert_eq!(router.notify(&Notification::new("c", "t", "m")).await.delivered, vec!["webhook"]);
+
+        let (from, to) = (Utc::now() - chrono::Duration::hours(1), Utc::now());
+        let recorded = router.deliveries().unwrap().list(from, to).unwrap();
+        assert_eq!(recorded.len(), 3);
+        assert_eq!(recorded[0].attempts[0].status, Some(503));
+        assert_eq!(recorded[0].attempts[0].response.as_deref(), Some("try later"));
+
+        let retried = router.retry(&recorded[0].id).await.unwrap();
+        assert!(retried.delivered);
+        assert_eq!(retried.attempts[1].trigger, DeliveryTrigger::Retry);
+
+        let report = router.replay(from, Utc::now(), true).await.unwrap();
+        assert_eq!(report.delivered, vec![recorded[1].id.clone()]);
+        assert!(report.failed.is_empty());
+        assert!(matches!(router.retry("missing").await, Err(AppError::NotFound { .. })));
+        assert!(NotificationRouter::default().deliveries().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_channel_is_reported() {
+        let (router, _, _) = router(0, "*=pager");
+        let report = router.notify(&Notification::new("x", "t", "m")).await;
+        assert_eq!(report.failed, vec!["pager"]);
+    }
+
+    #[test]
+    fn test_email_rendering() {
+        let email = EmailNotifier::new("/tmp", "from@example.com", "ops@example.com");
+        let rendered = email.render(&Notification::new("x", "Deploy done", "All good"));
+        assert!(rendered.contains("To: ops@example.com\r\n"));
+        assert!(rendered.contains("Subject: Deploy done\r\n"));
+        assert!(rendered.ends_with("\r\n\r\nAll good\r\n"));
+
+        let personal = email.render(&Notification::new("x", "Hi", "Hello").with_recipient("ann@example.com"));
+        assert!(personal.contains("To: ann@example.com\r\n"));
+    }
+}
@@ -0,0 +1,185 @@
+use serde_json::{json, Map, Value};
+
+use crate::auth::key_store::API_KEY_HEADER;
+use crate::routes::RouteRegistry;
+
+/// Generated OpenAPI document, shared as app data
+#[derive(Debug, Clone)]
+pub struct OpenApiDocument(pub Value);
+
+/// Builds an OpenAPI 3.1 document from the route registry
+///
+/// Routes with scope requirements reference the `bearerAuth` scheme with
+/// their scopes (allowed for non-OAuth schemes since 3.1) and also list them
+/// in an `x-required-scopes` extension for tooling that ignores the former.
+/// Key-protected and Basic-auth routes add the `apiKeyAuth` and `basicAuth`
+/// schemes to the same requirement, since all credentials must be presented.
+pub fn document(registry: &RouteRegistry) -> Value {
+    let mut paths = Map::new();
+
+    for spec in registry.routes() {
+        let mut operation = json!({
+            "summary": spec.summary,
+            "responses": {
+                "200": { "description": "Success" }
+            }
+        });
+
+        let parameters = path_parameters(spec.path);
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+
+        let mut requirement = Map::new();
+        if !spec.scopes.is_empty() {
+            requirement.insert("bearerAuth".to_string(), json!(spec.scopes));
+            operation["x-required-scopes"] = json!(spec.scopes);
+            operation["responses"]["401"] = json!({ "description": "Missing or invalid bearer token" });
+            operation["responses"]["403"] = json!({ "description": "Token lacks required scopes" });
+        }
+        if !spec.roles.is_empty() {
+            requirement.entry("bearerAuth").or_insert_with(|| json!([]));
+            operation["x-required-roles"] = json!(spec.roles);
+            operation["responses"]["401"] = json!({ "description": "Missing or invalid bearer token" });
+            operation["responses"]["403"] = json!({ "description": "Caller lacks every accepted role" });
+        }
+        if spec.policy {
+            requirement.entry("bearerAuth").or_insert_with(|| json!([]));
+            operation["x-policy-enforced"] = json!(true);
+            operation["responses"]["401"] = json!({ "description": "Missing or invalid bearer token" });
+            operation["responses"]["403"] = json!({ "description": "Denied by the authorization policy" });
+        }
+        if spec.api_key {
+            requirement.insert("apiKeyAuth".to_string(), json!([]));
+            operation["responses"]["401"] = json!({ "description": "Missing or invalid credentials" });
+        }
+        if spec.basic_auth {
+            requirement.insert("basicAuth".to_string(), json!([]));
+            operation["responses"]["401"] = json!({ "description": "Missing or invalid credentials" });
+        }
+        if spec.signed {
+            requirement.insert("signatureAuth".to_string(), json!([]));
+            operation["responses"]["401"] = json!({ "description": "Missing, invalid or expired request signature" });
+        }
+        if !requirement.is_empty() {
+            operation["security"] = json!([requirement]);
+        }
+
+        let item = paths
+            .entry(spec.path.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[spec.method.as_str().to_ascii_lowercase()] = operation;
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "simple-api-demo",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearerAuth": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "bearerFormat": "JWT"
+                },
+                "apiKeyAuth": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": API_KEY_HEADER
+                },
+                "basicAuth": {
+                    "type": "http",
+                    "scheme": "basic"
+                },
+                "signatureAuth": {
+                    "type": "apiKey",
+                    "in": "header",
+                    "name": "X-Signature",
+                    "description": "client=<id>,t=<unix ts>,v1=<hex HMAC-SHA256 of \"<t>\\n<METHOD>\\n<path?query>\\n<body>\">"
+                }
+            }
+        }
+    })
+}
+
+/// Extracts `{name}` segments as OpenAPI path parameters
+fn path_parameters(path: &str) -> Vec<Value> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            // actix allows `{name:regex}`; OpenAPI only wants the name
+            let name = name.split(':').next().unwrap_or(name);
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" }
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::RouteSpec;
+    use actix_web::{web, HttpResponse};
+
+    #[test]
+    fn test_document_lists_routes_and_scopes() {
+        let doc = document(&RouteRegistry::app_server());
+
+        assert_eq!(doc["openapi"], "3.1.0");
+        assert!(doc["paths"]["/public"]["get"]["security"].is_null());
+
+        let private = &doc["paths"]["/private"]["get"];
+        assert_eq!(private["security"][0]["bearerAuth"], json!(["read:private"]));
+        assert_eq!(private["x-required-scopes"], json!(["read:private"]));
+        assert!(private["responses"]["403"].is_object());
+    }
+
+    #[test]
+    fn test_key_protected_routes() {
+        let registry = RouteRegistry::new().route(
+            RouteSpec::get("/internal/report", "Report", || web::get().to(HttpResponse::Ok))
+                .require_scopes(&["read:private"])
+                .require_api_key(),
+        );
+        let doc = document(&registry);
+
+        let security = &doc["paths"]["/internal/report"]["get"]["security"];
+        assert_eq!(security, &json!([{ "bearerAuth": ["read:private"], "apiKeyAuth": [] }]));
+        assert_eq!(doc["components"]["securitySchemes"]["apiKeyAuth"]["name"], "X-Api-Key");
+    }
+
+    #[test]
+    fn test_basic_auth_routes() {
+        let registry = RouteRegistry::new()
+            .route(RouteSpec::get("/ops", "Operations", || web::get().to(HttpResponse::Ok)).require_basic_auth());
+        let doc = document(&registry);
+
+        assert_eq!(doc["paths"]["/ops"]["get"]["security"], json!([{ "basicAuth": [] }]));
+        assert_eq!(doc["components"]["securitySchemes"]["basicAuth"]["scheme"], "basic");
+    }
+
+    #[test]
+    fn test_signed_routes() {
+        let registry = RouteRegistry::new()
+            .route(RouteSpec::post("/orders", "Orders", || web::post().to(HttpResponse::Ok)).require_signature());
+        let doc = document(&registry);
+
+        assert_eq!(doc["paths"]["/orders"]["post"]["security"], json!([{ "signatureAuth": [] }]));
+        assert_eq!(doc["components"]["securitySchemes"]["signatureAuth"]["name"], "X-Signature");
+    }
+
+    #[test]
+    fn test_path_parameters() {
+        let doc = document(&RouteRegistry::app_server());
+        let params = &doc["paths"]["/hooks/{provider}"]["post"]["parameters"];
+        assert_eq!(params[0]["name"], "provider");
+        assert_eq!(params[0]["in"], "path");
+    }
+}
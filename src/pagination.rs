@@ -0,0 +1,234 @@
+use std::cmp::Ordering;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppError, AppResult};
+
+/// Items per page when `limit` is not given
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+
+/// Largest accepted `limit`
+pub const MAX_PAGE_SIZE: usize = 500;
+
+/// Direction in which a listing is ordered by its keyset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    OldestFirst,
+    NewestFirst,
+}
+
+/// Pagination parameters accepted by list endpoints
+///
+/// Listings are ordered by a timestamp, ties broken by id, and paged either
+/// with the opaque `cursor` of the previous page (keyset pagination: records
+/// inserted meanwhile never shift a page, so nothing is skipped or repeated)
+/// or with `page` (1-based) or `offset`. Without any of these parameters the
+/// whole listing is returned.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PageQuery {
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+    pub page: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+/// One page of a listing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Cursor of the next page, `None` on the last one
+    pub next_cursor: Option<String>,
+}
+
+/// Keyset position of the last item of a page
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    /// Timestamp in microseconds
+    t: i64,
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> AppResult<Self> {
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| AppError::validation("invalid cursor"))
+    }
+}
+
+impl PageQuery {
+    /// Orders `items` by the keyset `key` returns and cuts out the requested page
+    ///
+    /// # Errors
+    /// Returns a validation error for a malformed cursor, a `limit` out of
+    /// range or a cursor combined with `page`/`offset`
+    pub fn paginate<T, F>(&self, mut items: Vec<T>, order: Order, key: F) -> AppResult<Page<T>>
+    where
+        F: Fn(&T) -> (DateTime<Utc>, &str),
+    {
+        let compare = |a: &T, b: &T| {
+            let (a, b) = (key(a), key(b));
+            let ordering = (a.0.timestamp_micros(), a.1).cmp(&(b.0.timestamp_micros(), b.1));
+            match order {
+                Order::OldestFirst => ordering,
+                Order::NewestFirst => ordering.reverse(),
+            }
+        };
+        items.sort_by(compare);
+
+        if self.cursor.is_none() && self.limit.is_none() && self.page.is_none() && self.offset.is_none() {
+            return Ok(Page { items, next_cursor: None });
+        }
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if limit == 0 || limit > MAX_PAGE_SIZE {
+            return Err(AppError::validation(format!("limit must be between 1 and {}", MAX_PAGE_SIZE)));
+        }
+
+        let start = match (&self.cursor, self.page, self.offset) {
+            (Some(cursor), None, None) => {
+                let cursor = Cursor::decode(cursor)?;
+                // Position-independent: counts the items ordered before or at the cursor
+                items.partition_point(|item| {
+                    let (at, id) = key(item);
+                    let ordering = (at.timestamp_micros(), id).cmp(&(cursor.t, cursor.id.as_str()));
+                    match order {
+                        Order::OldestFirst => ordering != Ordering::Greater,
+                        Order::NewestFirst => ordering != Ordering::Less,
+                    }
+                })
+            }
+            (None, Some(0), _) => return Err(AppError::validation("page starts at 1")),
+            (None, Some(page), None) => (page - 1).saturating_mul(limit),
+            (None, None, offset) => offset.unwrap_or(0),
+            _ => return Err(AppError::validation("use either cursor, page or offset")),
+        };
+
+        let mut items: Vec<T> = items.into_iter().skip(start).collect();
+        let more = items.len() > limit;
+        items.truncate(limit);
+        let next_cursor = match items.last() {
+            Some(last) if more => {
+                let (at, id) = key(last);
+                Some(Cursor { t: at.timestamp_micros(), id: id.to_string() }.encode())
+            }
+            _ => None,
+        };
+        Ok(Page { items, next_cursor })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Record {
+        id: String,
+        at: DateTime<Utc>,
+    }
+
+    fn record(id: usize, second: i64) -> Record {
+        Record {
+            id: format!("r{:04}", id),
+            at: DateTime::from_timestamp(1_700_000_000 + second, 0).unwrap(),
+        }
+    }
+
+    fn key(record: &Record) -> (DateTime<Utc>, &str) {
+        (record.at, record.id.as_str())
+    }
+
+    fn query(cursor: Option<String>, limit: usize) -> PageQuery {
+        PageQuery { cursor, limit: Some(limit), ..PageQuery::default() }
+    }
+
+    #[test]
+    fn test_pages_by_cursor_page_and_offset() {
+        let records: Vec<Record> = (0..5).map(|i| record(i, i as i64 / 2)).collect();
+        let ids = |page: &Page<Record>| page.items.iter().map(|r| r.id.clone()).collect::<Vec<_>>();
+
+        let all = PageQuery::default().paginate(records.clone(), Order::NewestFirst, key).unwrap();
+        assert_eq!(ids(&all), vec!["r0004", "r0003", "r0002", "r0001", "r0000"]);
+        assert!(all.next_cursor.is_none());
+
+        let first = query(None, 2).paginate(records.clone(), Order::OldestFirst, key).unwrap();
+        assert_eq!(ids(&first), vec!["r0000", "r0001"]);
+        let second = query(first.next_cursor, 2).paginate(records.clone(), Order::OldestFirst, key).unwrap();
+        assert_eq!(ids(&second), vec!["r0002", "r0003"]);
+        let last = query(second.next_cursor, 2).paginate(records.clone(), Order::OldestFirst, key).unwrap();
+        assert_eq!(ids(&last), vec!["r0004"]);
+        assert!(last.next_cursor.is_none());
+
+        let page = PageQuery { page: Some(2), ..query(None, 2) };
+        assert_eq!(ids(&page.paginate(records.clone(), Order::OldestFirst, key).unwrap()), vec!["r0002", "r0003"]);
+        let offset = PageQuery { offset: Some(3), ..query(None, 2) };
+        assert_eq!(ids(&offset.paginate(records.clone(), Order::OldestFirst, key).unwrap()), vec!["r0003", "r0004"]);
+    }
+
+    #[test]
+    fn test_rejects_invalid_parameters() {
+        let records = vec![record(0, 0)];
+        let invalid = [
+            query(Some("not a cursor".to_string()), 10),
+            query(None, 0),
+            query(None, MAX_PAGE_SIZE + 1),
+            PageQuery { page: Some(0), ..PageQuery::default() },
+            PageQuery { page: Some(1), offset: Some(1), ..PageQuery::default() },
+            PageQuery { cursor: Some(Cursor { t: 0, id: "r".to_string() }.encode()), page: Some(1), ..PageQuery::default() },
+        ];
+        for query in invalid {
+            assert!(matches!(
+                query.paginate(records.clone(), Order::OldestFirst, key),
+                Err(AppError::Validation { .. })
+            ));
+        }
+    }
+
+    proptest! {
+        // Records present when the traversal starts are each seen exactly
+        // once, whatever gets inserted between pages
+        #[test]
+        fn prop_cursor_pages_survive_concurrent_inserts(
+            initial in prop::collection::vec(0i64..50, 0..60),
+            inserts in prop::collection::vec(prop::collection::vec(0i64..50, 0..5), 0..30),
+            limit in 1usize..8,
+            newest_first in any::<bool>(),
+        ) {
+            let order = if newest_first { Order::NewestFirst } else { Order::OldestFirst };
+            let mut records: Vec<Record> = initial.iter().enumerate().map(|(i, &second)| record(i, second)).collect();
+            let expected: HashSet<String> = records.iter().map(|r| r.id.clone()).collect();
+            let mut next_id = records.len();
+
+            let mut seen = HashSet::new();
+            let mut cursor = None;
+            let mut batches = inserts.into_iter();
+            loop {
+                let page = query(cursor, limit).paginate(records.clone(), order, key).unwrap();
+                prop_assert!(page.items.len() <= limit);
+                for item in page.items {
+                    prop_assert!(seen.insert(item.id.clone()), "{} returned twice", item.id);
+                }
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+                for second in batches.next().unwrap_or_default() {
+                    records.push(record(next_id, second));
+                    next_id += 1;
+                }
+            }
+            prop_assert!(expected.is_subset(&seen), "skipped {:?}", expected.difference(&seen).collect::<Vec<_>>());
+        }
+    }
+}
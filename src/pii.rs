@@ -0,0 +1,405 @@
+use std::io::Write;
+use std::net::IpAddr;
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// How a personal data field is masked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PiiKind {
+    /// Keeps the first character and the domain: `a***@example.com`
+    Email,
+    /// Keeps the network: `192.0.2.0/24`, `2001:db8:1::/48`
+    IpAddress,
+    /// Replaced entirely by [`REDACTED`]
+    Text,
+}
+
+/// Replacement for fully redacted values
+pub const REDACTED: &str = "[redacted]";
+
+/// Tags the serialized fields of a model that hold personal data
+///
+/// Field names refer to the serde (JSON) names. Tagged fields are masked
+/// wherever the model, or an audit payload using the same field names, leaves
+/// the process through logs, audit events or error messages.
+pub trait PiiFields {
+    /// Personal data fields and how to mask them
+    const PII_FIELDS: &'static [(&'static str, PiiKind)];
+}
+
+/// Every field tagged on the application's models
+///
+/// Used for payloads whose shape is not a single model, such as audit event
+/// data.
+pub fn tagged_fields() -> Vec<(&'static str, PiiKind)> {
+    let mut fields = Vec::new();
+    for tagged in [
+        crate::users::User::PII_FIELDS,
+        crate::auth::sessions::Session::PII_FIELDS,
+        crate::notifications::Notification::PII_FIELDS,
+    ] {
+        for field in tagged {
+            if !fields.contains(field) {
+                fields.push(*field);
+            }
+        }
+    }
+    fields
+}
+
+/// Serializes `model` with its tagged fields masked
+pub fn redact<T: Serialize + PiiFields>(model: &T) -> Value {
+    let mut value = serde_json::to_value(model).unwrap_or(Value::Null);
+    redact_json(&mut value, T::PII_FIELDS);
+    value
+}
+
+/// Masks `fields` anywhere in `value` and email addresses in every string
+pub fn redact_json(value: &mut Value, fields: &[(&str, PiiKind)]) {
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object.iter_mut() {
+                match fields.iter().find(|(name, _)| name == key) {
+                    Some((_, kind)) => mask_value(child, *kind),
+                    None => redact_json(child, fields),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact_json(item, fields)),
+        Value::String(text) => *text = redact_text(text),
+        _ => {}
+    }
+}
+
+fn mask_value(value: &mut Value, kind: PiiKind) {
+    match value {
+        Value::Null => {}
+        Value::String(text) => *text = mask(text, kind),
+        Value::Array(items) => items.iter_mut().for_each(|item| mask_value(item, kind)),
+        other => *other = Value::String(REDACTED.to_string()),
+    }
+}
+
+/// Masks a single value of the given kind
+pub fn mask(value: &str, kind: PiiKind) -> String {
+    match kind {
+        PiiKind::Email => mask_email(value),
+        PiiKind::IpAddress => mask_ip(value),
+        PiiKind::Text => REDACTED.to_string(),
+    }
+}
+
+/// Masks an email address as `e***@example.com`
+///
+/// Anything that is not an address is redacted entirely.
+pub fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) if !local.is_empty() && !domain.is_empty() => {
+            let first = local.chars().next().unwrap_or('*');
+            format!("{}***@{}", first, domain)
+        }
+        _ => REDACTED.to_string(),
+    }
+}
+
+/// Truncates an IP address to its /24 (IPv4) or /48 (IPv6) network
+pub fn mask_ip(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(v4)) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        Ok(IpAddr::V6(v6)) => {
+            let segments = v6.segments();
+            format!("{:x}:{:x}:{:x}::/48", segments[0], segments[1], segments[2])
+        }
+        Err(_) => REDACTED.to_string(),
+    }
+}
+
+/// Masks email addresses appearing in free text
+pub fn redact_text(text: &str) -> String {
+    replace_emails(text, mask_email)
+}
+
+/// Removes `fields` anywhere in `value` and email addresses in every string
+///
+/// Unlike [`redact_json`] nothing of the original values is kept, which is
+/// what anonymization needs. Returns whether anything changed.
+pub fn scrub_json(value: &mut Value, fields: &[(&str, PiiKind)]) -> bool {
+    match value {
+        Value::Object(object) => object.iter_mut().fold(false, |changed, (key, child)| {
+            let scrubbed = if fields.iter().any(|(name, _)| name == key) {
+                scrub_value(child)
+            } else {
+                scrub_json(child, fields)
+            };
+            changed | scrubbed
+        }),
+        Value::Array(items) => items
+            .iter_mut()
+            .fold(false, |changed, item| changed | scrub_json(item, fields)),
+        Value::String(text) => {
+            let scrubbed = replace_emails(text, |_| REDACTED.to_string());
+            let changed = scrubbed != *text;
+            *text = scrubbed;
+            changed
+        }
+        _ => false,
+    }
+}
+
+fn scrub_value(value: &mut Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::String(text) if text == REDACTED => false,
+        other => {
+            *other = Value::String(REDACTED.to_string());
+            true
+        }
+    }
+}
+
+fn replace_emails(text: &str, replace: impl Fn(&str) -> String) -> String {
+    if !text.contains('@') {
+        return text.to_string();
+    }
+
+    let is_address_char = |c: char| c.is_ascii_alphanumeric() || "._%+-@".contains(c);
+    let mut output = String::with_capacity(text.len());
+    let mut word = String::new();
+    let flush = |word: &mut String, output: &mut String| {
+        if looks_like_email(word) {
+            output.push_str(&replace(word));
+        } else {
+            output.push_str(word);
+        }
+        word.clear();
+    };
+    for c in text.chars() {
+        if is_address_char(c) {
+            word.push(c);
+        } else {
+            flush(&mut word, &mut output);
+            output.push(c);
+        }
+    }
+    flush(&mut word, &mut output);
+    output
+}
+
+fn looks_like_email(word: &str) -> bool {
+    let word = word.trim_end_matches('.');
+    match word.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty() && domain.contains('.') && !domain.starts_with('.') && !domain.contains('@')
+        }
+        None => false,
+    }
+}
+
+/// `env_logger` format masking email addresses in every log line
+///
+/// Lines logged while a request is handled end with its correlation fields
+/// (see [`LogContext`](crate::log_context::LogContext)). Install with
+/// `env_logger::Builder::format(pii::format_log_record)`.
+pub fn format_log_record(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> std::io::Result<()> {
+    write!(
+        buf,
+        "[{} {} {}] {}",
+        buf.timestamp(),
+        record.level(),
+        record.target(),
+        redact_text(&record.args().to_string())
+    )?;
+    if let Some(fields) = crate::region::log_fields() {
+        write!(buf, " {}", fields)?;
+    }
+    match crate::log_context::LogContext::current() {
+        Some(context) => writeln!(buf, " {}", context),
+        None => writeln!(buf),
+    }
+}
+
+/// JSON variant of [`format_log_record`], one object per line
+///
+/// Carries the same masking and fields, the placement and correlation
+/// fields as keys of their own.
+pub fn format_json_log_record(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> std::io::Result<()> {
+    let mut line = serde_json::json!({
+        "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": redact_text(&record.args().to_string()),
+    });
+    if let Some(fields) = crate::region::log_fields() {
+        for (name, value) in fields.split_whitespace().filter_map(|field| field.split_once('=')) {
+            line[name] = Value::from(value);
+        }
+    }
+    if let Some(context) = crate::log_context::LogContext::current() {
+        line["trace_id"] = Value::from(context.trace_id);
+        line["span_id"] = Value::from(context.span_id);
+        line["request_id"] = Value::from(context.request_id);
+    }
+    writeln!(buf, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_masking() {
+        assert_eq!(mask_email("erin@example.com"), "e***@example.com");
+        assert_eq!(mask_email("not an address"), REDACTED);
+        assert_eq!(mask_ip("192.0.2.17"), "192.0.2.0/24");
+        assert_eq!(mask_ip("2001:db8:1:2::5"), "2001:db8:1::/48");
+        assert_eq!(mask("Ann's laptop", PiiKind::Text), REDACTED);
+    }
+
+    #[test]
+    fn test_redact_text() {
+        assert_eq!(
+            redact_text("Sent reset link to ann.lee@example.com, bob@example.org."),
+            "Sent reset link to a***@example.com, b***@example.org."
+        );
+        assert_eq!(redact_text("user@localhost and a@b"), "user@localhost and a@b");
+        assert_eq!(redact_text("no personal data"), "no personal data");
+    }
+
+    #[test]
+    fn test_redact_tagged_models() {
+        let session = crate::auth::sessions::Session {
+            id: "s1".to_string(),
+            user_id: "u1".to_string(),
+            device_name: "Ann's phone".to_string(),
+            ip: "198.51.100.7".to_string(),
+            created_at: chrono::Utc::now(),
+            last_seen_at: chrono::Utc::now(),
+            expires_at: chrono::Utc::now(),
+        };
+        let redacted = redact(&session);
+        assert_eq!(redacted["id"], "s1");
+        assert_eq!(redacted["device_name"], REDACTED);
+        assert_eq!(redacted["ip"], "198.51.100.0/24");
+    }
+
+    #[test]
+    fn test_redact_nested_payloads() {
+        let mut payload = json!({
+            "user": {"email": "ann@example.com", "id": "u1"},
+            "sessions": [{"ip": "192.0.2.1"}],
+            "note": "contact ann@example.com",
+            "count": 3,
+        });
+        redact_json(&mut payload, &tagged_fields());
+        assert_eq!(payload["user"]["email"], "a***@example.com");
+        assert_eq!(payload["user"]["id"], "u1");
+        assert_eq!(payload["sessions"][0]["ip"], "192.0.2.0/24");
+        assert_eq!(payload["note"], "contact a***@example.com");
+        assert_eq!(payload["count"], 3);
+    }
+
+    #[test]
+    fn test_scrub_json() {
+        let mut payload = json!({"email": "ann@example.com", "reason": "asked by bob@example.com", "id": 7});
+        assert!(scrub_json(&mut payload, &tagged_fields()));
+        assert_eq!(payload, json!({"email": REDACTED, "reason": format!("asked by {}", REDACTED), "id": 7}));
+        assert!(!scrub_json(&mut payload, &tagged_fields()));
+    }
+
+    #[test]
+    fn test_log_lines_are_redacted() {
+        let output = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = SharedBuffer(output.clone());
+        let logger = env_logger::Builder::new()
+            .format(format_log_record)
+            .filter_level(log::LevelFilter::Info)
+            .target(env_logger::Target::Pipe(Box::new(sink)))
+            .build();
+
+        log::Log::log(
+            &logger,
+            &log::Record::builder()
+                .args(format_args!("Password reset requested for ann@example.com"))
+                .level(log::Level::Info)
+                .target("users")
+                .build(),
+        );
+        let line = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(line.contains("a***@example.com"), "{}", line);
+        assert!(!line.contains("ann@example.com"));
+        assert!(!line.contains("trace_id="), "{}", line);
+
+        // Lines logged while a request is handled carry its correlation fields
+        output.lock().unwrap().clear();
+        let context = crate::log_context::LogContext {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+            span_id: "00f067aa0ba902b7".to_string(),
+            request_id: "req-42".to_string(),
+        };
+        crate::log_context::sync_scope(Some(context), || {
+            log::Log::log(
+                &logger,
+                &log::Record::builder()
+                    .args(format_args!("Session created"))
+                    .level(log::Level::Info)
+                    .target("sessions")
+                    .build(),
+            )
+        });
+        let line = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert!(
+            line.ends_with("Session created trace_id=4bf92f3577b34da6a3ce929d0e0e4736 span_id=00f067aa0ba902b7 request_id=req-42\n"),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn test_json_log_lines() {
+        let output = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let logger = env_logger::Builder::new()
+            .format(format_json_log_record)
+            .filter_level(log::LevelFilter::Info)
+            .target(env_logger::Target::Pipe(Box::new(SharedBuffer(output.clone()))))
+            .build();
+        let context = crate::log_context::LogContext {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+            span_id: "00f067aa0ba902b7".to_string(),
+            request_id: "req-42".to_string(),
+        };
+        crate::log_context::sync_scope(Some(context), || {
+            log::Log::log(
+                &logger,
+                &log::Record::builder()
+                    .args(format_args!("Reset for ann@example.com"))
+                    .level(log::Level::Warn)
+                    .target("users")
+                    .build(),
+            )
+        });
+        let line = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let value: Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "users");
+        assert_eq!(value["message"], "Reset for a***@example.com");
+        assert_eq!(value["request_id"], "req-42");
+    }
+
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+use std::future::{ready, Future, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures::future::LocalBoxFuture;
+use log::info;
+
+use crate::error::{AppError, AppResult};
+
+/// Outcome of a plugin middleware
+pub type PluginResult = Result<ServiceResponse<BoxBody>, Error>;
+
+/// Request processing contributed by a plugin
+///
+/// Works like a `from_fn` middleware: handle the request, call
+/// [`PluginNext::call`] to continue down the chain, and inspect or replace
+/// the response. Closures `Fn(ServiceRequest, PluginNext) -> impl Future`
+/// implement this trait.
+pub trait Middleware: Send + Sync {
+    fn call(&self, req: ServiceRequest, next: PluginNext) -> LocalBoxFuture<'static, PluginResult>;
+}
+
+impl<F, Fut> Middleware for F
+where
+    F: Fn(ServiceRequest, PluginNext) -> Fut + Send + Sync,
+    Fut: Future<Output = PluginResult> + 'static,
+{
+    fn call(&self, req: ServiceRequest, next: PluginNext) -> LocalBoxFuture<'static, PluginResult> {
+        Box::pin(self(req, next))
+    }
+}
+
+/// Factory of a [`Middleware`], registered on
+/// [`ServerManager::builder`](crate::server::ServerManager::builder)
+///
+/// Plugins let embedding crates add cross-cutting behavior to the
+/// application server without forking its wiring. Each plugin reads its
+/// settings from a config section: the environment variables starting with
+/// [`config_section`](Self::config_section).
+pub trait MiddlewarePlugin: Send + Sync {
+    /// Unique plugin name, used in logs
+    fn name(&self) -> &str;
+
+    /// Prefix of the plugin's environment variables
+    ///
+    /// Defaults to `PLUGIN_{NAME}_`, the name upper-cased with dashes
+    /// replaced by underscores.
+    fn config_section(&self) -> String {
+        format!("PLUGIN_{}_", self.name().to_uppercase().replace('-', "_"))
+    }
+
+    /// Builds the middleware shared by all workers
+    ///
+    /// # Errors
+    /// Invalid or missing settings; the server refuses to start
+    fn build(&self, settings: &PluginSettings) -> AppResult<Arc<dyn Middleware>>;
+}
+
+/// Settings of one plugin's config section
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PluginSettings {
+    section: String,
+    values: BTreeMap<String, String>,
+}
+
+impl PluginSettings {
+    /// Collects the variables starting with `section`
+    ///
+    /// Keys are stored without the prefix and lower-cased, so
+    /// `PLUGIN_AUDIT_LEVEL` is `level` in section `PLUGIN_AUDIT_`.
+    pub fn from_vars(section: &str, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        let values = vars
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix(section)?;
+                (!key.is_empty()).then(|| (key.to_lowercase(), value))
+            })
+            .collect();
+        Self {
+            section: section.to_string(),
+            values,
+        }
+    }
+
+    /// Returns the value of `key`, if set
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// Returns the value of `key`
+    ///
+    /// # Errors
+    /// Returns an environment error naming the missing variable
+    pub fn require(&self, key: &str) -> AppResult<&str> {
+        self.get(key).ok_or_else(|| {
+            AppError::environment(format!("{}{}", self.section, key.to_uppercase()), "must be set")
+        })
+    }
+
+    /// Returns the environment prefix of the section
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+}
+
+/// Plugins registered for the application server, in registration order
+#[derive(Clone, Default)]
+pub struct PluginRegistry {
+    plugins: Vec<Arc<dyn MiddlewarePlugin>>,
+}
+
+impl PluginRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a plugin; the first registered plugin sees requests first
+    pub fn register(&mut self, plugin: Arc<dyn MiddlewarePlugin>) {
+        self.plugins.push(plugin);
+    }
+
+    /// Returns the registered plugin names
+    pub fn names(&self) -> Vec<&str> {
+        self.plugins.iter().map(|plugin| plugin.name()).collect()
+    }
+
+    /// Builds every plugin from its section of `vars`
+    ///
+    /// # Errors
+    /// Returns a config error for duplicate names and the first build error
+    pub fn build(&self, vars: impl IntoIterator<Item = (String, String)>) -> AppResult<PluginStack> {
+        let vars: Vec<(String, String)> = vars.into_iter().collect();
+        let mut middlewares = Vec::with_capacity(self.plugins.len());
+        for (index, plugin) in self.plugins.iter().enumerate() {
+            if self.plugins[..index].iter().any(|other| other.name() == plugin.name()) {
+                return Err(AppError::config(format!("middleware plugin {} registered twice", plugin.name())));
+            }
+            let settings = PluginSettings::from_vars(&plugin.config_section(), vars.iter().cloned());
+            middlewares.push(plugin.build(&settings)?);
+            info!("Loaded middleware plugin {}", plugin.name());
+        }
+        Ok(PluginStack {
+            middlewares: middlewares.into(),
+        })
+    }
+}
+
+/// Actix middleware running the built plugins in registration order
+#[derive(Clone, Default)]
+pub struct PluginStack {
+    middlewares: Arc<[Arc<dyn Middleware>]>,
+}
+
+impl<S, B> Transform<S, ServiceRequest> for PluginStack
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = PluginService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let service = Rc::new(service);
+        let inner = Rc::clone(&service);
+        let chain = Chain {
+            middlewares: Arc::clone(&self.middlewares),
+            service: Box::new(move |req| {
+                let response = inner.call(req);
+                Box::pin(async move { response.await.map(ServiceResponse::map_into_boxed_body) })
+            }),
+        };
+        ready(Ok(PluginService {
+            service,
+            chain: Rc::new(chain),
+        }))
+    }
+}
+
+/// The remaining plugins and the wrapped service
+struct Chain {
+    middlewares: Arc<[Arc<dyn Middleware>]>,
+    service: Box<dyn Fn(ServiceRequest) -> LocalBoxFuture<'static, PluginResult>>,
+}
+
+/// Continuation handed to a plugin [`Middleware`]
+pub struct PluginNext {
+    chain: Rc<Chain>,
+    index: usize,
+}
+
+impl PluginNext {
+    /// Passes the request to the next plugin, or to the routes after the last
+    pub fn call(self, req: ServiceRequest) -> LocalBoxFuture<'static, PluginResult> {
+        match self.chain.middlewares.get(self.index) {
+            Some(middleware) => {
+                let next = PluginNext {
+                    chain: Rc::clone(&self.chain),
+                    index: self.index + 1,
+                };
+                middleware.call(req, next)
+            }
+            None => (self.chain.service)(req),
+        }
+    }
+}
+
+/// Service produced by [`PluginStack`]
+pub struct PluginService<S> {
+    service: Rc<S>,
+    chain: Rc<Chain>,
+}
+
+impl<S, B> Service<ServiceRequest> for PluginService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, PluginResult>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        PluginNext {
+            chain: Rc::clone(&self.chain),
+            index: 0,
+        }
+        .call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    /// Appends its tag to `x-trace` on the way out, or answers 403 for `/blocked`
+    struct TracePlugin(&'static str);
+
+    impl MiddlewarePlugin for TracePlugin {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn build(&self, settings: &PluginSettings) -> AppResult<Arc<dyn Middleware>> {
+            let tag = settings.get("tag").unwrap_or(self.0).to_string();
+            Ok(Arc::new(move |req: ServiceRequest, next: PluginNext| {
+                let tag = tag.clone();
+                async move {
+                    if req.path() == "/blocked" {
+                        return Ok(req.into_response(HttpResponse::Forbidden().finish()));
+                    }
+                    let mut res = next.call(req).await?;
+                    let trace = match res.headers().get("x-trace").and_then(|value| value.to_str().ok()) {
+                        Some(inner) => format!("{},{}", inner, tag),
+                        None => tag,
+                    };
+                    res.headers_mut()
+                        .insert(HeaderName::from_static("x-trace"), HeaderValue::from_str(&trace).unwrap());
+                    Ok(res)
+                }
+            }))
+        }
+    }
+
+    struct RequiredSetting;
+
+    impl MiddlewarePlugin for RequiredSetting {
+        fn name(&self) -> &str {
+            "needs-key"
+        }
+
+        fn build(&self, settings: &PluginSettings) -> AppResult<Arc<dyn Middleware>> {
+            settings.require("key")?;
+            Ok(Arc::new(|req: ServiceRequest, next: PluginNext| next.call(req)))
+        }
+    }
+
+    fn vars(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_settings_section() {
+        let settings = PluginSettings::from_vars(
+            "PLUGIN_AUDIT_",
+            vars(&[("PLUGIN_AUDIT_LEVEL", "debug"), ("PLUGIN_AUDITOR_X", "1"), ("PORT", "8080")]),
+        );
+        assert_eq!(settings.get("level"), Some("debug"));
+        assert_eq!(settings.get("x"), None);
+        assert!(matches!(
+            settings.require("target"),
+            Err(AppError::Environment { var_name, .. }) if var_name == "PLUGIN_AUDIT_TARGET"
+        ));
+        assert_eq!(TracePlugin("rate-limit").config_section(), "PLUGIN_RATE_LIMIT_");
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let mut registry = PluginRegistry::new();
+        registry.register(Arc::new(RequiredSetting));
+        assert!(matches!(registry.build(vars(&[])), Err(AppError::Environment { .. })));
+        assert!(registry.build(vars(&[("PLUGIN_NEEDS_KEY_KEY", "x")])).is_ok());
+
+        registry.register(Arc::new(RequiredSetting));
+        assert!(matches!(
+            registry.build(vars(&[("PLUGIN_NEEDS_KEY_KEY", "x")])),
+            Err(AppError::Config { .. })
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_plugins_run_in_registration_order() {
+        let mut registry = PluginRegistry::new();
+        registry.register(Arc::new(TracePlugin("outer")));
+        registry.register(Arc::new(TracePlugin("inner")));
+        assert_eq!(registry.names(), vec!["outer", "inner"]);
+        let stack = registry.build(vars(&[("PLUGIN_INNER_TAG", "configured")])).unwrap();
+
+        let app = init_service(
+            App::new()
+                .wrap(stack)
+                .route("/", web::get().to(|| async { HttpResponse::Ok().body("routed") }))
+                .route("/blocked", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.headers().get("x-trace").unwrap(), "configured,outer");
+        assert_eq!(read_body(res).await, "routed");
+
+        // The outer plugin answers without reaching the inner one
+        let res = call_service(&app, TestRequest::get().uri("/blocked").to_request()).await;
+        assert_eq!(res.status(), 403);
+        assert!(res.headers().get("x-trace").is_none());
+    }
+}
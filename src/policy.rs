@@ -0,0 +1,848 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::auth::scopes::authenticate;
+use crate::auth::Claims;
+use crate::config::Config;
+use crate::context::{AuthPrincipal, RequestContext};
+use crate::error::{AppError, AppResult};
+
+/// Scope required to reload the policy at runtime
+pub const POLICY_ADMIN_SCOPE: &str = "admin:policy";
+
+/// Model used without `POLICY_MODEL_FILE`: RBAC over request paths and methods
+pub const DEFAULT_MODEL: &str = r#"
+[request_definition]
+r = sub, obj, act
+
+[policy_definition]
+p = sub, obj, act
+
+[role_definition]
+g = _, _
+
+[policy_effect]
+e = some(where (p.eft == allow))
+
+[matchers]
+m = g(r.sub, p.sub) && keyMatch2(r.obj, p.obj) && (r.act == p.act || p.act == "*")
+"#;
+
+/// Longest chain of role links followed
+const MAX_ROLE_DEPTH: usize = 10;
+
+/// How the effects of the matching policies combine into a decision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Effect {
+    /// `some(where (p.eft == allow))`
+    AllowOverride,
+    /// `!some(where (p.eft == deny))`
+    DenyOverride,
+    /// `some(where (p.eft == allow)) && !some(where (p.eft == deny))`
+    AllowAndDeny,
+}
+
+impl Effect {
+    fn parse(expression: &str) -> AppResult<Self> {
+        let normalized: String = expression.chars().filter(|c| !c.is_whitespace()).collect();
+        match normalized.as_str() {
+            "some(where(p.eft==allow))" => Ok(Self::AllowOverride),
+            "!some(where(p.eft==deny))" => Ok(Self::DenyOverride),
+            "some(where(p.eft==allow))&&!some(where(p.eft==deny))" => Ok(Self::AllowAndDeny),
+            _ => Err(AppError::config(format!("unsupported policy effect: {}", expression))),
+        }
+    }
+}
+
+/// Matcher expression of the model
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Str(String),
+    Bool(bool),
+    /// `r.sub`, `p.obj`, ...
+    Field(char, usize),
+    Call(String, Vec<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Open,
+    Close,
+    Comma,
+    Eq,
+    Ne,
+    Not,
+    And,
+    Or,
+}
+
+fn tokenize(source: &str) -> AppResult<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let operator = match (c, next) {
+            ('=', Some('=')) => Some((Token::Eq, 2)),
+            ('!', Some('=')) => Some((Token::Ne, 2)),
+            ('&', Some('&')) => Some((Token::And, 2)),
+            ('|', Some('|')) => Some((Token::Or, 2)),
+            ('!', _) => Some((Token::Not, 1)),
+            ('(', _) => Some((Token::Open, 1)),
+            (')', _) => Some((Token::Close, 1)),
+            (',', _) => Some((Token::Comma, 1)),
+            _ => None,
+        };
+        if let Some((token, width)) = operator {
+            tokens.push(token);
+            i += width;
+            continue;
+        }
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '"' | '\'' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&d| d == c)
+                    .ok_or_else(|| AppError::config("unterminated string in matcher"))?;
+                tokens.push(Token::Str(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            c => return Err(AppError::config(format!("unexpected '{}' in matcher", c))),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive-descent parser of matchers: `||` binds looser than `&&`, which
+/// binds looser than `!` and the comparisons
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    model: &'a ModelShape,
+}
+
+impl Parser<'_> {
+    fn parse(mut self) -> AppResult<Expr> {
+        let expr = self.or()?;
+        match self.tokens.get(self.position) {
+            None => Ok(expr),
+            Some(token) => Err(AppError::config(format!("unexpected {:?} in matcher", token))),
+        }
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.position) == Some(token) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> AppResult<Expr> {
+        let mut left = self.and()?;
+        while self.eat(&Token::Or) {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> AppResult<Expr> {
+        let mut left = self.unary()?;
+        while self.eat(&Token::And) {
+            left = Expr::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> AppResult<Expr> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        let left = self.operand()?;
+        if self.eat(&Token::Eq) {
+            return Ok(Expr::Eq(Box::new(left), Box::new(self.operand()?)));
+        }
+        if self.eat(&Token::Ne) {
+            return Ok(Expr::Ne(Box::new(left), Box::new(self.operand()?)));
+        }
+        Ok(left)
+    }
+
+    fn operand(&mut self) -> AppResult<Expr> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| AppError::config("matcher ends unexpectedly"))?;
+        self.position += 1;
+        match token {
+            Token::Open => {
+                let expr = self.or()?;
+                if !self.eat(&Token::Close) {
+                    return Err(AppError::config("missing ')' in matcher"));
+                }
+                Ok(expr)
+            }
+            Token::Str(value) => Ok(Expr::Str(value)),
+            Token::Ident(name) if name == "true" || name == "false" => Ok(Expr::Bool(name == "true")),
+            Token::Ident(name) if self.eat(&Token::Open) => {
+                let mut args = Vec::new();
+                if !self.eat(&Token::Close) {
+                    loop {
+                        args.push(self.or()?);
+                        if self.eat(&Token::Close) {
+                            break;
+                        }
+                        if !self.eat(&Token::Comma) {
+                            return Err(AppError::config(format!("missing ')' after the arguments of {}", name)));
+                        }
+                    }
+                }
+                self.model.check_call(&name, args.len())?;
+                Ok(Expr::Call(name, args))
+            }
+            Token::Ident(name) => self.model.field(&name),
+            token => Err(AppError::config(format!("unexpected {:?} in matcher", token))),
+        }
+    }
+}
+
+/// Definitions of a model, against which matchers and policies are checked
+#[derive(Debug, Clone, Default)]
+struct ModelShape {
+    request: Vec<String>,
+    policy: Vec<String>,
+    /// Role definition names (`g`, `g2`) and whether they carry a domain
+    roles: BTreeMap<String, bool>,
+}
+
+impl ModelShape {
+    fn field(&self, name: &str) -> AppResult<Expr> {
+        let (prefix, field) = name
+            .split_once('.')
+            .ok_or_else(|| AppError::config(format!("unknown identifier '{}' in matcher", name)))?;
+        let fields = match prefix {
+            "r" => &self.request,
+            "p" => &self.policy,
+            _ => return Err(AppError::config(format!("unknown identifier '{}' in matcher", name))),
+        };
+        let index = fields
+            .iter()
+            .position(|defined| defined == field)
+            .ok_or_else(|| AppError::config(format!("'{}' is not defined by the model", name)))?;
+        Ok(Expr::Field(prefix.chars().next().unwrap_or('r'), index))
+    }
+
+    fn check_call(&self, name: &str, arity: usize) -> AppResult<()> {
+        let expected = match name {
+            "keyMatch" | "keyMatch2" => 2,
+            name => match self.roles.get(name) {
+                Some(true) => 3,
+                Some(false) => 2,
+                None => return Err(AppError::config(format!("unsupported matcher function {}", name))),
+            },
+        };
+        if arity != expected {
+            return Err(AppError::config(format!("{} takes {} arguments", name, expected)));
+        }
+        Ok(())
+    }
+}
+
+/// A parsed model: what requests and policies look like, how they match and
+/// how matching policies combine
+#[derive(Debug, Clone)]
+pub struct Model {
+    shape: ModelShape,
+    effect: Effect,
+    matcher: Expr,
+}
+
+impl Model {
+    /// Parses a Casbin-style model (`request_definition`,
+    /// `policy_definition`, optional `role_definition`, `policy_effect` and
+    /// `matchers` sections)
+    ///
+    /// Matchers support `==`, `!=`, `!`, `&&`, `||`, parentheses, string
+    /// literals and the `keyMatch`, `keyMatch2` and role (`g`, `g2`, ...)
+    /// functions.
+    ///
+    /// # Errors
+    /// Returns a config error naming the missing section or unsupported construct
+    pub fn parse(text: &str) -> AppResult<Self> {
+        let mut sections: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        let mut current = None;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                current = Some(name.trim().to_string());
+                continue;
+            }
+            let section = current
+                .clone()
+                .ok_or_else(|| AppError::config(format!("model line outside a section: {}", line)))?;
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| AppError::config(format!("expected 'key = value' in the model: {}", line)))?;
+            sections
+                .entry(section)
+                .or_default()
+                .push((key.trim().to_string(), value.trim().to_string()));
+        }
+        let single = |section: &str, key: &str| -> AppResult<String> {
+            sections
+                .get(section)
+                .and_then(|entries| entries.iter().find(|(name, _)| name == key))
+                .map(|(_, value)| value.clone())
+                .ok_or_else(|| AppError::config(format!("model lacks {} in [{}]", key, section)))
+        };
+        let fields = |value: String| -> Vec<String> { value.split(',').map(|field| field.trim().to_string()).collect() };
+
+        let shape = ModelShape {
+            request: fields(single("request_definition", "r")?),
+            policy: fields(single("policy_definition", "p")?),
+            roles: sections
+                .get("role_definition")
+                .into_iter()
+                .flatten()
+                .map(|(name, value)| match value.split(',').count() {
+                    2 => Ok((name.clone(), false)),
+                    3 => Ok((name.clone(), true)),
+                    _ => Err(AppError::config(format!("role definition {} must be '_, _' or '_, _, _'", name))),
+                })
+                .collect::<AppResult<_>>()?,
+        };
+        let effect = Effect::parse(&single("policy_effect", "e")?)?;
+        let matcher = Parser {
+            tokens: tokenize(&single("matchers", "m")?)?,
+            position: 0,
+            model: &shape,
+        }
+        .parse()?;
+        Ok(Self { shape, effect, matcher })
+    }
+}
+
+/// Policies and role links loaded from a policy file
+///
+/// One rule per line, fields separated by commas, `#` starting a comment:
+/// `p, alice, /reports/*, GET` grants and `g, alice, editor` links a
+/// subject to a role (with a third field for the domain when the role
+/// definition has one). With an `eft` field in the policy definition,
+/// policies end with `allow` or `deny`.
+#[derive(Debug, Clone, Default)]
+pub struct PolicySet {
+    policies: Vec<Vec<String>>,
+    links: BTreeMap<String, Vec<Vec<String>>>,
+}
+
+impl PolicySet {
+    /// Parses policy lines against `model`
+    ///
+    /// # Errors
+    /// Returns a config error for a line with the wrong number of fields or
+    /// an undefined section
+    pub fn parse(text: &str, model: &Model) -> AppResult<Self> {
+        let mut set = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields: Vec<String> = line.split(',').map(|field| field.trim().to_string()).collect();
+            let kind = fields.remove(0);
+            let expected = match kind.as_str() {
+                "p" => model.shape.policy.len(),
+                kind => match model.shape.roles.get(kind) {
+                    Some(true) => 3,
+                    Some(false) => 2,
+                    None => return Err(AppError::config(format!("line {}: unknown rule type '{}'", number + 1, kind))),
+                },
+            };
+            if fields.len() != expected {
+                return Err(AppError::config(format!(
+                    "line {}: '{}' rules take {} fields, got {}",
+                    number + 1,
+                    kind,
+                    expected,
+                    fields.len()
+                )));
+            }
+            if kind == "p" {
+                set.policies.push(fields);
+            } else {
+                set.links.entry(kind).or_default().push(fields);
+            }
+        }
+        Ok(set)
+    }
+}
+
+/// A model and its policies, answering allow/deny questions
+#[derive(Debug, Clone)]
+pub struct Enforcer {
+    model: Model,
+    policies: PolicySet,
+}
+
+/// What a request is evaluated with
+struct Evaluation<'a> {
+    request: &'a [&'a str],
+    policy: &'a [String],
+    /// Roles the subject holds outside the policy, e.g. from its token
+    extra_roles: &'a [String],
+}
+
+impl Enforcer {
+    /// Combines a model and its policies
+    pub fn new(model: Model, policies: PolicySet) -> Self {
+        Self { model, policies }
+    }
+
+    /// Returns whether the policies allow `request`, whose values follow the
+    /// model's request definition
+    ///
+    /// `extra_roles` are roles of the request's first value (its subject)
+    /// granted outside the policy file, e.g. by the `roles` token claim; the
+    /// first role definition (`g`) honours them.
+    ///
+    /// # Errors
+    /// Returns a validation error when `request` does not have as many
+    /// values as the request definition
+    pub fn enforce(&self, request: &[&str], extra_roles: &[String]) -> AppResult<bool> {
+        if request.len() != self.model.shape.request.len() {
+            return Err(AppError::validation(format!(
+                "policy requests take {} values ({})",
+                self.model.shape.request.len(),
+                self.model.shape.request.join(", ")
+            )));
+        }
+        let eft = self.model.shape.policy.iter().position(|field| field == "eft");
+        let (mut allowed, mut denied) = (false, false);
+        for policy in &self.policies.policies {
+            let evaluation = Evaluation { request, policy, extra_roles };
+            if !self.truthy(&self.model.matcher, &evaluation) {
+                continue;
+            }
+            match eft.map(|index| policy[index].as_str()) {
+                Some("deny") => denied = true,
+                _ => allowed = true,
+            }
+        }
+        Ok(match self.model.effect {
+            Effect::AllowOverride => allowed,
+            Effect::DenyOverride => !denied,
+            Effect::AllowAndDeny => allowed && !denied,
+        })
+    }
+
+    /// Returns the roles `subject` holds through the policy's `g` links
+    pub fn roles_for(&self, subject: &str) -> Vec<String> {
+        let mut roles: Vec<String> = self
+            .policies
+            .links
+            .get("g")
+            .into_iter()
+            .flatten()
+            .filter(|link| link[0] == subject)
+            .map(|link| link[1].clone())
+            .collect();
+        roles.sort();
+        roles.dedup();
+        roles
+    }
+
+    fn truthy(&self, expr: &Expr, evaluation: &Evaluation) -> bool {
+        match expr {
+            Expr::Bool(value) => *value,
+            Expr::Not(inner) => !self.truthy(inner, evaluation),
+            Expr::And(left, right) => self.truthy(left, evaluation) && self.truthy(right, evaluation),
+            Expr::Or(left, right) => self.truthy(left, evaluation) || self.truthy(right, evaluation),
+            Expr::Eq(left, right) => self.value(left, evaluation) == self.value(right, evaluation),
+            Expr::Ne(left, right) => self.value(left, evaluation) != self.value(right, evaluation),
+            Expr::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| self.value(arg, evaluation)).collect();
+                match name.as_str() {
+                    "keyMatch" => key_match(&args[0], &args[1], false),
+                    "keyMatch2" => key_match(&args[0], &args[1], true),
+                    role => self.has_role(role, &args[0], &args[1], args.get(2).map(String::as_str), evaluation),
+                }
+            }
+            Expr::Str(value) => !value.is_empty(),
+            Expr::Field(..) => !self.value(expr, evaluation).is_empty(),
+        }
+    }
+
+    fn value(&self, expr: &Expr, evaluation: &Evaluation) -> String {
+        match expr {
+            Expr::Str(value) => value.clone(),
+            Expr::Field('r', index) => evaluation.request[*index].to_string(),
+            Expr::Field(_, index) => evaluation.policy[*index].clone(),
+            expr => self.truthy(expr, evaluation).to_string(),
+        }
+    }
+
+    /// Whether `name` reaches `role` through the links of the `definition`
+    /// role definition, within `domain` when it has one
+    fn has_role(&self, definition: &str, name: &str, role: &str, domain: Option<&str>, evaluation: &Evaluation) -> bool {
+        if name == role {
+            return true;
+        }
+        let links = self.policies.links.get(definition);
+        let mut frontier = vec![name.to_string()];
+        if definition == "g" && evaluation.request.first() == Some(&name) {
+            frontier.extend(evaluation.extra_roles.iter().cloned());
+        }
+        let mut visited: HashSet<String> = frontier.iter().cloned().collect();
+        for _ in 0..=MAX_ROLE_DEPTH {
+            if frontier.iter().any(|held| held == role) {
+                return true;
+            }
+            let next: Vec<String> = links
+                .into_iter()
+                .flatten()
+                .filter(|link| frontier.contains(&link[0]) && domain.is_none_or(|domain| link.get(2).map(String::as_str) == Some(domain)))
+                .map(|link| link[1].clone())
+                .filter(|parent| visited.insert(parent.clone()))
+                .collect();
+            if next.is_empty() {
+                return false;
+            }
+            frontier = next;
+        }
+        false
+    }
+}
+
+/// Casbin `keyMatch` (`*` matching anything) and, with `params`, `keyMatch2`
+/// (`:name` matching one path segment as well)
+fn key_match(key: &str, pattern: &str, params: bool) -> bool {
+    fn matches(key: &[u8], pattern: &[u8], params: bool) -> bool {
+        match pattern.first() {
+            None => key.is_empty(),
+            Some(b'*') => (0..=key.len()).any(|skip| matches(&key[skip..], &pattern[1..], params)),
+            Some(b':') if params => {
+                let name = pattern[1..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+                    .count();
+                let segment = key.iter().take_while(|c| **c != b'/').count();
+                segment > 0 && matches(&key[segment..], &pattern[1 + name..], params)
+            }
+            Some(c) => key.first() == Some(c) && matches(&key[1..], &pattern[1..], params),
+        }
+    }
+    matches(key.as_bytes(), pattern.as_bytes(), params)
+}
+
+/// Counts reported after loading the policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PolicySummary {
+    pub policies: usize,
+    pub role_links: usize,
+}
+
+/// Allow/deny decisions for handlers and middleware, from the model in
+/// `POLICY_MODEL_FILE` (or [`DEFAULT_MODEL`]) and the rules in `POLICY_FILE`
+///
+/// Registered as app data and consulted by [`require_policy`] on the routes in
+/// `POLICY_ROUTES`. [`Authorizer::reload`] reads both files again at
+/// runtime, keeping the current enforcer when they are invalid. Without
+/// `POLICY_FILE` there are no rules, so nothing is allowed.
+pub struct Authorizer {
+    model_file: Option<String>,
+    policy_file: Option<String>,
+    enforcer: RwLock<Arc<Enforcer>>,
+}
+
+impl Authorizer {
+    /// Creates an authorizer around a fixed enforcer, not backed by files
+    pub fn new(enforcer: Enforcer) -> Self {
+        Self {
+            model_file: None,
+            policy_file: None,
+            enforcer: RwLock::new(Arc::new(enforcer)),
+        }
+    }
+
+    /// Loads the model and policy files named in the configuration
+    ///
+    /// # Errors
+    /// Returns a config error when a file cannot be read or is invalid
+    pub fn from_config(config: &Config) -> AppResult<Self> {
+        let enforcer = Self::load(config.policy_model_file.as_deref(), config.policy_file.as_deref())?;
+        Ok(Self {
+            model_file: config.policy_model_file.clone(),
+            policy_file: config.policy_file.clone(),
+            enforcer: RwLock::new(Arc::new(enforcer)),
+        })
+    }
+
+    fn load(model_file: Option<&str>, policy_file: Option<&str>) -> AppResult<Enforcer> {
+        let read = |variable: &str, path: &str| {
+            std::fs::read_to_string(path).map_err(|e| AppError::config(format!("Failed to read {} {}: {}", variable, path, e)))
+        };
+        let model = match model_file {
+            Some(path) => Model::parse(&read("POLICY_MODEL_FILE", path)?)
+                .map_err(|e| AppError::config(format!("Invalid POLICY_MODEL_FILE {}: {}", path, e)))?,
+            None => Model::parse(DEFAULT_MODEL)?,
+        };
+        let policies = match policy_file {
+            Some(path) => PolicySet::parse(&read("POLICY_FILE", path)?, &model)
+                .map_err(|e| AppError::config(format!("Invalid POLICY_FILE {}: {}", path, e)))?,
+            None => PolicySet::default(),
+        };
+        Ok(Enforcer::new(model, policies))
+    }
+
+    /// Reads the model and policy files again and swaps them in
+    ///
+    /// # Errors
+    /// Returns a config error, leaving the current policy in force, when a
+    /// file cannot be read or is invalid
+    pub fn reload(&self) -> AppResult<PolicySummary> {
+        let enforcer = Self::load(self.model_file.as_deref(), self.policy_file.as_deref())?;
+        let summary = Self::summarize(&enforcer);
+        *self.enforcer.write().map_err(|_| poisoned())? = Arc::new(enforcer);
+        info!(
+            "Reloaded the authorization policy: {} policies, {} role links",
+            summary.policies, summary.role_links
+        );
+        Ok(summary)
+    }
+
+    /// Counts the rules in force
+    pub fn summary(&self) -> AppResult<PolicySummary> {
+        Ok(Self::summarize(self.enforcer()?.as_ref()))
+    }
+
+    fn summarize(enforcer: &Enforcer) -> PolicySummary {
+        PolicySummary {
+            policies: enforcer.policies.policies.len(),
+            role_links: enforcer.policies.links.values().map(Vec::len).sum(),
+        }
+    }
+
+    /// Returns the enforcer currently in force
+    pub fn enforcer(&self) -> AppResult<Arc<Enforcer>> {
+        Ok(self.enforcer.read().map_err(|_| poisoned())?.clone())
+    }
+
+    /// Returns whether the policy allows `request` (see [`Enforcer::enforce`])
+    pub fn enforce(&self, request: &[&str], extra_roles: &[String]) -> AppResult<bool> {
+        self.enforcer()?.enforce(request, extra_roles)
+    }
+
+    /// Requires the policy to allow the token's subject, with its `roles`
+    /// claim, to perform `action` on `object`
+    ///
+    /// # Errors
+    /// Forbidden when the policy denies it
+    pub fn authorize(&self, claims: &Claims, object: &str, action: &str) -> AppResult<()> {
+        if self.enforce(&[&claims.sub, object, action], &claims.roles())? {
+            Ok(())
+        } else {
+            Err(AppError::forbidden(format!("{} {} is denied by policy", action, object)))
+        }
+    }
+}
+
+fn poisoned() -> AppError {
+    AppError::internal("authorization policy lock poisoned")
+}
+
+/// Middleware asking the [`Authorizer`] whether the caller may use the
+/// request's method on its path, for use with `from_fn`
+///
+/// Authenticates the bearer credential unless a scope guard already did.
+///
+/// # Errors
+/// Unauthorized without a valid token, forbidden when the policy denies the
+/// request, internal error when no `web::Data<Authorizer>` is registered
+pub async fn require_policy(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let span = RequestContext::span(&req, "auth");
+    let claims = match req.extensions().get::<Claims>().cloned() {
+        Some(claims) => claims,
+        None => authenticate(&req)?,
+    };
+    let authorizer = req
+        .app_data::<web::Data<Authorizer>>()
+        .ok_or_else(|| AppError::internal("authorization policy not configured"))?;
+    if let Err(e) = authorizer.authorize(&claims, req.path(), req.method().as_str()) {
+        warn!("Policy denied {} {} to {}", req.method(), req.path(), claims.sub);
+        return Err(e.into());
+    }
+    RequestContext::set_principal(&req, AuthPrincipal::from_claims(&claims), Some(&claims));
+    req.extensions_mut().insert(claims);
+    drop(span);
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenService;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use serde_json::json;
+    use std::time::Duration;
+
+    const POLICY: &str = "
+        # Editors manage reports, readers read them
+        p, reader, /reports/*, GET
+        p, editor, /reports/:id, *
+        p, alice, /private, GET
+        g, bob, editor
+        g, editor, reader
+    ";
+
+    fn enforcer(model: &str, policy: &str) -> Enforcer {
+        let model = Model::parse(model).unwrap();
+        let policies = PolicySet::parse(policy, &model).unwrap();
+        Enforcer::new(model, policies)
+    }
+
+    #[test]
+    fn test_default_model_follows_roles_and_key_matches() {
+        let enforcer = enforcer(DEFAULT_MODEL, POLICY);
+        let allowed = |sub: &str, obj: &str, act: &str| enforcer.enforce(&[sub, obj, act], &[]).unwrap();
+        assert!(allowed("alice", "/private", "GET"));
+        assert!(!allowed("alice", "/private", "POST"));
+        assert!(!allowed("alice", "/reports/1", "GET"));
+        assert!(allowed("bob", "/reports/1", "DELETE"));
+        assert!(allowed("bob", "/reports/1/pages", "GET"));
+        assert!(!allowed("bob", "/reports/1/pages", "DELETE"));
+        assert!(enforcer.enforce(&["carol", "/reports/1", "GET"], &["reader".to_string()]).unwrap());
+        assert_eq!(enforcer.roles_for("bob"), vec!["editor".to_string()]);
+        assert!(enforcer.enforce(&["bob", "/reports"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_deny_effect_and_domains() {
+        let model = "
+            [request_definition]
+            r = sub, dom, obj, act
+            [policy_definition]
+            p = sub, dom, obj, act, eft
+            [role_definition]
+            g = _, _, _
+            [policy_effect]
+            e = some(where (p.eft == allow)) && !some(where (p.eft == deny))
+            [matchers]
+            m = g(r.sub, p.sub, r.dom) && r.dom == p.dom && keyMatch(r.obj, p.obj) && r.act == p.act
+        ";
+        let enforcer = enforcer(
+            model,
+            "p, admin, acme, /data/*, read, allow
+             p, admin, acme, /data/secret, read, deny
+             g, alice, admin, acme",
+        );
+        let allowed = |sub: &str, dom: &str, obj: &str| enforcer.enforce(&[sub, dom, obj, "read"], &[]).unwrap();
+        assert!(allowed("alice", "acme", "/data/report"));
+        assert!(!allowed("alice", "acme", "/data/secret"));
+        assert!(!allowed("alice", "globex", "/data/report"));
+    }
+
+    #[test]
+    fn test_invalid_models_and_policies() {
+        assert!(Model::parse("[request_definition]\nr = sub").is_err());
+        let unsupported = DEFAULT_MODEL.replace("keyMatch2(r.obj, p.obj)", "regexMatch(r.obj, p.obj)");
+        assert!(Model::parse(&unsupported).unwrap_err().to_string().contains("regexMatch"));
+        let unknown = DEFAULT_MODEL.replace("p.act == \"*\"", "p.action == \"*\"");
+        assert!(Model::parse(&unknown).is_err());
+        let model = Model::parse(DEFAULT_MODEL).unwrap();
+        assert!(PolicySet::parse("p, alice, /private", &model).unwrap_err().to_string().contains("line 1"));
+        assert!(PolicySet::parse("g2, alice, admin", &model).is_err());
+    }
+
+    #[test]
+    fn test_reload_keeps_the_current_policy_on_errors() {
+        let path = std::env::temp_dir().join(format!("policy-{}.csv", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "p, alice, /private, GET\n").unwrap();
+        let config = Config {
+            policy_file: Some(path.to_string_lossy().to_string()),
+            ..Config::default()
+        };
+        let authorizer = Authorizer::from_config(&config).unwrap();
+        assert!(authorizer.enforce(&["alice", "/private", "GET"], &[]).unwrap());
+
+        std::fs::write(&path, "p, bob, /private, GET\ng, alice, bob\n").unwrap();
+        assert_eq!(authorizer.reload().unwrap(), PolicySummary { policies: 1, role_links: 1 });
+        assert!(authorizer.enforce(&["alice", "/private", "GET"], &[]).unwrap());
+
+        std::fs::write(&path, "p, carol\n").unwrap();
+        assert!(authorizer.reload().is_err());
+        assert_eq!(authorizer.summary().unwrap().role_links, 1);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!Authorizer::from_config(&Config::default()).unwrap().enforce(&["alice", "/private", "GET"], &[]).unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_require_policy_middleware() {
+        let tokens = TokenService::new(b"policy-test-key-policy-test-key-", "test");
+        let token = |subject: &str, roles: &[&str]| {
+            let mut claims = tokens.claims(subject, &[], Duration::from_secs(60));
+            claims.extra.insert("roles".to_string(), json!(roles));
+            tokens.sign(&claims).unwrap()
+        };
+        let (bob, carol, dave) = (token("bob", &[]), token("carol", &["reader"]), token("dave", &[]));
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(tokens))
+                .app_data(web::Data::new(Authorizer::new(enforcer(DEFAULT_MODEL, POLICY))))
+                .route(
+                    "/reports/{id}",
+                    web::route().to(HttpResponse::Ok).wrap(from_fn(require_policy)),
+                ),
+        )
+        .await;
+
+        let request = |method: &str, token: &str| {
+            TestRequest::default()
+                .method(method.parse().unwrap())
+                .uri("/reports/7")
+                .insert_header(("Authorization", format!("Bearer {}", token)))
+                .to_request()
+        };
+        assert_eq!(call_service(&app, request("DELETE", &bob)).await.status(), 200);
+        assert_eq!(call_service(&app, request("GET", &carol)).await.status(), 200);
+        let err = try_call_service(&app, request("DELETE", &carol)).await.unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 403);
+        let err = try_call_service(&app, request("GET", &dave)).await.unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 403);
+        let err = try_call_service(&app, TestRequest::get().uri("/reports/7").to_request())
+            .await
+            .unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 401);
+    }
+}
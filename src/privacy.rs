@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde_json::{json, Value};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::auth::{ApiKeyService, SessionRegistry};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::events::CloudEvent;
+use crate::state::KeyValueStore;
+use crate::supervisor::Supervisor;
+use crate::users::{User, UserRepository};
+
+/// Identifier of the export layout, written into `manifest.json`
+pub const EXPORT_FORMAT: &str = "simple-api-demo-export/1";
+
+/// How often due erasures are looked for
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Data subject rights: export of a user's data and erasure of the account
+///
+/// Erasure is two-phase: [`schedule_erasure`](Self::schedule_erasure) marks
+/// the account and starts the grace period, during which the user can still
+/// sign in and cancel; [`purge_due`](Self::purge_due) then removes the
+/// account, its sessions, API keys and audit trail. Scheduled erasures are
+/// indexed under `pending` (JSON map of user id to due time).
+pub struct PrivacyService {
+    store: Arc<dyn KeyValueStore>,
+    repository: UserRepository,
+    sessions: Arc<SessionRegistry>,
+    api_keys: Arc<ApiKeyService>,
+    grace: Duration,
+}
+
+impl PrivacyService {
+    /// Creates the service
+    ///
+    /// # Arguments
+    /// * `store` - Holds the index of scheduled erasures
+    /// * `repository` - User storage
+    /// * `sessions` - Sessions to export and revoke
+    /// * `api_keys` - API keys to export and revoke
+    /// * `grace` - Delay between an erasure request and the erasure
+    pub fn new(
+        store: Arc<dyn KeyValueStore>,
+        repository: UserRepository,
+        sessions: Arc<SessionRegistry>,
+        api_keys: Arc<ApiKeyService>,
+        grace: Duration,
+    ) -> Self {
+        Self {
+            store,
+            repository,
+            sessions,
+            api_keys,
+            grace,
+        }
+    }
+
+    /// Builds the service with the `ACCOUNT_DELETION_GRACE_SECS` grace period
+    pub fn from_config(
+        config: &Config,
+        store: Arc<dyn KeyValueStore>,
+        repository: UserRepository,
+        sessions: Arc<SessionRegistry>,
+        api_keys: Arc<ApiKeyService>,
+    ) -> Self {
+        Self::new(
+            store,
+            repository,
+            sessions,
+            api_keys,
+            Duration::from_secs(config.account_deletion_grace_secs),
+        )
+    }
+
+    /// Builds a ZIP archive with everything stored about `user_id`
+    ///
+    /// The archive holds `manifest.json`, `profile.json`, `sessions.json`,
+    /// `api_keys.json` (metadata only, secrets are never stored) and
+    /// `audit_trail.json`. The export itself is recorded in the audit trail.
+    pub fn export(&self, user_id: &str) -> AppResult<Vec<u8>> {
+        let user = self.load(user_id)?;
+        self.repository.record_event(&user.id, "user.data_exported", Value::Null)?;
+
+        let mut profile = user.profile();
+        profile["terms_accepted_at"] = json!(user.terms_accepted_at.map(|at| at.to_rfc3339()));
+        profile["updated_at"] = json!(user.updated_at.to_rfc3339());
+        let files = [
+            ("profile.json", profile),
+            ("sessions.json", json!(self.sessions.list(&user.id)?)),
+            ("api_keys.json", json!(self.api_keys.list(&user.id)?)),
+            ("audit_trail.json", json!(self.repository.events(&user.id)?)),
+        ];
+        let manifest = json!({
+            "format": EXPORT_FORMAT,
+            "user_id": user.id,
+            "generated_at": Utc::now().to_rfc3339(),
+            "files": files.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+        });
+
+        let archive_error = |e: &dyn std::fmt::Display| AppError::internal(format!("Failed to build export: {}", e));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in std::iter::once(("manifest.json", manifest)).chain(files) {
+            let encoded = serde_json::to_vec_pretty(&content).map_err(|e| archive_error(&e))?;
+            archive.start_file(name, options).map_err(|e| archive_error(&e))?;
+            archive.write_all(&encoded).map_err(|e| archive_error(&e))?;
+        }
+        Ok(archive.finish().map_err(|e| archive_error(&e))?.into_inner())
+    }
+
+    /// Schedules erasure of `user_id` after the grace period
+    ///
+    /// Callers must have re-authenticated the user. Scheduling again keeps
+    /// the original due time.
+    pub fn schedule_erasure(&self, user_id: &str) -> AppResult<User> {
+        let mut user = self.load(user_id)?;
+        if user.erase_after.is_some() {
+            return Ok(user);
+        }
+
+        let erase_after = Utc::now() + chrono::Duration::seconds(self.grace.as_secs() as i64);
+        user.erase_after = Some(erase_after);
+        user.updated_at = Utc::now();
+        self.repository.save(&user)?;
+        let mut pending = self.pending()?;
+        pending.insert(user.id.clone(), erase_after);
+        self.save_pending(&pending)?;
+        self.repository.record_event(
+            &user.id,
+            "user.erasure_scheduled",
+            json!({ "erase_after": erase_after.to_rfc3339() }),
+        )?;
+        Ok(user)
+    }
+
+    /// Cancels a scheduled erasure during the grace period
+    ///
+    /// # Errors
+    /// Validation error when no erasure is scheduled
+    pub fn cancel_erasure(&self, user_id: &str) -> AppResult<User> {
+        let mut user = self.load(user_id)?;
+        if user.erase_after.take().is_none() {
+            return Err(AppError::validation("no account erasure is scheduled"));
+        }
+        user.updated_at = Utc::now();
+        self.repository.save(&user)?;
+        let mut pending = self.pending()?;
+        pending.remove(&user.id);
+        self.save_pending(&pending)?;
+        self.repository
+            .record_event(&user.id, "user.erasure_cancelled", Value::Null)?;
+        Ok(user)
+    }
+
+    /// Erases every account whose grace period has ended
+    ///
+    /// Returns the erased user ids. Each erasure is written to the `audit`
+    /// log target, which outlives the erased audit trail; the entry carries
+    /// the user id only.
+    pub fn purge_due(&self) -> AppResult<Vec<String>> {
+        let now = Utc::now();
+        let mut pending = self.pending()?;
+        let due: Vec<String> = pending
+            .iter()
+            .filter(|(_, erase_after)| **erase_after <= now)
+            .map(|(user_id, _)| user_id.clone())
+            .collect();
+
+        let mut erased = Vec::new();
+        for user_id in due {
+            pending.remove(&user_id);
+            // A cancellation racing the purge wins: only still-marked accounts go
+            let Some(user) = self.repository.get(&user_id)? else {
+                continue;
+            };
+            if user.erase_after.is_none_or(|at| at > now) {
+                continue;
+            }
+            let sessions = self.sessions.revoke_all(&user.id)?;
+            let api_keys = self.api_keys.revoke_all(&user.id)?;
+            self.repository.delete(&user)?;
+
+            let event = CloudEvent::new(
+                "com.simple-api-demo.user.erased",
+                json!({ "sessions_revoked": sessions, "api_keys_revoked": api_keys }),
+            )
+            .with_subject(user.id.clone());
+            info!(target: "audit", "{}", event.to_json()?);
+            erased.push(user.id);
+        }
+        self.save_pending(&pending)?;
+        Ok(erased)
+    }
+
+    /// Runs [`purge_due`](Self::purge_due) every `every` under `supervisor`
+    pub fn spawn_purger(service: Arc<Self>, every: Duration, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("account_erasure", move || {
+            let service = service.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(every);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = service.purge_due() {
+                        error!("Account erasure purge failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    fn load(&self, user_id: &str) -> AppResult<User> {
+        self.repository
+            .get(user_id)?
+            .ok_or_else(|| AppError::not_found("user not found"))
+    }
+
+    fn pending(&self) -> AppResult<BTreeMap<String, DateTime<Utc>>> {
+        self.store
+            .get("pending")?
+            .map(|raw| {
+                serde_json::from_str(&raw)
+                    .map_err(|e| AppError::internal(format!("Corrupt erasure index: {}", e)))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn save_pending(&self, pending: &BTreeMap<String, DateTime<Utc>>) -> AppResult<()> {
+        let encoded = serde_json::to_string(pending)
+            .map_err(|e| AppError::internal(format!("Failed to encode erasure index: {}", e)))?;
+        self.store.set("pending", &encoded, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenDenylist;
+    use crate::state::InMemoryStore;
+    use std::io::Read;
+
+    fn setup(grace: Duration) -> (PrivacyService, UserRepository, Arc<SessionRegistry>, Arc<ApiKeyService>) {
+        let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+        let sessions = Arc::new(SessionRegistry::new(
+            Arc::new(InMemoryStore::new()),
+            TokenDenylist::new(Arc::new(InMemoryStore::new())),
+            Duration::from_secs(3600),
+        ));
+        let api_keys = Arc::new(ApiKeyService::new(Arc::new(InMemoryStore::new())));
+        let service = PrivacyService::new(
+            Arc::new(InMemoryStore::new()),
+            repository.clone(),
+            sessions.clone(),
+            api_keys.clone(),
+            grace,
+        );
+        (service, repository, sessions, api_keys)
+    }
+
+    #[test]
+    fn test_export_archive() {
+        let (service, repository, sessions, api_keys) = setup(Duration::from_secs(60));
+        let user = repository.create("ann@example.com", "secret-hash".to_string()).unwrap();
+        sessions.create(&user.id, "Laptop", "192.0.2.1".parse().unwrap()).unwrap();
+        api_keys.create(&user.id, "CI", &[], &["read:private"], None).unwrap();
+
+        let bytes = service.export(&user.id).unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut read = |name: &str| {
+            let mut content = String::new();
+            archive.by_name(name).unwrap().read_to_string(&mut content).unwrap();
+            content
+        };
+
+        let manifest: Value = serde_json::from_str(&read("manifest.json")).unwrap();
+        assert_eq!(manifest["format"], EXPORT_FORMAT);
+        assert_eq!(manifest["files"].as_array().unwrap().len(), 4);
+        let profile = read("profile.json");
+        assert!(profile.contains("ann@example.com") && !profile.contains("secret-hash"));
+        assert!(read("sessions.json").contains("Laptop"));
+        assert!(read("api_keys.json").contains("CI"));
+        assert!(read("audit_trail.json").contains("user.data_exported"));
+    }
+
+    #[test]
+    fn test_erasure_after_grace_period() {
+        let (service, repository, sessions, api_keys) = setup(Duration::ZERO);
+        let user = repository.create("ann@example.com", String::new()).unwrap();
+        sessions.create(&user.id, "Laptop", "192.0.2.1".parse().unwrap()).unwrap();
+        api_keys.create(&user.id, "CI", &[], &["read:private"], None).unwrap();
+
+        // Cancelled erasures are not carried out
+        service.schedule_erasure(&user.id).unwrap();
+        assert!(service.cancel_erasure(&user.id).unwrap().erase_after.is_none());
+        assert!(service.purge_due().unwrap().is_empty());
+        assert!(service.cancel_erasure(&user.id).is_err());
+
+        let scheduled = service.schedule_erasure(&user.id).unwrap();
+        assert!(scheduled.erase_after.is_some());
+        assert_eq!(service.purge_due().unwrap(), vec![user.id.clone()]);
+        assert!(repository.get(&user.id).unwrap().is_none());
+        assert!(repository.find_by_email("ann@example.com").unwrap().is_none());
+        assert!(repository.events(&user.id).unwrap().is_empty());
+        assert!(sessions.list(&user.id).unwrap().is_empty());
+        assert!(api_keys.list(&user.id).unwrap().is_empty());
+
+        // The address can be registered again
+        assert!(repository.create("ann@example.com", String::new()).is_ok());
+    }
+
+    #[test]
+    fn test_erasure_waits_for_grace_period() {
+        let (service, repository, _, _) = setup(Duration::from_secs(3600));
+        let user = repository.create("ann@example.com", String::new()).unwrap();
+        service.schedule_erasure(&user.id).unwrap();
+        assert!(service.purge_due().unwrap().is_empty());
+        assert!(repository.get(&user.id).unwrap().is_some());
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::scopes::authenticate;
+use crate::auth::Claims;
+use crate::config::Config;
+use crate::context::{AuthPrincipal, RequestContext};
+use crate::error::{AppError, AppResult};
+
+/// Built-in admin role allowed to read admin data (usage, dumps)
+pub const VIEWER_ROLE: &str = "viewer";
+
+/// Built-in admin role allowed to run admin operations, inheriting [`VIEWER_ROLE`]
+pub const OPERATOR_ROLE: &str = "operator";
+
+/// Built-in admin role allowed everything, such as key rotation and
+/// impersonation, inheriting [`OPERATOR_ROLE`]
+pub const OWNER_ROLE: &str = "owner";
+
+/// Role of the admins predating the tiers, inheriting [`OWNER_ROLE`]
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Permission granted by a role, e.g. `reports:read`
+///
+/// `*` grants every permission and `reports:*` every permission of the
+/// `reports` resource.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Permission(String);
+
+impl Permission {
+    /// Creates a permission
+    pub fn new<T: Into<String>>(name: T) -> Self {
+        Self(name.into())
+    }
+
+    /// Returns whether holding this permission grants `requested`
+    pub fn grants(&self, requested: &str) -> bool {
+        match self.0.strip_suffix('*') {
+            Some(prefix) => (prefix.is_empty() || prefix.ends_with(':')) && requested.starts_with(prefix),
+            None => self.0 == requested,
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A named set of permissions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Role {
+    /// Permissions granted directly
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+    /// Roles held as well, with their permissions
+    #[serde(default)]
+    pub inherits: Vec<String>,
+}
+
+impl Role {
+    fn builtin(permission: &str, inherits: Option<&str>) -> Self {
+        Self {
+            permissions: vec![Permission::new(permission)],
+            inherits: inherits.into_iter().map(str::to_string).collect(),
+        }
+    }
+}
+
+/// Roles, their permissions and the routes they guard
+///
+/// A caller's roles are those in the `roles` claim of its access token plus
+/// the `default_roles`, and every role they inherit. The admin tiers
+/// [`VIEWER_ROLE`], [`OPERATOR_ROLE`] and [`OWNER_ROLE`] (with [`ADMIN_ROLE`]
+/// above them) are built in; a policy defining one of them replaces it. The
+/// policy file is JSON:
+///
+/// ```json
+/// {
+///   "default_roles": ["member"],
+///   "roles": {
+///     "member": { "permissions": ["private:read"] },
+///     "admin": { "permissions": ["*"], "inherits": ["member"] }
+///   },
+///   "routes": { "/private": ["member"] }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RbacPolicy {
+    /// Roles every authenticated caller holds
+    #[serde(default)]
+    pub default_roles: Vec<String>,
+    /// Role definitions by name
+    #[serde(default)]
+    pub roles: BTreeMap<String, Role>,
+    /// Application server paths and the roles allowed on them
+    #[serde(default)]
+    pub routes: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for RbacPolicy {
+    fn default() -> Self {
+        Self {
+            default_roles: Vec::new(),
+            roles: Self::builtin_roles(),
+            routes: BTreeMap::new(),
+        }
+    }
+}
+
+impl RbacPolicy {
+    /// Loads the policy from `RBAC_POLICY_FILE`; without one only the built-in admin roles exist
+    ///
+    /// # Errors
+    /// Returns a config error when the file cannot be read or parsed, or a
+    /// role inherits from an undefined role
+    pub fn from_config(config: &Config) -> AppResult<Self> {
+        let Some(path) = &config.rbac_policy_file else {
+            return Ok(Self::default());
+        };
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| AppError::config(format!("Failed to read RBAC_POLICY_FILE {}: {}", path, e)))?;
+        Self::parse(&content).map_err(|e| AppError::config(format!("Invalid RBAC_POLICY_FILE {}: {}", path, e)))
+    }
+
+    /// Parses a JSON policy
+    ///
+    /// # Errors
+    /// Returns a config error for malformed JSON or a role inheriting from
+    /// an undefined role
+    pub fn parse(json: &str) -> AppResult<Self> {
+        let mut policy: Self = serde_json::from_str(json).map_err(|e| AppError::config(e.to_string()))?;
+        for (name, role) in Self::builtin_roles() {
+            policy.roles.entry(name).or_insert(role);
+        }
+        for (name, role) in &policy.roles {
+            if let Some(parent) = role.inherits.iter().find(|parent| !policy.roles.contains_key(*parent)) {
+                return Err(AppError::config(format!("role '{}' inherits undefined role '{}'", name, parent)));
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Returns `roles` and every role they inherit
+    pub fn expand<'a>(&self, roles: impl IntoIterator<Item = &'a String>) -> BTreeSet<String> {
+        let mut pending: Vec<&String> = roles.into_iter().collect();
+        let mut visited = BTreeSet::new();
+        while let Some(name) = pending.pop() {
+            if !visited.insert(name.clone()) {
+                continue;
+            }
+            if let Some(role) = self.roles.get(name) {
+                pending.extend(&role.inherits);
+            }
+        }
+        visited
+    }
+
+    /// Returns the permissions of `roles`, including inherited ones
+    pub fn permissions<'a>(&self, roles: impl IntoIterator<Item = &'a String>) -> BTreeSet<Permission> {
+        self.expand(roles)
+            .iter()
+            .filter_map(|name| self.roles.get(name))
+            .flat_map(|role| role.permissions.iter().cloned())
+            .collect()
+    }
+
+    /// Resolves the roles and permissions of the token's subject
+    pub fn principal(&self, claims: &Claims) -> Principal {
+        let held: Vec<String> = claims.roles().into_iter().chain(self.default_roles.iter().cloned()).collect();
+        let roles = self.expand(&held);
+        Principal {
+            subject: claims.sub.clone(),
+            permissions: self.permissions(&roles),
+            roles,
+        }
+    }
+
+    /// The admin tiers, each inheriting the one below
+    fn builtin_roles() -> BTreeMap<String, Role> {
+        BTreeMap::from([
+            (VIEWER_ROLE.to_string(), Role::builtin("admin:read", None)),
+            (OPERATOR_ROLE.to_string(), Role::builtin("admin:operate", Some(VIEWER_ROLE))),
+            (OWNER_ROLE.to_string(), Role::builtin("admin:*", Some(OPERATOR_ROLE))),
+            (ADMIN_ROLE.to_string(), Role::builtin("admin:*", Some(OWNER_ROLE))),
+        ])
+    }
+}
+
+/// Authenticated caller with its resolved roles and permissions
+///
+/// Extract it in handlers behind a scope or role guard to check roles or
+/// permissions inline; it fails with 401 on unauthenticated requests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Principal {
+    pub subject: String,
+    pub roles: BTreeSet<String>,
+    pub permissions: BTreeSet<Permission>,
+}
+
+impl Principal {
+    /// Returns whether the principal holds `role`
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.contains(role)
+    }
+
+    /// Returns whether some role grants `permission`
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|granted| granted.grants(permission))
+    }
+
+    /// Requires at least one of `roles`
+    ///
+    /// # Errors
+    /// Forbidden, naming the accepted roles, when the principal holds none of them
+    pub fn require_any_role<S: AsRef<str>>(&self, roles: &[S]) -> AppResult<()> {
+        if roles.iter().any(|role| self.has_role(role.as_ref())) {
+            return Ok(());
+        }
+        let names: Vec<&str> = roles.iter().map(AsRef::as_ref).collect();
+        Err(AppError::forbidden(format!("requires one of the roles: {}", names.join(", "))))
+    }
+
+    /// Requires `permission`
+    ///
+    /// # Errors
+    /// Forbidden when no role grants it
+    pub fn require_permission(&self, permission: &str) -> AppResult<()> {
+        if self.has_permission(permission) {
+            Ok(())
+        } else {
+            Err(AppError::forbidden(format!("missing permission {}", permission)))
+        }
+    }
+
+    /// Returns the principal stored by [`require_roles`], or resolves it from
+    /// the [`Claims`] stored by the scope middleware
+    fn from_extensions(req: &HttpRequest) -> AppResult<Self> {
+        if let Some(principal) = req.extensions().get::<Principal>() {
+            return Ok(principal.clone());
+        }
+        let claims = req
+            .extensions()
+            .get::<Claims>()
+            .cloned()
+            .ok_or_else(|| AppError::unauthorized("authentication required"))?;
+        let policy = req
+            .app_data::<web::Data<RbacPolicy>>()
+            .ok_or_else(|| AppError::internal("RBAC policy not configured"))?;
+        Ok(policy.principal(&claims))
+    }
+}
+
+impl FromRequest for Principal {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::from_extensions(req))
+    }
+}
+
+/// Middleware requiring at least one of the given roles, for use with `from_fn`
+///
+/// Authenticates the bearer credential unless a scope guard already did; on
+/// success the [`Principal`] is stored in the request extensions.
+///
+/// # Errors
+/// Unauthorized without a valid token, forbidden without any of the roles,
+/// internal error when no `web::Data<RbacPolicy>` is registered
+pub async fn require_roles(
+    required: Rc<Vec<String>>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let span = RequestContext::span(&req, "auth");
+    let claims = match req.extensions().get::<Claims>().cloned() {
+        Some(claims) => claims,
+        None => authenticate(&req)?,
+    };
+    let policy = req
+        .app_data::<web::Data<RbacPolicy>>()
+        .ok_or_else(|| AppError::internal("RBAC policy not configured"))?;
+    let principal = policy.principal(&claims);
+    principal.require_any_role(&required)?;
+    RequestContext::set_principal(&req, AuthPrincipal::from_claims(&claims), Some(&claims));
+    req.extensions_mut().insert(claims);
+    req.extensions_mut().insert(principal);
+    drop(span);
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenService;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use serde_json::json;
+    use std::time::Duration;
+
+    const POLICY: &str = r#"{
+        "default_roles": ["member"],
+        "roles": {
+            "member": { "permissions": ["private:read"] },
+            "editor": { "permissions": ["reports:*"], "inherits": ["member"] },
+            "admin": { "permissions": ["*"], "inherits": ["editor"] }
+        },
+        "routes": { "/private": ["member"] }
+    }"#;
+
+    fn token(tokens: &TokenService, subject: &str, roles: &[&str]) -> String {
+        let mut claims = tokens.claims(subject, &[], Duration::from_secs(60));
+        claims.extra.insert("roles".to_string(), json!(roles));
+        tokens.sign(&claims).unwrap()
+    }
+
+    #[test]
+    fn test_permissions_follow_inheritance() {
+        let policy = RbacPolicy::parse(POLICY).unwrap();
+        let editor = policy.permissions(&["editor".to_string()]);
+        assert_eq!(
+            editor,
+            BTreeSet::from([Permission::new("private:read"), Permission::new("reports:*")])
+        );
+
+        let tokens = TokenService::new(b"rbac-test-key-rbac-test-key-0000", "test");
+        let claims = tokens.verify(&token(&tokens, "alice", &["editor"])).unwrap();
+        let principal = policy.principal(&claims);
+        assert!(principal.has_role("member"));
+        assert!(principal.has_permission("reports:write"));
+        assert!(!principal.has_permission("reportsx:write"));
+        assert!(principal.require_permission("users:delete").is_err());
+        assert!(policy.principal(&tokens.verify(&token(&tokens, "root", &["admin"])).unwrap()).has_permission("users:delete"));
+    }
+
+    #[test]
+    fn test_builtin_admin_tiers() {
+        let policy = RbacPolicy::default();
+        let tokens = TokenService::new(b"rbac-test-key-rbac-test-key-0000", "test");
+        let owner = policy.principal(&tokens.verify(&token(&tokens, "root", &[OWNER_ROLE])).unwrap());
+        assert_eq!(owner.roles, BTreeSet::from(["owner", "operator", "viewer"].map(String::from)));
+        assert!(owner.has_permission("admin:operate"));
+        let viewer = policy.principal(&tokens.verify(&token(&tokens, "support", &[VIEWER_ROLE])).unwrap());
+        assert!(viewer.require_any_role(&[VIEWER_ROLE]).is_ok());
+        assert!(viewer.require_any_role(&[OPERATOR_ROLE]).is_err());
+        assert!(!viewer.has_permission("admin:operate"));
+
+        // A policy file may add roles on top of the tiers, or redefine them
+        let custom = RbacPolicy::parse(r#"{"roles": {"support": {"inherits": ["viewer"]}, "owner": {}}}"#).unwrap();
+        let support = custom.principal(&tokens.verify(&token(&tokens, "sam", &["support"])).unwrap());
+        assert!(support.has_role(VIEWER_ROLE));
+        assert!(!custom.expand(&[OWNER_ROLE.to_string()]).contains(OPERATOR_ROLE));
+        assert!(custom.expand(&[ADMIN_ROLE.to_string()]).contains(OWNER_ROLE));
+    }
+
+    #[test]
+    fn test_parse_rejects_undefined_parent() {
+        let err = RbacPolicy::parse(r#"{"roles": {"admin": {"inherits": ["ghost"]}}}"#).unwrap_err();
+        assert!(err.to_string().contains("ghost"));
+        assert!(RbacPolicy::parse("not json").is_err());
+        let missing = Config {
+            rbac_policy_file: Some("/nonexistent/rbac.json".to_string()),
+            ..Config::default()
+        };
+        assert!(matches!(RbacPolicy::from_config(&missing), Err(AppError::Config { .. })));
+        assert_eq!(RbacPolicy::from_config(&Config::default()).unwrap(), RbacPolicy::default());
+    }
+
+    #[actix_web::test]
+    async fn test_require_roles_and_extractor() {
+        let tokens = TokenService::new(b"rbac-test-key-rbac-test-key-0000", "test");
+        let editor = token(&tokens, "alice", &["editor"]);
+        let member = token(&tokens, "bob", &[]);
+        let required = Rc::new(vec!["editor".to_string(), "admin".to_string()]);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(tokens))
+                .app_data(web::Data::new(RbacPolicy::parse(POLICY).unwrap()))
+                .route(
+                    "/reports",
+                    web::get()
+                        .to(|principal: Principal| async move { HttpResponse::Ok().json(principal) })
+                        .wrap(from_fn(move |req, next| require_roles(required.clone(), req, next))),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/reports")
+            .insert_header(("Authorization", format!("Bearer {}", editor)))
+            .to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["subject"], "alice");
+        assert_eq!(body["roles"], json!(["editor", "member"]));
+
+        let req = TestRequest::get()
+            .uri("/reports")
+            .insert_header(("Authorization", format!("Bearer {}", member)))
+            .to_request();
+        let err = try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 403);
+
+        let err = try_call_service(&app, TestRequest::get().uri("/reports").to_request())
+            .await
+            .unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 401);
+    }
+}
@@ -0,0 +1,196 @@
+use std::sync::RwLock;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, ResponseError};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::lb_weight::DRAIN_ROUTE;
+
+/// Header marking requests refused because the service is read-only
+pub const READ_ONLY_HEADER: &str = "x-read-only";
+
+/// Scope required to see and switch the read-only mode through `/admin/read-only`
+pub const MAINTENANCE_SCOPE: &str = "admin:maintenance";
+
+/// Route switching the mode, which stays writable so the mode can be left
+pub const TOGGLE_ROUTE: &str = "/admin/read-only";
+
+/// Current read-only state, as answered by `GET /admin/read-only`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReadOnlyStatus {
+    pub enabled: bool,
+    /// Explanation given to refused callers
+    pub reason: Option<String>,
+    /// When the mode was last switched on, `None` when enabled by `READ_ONLY`
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// Global switch refusing every mutating request while reads continue
+///
+/// Enabled at startup with `READ_ONLY` or at runtime through
+/// `PUT /admin/read-only`, e.g. during a data migration or to contain an
+/// incident. While enabled, requests other than `GET`, `HEAD` and `OPTIONS`
+/// are answered with 503, the [`READ_ONLY_HEADER`] and a JSON error carrying
+/// the reason, except on [`TOGGLE_ROUTE`], [`DRAIN_ROUTE`], which writes no
+/// data, and the `READ_ONLY_EXEMPT_ROUTES`.
+/// State is per instance.
+pub struct ReadOnlyMode {
+    status: RwLock<ReadOnlyStatus>,
+    exempt: Vec<String>,
+}
+
+impl ReadOnlyMode {
+    /// Creates the switch, writable routes `exempt` being answered as usual
+    pub fn new(status: ReadOnlyStatus, exempt: Vec<String>) -> Self {
+        Self {
+            status: RwLock::new(status),
+            exempt,
+        }
+    }
+
+    /// Builds the switch from `READ_ONLY`, `READ_ONLY_REASON` and `READ_ONLY_EXEMPT_ROUTES`
+    pub fn from_config(config: &Config) -> Self {
+        if config.read_only {
+            warn!("READ_ONLY is set: every mutating request will be refused");
+        }
+        Self::new(
+            ReadOnlyStatus {
+                enabled: config.read_only,
+                reason: config.read_only_reason.clone(),
+                since: None,
+            },
+            config.read_only_exempt_routes.clone(),
+        )
+    }
+
+    /// Returns the current state
+    pub fn status(&self) -> ReadOnlyStatus {
+        self.status.read().map(|status| status.clone()).unwrap_or_default()
+    }
+
+    /// Switches the mode on with `reason`, or off; returns the new state
+    pub fn set(&self, enabled: bool, reason: Option<String>) -> ReadOnlyStatus {
+        let Ok(mut status) = self.status.write() else {
+            return ReadOnlyStatus::default();
+        };
+        *status = match enabled {
+            true => ReadOnlyStatus {
+                enabled,
+                reason,
+                since: Some(status.since.filter(|_| status.enabled).unwrap_or_else(Utc::now)),
+            },
+            false => ReadOnlyStatus::default(),
+        };
+        status.clone()
+    }
+
+    /// Returns the error refusing a request with `method` on the route `path`,
+    /// if the mode refuses it
+    pub fn refusal(&self, method: &Method, path: Option<&str>) -> Option<AppError> {
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            return None;
+        }
+        if path.is_some_and(|path| {
+            path == TOGGLE_ROUTE || path == DRAIN_ROUTE || self.exempt.iter().any(|route| route == path)
+        }) {
+            return None;
+        }
+        let status = self.status();
+        if !status.enabled {
+            return None;
+        }
+        let message = match status.reason {
+            Some(reason) => format!("the service is read-only: {}", reason),
+            None => "the service is read-only, only reads are accepted".to_string(),
+        };
+        Some(AppError::unavailable(message))
+    }
+}
+
+/// Middleware refusing mutating requests while the registered
+/// `web::Data<ReadOnlyMode>` is enabled, for use with `from_fn`
+pub async fn reject_writes(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let refusal = req
+        .app_data::<web::Data<ReadOnlyMode>>()
+        .and_then(|mode| mode.refusal(req.method(), req.match_pattern().as_deref()));
+    match refusal {
+        Some(error) => {
+            let mut response = error.error_response();
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(READ_ONLY_HEADER), HeaderValue::from_static("on"));
+            Ok(req.into_response(response).map_into_right_body())
+        }
+        None => Ok(next.call(req).await?.map_into_left_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    #[actix_web::test]
+    async fn test_writes_are_refused_while_enabled() {
+        let mode = web::Data::new(ReadOnlyMode::new(ReadOnlyStatus::default(), vec!["/auth/login".to_string()]));
+        let app = init_service(
+            App::new()
+                .app_data(mode.clone())
+                .wrap(from_fn(reject_writes))
+                .route("/items", web::get().to(HttpResponse::Ok))
+                .route("/items", web::post().to(HttpResponse::Created))
+                .route("/auth/login", web::post().to(HttpResponse::Ok))
+                .route(TOGGLE_ROUTE, web::put().to(HttpResponse::Ok))
+                .route(DRAIN_ROUTE, web::put().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |method: Method, uri: &str| TestRequest::default().method(method).uri(uri).to_request();
+
+        assert_eq!(call_service(&app, request(Method::POST, "/items")).await.status(), 201);
+
+        let status = mode.set(true, Some("database migration".to_string()));
+        assert!(status.enabled && status.since.is_some());
+        let res = call_service(&app, request(Method::POST, "/items")).await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.headers().get(READ_ONLY_HEADER).unwrap(), "on");
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["error"]["message"], "Service unavailable: the service is read-only: database migration");
+
+        assert_eq!(call_service(&app, request(Method::GET, "/items")).await.status(), 200);
+        assert_eq!(call_service(&app, request(Method::POST, "/auth/login")).await.status(), 200);
+        assert_eq!(call_service(&app, request(Method::PUT, TOGGLE_ROUTE)).await.status(), 200);
+        assert_eq!(call_service(&app, request(Method::PUT, DRAIN_ROUTE)).await.status(), 200);
+
+        assert_eq!(mode.set(false, None), ReadOnlyStatus::default());
+        assert_eq!(call_service(&app, request(Method::POST, "/items")).await.status(), 201);
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = Config {
+            read_only: true,
+            read_only_reason: Some("incident".to_string()),
+            ..Config::default()
+        };
+        let mode = ReadOnlyMode::from_config(&config);
+        assert_eq!(
+            mode.status(),
+            ReadOnlyStatus { enabled: true, reason: Some("incident".to_string()), since: None }
+        );
+        assert!(mode.refusal(&Method::DELETE, Some("/me")).is_some());
+        assert!(ReadOnlyMode::from_config(&Config::default()).refusal(&Method::DELETE, Some("/me")).is_none());
+    }
+}
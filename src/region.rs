@@ -0,0 +1,172 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use actix_web::http::header::HeaderMap;
+use log::warn;
+
+use crate::config::Config;
+
+/// Response header naming the region and zone that served the request
+pub const SERVED_BY_HEADER: &str = "x-served-by";
+
+/// Request header naming the region a client expects to reach
+pub const EXPECTED_REGION_HEADER: &str = "x-expected-region";
+
+/// Placement fields appended to every log line, once installed
+static LOG_FIELDS: OnceLock<String> = OnceLock::new();
+
+/// Returns whether `value` is usable as a region or zone name
+pub fn valid_name(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 64
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// Region and zone this instance runs in, from `REGION` and `ZONE`
+///
+/// Reported in every log line, as labels of the OpenMetrics output, by
+/// `/version` and in the `X-Served-By` header of every response. With
+/// `REGION_AFFINITY_CHECK`, requests whose `X-Expected-Region` names another
+/// region (a misrouting load balancer or DNS entry) are logged and counted;
+/// they are still served.
+#[derive(Debug, Default)]
+pub struct Placement {
+    pub region: Option<String>,
+    pub zone: Option<String>,
+    affinity_check: bool,
+    mismatches: AtomicU64,
+}
+
+impl Placement {
+    /// Creates a placement without affinity check
+    pub fn new(region: Option<String>, zone: Option<String>) -> Self {
+        Self {
+            region,
+            zone,
+            ..Self::default()
+        }
+    }
+
+    /// Builds the placement from the `REGION`, `ZONE` and
+    /// `REGION_AFFINITY_CHECK` settings
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            affinity_check: config.region_affinity_check,
+            ..Self::new(config.region.clone(), config.zone.clone())
+        }
+    }
+
+    /// `X-Served-By` value: `region/zone`, or whichever of them is known
+    pub fn served_by(&self) -> Option<String> {
+        match (&self.region, &self.zone) {
+            (Some(region), Some(zone)) => Some(format!("{}/{}", region, zone)),
+            (Some(name), None) | (None, Some(name)) => Some(name.clone()),
+            (None, None) => None,
+        }
+    }
+
+    /// OpenMetrics labels, e.g. `region="eu-west-1",zone="eu-west-1a"`;
+    /// empty when neither is known
+    pub fn labels(&self) -> String {
+        [("region", &self.region), ("zone", &self.zone)]
+            .into_iter()
+            .filter_map(|(name, value)| value.as_ref().map(|value| format!("{}=\"{}\"", name, value)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Checks the `X-Expected-Region` of a request against this region
+    ///
+    /// Returns whether the request expected another region; such requests
+    /// are logged and counted when the affinity check is on.
+    pub fn check_affinity(&self, headers: &HeaderMap) -> bool {
+        let (true, Some(region)) = (self.affinity_check, &self.region) else {
+            return false;
+        };
+        let Some(expected) = headers.get(EXPECTED_REGION_HEADER).and_then(|value| value.to_str().ok()) else {
+            return false;
+        };
+        let expected = expected.trim();
+        if expected.eq_ignore_ascii_case(region) {
+            return false;
+        }
+        self.mismatches.fetch_add(1, Ordering::Relaxed);
+        let expected = if valid_name(expected) { expected } else { "(invalid)" };
+        warn!("Request expected region {} but was served by {}", expected, region);
+        true
+    }
+
+    /// Requests that expected another region since startup
+    pub fn affinity_mismatches(&self) -> u64 {
+        self.mismatches.load(Ordering::Relaxed)
+    }
+
+    /// Makes [`log_fields`] return this placement, for the log formatter
+    ///
+    /// Only the first call has an effect: the placement of a process does
+    /// not change.
+    pub fn install_log_fields(&self) {
+        let fields = [("region", &self.region), ("zone", &self.zone)]
+            .into_iter()
+            .filter_map(|(name, value)| value.as_ref().map(|value| format!("{}={}", name, value)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if !fields.is_empty() {
+            let _ = LOG_FIELDS.set(fields);
+        }
+    }
+}
+
+/// Placement fields to append to log lines, e.g. `region=eu-west-1 zone=eu-west-1a`
+pub fn log_fields() -> Option<&'static str> {
+    LOG_FIELDS.get().map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn placement(affinity_check: bool) -> Placement {
+        Placement {
+            affinity_check,
+            ..Placement::new(Some("eu-west-1".to_string()), Some("eu-west-1a".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_served_by_and_labels() {
+        assert_eq!(placement(false).served_by().as_deref(), Some("eu-west-1/eu-west-1a"));
+        assert_eq!(placement(false).labels(), "region=\"eu-west-1\",zone=\"eu-west-1a\"");
+        let region_only = Placement::new(Some("us-east-1".to_string()), None);
+        assert_eq!((region_only.served_by().as_deref(), region_only.labels().as_str()), (Some("us-east-1"), "region=\"us-east-1\""));
+        assert_eq!((Placement::default().served_by(), Placement::default().labels()), (None, String::new()));
+    }
+
+    #[test]
+    fn test_affinity_check() {
+        let headers = |region: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(HeaderName::from_static(EXPECTED_REGION_HEADER), HeaderValue::from_str(region).unwrap());
+            headers
+        };
+        let placement = placement(true);
+        assert!(!placement.check_affinity(&HeaderMap::new()));
+        assert!(!placement.check_affinity(&headers("EU-WEST-1")));
+        assert!(placement.check_affinity(&headers("us-east-1")));
+        assert_eq!(placement.affinity_mismatches(), 1);
+
+        // Off unless enabled
+        assert!(!self::placement(false).check_affinity(&headers("us-east-1")));
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("eu-west-1a"));
+        assert!(!valid_name("eu west"));
+        assert!(!valid_name("\"quoted\""));
+        assert!(!valid_name(""));
+    }
+}
@@ -0,0 +1,298 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use arc_swap::ArcSwap;
+use log::{info, warn, Log, Metadata, Record};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::config::{Config, ConfigLayers, LogFormat};
+use crate::error::{AppError, AppResult};
+use crate::pii;
+
+/// Settings applied at runtime when the configuration file or the remote
+/// store changes; any other change waits for a restart
+pub const RELOADABLE_SETTINGS: &[&str] = &[
+    "LOG_LEVEL",
+    "CORS_ALLOWED_ORIGINS",
+    "GUEST_TOKENS_PER_HOUR",
+    "NOTIFY_RATE_LIMIT_PER_MINUTE",
+];
+
+/// Configuration shared with the components that honour reloads
+///
+/// The CORS middleware, the guest token issuer and the notification router
+/// read their reloadable settings from here on every use, so a reload takes
+/// effect on the next request without restarting the servers.
+#[derive(Debug)]
+pub struct LiveConfig {
+    current: ArcSwap<Config>,
+}
+
+/// Outcome of [`LiveConfig::apply`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reload {
+    /// Reloadable settings that changed and are now in force
+    pub applied: Vec<&'static str>,
+    /// Whether other settings changed too, which only a restart applies
+    pub restart_required: bool,
+}
+
+impl LiveConfig {
+    /// Starts from the configuration the servers were started with
+    pub fn new(config: Config) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(config),
+        }
+    }
+
+    /// Returns the configuration in force
+    pub fn load(&self) -> Arc<Config> {
+        self.current.load_full()
+    }
+
+    /// Takes the [`RELOADABLE_SETTINGS`] of `next`, leaving the others as they are
+    pub fn apply(&self, next: &Config) -> Reload {
+        let mut updated = Config::clone(&self.load());
+        let mut applied = Vec::new();
+        if updated.log_level != next.log_level {
+            updated.log_level = next.log_level.clone();
+            set_log_filter(next.log_level.as_deref().unwrap_or(""));
+            applied.push("LOG_LEVEL");
+        }
+        if updated.cors_allowed_origins != next.cors_allowed_origins {
+            updated.cors_allowed_origins = next.cors_allowed_origins.clone();
+            applied.push("CORS_ALLOWED_ORIGINS");
+        }
+        if updated.guest_tokens_per_hour != next.guest_tokens_per_hour {
+            updated.guest_tokens_per_hour = next.guest_tokens_per_hour;
+            applied.push("GUEST_TOKENS_PER_HOUR");
+        }
+        if updated.notify_rate_limit_per_minute != next.notify_rate_limit_per_minute {
+            updated.notify_rate_limit_per_minute = next.notify_rate_limit_per_minute;
+            applied.push("NOTIFY_RATE_LIMIT_PER_MINUTE");
+        }
+        for name in &applied {
+            if let Some(source) = next.sources.get(name) {
+                updated.sources.record(name, source, next.sources.value(name).map(str::to_string));
+            }
+        }
+        // Sources alone moving, e.g. a default now spelled out in the file,
+        // change nothing
+        let unchanged = Config {
+            sources: updated.sources.clone(),
+            ..next.clone()
+        };
+        let restart_required = updated != unchanged;
+        if !applied.is_empty() {
+            self.current.store(Arc::new(updated));
+        }
+        Reload { applied, restart_required }
+    }
+}
+
+/// Watches the configuration file and applies its reloadable settings to a
+/// [`LiveConfig`] whenever it changes
+///
+/// The layers are resolved again like at startup, so flags, secret stores
+/// and the environment still win over the file; an invalid file is logged
+/// and ignored, keeping the settings in force. Watching stops when the
+/// watcher is dropped.
+pub struct ConfigWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigWatcher {
+    /// Starts watching the configuration file of `layers`
+    ///
+    /// The directory is watched rather than the file, so editors replacing
+    /// the file on save are noticed too.
+    ///
+    /// # Errors
+    /// Returns a config error when `layers` have no file or its directory
+    /// cannot be watched
+    pub fn spawn(layers: ConfigLayers, live: Arc<LiveConfig>) -> AppResult<Self> {
+        let path = layers
+            .file_path()
+            .map(PathBuf::from)
+            .ok_or_else(|| AppError::config("no configuration file to watch"))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let name = path.file_name().map(|name| name.to_os_string());
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) if event.kind.is_modify() || event.kind.is_create() => {
+                if event.paths.iter().any(|changed| changed.file_name() == name.as_deref()) {
+                    let origin = layers.file_path().map(|path| path.display().to_string()).unwrap_or_default();
+                    reload(&layers, &live, &origin);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Watching the configuration file failed: {}", e),
+        })
+        .map_err(|e| AppError::config(format!("Failed to watch {}: {}", path.display(), e)))?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(|e| AppError::config(format!("Failed to watch {}: {}", path.display(), e)))?;
+        info!(
+            "Watching {} for changes to {}",
+            path.display(),
+            RELOADABLE_SETTINGS.join(", ")
+        );
+        Ok(Self { _watcher: watcher })
+    }
+}
+
+/// Resolves `layers` again after `origin` changed and applies the result
+/// to `live`, keeping the settings in force when it is invalid
+pub(crate) fn reload(layers: &ConfigLayers, live: &LiveConfig, origin: &str) {
+    let next = match layers.resolve() {
+        Ok(next) => next,
+        Err(e) => {
+            warn!("Keeping the current settings: {} is invalid: {}", origin, e);
+            return;
+        }
+    };
+    let reload = live.apply(&next);
+    if !reload.applied.is_empty() {
+        info!("Reloaded {} from {}", reload.applied.join(", "), origin);
+    }
+    if reload.restart_required {
+        warn!(
+            "{} changed settings that are only applied on restart; only {} are reloaded",
+            origin,
+            RELOADABLE_SETTINGS.join(", ")
+        );
+    }
+}
+
+/// Process logger whose filter can be replaced while it runs
+struct ReloadableLogger {
+    inner: ArcSwap<env_logger::Logger>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.load().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.load().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.load().flush()
+    }
+}
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+/// Whether loggers built from now on write JSON lines
+static JSON_LINES: AtomicBool = AtomicBool::new(false);
+
+/// `RUST_LOG` (default `info`) refined by `filter`, lines formatted by
+/// [`pii::format_log_record`] or, once [`set_log_format`] chose JSON,
+/// [`pii::format_json_log_record`]
+fn build_logger(filter: &str) -> env_logger::Logger {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"));
+    builder.parse_filters(filter);
+    match JSON_LINES.load(Ordering::Relaxed) {
+        true => builder.format(pii::format_json_log_record).build(),
+        false => builder.format(pii::format_log_record).build(),
+    }
+}
+
+/// Installs the process logger, `filter` refining `RUST_LOG`
+///
+/// # Errors
+/// Fails when a logger is already installed
+pub fn init_logger(filter: Option<&str>) -> Result<(), log::SetLoggerError> {
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        inner: ArcSwap::from_pointee(build_logger(filter.unwrap_or(""))),
+    });
+    log::set_logger(logger)?;
+    log::set_max_level(logger.inner.load().filter());
+    Ok(())
+}
+
+/// Picks the format of the lines written once the logger is next rebuilt by
+/// [`set_log_filter`]
+pub fn set_log_format(format: LogFormat) {
+    JSON_LINES.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// Replaces the filter refining `RUST_LOG`; a no-op unless [`init_logger`]
+/// installed the logger
+pub fn set_log_filter(filter: &str) {
+    if let Some(logger) = LOGGER.get() {
+        let rebuilt = build_logger(filter);
+        log::set_max_level(rebuilt.filter());
+        logger.inner.store(Arc::new(rebuilt));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigSource;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_only_reloadable_settings_are_applied() {
+        let live = LiveConfig::new(Config::default());
+        let next = Config {
+            cors_allowed_origins: vec!["https://app.example.com".to_string()],
+            guest_tokens_per_hour: 3,
+            app_port: 9999,
+            ..Config::default()
+        };
+        let reload = live.apply(&next);
+        assert_eq!(reload.applied, vec!["CORS_ALLOWED_ORIGINS", "GUEST_TOKENS_PER_HOUR"]);
+        assert!(reload.restart_required);
+        let current = live.load();
+        assert_eq!(current.cors_allowed_origins, next.cors_allowed_origins);
+        assert_eq!((current.guest_tokens_per_hour, current.app_port), (3, Config::default().app_port));
+
+        assert_eq!(live.apply(&Config::default()).applied.len(), 2);
+        assert_eq!(live.apply(&Config::default()), Reload::default());
+
+        // A default now spelled out in the file is no change
+        let mut spelled_out = Config::default();
+        spelled_out.sources.set("PORT", ConfigSource::File);
+        assert_eq!(live.apply(&spelled_out), Reload::default());
+    }
+
+    #[test]
+    fn test_watcher_reloads_on_change() {
+        let dir = std::env::temp_dir().join(format!("reload-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.toml");
+        std::fs::write(&path, "GUEST_TOKENS_PER_HOUR = 10\n").unwrap();
+        let live = Arc::new(LiveConfig::new(Config::from_file(&path).unwrap()));
+        let layers = ConfigLayers::new().file(&path).environment();
+        let _watcher = ConfigWatcher::spawn(layers, live.clone()).unwrap();
+        // Replaced in one step, so no event sees a truncated file
+        let write = |content: &str| {
+            let partial = dir.join("app.toml.tmp");
+            std::fs::write(&partial, content).unwrap();
+            std::fs::rename(&partial, &path).unwrap();
+        };
+        let wait_for = |per_hour: u64| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while live.load().guest_tokens_per_hour != per_hour && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            live.load().guest_tokens_per_hour
+        };
+
+        write("GUEST_TOKENS_PER_HOUR = 25\n");
+        assert_eq!(wait_for(25), 25);
+
+        // Invalid content keeps the settings in force
+        write("GUEST_TOKENS_PER_HOUR = \"many\"\n");
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(live.load().guest_tokens_per_hour, 25);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
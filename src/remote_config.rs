@@ -0,0 +1,536 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::{ConfigLayers, SecretProvider};
+use crate::error::{AppError, AppResult};
+use crate::reload::{self, LiveConfig};
+use crate::supervisor::Supervisor;
+
+/// Timeout of every request to the remote store, on top of the time a
+/// Consul blocking query may wait for a change
+pub const REMOTE_CONFIG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Key prefix read when `REMOTE_CONFIG_PREFIX` is unset
+pub const DEFAULT_PREFIX: &str = "simple-api-demo/";
+
+/// Key-value store the configuration is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteBackend {
+    /// Consul KV, watched through blocking queries
+    Consul,
+    /// etcd v3 through its JSON gateway, polled
+    Etcd,
+}
+
+impl FromStr for RemoteBackend {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "consul" => Ok(RemoteBackend::Consul),
+            "etcd" => Ok(RemoteBackend::Etcd),
+            other => Err(AppError::environment(
+                "REMOTE_CONFIG_BACKEND",
+                format!("must be consul or etcd, got: {}", other),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RemoteBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RemoteBackend::Consul => "Consul",
+            RemoteBackend::Etcd => "etcd",
+        })
+    }
+}
+
+/// Connection to the remote store, from the `REMOTE_CONFIG_*` settings
+///
+/// Read before the rest of the configuration, so they are not part of
+/// [`Config`](crate::config::Config). Deliberately not `Debug`: it holds the token.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RemoteConfigSettings {
+    pub backend: RemoteBackend,
+    /// Store address, e.g. `http://consul.internal:8500`
+    pub addr: String,
+    /// Keys read, ending with `/` unless empty
+    pub prefix: String,
+    /// Consul ACL token (`X-Consul-Token`) or etcd auth token (`Authorization`)
+    pub token: Option<String>,
+    /// How long a Consul query waits for a change, or between two etcd reads
+    pub poll_interval: Duration,
+}
+
+impl RemoteConfigSettings {
+    /// Reads `REMOTE_CONFIG_BACKEND`, `REMOTE_CONFIG_ADDR`,
+    /// `REMOTE_CONFIG_PREFIX`, `REMOTE_CONFIG_TOKEN` and
+    /// `REMOTE_CONFIG_POLL_SECS`; `None` when no backend is set
+    ///
+    /// # Errors
+    /// Returns an environment error for an unknown backend, a backend
+    /// without an address, or a poll interval that is not a positive number
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> AppResult<Option<Self>> {
+        let value = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+        let Some(backend) = value("REMOTE_CONFIG_BACKEND") else {
+            return Ok(None);
+        };
+        let backend = backend.parse()?;
+        let addr = value("REMOTE_CONFIG_ADDR")
+            .ok_or_else(|| AppError::environment("REMOTE_CONFIG_ADDR", "required when REMOTE_CONFIG_BACKEND is set"))?;
+        let mut prefix = value("REMOTE_CONFIG_PREFIX")
+            .unwrap_or_else(|| DEFAULT_PREFIX.to_string())
+            .trim_start_matches('/')
+            .to_string();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        let poll_secs = match value("REMOTE_CONFIG_POLL_SECS") {
+            Some(secs) => secs
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .ok_or_else(|| AppError::environment("REMOTE_CONFIG_POLL_SECS", "must be at least 1"))?,
+            None => 30,
+        };
+        Ok(Some(Self {
+            backend,
+            addr: addr.trim_end_matches('/').to_string(),
+            prefix,
+            token: value("REMOTE_CONFIG_TOKEN"),
+            poll_interval: Duration::from_secs(poll_secs),
+        }))
+    }
+}
+
+/// One key of a Consul `?recurse` listing
+#[derive(Debug, Deserialize)]
+struct ConsulEntry {
+    #[serde(rename = "Key")]
+    key: String,
+    /// Base64, `null` for folders and empty values
+    #[serde(rename = "Value")]
+    value: Option<String>,
+}
+
+/// etcd `/v3/kv/range` response
+#[derive(Debug, Default, Deserialize)]
+struct EtcdRange {
+    #[serde(default)]
+    kvs: Vec<EtcdEntry>,
+}
+
+/// One key of an etcd range, key and value in base64
+#[derive(Debug, Deserialize)]
+struct EtcdEntry {
+    key: String,
+    #[serde(default)]
+    value: String,
+}
+
+/// [`SecretProvider`] serving configuration variables from Consul or etcd
+///
+/// Every key under the prefix sets the variable named like a
+/// [`ConfigFile`](crate::config::ConfigFile) key: the rest of the key in
+/// upper case, `/`, `-` and `.` turned into `_`, so
+/// `simple-api-demo/cors/allowed_origins` sets `CORS_ALLOWED_ORIGINS`.
+/// [`load`](Self::load) reads the keys once before the configuration is
+/// built; [`spawn_watcher`](Self::spawn_watcher) then follows changes and
+/// resolves the configuration again, applying the reloadable settings
+/// through the same [`LiveConfig`] as the file watcher.
+pub struct RemoteConfig {
+    settings: RemoteConfigSettings,
+    values: RwLock<BTreeMap<String, String>>,
+    /// `X-Consul-Index` of the last read, 0 before the first
+    index: AtomicU64,
+}
+
+impl RemoteConfig {
+    /// Creates a provider; nothing is read before [`load`](Self::load)
+    pub fn new(settings: RemoteConfigSettings) -> Self {
+        Self {
+            settings,
+            values: RwLock::new(BTreeMap::new()),
+            index: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates a provider from the `REMOTE_CONFIG_*` environment variables;
+    /// `None` when no backend is set
+    ///
+    /// # Errors
+    /// Same as [`RemoteConfigSettings::from_lookup`]
+    pub fn from_env() -> AppResult<Option<Self>> {
+        Ok(RemoteConfigSettings::from_lookup(|name| env::var(name).ok())?.map(Self::new))
+    }
+
+    /// Reads every key under the prefix
+    ///
+    /// # Errors
+    /// Unavailable when the store cannot be reached or refuses the read
+    pub async fn load(&self) -> AppResult<()> {
+        self.read(false).await?;
+        let count = self.values.read().map(|values| values.len()).unwrap_or_default();
+        info!("Loaded {} settings from {}", count, self.origin());
+        Ok(())
+    }
+
+    /// Waits for the next change and caches the new values; returns
+    /// whether a value changed
+    ///
+    /// Consul answers a blocking query as soon as a key under the prefix
+    /// changes, or after the poll interval; etcd is read again after it.
+    ///
+    /// # Errors
+    /// Unavailable when the store cannot be reached or refuses the read
+    pub async fn poll(&self) -> AppResult<bool> {
+        if self.settings.backend == RemoteBackend::Etcd {
+            actix_web::rt::time::sleep(self.settings.poll_interval).await;
+        }
+        self.read(true).await
+    }
+
+    /// Follows changes in a supervised background task, resolving `layers`
+    /// again into `live` after each one
+    ///
+    /// A failed read ends the task, which the supervisor restarts with its
+    /// backoff.
+    pub fn spawn_watcher(remote: Arc<Self>, layers: ConfigLayers, live: Arc<LiveConfig>, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("remote_config", move || {
+            let (remote, layers, live) = (remote.clone(), layers.clone(), live.clone());
+            async move {
+                loop {
+                    if remote.poll().await? {
+                        reload::reload(&layers, &live, &remote.origin());
+                    }
+                }
+            }
+        });
+    }
+
+    /// Store and prefix the values come from, for log lines
+    pub fn origin(&self) -> String {
+        format!("{} keys under {}/{}", self.settings.backend, self.settings.addr, self.settings.prefix)
+    }
+
+    /// Reads the keys, waiting for a change on Consul when `wait`; returns
+    /// whether a cached value changed
+    async fn read(&self, wait: bool) -> AppResult<bool> {
+        let entries = match self.settings.backend {
+            RemoteBackend::Consul => self.read_consul(wait).await?,
+            RemoteBackend::Etcd => self.read_etcd().await?,
+        };
+        let mut read = BTreeMap::new();
+        for (key, value) in entries {
+            let Some(name) = key.strip_prefix(&self.settings.prefix).filter(|name| !name.is_empty()) else {
+                continue;
+            };
+            // Consul folders
+            if name.ends_with('/') {
+                continue;
+            }
+            let name = name.to_ascii_uppercase().replace(['/', '-', '.'], "_");
+            read.insert(name, value);
+        }
+        let mut values = self
+            .values
+            .write()
+            .map_err(|_| AppError::internal("remote configuration lock poisoned"))?;
+        let changed = *values != read;
+        *values = read;
+        Ok(changed)
+    }
+
+    async fn read_consul(&self, wait: bool) -> AppResult<Vec<(String, String)>> {
+        let url = format!("{}/v1/kv/{}", self.settings.addr, self.settings.prefix);
+        let mut query = vec![("recurse", "true".to_string())];
+        let index = self.index.load(Ordering::SeqCst);
+        if wait && index > 0 {
+            query.push(("index", index.to_string()));
+            query.push(("wait", format!("{}s", self.settings.poll_interval.as_secs())));
+        }
+        let mut request = self.client()?.get(&url).query(&query);
+        if let Some(token) = &self.settings.token {
+            request = request.header("X-Consul-Token", token);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| AppError::unavailable(format!("Consul unreachable: {}", e)))?;
+        let next = response
+            .headers()
+            .get("x-consul-index")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or_default();
+        // An index going backwards means the store was reset
+        self.index.store(if next < index { 0 } else { next }, Ordering::SeqCst);
+        let status = response.status();
+        // No key under the prefix
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        if !status.is_success() {
+            return Err(AppError::unavailable(format!("Consul answered {} for {}", status, self.settings.prefix)));
+        }
+        let entries: Vec<ConsulEntry> = response
+            .json()
+            .await
+            .map_err(|e| AppError::unavailable(format!("Invalid Consul response: {}", e)))?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|entry| {
+                let value = entry.value.as_deref().map(decode).unwrap_or(Some(String::new()));
+                value.map(|value| (entry.key, value))
+            })
+            .collect())
+    }
+
+    async fn read_etcd(&self) -> AppResult<Vec<(String, String)>> {
+        let url = format!("{}/v3/kv/range", self.settings.addr);
+        let prefix = self.settings.prefix.as_bytes();
+        let body = json!({
+            "key": STANDARD.encode(prefix),
+            "range_end": STANDARD.encode(prefix_end(prefix)),
+        });
+        let mut request = self.client()?.post(&url).json(&body);
+        if let Some(token) = &self.settings.token {
+            request = request.header("Authorization", token);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| AppError::unavailable(format!("etcd unreachable: {}", e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(AppError::unavailable(format!("etcd answered {} for {}", status, self.settings.prefix)));
+        }
+        let range: EtcdRange = response
+            .json()
+            .await
+            .map_err(|e| AppError::unavailable(format!("Invalid etcd response: {}", e)))?;
+        Ok(range
+            .kvs
+            .into_iter()
+            .filter_map(|entry| Some((decode(&entry.key)?, decode(&entry.value)?)))
+            .collect())
+    }
+
+    /// A client per call: startup and watching run on different runtimes,
+    /// and pooled connections must not outlive theirs
+    fn client(&self) -> AppResult<reqwest::Client> {
+        reqwest::Client::builder()
+            .timeout(REMOTE_CONFIG_TIMEOUT + self.settings.poll_interval)
+            .build()
+            .map_err(|e| AppError::internal(format!("Failed to build the remote configuration client: {}", e)))
+    }
+}
+
+impl SecretProvider for RemoteConfig {
+    fn secret(&self, name: &str) -> Option<String> {
+        self.values.read().ok()?.get(name).cloned()
+    }
+}
+
+/// Decodes a base64 UTF-8 string, `None` (logged) for anything else
+fn decode(value: &str) -> Option<String> {
+    match STANDARD.decode(value).map(String::from_utf8) {
+        Ok(Ok(value)) => Some(value),
+        _ => {
+            warn!("Ignoring a remote configuration value that is not base64-encoded UTF-8");
+            None
+        }
+    }
+}
+
+/// End of the etcd range holding every key starting with `prefix`
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every key: etcd reads `\0` as no upper bound
+    vec![0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Fake Consul and etcd serving the same keys
+    #[derive(Default)]
+    struct FakeStore {
+        keys: Mutex<BTreeMap<String, String>>,
+        index: AtomicU64,
+    }
+
+    impl FakeStore {
+        fn put(&self, key: &str, value: &str) {
+            self.keys.lock().unwrap().insert(key.to_string(), value.to_string());
+            self.index.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    async fn start_store(store: Arc<FakeStore>) -> String {
+        let data = web::Data::from(store);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route(
+                    "/v1/kv/{prefix:.*}",
+                    web::get().to(|req: HttpRequest, store: web::Data<FakeStore>| async move {
+                        if req.headers().get("x-consul-token").is_none_or(|token| token != "acl-token") {
+                            return HttpResponse::Forbidden().finish();
+                        }
+                        let prefix = req.match_info().query("prefix").to_string();
+                        let index = store.index.load(Ordering::SeqCst).to_string();
+                        let entries: Vec<Value> = store
+                            .keys
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .filter(|(key, _)| key.starts_with(&prefix))
+                            .map(|(key, value)| json!({"Key": key, "Value": STANDARD.encode(value)}))
+                            .collect();
+                        if entries.is_empty() {
+                            return HttpResponse::NotFound().insert_header(("X-Consul-Index", index)).finish();
+                        }
+                        HttpResponse::Ok().insert_header(("X-Consul-Index", index)).json(entries)
+                    }),
+                )
+                .route(
+                    "/v3/kv/range",
+                    web::post().to(|body: web::Json<Value>, store: web::Data<FakeStore>| async move {
+                        let bound = |field: &str| STANDARD.decode(body[field].as_str().unwrap_or_default()).unwrap();
+                        let (start, end) = (bound("key"), bound("range_end"));
+                        let kvs: Vec<Value> = store
+                            .keys
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .filter(|(key, _)| key.as_bytes() >= start.as_slice() && key.as_bytes() < end.as_slice())
+                            .map(|(key, value)| json!({"key": STANDARD.encode(key), "value": STANDARD.encode(value)}))
+                            .collect();
+                        HttpResponse::Ok().json(json!({"header": {"revision": "1"}, "kvs": kvs}))
+                    }),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    fn settings(backend: RemoteBackend, addr: &str) -> RemoteConfigSettings {
+        RemoteConfigSettings {
+            backend,
+            addr: addr.to_string(),
+            prefix: DEFAULT_PREFIX.to_string(),
+            token: Some("acl-token".to_string()),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn test_settings() {
+        assert!(RemoteConfigSettings::from_lookup(|_| None).unwrap().is_none());
+        let vars = HashMap::from([("REMOTE_CONFIG_BACKEND", "consul")]);
+        assert!(matches!(
+            RemoteConfigSettings::from_lookup(|name| vars.get(name).map(|value| value.to_string())),
+            Err(AppError::Environment { var_name, .. }) if var_name == "REMOTE_CONFIG_ADDR"
+        ));
+        let vars = HashMap::from([("REMOTE_CONFIG_BACKEND", "zookeeper"), ("REMOTE_CONFIG_ADDR", "http://zk")]);
+        assert!(RemoteConfigSettings::from_lookup(|name| vars.get(name).map(|value| value.to_string())).is_err());
+
+        let vars = HashMap::from([
+            ("REMOTE_CONFIG_BACKEND", "ETCD"),
+            ("REMOTE_CONFIG_ADDR", "http://etcd.internal:2379/"),
+            ("REMOTE_CONFIG_PREFIX", "/config/api"),
+        ]);
+        let settings = RemoteConfigSettings::from_lookup(|name| vars.get(name).map(|value| value.to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.backend, RemoteBackend::Etcd);
+        assert_eq!((settings.addr.as_str(), settings.prefix.as_str()), ("http://etcd.internal:2379", "config/api/"));
+        assert_eq!((settings.token, settings.poll_interval), (None, Duration::from_secs(30)));
+
+        assert_eq!(prefix_end(b"app/"), b"app0");
+        assert_eq!(prefix_end(b"a\xff"), b"b");
+    }
+
+    #[actix_web::test]
+    async fn test_load_and_poll() {
+        let store = Arc::new(FakeStore::default());
+        store.put("simple-api-demo/cors/allowed-origins", "https://app.example.com");
+        store.put("simple-api-demo/guest_tokens_per_hour", "12");
+        store.put("other-app/port", "1");
+        let addr = start_store(store.clone()).await;
+
+        for backend in [RemoteBackend::Consul, RemoteBackend::Etcd] {
+            let remote = RemoteConfig::new(settings(backend, &addr));
+            remote.load().await.unwrap();
+            assert_eq!(remote.secret("CORS_ALLOWED_ORIGINS").as_deref(), Some("https://app.example.com"));
+            assert_eq!(remote.secret("GUEST_TOKENS_PER_HOUR").as_deref(), Some("12"));
+            assert_eq!(remote.secret("PORT"), None);
+        }
+
+        // The blocking query returns once the index moved; only a changed
+        // value counts as a change
+        let remote = RemoteConfig::new(settings(RemoteBackend::Consul, &addr));
+        remote.load().await.unwrap();
+        store.put("other-app/port", "2");
+        assert!(!remote.poll().await.unwrap());
+        store.put("simple-api-demo/guest_tokens_per_hour", "20");
+        assert!(remote.poll().await.unwrap());
+        assert_eq!(remote.secret("GUEST_TOKENS_PER_HOUR").as_deref(), Some("20"));
+
+        let mut refused = settings(RemoteBackend::Consul, &addr);
+        refused.token = None;
+        let err = RemoteConfig::new(refused).load().await.unwrap_err();
+        assert!(err.to_string().contains("403"), "{}", err);
+    }
+
+    #[actix_web::test]
+    async fn test_changes_reach_the_live_config() {
+        let store = Arc::new(FakeStore::default());
+        store.put("simple-api-demo/guest_tokens_per_hour", "12");
+        let addr = start_store(store.clone()).await;
+        let remote = Arc::new(RemoteConfig::new(settings(RemoteBackend::Etcd, &addr)));
+        remote.load().await.unwrap();
+        let layers = ConfigLayers::new().layer(crate::config::ConfigSource::Remote, remote.clone());
+        let live = Arc::new(LiveConfig::new(layers.resolve().unwrap()));
+        assert_eq!(live.load().guest_tokens_per_hour, 12);
+        assert_eq!(live.load().sources.get("GUEST_TOKENS_PER_HOUR"), Some(crate::config::ConfigSource::Remote));
+
+        let supervisor = Arc::new(Supervisor::default());
+        RemoteConfig::spawn_watcher(remote, layers, live.clone(), &supervisor);
+        store.put("simple-api-demo/guest_tokens_per_hour", "40");
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while live.load().guest_tokens_per_hour != 40 && std::time::Instant::now() < deadline {
+            actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(live.load().guest_tokens_per_hour, 40);
+    }
+}
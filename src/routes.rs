@@ -0,0 +1,858 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use actix_web::http::Method;
+use actix_web::middleware::from_fn;
+use actix_web::{web, Route};
+
+use crate::anonymization::DATA_ADMIN_SCOPE;
+use crate::assets;
+use crate::auth::basic::require_basic_auth;
+use crate::auth::impersonation::IMPERSONATE_SCOPE;
+use crate::auth::key_store::require_api_key;
+use crate::auth::quotas::enforce_quota;
+use crate::auth::scopes::require_scopes;
+use crate::auth::signatures::require_signature;
+use crate::auth::signing_keys::KEYS_ADMIN_SCOPE;
+use crate::backup::BACKUP_SCOPE;
+use crate::config::CONFIG_SCOPE;
+use crate::consent::TERMS_ADMIN_SCOPE;
+use crate::deliveries::WEBHOOKS_ADMIN_SCOPE;
+use crate::subscriptions::SUBSCRIPTIONS_SCOPE;
+use crate::dumps::DUMPS_SCOPE;
+use crate::error::{AppError, AppResult};
+use crate::files::FILES_SCOPE;
+use crate::handlers::{admin, app_server, auth, files, hooks, me, terms, webhooks};
+use crate::lb_weight::DRAIN_ROUTE;
+use crate::metrics_export::METRICS_SCOPE;
+use crate::policy::{require_policy, POLICY_ADMIN_SCOPE};
+use crate::rbac::{require_roles, OPERATOR_ROLE, OWNER_ROLE, VIEWER_ROLE};
+use crate::read_only::{MAINTENANCE_SCOPE, TOGGLE_ROUTE};
+use crate::users::ACCOUNT_SCOPE;
+
+/// Declarative description of one application server route
+///
+/// The registry is the single source of truth for routing, authorization
+/// requirements and the OpenAPI document, so they cannot drift apart.
+#[derive(Clone)]
+pub struct RouteSpec {
+    /// HTTP method
+    pub method: Method,
+    /// Path pattern in actix syntax (`/hooks/{provider}`)
+    pub path: &'static str,
+    /// One-line description used in the OpenAPI document
+    pub summary: &'static str,
+    /// OAuth scopes a bearer token must grant
+    pub scopes: Vec<String>,
+    /// Whether a known `X-Api-Key` is required
+    pub api_key: bool,
+    /// Whether key-protected requests skip the key's quota
+    pub quota_exempt: bool,
+    /// Whether HTTP Basic credentials are required
+    pub basic_auth: bool,
+    /// Whether an HMAC `X-Signature` of the request is required
+    pub signed: bool,
+    /// Roles of which a bearer token must carry at least one
+    pub roles: Vec<String>,
+    /// Whether the `Authorizer` policy must allow the caller the method on the path
+    pub policy: bool,
+    /// Whether the cache warm-up requests the route after startup
+    pub cache_warm: bool,
+    /// Named `CORS_POLICIES` entry applied instead of the default CORS policy
+    pub cors_policy: Option<String>,
+    factory: fn() -> Route,
+}
+
+impl RouteSpec {
+    /// Describes a route; `factory` builds the actix route (method + handler)
+    pub fn new(method: Method, path: &'static str, summary: &'static str, factory: fn() -> Route) -> Self {
+        Self {
+            method,
+            path,
+            summary,
+            scopes: Vec::new(),
+            api_key: false,
+            quota_exempt: false,
+            basic_auth: false,
+            signed: false,
+            roles: Vec::new(),
+            policy: false,
+            cache_warm: false,
+            cors_policy: None,
+            factory,
+        }
+    }
+
+    /// Shorthand for a GET route
+    pub fn get(path: &'static str, summary: &'static str, factory: fn() -> Route) -> Self {
+        Self::new(Method::GET, path, summary, factory)
+    }
+
+    /// Shorthand for a POST route
+    pub fn post(path: &'static str, summary: &'static str, factory: fn() -> Route) -> Self {
+        Self::new(Method::POST, path, summary, factory)
+    }
+
+    /// Shorthand for a PUT route
+    pub fn put(path: &'static str, summary: &'static str, factory: fn() -> Route) -> Self {
+        Self::new(Method::PUT, path, summary, factory)
+    }
+
+    /// Shorthand for a DELETE route
+    pub fn delete(path: &'static str, summary: &'static str, factory: fn() -> Route) -> Self {
+        Self::new(Method::DELETE, path, summary, factory)
+    }
+
+    /// Requires a bearer token, or self-service API key, granting all of
+    /// `scopes`; requests lacking some answer 403 listing the missing ones
+    pub fn require_scopes(mut self, scopes: &[&str]) -> Self {
+        for scope in scopes {
+            if !self.scopes.iter().any(|held| held == scope) {
+                self.scopes.push(scope.to_string());
+            }
+        }
+        self
+    }
+
+    /// Requires a key from the registered `KeyStore` in the `X-Api-Key` header
+    pub fn require_api_key(mut self) -> Self {
+        self.api_key = true;
+        self
+    }
+
+    /// Serves key-protected requests without counting them against the key's quota
+    pub fn exempt_from_quota(mut self) -> Self {
+        self.quota_exempt = true;
+        self
+    }
+
+    /// Requires HTTP Basic credentials known to the `BasicAuthenticator`
+    pub fn require_basic_auth(mut self) -> Self {
+        self.basic_auth = true;
+        self
+    }
+
+    /// Requires an `X-Signature` from a client known to the `SignatureVerifier`
+    pub fn require_signature(mut self) -> Self {
+        self.signed = true;
+        self
+    }
+
+    /// Requires a bearer token whose subject holds at least one of `roles`
+    /// in the registered `RbacPolicy`
+    pub fn require_roles(mut self, roles: &[&str]) -> Self {
+        self.roles.extend(roles.iter().map(|role| role.to_string()));
+        self
+    }
+
+    /// Requires the registered `Authorizer` to allow the bearer token's
+    /// subject the route's method on the request path
+    pub fn require_policy(mut self) -> Self {
+        self.policy = true;
+        self
+    }
+
+    /// Lists the route in the generated cache warm-up manifest
+    ///
+    /// Only for GET routes without path parameters or side effects.
+    pub fn warm_cache(mut self) -> Self {
+        self.cache_warm = true;
+        self
+    }
+
+    /// Applies the named `CORS_POLICIES` entry to the route
+    ///
+    /// Every method of a path must use the same policy; starting the server
+    /// fails when the configuration does not define it.
+    pub fn cors_policy(mut self, name: &str) -> Self {
+        self.cors_policy = Some(name.to_string());
+        self
+    }
+
+    /// Builds the actix route with its authorization middleware
+    fn build(&self) -> Route {
+        let mut route = (self.factory)();
+        // Wrapped first so they run after the scope guard and reuse its claims
+        if self.policy {
+            route = route.wrap(from_fn(require_policy));
+        }
+        if !self.roles.is_empty() {
+            let roles = Rc::new(self.roles.clone());
+            route = route.wrap(from_fn(move |req, next| require_roles(roles.clone(), req, next)));
+        }
+        if !self.scopes.is_empty() {
+            let scopes = Rc::new(self.scopes.clone());
+            route = route.wrap(from_fn(move |req, next| require_scopes(scopes.clone(), req, next)));
+        }
+        if self.api_key {
+            if !self.quota_exempt {
+                route = route.wrap(from_fn(enforce_quota));
+            }
+            route = route.wrap(from_fn(require_api_key));
+        }
+        if self.basic_auth {
+            route = route.wrap(from_fn(require_basic_auth));
+        }
+        // Outermost, so tampered or replayed requests are rejected before any other check
+        if self.signed {
+            route = route.wrap(from_fn(require_signature));
+        }
+        route
+    }
+}
+
+/// Ordered collection of route specs
+#[derive(Clone, Default)]
+pub struct RouteRegistry {
+    routes: Vec<RouteSpec>,
+}
+
+impl RouteRegistry {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route
+    pub fn route(mut self, spec: RouteSpec) -> Self {
+        self.routes.push(spec);
+        self
+    }
+
+    /// Requires Basic credentials on every route whose path is in `paths`
+    ///
+    /// # Errors
+    /// Returns a config error naming a path no route has
+    pub fn require_basic_auth_on(mut self, paths: &[String]) -> AppResult<Self> {
+        for path in paths {
+            let mut found = false;
+            for spec in self.routes.iter_mut().filter(|spec| spec.path == path) {
+                spec.basic_auth = true;
+                found = true;
+            }
+            if !found {
+                return Err(AppError::config(format!("BASIC_AUTH_ROUTES names unknown route {}", path)));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Requires a request signature on every route whose path is in `paths`
+    ///
+    /// # Errors
+    /// Returns a config error naming a path no route has
+    pub fn require_signature_on(mut self, paths: &[String]) -> AppResult<Self> {
+        for path in paths {
+            let mut found = false;
+            for spec in self.routes.iter_mut().filter(|spec| spec.path == path) {
+                spec.signed = true;
+                found = true;
+            }
+            if !found {
+                return Err(AppError::config(format!("SIGNED_ROUTES names unknown route {}", path)));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Requires the `Authorizer` to allow the method on every route whose
+    /// path is in `paths`
+    ///
+    /// # Errors
+    /// Returns a config error naming a path no route has
+    pub fn require_policy_on(mut self, paths: &[String]) -> AppResult<Self> {
+        for path in paths {
+            let mut found = false;
+            for spec in self.routes.iter_mut().filter(|spec| spec.path == path) {
+                spec.policy = true;
+                found = true;
+            }
+            if !found {
+                return Err(AppError::config(format!("POLICY_ROUTES names unknown route {}", path)));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Requires the scopes of each `(path, scopes)` pair on every route of the path
+    ///
+    /// # Errors
+    /// Returns a config error naming a path no route has
+    pub fn require_scopes_on(mut self, routes: &[(String, Vec<String>)]) -> AppResult<Self> {
+        for (path, scopes) in routes {
+            let mut found = false;
+            for spec in self.routes.iter_mut().filter(|spec| spec.path == path) {
+                for scope in scopes {
+                    if !spec.scopes.contains(scope) {
+                        spec.scopes.push(scope.clone());
+                    }
+                }
+                found = true;
+            }
+            if !found {
+                return Err(AppError::config(format!("ROUTE_SCOPES names unknown route {}", path)));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Requires the listed roles on the routes of an RBAC policy's `routes`
+    ///
+    /// # Errors
+    /// Returns a config error naming a path no route has
+    pub fn require_roles_on(mut self, routes: &BTreeMap<String, Vec<String>>) -> AppResult<Self> {
+        for (path, roles) in routes {
+            let mut found = false;
+            for spec in self.routes.iter_mut().filter(|spec| spec.path == path) {
+                spec.roles.extend(roles.iter().cloned());
+                found = true;
+            }
+            if !found {
+                return Err(AppError::config(format!("RBAC policy names unknown route {}", path)));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Applies the named CORS policy of each `(path, policy)` pair to every
+    /// route of the path
+    ///
+    /// # Errors
+    /// Returns a config error naming a path no route has
+    pub fn cors_policy_on(mut self, routes: &[(String, String)]) -> AppResult<Self> {
+        for (path, policy) in routes {
+            let mut found = false;
+            for spec in self.routes.iter_mut().filter(|spec| spec.path == path) {
+                spec.cors_policy = Some(policy.clone());
+                found = true;
+            }
+            if !found {
+                return Err(AppError::config(format!("CORS_ROUTES names unknown route {}", path)));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Returns the routes in registration order
+    pub fn routes(&self) -> &[RouteSpec] {
+        &self.routes
+    }
+
+    /// Paths of the routes marked with [`RouteSpec::warm_cache`] that can be
+    /// requested anonymously
+    pub fn warm_paths(&self) -> Vec<&'static str> {
+        self.routes
+            .iter()
+            .filter(|spec| spec.cache_warm && spec.method == Method::GET)
+            .filter(|spec| spec.scopes.is_empty() && spec.roles.is_empty() && !spec.policy && !spec.api_key && !spec.basic_auth && !spec.signed)
+            .map(|spec| spec.path)
+            .collect()
+    }
+
+    /// The application server's routes
+    pub fn app_server() -> Self {
+        Self::new()
+            .route(RouteSpec::get("/", "Service status", || web::get().to(app_server::root)))
+            .route(RouteSpec::get("/health", "Health check", || web::get().to(app_server::root)))
+            .route(RouteSpec::get("/version", "Build, API version and region", || {
+                web::get().to(app_server::version)
+            }))
+            .route(RouteSpec::get("/public", "Public content", || {
+                web::get().to(app_server::public_route)
+            }))
+            .route(
+                RouteSpec::get("/private", "Protected content", || {
+                    web::get().to(app_server::private_route)
+                })
+                .require_scopes(&["read:private"]),
+            )
+            .route(RouteSpec::get("/whoami", "Resolved client address, user agent, geo and TLS details", || {
+                web::get().to(app_server::whoami)
+            }))
+            .route(
+                RouteSpec::get("/quota", "Quota usage of the calling API key", || web::get().to(app_server::quota))
+                    .require_api_key()
+                    .exempt_from_quota(),
+            )
+            .route(RouteSpec::post("/hooks/{provider}", "Inbound webhook receiver", || {
+                web::post().to(hooks::receive)
+            }))
+            .route(
+                RouteSpec::get("/webhooks/deliveries", "List outbound notification deliveries", || {
+                    web::get().to(webhooks::deliveries)
+                })
+                .require_scopes(&[WEBHOOKS_ADMIN_SCOPE]),
+            )
+            // Before `/webhooks/deliveries/{id}`, which would match it first
+            .route(
+                RouteSpec::post("/webhooks/deliveries/replay", "Replay the deliveries of a time range", || {
+                    web::post().to(webhooks::replay)
+                })
+                .require_scopes(&[WEBHOOKS_ADMIN_SCOPE]),
+            )
+            .route(
+                RouteSpec::get("/webhooks/deliveries/{id}", "Get a delivery and its attempts", || {
+                    web::get().to(webhooks::delivery)
+                })
+                .require_scopes(&[WEBHOOKS_ADMIN_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/webhooks/deliveries/{id}/retry", "Send a delivery again", || {
+                    web::post().to(webhooks::retry)
+                })
+                .require_scopes(&[WEBHOOKS_ADMIN_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/webhooks/subscriptions", "Subscribe a webhook endpoint to events", || {
+                    web::post().to(webhooks::create_subscription)
+                })
+                .require_scopes(&[SUBSCRIPTIONS_SCOPE]),
+            )
+            .route(
+                RouteSpec::get("/webhooks/subscriptions", "List your webhook subscriptions", || {
+                    web::get().to(webhooks::list_subscriptions)
+                })
+                .require_scopes(&[SUBSCRIPTIONS_SCOPE]),
+            )
+            .route(
+                RouteSpec::get("/webhooks/subscriptions/{id}", "Get one of your webhook subscriptions", || {
+                    web::get().to(webhooks::subscription)
+                })
+                .require_scopes(&[SUBSCRIPTIONS_SCOPE]),
+            )
+            .route(
+                RouteSpec::delete("/webhooks/subscriptions/{id}", "Delete one of your webhook subscriptions", || {
+                    web::delete().to(webhooks::delete_subscription)
+                })
+                .require_scopes(&[SUBSCRIPTIONS_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/webhooks/subscriptions/{id}/enable", "Resume deliveries to a subscription", || {
+                    web::post().to(webhooks::enable_subscription)
+                })
+                .require_scopes(&[SUBSCRIPTIONS_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/webhooks/subscriptions/{id}/disable", "Pause deliveries to a subscription", || {
+                    web::post().to(webhooks::disable_subscription)
+                })
+                .require_scopes(&[SUBSCRIPTIONS_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/webhooks/subscriptions/{id}/rotate-secret", "Rotate a subscription's signing secret", || {
+                    web::post().to(webhooks::rotate_subscription_secret)
+                })
+                .require_scopes(&[SUBSCRIPTIONS_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/webhooks/subscriptions/{id}/test", "Send a test event to a subscription", || {
+                    web::post().to(webhooks::test_subscription)
+                })
+                .require_scopes(&[SUBSCRIPTIONS_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/files", "Upload a file", || web::post().to(files::upload))
+                    .require_scopes(&[FILES_SCOPE]),
+            )
+            .route(
+                RouteSpec::get("/files/by-hash/{sha256}", "Download file content by its SHA-256", || {
+                    web::get().to(files::by_hash)
+                })
+                .require_scopes(&[FILES_SCOPE]),
+            )
+            .route(
+                RouteSpec::get("/files/{id}", "Get one of your files", || web::get().to(files::file))
+                    .require_scopes(&[FILES_SCOPE]),
+            )
+            .route(
+                RouteSpec::delete("/files/{id}", "Delete one of your files", || web::delete().to(files::delete))
+                    .require_scopes(&[FILES_SCOPE]),
+            )
+            .route(RouteSpec::post("/auth/introspect", "Token introspection (RFC 7662)", || {
+                web::post().to(auth::introspect)
+            }))
+            .route(RouteSpec::post("/auth/register", "Register an account", || {
+                web::post().to(auth::register)
+            }))
+            .route(RouteSpec::post("/auth/login", "Log in with email and password", || {
+                web::post().to(auth::login)
+            }))
+            .route(
+                RouteSpec::post("/auth/2fa/setup", "Start TOTP enrollment", || {
+                    web::post().to(auth::two_factor_setup)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/auth/2fa/confirm", "Confirm TOTP enrollment", || {
+                    web::post().to(auth::two_factor_confirm)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(RouteSpec::post("/auth/token", "Log in for an access token and a refresh token", || {
+                web::post().to(auth::token)
+            }))
+            .route(RouteSpec::post("/auth/refresh", "Exchange a refresh token for new tokens", || {
+                web::post().to(auth::refresh)
+            }))
+            .route(RouteSpec::post("/auth/revoke", "Revoke a refresh token and its session", || {
+                web::post().to(auth::revoke)
+            }))
+            .route(RouteSpec::post("/auth/session/login", "Log in with a session cookie", || {
+                web::post().to(auth::session_login)
+            }))
+            .route(RouteSpec::post("/auth/session/logout", "End the cookie session", || {
+                web::post().to(auth::session_logout)
+            }))
+            .route(RouteSpec::get("/auth/session", "Current cookie session", || {
+                web::get().to(auth::session_info)
+            }))
+            .route(RouteSpec::get("/auth/client-certificate", "Client certificate of the TLS connection", || {
+                web::get().to(auth::client_certificate)
+            }))
+            .route(RouteSpec::post("/auth/verify", "Verify an email address", || {
+                web::post().to(auth::verify)
+            }))
+            .route(RouteSpec::post("/auth/reset", "Request or complete a password reset", || {
+                web::post().to(auth::reset)
+            }))
+            .route(RouteSpec::post("/auth/guest", "Anonymous guest token", || {
+                web::post().to(auth::guest)
+            }))
+            .route(RouteSpec::get("/.well-known/jwks.json", "Public keys verifying issued tokens", || {
+                web::get().to(auth::jwks)
+            }))
+            .route(RouteSpec::get("/auth/oidc/login", "Start an OpenID Connect login", || {
+                web::get().to(auth::oidc_login)
+            }))
+            .route(RouteSpec::get("/auth/oidc/callback", "Complete an OpenID Connect login", || {
+                web::get().to(auth::oidc_callback)
+            }))
+            .route(RouteSpec::get("/auth/oidc/me", "Claims of an OpenID Connect ID token", || {
+                web::get().to(auth::oidc_me)
+            }))
+            .route(RouteSpec::get("/tos", "Current Terms of Service", || web::get().to(terms::current)))
+            .route(
+                RouteSpec::post("/tos/accept", "Accept the current Terms of Service", || {
+                    web::post().to(terms::accept)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::get("/me/export", "Download all your data", || web::get().to(me::export))
+                    .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::delete("/me", "Request erasure of your account", || web::delete().to(me::erase))
+                    .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/me/deletion/cancel", "Cancel a pending account erasure", || {
+                    web::post().to(me::cancel_erasure)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::put("/me/privacy", "Set your analytics opt-out", || {
+                    web::put().to(me::update_privacy)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::get("/me/sessions", "List your active sessions", || {
+                    web::get().to(me::list_sessions)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::delete("/me/sessions/{id}", "Revoke one of your sessions", || {
+                    web::delete().to(me::revoke_session)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/me/api-keys", "Create an API key", || {
+                    web::post().to(me::create_api_key)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::get("/me/api-keys", "List your API keys", || {
+                    web::get().to(me::list_api_keys)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::delete("/me/api-keys/{id}", "Revoke one of your API keys", || {
+                    web::delete().to(me::revoke_api_key)
+                })
+                .require_scopes(&[ACCOUNT_SCOPE]),
+            )
+            .route(
+                RouteSpec::post("/admin/impersonate", "Mint a token acting as another user", || {
+                    web::post().to(admin::impersonate)
+                })
+                .require_scopes(&[IMPERSONATE_SCOPE])
+                .require_roles(&[OWNER_ROLE]),
+            )
+            .route(
+                RouteSpec::post("/admin/jwt/rotate", "Rotate the token signing key", || {
+                    web::post().to(admin::rotate_signing_key)
+                })
+                .require_scopes(&[KEYS_ADMIN_SCOPE])
+                .require_roles(&[OWNER_ROLE]),
+            )
+            .route(
+                RouteSpec::post("/admin/tos", "Publish a new Terms of Service version", || {
+                    web::post().to(admin::bump_terms)
+                })
+                .require_scopes(&[TERMS_ADMIN_SCOPE])
+                .require_roles(&[OPERATOR_ROLE]),
+            )
+            .route(
+                RouteSpec::post("/admin/anonymize", "Anonymize records past the retention window", || {
+                    web::post().to(admin::anonymize)
+                })
+                .require_scopes(&[DATA_ADMIN_SCOPE])
+                .require_roles(&[OPERATOR_ROLE]),
+            )
+            .route(
+                RouteSpec::get("/admin/config", "Report the effective configuration, secrets masked", || {
+                    web::get().to(admin::effective_config)
+                })
+                .require_scopes(&[CONFIG_SCOPE])
+                .require_roles(&[OPERATOR_ROLE]),
+            )
+            .route(
+                RouteSpec::get("/admin/integrity", "Report storage integrity issues", || {
+                    web::get().to(admin::integrity)
+                })
+                .require_scopes(&[DATA_ADMIN_SCOPE])
+                .require_roles(&[VIEWER_ROLE]),
+            )
+            .route(
+                RouteSpec::get("/admin/metrics.json", "Snapshot every metric as JSON", || {
+                    web::get().to(admin::metrics_snapshot)
+                })
+                .require_scopes(&[METRICS_SCOPE])
+                .require_roles(&[VIEWER_ROLE]),
+            )
+            .route(
+                RouteSpec::get("/admin/slow-queries", "Report slow storage queries", || {
+                    web::get().to(admin::slow_queries)
+                })
+                .require_scopes(&[METRICS_SCOPE])
+                .require_roles(&[VIEWER_ROLE]),
+            )
+            .route(
+                RouteSpec::get("/admin/usage", "Export aggregated daily usage", || {
+                    web::get().to(admin::usage)
+                })
+                .require_scopes(&[DATA_ADMIN_SCOPE])
+                .require_roles(&[VIEWER_ROLE]),
+            )
+            .route(
+                RouteSpec::post("/admin/demo-data", "Generate demo users, audit trails and usage", || {
+                    web::post().to(admin::demo_data)
+                })
+                .require_scopes(&[DATA_ADMIN_SCOPE])
+                .require_roles(&[OPERATOR_ROLE]),
+            )
+            .route(
+                RouteSpec::post("/admin/policy/reload", "Reload the authorization policy files", || {
+                    web::post().to(admin::reload_policy)
+                })
+                .require_scopes(&[POLICY_ADMIN_SCOPE])
+                .require_roles(&[OPERATOR_ROLE]),
+            )
+            .route(
+                RouteSpec::get(TOGGLE_ROUTE, "Read-only mode status", || web::get().to(admin::read_only_status))
+                    .require_scopes(&[MAINTENANCE_SCOPE])
+                    .require_roles(&[VIEWER_ROLE]),
+            )
+            .route(
+                RouteSpec::put(DRAIN_ROUTE, "Drain the load balancer weight or restore it", || {
+                    web::put().to(admin::set_drain)
+                })
+                .require_scopes(&[MAINTENANCE_SCOPE])
+                .require_roles(&[OPERATOR_ROLE]),
+            )
+            .route(
+                RouteSpec::put(TOGGLE_ROUTE, "Switch the read-only mode on or off", || {
+                    web::put().to(admin::set_read_only)
+                })
+                .require_scopes(&[MAINTENANCE_SCOPE])
+                .require_roles(&[OPERATOR_ROLE]),
+            )
+            .route(
+                RouteSpec::post("/admin/backup", "Snapshot the in-memory state store", || {
+                    web::post().to(admin::backup)
+                })
+                .require_scopes(&[BACKUP_SCOPE])
+                .require_roles(&[OPERATOR_ROLE]),
+            )
+            .route(
+                RouteSpec::get("/admin/dumps", "List captured dumps of failed requests", || {
+                    web::get().to(admin::error_dumps)
+                })
+                .require_scopes(&[DUMPS_SCOPE])
+                .require_roles(&[VIEWER_ROLE]),
+            )
+            .route(
+                RouteSpec::get("/admin/dumps/{id}", "Get a captured dump of a failed request", || {
+                    web::get().to(admin::error_dump)
+                })
+                .require_scopes(&[DUMPS_SCOPE])
+                .require_roles(&[VIEWER_ROLE]),
+            )
+            .route(
+                RouteSpec::get("/openapi.json", "OpenAPI document", || web::get().to(app_server::openapi))
+                    .warm_cache(),
+            )
+            .route(
+                RouteSpec::get("/docs", "Interactive API reference (Swagger UI)", || web::get().to(assets::serve))
+                    .warm_cache(),
+            )
+            .route(RouteSpec::get("/console", "Browser API console", || web::get().to(assets::serve)).warm_cache())
+            .route(RouteSpec::get("/dashboard", "Service dashboard", || web::get().to(assets::serve)).warm_cache())
+            .route(RouteSpec::get("/favicon.ico", "Favicon", || web::get().to(assets::serve)).warm_cache())
+    }
+
+    /// Registers every route, grouping methods that share a path into one resource
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        let mut paths: Vec<&'static str> = Vec::new();
+        for spec in &self.routes {
+            if !paths.contains(&spec.path) {
+                paths.push(spec.path);
+            }
+        }
+
+        for path in paths {
+            let resource = self
+                .routes
+                .iter()
+                .filter(|spec| spec.path == path)
+                .fold(web::resource(path), |resource, spec| resource.route(spec.build()));
+            cfg.service(resource);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test::{call_service, init_service, TestRequest}, App, HttpResponse};
+
+    #[test]
+    fn test_app_server_routes_declare_private_scope() {
+        let registry = RouteRegistry::app_server();
+        let private = registry
+            .routes()
+            .iter()
+            .find(|spec| spec.path == "/private")
+            .unwrap();
+        assert_eq!(private.scopes, vec!["read:private"]);
+    }
+
+    #[test]
+    fn test_require_scopes_on_paths() {
+        let routes = vec![
+            ("/public".to_string(), vec!["read:public".to_string()]),
+            ("/private".to_string(), vec!["read:private".to_string(), "audit".to_string()]),
+        ];
+        let registry = RouteRegistry::app_server().require_scopes_on(&routes).unwrap();
+        let scopes = |path: &str| registry.routes().iter().find(|spec| spec.path == path).unwrap().scopes.clone();
+        assert_eq!(scopes("/public"), vec!["read:public"]);
+        assert_eq!(scopes("/private"), vec!["read:private", "audit"]);
+
+        let unknown = vec![("/nope".to_string(), vec!["read:public".to_string()])];
+        assert!(matches!(
+            RouteRegistry::app_server().require_scopes_on(&unknown),
+            Err(AppError::Config { .. })
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_methods_sharing_a_path_are_grouped() {
+        let registry = RouteRegistry::new()
+            .route(RouteSpec::get("/items", "List", || web::get().to(HttpResponse::Ok)))
+            .route(RouteSpec::post("/items", "Create", || web::post().to(HttpResponse::Created)));
+        let app = init_service(App::new().configure(|cfg| registry.configure(cfg))).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/items").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&app, TestRequest::post().uri("/items").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = call_service(&app, TestRequest::delete().uri("/items").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_require_basic_auth_on_paths() {
+        let registry = RouteRegistry::app_server()
+            .require_basic_auth_on(&["/public".to_string()])
+            .unwrap();
+        let protected: Vec<&str> = registry
+            .routes()
+            .iter()
+            .filter(|spec| spec.basic_auth)
+            .map(|spec| spec.path)
+            .collect();
+        assert_eq!(protected, vec!["/public"]);
+
+        assert!(matches!(
+            RouteRegistry::app_server().require_basic_auth_on(&["/nope".to_string()]),
+            Err(AppError::Config { .. })
+        ));
+    }
+
+    #[test]
+    fn test_require_signature_on_paths() {
+        let registry = RouteRegistry::app_server()
+            .require_signature_on(&["/hooks/{provider}".to_string()])
+            .unwrap();
+        let signed: Vec<&str> = registry.routes().iter().filter(|spec| spec.signed).map(|spec| spec.path).collect();
+        assert_eq!(signed, vec!["/hooks/{provider}"]);
+
+        assert!(matches!(
+            RouteRegistry::app_server().require_signature_on(&["/nope".to_string()]),
+            Err(AppError::Config { .. })
+        ));
+    }
+
+    #[test]
+    fn test_require_policy_on_paths() {
+        let registry = RouteRegistry::app_server()
+            .require_policy_on(&["/private".to_string()])
+            .unwrap();
+        let guarded: Vec<&str> = registry.routes().iter().filter(|spec| spec.policy).map(|spec| spec.path).collect();
+        assert_eq!(guarded, vec!["/private"]);
+
+        assert!(matches!(
+            RouteRegistry::app_server().require_policy_on(&["/nope".to_string()]),
+            Err(AppError::Config { .. })
+        ));
+    }
+
+    #[test]
+    fn test_require_roles_on_paths() {
+        let policy = BTreeMap::from([("/private".to_string(), vec!["member".to_string()])]);
+        let registry = RouteRegistry::app_server().require_roles_on(&policy).unwrap();
+        let private = registry.routes().iter().find(|spec| spec.path == "/private").unwrap();
+        assert_eq!(private.roles, vec!["member".to_string()]);
+
+        let unknown = BTreeMap::from([("/nope".to_string(), vec!["member".to_string()])]);
+        assert!(matches!(
+            RouteRegistry::app_server().require_roles_on(&unknown),
+            Err(AppError::Config { .. })
+        ));
+    }
+    #[test]
+    fn test_warm_paths_skip_protected_routes() {
+        let registry = RouteRegistry::app_server();
+        assert_eq!(registry.warm_paths(), vec!["/openapi.json", "/docs", "/console", "/dashboard", "/favicon.ico"]);
+
+        let registry = registry.require_basic_auth_on(&["/docs".to_string()]).unwrap();
+        assert!(!registry.warm_paths().contains(&"/docs"));
+    }
+}
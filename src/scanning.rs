@@ -0,0 +1,306 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use futures::future::BoxFuture;
+use log::warn;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+
+/// Bytes sent to clamd per `INSTREAM` chunk
+const CLAMAV_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Leading bytes identifying content whose declared type is checked
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"MZ", "application/x-msdownload"),
+];
+
+/// A file received by an upload endpoint, before it is persisted
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub filename: String,
+    /// Type declared by the client
+    pub content_type: String,
+    pub bytes: Bytes,
+}
+
+impl Upload {
+    /// Creates an upload
+    pub fn new(filename: impl Into<String>, content_type: impl Into<String>, bytes: impl Into<Bytes>) -> Self {
+        Self {
+            filename: filename.into(),
+            content_type: content_type.into(),
+            bytes: bytes.into(),
+        }
+    }
+}
+
+/// Inspects uploads before they are persisted
+///
+/// Upload handlers call the scanner registered as app data and store the
+/// file only when it passes.
+pub trait ContentScanner: Send + Sync {
+    /// Accepts `upload`, or refuses it with [`AppError::Unprocessable`]
+    ///
+    /// A scanner unable to reach a verdict fails with another error rather
+    /// than letting the upload through.
+    fn scan<'a>(&'a self, upload: &'a Upload) -> BoxFuture<'a, AppResult<()>>;
+}
+
+impl<T: ContentScanner + ?Sized> ContentScanner for Arc<T> {
+    fn scan<'a>(&'a self, upload: &'a Upload) -> BoxFuture<'a, AppResult<()>> {
+        (**self).scan(upload)
+    }
+}
+
+/// Scanner admitting a list of MIME types
+///
+/// Content starting with a known signature (images, PDF, executables) must
+/// also be of the type it is declared as, so a renamed executable is caught.
+pub struct MimeTypeScanner {
+    allowed: Vec<String>,
+}
+
+impl MimeTypeScanner {
+    /// Creates a scanner admitting `allowed` types, such as `image/*`
+    pub fn new(allowed: Vec<String>) -> Self {
+        Self {
+            allowed: allowed.into_iter().map(|pattern| pattern.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    fn allows(&self, content_type: &str) -> bool {
+        self.allowed.iter().any(|pattern| match pattern.strip_suffix("/*") {
+            Some(prefix) => content_type.split('/').next() == Some(prefix),
+            None => pattern == "*" || pattern == content_type,
+        })
+    }
+
+    fn check(&self, upload: &Upload) -> AppResult<()> {
+        // Parameters such as `; charset=utf-8` do not change the type
+        let declared = upload
+            .content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if !self.allows(&declared) {
+            return Err(AppError::unprocessable(format!("uploads of type '{}' are not accepted", declared)));
+        }
+        let sniffed = SIGNATURES
+            .iter()
+            .find(|(signature, _)| upload.bytes.starts_with(signature))
+            .map(|(_, content_type)| *content_type);
+        match sniffed {
+            Some(actual) if actual != declared => Err(AppError::unprocessable(format!(
+                "upload declared as '{}' looks like '{}'",
+                declared, actual
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ContentScanner for MimeTypeScanner {
+    fn scan<'a>(&'a self, upload: &'a Upload) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move { self.check(upload) })
+    }
+}
+
+/// Scanner streaming uploads to a clamd daemon (`INSTREAM` command)
+///
+/// Infected files are refused; an unreachable daemon fails the scan with a
+/// 503 so nothing is stored unscanned.
+pub struct ClamAvScanner {
+    address: String,
+    timeout: Duration,
+}
+
+impl ClamAvScanner {
+    /// Creates a scanner talking to clamd at `address` (`host:port`)
+    pub fn new(address: impl Into<String>, timeout: Duration) -> Self {
+        Self {
+            address: address.into(),
+            timeout,
+        }
+    }
+
+    fn instream(address: &str, timeout: Duration, bytes: &[u8]) -> std::io::Result<String> {
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::other(format!("{} does not resolve", address)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.write_all(b"zINSTREAM\0")?;
+        for chunk in bytes.chunks(CLAMAV_CHUNK_SIZE) {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes())?;
+            stream.write_all(chunk)?;
+        }
+        stream.write_all(&0u32.to_be_bytes())?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply)?;
+        Ok(String::from_utf8_lossy(&reply).trim_end_matches('\0').trim().to_string())
+    }
+}
+
+impl ContentScanner for ClamAvScanner {
+    fn scan<'a>(&'a self, upload: &'a Upload) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            let (address, timeout, bytes) = (self.address.clone(), self.timeout, upload.bytes.clone());
+            let reply = tokio::task::spawn_blocking(move || Self::instream(&address, timeout, &bytes))
+                .await
+                .map_err(|e| AppError::internal(format!("content scan failed: {}", e)))?
+                .map_err(|e| {
+                    warn!("clamd at {} is unreachable: {}", self.address, e);
+                    AppError::unavailable("content scanner unavailable")
+                })?;
+            let verdict = reply.strip_prefix("stream:").unwrap_or(&reply).trim();
+            if verdict == "OK" {
+                Ok(())
+            } else if let Some(signature) = verdict.strip_suffix("FOUND") {
+                Err(AppError::unprocessable(format!(
+                    "upload '{}' contains malware ({})",
+                    upload.filename,
+                    signature.trim()
+                )))
+            } else if verdict.contains("size limit exceeded") {
+                Err(AppError::unprocessable("upload is too large to be scanned"))
+            } else {
+                warn!("clamd at {} answered: {}", self.address, reply);
+                Err(AppError::unavailable("content scanner unavailable"))
+            }
+        })
+    }
+}
+
+/// Scanners run one after the other, the first refusal winning
+#[derive(Default)]
+pub struct ScannerChain {
+    scanners: Vec<Arc<dyn ContentScanner>>,
+}
+
+impl ScannerChain {
+    /// Creates a chain accepting everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the chain from `UPLOAD_ALLOWED_TYPES` and `CLAMAV_*`
+    ///
+    /// Types are checked first, so clamd only sees admissible files.
+    pub fn from_config(config: &Config) -> Self {
+        let mut chain = Self::new();
+        if !config.upload_allowed_types.is_empty() {
+            chain = chain.with(Arc::new(MimeTypeScanner::new(config.upload_allowed_types.clone())));
+        }
+        if let Some(address) = &config.clamav_address {
+            chain = chain.with(Arc::new(ClamAvScanner::new(
+                address.clone(),
+                Duration::from_secs(config.clamav_timeout_secs),
+            )));
+        }
+        chain
+    }
+
+    /// Appends a scanner
+    pub fn with(mut self, scanner: Arc<dyn ContentScanner>) -> Self {
+        self.scanners.push(scanner);
+        self
+    }
+}
+
+impl ContentScanner for ScannerChain {
+    fn scan<'a>(&'a self, upload: &'a Upload) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            for scanner in &self.scanners {
+                scanner.scan(upload).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[actix_web::test]
+    async fn test_mime_types_and_signatures() {
+        let scanner = MimeTypeScanner::new(vec!["image/*".to_string(), "text/plain".to_string()]);
+        let png = b"\x89PNG\r\n\x1a\n....".to_vec();
+        assert!(scanner.scan(&Upload::new("a.png", "image/png", png.clone())).await.is_ok());
+        assert!(scanner.scan(&Upload::new("a.txt", "text/plain; charset=utf-8", "hello")).await.is_ok());
+
+        for upload in [
+            Upload::new("a.pdf", "application/pdf", "%PDF-1.7"),
+            Upload::new("a.txt", "text/plain", png),
+            Upload::new("a.jpg", "image/jpeg", b"MZ\x90\x00".to_vec()),
+        ] {
+            let result = scanner.scan(&upload).await;
+            assert!(matches!(result, Err(AppError::Unprocessable { .. })), "{}", upload.filename);
+        }
+    }
+
+    /// Fake clamd answering every `INSTREAM` with `reply` once the stream ends
+    fn clamd(reply: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut command = [0u8; 10];
+                stream.read_exact(&mut command).unwrap();
+                assert_eq!(&command, b"zINSTREAM\0");
+                loop {
+                    let mut length = [0u8; 4];
+                    stream.read_exact(&mut length).unwrap();
+                    let length = u32::from_be_bytes(length) as usize;
+                    if length == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0u8; length];
+                    stream.read_exact(&mut chunk).unwrap();
+                }
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        address
+    }
+
+    #[actix_web::test]
+    async fn test_clamav_verdicts() {
+        let upload = Upload::new("report.pdf", "application/pdf", vec![0u8; CLAMAV_CHUNK_SIZE + 1]);
+        let timeout = Duration::from_secs(5);
+
+        assert!(ClamAvScanner::new(clamd("stream: OK\0"), timeout).scan(&upload).await.is_ok());
+        let infected = ClamAvScanner::new(clamd("stream: Eicar-Test-Signature FOUND\0"), timeout)
+            .scan(&upload)
+            .await;
+        match infected {
+            Err(AppError::Unprocessable { message }) => assert!(message.contains("Eicar-Test-Signature")),
+            other => panic!("unexpected verdict: {:?}", other),
+        }
+
+        // Nothing listens there anymore: the scan fails closed
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let chain = ScannerChain::new()
+            .with(Arc::new(MimeTypeScanner::new(vec!["application/pdf".to_string()])))
+            .with(Arc::new(ClamAvScanner::new(address, timeout)));
+        assert!(matches!(chain.scan(&upload).await, Err(AppError::Unavailable { .. })));
+        let refused = Upload::new("run.exe", "application/x-msdownload", "MZ");
+        assert!(matches!(chain.scan(&refused).await, Err(AppError::Unprocessable { .. })));
+    }
+}
@@ -0,0 +1,593 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+
+use crate::config::Config;
+use crate::error::AppResult;
+
+#[cfg(feature = "scripting")]
+pub use self::engine::{ScriptHooks, SCRIPT_RELOAD_INTERVAL};
+
+/// Resource limits applied to every hook invocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptLimits {
+    /// Operations (roughly, evaluated expressions) a single call may perform
+    pub max_operations: u64,
+    /// Wall-clock budget of a single call
+    pub timeout: Duration,
+}
+
+impl ScriptLimits {
+    /// Reads `SCRIPT_MAX_OPERATIONS` and `SCRIPT_TIMEOUT_MS`
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_operations: config.script_max_operations,
+            timeout: Duration::from_millis(config.script_timeout_ms),
+        }
+    }
+}
+
+/// Request hooks are only available with the `scripting` feature; without
+/// it no hooks can be built
+#[cfg(not(feature = "scripting"))]
+pub enum ScriptHooks {}
+
+#[cfg(not(feature = "scripting"))]
+impl ScriptHooks {
+    /// Fails: the binary was built without the `scripting` feature
+    pub fn new(_dir: PathBuf, _limits: ScriptLimits) -> AppResult<Self> {
+        Err(crate::error::AppError::config("SCRIPTS_DIR requires building with the `scripting` feature"))
+    }
+}
+
+impl ScriptHooks {
+    /// Loads the hooks from `SCRIPTS_DIR`, if set
+    ///
+    /// # Errors
+    /// Returns a config error when the directory cannot be read or the
+    /// feature is missing
+    pub fn from_config(config: &Config) -> AppResult<Option<Self>> {
+        config
+            .scripts_dir
+            .as_ref()
+            .map(|dir| Self::new(PathBuf::from(dir), ScriptLimits::from_config(config)))
+            .transpose()
+    }
+}
+
+/// Runs the registered [`ScriptHooks`] around the request
+///
+/// Request hooks run before routing, so a rewritten path selects the route
+/// that handles the request. Response hooks only see responses produced by
+/// the routes, not rejections raised by middleware.
+pub async fn run_scripts(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    #[cfg(feature = "scripting")]
+    if let Some(hooks) = req.app_data::<actix_web::web::Data<ScriptHooks>>().cloned() {
+        let mut req = req;
+        hooks.on_request(&mut req)?;
+        let mut res = next.call(req).await?;
+        hooks.on_response(&mut res);
+        return Ok(res);
+    }
+    next.call(req).await
+}
+
+#[cfg(feature = "scripting")]
+mod engine {
+    use std::cell::Cell;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::{Duration, Instant, SystemTime};
+
+    use actix_web::dev::{ServiceRequest, ServiceResponse};
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use actix_web::http::Uri;
+    use log::{debug, info, warn};
+    use rhai::module_resolvers::DummyModuleResolver;
+    use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+
+    use super::ScriptLimits;
+    use crate::error::{AppError, AppResult};
+    use crate::supervisor::Supervisor;
+
+    /// How often the script directory is checked for changes
+    pub const SCRIPT_RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// Operations between two wall-clock checks
+    const DEADLINE_CHECK_EVERY: u64 = 256;
+
+    thread_local! {
+        /// Deadline of the hook running on this thread
+        static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    /// A compiled script file
+    #[derive(Clone)]
+    struct Script {
+        name: String,
+        ast: AST,
+        on_request: bool,
+        on_response: bool,
+    }
+
+    /// Modification time and size of every script file, to detect changes
+    type Fingerprint = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+    /// Operator-provided rhai scripts run around every application request
+    ///
+    /// Every `*.rhai` file of the script directory may define either or both
+    /// hooks; scripts run in file name order and each sees the changes made by
+    /// the previous ones:
+    ///
+    /// ```rhai
+    /// // req: #{ method, path, query, headers }
+    /// fn on_request(req) {
+    ///     if req.path.starts_with("/internal") {
+    ///         return #{ reject: "internal routes are not exposed", status: 404 };
+    ///     }
+    ///     #{ path: req.path.replace("/v1/", "/"), headers: #{ "x-rewritten": "1" } }
+    /// }
+    ///
+    /// // res: #{ status, path, headers }
+    /// fn on_response(res) {
+    ///     #{ headers: #{ "x-frame-options": "DENY" } }
+    /// }
+    /// ```
+    ///
+    /// A hook returning `()` changes nothing. Hooks that fail, exceed their
+    /// [`ScriptLimits`] or return malformed values are logged and skipped, so a
+    /// broken script never takes the API down. The directory is polled for
+    /// changes; a script that no longer compiles keeps its previous version.
+    pub struct ScriptHooks {
+        dir: PathBuf,
+        limits: ScriptLimits,
+        engine: Engine,
+        scripts: RwLock<Vec<Script>>,
+        fingerprint: Mutex<Fingerprint>,
+    }
+
+    impl ScriptHooks {
+        /// Compiles every script in `dir`
+        ///
+        /// # Errors
+        /// Returns a config error when `dir` cannot be read; scripts that
+        /// fail to compile are logged and left out
+        pub fn new(dir: PathBuf, limits: ScriptLimits) -> AppResult<Self> {
+            let hooks = Self {
+                dir,
+                limits,
+                engine: Self::engine(limits),
+                scripts: RwLock::new(Vec::new()),
+                fingerprint: Mutex::new(Vec::new()),
+            };
+            hooks.reload()?;
+            Ok(hooks)
+        }
+
+        /// Builds a sandboxed engine: no `eval`, no module imports, bounded
+        /// recursion and data sizes
+        fn engine(limits: ScriptLimits) -> Engine {
+            let mut engine = Engine::new();
+            engine
+                .set_max_operations(limits.max_operations)
+                .set_max_call_levels(16)
+                .set_max_expr_depths(64, 32)
+                .set_max_string_size(64 * 1024)
+                .set_max_array_size(1024)
+                .set_max_map_size(256)
+                .set_module_resolver(DummyModuleResolver::new())
+                .disable_symbol("eval");
+            engine
+                .on_print(|text| info!(target: "scripts", "{}", text))
+                .on_debug(|text, source, _| debug!(target: "scripts", "{}: {}", source.unwrap_or("script"), text))
+                .on_progress(|operations| {
+                    let expired = operations % DEADLINE_CHECK_EVERY == 0
+                        && DEADLINE.with(Cell::get).is_some_and(|deadline| Instant::now() >= deadline);
+                    expired.then(|| "time limit exceeded".into())
+                });
+            engine
+        }
+
+        /// Recompiles the script directory
+        ///
+        /// # Errors
+        /// Returns a config error when the directory cannot be read
+        pub fn reload(&self) -> AppResult<()> {
+            let fingerprint = self.fingerprint()?;
+            let previous = self.scripts.read().map_err(|_| poisoned())?.clone();
+            let mut scripts = Vec::new();
+            for (path, ..) in &fingerprint {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                match self.compile(path) {
+                    Ok(ast) => {
+                        let defines = |hook: &str| ast.iter_functions().any(|f| f.name == hook && f.params.len() == 1);
+                        let (on_request, on_response) = (defines("on_request"), defines("on_response"));
+                        scripts.push(Script { name, ast, on_request, on_response });
+                    }
+                    Err(e) => {
+                        warn!("Script {} not loaded: {}", path.display(), e);
+                        if let Some(script) = previous.iter().find(|script| script.name == name) {
+                            scripts.push(script.clone());
+                        }
+                    }
+                }
+            }
+            info!(
+                "Loaded {} request script(s) from {}",
+                scripts.len(),
+                self.dir.display()
+            );
+            *self.scripts.write().map_err(|_| poisoned())? = scripts;
+            *self.fingerprint.lock().map_err(|_| poisoned())? = fingerprint;
+            Ok(())
+        }
+
+        /// Reloads the scripts if a file was added, changed or removed
+        ///
+        /// Returns whether a reload happened.
+        pub fn reload_if_changed(&self) -> AppResult<bool> {
+            let fingerprint = self.fingerprint()?;
+            if *self.fingerprint.lock().map_err(|_| poisoned())? == fingerprint {
+                return Ok(false);
+            }
+            self.reload()?;
+            Ok(true)
+        }
+
+        /// Returns the names of the loaded scripts, in execution order
+        pub fn names(&self) -> Vec<String> {
+            self.scripts
+                .read()
+                .map(|scripts| scripts.iter().map(|script| script.name.clone()).collect())
+                .unwrap_or_default()
+        }
+
+        /// Checks the script directory for changes every `every` under `supervisor`
+        pub fn spawn_watcher(hooks: Arc<Self>, every: Duration, supervisor: &Arc<Supervisor>) {
+            supervisor.spawn("script_watcher", move || {
+                let hooks = hooks.clone();
+                async move {
+                    let mut interval = actix_web::rt::time::interval(every);
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = hooks.reload_if_changed() {
+                            warn!("Script reload failed: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+
+        /// Runs the `on_request` hooks, rewriting or rejecting the request
+        ///
+        /// # Errors
+        /// Returns the error a hook rejected the request with
+        pub fn on_request(&self, req: &mut ServiceRequest) -> AppResult<()> {
+            let scripts = self.scripts.read().map_err(|_| poisoned())?;
+            for script in scripts.iter().filter(|script| script.on_request) {
+                let mut input = Map::new();
+                input.insert("method".into(), req.method().as_str().into());
+                input.insert("path".into(), req.path().into());
+                input.insert("query".into(), req.query_string().into());
+                input.insert("headers".into(), headers_map(req.headers()).into());
+                let Some(output) = self.call(script, "on_request", input) else {
+                    continue;
+                };
+
+                if let Some(reject) = output.get("reject").filter(|value| !value.is_unit()) {
+                    let status = output.get("status").and_then(|status| status.as_int().ok());
+                    let message = match reject.clone().into_string() {
+                        Ok(message) => message,
+                        Err(_) => "request rejected by rule".to_string(),
+                    };
+                    info!("Script {} rejected {} {}", script.name, req.method(), req.path());
+                    return Err(rejection(status, message));
+                }
+                if let Some(path) = output.get("path").and_then(|path| path.clone().into_string().ok()) {
+                    if let Err(e) = rewrite_path(req, &path) {
+                        warn!("Script {} returned an invalid path: {}", script.name, e);
+                    }
+                }
+                set_headers(&script.name, req.headers_mut(), &output);
+            }
+            Ok(())
+        }
+
+        /// Runs the `on_response` hooks, adding the headers they return
+        pub fn on_response<B>(&self, res: &mut ServiceResponse<B>) {
+            let Ok(scripts) = self.scripts.read() else {
+                return;
+            };
+            for script in scripts.iter().filter(|script| script.on_response) {
+                let mut input = Map::new();
+                input.insert("status".into(), i64::from(res.status().as_u16()).into());
+                input.insert("path".into(), res.request().path().into());
+                input.insert("headers".into(), headers_map(res.headers()).into());
+                if let Some(output) = self.call(script, "on_response", input) {
+                    set_headers(&script.name, res.headers_mut(), &output);
+                }
+            }
+        }
+
+        /// Calls `hook` within the limits; failures are logged and yield `None`
+        fn call(&self, script: &Script, hook: &str, input: Map) -> Option<Map> {
+            DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.limits.timeout)));
+            let result = self.engine.call_fn_with_options::<Dynamic>(
+                CallFnOptions::new().eval_ast(false),
+                &mut Scope::new(),
+                &script.ast,
+                hook,
+                (input,),
+            );
+            DEADLINE.with(|deadline| deadline.set(None));
+            match result {
+                Ok(output) if output.is_unit() => None,
+                Ok(output) => {
+                    let output = output.try_cast::<Map>();
+                    if output.is_none() {
+                        warn!("Script {} {} must return a map or ()", script.name, hook);
+                    }
+                    output
+                }
+                Err(e) => {
+                    warn!("Script {} {} failed: {}", script.name, hook, e);
+                    None
+                }
+            }
+        }
+
+        fn compile(&self, path: &Path) -> AppResult<AST> {
+            let source = std::fs::read_to_string(path)
+                .map_err(|e| AppError::config(format!("Failed to read {}: {}", path.display(), e)))?;
+            self.engine
+                .compile(source)
+                .map_err(|e| AppError::config(format!("Failed to compile {}: {}", path.display(), e)))
+        }
+
+        fn fingerprint(&self) -> AppResult<Fingerprint> {
+            let entries = std::fs::read_dir(&self.dir).map_err(|e| {
+                AppError::config(format!("Failed to read scripts directory {}: {}", self.dir.display(), e))
+            })?;
+            let mut fingerprint: Fingerprint = entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|extension| extension == "rhai"))
+                .map(|path| {
+                    let metadata = std::fs::metadata(&path).ok();
+                    let modified = metadata.as_ref().and_then(|metadata| metadata.modified().ok());
+                    let len = metadata.map(|metadata| metadata.len()).unwrap_or_default();
+                    (path, modified, len)
+                })
+                .collect();
+            fingerprint.sort();
+            Ok(fingerprint)
+        }
+    }
+
+    fn poisoned() -> AppError {
+        AppError::internal("script registry lock poisoned")
+    }
+
+    /// Maps a hook's rejection to the error answered to the client
+    fn rejection(status: Option<i64>, message: String) -> AppError {
+        match status {
+            Some(400) => AppError::validation(message),
+            Some(401) => AppError::unauthorized(message),
+            Some(404) => AppError::not_found(message),
+            Some(503) => AppError::unavailable(message),
+            _ => AppError::forbidden(message),
+        }
+    }
+
+    /// Headers as a map of lowercase names to values, repeated values joined
+    fn headers_map(headers: &HeaderMap) -> Map {
+        let mut map = Map::new();
+        for name in headers.keys() {
+            let values: Vec<&str> = headers
+                .get_all(name)
+                .filter_map(|value| value.to_str().ok())
+                .collect();
+            map.insert(name.as_str().into(), values.join(", ").into());
+        }
+        map
+    }
+
+    /// Replaces the request path, keeping the query string
+    fn rewrite_path(req: &mut ServiceRequest, path: &str) -> AppResult<()> {
+        if !path.starts_with('/') {
+            return Err(AppError::validation(format!("{} is not an absolute path", path)));
+        }
+        let target = match req.query_string() {
+            "" => path.to_string(),
+            query => format!("{}?{}", path, query),
+        };
+        let uri: Uri = target
+            .parse()
+            .map_err(|e| AppError::validation(format!("{} is not a valid path: {}", path, e)))?;
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+        Ok(())
+    }
+
+    /// Applies the `headers` map a hook returned
+    fn set_headers(script: &str, headers: &mut HeaderMap, output: &Map) {
+        let Some(values) = output.get("headers").and_then(|values| values.clone().try_cast::<Map>()) else {
+            return;
+        };
+        for (name, value) in values {
+            let parsed = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(&value.to_string()),
+            );
+            match parsed {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => warn!("Script {} returned an invalid header {}", script, name),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "scripting"))]
+    #[test]
+    fn test_scripts_require_feature() {
+        let config = Config {
+            scripts_dir: Some("scripts".to_string()),
+            ..Config::default()
+        };
+        assert!(matches!(ScriptHooks::from_config(&config), Err(crate::error::AppError::Config { .. })));
+        assert!(ScriptHooks::from_config(&Config::default()).unwrap().is_none());
+    }
+
+    #[cfg(feature = "scripting")]
+    mod hooks {
+        use super::*;
+        use actix_web::middleware::from_fn;
+        use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+        use actix_web::{web, App, HttpRequest, HttpResponse};
+
+        fn script_dir(scripts: &[(&str, &str)]) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("scripts-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            for (name, source) in scripts {
+                std::fs::write(dir.join(name), source).unwrap();
+            }
+            dir
+        }
+
+        fn limits() -> ScriptLimits {
+            ScriptLimits {
+                max_operations: 10_000,
+                timeout: Duration::from_millis(200),
+            }
+        }
+
+        async fn echo(req: HttpRequest) -> HttpResponse {
+            let tag = req
+                .headers()
+                .get("x-tag")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("-");
+            HttpResponse::Ok().body(format!("{} {}", req.path(), tag))
+        }
+
+        #[actix_web::test]
+        async fn test_rewrite_reject_and_response_headers() {
+            let dir = script_dir(&[
+                (
+                    "10-gate.rhai",
+                    r#"
+                    fn on_request(req) {
+                        if req.path.starts_with("/internal") {
+                            return #{ reject: "not exposed", status: 404 };
+                        }
+                        if req.headers["x-block"] == "1" { return #{ reject: true }; }
+                    }
+                    "#,
+                ),
+                (
+                    "20-rewrite.rhai",
+                    r#"
+                    fn on_request(req) {
+                        if req.path.starts_with("/v1/") {
+                            return #{ path: req.path.sub_string(3), headers: #{ "x-tag": "rewritten" } };
+                        }
+                    }
+                    fn on_response(res) { #{ headers: #{ "x-frame-options": "DENY" } } }
+                    "#,
+                ),
+                ("30-broken.rhai", "fn on_request(req) { let = ; }"),
+                ("notes.txt", "not a script"),
+            ]);
+            let hooks = web::Data::new(ScriptHooks::new(dir.clone(), limits()).unwrap());
+            assert_eq!(hooks.names(), vec!["10-gate.rhai", "20-rewrite.rhai"]);
+
+            let app = init_service(
+                App::new()
+                    .app_data(hooks)
+                    .wrap(from_fn(run_scripts))
+                    .route("/items/{id}", web::get().to(echo)),
+            )
+            .await;
+
+            let res = call_service(&app, TestRequest::get().uri("/v1/items/7").to_request()).await;
+            assert_eq!(res.headers().get("x-frame-options").unwrap(), "DENY");
+            assert_eq!(actix_web::test::read_body(res).await, "/items/7 rewritten");
+
+            let err = try_call_service(&app, TestRequest::get().uri("/internal/x").to_request())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.as_response_error().status_code(), 404);
+            let err = try_call_service(&app, TestRequest::get().uri("/items/1").insert_header(("X-Block", "1")).to_request())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.as_response_error().status_code(), 403);
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[actix_web::test]
+        async fn test_limits_fail_open() {
+            let dir = script_dir(&[
+                ("spin.rhai", "fn on_request(req) { loop { } }"),
+                ("slow.rhai", "fn on_request(req) { let x = 0; while true { x += 1; } }"),
+            ]);
+            let generous = ScriptLimits {
+                max_operations: u64::MAX,
+                timeout: Duration::from_millis(20),
+            };
+            for limits in [limits(), generous] {
+                let hooks = web::Data::new(ScriptHooks::new(dir.clone(), limits).unwrap());
+                let app = init_service(
+                    App::new()
+                        .app_data(hooks)
+                        .wrap(from_fn(run_scripts))
+                        .route("/items/{id}", web::get().to(echo)),
+                )
+                .await;
+                let res = call_service(&app, TestRequest::get().uri("/items/1").to_request()).await;
+                assert!(res.status().is_success());
+            }
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_hot_reload_keeps_last_good_version() {
+            let dir = script_dir(&[("a.rhai", "fn on_request(req) { }")]);
+            let hooks = ScriptHooks::new(dir.clone(), limits()).unwrap();
+            assert!(!hooks.reload_if_changed().unwrap());
+
+            std::fs::write(dir.join("b.rhai"), "fn on_response(res) { }").unwrap();
+            assert!(hooks.reload_if_changed().unwrap());
+            assert_eq!(hooks.names(), vec!["a.rhai", "b.rhai"]);
+
+            // A syntax error keeps the previous version of the script
+            std::fs::write(dir.join("a.rhai"), "fn on_request(req) { ").unwrap();
+            assert!(hooks.reload_if_changed().unwrap());
+            assert_eq!(hooks.names(), vec!["a.rhai", "b.rhai"]);
+
+            std::fs::remove_file(dir.join("b.rhai")).unwrap();
+            assert!(hooks.reload_if_changed().unwrap());
+            assert_eq!(hooks.names(), vec!["a.rhai"]);
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use rand::RngCore;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::hardening::is_default_secret;
+
+/// Minimum accepted secret length in characters
+pub const MIN_SECRET_LENGTH: usize = 16;
+
+/// Minimum accepted estimated entropy in bits
+pub const MIN_SECRET_ENTROPY_BITS: f64 = 128.0;
+
+/// Why a secret was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum Weakness {
+    /// Set but empty (or whitespace only)
+    Empty,
+    /// Matches a well-known default such as `changeme`
+    KnownDefault,
+    /// Shorter than [`MIN_SECRET_LENGTH`]
+    TooShort(usize),
+    /// Estimated entropy below [`MIN_SECRET_ENTROPY_BITS`]
+    LowEntropy(f64),
+}
+
+impl Weakness {
+    fn describe(&self) -> String {
+        match self {
+            Weakness::Empty => "is empty".to_string(),
+            Weakness::KnownDefault => "is a well-known default value".to_string(),
+            Weakness::TooShort(len) => format!(
+                "is only {} characters long (minimum {})",
+                len, MIN_SECRET_LENGTH
+            ),
+            Weakness::LowEntropy(bits) => format!(
+                "has too little entropy (~{:.0} bits, minimum {:.0})",
+                bits, MIN_SECRET_ENTROPY_BITS
+            ),
+        }
+    }
+}
+
+/// Estimates the entropy of `value` in bits
+///
+/// Uses the Shannon entropy of the character distribution multiplied by the
+/// length, which penalizes repetition (`aaaa...`) as well as short values.
+pub fn estimate_entropy_bits(value: &str) -> f64 {
+    let len = value.chars().count();
+    if len == 0 {
+        return 0.0;
+    }
+
+    let mut frequencies: HashMap<char, usize> = HashMap::new();
+    for c in value.chars() {
+        *frequencies.entry(c).or_default() += 1;
+    }
+
+    let per_char: f64 = frequencies
+        .values()
+        .map(|&count| {
+            let p = count as f64 / len as f64;
+            -p * p.log2()
+        })
+        .sum();
+    per_char * len as f64
+}
+
+/// Checks a single secret value, returning its first weakness
+pub fn assess(value: &str) -> Option<Weakness> {
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        return Some(Weakness::Empty);
+    }
+    if is_default_secret(trimmed) {
+        return Some(Weakness::KnownDefault);
+    }
+    let len = trimmed.chars().count();
+    if len < MIN_SECRET_LENGTH {
+        return Some(Weakness::TooShort(len));
+    }
+    let bits = estimate_entropy_bits(trimmed);
+    if bits < MIN_SECRET_ENTROPY_BITS {
+        return Some(Weakness::LowEntropy(bits));
+    }
+    None
+}
+
+/// Rejects configurations containing weak secrets
+///
+/// Every configured secret is checked; the error lists all offenders with
+/// the fix so operators don't have to restart once per secret.
+///
+/// # Errors
+/// Returns a configuration error naming each weak secret
+pub fn validate(config: &Config) -> AppResult<()> {
+    let problems: Vec<String> = config
+        .secrets()
+        .into_iter()
+        .filter_map(|(name, value)| assess(value).map(|weakness| format!("{} {}", name, weakness.describe())))
+        .collect();
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::config(format!(
+        "weak secrets detected: {}. Run `simple-api-demo --rotate-secrets` to generate strong replacements",
+        problems.join("; ")
+    )))
+}
+
+/// Generates a random secret of 32 bytes, hex-encoded (256 bits)
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Generates a fresh value for every secret variable, in `.env` format
+pub fn rotated_env() -> String {
+    Config::secret_vars()
+        .map(|name| format!("{}={}\n", name, generate_secret()))
+        .collect()
+}
+
+/// Writes freshly generated secrets to `path` readable by the owner only
+///
+/// Refuses to overwrite an existing file so a previous rotation is never
+/// lost by accident.
+///
+/// # Errors
+/// Returns a configuration error when the file exists or cannot be written
+pub fn write_rotated_env(path: &Path) -> AppResult<()> {
+    write_private_file(path, &rotated_env())
+}
+
+/// Creates `path` readable by the owner only and writes `contents` to it
+///
+/// # Errors
+/// Returns a configuration error when the file exists or cannot be written
+pub fn write_private_file(path: &Path, contents: &str) -> AppResult<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options
+        .open(path)
+        .map_err(|e| AppError::config(format!("Cannot create {}: {}", path.display(), e)))?;
+    file.write_all(contents.as_bytes())
+        .map_err(|e| AppError::config(format!("Cannot write {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assess_rejects_weak_values() {
+        assert_eq!(assess("  "), Some(Weakness::Empty));
+        assert_eq!(assess("changeme"), Some(Weakness::KnownDefault));
+        assert_eq!(assess("short"), Some(Weakness::TooShort(5)));
+        assert!(matches!(
+            assess("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"),
+            Some(Weakness::LowEntropy(_))
+        ));
+    }
+
+    #[test]
+    fn test_generated_secrets_are_strong_and_unique() {
+        let a = generate_secret();
+        let b = generate_secret();
+        assert_eq!(a.len(), 64);
+        assert_ne!(a, b);
+        assert_eq!(assess(&a), None);
+    }
+
+    #[test]
+    fn test_validate_lists_every_weak_secret() {
+        let config = Config {
+            webhook_github_secret: Some("changeme".to_string()),
+            webhook_stripe_secret: Some("".to_string()),
+            ..Config::default()
+        };
+        let message = validate(&config).unwrap_err().to_string();
+        assert!(message.contains("WEBHOOK_GITHUB_SECRET is a well-known default value"));
+        assert!(message.contains("WEBHOOK_STRIPE_SECRET is empty"));
+        assert!(message.contains("--rotate-secrets"));
+
+        let config = Config {
+            webhook_github_secret: Some(generate_secret()),
+            ..Config::default()
+        };
+        assert!(validate(&config).is_ok());
+    }
+
+    #[test]
+    fn test_rotated_env_covers_every_secret_var() {
+        let env = rotated_env();
+        for name in Config::secret_vars() {
+            assert!(env.contains(&format!("{}=", name)));
+        }
+    }
+
+    #[test]
+    fn test_write_rotated_env_refuses_to_overwrite() {
+        let path = std::env::temp_dir().join(format!("rotated-{}.env", uuid::Uuid::new_v4()));
+        write_rotated_env(&path).unwrap();
+        assert!(write_rotated_env(&path).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
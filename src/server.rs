@@ -1,19 +1,628 @@
 use actix_web::{
-    middleware::Logger,
-    web, App, HttpServer,
+    dev::{Server, ServerHandle},
+    middleware::{from_fn, Logger},
+    web, App, HttpMessage, HttpServer,
 };
-use actix_cors::Cors;
+use futures::future::{FutureExt, LocalBoxFuture};
 use log::info;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::config::Config;
-use crate::handlers::{app_server, main_server};
+use crate::analytics::{track_usage, AnalyticsPipeline, UsageAggregator};
+use crate::anonymization::{AnonymizationJob, ANONYMIZATION_INTERVAL};
+use crate::assets::AssetStore;
+use crate::audit::{self, audit_requests, AuditSink};
+use crate::auth::challenge::ChallengeProvider;
+use crate::auth::impersonation::mark_impersonated;
+use crate::auth::{
+    ApiKeyService, BasicAuthenticator, ChallengeGate, ClientRegistry, CookieSessionManager, GuestTokenIssuer, ImpersonationService, InMemoryKeyStore, KeyStore, LoginLockout,
+    OidcClient, QuotaService, RefreshTokenService, SessionRegistry, SignatureVerifier, SigningKeyRing, TokenDenylist, TokenService, TwoFactorService,
+};
+use crate::backup::{BackupService, Snapshot};
+use crate::change_guard::ChangeGuard;
+use crate::client_info::{self, ClientResolver};
+use crate::clock::ClockCheck;
+use crate::config::{Config, ConfigLayers};
+use crate::consent::{require_consent, ConsentService};
+use crate::context::{attach_context, RequestContext};
+use crate::cookies::CookieSigner;
+use crate::cors::CorsRouter;
+use crate::daemon::ShutdownTrigger;
+use crate::degradation::{serve_stale, DegradationPolicy};
+use crate::deliveries::DeliveryLog;
+use crate::egress::EgressLimiter;
+use crate::dumps::{capture_error_dumps, DumpSpool};
+use crate::files::FileStore;
+use crate::error_circuit::{error_circuit, ErrorCircuit};
+use crate::error::AppResult;
+use crate::event_bus::{topics, EventBus};
+use crate::events::CloudEvent;
+use crate::hardening;
+use crate::integrity::IntegrityChecker;
+use crate::ip_filter::{ip_filter, IpFilter};
+use crate::lb_weight::{track_load, LoadMonitor};
+use crate::metrics_export::{MetricsExporter, MetricsLog};
+use crate::slow_queries::SlowQueryLog;
+use crate::secrets;
+use crate::jobs::{Job, JobHandlers, JobQueue};
+use crate::notifications::{Notification, NotificationRouter};
+use crate::openapi::{self, OpenApiDocument};
+use crate::plugins::{MiddlewarePlugin, PluginRegistry, PluginStack};
+use crate::privacy::{PrivacyService, PURGE_INTERVAL};
+use crate::policy::Authorizer;
+use crate::rbac::RbacPolicy;
+use crate::read_only::{reject_writes, ReadOnlyMode};
+use crate::region::Placement;
+use crate::reload::{ConfigWatcher, LiveConfig};
+use crate::remote_config::RemoteConfig;
+use crate::log_context::LogContext;
+use crate::listeners::{ListenerRuntime, ListenerSpec, MiddlewareProfile, RouteProfile, ServerLimits};
+use crate::routes::{RouteRegistry, RouteSpec};
+use crate::scanning::{ContentScanner, ScannerChain};
+use crate::scripting::{run_scripts, ScriptHooks};
+use crate::state::{KeyValueStore, StateManager};
+use crate::statsd::StatsdExporter;
+use crate::subscriptions::SubscriptionRegistry;
+use crate::supervisor::Supervisor;
+use crate::tls;
+use crate::users::UserService;
+use crate::vault::VaultProvider;
+use crate::simulation::{self, simulate_responses};
+use crate::version_skew::version_handshake;
+use crate::warmup::{CacheWarmer, WarmupStatus};
+use crate::webhooks::WebhookVerifier;
 
 /// Server manager responsible for creating and starting HTTP servers
 /// 
-/// Manages the lifecycle of both main and application servers,
-/// including configuration, routing, and graceful startup.
+/// Manages the lifecycle of the main and application servers and of the
+/// extra listeners declared in `LISTENERS`, including configuration,
+/// routing, and graceful startup.
 pub struct ServerManager {
     config: Config,
+    plugins: PluginRegistry,
+    routes: RouteRegistry,
+    key_store: Option<Arc<dyn KeyStore>>,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    vault: Option<Arc<VaultProvider>>,
+    challenge_provider: Option<Arc<dyn ChallengeProvider>>,
+    content_scanner: Option<Arc<dyn ContentScanner>>,
+    restore: Option<Snapshot>,
+    config_layers: Option<ConfigLayers>,
+    remote_config: Option<(Arc<RemoteConfig>, ConfigLayers)>,
+    shutdown: Option<ShutdownTrigger>,
+}
+
+/// Builder for a [`ServerManager`] with embedder-provided middleware plugins,
+/// routes, key store and audit sink, and the Vault secrets to keep alive
+pub struct ServerManagerBuilder {
+    config: Config,
+    plugins: PluginRegistry,
+    routes: RouteRegistry,
+    key_store: Option<Arc<dyn KeyStore>>,
+    audit_sink: Option<Arc<dyn AuditSink>>,
+    vault: Option<Arc<VaultProvider>>,
+    challenge_provider: Option<Arc<dyn ChallengeProvider>>,
+    content_scanner: Option<Arc<dyn ContentScanner>>,
+    restore: Option<Snapshot>,
+    config_layers: Option<ConfigLayers>,
+    remote_config: Option<(Arc<RemoteConfig>, ConfigLayers)>,
+    shutdown: Option<ShutdownTrigger>,
+}
+
+impl ServerManagerBuilder {
+    /// Registers a middleware plugin on the application server
+    ///
+    /// Plugins run inside the built-in middleware, right before routing, in
+    /// registration order.
+    pub fn plugin(mut self, plugin: impl MiddlewarePlugin + 'static) -> Self {
+        self.plugins.register(Arc::new(plugin));
+        self
+    }
+
+    /// Adds a route to the application server
+    ///
+    /// Mark it with [`RouteSpec::require_api_key`] to only admit callers
+    /// presenting a key from the key store, with
+    /// [`RouteSpec::require_basic_auth`] to ask for Basic credentials, or with
+    /// [`RouteSpec::require_signature`] to only accept HMAC-signed requests.
+    pub fn route(mut self, spec: RouteSpec) -> Self {
+        self.routes = self.routes.route(spec);
+        self
+    }
+
+    /// Replaces the store checked on key-protected routes
+    ///
+    /// Defaults to an [`InMemoryKeyStore`] holding `STATIC_API_KEYS`.
+    pub fn key_store(mut self, store: Arc<dyn KeyStore>) -> Self {
+        self.key_store = Some(store);
+        self
+    }
+
+    /// Records every application server request in `sink`
+    ///
+    /// Replaces the stdout or file sink selected by `AUDIT_LOG`.
+    pub fn audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit_sink = Some(sink);
+        self
+    }
+
+    /// Verifies the challenges demanded from addresses with repeated failed
+    /// logins
+    ///
+    /// Replaces the hCaptcha/Turnstile provider selected by
+    /// `CHALLENGE_PROVIDER`, and enables challenges without it; the other
+    /// `CHALLENGE_*` settings still decide when one is required.
+    pub fn challenge_provider(mut self, provider: Arc<dyn ChallengeProvider>) -> Self {
+        self.challenge_provider = Some(provider);
+        self
+    }
+
+    /// Inspects uploads before upload handlers persist them
+    ///
+    /// Replaces the MIME type and ClamAV checks configured through
+    /// `UPLOAD_ALLOWED_TYPES` and `CLAMAV_ADDRESS`.
+    pub fn content_scanner(mut self, scanner: Arc<dyn ContentScanner>) -> Self {
+        self.content_scanner = Some(scanner);
+        self
+    }
+
+    /// Renews the Vault leases of the secrets the configuration was read from
+    /// while the servers run
+    pub fn vault(mut self, provider: Arc<VaultProvider>) -> Self {
+        self.vault = Some(provider);
+        self
+    }
+
+    /// Loads `snapshot` into the in-memory state store before anything uses it
+    ///
+    /// Starting fails when the state store is not in memory.
+    pub fn restore(mut self, snapshot: Snapshot) -> Self {
+        self.restore = Some(snapshot);
+        self
+    }
+
+    /// Applies the reloadable settings of the configuration file of
+    /// `layers` whenever it changes, resolving the layers again like at
+    /// startup
+    pub fn watch_config(mut self, layers: ConfigLayers) -> Self {
+        self.config_layers = Some(layers);
+        self
+    }
+
+    /// Applies the reloadable settings of `layers` whenever the keys of
+    /// `remote`, one of its layers, change
+    pub fn watch_remote_config(mut self, remote: Arc<RemoteConfig>, layers: ConfigLayers) -> Self {
+        self.remote_config = Some((remote, layers));
+        self
+    }
+
+    /// Stops the listeners gracefully once `trigger` fires, like `SIGTERM`
+    ///
+    /// Lets a caller outside the async runtime, such as the Windows service
+    /// control handler, drain in-flight requests before the process exits.
+    pub fn stop_on(mut self, trigger: ShutdownTrigger) -> Self {
+        self.shutdown = Some(trigger);
+        self
+    }
+
+    /// Finishes the server manager
+    pub fn build(self) -> ServerManager {
+        ServerManager {
+            config: self.config,
+            plugins: self.plugins,
+            routes: self.routes,
+            key_store: self.key_store,
+            audit_sink: self.audit_sink,
+            vault: self.vault,
+            challenge_provider: self.challenge_provider,
+            content_scanner: self.content_scanner,
+            restore: self.restore,
+            config_layers: self.config_layers,
+            remote_config: self.remote_config,
+            shutdown: self.shutdown,
+        }
+    }
+}
+
+/// Address reaching a listener bound to `addr` from this host
+fn loopback(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::from((Ipv4Addr::LOCALHOST, addr.port())),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::from((Ipv6Addr::LOCALHOST, addr.port())),
+        _ => addr,
+    }
+}
+
+/// A bound listener; `done` resolves when its server stops
+struct RunningListener {
+    name: String,
+    addrs: Vec<SocketAddr>,
+    handle: ServerHandle,
+    done: LocalBoxFuture<'static, std::io::Result<()>>,
+}
+
+/// Shared components handed to every application server worker
+/// 
+/// Built once in [`ServerManager::start`] and registered as app data so all
+/// workers share the same queue, stores and verifiers.
+#[derive(Clone)]
+struct AppComponents {
+    jobs: web::Data<JobQueue>,
+    webhooks: web::Data<WebhookVerifier>,
+    notifications: web::Data<NotificationRouter>,
+    subscriptions: web::Data<SubscriptionRegistry>,
+    tokens: web::Data<TokenService>,
+    signing_keys: Option<web::Data<SigningKeyRing>>,
+    introspection_clients: web::Data<ClientRegistry>,
+    guests: web::Data<GuestTokenIssuer>,
+    challenge: web::Data<ChallengeGate>,
+    lockout: web::Data<LoginLockout>,
+    client_resolver: web::Data<ClientResolver>,
+    ip_filter: web::Data<IpFilter>,
+    oidc: Option<web::Data<OidcClient>>,
+    users: web::Data<UserService>,
+    two_factor: web::Data<TwoFactorService>,
+    impersonation: web::Data<ImpersonationService>,
+    denylist: web::Data<TokenDenylist>,
+    sessions: web::Data<SessionRegistry>,
+    refresh_tokens: web::Data<RefreshTokenService>,
+    cookie_sessions: web::Data<CookieSessionManager>,
+    cookies: web::Data<CookieSigner>,
+    api_keys: web::Data<ApiKeyService>,
+    consent: web::Data<ConsentService>,
+    privacy: web::Data<PrivacyService>,
+    anonymization: web::Data<AnonymizationJob>,
+    integrity: web::Data<IntegrityChecker>,
+    usage: web::Data<UsageAggregator>,
+    analytics: web::Data<AnalyticsPipeline>,
+    metrics_export: web::Data<MetricsExporter>,
+    assets: web::Data<AssetStore>,
+    scripts: Option<web::Data<ScriptHooks>>,
+    audit: Option<web::Data<dyn AuditSink>>,
+    dumps: Option<web::Data<DumpSpool>>,
+    slow_queries: Option<web::Data<SlowQueryLog>>,
+    backups: Option<web::Data<BackupService>>,
+    files: Option<web::Data<FileStore>>,
+    error_circuit: Option<web::Data<ErrorCircuit>>,
+    degradation: Option<web::Data<DegradationPolicy>>,
+    egress: Option<web::Data<EgressLimiter>>,
+    key_store: web::Data<dyn KeyStore>,
+    content_scanner: web::Data<dyn ContentScanner>,
+    quotas: web::Data<QuotaService>,
+    basic_auth: web::Data<BasicAuthenticator>,
+    signatures: web::Data<SignatureVerifier>,
+    rbac: web::Data<RbacPolicy>,
+    authorizer: web::Data<Authorizer>,
+    change_guard: web::Data<ChangeGuard>,
+    read_only: web::Data<ReadOnlyMode>,
+    load: web::Data<LoadMonitor>,
+    placement: web::Data<Placement>,
+    clock: Option<web::Data<ClockCheck>>,
+    config: web::Data<Config>,
+    live_config: web::Data<LiveConfig>,
+    readiness: web::Data<dyn KeyValueStore>,
+    warmup: web::Data<WarmupStatus>,
+    openapi: web::Data<OpenApiDocument>,
+    supervisor: web::Data<Supervisor>,
+    events: web::Data<EventBus>,
+}
+
+impl AppComponents {
+    /// Builds the shared components from configuration
+    ///
+    /// The OpenAPI document describes `routes`; `rbac` resolves the roles
+    /// checked by role-guarded routes. `challenge_provider` replaces the
+    /// provider selected by `CHALLENGE_PROVIDER`.
+    fn build(
+        config: &Config,
+        state: &StateManager,
+        routes: &RouteRegistry,
+        rbac: RbacPolicy,
+        challenge_provider: Option<Arc<dyn ChallengeProvider>>,
+    ) -> AppResult<Self> {
+        let supervisor = Arc::new(Supervisor::from_config(config));
+        let live_config = Arc::new(LiveConfig::new(config.clone()));
+        let mut challenge = ChallengeGate::from_config(config, state.store("challenge_failures"))?;
+        if let Some(provider) = challenge_provider {
+            challenge = challenge.with_provider(provider);
+        }
+        let mut notifications = NotificationRouter::from_config(config, state.store("notification_rate_limit"))?
+            .with_live_config(live_config.clone());
+        if let Some(log) = DeliveryLog::from_config(config, state.store("webhook_deliveries")) {
+            notifications = notifications.with_deliveries(log);
+        }
+        let subscriptions = Arc::new(SubscriptionRegistry::from_config(config, state.store("webhook_subscriptions"))?);
+        notifications = notifications.with_subscriptions(subscriptions.clone());
+        let notifications = web::Data::new(notifications);
+        let egress = notifications
+            .egress()
+            .enabled()
+            .then(|| web::Data::from(notifications.egress().clone()));
+
+        // Processed webhooks are announced on the bus; a supervised consumer notifies about them
+        let events = Arc::new(EventBus::from_config(config));
+        let publisher = events.clone();
+        let handlers = JobHandlers::new().register("webhook.", move |job: &Job| {
+            let event = CloudEvent::new(format!("com.simple-api-demo.{}", job.kind), job.payload.clone())
+                .with_subject(job.id.clone());
+            info!("Processed webhook job: {}", event.to_json()?);
+            publisher.try_publish(&topics::WEBHOOK_PROCESSED, job.clone());
+            Ok(())
+        });
+        let processed = Arc::new(tokio::sync::Mutex::new(events.subscribe(&topics::WEBHOOK_PROCESSED)));
+        let router = notifications.clone();
+        NotificationRouter::spawn_batch_flusher(notifications.clone().into_inner(), &supervisor);
+        supervisor.spawn("webhook_notifier", move || {
+            let processed = processed.clone();
+            let router = router.clone();
+            async move {
+                let mut processed = processed.lock().await;
+                while let Some(job) = processed.recv().await {
+                    let notification = Notification::new(
+                        job.kind.clone(),
+                        format!("Webhook received: {}", job.kind),
+                        format!("Job {} processed", job.id),
+                    )
+                    .with_data(job.payload.clone());
+                    router.notify(&notification).await;
+                }
+                Ok(())
+            }
+        });
+
+        let signing_keys = SigningKeyRing::from_config(config, state.store("jwt_signing_keys"))?.map(Arc::new);
+        let mut tokens = TokenService::from_config(config);
+        if let Some(keys) = &signing_keys {
+            tokens = tokens.with_signing_keys(keys.clone());
+            SigningKeyRing::spawn_rotation(keys.clone(), &supervisor);
+        }
+        let clock = ClockCheck::from_config(config).map(Arc::new);
+        if let Some(clock) = &clock {
+            ClockCheck::spawn_checks(clock.clone(), &supervisor);
+        }
+        let users = UserService::from_config(config, &tokens, state)?;
+        let two_factor = TwoFactorService::from_config(config, users.repository().clone());
+        let denylist = TokenDenylist::new(state.store("token_denylist"));
+        let sessions = web::Data::new(SessionRegistry::from_config(config, state.store("sessions"), denylist.clone()));
+        let refresh_tokens = RefreshTokenService::from_config(config, &tokens, denylist.clone());
+        let api_keys = web::Data::new(ApiKeyService::new(state.store("api_keys")));
+        let consent = ConsentService::from_config(config, state.store("terms"), users.repository().clone());
+        let privacy = web::Data::new(PrivacyService::from_config(
+            config,
+            state.store("account_erasure"),
+            users.repository().clone(),
+            sessions.clone().into_inner(),
+            api_keys.clone().into_inner(),
+        ));
+        PrivacyService::spawn_purger(privacy.clone().into_inner(), PURGE_INTERVAL, &supervisor);
+        let anonymization = web::Data::new(AnonymizationJob::from_config(
+            config,
+            users.repository().clone(),
+            sessions.clone().into_inner(),
+        ));
+        AnonymizationJob::spawn_scheduler(anonymization.clone().into_inner(), ANONYMIZATION_INTERVAL, &supervisor);
+        let integrity = web::Data::new(IntegrityChecker::from_config(
+            config,
+            users.repository().clone(),
+            sessions.clone().into_inner(),
+            api_keys.clone().into_inner(),
+        ));
+        if config.integrity_check_interval_secs > 0 {
+            IntegrityChecker::spawn_scheduler(
+                integrity.clone().into_inner(),
+                Duration::from_secs(config.integrity_check_interval_secs),
+                &supervisor,
+            );
+        }
+        let usage = web::Data::new(UsageAggregator::new(state.store("analytics")));
+        let analytics = web::Data::new(
+            AnalyticsPipeline::new(Some(users.repository().clone())).with_sink(usage.clone().into_inner()),
+        );
+        let scripts = ScriptHooks::from_config(config)?.map(web::Data::new);
+        let backups = BackupService::from_config(config, state)?.map(web::Data::new);
+        if let Some(backups) = backups.as_ref().filter(|_| config.backup_interval_secs > 0) {
+            BackupService::spawn_scheduler(
+                backups.clone().into_inner(),
+                Duration::from_secs(config.backup_interval_secs),
+                &supervisor,
+            );
+        }
+        let files = FileStore::from_config(config, state.store("files"))?.map(web::Data::new);
+        if let Some(files) = &files {
+            FileStore::spawn_scheduler(
+                files.clone().into_inner(),
+                Duration::from_secs(config.files_gc_interval_secs),
+                &supervisor,
+            );
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(scripts) = &scripts {
+            ScriptHooks::spawn_watcher(
+                scripts.clone().into_inner(),
+                crate::scripting::SCRIPT_RELOAD_INTERVAL,
+                &supervisor,
+            );
+        }
+        let degradation = DegradationPolicy::from_config(config, routes)?.map(web::Data::new);
+        let placement = web::Data::new(Placement::from_config(config));
+        let metrics_export = web::Data::new(
+            MetricsExporter::new(analytics.clone().into_inner())
+                .with_supervisor(supervisor.clone())
+                .with_events(events.clone())
+                .with_degradation(degradation.clone().map(web::Data::into_inner))
+                .with_egress(egress.clone().map(web::Data::into_inner))
+                .with_placement(placement.clone().into_inner())
+                .with_clock(clock.clone()),
+        );
+        if let Some(log) = MetricsLog::from_config(config, metrics_export.clone().into_inner()) {
+            MetricsLog::spawn_scheduler(
+                Arc::new(log),
+                Duration::from_secs(config.metrics_export_interval_secs),
+                &supervisor,
+            );
+        }
+        let load = web::Data::new(LoadMonitor::from_config(config));
+        if let Some(address) = &config.lb_agent_address {
+            LoadMonitor::spawn_agent(load.clone().into_inner(), address.clone(), &supervisor);
+        }
+        if let Some(statsd) = StatsdExporter::from_config(config, metrics_export.clone().into_inner()) {
+            StatsdExporter::spawn_scheduler(
+                Arc::new(statsd),
+                Duration::from_secs(config.statsd_interval_secs),
+                &supervisor,
+            );
+        }
+
+        Ok(Self {
+            jobs: web::Data::new(JobQueue::start(config.job_queue_capacity, handlers, &supervisor)),
+            webhooks: web::Data::new(WebhookVerifier::from_config(config)),
+            notifications,
+            subscriptions: web::Data::from(subscriptions),
+            tokens: web::Data::new(tokens),
+            signing_keys: signing_keys.map(web::Data::from),
+            introspection_clients: web::Data::new(ClientRegistry::new(config.introspection_clients.clone())),
+            guests: web::Data::new(
+                GuestTokenIssuer::from_config(config, state.store("guest_tokens")).with_live_config(live_config.clone()),
+            ),
+            challenge: web::Data::new(challenge),
+            lockout: web::Data::new(LoginLockout::from_config(config, state.store("login_lockout"))),
+            client_resolver: web::Data::new(ClientResolver::from_config(config)),
+            ip_filter: web::Data::new(IpFilter::from_config(config)),
+            oidc: OidcClient::from_config(config, state.store("oidc_login"))?.map(web::Data::new),
+            users: web::Data::new(users),
+            two_factor: web::Data::new(two_factor),
+            impersonation: web::Data::new(ImpersonationService::from_config(config)),
+            refresh_tokens: web::Data::new(refresh_tokens),
+            denylist: web::Data::new(denylist),
+            sessions,
+            cookie_sessions: web::Data::new(CookieSessionManager::from_config(config)),
+            cookies: web::Data::new(CookieSigner::from_config(config)),
+            api_keys,
+            consent: web::Data::new(consent),
+            privacy,
+            anonymization,
+            integrity,
+            usage,
+            analytics,
+            metrics_export,
+            assets: web::Data::new(AssetStore::from_config(config)),
+            scripts,
+            audit: audit::sink_from_config(config)?.map(web::Data::from),
+            dumps: DumpSpool::from_config(config)?.map(web::Data::new),
+            slow_queries: state.query_log().map(web::Data::from),
+            backups,
+            files,
+            error_circuit: ErrorCircuit::from_config(config, routes)?.map(web::Data::new),
+            degradation,
+            egress,
+            key_store: web::Data::from(Arc::new(InMemoryKeyStore::from_config(config)) as Arc<dyn KeyStore>),
+            content_scanner: web::Data::from(Arc::new(ScannerChain::from_config(config)) as Arc<dyn ContentScanner>),
+            quotas: web::Data::new(QuotaService::from_config(config, state.store("api_key_quotas"))),
+            basic_auth: web::Data::new(BasicAuthenticator::from_config(config)?),
+            signatures: web::Data::new(SignatureVerifier::from_config(config)),
+            rbac: web::Data::new(rbac),
+            authorizer: web::Data::new(Authorizer::from_config(config)?),
+            change_guard: web::Data::new(ChangeGuard::from_config(config)),
+            read_only: web::Data::new(ReadOnlyMode::from_config(config)),
+            load,
+            placement,
+            clock: clock.map(web::Data::from),
+            config: web::Data::new(config.clone()),
+            live_config: web::Data::from(live_config),
+            readiness: web::Data::from(state.store("readiness")),
+            warmup: web::Data::new(WarmupStatus::from_config(config)),
+            openapi: web::Data::new(OpenApiDocument(openapi::document(routes))),
+            supervisor: web::Data::from(supervisor),
+            events: web::Data::from(events),
+        })
+    }
+
+    /// Registers the components as app data
+    fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(self.jobs.clone())
+            .app_data(self.webhooks.clone())
+            .app_data(self.notifications.clone())
+            .app_data(self.subscriptions.clone())
+            .app_data(self.tokens.clone())
+            .app_data(self.introspection_clients.clone())
+            .app_data(self.guests.clone())
+            .app_data(self.challenge.clone())
+            .app_data(self.lockout.clone())
+            .app_data(self.client_resolver.clone())
+            .app_data(self.ip_filter.clone())
+            .app_data(self.users.clone())
+            .app_data(self.two_factor.clone())
+            .app_data(self.impersonation.clone())
+            .app_data(self.denylist.clone())
+            .app_data(self.sessions.clone())
+            .app_data(self.refresh_tokens.clone())
+            .app_data(self.cookie_sessions.clone())
+            .app_data(self.cookies.clone())
+            .app_data(self.api_keys.clone())
+            .app_data(self.consent.clone())
+            .app_data(self.privacy.clone())
+            .app_data(self.anonymization.clone())
+            .app_data(self.integrity.clone())
+            .app_data(self.usage.clone())
+            .app_data(self.analytics.clone())
+            .app_data(self.metrics_export.clone())
+            .app_data(self.assets.clone())
+            .app_data(self.key_store.clone())
+            .app_data(self.content_scanner.clone())
+            .app_data(self.quotas.clone())
+            .app_data(self.basic_auth.clone())
+            .app_data(self.signatures.clone())
+            .app_data(self.rbac.clone())
+            .app_data(self.authorizer.clone())
+            .app_data(self.change_guard.clone())
+            .app_data(self.read_only.clone())
+            .app_data(self.load.clone())
+            .app_data(self.placement.clone())
+            .app_data(self.config.clone())
+            .app_data(self.live_config.clone())
+            .app_data(self.readiness.clone())
+            .app_data(self.warmup.clone())
+            .app_data(self.openapi.clone())
+            .app_data(self.supervisor.clone())
+            .app_data(self.events.clone());
+        if let Some(scripts) = &self.scripts {
+            cfg.app_data(scripts.clone());
+        }
+        if let Some(audit) = &self.audit {
+            cfg.app_data(audit.clone());
+        }
+        if let Some(oidc) = &self.oidc {
+            cfg.app_data(oidc.clone());
+        }
+        if let Some(keys) = &self.signing_keys {
+            cfg.app_data(keys.clone());
+        }
+        if let Some(dumps) = &self.dumps {
+            cfg.app_data(dumps.clone());
+        }
+        if let Some(slow_queries) = &self.slow_queries {
+            cfg.app_data(slow_queries.clone());
+        }
+        if let Some(backups) = &self.backups {
+            cfg.app_data(backups.clone());
+        }
+        if let Some(files) = &self.files {
+            cfg.app_data(files.clone());
+        }
+        if let Some(circuit) = &self.error_circuit {
+            cfg.app_data(circuit.clone());
+        }
+        if let Some(degradation) = &self.degradation {
+            cfg.app_data(degradation.clone());
+        }
+        if let Some(egress) = &self.egress {
+            cfg.app_data(egress.clone());
+        }
+        if let Some(clock) = &self.clock {
+            cfg.app_data(clock.clone());
+        }
+    }
 }
 
 impl ServerManager {
@@ -22,98 +631,451 @@ impl ServerManager {
     /// # Arguments
     /// * `config` - Application configuration containing server settings
     pub fn new(config: Config) -> Self {
-        Self { config }
+        Self::builder(config).build()
     }
 
-    /// Starts both HTTP servers concurrently
+    /// Starts building a ServerManager, to register middleware plugins
+    ///
+    /// # Arguments
+    /// * `config` - Application configuration containing server settings
+    pub fn builder(config: Config) -> ServerManagerBuilder {
+        ServerManagerBuilder {
+            config,
+            plugins: PluginRegistry::new(),
+            routes: RouteRegistry::app_server(),
+            key_store: None,
+            audit_sink: None,
+            vault: None,
+            challenge_provider: None,
+            content_scanner: None,
+            restore: None,
+            config_layers: None,
+            remote_config: None,
+            shutdown: None,
+        }
+    }
+
+    /// Starts every configured listener concurrently
     /// 
-    /// Creates and binds the main server and application server,
-    /// then starts them in parallel using tokio's join functionality.
+    /// Creates and binds the main server, the application server and the
+    /// listeners declared in `LISTENERS`, then runs them in parallel.
     /// 
     /// # Returns
     /// Result indicating success or failure of server startup
-    pub async fn start(self) -> std::io::Result<()> {
+    pub async fn start(mut self) -> std::io::Result<()> {
         info!("Starting servers with configuration: {:?}", self.config);
 
-        // Create and configure both servers
-        let main_server = self.create_main_server()?;
-        let app_server = self.create_app_server()?;
+        // Secret strength and production hardening checks run before anything binds
+        secrets::validate(&self.config)
+            .and_then(|_| hardening::enforce(&self.config))
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
 
-        info!("Main server starting on {}:{}", self.config.bind_address, self.config.main_port);
-        info!("Application server starting on {}:{}", self.config.bind_address, self.config.app_port);
+        // Shared state backend; components obtain their stores from here
+        let state = StateManager::from_config(&self.config)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        if self.config.stub_dependencies {
+            log::warn!(
+                "STUB_DEPENDENCIES is set: state and notifications use in-process fakes ({}ms latency, {:.0}% errors)",
+                self.config.stub_latency_ms,
+                self.config.stub_error_rate * 100.0
+            );
+        }
+        if let Some(snapshot) = &self.restore {
+            let store = state
+                .embedded_store()
+                .ok_or_else(|| std::io::Error::other("restoring a snapshot requires the in-memory state store (STATE_MODE=local)"))?;
+            let count = snapshot
+                .restore_into(&store)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            info!("Restored {} state keys from the snapshot taken at {}", count, snapshot.created_at);
+        }
+        if simulation::enabled(&self.config) {
+            log::info!("X-Simulate is honoured: clients can request simulated 429 and 503 responses");
+        }
 
-        // Start both servers concurrently
-        let result = futures::future::try_join(main_server, app_server).await;
+        // `BASIC_AUTH_ROUTES`, `SIGNED_ROUTES`, `ROUTE_SCOPES`, the RBAC policy and `POLICY_ROUTES` guard routes before they are documented and served;
+        // `CORS_ROUTES` attaches the named CORS policies
+        let rbac = RbacPolicy::from_config(&self.config).map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.routes = std::mem::take(&mut self.routes)
+            .require_basic_auth_on(&self.config.basic_auth_routes)
+            .and_then(|routes| routes.require_signature_on(&self.config.signed_routes))
+            .and_then(|routes| routes.require_scopes_on(&self.config.route_scopes))
+            .and_then(|routes| routes.require_roles_on(&rbac.routes))
+            .and_then(|routes| routes.require_policy_on(&self.config.policy_routes))
+            .and_then(|routes| routes.cors_policy_on(&self.config.cors_routes))
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
 
-        match result {
-            Ok(_) => {
-                info!("Both servers shutdown gracefully");
-                Ok(())
+        // Components are shared by every listener
+        let mut components = AppComponents::build(&self.config, &state, &self.routes, rbac, self.challenge_provider.clone())
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        components.placement.install_log_fields();
+        crate::error::install_error_detail(self.config.error_detail);
+        if components.basic_auth.is_empty() && self.routes.routes().iter().any(|spec| spec.basic_auth) {
+            log::warn!(
+                "Routes require Basic auth but neither BASIC_AUTH_USERS nor BASIC_AUTH_FILE is set; they reject every request"
+            );
+        }
+        if components.signatures.is_empty() && self.routes.routes().iter().any(|spec| spec.signed) {
+            log::warn!("Routes require request signatures but SIGNED_REQUEST_CLIENTS is empty; they reject every request");
+        }
+        if let Some(store) = &self.key_store {
+            components.key_store = web::Data::from(store.clone());
+        }
+        if let Some(sink) = &self.audit_sink {
+            components.audit = Some(web::Data::from(sink.clone()));
+        }
+        if let Some(scanner) = &self.content_scanner {
+            components.content_scanner = web::Data::from(scanner.clone());
+        }
+        // Kept until the servers stop
+        let _watcher = match &self.config_layers {
+            Some(layers) => Some(
+                ConfigWatcher::spawn(layers.clone(), components.live_config.clone().into_inner())
+                    .map_err(|e| std::io::Error::other(e.to_string()))?,
+            ),
+            None => None,
+        };
+        if let Some((remote, layers)) = &self.remote_config {
+            RemoteConfig::spawn_watcher(
+                remote.clone(),
+                layers.clone(),
+                components.live_config.clone().into_inner(),
+                &components.supervisor.clone().into_inner(),
+            );
+        }
+        if let Some(vault) = &self.vault {
+            VaultProvider::spawn_renewal(vault.clone(), &components.supervisor.clone().into_inner());
+        }
+        let plugins = self
+            .plugins
+            .build(std::env::vars())
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        let warm_paths = match self.config.cache_warm_enabled {
+            true => Some(
+                CacheWarmer::manifest(&self.config, &self.routes).map_err(|e| std::io::Error::other(e.to_string()))?,
+            ),
+            false => None,
+        };
+
+        let mut servers = Vec::new();
+        let mut warm_target = None;
+        for listener in self.config.listeners() {
+            match self.create_server(&listener, components.clone(), plugins.clone()) {
+                Ok(running) => {
+                    info!("Listener {} starting on {:?}", listener, running.addrs);
+                    if warm_target.is_none() && listener.routes == RouteProfile::App && listener.tls.is_none() {
+                        warm_target = running.addrs.first().copied().map(loopback);
+                    }
+                    servers.push(running);
+                }
+                Err(e) => {
+                    // Listeners started so far must not keep serving on their own
+                    let error = std::io::Error::new(e.kind(), format!("listener {} failed to start: {}", listener.name, e));
+                    log::error!("{}", error);
+                    let _ = Self::supervise(servers, true).await;
+                    return Err(error);
+                }
+            }
+        }
+        state.warn_if_local_with_replicas(self.config.replica_count);
+        if let Some(paths) = warm_paths {
+            Self::spawn_warmup(paths, warm_target, &components);
+        }
+        if let Some(trigger) = &self.shutdown {
+            Self::stop_when(trigger.clone(), &servers);
+        }
+
+        let result = Self::supervise(servers, false).await;
+
+        // Subscribers get to handle the events published before the listeners stopped
+        components.events.shutdown();
+        let pending = components
+            .events
+            .drain(Duration::from_secs(self.config.event_bus_drain_timeout_secs))
+            .await;
+        if pending > 0 {
+            log::warn!("{} events were still queued for subscribers at shutdown", pending);
+        }
+        // Batched webhook deliveries are not kept across restarts
+        let flushed = components.notifications.flush_batches(true).await;
+        if flushed > 0 {
+            info!("Sent {} pending webhook batches at shutdown", flushed);
+        }
+        result
+    }
+
+    /// Warms the caches through the plain HTTP app listener at `target`
+    ///
+    /// Without one the warm-up is skipped so readiness is not held back forever.
+    fn spawn_warmup(paths: Vec<String>, target: Option<SocketAddr>, components: &AppComponents) {
+        let status = components.warmup.clone().into_inner();
+        let Some(target) = target else {
+            log::warn!("Cache warm-up skipped: no plain HTTP listener serves the app routes");
+            status.mark_complete();
+            return;
+        };
+        let mut warmer = CacheWarmer::new(paths, status);
+        if let Some(oidc) = &components.oidc {
+            warmer = warmer.with_oidc(oidc.clone().into_inner());
+        }
+        actix_web::rt::spawn(async move { warmer.run(target).await });
+    }
+
+    /// Stops every listener gracefully once `trigger` fires
+    fn stop_when(trigger: ShutdownTrigger, servers: &[RunningListener]) {
+        let handles: Vec<ServerHandle> = servers.iter().map(|server| server.handle.clone()).collect();
+        actix_web::rt::spawn(async move {
+            trigger.triggered().await;
+            info!("Stop requested, stopping the listeners gracefully");
+            for handle in handles {
+                handle.stop(true).await;
             }
-            Err(e) => {
-                log::error!("Server error: {}", e);
-                Err(e)
+        });
+    }
+
+    /// Runs the listeners until every one of them has stopped
+    /// 
+    /// As soon as one listener stops, whether it failed or shut down, the
+    /// others are stopped gracefully: they finish the requests in flight
+    /// instead of serving on alone or dropping accepted connections. Pass
+    /// `stop` to stop all of them right away.
+    /// 
+    /// # Errors
+    /// One error naming every listener that failed and why
+    async fn supervise(servers: Vec<RunningListener>, stop: bool) -> std::io::Result<()> {
+        let handles: Vec<(String, ServerHandle)> = servers
+            .iter()
+            .map(|server| (server.name.clone(), server.handle.clone()))
+            .collect();
+        let stop_all = |except: Option<&str>| {
+            for (name, handle) in &handles {
+                if Some(name.as_str()) != except {
+                    // The command is sent right away; completion is observed through `done`
+                    drop(handle.stop(true));
+                }
             }
+        };
+        if stop {
+            stop_all(None);
+        }
+
+        let mut pending: Vec<LocalBoxFuture<'static, (String, std::io::Result<()>)>> = servers
+            .into_iter()
+            .map(|server| {
+                let name = server.name;
+                Box::pin(server.done.map(move |result| (name, result))) as LocalBoxFuture<'static, _>
+            })
+            .collect();
+        let mut stopping = stop;
+        let mut failures = Vec::new();
+        while !pending.is_empty() {
+            let ((name, result), _, rest) = futures::future::select_all(pending).await;
+            pending = rest;
+            match result {
+                Ok(()) => info!("Listener {} stopped", name),
+                Err(e) => {
+                    log::error!("Listener {} failed: {}", name, e);
+                    failures.push(format!("listener {} failed: {}", name, e));
+                }
+            }
+            if !stopping && !pending.is_empty() {
+                info!("Stopping the remaining listeners gracefully");
+                stop_all(Some(&name));
+                stopping = true;
+            }
+        }
+
+        if failures.is_empty() {
+            info!("All servers shutdown gracefully");
+            Ok(())
+        } else {
+            Err(std::io::Error::other(failures.join("; ")))
         }
     }
 
-    /// Creates and configures the main HTTP server
+    /// Creates and binds the HTTP server of one listener
     /// 
-    /// Sets up the main server with a simple hello world endpoint
-    /// and logging middleware.
-    fn create_main_server(&self) -> std::io::Result<actix_web::dev::Server> {
-        let server = HttpServer::new(|| {
-            App::new()
-                .wrap(Self::create_cors())
-                .wrap(Logger::new("%a - - [%t] \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T"))
-                .service(
-                    web::scope("")
-                        .route("/", web::get().to(main_server::hello))
-                        .route("/health", web::get().to(main_server::hello)) // Health check endpoint
-                )
+    /// Listeners on the shared runtime are driven by the current actix
+    /// system; a dedicated listener gets its own system on a thread named
+    /// `listener-{name}`. Binding errors are returned either way.
+    fn create_server(
+        &self,
+        listener: &ListenerSpec,
+        components: AppComponents,
+        plugins: PluginStack,
+    ) -> std::io::Result<RunningListener> {
+        let spec = listener.clone();
+        let cors = CorsRouter::from_config(&self.config, &self.routes)
+            .map_err(|e| std::io::Error::other(e.to_string()))?
+            .with_live_config(components.live_config.clone().into_inner());
+        let (routes, debug_endpoints, limits) = (self.routes.clone(), self.config.debug_endpoints, self.config.server_limits());
+        let bind = move || Self::bind_server(&spec, limits, cors, routes, debug_endpoints, components, plugins);
+
+        match listener.runtime {
+            ListenerRuntime::Shared => {
+                let (server, addrs) = bind()?;
+                Ok(RunningListener {
+                    name: listener.name.clone(),
+                    addrs,
+                    handle: server.handle(),
+                    done: Box::pin(server),
+                })
+            }
+            ListenerRuntime::Dedicated => Self::spawn_dedicated(&listener.name, bind),
+        }
+    }
+
+    /// Runs `bind` and the resulting server on a new actix system and thread
+    fn spawn_dedicated(
+        name: &str,
+        bind: impl FnOnce() -> std::io::Result<(Server, Vec<SocketAddr>)> + Send + 'static,
+    ) -> std::io::Result<RunningListener> {
+        let (bound_tx, bound_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        std::thread::Builder::new()
+            .name(format!("listener-{}", name))
+            .spawn(move || {
+                let result = actix_web::rt::System::new().block_on(async move {
+                    match bind() {
+                        Ok((server, addrs)) => {
+                            let _ = bound_tx.send(Ok((addrs, server.handle())));
+                            server.await
+                        }
+                        Err(e) => {
+                            let _ = bound_tx.send(Err(e));
+                            Ok(())
+                        }
+                    }
+                });
+                let _ = done_tx.send(result);
+            })?;
+
+        let exited = || std::io::Error::other(format!("listener {} thread exited unexpectedly", name));
+        let (addrs, handle) = bound_rx.recv().map_err(|_| exited())??;
+        let exited = exited();
+        Ok(RunningListener {
+            name: name.to_string(),
+            addrs,
+            handle,
+            done: Box::pin(async move { done_rx.await.map_err(|_| exited)? }),
         })
-        .bind((self.config.bind_address.as_str(), self.config.main_port))?
-        .run();
-        
-        Ok(server)
     }
 
-    /// Creates and configures the application HTTP server
+    /// Binds the HTTP server of one listener
     /// 
-    /// Sets up the application server with multiple JSON endpoints,
-    /// CORS support, and logging middleware.
-    fn create_app_server(&self) -> std::io::Result<actix_web::dev::Server> {
-        let server = HttpServer::new(|| {
-            App::new()
-                .wrap(Self::create_cors())
-                .wrap(Logger::new("%a - - [%t] \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T"))
-                .service(
-                    web::scope("")
-                        .route("/", web::get().to(app_server::root))
-                        .route("/health", web::get().to(app_server::root)) // Health check endpoint
-                        .route("/public", web::get().to(app_server::public_route))
-                        .route("/private", web::get().to(app_server::private_route))
-                )
-        })
-        .bind((self.config.bind_address.as_str(), self.config.app_port))?
-        .run();
-        
+    /// Every listener shares the same components; its routes profile picks
+    /// what it serves and its middleware profile what wraps it. The `full`
+    /// profile adds the application middleware and the registered
+    /// middleware `plugins` to CORS support and logging. Listeners with TLS
+    /// settings serve HTTPS and expose verified client certificates to
+    /// handlers through [`ClientCertificate`](crate::tls::ClientCertificate).
+    /// Timeouts and connection limits come from `limits` rather than the
+    /// actix defaults.
+    fn bind_server(
+        listener: &ListenerSpec,
+        limits: ServerLimits,
+        cors: CorsRouter,
+        routes: RouteRegistry,
+        debug_endpoints: bool,
+        components: AppComponents,
+        plugins: PluginStack,
+    ) -> std::io::Result<(Server, Vec<SocketAddr>)> {
+        let profile = listener.routes;
+        let configure_routes = move |cfg: &mut web::ServiceConfig| profile.configure(cfg, &routes, debug_endpoints);
+        let address = (listener.address.as_str(), listener.port);
+        let tls = listener
+            .tls
+            .as_ref()
+            .map(tls::server_config)
+            .transpose()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        macro_rules! bind {
+            ($server:expr) => {{
+                let mut server = $server
+                    .keep_alive(limits.keep_alive)
+                    .client_request_timeout(limits.client_request_timeout)
+                    .shutdown_timeout(limits.shutdown_timeout.as_secs())
+                    .max_connections(limits.max_connections);
+                if let Some(workers) = listener.workers {
+                    server = server.workers(workers);
+                }
+                let server = match tls {
+                    Some(config) => server.on_connect(tls::on_connect).bind_rustls_0_23(address, config)?,
+                    None => server.bind(address)?,
+                };
+                let addrs = server.addrs();
+                (server.run(), addrs)
+            }};
+        }
+
+        let server = match listener.middleware {
+            MiddlewareProfile::Full => bind!(HttpServer::new(move || {
+                App::new()
+                    .configure(|cfg| components.configure(cfg))
+                    .wrap(plugins.clone())
+                    .wrap(from_fn(require_consent))
+                    .wrap(from_fn(mark_impersonated))
+                    .wrap(from_fn(track_usage))
+                    .wrap(from_fn(run_scripts))
+                    .wrap(from_fn(reject_writes))
+                    .wrap(from_fn(simulate_responses))
+                    .wrap(from_fn(version_handshake))
+                    .wrap(from_fn(ip_filter))
+                    .wrap(from_fn(serve_stale))
+                    .wrap(cors.clone())
+                    .wrap(from_fn(error_circuit))
+                    .wrap(from_fn(track_load))
+                    .wrap(Self::create_logger())
+                    .wrap(from_fn(capture_error_dumps))
+                    .wrap(from_fn(audit_requests))
+                    .wrap(from_fn(attach_context))
+                    .configure(configure_routes.clone())
+            })),
+            MiddlewareProfile::Standard => bind!(HttpServer::new(move || {
+                App::new()
+                    .configure(|cfg| components.configure(cfg))
+                    .wrap(from_fn(simulate_responses))
+                    .wrap(from_fn(version_handshake))
+                    .wrap(from_fn(ip_filter))
+                    .wrap(from_fn(serve_stale))
+                    .wrap(cors.clone())
+                    .wrap(from_fn(error_circuit))
+                    .wrap(Self::create_logger())
+                    .wrap(from_fn(attach_context))
+                    .configure(configure_routes.clone())
+            })),
+            MiddlewareProfile::Minimal => bind!(HttpServer::new(move || {
+                App::new()
+                    .configure(|cfg| components.configure(cfg))
+                    .wrap(from_fn(simulate_responses))
+                    .wrap(from_fn(version_handshake))
+                    .wrap(from_fn(ip_filter))
+                    .wrap(Self::create_logger())
+                    .wrap(from_fn(attach_context))
+                    .configure(configure_routes.clone())
+            })),
+        };
+
         Ok(server)
     }
 
-    /// Creates a CORS configuration for the servers
-    /// 
-    /// Configures CORS to allow common methods and headers for API access.
-    fn create_cors() -> Cors {
-        Cors::default()
-            .allow_any_origin()
-            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
-            .allowed_headers(vec![
-                actix_web::http::header::AUTHORIZATION,
-                actix_web::http::header::ACCEPT,
-                actix_web::http::header::CONTENT_TYPE,
-            ])
-            .max_age(3600)
+    /// Creates the access log middleware shared by every listener
+    ///
+    /// Requests are logged with the client address resolved through trusted
+    /// proxies rather than the peer address. Access lines are written once
+    /// the response is sent, outside the request's log context, so they carry
+    /// its correlation fields explicitly.
+    fn create_logger() -> Logger {
+        Logger::new("%{client_ip}xi - - [%t] \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T %{correlation}xi")
+            .custom_request_replace("client_ip", |req| {
+                client_info::client_ip(req.request()).map_or_else(|| "-".to_string(), |ip| ip.to_string())
+            })
+            .custom_request_replace("correlation", |req| {
+                req.extensions()
+                    .get::<RequestContext>()
+                    .map_or_else(|| "-".to_string(), |context| LogContext::of(context).to_string())
+            })
     }
 }
 
@@ -127,6 +1089,7 @@ mod tests {
             main_port: 8080,
             app_port: 4242,
             bind_address: "127.0.0.1".to_string(),
+            ..Config::default()
         };
 
         let server_manager = ServerManager::new(config);
@@ -134,10 +1097,226 @@ mod tests {
         assert_eq!(server_manager.config.app_port, 4242);
     }
 
+    #[actix_web::test]
+    async fn test_dedicated_listener_answers_while_app_workers_are_busy() {
+        use crate::listeners::RouteProfile;
+        use actix_web::HttpResponse;
+        use std::time::{Duration, Instant};
+
+        let config = Config::default();
+        let routes = RouteRegistry::new()
+            .route(RouteSpec::get("/slow", "Blocks its worker thread", || {
+                web::get().to(|| async {
+                    std::thread::sleep(Duration::from_millis(1500));
+                    HttpResponse::Ok().finish()
+                })
+            }))
+            .route(RouteSpec::get("/fast", "Answers at once", || web::get().to(HttpResponse::Ok)));
+        let state = StateManager::from_config(&config).unwrap();
+        let components = AppComponents::build(&config, &state, &routes, RbacPolicy::default(), None).unwrap();
+        let manager = ServerManager {
+            config,
+            plugins: PluginRegistry::new(),
+            routes,
+            key_store: None,
+            audit_sink: None,
+            vault: None,
+            challenge_provider: None,
+            content_scanner: None,
+            restore: None,
+            config_layers: None,
+            remote_config: None,
+            shutdown: None,
+        };
+
+        let app = ListenerSpec {
+            workers: Some(1),
+            middleware: MiddlewareProfile::Minimal,
+            ..ListenerSpec::new("app", "127.0.0.1", 0, RouteProfile::App)
+        };
+        let health = ListenerSpec {
+            workers: Some(1),
+            runtime: ListenerRuntime::Dedicated,
+            ..ListenerSpec::new("health", "127.0.0.1", 0, RouteProfile::Health)
+        };
+        let app = manager.create_server(&app, components.clone(), PluginStack::default()).unwrap();
+        let health = manager.create_server(&health, components, PluginStack::default()).unwrap();
+        let (app_url, health_url) = (format!("http://{}", app.addrs[0]), format!("http://{}", health.addrs[0]));
+        actix_web::rt::spawn(app.done);
+        actix_web::rt::spawn(health.done);
+
+        let client = reqwest::Client::new();
+        let slow = actix_web::rt::spawn(client.get(format!("{}/slow", app_url)).send());
+        actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+
+        // The health listener answers at once while the app's only worker is blocked
+        let started = Instant::now();
+        let res = client.get(format!("{}/health", health_url)).send().await.unwrap();
+        assert!(res.status().is_success());
+        assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+
+        // whereas the app listener's next request waits for its worker
+        let started = Instant::now();
+        let res = client.get(format!("{}/fast", app_url)).send().await.unwrap();
+        assert!(res.status().is_success());
+        assert!(started.elapsed() >= Duration::from_millis(800), "{:?}", started.elapsed());
+        assert!(slow.await.unwrap().unwrap().status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_failed_listener_stops_the_others() {
+        use crate::listeners::RouteProfile;
+        use std::time::Duration;
+
+        let config = Config::default();
+        let routes = RouteRegistry::app_server();
+        let state = StateManager::from_config(&config).unwrap();
+        let components = AppComponents::build(&config, &state, &routes, RbacPolicy::default(), None).unwrap();
+        let manager = ServerManager::new(config);
+
+        let mut servers = Vec::new();
+        for (name, runtime) in [("main", ListenerRuntime::Shared), ("health", ListenerRuntime::Dedicated)] {
+            let listener = ListenerSpec {
+                workers: Some(1),
+                runtime,
+                ..ListenerSpec::new(name, "127.0.0.1", 0, RouteProfile::Health)
+            };
+            servers.push(manager.create_server(&listener, components.clone(), PluginStack::default()).unwrap());
+        }
+        let health_url = format!("http://{}/health", servers[1].addrs[0]);
+        assert!(reqwest::get(&health_url).await.unwrap().status().is_success());
+
+        // The main listener's server fails shortly after startup
+        let main = &mut servers[0];
+        let server = std::mem::replace(&mut main.done, Box::pin(async { Ok(()) }));
+        main.done = Box::pin(async move {
+            actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+            drop(server);
+            Err(std::io::Error::other("worker crashed"))
+        });
+
+        let result = actix_web::rt::time::timeout(Duration::from_secs(10), ServerManager::supervise(servers, false))
+            .await
+            .expect("the remaining listener was not stopped");
+        assert_eq!(result.unwrap_err().to_string(), "listener main failed: worker crashed");
+        assert!(reqwest::get(&health_url).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_shutdown_trigger_stops_every_listener() {
+        use crate::listeners::RouteProfile;
+        use std::time::Duration;
+
+        let config = Config::default();
+        let routes = RouteRegistry::app_server();
+        let state = StateManager::from_config(&config).unwrap();
+        let components = AppComponents::build(&config, &state, &routes, RbacPolicy::default(), None).unwrap();
+        let manager = ServerManager::new(config);
+
+        let mut servers = Vec::new();
+        for (name, runtime) in [("main", ListenerRuntime::Shared), ("health", ListenerRuntime::Dedicated)] {
+            let listener = ListenerSpec {
+                workers: Some(1),
+                runtime,
+                ..ListenerSpec::new(name, "127.0.0.1", 0, RouteProfile::Health)
+            };
+            servers.push(manager.create_server(&listener, components.clone(), PluginStack::default()).unwrap());
+        }
+        let urls: Vec<String> = servers.iter().map(|server| format!("http://{}/health", server.addrs[0])).collect();
+        let trigger = ShutdownTrigger::new();
+        ServerManager::stop_when(trigger.clone(), &servers);
+        let supervised = actix_web::rt::spawn(ServerManager::supervise(servers, false));
+        for url in &urls {
+            assert!(reqwest::get(url).await.unwrap().status().is_success());
+        }
+
+        // Fired from another thread, as the service control handler does
+        std::thread::spawn(move || trigger.trigger());
+        let result = actix_web::rt::time::timeout(Duration::from_secs(10), supervised)
+            .await
+            .expect("the listeners were not stopped");
+        assert!(result.unwrap().is_ok());
+        for url in &urls {
+            assert!(reqwest::get(url).await.is_err());
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_mutual_tls_listener_exposes_client_certificate() {
+        use crate::listeners::RouteProfile;
+        use crate::tls::{ClientAuth, TlsSettings};
+
+        let fixture = |name: &str| format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let read = |name: &str| std::fs::read(fixture(name)).unwrap();
+        let config = Config::default();
+        let routes = RouteRegistry::app_server();
+        let state = StateManager::from_config(&config).unwrap();
+        let components = AppComponents::build(&config, &state, &routes, RbacPolicy::default(), None).unwrap();
+        let manager = ServerManager::new(config);
+
+        let listener = |client_auth| ListenerSpec {
+            workers: Some(1),
+            tls: Some(TlsSettings {
+                cert_path: fixture("server.pem"),
+                key_path: fixture("server-key.pem"),
+                client_ca_path: Some(fixture("ca.pem")),
+                client_auth,
+            }),
+            ..ListenerSpec::new("app", "127.0.0.1", 0, RouteProfile::App)
+        };
+        let required = manager
+            .create_server(&listener(ClientAuth::Required), components.clone(), PluginStack::default())
+            .unwrap();
+        let optional = manager
+            .create_server(&listener(ClientAuth::Optional), components, PluginStack::default())
+            .unwrap();
+        let url = |running: &RunningListener| {
+            format!("https://localhost:{}/auth/client-certificate", running.addrs[0].port())
+        };
+        let (required_url, optional_url) = (url(&required), url(&optional));
+        actix_web::rt::spawn(required.done);
+        actix_web::rt::spawn(optional.done);
+
+        let ca = reqwest::Certificate::from_pem(&read("ca.pem")).unwrap();
+        let identity = reqwest::Identity::from_pem(&[read("client.pem"), read("client-key.pem")].concat()).unwrap();
+        let client = |identity: Option<reqwest::Identity>| {
+            let builder = reqwest::Client::builder()
+                .use_rustls_tls()
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(ca.clone())
+                .resolve("localhost", "127.0.0.1:0".parse().unwrap());
+            match identity {
+                Some(identity) => builder.identity(identity),
+                None => builder,
+            }
+            .build()
+            .unwrap()
+        };
+
+        let res = client(Some(identity.clone())).get(&required_url).send().await.unwrap();
+        assert!(res.status().is_success());
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["subject"], "CN=billing-service, O=Simple API Demo");
+        assert_eq!(body["common_name"], "billing-service");
+
+        // The negotiated parameters and the certificate show up in the client info
+        let whoami = required_url.replace("/auth/client-certificate", "/whoami");
+        let res = client(Some(identity)).get(&whoami).send().await.unwrap();
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["tls"]["protocol"], "TLSv1_3");
+        assert_eq!(body["tls"]["server_name"], "localhost");
+        assert_eq!(body["tls"]["client_certificate"]["common_name"], "billing-service");
+
+        // Without a certificate the handshake fails, or the handler refuses when certificates are optional
+        assert!(client(None).get(&required_url).send().await.is_err());
+        let res = client(None).get(&optional_url).send().await.unwrap();
+        assert_eq!(res.status(), 401);
+    }
+
     #[test]
-    fn test_cors_creation() {
-        let _cors = ServerManager::create_cors();
-        // Basic test that CORS can be created without errors
-        // In a real application, you might want more detailed CORS testing
+    fn test_loopback_replaces_unspecified_addresses() {
+        assert_eq!(loopback("0.0.0.0:4242".parse().unwrap()), "127.0.0.1:4242".parse().unwrap());
+        assert_eq!(loopback("[::]:4242".parse().unwrap()), "[::1]:4242".parse().unwrap());
+        assert_eq!(loopback("10.1.2.3:4242".parse().unwrap()), "10.1.2.3:4242".parse().unwrap());
     }
 } 
\ No newline at end of file
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Response header carrying the recorded phases
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+/// A recorded phase name and its duration
+pub type Phase = (&'static str, Duration);
+
+/// Phase durations of one request, reported in the `Server-Timing` header
+///
+/// Lives in the [`RequestContext`](crate::context::RequestContext); clones
+/// share the same recording, so spans recorded through a context a handler
+/// extracted end up in the header the `attach_context` middleware writes.
+/// A disabled instance records nothing.
+#[derive(Clone, Default)]
+pub struct ServerTiming {
+    spans: Option<Arc<Mutex<Vec<Phase>>>>,
+}
+
+impl ServerTiming {
+    /// Creates a recording, a no-op unless `enabled`
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            spans: enabled.then(Default::default),
+        }
+    }
+
+    /// Whether spans are recorded
+    pub fn is_enabled(&self) -> bool {
+        self.spans.is_some()
+    }
+
+    /// Adds `duration` to the phase `name`
+    ///
+    /// `name` must be a header token (letters, digits, `-`, `_`, `.`).
+    pub fn record(&self, name: &'static str, duration: Duration) {
+        if let Some(spans) = &self.spans {
+            if let Ok(mut spans) = spans.lock() {
+                spans.push((name, duration));
+            }
+        }
+    }
+
+    /// Starts a span of the phase `name`, recorded when the guard is dropped
+    pub fn span(&self, name: &'static str) -> TimingSpan {
+        TimingSpan {
+            timing: self.clone(),
+            name,
+            started: Instant::now(),
+        }
+    }
+
+    /// Runs `f`, recording its duration under the phase `name`
+    pub fn measure<T>(&self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let _span = self.span(name);
+        f()
+    }
+
+    /// Recorded phases in first-recorded order, durations of repeated phases summed
+    pub fn phases(&self) -> Vec<Phase> {
+        let mut phases: Vec<Phase> = Vec::new();
+        let Some(spans) = &self.spans else {
+            return phases;
+        };
+        for (name, duration) in spans.lock().map(|spans| spans.clone()).unwrap_or_default() {
+            match phases.iter_mut().find(|(phase, _)| *phase == name) {
+                Some((_, total)) => *total += duration,
+                None => phases.push((name, duration)),
+            }
+        }
+        phases
+    }
+
+    /// Builds the `Server-Timing` value: the phases, then `total` and the
+    /// request's time `budget`
+    pub fn header_value(&self, total: Duration, budget: Duration) -> String {
+        self.phases()
+            .into_iter()
+            .chain([("total", total), ("budget", budget)])
+            .map(|(name, duration)| format!("{};dur={:.1}", name, duration.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl fmt::Debug for ServerTiming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerTiming").field("phases", &self.phases()).finish()
+    }
+}
+
+/// Spans are bookkeeping, so contexts compare equal whatever they recorded
+impl PartialEq for ServerTiming {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for ServerTiming {}
+
+/// Running span started by [`ServerTiming::span`]
+pub struct TimingSpan {
+    timing: ServerTiming,
+    name: &'static str,
+    started: Instant,
+}
+
+impl Drop for TimingSpan {
+    fn drop(&mut self) {
+        self.timing.record(self.name, self.started.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phases_are_summed_in_order() {
+        let timing = ServerTiming::new(true);
+        timing.record("auth", Duration::from_millis(2));
+        let shared = timing.clone();
+        shared.record("db", Duration::from_micros(1500));
+        timing.record("auth", Duration::from_millis(3));
+        assert_eq!(
+            timing.phases(),
+            vec![("auth", Duration::from_millis(5)), ("db", Duration::from_micros(1500))]
+        );
+        assert_eq!(
+            timing.header_value(Duration::from_millis(12), Duration::from_secs(30)),
+            "auth;dur=5.0, db;dur=1.5, total;dur=12.0, budget;dur=30000.0"
+        );
+    }
+
+    #[test]
+    fn test_spans_record_on_drop() {
+        let timing = ServerTiming::new(true);
+        let value = timing.measure("render", || 42);
+        assert_eq!(value, 42);
+        {
+            let _span = timing.span("db");
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let phases = timing.phases();
+        assert_eq!(phases.iter().map(|(name, _)| *name).collect::<Vec<_>>(), vec!["render", "db"]);
+        assert!(phases[1].1 >= Duration::from_millis(2));
+    }
+
+    #[test]
+    fn test_disabled_timing_records_nothing() {
+        let timing = ServerTiming::new(false);
+        assert!(!timing.is_enabled());
+        timing.record("auth", Duration::from_millis(2));
+        timing.measure("db", || ());
+        assert!(timing.phases().is_empty());
+        assert_eq!(timing.header_value(Duration::ZERO, Duration::ZERO), "total;dur=0.0, budget;dur=0.0");
+    }
+}
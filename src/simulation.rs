@@ -0,0 +1,231 @@
+use std::str::FromStr;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, ResponseError};
+use chrono::{DateTime, Utc};
+use log::debug;
+
+use crate::auth::quotas::{QuotaPeriod, QUOTA_REMAINING_HEADER};
+use crate::config::{AppEnv, Config};
+use crate::error::{AppError, AppResult};
+
+/// Request header asking for a simulated failure, e.g. `rate_limited; retry_after=5`
+pub const SIMULATE_HEADER: &str = "x-simulate";
+/// Response header naming the simulation that produced the response
+pub const SIMULATED_HEADER: &str = "x-simulated";
+
+/// Delay announced in `Retry-After` when the client does not pick one
+const DEFAULT_RETRY_AFTER_SECS: u64 = 30;
+
+/// Failure a client can ask for with `X-Simulate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Simulation {
+    /// 429 as sent by the rate limiters
+    RateLimited,
+    /// 429 as sent for an API key whose daily quota is used up
+    QuotaExhausted,
+    /// 503 as sent while the service is down for maintenance
+    Maintenance,
+}
+
+impl Simulation {
+    /// Every simulation, in the order they are documented
+    pub const ALL: [Simulation; 3] = [Simulation::RateLimited, Simulation::QuotaExhausted, Simulation::Maintenance];
+
+    /// Value of the `X-Simulate` header selecting the simulation
+    pub fn name(self) -> &'static str {
+        match self {
+            Simulation::RateLimited => "rate_limited",
+            Simulation::QuotaExhausted => "quota_exhausted",
+            Simulation::Maintenance => "maintenance",
+        }
+    }
+
+    /// Error the real condition produces, announcing a retry after `retry_after` seconds
+    ///
+    /// Without `retry_after`, quota exhaustion lasts until the next UTC
+    /// midnight like a real daily quota, the others 30 seconds.
+    pub fn error(self, retry_after: Option<u64>, now: DateTime<Utc>) -> AppError {
+        match self {
+            Simulation::RateLimited => AppError::rate_limited(
+                "too many requests (simulated)",
+                retry_after.unwrap_or(DEFAULT_RETRY_AFTER_SECS),
+            ),
+            Simulation::QuotaExhausted => {
+                let until_reset = (QuotaPeriod::Daily.resets_at(now) - now).num_seconds().max(1) as u64;
+                AppError::rate_limited(
+                    "daily quota exhausted for API key (simulated)",
+                    retry_after.unwrap_or(until_reset),
+                )
+            }
+            Simulation::Maintenance => AppError::unavailable("service is down for maintenance (simulated)"),
+        }
+    }
+}
+
+impl FromStr for Simulation {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim().to_ascii_lowercase();
+        Self::ALL.into_iter().find(|simulation| simulation.name() == value).ok_or_else(|| {
+            let names: Vec<&str> = Self::ALL.iter().map(|simulation| simulation.name()).collect();
+            AppError::validation(format!("X-Simulate must be one of {}, got: {}", names.join(", "), value))
+        })
+    }
+}
+
+/// Parses `name[; retry_after=SECS]`
+///
+/// # Errors
+/// Returns a validation error for unknown simulations or parameters
+pub fn parse_header(value: &str) -> AppResult<(Simulation, Option<u64>)> {
+    let mut parts = value.split(';');
+    let simulation = parts.next().unwrap_or_default().parse()?;
+    let mut retry_after = None;
+    for parameter in parts {
+        match parameter.trim().split_once('=') {
+            Some(("retry_after", secs)) => {
+                retry_after = Some(secs.trim().parse().map_err(|_| {
+                    AppError::validation(format!("X-Simulate retry_after must be a number of seconds, got: {}", secs))
+                })?)
+            }
+            _ => {
+                return Err(AppError::validation(format!(
+                    "unknown X-Simulate parameter: {}",
+                    parameter.trim()
+                )))
+            }
+        }
+    }
+    Ok((simulation, retry_after))
+}
+
+/// Whether `X-Simulate` is honoured: in development and with
+/// `STUB_DEPENDENCIES`, never in production
+pub fn enabled(config: &Config) -> bool {
+    config.app_env != AppEnv::Production && (config.app_env == AppEnv::Development || config.stub_dependencies)
+}
+
+/// Middleware answering requests carrying `X-Simulate` with the requested
+/// failure instead of handling them, for use with `from_fn`
+///
+/// Lets client teams exercise their 429 and 503 handling without tripping
+/// real limits. Simulated responses have the status, body and `Retry-After`
+/// of the real ones plus `X-Simulated`; quota exhaustion also carries
+/// `X-Quota-Remaining: 0`. The header is ignored unless [`enabled`].
+///
+/// # Errors
+/// Validation error for an `X-Simulate` value that names no simulation
+pub async fn simulate_responses(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let active = req.app_data::<web::Data<Config>>().is_some_and(|config| enabled(config));
+    let requested = req
+        .headers()
+        .get(SIMULATE_HEADER)
+        .filter(|_| active)
+        .map(|value| parse_header(value.to_str().unwrap_or_default()))
+        .transpose()?;
+    let Some((simulation, retry_after)) = requested else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+
+    debug!("Simulating '{}' for {} {}", simulation.name(), req.method(), req.path());
+    let error = simulation.error(retry_after, Utc::now());
+    let mut response = error.error_response();
+    let headers = response.headers_mut();
+    headers.insert(HeaderName::from_static(SIMULATED_HEADER), HeaderValue::from_static(simulation.name()));
+    match simulation {
+        Simulation::QuotaExhausted => {
+            headers.insert(HeaderName::from_static(QUOTA_REMAINING_HEADER), HeaderValue::from(0));
+        }
+        Simulation::Maintenance => {
+            headers.insert(RETRY_AFTER, HeaderValue::from(retry_after.unwrap_or(DEFAULT_RETRY_AFTER_SECS)));
+        }
+        Simulation::RateLimited => {}
+    }
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(parse_header("rate_limited").unwrap(), (Simulation::RateLimited, None));
+        assert_eq!(parse_header(" Maintenance ; retry_after=120").unwrap(), (Simulation::Maintenance, Some(120)));
+        for invalid in ["", "teapot", "rate_limited; retry_after=soon", "quota_exhausted; period=monthly"] {
+            assert!(matches!(parse_header(invalid), Err(AppError::Validation { .. })), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_quota_exhaustion_lasts_until_midnight() {
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 23, 0, 0).unwrap();
+        match Simulation::QuotaExhausted.error(None, now) {
+            AppError::RateLimited { retry_after_secs, .. } => assert_eq!(retry_after_secs, 3600),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_enabled() {
+        let with = |app_env, stub_dependencies| Config {
+            app_env,
+            stub_dependencies,
+            ..Config::default()
+        };
+        assert!(enabled(&with(AppEnv::Development, false)));
+        assert!(enabled(&with(AppEnv::Staging, true)));
+        assert!(!enabled(&with(AppEnv::Staging, false)));
+        assert!(!enabled(&with(AppEnv::Production, true)));
+    }
+
+    #[actix_web::test]
+    async fn test_simulate_responses() {
+        let app = |app_env| {
+            App::new()
+                .app_data(web::Data::new(Config {
+                    app_env,
+                    ..Config::default()
+                }))
+                .wrap(from_fn(simulate_responses))
+                .route("/", web::get().to(HttpResponse::Ok))
+        };
+        let request = |value: &str| TestRequest::get().uri("/").insert_header((SIMULATE_HEADER, value)).to_request();
+        let service = init_service(app(AppEnv::Development)).await;
+
+        let res = call_service(&service, request("rate_limited; retry_after=5")).await;
+        assert_eq!(res.status(), 429);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "5");
+        assert_eq!(res.headers().get(SIMULATED_HEADER).unwrap(), "rate_limited");
+
+        let res = call_service(&service, request("quota_exhausted")).await;
+        assert_eq!(res.status(), 429);
+        assert_eq!(res.headers().get(QUOTA_REMAINING_HEADER).unwrap(), "0");
+
+        let res = call_service(&service, request("maintenance")).await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "30");
+
+        let err = try_call_service(&service, request("teapot")).await.err().unwrap();
+        assert_eq!(err.error_response().status(), 400);
+        let res = call_service(&service, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(res.status(), 200);
+
+        // Production never simulates
+        let service = init_service(app(AppEnv::Production)).await;
+        let res = call_service(&service, request("maintenance")).await;
+        assert_eq!(res.status(), 200);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::AppResult;
+use crate::log_context::LogContext;
+use crate::state::{KeyValueStore, StateMode};
+
+/// One storage query that took at least the threshold
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SlowQuery {
+    /// `{component}.{operation}`, e.g. `users.get`
+    pub name: String,
+    /// The key with every segment after the first replaced by `?`, e.g. `email:?`
+    pub key: String,
+    pub duration_ms: f64,
+    pub at: DateTime<Utc>,
+    /// Request the query ran for, when it ran for one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Timings of every query of one name
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct QueryStats {
+    pub name: String,
+    pub calls: u64,
+    /// Calls that took at least the threshold
+    pub slow: u64,
+    pub total_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Answer of `GET /admin/slow-queries`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SlowQueryReport {
+    pub threshold_ms: u64,
+    /// Per query name, slowest total first
+    pub queries: Vec<QueryStats>,
+    /// Most recent slow queries, newest first
+    pub recent: Vec<SlowQuery>,
+}
+
+/// Times the queries of component stores and remembers the slow ones
+///
+/// Queries taking at least `threshold` are logged as warnings and kept in
+/// a ring of the last `capacity`, with their keys redacted: values are
+/// never recorded and keys keep their prefix only, since the rest usually
+/// holds ids or email addresses. Every query counts towards the stats of
+/// its name, so slow ones can be told apart from frequent ones.
+#[derive(Debug)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    capacity: usize,
+    recent: Mutex<VecDeque<SlowQuery>>,
+    stats: Mutex<BTreeMap<String, QueryStats>>,
+}
+
+impl SlowQueryLog {
+    pub fn new(threshold: Duration, capacity: usize) -> Self {
+        Self {
+            threshold,
+            capacity,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            stats: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Log configured by `SLOW_QUERY_THRESHOLD_MS` and `SLOW_QUERY_LOG_SIZE`
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            Duration::from_millis(config.slow_query_threshold_ms),
+            config.slow_query_log_size,
+        )
+    }
+
+    /// Records one query of `name` on `key` that took `elapsed`
+    pub fn record(&self, name: &str, key: &str, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        let slow = elapsed >= self.threshold;
+        if let Ok(mut stats) = self.stats.lock() {
+            let entry = stats.entry(name.to_string()).or_insert_with(|| QueryStats {
+                name: name.to_string(),
+                ..QueryStats::default()
+            });
+            entry.calls += 1;
+            entry.slow += u64::from(slow);
+            entry.total_ms += ms;
+            entry.max_ms = entry.max_ms.max(ms);
+        }
+        if !slow {
+            return;
+        }
+
+        let query = SlowQuery {
+            name: name.to_string(),
+            key: redact_key(key),
+            duration_ms: ms,
+            at: Utc::now(),
+            request_id: LogContext::current().map(|context| context.request_id),
+        };
+        warn!("Slow query {} on {} took {:.1} ms", query.name, query.key, ms);
+        if let Ok(mut recent) = self.recent.lock() {
+            if recent.len() == self.capacity {
+                recent.pop_back();
+            }
+            if self.capacity > 0 {
+                recent.push_front(query);
+            }
+        }
+    }
+
+    /// Stats per query name and the recent slow queries
+    pub fn report(&self) -> SlowQueryReport {
+        let mut queries: Vec<QueryStats> = self
+            .stats
+            .lock()
+            .map(|stats| stats.values().cloned().collect())
+            .unwrap_or_default();
+        queries.sort_by(|a, b| b.total_ms.total_cmp(&a.total_ms));
+        SlowQueryReport {
+            threshold_ms: self.threshold.as_millis() as u64,
+            queries,
+            recent: self.recent.lock().map(|recent| recent.iter().cloned().collect()).unwrap_or_default(),
+        }
+    }
+
+    /// Wraps `inner`, the store of `component`, so its queries are timed
+    pub fn instrument(self: &Arc<Self>, component: &str, inner: Arc<dyn KeyValueStore>) -> Arc<dyn KeyValueStore> {
+        Arc::new(TimedStore {
+            component: component.to_string(),
+            inner,
+            log: self.clone(),
+        })
+    }
+}
+
+/// Keeps the first `:`-separated segment of `key` and masks the others
+fn redact_key(key: &str) -> String {
+    let mut segments = key.split(':');
+    let first = segments.next().unwrap_or_default();
+    std::iter::once(first)
+        .chain(segments.map(|_| "?"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// [`KeyValueStore`] reporting the time of every call to a [`SlowQueryLog`]
+struct TimedStore {
+    component: String,
+    inner: Arc<dyn KeyValueStore>,
+    log: Arc<SlowQueryLog>,
+}
+
+impl TimedStore {
+    fn timed<T>(&self, operation: &str, key: &str, query: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = query();
+        self.log
+            .record(&format!("{}.{}", self.component, operation), key, started.elapsed());
+        result
+    }
+}
+
+impl KeyValueStore for TimedStore {
+    fn get(&self, key: &str) -> AppResult<Option<String>> {
+        self.timed("get", key, || self.inner.get(key))
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<()> {
+        self.timed("set", key, || self.inner.set(key, value, ttl))
+    }
+
+    fn set_if_absent(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<bool> {
+        self.timed("set_if_absent", key, || self.inner.set_if_absent(key, value, ttl))
+    }
+
+    fn delete(&self, key: &str) -> AppResult<bool> {
+        self.timed("delete", key, || self.inner.delete(key))
+    }
+
+    fn increment(&self, key: &str, ttl: Option<Duration>) -> AppResult<u64> {
+        self.timed("increment", key, || self.inner.increment(key, ttl))
+    }
+
+    fn mode(&self) -> StateMode {
+        self.inner.mode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+
+    #[test]
+    fn test_redact_key() {
+        assert_eq!(redact_key("email:ann@example.com"), "email:?");
+        assert_eq!(redact_key("events:42:2024-01-01"), "events:?:?");
+        assert_eq!(redact_key("index"), "index");
+    }
+
+    #[test]
+    fn test_queries_are_counted_and_slow_ones_kept() {
+        let log = Arc::new(SlowQueryLog::new(Duration::from_millis(50), 2));
+        let store = log.instrument("users", Arc::new(InMemoryStore::new()));
+        store.set("user:1", "{}", None).unwrap();
+        assert_eq!(store.get("user:1").unwrap().as_deref(), Some("{}"));
+        log.record("users.get", "email:ann@example.com", Duration::from_millis(80));
+        for _ in 0..2 {
+            log.record("users.delete", "user:2", Duration::from_millis(60));
+        }
+
+        let report = log.report();
+        assert_eq!(report.threshold_ms, 50);
+        let stats: BTreeMap<_, _> = report.queries.into_iter().map(|stats| (stats.name.clone(), stats)).collect();
+        assert_eq!((stats["users.get"].calls, stats["users.get"].slow), (2, 1));
+        assert_eq!((stats["users.set"].calls, stats["users.set"].slow), (1, 0));
+        assert_eq!(stats["users.delete"].slow, 2);
+        // The ring keeps the last two, newest first
+        let names: Vec<_> = report.recent.iter().map(|query| query.name.as_str()).collect();
+        assert_eq!(names, ["users.delete", "users.delete"]);
+        assert!(report.recent.iter().all(|query| query.key == "user:?"));
+    }
+}
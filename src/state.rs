@@ -0,0 +1,641 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+#[cfg(feature = "redis")]
+use crate::budgets::Budget;
+use crate::budgets::Budgets;
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::slow_queries::SlowQueryLog;
+use crate::stubs::{FaultProfile, StubStore};
+
+/// Where shared runtime state (rate limits, sessions, caches, idempotency keys) lives
+///
+/// `Local` keeps everything in process memory, which is only correct with a
+/// single replica. `Distributed` selects the Redis-backed implementations so
+/// several replicas behind a load balancer see the same state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateMode {
+    /// In-process memory (default)
+    Local,
+    /// Shared Redis instance (requires the `redis` feature and `REDIS_URL`)
+    Distributed,
+}
+
+impl FromStr for StateMode {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "local" | "memory" => Ok(StateMode::Local),
+            "distributed" | "redis" => Ok(StateMode::Distributed),
+            other => Err(AppError::environment(
+                "STATE_MODE",
+                format!("must be 'local' or 'distributed', got: {}", other),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StateMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateMode::Local => write!(f, "local"),
+            StateMode::Distributed => write!(f, "distributed"),
+        }
+    }
+}
+
+/// Key/value storage used by every stateful component
+///
+/// Implementations must be safe to share across actix workers. Values are
+/// plain strings so the same data can live in memory or in Redis; callers
+/// serialize structured data with serde_json when needed.
+pub trait KeyValueStore: Send + Sync {
+    /// Returns the value stored under `key`, if present and not expired
+    fn get(&self, key: &str) -> AppResult<Option<String>>;
+
+    /// Stores `value` under `key`, optionally expiring after `ttl`
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<()>;
+
+    /// Stores `value` only if `key` is absent; returns whether it was stored
+    ///
+    /// This is the primitive idempotency keys and locks are built on.
+    fn set_if_absent(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<bool>;
+
+    /// Removes `key`; returns whether it existed
+    fn delete(&self, key: &str) -> AppResult<bool>;
+
+    /// Atomically increments the counter under `key` and returns the new value
+    ///
+    /// The TTL is only applied when the counter is created, which gives
+    /// fixed-window semantics for rate limiting.
+    fn increment(&self, key: &str, ttl: Option<Duration>) -> AppResult<u64>;
+
+    /// Returns the mode this store implements
+    fn mode(&self) -> StateMode;
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    value: String,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// In-process implementation of [`KeyValueStore`]
+///
+/// Expired entries are dropped lazily when they are next touched.
+#[derive(Debug, Default)]
+pub struct InMemoryStore {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl InMemoryStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copies every live entry with its remaining lifetime, under one lock so
+    /// the copy is a consistent point-in-time view
+    pub fn dump(&self) -> AppResult<Vec<(String, String, Option<Duration>)>> {
+        let entries = self.lock()?;
+        let now = Instant::now();
+        Ok(entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| {
+                let ttl = entry.expires_at.map(|at| at.saturating_duration_since(now));
+                (key.clone(), entry.value.clone(), ttl)
+            })
+            .collect())
+    }
+
+    /// Replaces the whole content with `entries`, lifetimes counting from now
+    pub fn load(&self, entries: impl IntoIterator<Item = (String, String, Option<Duration>)>) -> AppResult<usize> {
+        let now = Instant::now();
+        let loaded: HashMap<String, Entry> = entries
+            .into_iter()
+            .map(|(key, value, ttl)| (key, Entry { value, expires_at: ttl.map(|ttl| now + ttl) }))
+            .collect();
+        let count = loaded.len();
+        *self.lock()? = loaded;
+        Ok(count)
+    }
+
+    fn lock(&self) -> AppResult<std::sync::MutexGuard<'_, HashMap<String, Entry>>> {
+        self.entries
+            .lock()
+            .map_err(|_| AppError::internal("state store lock poisoned"))
+    }
+}
+
+impl KeyValueStore for InMemoryStore {
+    fn get(&self, key: &str) -> AppResult<Option<String>> {
+        let mut entries = self.lock()?;
+        let now = Instant::now();
+        match entries.get(key) {
+            Some(entry) if entry.is_expired(now) => {
+                entries.remove(key);
+                Ok(None)
+            }
+            Some(entry) => Ok(Some(entry.value.clone())),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<()> {
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        self.lock()?.insert(
+            key.to_string(),
+            Entry {
+                value: value.to_string(),
+                expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    fn set_if_absent(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<bool> {
+        let mut entries = self.lock()?;
+        let now = Instant::now();
+        if entries.get(key).is_some_and(|entry| !entry.is_expired(now)) {
+            return Ok(false);
+        }
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value: value.to_string(),
+                expires_at: ttl.map(|ttl| now + ttl),
+            },
+        );
+        Ok(true)
+    }
+
+    fn delete(&self, key: &str) -> AppResult<bool> {
+        let now = Instant::now();
+        Ok(self
+            .lock()?
+            .remove(key)
+            .is_some_and(|entry| !entry.is_expired(now)))
+    }
+
+    fn increment(&self, key: &str, ttl: Option<Duration>) -> AppResult<u64> {
+        let mut entries = self.lock()?;
+        let now = Instant::now();
+        let current = match entries.get(key) {
+            Some(entry) if !entry.is_expired(now) => Some(entry.clone()),
+            _ => None,
+        };
+
+        let (count, expires_at) = match current {
+            Some(entry) => {
+                let count = entry.value.parse::<u64>().map_err(|_| {
+                    AppError::internal(format!("state key '{}' does not hold a counter", key))
+                })?;
+                (count + 1, entry.expires_at)
+            }
+            None => (1, ttl.map(|ttl| now + ttl)),
+        };
+
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value: count.to_string(),
+                expires_at,
+            },
+        );
+        Ok(count)
+    }
+
+    fn mode(&self) -> StateMode {
+        StateMode::Local
+    }
+}
+
+/// Redis implementation of [`KeyValueStore`]
+///
+/// Uses a single synchronous connection guarded by a mutex; commands are
+/// short and the connection is re-opened transparently after failures.
+/// Reads run within the `DB_READ_*` budget and writes within the `CACHE_*`
+/// one: the connect and socket timeouts are the budget's timeout, and
+/// failed reads and idempotent writes are retried on a fresh connection.
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    client: redis::Client,
+    connection: Mutex<Option<redis::Connection>>,
+    budgets: Budgets,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    /// Creates a store for the given `redis://` URL without connecting yet
+    pub fn new(url: &str) -> AppResult<Self> {
+        let client = redis::Client::open(url)
+            .map_err(|e| AppError::config(format!("Invalid REDIS_URL: {}", e)))?;
+        Ok(Self {
+            client,
+            connection: Mutex::new(None),
+            budgets: Budgets::default(),
+        })
+    }
+
+    /// Applies the read and write budgets
+    pub fn with_budgets(mut self, budgets: Budgets) -> Self {
+        self.budgets = budgets;
+        self
+    }
+
+    fn with_connection<T>(
+        &self,
+        budget: &Budget,
+        op: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>,
+    ) -> AppResult<T> {
+        let mut guard = self
+            .connection
+            .lock()
+            .map_err(|_| AppError::internal("redis connection lock poisoned"))?;
+        if guard.is_none() {
+            let connection = self
+                .client
+                .get_connection_with_timeout(budget.timeout)
+                .map_err(|e| AppError::internal(format!("Redis connection failed: {}", e)))?;
+            *guard = Some(connection);
+        }
+
+        let connection = guard.as_mut().expect("connection initialized above");
+        let timeouts = connection
+            .set_read_timeout(Some(budget.timeout))
+            .and_then(|()| connection.set_write_timeout(Some(budget.timeout)));
+        timeouts.and_then(|()| op(connection)).map_err(|e| {
+            // Drop the connection so the next call reconnects
+            *guard = None;
+            if e.is_timeout() {
+                budget.timed_out()
+            } else {
+                AppError::internal(format!("Redis command failed: {}", e))
+            }
+        })
+    }
+}
+
+#[cfg(feature = "redis")]
+impl KeyValueStore for RedisStore {
+    fn get(&self, key: &str) -> AppResult<Option<String>> {
+        let budget = &self.budgets.db_read;
+        budget.retry(|| self.with_connection(budget, |conn| redis::cmd("GET").arg(key).query(conn)))
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<()> {
+        let budget = &self.budgets.cache;
+        budget.retry(|| {
+            self.with_connection(budget, |conn| {
+                let mut cmd = redis::cmd("SET");
+                cmd.arg(key).arg(value);
+                if let Some(ttl) = ttl {
+                    cmd.arg("PX").arg(ttl.as_millis() as u64);
+                }
+                cmd.query(conn)
+            })
+        })
+    }
+
+    // Not retried: a lost reply could hide a successful first attempt
+    fn set_if_absent(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<bool> {
+        self.with_connection(&self.budgets.cache, |conn| {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(key).arg(value).arg("NX");
+            if let Some(ttl) = ttl {
+                cmd.arg("PX").arg(ttl.as_millis() as u64);
+            }
+            let reply: Option<String> = cmd.query(conn)?;
+            Ok(reply.is_some())
+        })
+    }
+
+    fn delete(&self, key: &str) -> AppResult<bool> {
+        let budget = &self.budgets.cache;
+        budget.retry(|| {
+            self.with_connection(budget, |conn| {
+                let removed: u64 = redis::cmd("DEL").arg(key).query(conn)?;
+                Ok(removed > 0)
+            })
+        })
+    }
+
+    // Not retried: the counter may already have been incremented
+    fn increment(&self, key: &str, ttl: Option<Duration>) -> AppResult<u64> {
+        self.with_connection(&self.budgets.cache, |conn| {
+            let count: u64 = redis::cmd("INCR").arg(key).query(conn)?;
+            if count == 1 {
+                if let Some(ttl) = ttl {
+                    redis::cmd("PEXPIRE")
+                        .arg(key)
+                        .arg(ttl.as_millis() as u64)
+                        .query::<()>(conn)?;
+                }
+            }
+            Ok(count)
+        })
+    }
+
+    fn mode(&self) -> StateMode {
+        StateMode::Distributed
+    }
+}
+
+/// Store wrapper that prefixes every key with the owning component's name
+///
+/// Keeps components from colliding when they share one Redis database.
+struct NamespacedStore {
+    prefix: String,
+    inner: Arc<dyn KeyValueStore>,
+}
+
+impl NamespacedStore {
+    fn key(&self, key: &str) -> String {
+        format!("{}:{}", self.prefix, key)
+    }
+}
+
+impl KeyValueStore for NamespacedStore {
+    fn get(&self, key: &str) -> AppResult<Option<String>> {
+        self.inner.get(&self.key(key))
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<()> {
+        self.inner.set(&self.key(key), value, ttl)
+    }
+
+    fn set_if_absent(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<bool> {
+        self.inner.set_if_absent(&self.key(key), value, ttl)
+    }
+
+    fn delete(&self, key: &str) -> AppResult<bool> {
+        self.inner.delete(&self.key(key))
+    }
+
+    fn increment(&self, key: &str, ttl: Option<Duration>) -> AppResult<u64> {
+        self.inner.increment(&self.key(key), ttl)
+    }
+
+    fn mode(&self) -> StateMode {
+        self.inner.mode()
+    }
+}
+
+/// Hands out state stores to components and keeps track of who uses what
+///
+/// Every stateful component asks the manager for its store instead of
+/// creating a `HashMap` of its own, so switching to distributed mode is a
+/// configuration change rather than a code change.
+pub struct StateManager {
+    mode: StateMode,
+    shared: Arc<dyn KeyValueStore>,
+    /// The shared store when it lives in process memory, for backups
+    embedded: Option<Arc<InMemoryStore>>,
+    /// Times the queries of the component stores handed out
+    query_log: Option<Arc<SlowQueryLog>>,
+    components: Mutex<Vec<(String, StateMode)>>,
+}
+
+impl StateManager {
+    /// Creates a manager backed by the store selected in the configuration
+    ///
+    /// # Errors
+    /// Returns an AppError when distributed mode is requested without a Redis
+    /// URL or in a build without the `redis` feature.
+    pub fn from_config(config: &Config) -> AppResult<Self> {
+        // `STUB_DEPENDENCIES` stands in for Redis whatever the mode
+        let budgets = Budgets::from_config(config);
+        let manager = match config.state_mode {
+            _ if config.stub_dependencies => Self::with_store(Arc::new(
+                StubStore::new(FaultProfile::from_config(config)).with_budgets(budgets),
+            )),
+            StateMode::Local => Self::embedded(Arc::new(InMemoryStore::new())),
+            StateMode::Distributed => Self::with_store(Self::redis_store(config.redis_url.as_deref(), budgets)?),
+        };
+
+        Ok(manager.with_query_log(Arc::new(SlowQueryLog::from_config(config))))
+    }
+
+    /// Creates a manager around an in-memory store that can be backed up and restored
+    pub fn embedded(store: Arc<InMemoryStore>) -> Self {
+        Self {
+            embedded: Some(store.clone()),
+            ..Self::with_store(store)
+        }
+    }
+
+    /// Creates a manager around an existing store (useful for tests)
+    pub fn with_store(shared: Arc<dyn KeyValueStore>) -> Self {
+        Self {
+            mode: shared.mode(),
+            shared,
+            embedded: None,
+            query_log: None,
+            components: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Times the queries of every component store handed out from now on
+    pub fn with_query_log(mut self, log: Arc<SlowQueryLog>) -> Self {
+        self.query_log = Some(log);
+        self
+    }
+
+    /// Returns the log timing component store queries, if any
+    pub fn query_log(&self) -> Option<Arc<SlowQueryLog>> {
+        self.query_log.clone()
+    }
+
+    #[cfg(feature = "redis")]
+    fn redis_store(url: Option<&str>, budgets: Budgets) -> AppResult<Arc<dyn KeyValueStore>> {
+        let url = url.ok_or_else(|| {
+            AppError::config("STATE_MODE=distributed requires REDIS_URL to be set")
+        })?;
+        Ok(Arc::new(RedisStore::new(url)?.with_budgets(budgets)))
+    }
+
+    #[cfg(not(feature = "redis"))]
+    fn redis_store(_url: Option<&str>, _budgets: Budgets) -> AppResult<Arc<dyn KeyValueStore>> {
+        Err(AppError::config(
+            "STATE_MODE=distributed requires building with the `redis` feature",
+        ))
+    }
+
+    /// Returns the configured mode
+    pub fn mode(&self) -> StateMode {
+        self.mode
+    }
+
+    /// Returns the shared store when it lives in process memory
+    ///
+    /// Component stores obtained through [`StateManager::local_store`] are
+    /// separate and not included.
+    pub fn embedded_store(&self) -> Option<Arc<InMemoryStore>> {
+        self.embedded.clone()
+    }
+
+    /// Returns the shared store for `component`, namespaced by its name and
+    /// timed by the query log, if any
+    pub fn store(&self, component: &str) -> Arc<dyn KeyValueStore> {
+        self.register(component, self.mode);
+        let store: Arc<dyn KeyValueStore> = Arc::new(NamespacedStore {
+            prefix: component.to_string(),
+            inner: self.shared.clone(),
+        });
+        match &self.query_log {
+            Some(log) => log.instrument(component, store),
+            None => store,
+        }
+    }
+
+    /// Returns a process-local store for `component` regardless of mode
+    ///
+    /// For state that cannot be shared (e.g. non-serializable handles). The
+    /// component is reported by [`StateManager::warn_if_local_with_replicas`].
+    pub fn local_store(&self, component: &str) -> Arc<dyn KeyValueStore> {
+        self.register(component, StateMode::Local);
+        Arc::new(InMemoryStore::new())
+    }
+
+    fn register(&self, component: &str, mode: StateMode) {
+        if let Ok(mut components) = self.components.lock() {
+            if !components.iter().any(|(name, _)| name == component) {
+                components.push((component.to_string(), mode));
+            }
+        }
+    }
+
+    /// Returns the names of components whose state lives in process memory
+    pub fn local_components(&self) -> Vec<String> {
+        self.components
+            .lock()
+            .map(|components| {
+                components
+                    .iter()
+                    .filter(|(_, mode)| *mode == StateMode::Local)
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Logs a warning when more than one replica is declared but some state is local
+    ///
+    /// Returns the offending component names so callers can act on them.
+    pub fn warn_if_local_with_replicas(&self, replica_count: u16) -> Vec<String> {
+        let local = self.local_components();
+        if replica_count > 1 && !local.is_empty() {
+            warn!(
+                "REPLICA_COUNT={} but these components keep state in local memory and will diverge between replicas: {}",
+                replica_count,
+                local.join(", ")
+            );
+        } else {
+            info!("State backend: {} ({} component(s) registered)", self.mode, self.component_count());
+        }
+        local
+    }
+
+    fn component_count(&self) -> usize {
+        self.components.lock().map(|c| c.len()).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_mode_parsing() {
+        assert_eq!("local".parse::<StateMode>().unwrap(), StateMode::Local);
+        assert_eq!("Distributed".parse::<StateMode>().unwrap(), StateMode::Distributed);
+        assert!("cluster".parse::<StateMode>().is_err());
+    }
+
+    #[test]
+    fn test_in_memory_store_operations() {
+        let store = InMemoryStore::new();
+        store.set("a", "1", None).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some("1".to_string()));
+
+        assert!(!store.set_if_absent("a", "2", None).unwrap());
+        assert!(store.set_if_absent("b", "2", None).unwrap());
+
+        assert_eq!(store.increment("counter", None).unwrap(), 1);
+        assert_eq!(store.increment("counter", None).unwrap(), 2);
+
+        assert!(store.delete("a").unwrap());
+        assert_eq!(store.get("a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_in_memory_store_expiry() {
+        let store = InMemoryStore::new();
+        store.set("short", "x", Some(Duration::from_millis(0))).unwrap();
+        assert_eq!(store.get("short").unwrap(), None);
+        assert!(store.set_if_absent("short", "y", None).unwrap());
+    }
+
+    #[test]
+    fn test_namespaced_stores_do_not_collide() {
+        let manager = StateManager::with_store(Arc::new(InMemoryStore::new()));
+        let sessions = manager.store("sessions");
+        let cache = manager.store("cache");
+
+        sessions.set("key", "session", None).unwrap();
+        cache.set("key", "cached", None).unwrap();
+
+        assert_eq!(sessions.get("key").unwrap(), Some("session".to_string()));
+        assert_eq!(cache.get("key").unwrap(), Some("cached".to_string()));
+    }
+
+    #[test]
+    fn test_local_components_reported_with_replicas() {
+        let manager = StateManager::with_store(Arc::new(InMemoryStore::new()));
+        manager.store("rate_limit");
+        manager.store("rate_limit");
+        manager.local_store("cache");
+
+        assert_eq!(manager.warn_if_local_with_replicas(3), vec!["rate_limit", "cache"]);
+    }
+
+    #[cfg(not(feature = "redis"))]
+    #[test]
+    fn test_distributed_mode_requires_feature() {
+        let config = Config {
+            state_mode: StateMode::Distributed,
+            redis_url: Some("redis://localhost".to_string()),
+            ..Config::default()
+        };
+        assert!(StateManager::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_stubbed_dependencies_replace_redis() {
+        let config = Config {
+            state_mode: StateMode::Distributed,
+            redis_url: None,
+            stub_dependencies: true,
+            ..Config::default()
+        };
+        let manager = StateManager::from_config(&config).unwrap();
+        assert_eq!(manager.mode(), StateMode::Distributed);
+        let store = manager.store("sessions");
+        store.set("sid", "alice", None).unwrap();
+        assert_eq!(store.get("sid").unwrap(), Some("alice".to_string()));
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::rt::net::UdpSocket;
+use log::error;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::metrics_export::{MetricsExporter, MetricsSnapshot};
+use crate::supervisor::Supervisor;
+
+/// Largest datagram sent, below the usual 1500-byte MTU once the IP and UDP
+/// headers are added
+pub const MAX_PACKET_BYTES: usize = 1432;
+
+/// Line protocol spoken to the `STATSD_ADDR` agent, from `STATSD_FLAVOR`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsdFlavor {
+    /// DogStatsD: labels and `STATSD_TAGS` are sent as `|#name:value` tags
+    DogStatsd,
+    /// Plain StatsD, which has no tags: label values are appended to the
+    /// metric name and `STATSD_TAGS` are ignored
+    Plain,
+}
+
+impl fmt::Display for StatsdFlavor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StatsdFlavor::DogStatsd => "dogstatsd",
+            StatsdFlavor::Plain => "statsd",
+        })
+    }
+}
+
+impl FromStr for StatsdFlavor {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "dogstatsd" | "datadog" => Ok(StatsdFlavor::DogStatsd),
+            "statsd" | "plain" => Ok(StatsdFlavor::Plain),
+            other => Err(AppError::config(format!(
+                "unknown StatsD flavor '{}' (expected dogstatsd or statsd)",
+                other
+            ))),
+        }
+    }
+}
+
+/// Pushes the [`MetricsExporter`] snapshot to a StatsD or DogStatsD agent
+/// over UDP every `STATSD_INTERVAL_SECS`
+///
+/// Reads the same metrics as `/metrics` and `GET /admin/metrics.json`, so
+/// it works alongside a `routes=metrics` listener or instead of one.
+/// Counters are sent as the increase since the previous push (`|c`), gauges
+/// as their value (`|g`); the latency histogram becomes the `_count`, `_sum`
+/// and per-`le` `_bucket` counters of the OpenMetrics exposition. Datagrams
+/// are lost silently when no agent listens.
+pub struct StatsdExporter {
+    exporter: Arc<MetricsExporter>,
+    address: String,
+    flavor: StatsdFlavor,
+    prefix: Option<String>,
+    tags: Vec<String>,
+    /// Counter totals at the previous push, keyed by name and labels
+    previous: Mutex<HashMap<String, f64>>,
+}
+
+impl StatsdExporter {
+    /// Creates the exporter pushing to `address` (`host:port`)
+    pub fn new(exporter: Arc<MetricsExporter>, address: impl Into<String>, flavor: StatsdFlavor) -> Self {
+        Self {
+            exporter,
+            address: address.into(),
+            flavor,
+            prefix: None,
+            tags: Vec::new(),
+            previous: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Prepends `prefix.` to every metric name
+    pub fn with_prefix(mut self, prefix: Option<String>) -> Self {
+        self.prefix = prefix.filter(|prefix| !prefix.is_empty());
+        self
+    }
+
+    /// Adds `name:value` tags to every DogStatsD line
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Builds the exporter from `STATSD_ADDR`, `STATSD_FLAVOR`,
+    /// `STATSD_PREFIX` and `STATSD_TAGS`; `None` when no address is set
+    pub fn from_config(config: &Config, exporter: Arc<MetricsExporter>) -> Option<Self> {
+        let address = config.statsd_addr.as_deref()?;
+        Some(
+            Self::new(exporter, address, config.statsd_flavor)
+                .with_prefix(config.statsd_prefix.clone())
+                .with_tags(config.statsd_tags.clone()),
+        )
+    }
+
+    /// Renders `snapshot` as StatsD lines, counters relative to the
+    /// previous call
+    ///
+    /// Counters that did not move are left out; one that went down, e.g.
+    /// after its source was rebuilt, is sent from zero.
+    pub fn lines(&self, snapshot: &MetricsSnapshot) -> Vec<String> {
+        let Ok(mut previous) = self.previous.lock() else {
+            return Vec::new();
+        };
+        let mut lines = Vec::new();
+        let mut counter = |name: &str, labels: Vec<(&str, String)>, total: f64| {
+            let metric = self.metric(name, &labels);
+            let key = format!("{}{:?}", name, labels);
+            let last = previous.insert(key, total).unwrap_or(0.0);
+            let delta = if total >= last { total - last } else { total };
+            if delta > 0.0 {
+                lines.push(self.line(&metric, delta, "c", snapshot, &labels));
+            }
+        };
+        for sample in &snapshot.counters {
+            counter(sample.name, owned(&sample.labels), sample.value as f64);
+        }
+        for sample in &snapshot.histograms {
+            let histogram = &sample.value;
+            counter(&format!("{}_count", sample.name), owned(&sample.labels), histogram.count as f64);
+            counter(&format!("{}_sum", sample.name), owned(&sample.labels), histogram.sum);
+            for bucket in &histogram.buckets {
+                let mut labels = owned(&sample.labels);
+                labels.push(("le", bucket.le.map_or_else(|| "+Inf".to_string(), |le| le.to_string())));
+                counter(&format!("{}_bucket", sample.name), labels, bucket.count as f64);
+            }
+        }
+        for sample in &snapshot.gauges {
+            let labels = owned(&sample.labels);
+            let metric = self.metric(sample.name, &labels);
+            lines.push(self.line(&metric, sample.value, "g", snapshot, &labels));
+        }
+        lines
+    }
+
+    /// Sends the current snapshot; returns the number of lines sent
+    ///
+    /// # Errors
+    /// Internal error when the address cannot be resolved or a datagram
+    /// cannot be sent
+    pub async fn push(&self) -> AppResult<usize> {
+        let lines = self.lines(&self.exporter.snapshot());
+        if lines.is_empty() {
+            return Ok(0);
+        }
+        let agent = tokio::net::lookup_host(&self.address)
+            .await
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or_else(|| AppError::internal(format!("cannot resolve StatsD agent {}", self.address)))?;
+        let local = if agent.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local).await.map_err(AppError::internal)?;
+        socket.connect(agent).await.map_err(AppError::internal)?;
+        for packet in packets(&lines) {
+            socket.send(packet.as_bytes()).await.map_err(AppError::internal)?;
+        }
+        Ok(lines.len())
+    }
+
+    /// Pushes every `every` under `supervisor`
+    pub fn spawn_scheduler(exporter: Arc<Self>, every: Duration, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("statsd", move || {
+            let exporter = exporter.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(every);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = exporter.push().await {
+                        error!("StatsD push to {} failed: {}", exporter.address, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Metric name with the prefix and, for plain StatsD, the label values
+    fn metric(&self, name: &str, labels: &[(&str, String)]) -> String {
+        let mut metric = match &self.prefix {
+            Some(prefix) => format!("{}.{}", prefix, name),
+            None => name.to_string(),
+        };
+        if self.flavor == StatsdFlavor::Plain {
+            for (_, value) in labels {
+                metric.push('.');
+                metric.push_str(&sanitize(value, true));
+            }
+        }
+        metric
+    }
+
+    fn line(&self, metric: &str, value: f64, kind: &str, snapshot: &MetricsSnapshot, labels: &[(&str, String)]) -> String {
+        let mut line = format!("{}:{}|{}", metric, value, kind);
+        if self.flavor == StatsdFlavor::DogStatsd {
+            let tags: Vec<String> = self
+                .tags
+                .iter()
+                .map(|tag| sanitize(tag, false))
+                .chain(snapshot.labels.iter().map(|(name, value)| format!("{}:{}", name, sanitize(value, false))))
+                .chain(labels.iter().map(|(name, value)| format!("{}:{}", name, sanitize(value, false))))
+                .collect();
+            if !tags.is_empty() {
+                line.push_str("|#");
+                line.push_str(&tags.join(","));
+            }
+        }
+        line
+    }
+}
+
+fn owned<'a>(labels: &std::collections::BTreeMap<&'a str, String>) -> Vec<(&'a str, String)> {
+    labels.iter().map(|(name, value)| (*name, value.clone())).collect()
+}
+
+/// Replaces the characters the line protocol reserves; a segment of a
+/// plain StatsD name keeps only letters, digits, `-` and `_`
+fn sanitize(value: &str, segment: bool) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '|' | ',' | '#' | '@' | '\n' | ' ' => '_',
+            c if segment && !(c.is_ascii_alphanumeric() || c == '-' || c == '_') => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Joins `lines` with newlines into datagrams of at most [`MAX_PACKET_BYTES`],
+/// a longer line going alone in its own
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET_BYTES => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analytics::{AnalyticsPipeline, UsageEvent};
+    use crate::region::Placement;
+
+    fn request(pipeline: &AnalyticsPipeline, status: u16) {
+        let event = UsageEvent {
+            day: chrono::Utc::now().date_naive(),
+            route: "GET /".to_string(),
+            status,
+            user_id: None,
+        };
+        pipeline.observe(event, false);
+    }
+
+    fn exporter(flavor: StatsdFlavor) -> (Arc<AnalyticsPipeline>, StatsdExporter) {
+        let pipeline = Arc::new(AnalyticsPipeline::new(None));
+        let metrics = MetricsExporter::new(pipeline.clone())
+            .with_placement(Arc::new(Placement::new(Some("eu-west".to_string()), None)));
+        let exporter = StatsdExporter::new(Arc::new(metrics), "127.0.0.1:8125", flavor)
+            .with_prefix(Some("api".to_string()))
+            .with_tags(vec!["env:test".to_string()]);
+        (pipeline, exporter)
+    }
+
+    #[test]
+    fn test_counters_are_sent_as_increases() {
+        let (pipeline, exporter) = exporter(StatsdFlavor::DogStatsd);
+        request(&pipeline, 200);
+        request(&pipeline, 503);
+        let lines = exporter.lines(&exporter.exporter.snapshot());
+        assert!(lines.contains(&"api.http_requests_total:2|c|#env:test,region:eu-west".to_string()));
+        assert!(lines.contains(&"api.http_server_errors_total:1|c|#env:test,region:eu-west".to_string()));
+
+        request(&pipeline, 200);
+        let lines = exporter.lines(&exporter.exporter.snapshot());
+        assert!(lines.contains(&"api.http_requests_total:1|c|#env:test,region:eu-west".to_string()));
+        assert!(!lines.iter().any(|line| line.starts_with("api.http_server_errors_total")));
+    }
+
+    #[test]
+    fn test_plain_statsd_folds_labels_into_names() {
+        let (pipeline, exporter) = exporter(StatsdFlavor::Plain);
+        pipeline.observe_latency(Duration::from_millis(30), None);
+        let lines = exporter.lines(&exporter.exporter.snapshot());
+        assert!(lines.contains(&"api.http_request_duration_seconds_count:1|c".to_string()));
+        assert!(lines.contains(&"api.http_request_duration_seconds_bucket._Inf:1|c".to_string()));
+        assert!(lines.iter().all(|line| !line.contains('#')));
+    }
+
+    #[test]
+    fn test_flavor_and_packets() {
+        assert_eq!("Datadog".parse::<StatsdFlavor>().unwrap(), StatsdFlavor::DogStatsd);
+        assert_eq!("statsd".parse::<StatsdFlavor>().unwrap().to_string(), "statsd");
+        assert!("graphite".parse::<StatsdFlavor>().is_err());
+
+        let lines: Vec<String> = (0..100).map(|i| format!("metric_{:03}:{}|c", i, i)).collect();
+        let packets = packets(&lines);
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|packet| packet.len() <= MAX_PACKET_BYTES));
+        assert_eq!(packets.join("\n").lines().count(), 100);
+    }
+
+    #[actix_web::test]
+    async fn test_push_sends_datagrams() {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let pipeline = Arc::new(AnalyticsPipeline::new(None));
+        request(&pipeline, 200);
+        let exporter = StatsdExporter::new(
+            Arc::new(MetricsExporter::new(pipeline)),
+            agent.local_addr().unwrap().to_string(),
+            StatsdFlavor::DogStatsd,
+        );
+
+        let sent = exporter.push().await.unwrap();
+        assert!(sent > 0);
+        let mut datagram = [0u8; MAX_PACKET_BYTES];
+        let len = agent.recv(&mut datagram).await.unwrap();
+        let datagram = std::str::from_utf8(&datagram[..len]).unwrap();
+        assert!(datagram.lines().any(|line| line == "http_requests_total:1|c"));
+    }
+}
@@ -0,0 +1,259 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use log::debug;
+use rand::Rng;
+
+use crate::budgets::{Budget, Budgets};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::notifications::{Notification, Notifier};
+use crate::state::{InMemoryStore, KeyValueStore, StateMode};
+
+/// Notification channels replaced by fakes when `STUB_DEPENDENCIES` is set
+pub const STUBBED_CHANNELS: &[&str] = &["slack", "webhook", "email"];
+
+/// Latency and failure rate applied to every call of a stubbed dependency
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FaultProfile {
+    pub latency: Duration,
+    pub error_rate: f64,
+}
+
+impl FaultProfile {
+    /// Creates a profile; `error_rate` is clamped to `0..=1`
+    pub fn new(latency: Duration, error_rate: f64) -> Self {
+        Self {
+            latency,
+            error_rate: error_rate.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Builds the profile from `STUB_LATENCY_MS` and `STUB_ERROR_RATE`
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(Duration::from_millis(config.stub_latency_ms), config.stub_error_rate)
+    }
+
+    /// Draws whether the current call fails
+    ///
+    /// # Errors
+    /// Returns an unavailable error naming `dependency` for the failing share of calls
+    fn outcome(&self, dependency: &str) -> AppResult<()> {
+        if self.error_rate > 0.0 && rand::thread_rng().gen_bool(self.error_rate) {
+            debug!("Stubbed {} call failed by injection", dependency);
+            return Err(AppError::unavailable(format!("stubbed {} failure", dependency)));
+        }
+        Ok(())
+    }
+
+    /// Blocks for the configured latency, then draws the outcome
+    ///
+    /// A latency above the `budget` timeout blocks for the timeout and
+    /// fails, like a socket timeout would.
+    fn inject_blocking(&self, dependency: &str, budget: &Budget) -> AppResult<()> {
+        if self.latency > budget.timeout {
+            std::thread::sleep(budget.timeout);
+            return Err(budget.timed_out());
+        }
+        if !self.latency.is_zero() {
+            std::thread::sleep(self.latency);
+        }
+        self.outcome(dependency)
+    }
+
+    /// Waits for the configured latency, then draws the outcome
+    async fn inject(&self, dependency: &str) -> AppResult<()> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        self.outcome(dependency)
+    }
+}
+
+/// In-process stand-in for the Redis store
+///
+/// Keeps data in memory but behaves like a remote store: every call blocks
+/// for the profile's latency, as the synchronous Redis client does, and
+/// fails at its error rate, within the same budgets as [`RedisStore`].
+/// Reports [`StateMode::Distributed`] so the rest of the service runs
+/// exactly as it would against Redis.
+///
+/// [`RedisStore`]: crate::state::RedisStore
+#[derive(Debug, Default)]
+pub struct StubStore {
+    inner: InMemoryStore,
+    faults: FaultProfile,
+    budgets: Budgets,
+}
+
+impl StubStore {
+    /// Creates an empty store with the given faults
+    pub fn new(faults: FaultProfile) -> Self {
+        Self {
+            inner: InMemoryStore::new(),
+            faults,
+            budgets: Budgets::default(),
+        }
+    }
+
+    /// Applies the read and write budgets
+    pub fn with_budgets(mut self, budgets: Budgets) -> Self {
+        self.budgets = budgets;
+        self
+    }
+}
+
+impl KeyValueStore for StubStore {
+    fn get(&self, key: &str) -> AppResult<Option<String>> {
+        let budget = &self.budgets.db_read;
+        budget.retry(|| {
+            self.faults.inject_blocking("state store", budget)?;
+            self.inner.get(key)
+        })
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<()> {
+        let budget = &self.budgets.cache;
+        budget.retry(|| {
+            self.faults.inject_blocking("state store", budget)?;
+            self.inner.set(key, value, ttl)
+        })
+    }
+
+    fn set_if_absent(&self, key: &str, value: &str, ttl: Option<Duration>) -> AppResult<bool> {
+        self.faults.inject_blocking("state store", &self.budgets.cache)?;
+        self.inner.set_if_absent(key, value, ttl)
+    }
+
+    fn delete(&self, key: &str) -> AppResult<bool> {
+        let budget = &self.budgets.cache;
+        budget.retry(|| {
+            self.faults.inject_blocking("state store", budget)?;
+            self.inner.delete(key)
+        })
+    }
+
+    fn increment(&self, key: &str, ttl: Option<Duration>) -> AppResult<u64> {
+        self.faults.inject_blocking("state store", &self.budgets.cache)?;
+        self.inner.increment(key, ttl)
+    }
+
+    fn mode(&self) -> StateMode {
+        StateMode::Distributed
+    }
+}
+
+/// Notification channel that accepts deliveries without sending anything
+///
+/// Registered under the name of the real channel it replaces, so routing
+/// rules and rate limits apply unchanged.
+pub struct StubNotifier {
+    name: String,
+    faults: FaultProfile,
+    delivered: AtomicUsize,
+}
+
+impl StubNotifier {
+    /// Creates a fake of the channel `name`
+    pub fn new<T: Into<String>>(name: T, faults: FaultProfile) -> Self {
+        Self {
+            name: name.into(),
+            faults,
+            delivered: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns how many notifications were accepted
+    pub fn delivered(&self) -> usize {
+        self.delivered.load(Ordering::SeqCst)
+    }
+}
+
+impl Notifier for StubNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            self.faults.inject(&format!("{} channel", self.name)).await?;
+            self.delivered.fetch_add(1, Ordering::SeqCst);
+            debug!("Stubbed '{}' channel accepted {}", self.name, notification.event_type);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budgets::Backend;
+    use std::time::Instant;
+
+    #[test]
+    fn test_fault_profile_from_config() {
+        let config = Config {
+            stub_latency_ms: 40,
+            stub_error_rate: 0.25,
+            ..Config::default()
+        };
+        assert_eq!(
+            FaultProfile::from_config(&config),
+            FaultProfile::new(Duration::from_millis(40), 0.25)
+        );
+        assert_eq!(FaultProfile::new(Duration::ZERO, 3.0).error_rate, 1.0);
+    }
+
+    #[test]
+    fn test_stub_store_behaves_like_a_store() {
+        let store = StubStore::new(FaultProfile::default());
+        assert_eq!(store.mode(), StateMode::Distributed);
+        store.set("a", "1", None).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some("1".to_string()));
+        assert!(!store.set_if_absent("a", "2", None).unwrap());
+        assert_eq!(store.increment("n", None).unwrap(), 1);
+        assert_eq!(store.increment("n", None).unwrap(), 2);
+        assert!(store.delete("a").unwrap());
+    }
+
+    #[test]
+    fn test_stub_store_injects_latency_and_failures() {
+        let slow = StubStore::new(FaultProfile::new(Duration::from_millis(15), 0.0));
+        let started = Instant::now();
+        slow.set("a", "1", None).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(15));
+
+        let broken = StubStore::new(FaultProfile::new(Duration::ZERO, 1.0));
+        assert!(matches!(broken.get("a"), Err(AppError::Unavailable { .. })));
+        assert!(broken.increment("n", None).is_err());
+    }
+
+    #[test]
+    fn test_stub_store_applies_the_budgets() {
+        let budgets = Budgets {
+            db_read: Budget::new(Backend::DbRead, Duration::from_millis(10), 2),
+            ..Budgets::default()
+        };
+        let store = StubStore::new(FaultProfile::new(Duration::from_millis(200), 0.0)).with_budgets(budgets);
+        let started = Instant::now();
+        let err = store.get("a").unwrap_err();
+        assert!(err.to_string().contains("state store read timed out after 10ms"), "{}", err);
+        // Three attempts of 10ms, none of them waiting for the latency
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert!(started.elapsed() < Duration::from_millis(200));
+    }
+
+    #[actix_web::test]
+    async fn test_stub_notifier() {
+        let notification = Notification::new("user.created", "New user", "alice signed up");
+        let channel = StubNotifier::new("slack", FaultProfile::default());
+        channel.send(&notification).await.unwrap();
+        assert_eq!((channel.name(), channel.delivered()), ("slack", 1));
+
+        let failing = StubNotifier::new("webhook", FaultProfile::new(Duration::ZERO, 1.0));
+        let err = failing.send(&notification).await.unwrap_err();
+        assert!(err.to_string().contains("webhook channel"), "{}", err);
+        assert_eq!(failing.delivered(), 0);
+    }
+}
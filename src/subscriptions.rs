@@ -0,0 +1,820 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::budgets::Budgets;
+use crate::config::{AppEnv, Config};
+use crate::crypto::Cipher;
+use crate::error::{AppError, AppResult};
+use crate::filters::EventFilter;
+use crate::notifications::{encode_event, pattern_matches, send_request, DeliveryResponse, Notification, Notifier};
+use crate::state::KeyValueStore;
+
+/// Scope required to manage one's own webhook subscriptions
+pub const SUBSCRIPTIONS_SCOPE: &str = "webhooks";
+
+/// Header carrying `t=<unix ts>,v1=<hex hmac("<ts>.<body>")>` on subscription deliveries
+pub const SIGNATURE_HEADER: &str = "Webhook-Signature";
+
+/// Event type of the deliveries sent by `POST /webhooks/subscriptions/{id}/test`
+pub const TEST_EVENT_TYPE: &str = "webhook.test";
+
+/// Prefix of the channel name of a subscription, followed by its id
+pub const CHANNEL_PREFIX: &str = "subscription:";
+
+/// Prefix of subscription signing secrets
+const SECRET_PREFIX: &str = "whsec_";
+
+/// Maximum event type patterns per subscription
+const MAX_EVENT_TYPES: usize = 20;
+
+/// Maximum subscription URL length
+const MAX_URL_LENGTH: usize = 2048;
+
+/// Largest accepted batch size
+pub const MAX_BATCH_EVENTS: usize = 100;
+
+/// Longest accepted batch wait
+pub const MAX_BATCH_WAIT_MS: u64 = 60_000;
+
+/// Content type of batched deliveries
+pub const BATCH_CONTENT_TYPE: &str = "application/vnd.simple-api-demo.batch+json";
+
+/// How the body of a subscription's deliveries is encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    /// `Content-Encoding: gzip`; the signature covers the compressed body
+    Gzip,
+}
+
+/// Groups a subscription's events into one delivery
+///
+/// A batch goes out once it holds `max_events` events or its first event
+/// waited `max_wait_ms`, whichever comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchSettings {
+    pub max_events: usize,
+    pub max_wait_ms: u64,
+}
+
+impl BatchSettings {
+    /// How long the first event of a batch may wait
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_millis(self.max_wait_ms)
+    }
+}
+
+/// What an API consumer asks for when subscribing
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SubscriptionRequest {
+    pub url: String,
+    /// Event type patterns: `webhook.github`, `webhook.*` or `*`
+    pub event_types: Vec<String>,
+    /// Filter expression on the notification, see [`EventFilter`]
+    #[serde(default)]
+    pub filter: Option<String>,
+    /// Batch events instead of sending them one by one
+    #[serde(default)]
+    pub batch: Option<BatchSettings>,
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+/// A webhook endpoint registered by an API consumer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    /// `sub` of the user who registered it
+    pub owner: String,
+    pub url: String,
+    /// Event type patterns delivered, as in `NOTIFY_ROUTES`: `webhook.github`, `webhook.*` or `*`
+    pub event_types: Vec<String>,
+    /// Expression the notification must also pass, e.g. `data.repository.name == "api"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<EventFilter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<BatchSettings>,
+    #[serde(default)]
+    pub compression: Compression,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_rotated_at: Option<DateTime<Utc>>,
+}
+
+impl Subscription {
+    /// Channel name of the subscription's deliveries
+    pub fn channel(&self) -> String {
+        format!("{}{}", CHANNEL_PREFIX, self.id)
+    }
+
+    /// Whether `notification` is delivered to the subscription: its event
+    /// type matches a pattern and it passes the filter
+    ///
+    /// The filter sees the notification as JSON: `event_type`, `title`,
+    /// `message`, `data.*`.
+    pub fn matches(&self, notification: &Notification) -> bool {
+        self.event_types
+            .iter()
+            .any(|pattern| pattern_matches(pattern, &notification.event_type))
+            && self.filter.as_ref().is_none_or(|filter| {
+                serde_json::to_value(notification).is_ok_and(|event| filter.matches(&event))
+            })
+    }
+}
+
+/// A subscription as stored, with its encrypted secrets
+#[derive(Serialize, Deserialize)]
+struct StoredSubscription {
+    #[serde(flatten)]
+    subscription: Subscription,
+    secret: String,
+    /// Secret replaced by the last rotation, still signing until it expires
+    #[serde(default)]
+    previous_secret: Option<String>,
+    #[serde(default)]
+    previous_secret_expires_at: Option<DateTime<Utc>>,
+}
+
+/// Self-service webhook subscriptions
+///
+/// Each subscription receives the notifications whose event type matches one
+/// of its patterns, as a CloudEvent signed with its own secret. Secrets are
+/// shown once, when created or rotated, and stored encrypted with the
+/// data-at-rest [`Cipher`]; after a rotation deliveries carry a signature
+/// from both secrets for `WEBHOOK_SECRET_GRACE_SECS` so receivers can switch
+/// over. Keys: `subscription:{id}` (JSON), `owner:{sub}` and `all` (JSON
+/// arrays of subscription ids).
+pub struct SubscriptionRegistry {
+    store: Arc<dyn KeyValueStore>,
+    cipher: Cipher,
+    client: reqwest::Client,
+    secret_grace: Duration,
+    max_per_owner: usize,
+    allow_http: bool,
+}
+
+impl SubscriptionRegistry {
+    /// Creates a registry on `store`
+    ///
+    /// # Arguments
+    /// * `secret_grace` - How long a rotated secret keeps signing deliveries
+    /// * `max_per_owner` - Subscriptions each user may register
+    /// * `allow_http` - Whether plain `http://` URLs are accepted
+    pub fn new(
+        store: Arc<dyn KeyValueStore>,
+        cipher: Cipher,
+        client: reqwest::Client,
+        secret_grace: Duration,
+        max_per_owner: usize,
+        allow_http: bool,
+    ) -> Self {
+        Self {
+            store,
+            cipher,
+            client,
+            secret_grace,
+            max_per_owner,
+            allow_http,
+        }
+    }
+
+    /// Builds the registry from the `WEBHOOK_*` settings; only HTTPS URLs
+    /// are accepted in production
+    pub fn from_config(config: &Config, store: Arc<dyn KeyValueStore>) -> AppResult<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Budgets::from_config(config).webhook.timeout)
+            .build()
+            .map_err(|e| AppError::config(format!("Failed to build HTTP client: {}", e)))?;
+        Ok(Self::new(
+            store,
+            Cipher::from_config(config),
+            client,
+            Duration::from_secs(config.webhook_secret_grace_secs),
+            config.webhook_subscriptions_per_user,
+            config.app_env != AppEnv::Production,
+        ))
+    }
+
+    /// Registers an enabled subscription for `owner` and returns it with its secret
+    ///
+    /// # Errors
+    /// Validation error for a bad URL, event type list, filter or batch
+    /// settings, or when `owner` already has `WEBHOOK_SUBSCRIPTIONS_PER_USER`
+    /// subscriptions
+    pub fn create(&self, owner: &str, request: &SubscriptionRequest) -> AppResult<(Subscription, String)> {
+        let url = self.validate_url(&request.url)?;
+        let event_types = validate_event_types(&request.event_types)?;
+        let filter = request.filter.as_deref().map(str::parse::<EventFilter>).transpose()?;
+        if let Some(batch) = &request.batch {
+            if batch.max_events == 0 || batch.max_events > MAX_BATCH_EVENTS {
+                return Err(AppError::validation(format!(
+                    "batch.max_events must be between 1 and {}",
+                    MAX_BATCH_EVENTS
+                )));
+            }
+            if batch.max_wait_ms == 0 || batch.max_wait_ms > MAX_BATCH_WAIT_MS {
+                return Err(AppError::validation(format!(
+                    "batch.max_wait_ms must be between 1 and {}",
+                    MAX_BATCH_WAIT_MS
+                )));
+            }
+        }
+        let mut ids = self.ids(&owner_key(owner))?;
+        if ids.len() >= self.max_per_owner {
+            return Err(AppError::validation(format!(
+                "at most {} webhook subscriptions per user",
+                self.max_per_owner
+            )));
+        }
+
+        let secret = generate_secret();
+        let stored = StoredSubscription {
+            subscription: Subscription {
+                id: uuid::Uuid::new_v4().to_string(),
+                owner: owner.to_string(),
+                url,
+                event_types,
+                filter,
+                batch: request.batch,
+                compression: request.compression,
+                enabled: true,
+                created_at: Utc::now(),
+                secret_rotated_at: None,
+            },
+            secret: self.cipher.encrypt(secret.as_bytes())?,
+            previous_secret: None,
+            previous_secret_expires_at: None,
+        };
+        self.save(&stored)?;
+        ids.push(stored.subscription.id.clone());
+        self.save_ids(&owner_key(owner), &ids)?;
+        let mut all = self.ids("all")?;
+        all.push(stored.subscription.id.clone());
+        self.save_ids("all", &all)?;
+        Ok((stored.subscription, secret))
+    }
+
+    /// Lists `owner`'s subscriptions, newest first
+    pub fn list(&self, owner: &str) -> AppResult<Vec<Subscription>> {
+        let mut subscriptions = Vec::new();
+        for id in self.ids(&owner_key(owner))? {
+            if let Some(stored) = self.load(&id)? {
+                subscriptions.push(stored.subscription);
+            }
+        }
+        subscriptions.sort_by_key(|subscription| std::cmp::Reverse(subscription.created_at));
+        Ok(subscriptions)
+    }
+
+    /// Loads one of `owner`'s subscriptions
+    ///
+    /// # Errors
+    /// Not found if the subscription does not exist or belongs to someone else
+    pub fn get(&self, owner: &str, id: &str) -> AppResult<Subscription> {
+        Ok(self.owned(owner, id)?.subscription)
+    }
+
+    /// Deletes one of `owner`'s subscriptions
+    ///
+    /// # Errors
+    /// Not found if the subscription does not exist or belongs to someone else
+    pub fn delete(&self, owner: &str, id: &str) -> AppResult<()> {
+        self.owned(owner, id)?;
+        self.store.delete(&subscription_key(id))?;
+        for index in [owner_key(owner), "all".to_string()] {
+            let ids: Vec<String> = self.ids(&index)?.into_iter().filter(|other| other != id).collect();
+            self.save_ids(&index, &ids)?;
+        }
+        Ok(())
+    }
+
+    /// Enables or disables deliveries to one of `owner`'s subscriptions
+    ///
+    /// # Errors
+    /// Not found if the subscription does not exist or belongs to someone else
+    pub fn set_enabled(&self, owner: &str, id: &str, enabled: bool) -> AppResult<Subscription> {
+        let mut stored = self.owned(owner, id)?;
+        stored.subscription.enabled = enabled;
+        self.save(&stored)?;
+        Ok(stored.subscription)
+    }
+
+    /// Replaces the secret of one of `owner`'s subscriptions and returns the new one
+    ///
+    /// The current secret keeps signing deliveries, next to the new one, for
+    /// `WEBHOOK_SECRET_GRACE_SECS`.
+    ///
+    /// # Errors
+    /// Not found if the subscription does not exist or belongs to someone else
+    pub fn rotate_secret(&self, owner: &str, id: &str) -> AppResult<(Subscription, String)> {
+        let mut stored = self.owned(owner, id)?;
+        let secret = generate_secret();
+        let now = Utc::now();
+        stored.previous_secret = Some(std::mem::replace(&mut stored.secret, self.cipher.encrypt(secret.as_bytes())?));
+        stored.previous_secret_expires_at = Some(now + chrono::Duration::seconds(self.secret_grace.as_secs() as i64));
+        stored.subscription.secret_rotated_at = Some(now);
+        self.save(&stored)?;
+        Ok((stored.subscription, secret))
+    }
+
+    /// Channels of the enabled subscriptions receiving `notification`
+    pub fn matching(&self, notification: &Notification) -> AppResult<Vec<SubscriptionNotifier>> {
+        let mut notifiers = Vec::new();
+        for id in self.ids("all")? {
+            if let Some(stored) = self.load(&id)? {
+                if stored.subscription.enabled && stored.subscription.matches(notification) {
+                    notifiers.push(self.notifier_for(stored)?);
+                }
+            }
+        }
+        Ok(notifiers)
+    }
+
+    /// Channel of the subscription `id`, enabled or not
+    pub fn notifier(&self, id: &str) -> AppResult<Option<SubscriptionNotifier>> {
+        self.load(id)?.map(|stored| self.notifier_for(stored)).transpose()
+    }
+
+    fn notifier_for(&self, stored: StoredSubscription) -> AppResult<SubscriptionNotifier> {
+        let mut secrets = vec![self.decrypt(&stored.secret)?];
+        if let (Some(previous), Some(expires_at)) = (&stored.previous_secret, stored.previous_secret_expires_at) {
+            if expires_at > Utc::now() {
+                secrets.push(self.decrypt(previous)?);
+            }
+        }
+        Ok(SubscriptionNotifier {
+            client: self.client.clone(),
+            channel: stored.subscription.channel(),
+            subscription: stored.subscription,
+            secrets,
+        })
+    }
+
+    fn validate_url(&self, url: &str) -> AppResult<String> {
+        let url = url.trim();
+        if url.len() > MAX_URL_LENGTH {
+            return Err(AppError::validation(format!("url must be at most {} characters", MAX_URL_LENGTH)));
+        }
+        let parsed = reqwest::Url::parse(url).map_err(|e| AppError::validation(format!("invalid url: {}", e)))?;
+        match parsed.scheme() {
+            "https" => {}
+            "http" if self.allow_http => {}
+            _ => return Err(AppError::validation("url must use https")),
+        }
+        if parsed.host_str().is_none() {
+            return Err(AppError::validation("url must name a host"));
+        }
+        Ok(url.to_string())
+    }
+
+    fn owned(&self, owner: &str, id: &str) -> AppResult<StoredSubscription> {
+        self.load(id)?
+            .filter(|stored| stored.subscription.owner == owner)
+            .ok_or_else(|| AppError::not_found("webhook subscription not found"))
+    }
+
+    fn load(&self, id: &str) -> AppResult<Option<StoredSubscription>> {
+        self.store
+            .get(&subscription_key(id))?
+            .map(|raw| {
+                serde_json::from_str(&raw).map_err(|e| AppError::internal(format!("Corrupt subscription {}: {}", id, e)))
+            })
+            .transpose()
+    }
+
+    fn save(&self, stored: &StoredSubscription) -> AppResult<()> {
+        let json = serde_json::to_string(stored).map_err(|e| AppError::internal(e.to_string()))?;
+        self.store.set(&subscription_key(&stored.subscription.id), &json, None)
+    }
+
+    fn decrypt(&self, secret: &str) -> AppResult<String> {
+        String::from_utf8(self.cipher.decrypt(secret)?).map_err(|_| AppError::internal("corrupt subscription secret"))
+    }
+
+    fn ids(&self, key: &str) -> AppResult<Vec<String>> {
+        match self.store.get(key)? {
+            Some(raw) => serde_json::from_str(&raw).map_err(|e| AppError::internal(e.to_string())),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn save_ids(&self, key: &str, ids: &[String]) -> AppResult<()> {
+        let json = serde_json::to_string(ids).map_err(|e| AppError::internal(e.to_string()))?;
+        self.store.set(key, &json, None)
+    }
+}
+
+/// Posts notifications to one subscription, signed with its secrets
+pub struct SubscriptionNotifier {
+    client: reqwest::Client,
+    channel: String,
+    subscription: Subscription,
+    secrets: Vec<String>,
+}
+
+impl SubscriptionNotifier {
+    /// The subscription delivered to
+    pub fn subscription(&self) -> &Subscription {
+        &self.subscription
+    }
+}
+
+impl Notifier for SubscriptionNotifier {
+    fn name(&self) -> &str {
+        &self.channel
+    }
+
+    fn send<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move { self.deliver(notification).await.0 })
+    }
+
+    fn deliver<'a>(&'a self, notification: &'a Notification) -> BoxFuture<'a, (AppResult<()>, DeliveryResponse)> {
+        Box::pin(async move {
+            let body = encode_event(notification)
+                .and_then(|event| serde_json::to_vec(&event).map_err(|e| AppError::internal(e.to_string())));
+            self.post("application/cloudevents+json", body).await
+        })
+    }
+}
+
+impl SubscriptionNotifier {
+    /// Delivers `notifications` at once, as CloudEvents listed in a manifest
+    ///
+    /// The body is `{"manifest": {"batch_id", "subscription_id", "count",
+    /// "created_at", "events": [{"id", "type"}]}, "events": [...]}`.
+    pub async fn deliver_batch(&self, notifications: &[Notification]) -> (AppResult<()>, DeliveryResponse) {
+        let body = notifications
+            .iter()
+            .map(encode_event)
+            .collect::<AppResult<Vec<_>>>()
+            .and_then(|events| {
+                let listed: Vec<_> = events
+                    .iter()
+                    .map(|event| json!({ "id": event["id"], "type": event["type"] }))
+                    .collect();
+                let envelope = json!({
+                    "manifest": {
+                        "batch_id": uuid::Uuid::new_v4().to_string(),
+                        "subscription_id": self.subscription.id,
+                        "count": events.len(),
+                        "created_at": Utc::now(),
+                        "events": listed,
+                    },
+                    "events": events,
+                });
+                serde_json::to_vec(&envelope).map_err(|e| AppError::internal(e.to_string()))
+            });
+        self.post(BATCH_CONTENT_TYPE, body).await
+    }
+
+    /// Compresses, signs and posts `body`
+    async fn post(&self, content_type: &str, body: AppResult<Vec<u8>>) -> (AppResult<()>, DeliveryResponse) {
+        let body = match body.and_then(|body| self.compress(body)) {
+            Ok(body) => body,
+            Err(e) => return (Err(e), DeliveryResponse::default()),
+        };
+        let signature = signature_header(&self.secrets, Utc::now().timestamp(), &body);
+        let mut request = self
+            .client
+            .post(&self.subscription.url)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .header(SIGNATURE_HEADER, signature);
+        if self.subscription.compression == Compression::Gzip {
+            request = request.header(reqwest::header::CONTENT_ENCODING, "gzip");
+        }
+        send_request(request.body(body), &self.subscription.url).await
+    }
+
+    fn compress(&self, body: Vec<u8>) -> AppResult<Vec<u8>> {
+        match self.subscription.compression {
+            Compression::None => Ok(body),
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(&body)
+                    .and_then(|_| encoder.finish())
+                    .map_err(|e| AppError::internal(format!("Failed to compress delivery: {}", e)))
+            }
+        }
+    }
+}
+
+/// Builds `t=<ts>,v1=<sig>[,v1=<sig>]`, one signature per secret, in the
+/// format inbound Stripe-style webhooks are verified with
+pub fn signature_header(secrets: &[String], timestamp: i64, body: &[u8]) -> String {
+    let mut signed = format!("{}.", timestamp).into_bytes();
+    signed.extend_from_slice(body);
+    let mut header = format!("t={}", timestamp);
+    for secret in secrets {
+        header.push_str(",v1=");
+        header.push_str(&crate::webhooks::sign(secret, &signed));
+    }
+    header
+}
+
+fn validate_event_types(event_types: &[String]) -> AppResult<Vec<String>> {
+    let mut unique: Vec<String> = Vec::new();
+    for pattern in event_types.iter().map(|pattern| pattern.trim()) {
+        let prefix = pattern.strip_suffix('*').unwrap_or(pattern);
+        if pattern.is_empty() || prefix.contains('*') || pattern.chars().any(char::is_whitespace) {
+            return Err(AppError::validation(format!(
+                "event type '{}' must be an event type, a prefix ending in '*' or '*'",
+                pattern
+            )));
+        }
+        if !unique.iter().any(|other| other == pattern) {
+            unique.push(pattern.to_string());
+        }
+    }
+    if unique.is_empty() || unique.len() > MAX_EVENT_TYPES {
+        return Err(AppError::validation(format!(
+            "between 1 and {} event types are required",
+            MAX_EVENT_TYPES
+        )));
+    }
+    Ok(unique)
+}
+
+fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", SECRET_PREFIX, hex::encode(bytes))
+}
+
+fn subscription_key(id: &str) -> String {
+    format!("subscription:{}", id)
+}
+
+fn owner_key(owner: &str) -> String {
+    format!("owner:{}", owner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::InMemoryStore;
+    use crate::webhooks::{SignatureScheme, WebhookVerifier};
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+    fn registry(grace: Duration) -> SubscriptionRegistry {
+        SubscriptionRegistry::new(
+            Arc::new(InMemoryStore::new()),
+            Cipher::new(b"subscriptions-test-key-000000000"),
+            reqwest::Client::new(),
+            grace,
+            2,
+            false,
+        )
+    }
+
+    fn request(url: &str, patterns: &[&str]) -> SubscriptionRequest {
+        SubscriptionRequest {
+            url: url.to_string(),
+            event_types: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            ..SubscriptionRequest::default()
+        }
+    }
+
+    #[test]
+    fn test_create_list_and_delete() {
+        let registry = registry(Duration::from_secs(60));
+        let (created, secret) = registry
+            .create("alice", &request("https://hooks.example.com/in", &["webhook.*", "webhook.*"]))
+            .unwrap();
+        assert!(secret.starts_with(SECRET_PREFIX));
+        assert_eq!(created.event_types, vec!["webhook.*"]);
+        assert!(created.enabled);
+
+        assert_eq!(registry.list("alice").unwrap(), vec![created.clone()]);
+        assert!(registry.list("bob").unwrap().is_empty());
+        assert!(matches!(registry.get("bob", &created.id), Err(AppError::NotFound { .. })));
+        assert!(matches!(registry.delete("bob", &created.id), Err(AppError::NotFound { .. })));
+
+        registry.create("alice", &request("https://hooks.example.com/2", &["*"])).unwrap();
+        assert!(registry.create("alice", &request("https://hooks.example.com/3", &["*"])).is_err());
+        registry.delete("alice", &created.id).unwrap();
+        assert_eq!(registry.list("alice").unwrap().len(), 1);
+        assert!(registry.notifier(&created.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_validation() {
+        let registry = registry(Duration::from_secs(60));
+        for url in ["http://hooks.example.com", "ftp://hooks.example.com", "not a url"] {
+            assert!(registry.create("alice", &request(url, &["*"])).is_err(), "{}", url);
+        }
+        for patterns in [&[][..], &[""], &["web*hook"], &["a b"]] {
+            assert!(registry.create("alice", &request("https://hooks.example.com", patterns)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_matching_skips_disabled_subscriptions() {
+        let registry = registry(Duration::from_secs(60));
+        let (github, _) = registry
+            .create("alice", &request("https://hooks.example.com/gh", &["webhook.github"]))
+            .unwrap();
+        let (everything, _) = registry.create("bob", &request("https://hooks.example.com/all", &["*"])).unwrap();
+        let channels = |event_type: &str| -> Vec<String> {
+            registry
+                .matching(&Notification::new(event_type, "", ""))
+                .unwrap()
+                .iter()
+                .map(|notifier| notifier.name().to_string())
+                .collect()
+        };
+        assert_eq!(channels("webhook.github"), vec![github.channel(), everything.channel()]);
+        assert_eq!(channels("webhook.stripe"), vec![everything.channel()]);
+
+        assert!(!registry.set_enabled("bob", &everything.id, false).unwrap().enabled);
+        assert!(channels("webhook.stripe").is_empty());
+        assert!(registry.notifier(&everything.id).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_filters_narrow_event_types() {
+        let registry = registry(Duration::from_secs(60));
+        let filtered = SubscriptionRequest {
+            filter: Some("data.repository == 'api' AND NOT data.draft == true".to_string()),
+            ..request("https://hooks.example.com/api", &["webhook.*"])
+        };
+        let (api, _) = registry.create("alice", &filtered).unwrap();
+        assert_eq!(api.filter.as_ref().unwrap().source(), "data.repository == 'api' AND NOT data.draft == true");
+
+        let push = |data: serde_json::Value| Notification::new("webhook.github", "Push", "").with_data(data);
+        assert!(api.matches(&push(serde_json::json!({ "repository": "api" }))));
+        assert!(!api.matches(&push(serde_json::json!({ "repository": "api", "draft": true }))));
+        assert!(!api.matches(&push(serde_json::json!({ "repository": "web" }))));
+        assert_eq!(registry.matching(&push(serde_json::json!({ "repository": "web" }))).unwrap().len(), 0);
+
+        let invalid = SubscriptionRequest {
+            filter: Some("data.repository =".to_string()),
+            ..request("https://hooks.example.com", &["*"])
+        };
+        assert!(matches!(registry.create("alice", &invalid), Err(AppError::Validation { .. })));
+    }
+
+    #[test]
+    fn test_rotation_signs_with_both_secrets_during_grace() {
+        let registry = registry(Duration::from_secs(60));
+        let (created, old) = registry.create("alice", &request("https://hooks.example.com", &["*"])).unwrap();
+        let (rotated, new) = registry.rotate_secret("alice", &created.id).unwrap();
+        assert_ne!(old, new);
+        assert!(rotated.secret_rotated_at.is_some());
+        assert_eq!(registry.notifier(&created.id).unwrap().unwrap().secrets, vec![new.clone(), old.clone()]);
+
+        // Receivers verify with either secret
+        let body = br#"{"type":"webhook.test"}"#;
+        let header = signature_header(&[new.clone(), old.clone()], Utc::now().timestamp(), body);
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static("stripe-signature"), HeaderValue::from_str(&header).unwrap());
+        for secret in [&old, &new] {
+            let verifier = WebhookVerifier::new(Duration::from_secs(300)).with_provider("sub", SignatureScheme::Stripe, secret);
+            assert!(verifier.verify("sub", &headers, body).is_ok());
+        }
+
+        let expired = self::registry(Duration::ZERO);
+        let (created, _) = expired.create("alice", &request("https://hooks.example.com", &["*"])).unwrap();
+        let (_, new) = expired.rotate_secret("alice", &created.id).unwrap();
+        assert_eq!(expired.notifier(&created.id).unwrap().unwrap().secrets, vec![new]);
+    }
+
+    #[actix_web::test]
+    async fn test_router_delivers_signed_events_to_subscriptions() {
+        use crate::notifications::NotificationRouter;
+        use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+        use std::sync::Mutex;
+
+        let received: web::Data<Mutex<Vec<(String, web::Bytes)>>> = web::Data::new(Mutex::new(Vec::new()));
+        let data = received.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(data.clone()).route(
+                "/hook",
+                web::post().to(
+                    |req: HttpRequest, body: web::Bytes, received: web::Data<Mutex<Vec<(String, web::Bytes)>>>| async move {
+                        let signature = req.headers().get(SIGNATURE_HEADER).unwrap().to_str().unwrap().to_string();
+                        received.lock().unwrap().push((signature, body));
+                        HttpResponse::NoContent().finish()
+                    },
+                ),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        let registry = Arc::new(SubscriptionRegistry::new(
+            Arc::new(InMemoryStore::new()),
+            Cipher::new(b"subscriptions-test-key-000000000"),
+            reqwest::Client::new(),
+            Duration::from_secs(60),
+            10,
+            true,
+        ));
+        let (subscription, secret) = registry.create("alice", &request(&url, &["webhook.*"])).unwrap();
+        let router = NotificationRouter::default().with_subscriptions(registry.clone());
+
+        let report = router.notify(&Notification::new("webhook.github", "Push", "main updated")).await;
+        assert_eq!(report.delivered, vec![subscription.channel()]);
+        assert!(router.notify(&Notification::new("user.created", "Hi", "")).await.delivered.is_empty());
+
+        let (signature, body) = received.lock().unwrap()[0].clone();
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static("stripe-signature"), HeaderValue::from_str(&signature).unwrap());
+        let verifier = WebhookVerifier::new(Duration::from_secs(300)).with_provider("sub", SignatureScheme::Stripe, &secret);
+        assert!(verifier.verify("sub", &headers, &body).is_ok());
+
+        // Disabled subscriptions only receive test deliveries
+        registry.set_enabled("alice", &subscription.id, false).unwrap();
+        assert!(router.notify(&Notification::new("webhook.github", "Push", "")).await.delivered.is_empty());
+        let attempt = router.send_test("alice", &subscription.id).await.unwrap();
+        assert_eq!((attempt.ok, attempt.status), (true, Some(204)));
+        assert!(router.send_test("bob", &subscription.id).await.is_err());
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_router_batches_and_compresses_deliveries() {
+        use crate::notifications::NotificationRouter;
+        use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+        use std::sync::Mutex;
+
+        let received: web::Data<Mutex<Vec<serde_json::Value>>> = web::Data::new(Mutex::new(Vec::new()));
+        let data = received.clone();
+        let server = HttpServer::new(move || {
+            App::new().app_data(data.clone()).route(
+                "/hook",
+                web::post().to(|req: HttpRequest, body: web::Bytes, received: web::Data<Mutex<Vec<serde_json::Value>>>| async move {
+                    assert_eq!(req.headers().get("content-encoding").unwrap(), "gzip");
+                    assert_eq!(req.headers().get("content-type").unwrap(), BATCH_CONTENT_TYPE);
+                    // actix inflates the body according to Content-Encoding
+                    received.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                    HttpResponse::NoContent().finish()
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}/hook", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        let registry = Arc::new(SubscriptionRegistry::new(
+            Arc::new(InMemoryStore::new()),
+            Cipher::new(b"subscriptions-test-key-000000000"),
+            reqwest::Client::new(),
+            Duration::from_secs(60),
+            10,
+            true,
+        ));
+        let batched = SubscriptionRequest {
+            batch: Some(BatchSettings { max_events: 2, max_wait_ms: 60_000 }),
+            compression: Compression::Gzip,
+            ..request(&url, &["webhook.*"])
+        };
+        let (subscription, _) = registry.create("alice", &batched).unwrap();
+        let router = NotificationRouter::default().with_subscriptions(registry.clone());
+
+        let report = router.notify(&Notification::new("webhook.github", "Push", "")).await;
+        assert_eq!(report.batched, vec![subscription.channel()]);
+        assert!(received.lock().unwrap().is_empty());
+        let report = router.notify(&Notification::new("webhook.stripe", "Charge", "")).await;
+        assert_eq!(report.delivered, vec![subscription.channel()]);
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0]["manifest"]["count"], 2);
+            assert_eq!(received[0]["manifest"]["subscription_id"], subscription.id.as_str());
+            assert_eq!(received[0]["manifest"]["events"][1]["type"], "com.simple-api-demo.notification.webhook.stripe");
+            assert_eq!(received[0]["events"].as_array().unwrap().len(), 2);
+        }
+
+        // A partial batch waits for its deadline unless forced out
+        router.notify(&Notification::new("webhook.github", "Push", "")).await;
+        assert_eq!(router.flush_batches(false).await, 0);
+        assert_eq!(router.flush_batches(true).await, 1);
+        assert_eq!(received.lock().unwrap()[1]["manifest"]["count"], 1);
+
+        assert!(registry
+            .create(
+                "alice",
+                &SubscriptionRequest {
+                    batch: Some(BatchSettings { max_events: MAX_BATCH_EVENTS + 1, max_wait_ms: 1000 }),
+                    ..request(&url, &["*"])
+                }
+            )
+            .is_err());
+    }
+}
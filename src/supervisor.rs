@@ -0,0 +1,343 @@
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::error::AppResult;
+
+/// How crashed background tasks are restarted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Delay before the first restart, doubled after each crash
+    pub initial_backoff: Duration,
+    /// Upper bound of the restart delay
+    pub max_backoff: Duration,
+    /// Restarts allowed within `window` before the task is given up
+    pub max_restarts: usize,
+    /// Window restart storms are counted over; a run lasting this long
+    /// resets the backoff and the consecutive crash count
+    pub window: Duration,
+    /// Consecutive crashes after which the task is reported degraded
+    pub degraded_after: u32,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            max_restarts: 10,
+            window: Duration::from_secs(300),
+            degraded_after: 3,
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Creates the policy from the `SUPERVISOR_*` settings
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(config.supervisor_backoff_ms),
+            max_backoff: Duration::from_secs(config.supervisor_max_backoff_secs),
+            max_restarts: config.supervisor_max_restarts,
+            window: Duration::from_secs(config.supervisor_restart_window_secs),
+            degraded_after: config.supervisor_degraded_after.max(1),
+        }
+    }
+}
+
+/// Lifecycle state of a supervised task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// Running, or restarting after isolated crashes
+    Running,
+    /// Crashed repeatedly and was restarted too recently to be trusted
+    Degraded,
+    /// Given up after exceeding the restart limit
+    Failed,
+    /// Finished on its own
+    Stopped,
+}
+
+/// Health of a supervised task, as reported by `/metrics`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskHealth {
+    pub status: TaskStatus,
+    /// Restarts since the process started
+    pub restarts: u64,
+    pub consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+struct TaskState {
+    restarts: u64,
+    consecutive_failures: u32,
+    recent_restarts: VecDeque<Instant>,
+    started_at: Instant,
+    last_error: Option<String>,
+    gave_up: bool,
+    finished: bool,
+}
+
+impl TaskState {
+    fn new() -> Self {
+        Self {
+            restarts: 0,
+            consecutive_failures: 0,
+            recent_restarts: VecDeque::new(),
+            started_at: Instant::now(),
+            last_error: None,
+            gave_up: false,
+            finished: false,
+        }
+    }
+
+    fn status(&self, policy: &RestartPolicy) -> TaskStatus {
+        if self.finished {
+            TaskStatus::Stopped
+        } else if self.gave_up {
+            TaskStatus::Failed
+        } else if self.consecutive_failures >= policy.degraded_after && self.started_at.elapsed() < policy.window {
+            TaskStatus::Degraded
+        } else {
+            TaskStatus::Running
+        }
+    }
+}
+
+/// Runs long-lived background tasks and restarts them when they crash
+///
+/// A task crashes when its future panics or resolves to an error. It is
+/// restarted after an exponential backoff until it crashes more than
+/// [`RestartPolicy::max_restarts`] times within the window, after which it
+/// is given up. Degraded and failed tasks make `/ready` answer 503.
+#[derive(Default)]
+pub struct Supervisor {
+    policy: RestartPolicy,
+    tasks: Mutex<BTreeMap<String, TaskState>>,
+}
+
+impl Supervisor {
+    /// Creates a supervisor applying `policy`
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            tasks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Creates a supervisor from the `SUPERVISOR_*` settings
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(RestartPolicy::from_config(config))
+    }
+
+    /// Runs the task created by `factory` on the current runtime under supervision
+    ///
+    /// `factory` is called again for every restart.
+    pub fn spawn<F, Fut>(self: &Arc<Self>, name: &str, factory: F)
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = AppResult<()>> + 'static,
+    {
+        self.update(name, |_| ());
+        let supervisor = self.clone();
+        let name = name.to_string();
+        actix_web::rt::spawn(async move { supervisor.run(&name, factory).await });
+    }
+
+    async fn run<F, Fut>(&self, name: &str, factory: F)
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = AppResult<()>> + 'static,
+    {
+        let mut backoff = self.policy.initial_backoff;
+        loop {
+            let started = Instant::now();
+            let reason = match actix_web::rt::spawn(factory()).await {
+                Ok(Ok(())) => {
+                    self.update(name, |state| state.finished = true);
+                    info!("Background task '{}' finished", name);
+                    return;
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(e) if e.is_panic() => panic_message(e.into_panic()),
+                // The runtime is shutting down
+                Err(_) => return,
+            };
+
+            if started.elapsed() >= self.policy.window {
+                backoff = self.policy.initial_backoff;
+            }
+            if !self.record_crash(name, started, reason.clone()) {
+                error!(
+                    "Background task '{}' crashed {} times within {:?}, giving up: {}",
+                    name,
+                    self.policy.max_restarts + 1,
+                    self.policy.window,
+                    reason
+                );
+                return;
+            }
+            warn!("Background task '{}' crashed, restarting in {:?}: {}", name, backoff, reason);
+            actix_web::rt::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.policy.max_backoff);
+            self.update(name, |state| {
+                let now = Instant::now();
+                state.restarts += 1;
+                state.recent_restarts.push_back(now);
+                state.started_at = now;
+            });
+        }
+    }
+
+    /// Records a crash; returns whether the task may be restarted
+    fn record_crash(&self, name: &str, started: Instant, reason: String) -> bool {
+        let window = self.policy.window;
+        let max_restarts = self.policy.max_restarts;
+        let mut allowed = true;
+        self.update(name, |state| {
+            if started.elapsed() >= window {
+                state.consecutive_failures = 0;
+            }
+            state.consecutive_failures += 1;
+            state.last_error = Some(reason);
+            state.recent_restarts.retain(|at| at.elapsed() < window);
+            if state.recent_restarts.len() >= max_restarts {
+                state.gave_up = true;
+                allowed = false;
+            }
+        });
+        allowed
+    }
+
+    fn update(&self, name: &str, apply: impl FnOnce(&mut TaskState)) {
+        if let Ok(mut tasks) = self.tasks.lock() {
+            apply(tasks.entry(name.to_string()).or_insert_with(TaskState::new));
+        }
+    }
+
+    /// Returns the health of every supervised task, by name
+    pub fn health(&self) -> BTreeMap<String, TaskHealth> {
+        let Ok(tasks) = self.tasks.lock() else {
+            return BTreeMap::new();
+        };
+        tasks
+            .iter()
+            .map(|(name, state)| {
+                let health = TaskHealth {
+                    status: state.status(&self.policy),
+                    restarts: state.restarts,
+                    consecutive_failures: state.consecutive_failures,
+                    last_error: state.last_error.clone(),
+                };
+                (name.clone(), health)
+            })
+            .collect()
+    }
+
+    /// Returns the names of the degraded or failed tasks
+    pub fn unhealthy(&self) -> Vec<String> {
+        self.health()
+            .into_iter()
+            .filter(|(_, health)| matches!(health.status, TaskStatus::Degraded | TaskStatus::Failed))
+            .map(|(name, _)| name)
+            .collect()
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .map(|message| format!("panicked: {}", message))
+        .unwrap_or_else(|| "panicked".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AppError;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn supervisor() -> Arc<Supervisor> {
+        Arc::new(Supervisor::new(RestartPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+            degraded_after: 2,
+        }))
+    }
+
+    #[actix_web::test]
+    async fn test_restart_storm_gives_up() {
+        let supervisor = supervisor();
+        let runs = Arc::new(AtomicU32::new(0));
+        let counter = runs.clone();
+        supervisor.spawn("scheduler", move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { panic!("boom") }
+        });
+
+        // Restarts take a few milliseconds, but the runtime may be busy with other tests
+        for _ in 0..100 {
+            if supervisor.health()["scheduler"].status == TaskStatus::Failed {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let health = &supervisor.health()["scheduler"];
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+        assert_eq!(health.status, TaskStatus::Failed);
+        assert_eq!(health.restarts, 3);
+        assert_eq!(health.last_error.as_deref(), Some("panicked: boom"));
+        assert_eq!(supervisor.unhealthy(), vec!["scheduler".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn test_repeated_failures_mark_task_degraded() {
+        let supervisor = supervisor();
+        let runs = Arc::new(AtomicU32::new(0));
+        let counter = runs.clone();
+        supervisor.spawn("consumer", move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run < 2 {
+                    return Err(AppError::internal("connection lost"));
+                }
+                std::future::pending::<()>().await;
+                Ok(())
+            }
+        });
+        supervisor.spawn("oneshot", || async { Ok(()) });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let health = supervisor.health();
+        assert_eq!(health["consumer"].status, TaskStatus::Degraded);
+        assert_eq!(health["consumer"].restarts, 2);
+        assert_eq!(health["consumer"].consecutive_failures, 2);
+        assert_eq!(health["oneshot"].status, TaskStatus::Stopped);
+        assert_eq!(supervisor.unhealthy(), vec!["consumer".to_string()]);
+    }
+
+    #[test]
+    fn test_policy_from_config() {
+        let config = Config {
+            supervisor_backoff_ms: 250,
+            supervisor_degraded_after: 0,
+            ..Config::default()
+        };
+        let policy = RestartPolicy::from_config(&config);
+        assert_eq!(policy.initial_backoff, Duration::from_millis(250));
+        assert_eq!(policy.degraded_after, 1);
+        assert_eq!(policy.max_restarts, 10);
+    }
+}
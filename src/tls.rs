@@ -0,0 +1,302 @@
+use std::any::Any;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::{Extensions, Payload};
+use actix_web::rt::net::TcpStream;
+use actix_web::{FromRequest, HttpRequest};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use serde::Serialize;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::error::{AppError, AppResult};
+
+/// Whether a TLS listener asks clients for a certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientAuth {
+    /// Handshakes without a certificate signed by the client CA fail
+    #[default]
+    Required,
+    /// Clients may connect without a certificate; those presenting one must
+    /// present a valid one
+    Optional,
+}
+
+impl fmt::Display for ClientAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClientAuth::Required => "required",
+            ClientAuth::Optional => "optional",
+        })
+    }
+}
+
+impl FromStr for ClientAuth {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "required" => Ok(ClientAuth::Required),
+            "optional" => Ok(ClientAuth::Optional),
+            other => Err(AppError::config(format!(
+                "unknown client auth mode '{}' (expected required or optional)",
+                other
+            ))),
+        }
+    }
+}
+
+/// TLS settings of one listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsSettings {
+    /// PEM certificate chain presented by the server
+    pub cert_path: String,
+    /// PEM private key of the server certificate
+    pub key_path: String,
+    /// PEM bundle of the CAs client certificates must chain to; client
+    /// certificates are not requested when unset
+    pub client_ca_path: Option<String>,
+    pub client_auth: ClientAuth,
+}
+
+/// Builds the rustls configuration of a TLS listener
+///
+/// # Errors
+/// Returns a config error naming the file when a certificate, key or CA
+/// bundle cannot be read or is invalid, and naming both when the key does
+/// not match the certificate
+pub fn server_config(settings: &TlsSettings) -> AppResult<ServerConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| AppError::config(format!("Invalid TLS protocol versions: {}", e)))?;
+
+    let builder = match &settings.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| AppError::config(format!("Invalid client CA certificate in {}: {}", path, e)))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match settings.client_auth {
+                ClientAuth::Required => verifier,
+                ClientAuth::Optional => verifier.allow_unauthenticated(),
+            };
+            let verifier = verifier
+                .build()
+                .map_err(|e| AppError::config(format!("Invalid client CA bundle {}: {}", path, e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let key = PrivateKeyDer::from_pem_file(&settings.key_path)
+        .map_err(|e| AppError::config(format!("Failed to read TLS key {}: {}", settings.key_path, e)))?;
+    builder
+        .with_single_cert(load_certs(&settings.cert_path)?, key)
+        .map_err(|e| match e {
+            rustls::Error::InconsistentKeys(_) => AppError::config(format!(
+                "TLS key {} does not belong to the certificate {}",
+                settings.key_path, settings.cert_path
+            )),
+            e => AppError::config(format!("Invalid TLS certificate {}: {}", settings.cert_path, e)),
+        })
+}
+
+fn load_certs(path: &str) -> AppResult<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| AppError::config(format!("Failed to read certificates from {}: {}", path, e)))?;
+    if certs.is_empty() {
+        return Err(AppError::config(format!("No certificate found in {}", path)));
+    }
+    Ok(certs)
+}
+
+/// Certificate a client authenticated the TLS connection with
+///
+/// Extracting it in a handler requires a connection made with a verified
+/// client certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientCertificate {
+    /// Subject distinguished name, e.g. `CN=billing-service, O=Example`
+    pub subject: String,
+    /// Subject common name, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub common_name: Option<String>,
+    pub issuer: String,
+    /// Serial number in hexadecimal
+    pub serial: String,
+}
+
+impl ClientCertificate {
+    /// Parses a DER-encoded certificate
+    ///
+    /// # Errors
+    /// Returns a validation error when `der` is not an X.509 certificate
+    pub fn from_der(der: &[u8]) -> AppResult<Self> {
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|e| AppError::validation(format!("invalid client certificate: {}", e)))?;
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string);
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            common_name,
+            issuer: cert.issuer().to_string(),
+            serial: cert.raw_serial_as_string().replace(':', ""),
+        })
+    }
+}
+
+/// Negotiated parameters of a TLS connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TlsConnection {
+    /// Protocol version, e.g. `TLSv1_3`
+    pub protocol: String,
+    /// Cipher suite, e.g. `TLS13_AES_256_GCM_SHA384`
+    pub cipher_suite: String,
+    /// Host name the client asked for with SNI
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+}
+
+/// Connection hook of TLS listeners, for use with `HttpServer::on_connect`
+///
+/// Stores the [`TlsConnection`] parameters and, for connections
+/// authenticated with one, the [`ClientCertificate`] in the connection data.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let session = stream.get_ref().1;
+    let name = |value: Option<&'static str>| value.unwrap_or("unknown").to_string();
+    data.insert(TlsConnection {
+        protocol: name(session.protocol_version().and_then(|version| version.as_str())),
+        cipher_suite: name(session.negotiated_cipher_suite().and_then(|suite| suite.suite().as_str())),
+        server_name: session.server_name().map(str::to_string),
+    });
+    let leaf = session.peer_certificates().and_then(|certs| certs.first());
+    if let Some(certificate) = leaf.and_then(|der| ClientCertificate::from_der(der).ok()) {
+        data.insert(certificate);
+    }
+}
+
+impl FromRequest for ClientCertificate {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.conn_data::<ClientCertificate>()
+                .cloned()
+                .ok_or_else(|| AppError::unauthorized("client certificate required")),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+
+    fn fixture(name: &str) -> String {
+        format!("{}/{}", FIXTURES, name)
+    }
+
+    fn settings() -> TlsSettings {
+        TlsSettings {
+            cert_path: fixture("server.pem"),
+            key_path: fixture("server-key.pem"),
+            client_ca_path: Some(fixture("ca.pem")),
+            client_auth: ClientAuth::Required,
+        }
+    }
+
+    #[test]
+    fn test_client_auth_parsing() {
+        assert_eq!(" Optional ".parse::<ClientAuth>().unwrap(), ClientAuth::Optional);
+        assert_eq!("required".parse::<ClientAuth>().unwrap().to_string(), "required");
+        assert!("sometimes".parse::<ClientAuth>().is_err());
+    }
+
+    #[test]
+    fn test_server_config_loads_fixtures() {
+        assert!(server_config(&settings()).is_ok());
+        let optional = TlsSettings {
+            client_auth: ClientAuth::Optional,
+            ..settings()
+        };
+        assert!(server_config(&optional).is_ok());
+        let without_clients = TlsSettings {
+            client_ca_path: None,
+            ..settings()
+        };
+        assert!(server_config(&without_clients).is_ok());
+
+        let missing_ca = TlsSettings {
+            client_ca_path: Some(fixture("missing.pem")),
+            ..settings()
+        };
+        let err = server_config(&missing_ca).unwrap_err();
+        assert!(matches!(err, AppError::Config { .. }));
+        assert!(err.to_string().contains("missing.pem"), "{}", err);
+        // A private key is not a CA bundle
+        let not_a_ca = TlsSettings {
+            client_ca_path: Some(fixture("client-key.pem")),
+            ..settings()
+        };
+        assert!(server_config(&not_a_ca).is_err());
+
+        let mismatched = TlsSettings {
+            key_path: fixture("client-key.pem"),
+            ..settings()
+        };
+        let err = server_config(&mismatched).unwrap_err().to_string();
+        assert!(err.contains("does not belong to the certificate"), "{}", err);
+        let missing_key = TlsSettings {
+            key_path: fixture("missing-key.pem"),
+            ..settings()
+        };
+        let err = server_config(&missing_key).unwrap_err().to_string();
+        assert!(err.contains("Failed to read TLS key") && err.contains("missing-key.pem"), "{}", err);
+    }
+
+    #[test]
+    fn test_client_certificate_from_der() {
+        let der = CertificateDer::from_pem_file(fixture("client.pem")).unwrap();
+        let certificate = ClientCertificate::from_der(&der).unwrap();
+        assert_eq!(certificate.subject, "CN=billing-service, O=Simple API Demo");
+        assert_eq!(certificate.common_name.as_deref(), Some("billing-service"));
+        assert_eq!(certificate.issuer, "CN=simple-api-demo test CA");
+        assert!(!certificate.serial.is_empty());
+        assert!(ClientCertificate::from_der(b"not a certificate").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_extractor_requires_certificate() {
+        let app = init_service(App::new().route(
+            "/peer",
+            web::get().to(|certificate: ClientCertificate| async move { HttpResponse::Ok().body(certificate.subject) }),
+        ))
+        .await;
+        let res = call_service(&app, TestRequest::get().uri("/peer").to_request()).await;
+        assert_eq!(res.status(), 401);
+        assert!(read_body(res).await.starts_with(b"{"));
+    }
+}
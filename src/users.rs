@@ -0,0 +1,818 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::auth::TokenService;
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::events::CloudEvent;
+use crate::integrity::{IntegrityIssue, IssueKind};
+use crate::notifications::{EmailNotifier, Notification, Notifier};
+use crate::pii::{self, PiiFields, PiiKind};
+use crate::state::{KeyValueStore, StateManager};
+
+/// Minimum accepted password length
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Scope for managing one's own account (2FA enrollment, ...)
+///
+/// Always granted on login, and the only scope granted while a required
+/// two-factor enrollment is pending.
+pub const ACCOUNT_SCOPE: &str = "account";
+
+/// Role to scope mapping used when `ROLE_SCOPES` is not set
+pub const DEFAULT_ROLE_SCOPES: &str = "admin=admin:impersonate admin:terms admin:data; \
+    owner=admin:impersonate admin:keys admin:terms admin:data admin:policy admin:dumps admin:maintenance admin:backup admin:metrics admin:config; \
+    operator=admin:terms admin:data admin:policy admin:dumps admin:maintenance admin:backup admin:metrics admin:config; \
+    viewer=admin:data admin:dumps admin:maintenance admin:metrics";
+
+/// A registered account
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    /// Normalized (trimmed, lowercase) email address
+    pub email: String,
+    /// Argon2id PHC string
+    pub password_hash: String,
+    pub email_verified: bool,
+    /// Role names, e.g. `admin`
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// TOTP enrollment, pending until confirmed with a first code
+    #[serde(default)]
+    pub totp: Option<TotpEnrollment>,
+    /// Terms of Service version last accepted
+    #[serde(default)]
+    pub terms_version: Option<u32>,
+    #[serde(default)]
+    pub terms_accepted_at: Option<DateTime<Utc>>,
+    /// When a requested account erasure takes effect
+    #[serde(default)]
+    pub erase_after: Option<DateTime<Utc>>,
+    /// Excludes the user's requests from analytics
+    #[serde(default)]
+    pub analytics_opt_out: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Stored TOTP state of a user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    /// Secret encrypted with [`crate::crypto::Cipher`]
+    pub secret: String,
+    pub confirmed: bool,
+    /// Last accepted time step, to reject replayed codes
+    #[serde(default)]
+    pub last_used_step: Option<u64>,
+    /// SHA-256 hashes of the unused recovery codes
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+}
+
+impl PiiFields for User {
+    const PII_FIELDS: &'static [(&'static str, PiiKind)] =
+        &[("email", PiiKind::Email), ("password_hash", PiiKind::Text)];
+}
+
+impl User {
+    /// Returns whether a confirmed TOTP enrollment exists
+    pub fn two_factor_enabled(&self) -> bool {
+        self.totp.as_ref().is_some_and(|totp| totp.confirmed)
+    }
+
+    /// Public representation returned by the API
+    pub fn profile(&self) -> Value {
+        json!({
+            "id": self.id,
+            "email": self.email,
+            "email_verified": self.email_verified,
+            "roles": self.roles,
+            "two_factor_enabled": self.two_factor_enabled(),
+            "terms_version": self.terms_version,
+            "erase_after": self.erase_after.map(|at| at.to_rfc3339()),
+            "analytics_opt_out": self.analytics_opt_out,
+            "created_at": self.created_at.to_rfc3339(),
+        })
+    }
+}
+
+/// A state transition recorded in a user's audit trail
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserEvent {
+    pub id: String,
+    pub user_id: String,
+    /// Event type, e.g. `user.email_verified`
+    pub action: String,
+    pub at: DateTime<Utc>,
+    #[serde(default)]
+    pub data: Value,
+}
+
+/// Persists users and their audit trail in a [`KeyValueStore`]
+///
+/// Keys: `user:{id}` (JSON), `email:{email}` (id), `events:{id}` (JSON
+/// array) and `index` (JSON array of user ids, for batch jobs). Email
+/// uniqueness relies on `set_if_absent`, so it also holds across replicas
+/// with a distributed store.
+#[derive(Clone)]
+pub struct UserRepository {
+    store: Arc<dyn KeyValueStore>,
+}
+
+impl UserRepository {
+    /// Creates a repository on `store`
+    pub fn new(store: Arc<dyn KeyValueStore>) -> Self {
+        Self { store }
+    }
+
+    /// Creates a user with an unverified email
+    ///
+    /// # Errors
+    /// Returns a validation error if the email is already registered
+    pub fn create(&self, email: &str, password_hash: String) -> AppResult<User> {
+        let email = normalize_email(email);
+        let now = Utc::now();
+        let user = User {
+            id: uuid::Uuid::new_v4().to_string(),
+            email,
+            password_hash,
+            email_verified: false,
+            roles: Vec::new(),
+            totp: None,
+            terms_version: None,
+            terms_accepted_at: None,
+            erase_after: None,
+            analytics_opt_out: false,
+            created_at: now,
+            updated_at: now,
+        };
+
+        if !self.store.set_if_absent(&format!("email:{}", user.email), &user.id, None)? {
+            return Err(AppError::validation("email is already registered"));
+        }
+        self.save(&user)?;
+        let mut ids = self.ids()?;
+        ids.push(user.id.clone());
+        self.save_ids(&ids)?;
+        Ok(user)
+    }
+
+    /// Returns the ids of all users, oldest first
+    pub fn ids(&self) -> AppResult<Vec<String>> {
+        self.store
+            .get("index")?
+            .map(|raw| {
+                serde_json::from_str(&raw).map_err(|e| AppError::internal(format!("Corrupt user index: {}", e)))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    fn save_ids(&self, ids: &[String]) -> AppResult<()> {
+        let encoded = serde_json::to_string(ids)
+            .map_err(|e| AppError::internal(format!("Failed to encode user index: {}", e)))?;
+        self.store.set("index", &encoded, None)
+    }
+
+    /// Stores `user`, replacing the previous version
+    pub fn save(&self, user: &User) -> AppResult<()> {
+        let encoded = serde_json::to_string(user)
+            .map_err(|e| AppError::internal(format!("Failed to encode user: {}", e)))?;
+        self.store.set(&format!("user:{}", user.id), &encoded, None)
+    }
+
+    /// Removes `user`, their email reservation and their audit trail
+    pub fn delete(&self, user: &User) -> AppResult<()> {
+        self.store.delete(&format!("email:{}", user.email))?;
+        self.store.delete(&format!("events:{}", user.id))?;
+        self.store.delete(&format!("user:{}", user.id))?;
+        let ids: Vec<String> = self.ids()?.into_iter().filter(|id| *id != user.id).collect();
+        self.save_ids(&ids)
+    }
+
+    /// Loads a user by id
+    pub fn get(&self, id: &str) -> AppResult<Option<User>> {
+        self.store
+            .get(&format!("user:{}", id))?
+            .map(|raw| {
+                serde_json::from_str(&raw).map_err(|e| AppError::internal(format!("Corrupt user record {}: {}", id, e)))
+            })
+            .transpose()
+    }
+
+    /// Loads a user by email address
+    pub fn find_by_email(&self, email: &str) -> AppResult<Option<User>> {
+        match self.store.get(&format!("email:{}", normalize_email(email)))? {
+            Some(id) => self.get(&id),
+            None => Ok(None),
+        }
+    }
+
+    /// Appends an event to the user's audit trail and logs it as a CloudEvent
+    pub fn record_event(&self, user_id: &str, action: &str, data: Value) -> AppResult<UserEvent> {
+        let event = UserEvent {
+            id: uuid::Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            action: action.to_string(),
+            at: Utc::now(),
+            data,
+        };
+
+        let mut events = self.events(user_id)?;
+        events.push(event.clone());
+        self.save_events(user_id, &events)?;
+
+        info!(target: "audit", "{}", audit_event(&event).to_json()?);
+        Ok(event)
+    }
+
+    /// Replaces the user's audit trail, e.g. after anonymization
+    pub fn save_events(&self, user_id: &str, events: &[UserEvent]) -> AppResult<()> {
+        let encoded = serde_json::to_string(events)
+            .map_err(|e| AppError::internal(format!("Failed to encode user events: {}", e)))?;
+        self.store.set(&format!("events:{}", user_id), &encoded, None)
+    }
+
+    /// Returns the user's audit trail, oldest first
+    pub fn events(&self, user_id: &str) -> AppResult<Vec<UserEvent>> {
+        self.store
+            .get(&format!("events:{}", user_id))?
+            .map(|raw| {
+                serde_json::from_str(&raw)
+                    .map_err(|e| AppError::internal(format!("Corrupt event log for {}: {}", user_id, e)))
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Checks the user index and returns the ids of existing users
+    ///
+    /// Ids listed twice or without a record are reported and, with `repair`,
+    /// dropped from the index.
+    pub fn check_index(&self, repair: bool) -> AppResult<(Vec<String>, Vec<IntegrityIssue>)> {
+        let listed = self.ids()?;
+        let mut seen = HashSet::new();
+        let mut ids = Vec::with_capacity(listed.len());
+        let mut issues = Vec::new();
+        for id in listed {
+            if !seen.insert(id.clone()) {
+                let detail = format!("user {} is listed more than once", id);
+                issues.push(IntegrityIssue::repairable(IssueKind::DuplicateKey, "index", detail, repair));
+            } else if self.get(&id)?.is_none() {
+                let detail = format!("user {} has no record", id);
+                issues.push(IntegrityIssue::repairable(IssueKind::OrphanedReference, "index", detail, repair));
+            } else {
+                ids.push(id);
+            }
+        }
+        if repair && !issues.is_empty() {
+            self.save_ids(&ids)?;
+        }
+        Ok((ids, issues))
+    }
+
+    /// Checks the record and audit trail of `user_id`, timestamps after
+    /// `horizon` counting as in the future
+    ///
+    /// A missing email reservation is restored with `repair`, as are events
+    /// recorded twice; other issues are only reported. Details name user and
+    /// event ids, never the email address.
+    pub fn check_user(&self, user_id: &str, horizon: DateTime<Utc>, repair: bool) -> AppResult<Vec<IntegrityIssue>> {
+        let Some(user) = self.get(user_id)? else {
+            return Ok(Vec::new());
+        };
+        let key = format!("user:{}", user_id);
+        let mut issues = Vec::new();
+
+        let reservation = format!("email:{}", user.email);
+        match self.store.get(&reservation)? {
+            Some(owner) if owner != user.id => {
+                let detail = format!("email address is reserved by user {}", owner);
+                issues.push(IntegrityIssue::new(IssueKind::DuplicateKey, &key, detail));
+            }
+            Some(_) => {}
+            None => {
+                // Only while still free, in case another account took the address meanwhile
+                let repaired = repair && self.store.set_if_absent(&reservation, &user.id, None)?;
+                let detail = "email address is not reserved";
+                issues.push(IntegrityIssue::repairable(IssueKind::OrphanedReference, &key, detail, repaired));
+            }
+        }
+        if user.updated_at < user.created_at {
+            issues.push(IntegrityIssue::new(IssueKind::InvalidTimestamp, &key, "updated before it was created"));
+        }
+        if user.created_at > horizon {
+            issues.push(IntegrityIssue::new(IssueKind::InvalidTimestamp, &key, "created in the future"));
+        }
+        if user.terms_accepted_at.is_some_and(|at| at < user.created_at) {
+            let detail = "terms accepted before the account was created";
+            issues.push(IntegrityIssue::new(IssueKind::InvalidTimestamp, &key, detail));
+        }
+
+        let key = format!("events:{}", user_id);
+        let events = self.events(user_id)?;
+        let mut kept: Vec<UserEvent> = Vec::with_capacity(events.len());
+        for event in &events {
+            match kept.iter().find(|other| other.id == event.id) {
+                Some(other) if other == event => {
+                    let detail = format!("event {} is recorded twice", event.id);
+                    issues.push(IntegrityIssue::repairable(IssueKind::DuplicateKey, &key, detail, repair));
+                    continue;
+                }
+                Some(_) => {
+                    let detail = format!("two different events share the id {}", event.id);
+                    issues.push(IntegrityIssue::new(IssueKind::DuplicateKey, &key, detail));
+                }
+                None => {}
+            }
+            if event.user_id != user_id {
+                let detail = format!("event {} belongs to user {}", event.id, event.user_id);
+                issues.push(IntegrityIssue::new(IssueKind::OrphanedReference, &key, detail));
+            }
+            if event.at > horizon {
+                let detail = format!("event {} is dated in the future", event.id);
+                issues.push(IntegrityIssue::new(IssueKind::InvalidTimestamp, &key, detail));
+            }
+            kept.push(event.clone());
+        }
+        if repair && kept.len() != events.len() {
+            self.save_events(user_id, &kept)?;
+        }
+        Ok(issues)
+    }
+}
+
+/// CloudEvent written to the `audit` log for `event`, with personal data masked
+///
+/// The per-user trail keeps the full data (it is the user's own and part of
+/// their export); the shared audit log only gets the redacted form.
+pub fn audit_event(event: &UserEvent) -> CloudEvent {
+    let mut data = event.data.clone();
+    pii::redact_json(&mut data, &pii::tagged_fields());
+    CloudEvent::new(format!("com.simple-api-demo.{}", event.action), data).with_subject(event.user_id.clone())
+}
+
+/// Purpose of a signed, expiring account action token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl ActionPurpose {
+    /// Token audience; tokens for one purpose are rejected for any other
+    pub fn audience(self) -> &'static str {
+        match self {
+            ActionPurpose::VerifyEmail => "email_verify",
+            ActionPurpose::ResetPassword => "password_reset",
+        }
+    }
+
+    /// How long a token stays valid
+    pub fn ttl(self) -> Duration {
+        match self {
+            ActionPurpose::VerifyEmail => Duration::from_secs(24 * 3600),
+            ActionPurpose::ResetPassword => Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Account lifecycle: registration, email verification and password reset
+///
+/// Action tokens are JWTs signed with the service key under a per-purpose
+/// audience, so they cannot double as access tokens, and are single use:
+/// their `jti` is burnt on first redemption.
+pub struct UserService {
+    repository: UserRepository,
+    verify_tokens: TokenService,
+    reset_tokens: TokenService,
+    used_tokens: Arc<dyn KeyValueStore>,
+    mailer: Option<Arc<dyn Notifier>>,
+    access_scopes: Vec<String>,
+    role_scopes: HashMap<String, Vec<String>>,
+    access_ttl: Duration,
+}
+
+impl UserService {
+    /// Creates the service
+    ///
+    /// # Arguments
+    /// * `repository` - User storage
+    /// * `tokens` - Service whose key signs action tokens
+    /// * `used_tokens` - Store remembering redeemed token ids
+    pub fn new(repository: UserRepository, tokens: &TokenService, used_tokens: Arc<dyn KeyValueStore>) -> Self {
+        Self {
+            repository,
+            verify_tokens: tokens.for_audience(ActionPurpose::VerifyEmail.audience()),
+            reset_tokens: tokens.for_audience(ActionPurpose::ResetPassword.audience()),
+            used_tokens,
+            mailer: None,
+            access_scopes: vec!["read:private".to_string()],
+            role_scopes: HashMap::new(),
+            access_ttl: Duration::from_secs(900),
+        }
+    }
+
+    /// Builds the service; mail goes to the `NOTIFY_EMAIL_OUTBOX` pickup directory
+    ///
+    /// # Errors
+    /// Returns a configuration error for a malformed `ROLE_SCOPES`
+    pub fn from_config(config: &Config, tokens: &TokenService, state: &StateManager) -> AppResult<Self> {
+        let role_scopes = parse_role_scopes(config.role_scopes.as_deref().unwrap_or(DEFAULT_ROLE_SCOPES))?;
+        let service = Self::new(
+            UserRepository::new(state.store("users")),
+            tokens,
+            state.store("user_action_tokens"),
+        )
+        .with_access_policy(config.user_scopes.clone(), Duration::from_secs(config.access_token_ttl_secs))
+        .with_role_scopes(role_scopes);
+        Ok(match &config.notify_email_outbox {
+            Some(outbox) => service.with_mailer(EmailNotifier::new(
+                outbox,
+                "simple-api-demo@localhost",
+                config.notify_email_to.clone().unwrap_or_default(),
+            )),
+            None => service,
+        })
+    }
+
+    /// Sets the channel delivering account emails
+    pub fn with_mailer(mut self, mailer: impl Notifier + 'static) -> Self {
+        self.mailer = Some(Arc::new(mailer));
+        self
+    }
+
+    /// Sets the scopes and lifetime of access tokens issued on login
+    pub fn with_access_policy(mut self, scopes: Vec<String>, ttl: Duration) -> Self {
+        self.access_scopes = scopes;
+        self.access_ttl = ttl;
+        self
+    }
+
+    /// Grants extra scopes to users holding the given roles
+    pub fn with_role_scopes(mut self, role_scopes: HashMap<String, Vec<String>>) -> Self {
+        self.role_scopes = role_scopes;
+        self
+    }
+
+    /// Returns every scope a full access token for `user` carries
+    pub fn scopes_for(&self, user: &User) -> Vec<String> {
+        let role_scopes = user
+            .roles
+            .iter()
+            .filter_map(|role| self.role_scopes.get(role))
+            .flatten();
+        let mut scopes = vec![ACCOUNT_SCOPE.to_string()];
+        for scope in self.access_scopes.iter().chain(role_scopes) {
+            if !scopes.contains(scope) {
+                scopes.push(scope.clone());
+            }
+        }
+        scopes
+    }
+
+    /// Returns the lifetime of access tokens issued on login
+    pub fn access_ttl(&self) -> Duration {
+        self.access_ttl
+    }
+
+    /// Returns the underlying repository
+    pub fn repository(&self) -> &UserRepository {
+        &self.repository
+    }
+
+    /// Registers an account and sends its verification email
+    ///
+    /// # Errors
+    /// Returns a validation error for malformed emails, short passwords or
+    /// an already registered address.
+    pub async fn register(&self, email: &str, password: &str) -> AppResult<User> {
+        if !is_plausible_email(email) {
+            return Err(AppError::validation("email address is invalid"));
+        }
+        let user = self.repository.create(email, hash_password(password)?)?;
+        self.repository.record_event(&user.id, "user.registered", Value::Null)?;
+        self.send_action_email(&user, ActionPurpose::VerifyEmail).await?;
+        Ok(user)
+    }
+
+    /// Checks an email and password
+    ///
+    /// # Errors
+    /// Returns the same unauthorized error for unknown emails and wrong
+    /// passwords, so responses do not reveal which addresses exist.
+    pub fn authenticate(&self, email: &str, password: &str) -> AppResult<User> {
+        match self.repository.find_by_email(email)? {
+            Some(user) if verify_password(password, &user.password_hash) => Ok(user),
+            _ => Err(AppError::unauthorized("invalid email or password")),
+        }
+    }
+
+    /// Issues an access token response for `user`
+    ///
+    /// With `enrollment_only` the token carries just [`ACCOUNT_SCOPE`], for
+    /// users who must enroll in two-factor authentication first. `session_id`
+    /// ties the token to a session so revoking the session revokes it.
+    pub fn issue_access_token(
+        &self,
+        tokens: &TokenService,
+        user: &User,
+        enrollment_only: bool,
+        session_id: Option<&str>,
+    ) -> AppResult<Value> {
+        let scopes = if enrollment_only {
+            vec![ACCOUNT_SCOPE.to_string()]
+        } else {
+            self.scopes_for(user)
+        };
+        let scopes: Vec<&str> = scopes.iter().map(String::as_str).collect();
+        let mut claims = tokens.claims(&user.id, &scopes, self.access_ttl);
+        claims.sid = session_id.map(str::to_string);
+        if !enrollment_only && !user.roles.is_empty() {
+            claims.extra.insert("roles".to_string(), json!(user.roles));
+        }
+        let access_token = tokens.sign(&claims)?;
+        let mut response = json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+            "expires_in": self.access_ttl.as_secs(),
+            "scope": scopes.join(" "),
+        });
+        if enrollment_only {
+            response["mfa_enrollment_required"] = json!(true);
+        }
+        Ok(response)
+    }
+
+    /// Marks the email of the token's user as verified
+    ///
+    /// # Errors
+    /// Returns unauthorized for invalid, expired or already used tokens
+    pub async fn verify_email(&self, token: &str) -> AppResult<User> {
+        let mut user = self.redeem(token, ActionPurpose::VerifyEmail)?;
+        if !user.email_verified {
+            user.email_verified = true;
+            user.updated_at = Utc::now();
+            self.repository.save(&user)?;
+            self.repository.record_event(&user.id, "user.email_verified", Value::Null)?;
+        }
+        Ok(user)
+    }
+
+    /// Sends a password reset email if `email` belongs to an account
+    ///
+    /// Succeeds either way so the endpoint cannot be used to probe which
+    /// addresses are registered.
+    pub async fn request_password_reset(&self, email: &str) -> AppResult<()> {
+        let Some(user) = self.repository.find_by_email(email)? else {
+            return Ok(());
+        };
+        self.repository
+            .record_event(&user.id, "user.password_reset_requested", Value::Null)?;
+        self.send_action_email(&user, ActionPurpose::ResetPassword).await
+    }
+
+    /// Sets a new password using a reset token
+    ///
+    /// # Errors
+    /// Unauthorized for invalid, expired or used tokens; validation error for
+    /// a password that is too short.
+    pub async fn reset_password(&self, token: &str, new_password: &str) -> AppResult<User> {
+        let password_hash = hash_password(new_password)?;
+        let mut user = self.redeem(token, ActionPurpose::ResetPassword)?;
+        user.password_hash = password_hash;
+        // Receiving the reset mail proves ownership of the address
+        user.email_verified = true;
+        user.updated_at = Utc::now();
+        self.repository.save(&user)?;
+        self.repository.record_event(&user.id, "user.password_reset", Value::Null)?;
+        Ok(user)
+    }
+
+    /// Issues a signed action token for `user`
+    pub fn issue_action_token(&self, user: &User, purpose: ActionPurpose) -> AppResult<String> {
+        self.tokens_for(purpose).issue(&user.id, &[], purpose.ttl())
+    }
+
+    /// Verifies a token for `purpose`, burns it and returns its user
+    fn redeem(&self, token: &str, purpose: ActionPurpose) -> AppResult<User> {
+        let claims = self.tokens_for(purpose).verify(token)?;
+        let remaining = (claims.exp - Utc::now().timestamp()).max(1) as u64;
+        if !self
+            .used_tokens
+            .set_if_absent(&claims.jti, purpose.audience(), Some(Duration::from_secs(remaining)))?
+        {
+            return Err(AppError::unauthorized("token has already been used"));
+        }
+        self.repository
+            .get(&claims.sub)?
+            .ok_or_else(|| AppError::unauthorized("token refers to an unknown account"))
+    }
+
+    fn tokens_for(&self, purpose: ActionPurpose) -> &TokenService {
+        match purpose {
+            ActionPurpose::VerifyEmail => &self.verify_tokens,
+            ActionPurpose::ResetPassword => &self.reset_tokens,
+        }
+    }
+
+    async fn send_action_email(&self, user: &User, purpose: ActionPurpose) -> AppResult<()> {
+        let Some(mailer) = &self.mailer else {
+            warn!(
+                "No email channel configured (NOTIFY_EMAIL_OUTBOX); {} mail for user {} not sent",
+                purpose.audience(),
+                user.id
+            );
+            return Ok(());
+        };
+
+        let token = self.issue_action_token(user, purpose)?;
+        let (event_type, title, endpoint) = match purpose {
+            ActionPurpose::VerifyEmail => ("user.verification_requested", "Verify your email address", "/auth/verify"),
+            ActionPurpose::ResetPassword => ("user.password_reset_requested", "Reset your password", "/auth/reset"),
+        };
+        let message = format!(
+            "Submit this token to POST {} within {} minutes:\n\n{}\n\nIf you did not request this, ignore this message.",
+            endpoint,
+            purpose.ttl().as_secs() / 60,
+            token
+        );
+        let notification = Notification::new(event_type, title, message).with_recipient(user.email.clone());
+        mailer.send(&notification).await
+    }
+}
+
+/// Hashes a password with Argon2id
+///
+/// # Errors
+/// Returns a validation error for passwords shorter than [`MIN_PASSWORD_LENGTH`]
+pub fn hash_password(password: &str) -> AppResult<String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppError::validation(format!(
+            "password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AppError::internal(format!("Failed to hash password: {}", e)))
+}
+
+/// Checks `password` against an Argon2 PHC string
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// Parses `role=scope scope;role=scope` as used by `ROLE_SCOPES`
+///
+/// # Errors
+/// Returns a configuration error for entries without `=`
+pub fn parse_role_scopes(spec: &str) -> AppResult<HashMap<String, Vec<String>>> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (role, scopes) = entry
+                .split_once('=')
+                .ok_or_else(|| AppError::config(format!("role scopes '{}' must look like 'role=scope ...'", entry)))?;
+            Ok((
+                role.trim().to_string(),
+                scopes.split([' ', ',']).filter(|s| !s.is_empty()).map(str::to_string).collect(),
+            ))
+        })
+        .collect()
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn is_plausible_email(email: &str) -> bool {
+    let email = email.trim();
+    email
+        .split_once('@')
+        .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.') && !email.contains(char::is_whitespace))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::RecordingNotifier;
+    use crate::state::InMemoryStore;
+
+    fn service() -> (UserService, RecordingNotifier) {
+        let tokens = TokenService::new(b"users-test-key-users-test-key-000", "test");
+        let mailer = RecordingNotifier::new("email");
+        let service = UserService::new(
+            UserRepository::new(Arc::new(InMemoryStore::new())),
+            &tokens,
+            Arc::new(InMemoryStore::new()),
+        )
+        .with_mailer(mailer.clone());
+        (service, mailer)
+    }
+
+    fn token_from(notification: &Notification) -> String {
+        notification.message.lines().nth(2).unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn test_register_and_verify_email() {
+        let (service, mailer) = service();
+        let user = service.register("Ann@Example.com ", "correct horse").await.unwrap();
+        assert_eq!(user.email, "ann@example.com");
+        assert!(!user.email_verified);
+        assert!(service.register("ann@example.com", "another pass").await.is_err());
+
+        let sent = mailer.sent();
+        assert_eq!(sent[0].recipient.as_deref(), Some("ann@example.com"));
+        let token = token_from(&sent[0]);
+
+        let verified = service.verify_email(&token).await.unwrap();
+        assert!(verified.email_verified);
+        // Single use
+        assert!(service.verify_email(&token).await.is_err());
+
+        let actions: Vec<String> = service.repository().events(&user.id).unwrap().into_iter().map(|e| e.action).collect();
+        assert_eq!(actions, vec!["user.registered", "user.email_verified"]);
+    }
+
+    #[actix_web::test]
+    async fn test_password_reset() {
+        let (service, mailer) = service();
+        let user = service.register("bob@example.com", "old password").await.unwrap();
+
+        service.request_password_reset("nobody@example.com").await.unwrap();
+        assert_eq!(mailer.sent().len(), 1);
+
+        service.request_password_reset("BOB@example.com").await.unwrap();
+        let token = token_from(&mailer.sent()[1]);
+
+        // A reset token is not a verification token
+        assert!(service.verify_email(&token).await.is_err());
+        assert!(service.reset_password(&token, "short").await.is_err());
+
+        let updated = service.reset_password(&token, "new password!").await.unwrap();
+        assert!(verify_password("new password!", &updated.password_hash));
+        assert!(!verify_password("old password", &updated.password_hash));
+        assert_eq!(updated.id, user.id);
+        assert!(service.reset_password(&token, "another one!").await.is_err());
+    }
+
+    #[test]
+    fn test_scopes_for_roles() {
+        let tokens = TokenService::new(b"users-test-key-users-test-key-000", "test");
+        let service = UserService::new(
+            UserRepository::new(Arc::new(InMemoryStore::new())),
+            &tokens,
+            Arc::new(InMemoryStore::new()),
+        )
+        .with_role_scopes(parse_role_scopes("admin=admin:impersonate read:private; support=read:logs").unwrap());
+        let mut user = service.repository().create("root@example.com", String::new()).unwrap();
+        assert_eq!(service.scopes_for(&user), vec!["account", "read:private"]);
+
+        user.roles = vec!["admin".to_string()];
+        assert_eq!(service.scopes_for(&user), vec!["account", "read:private", "admin:impersonate"]);
+        assert!(parse_role_scopes("admin").is_err());
+
+        // Roles travel in the access token for RBAC guards
+        let response = service.issue_access_token(&tokens, &user, false, None).unwrap();
+        let claims = tokens.verify(response["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.roles(), vec!["admin".to_string()]);
+    }
+
+    #[test]
+    fn test_audit_events_are_redacted() {
+        let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+        let event = repository
+            .record_event("u1", "user.email_changed", json!({ "email": "ann@example.com", "ip": "192.0.2.9" }))
+            .unwrap();
+        // The user's own trail keeps the data, the audit log does not
+        assert_eq!(event.data["email"], "ann@example.com");
+        let logged = audit_event(&event).to_json().unwrap();
+        assert!(!logged.contains("ann@example.com") && logged.contains("a***@example.com"));
+        assert!(logged.contains("192.0.2.0/24"));
+    }
+
+    #[test]
+    fn test_password_hashing() {
+        let hash = hash_password("hunter2hunter2").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("hunter2hunter2", &hash));
+        assert!(!verify_password("hunter3hunter3", &hash));
+        assert!(hash_password("short").is_err());
+        assert!(!is_plausible_email("not-an-email"));
+    }
+}
@@ -0,0 +1,519 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::SecretProvider;
+use crate::error::{AppError, AppResult};
+use crate::supervisor::Supervisor;
+
+/// Timeout of every request to Vault
+pub const VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shortest pause between two renewal rounds
+pub const MIN_RENEWAL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// One configuration variable read from Vault: `JWT_SECRET=secret/data/api#jwt`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VaultSecret {
+    /// Configuration variable the value stands in for
+    pub var: String,
+    /// Secret path below `/v1/`
+    pub path: String,
+    /// Field of the secret holding the value
+    pub field: String,
+}
+
+impl VaultSecret {
+    /// Parses `VAULT_SECRETS`: comma-separated `VAR=path#field` entries, the
+    /// field defaulting to the variable name in lower case
+    ///
+    /// # Errors
+    /// Returns an environment error naming the malformed entry
+    pub fn parse_list(value: &str) -> AppResult<Vec<Self>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let invalid = || AppError::environment("VAULT_SECRETS", format!("expected VAR=path#field, got: {}", entry));
+                let (var, location) = entry.split_once('=').ok_or_else(invalid)?;
+                let (path, field) = match location.split_once('#') {
+                    Some((path, field)) => (path, field.to_string()),
+                    None => (location, var.trim().to_lowercase()),
+                };
+                let (var, path) = (var.trim(), path.trim().trim_matches('/'));
+                if var.is_empty() || path.is_empty() || field.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(Self {
+                    var: var.to_string(),
+                    path: path.to_string(),
+                    field,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Connection to Vault and the variables read from it, from the `VAULT_*`
+/// settings
+///
+/// Read before the rest of the configuration, so they are not part of
+/// [`Config`](crate::config::Config). Deliberately not `Debug`: it holds the token.
+#[derive(Clone, PartialEq, Eq)]
+pub struct VaultSettings {
+    /// Vault address, e.g. `https://vault.internal:8200`
+    pub addr: String,
+    pub token: String,
+    /// Enterprise namespace sent as `X-Vault-Namespace`
+    pub namespace: Option<String>,
+    pub secrets: Vec<VaultSecret>,
+}
+
+impl VaultSettings {
+    /// Reads `VAULT_ADDR`, `VAULT_TOKEN`, `VAULT_NAMESPACE` and
+    /// `VAULT_SECRETS`; `None` when `VAULT_SECRETS` is empty
+    ///
+    /// # Errors
+    /// Returns an environment error when secrets are mapped without an
+    /// address or token, or `VAULT_SECRETS` is malformed
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> AppResult<Option<Self>> {
+        let value = |name: &str| lookup(name).filter(|value| !value.is_empty());
+        let secrets = VaultSecret::parse_list(&value("VAULT_SECRETS").unwrap_or_default())?;
+        if secrets.is_empty() {
+            return Ok(None);
+        }
+        let addr = value("VAULT_ADDR")
+            .ok_or_else(|| AppError::environment("VAULT_ADDR", "required when VAULT_SECRETS is set"))?;
+        let token = value("VAULT_TOKEN")
+            .ok_or_else(|| AppError::environment("VAULT_TOKEN", "required when VAULT_SECRETS is set"))?;
+        Ok(Some(Self {
+            addr: addr.trim_end_matches('/').to_string(),
+            token,
+            namespace: value("VAULT_NAMESPACE"),
+            secrets,
+        }))
+    }
+}
+
+/// Vault read or lease renewal response
+#[derive(Debug, Deserialize)]
+struct LeaseResponse {
+    #[serde(default)]
+    lease_id: String,
+    #[serde(default)]
+    lease_duration: u64,
+    #[serde(default)]
+    renewable: bool,
+    #[serde(default)]
+    data: Option<Value>,
+}
+
+/// Lease of one secret path
+#[derive(Debug, Clone)]
+struct Lease {
+    path: String,
+    lease_id: String,
+    renewable: bool,
+    duration: Duration,
+    /// Two thirds into the lease; `None` for secrets that never expire
+    renew_at: Option<Instant>,
+}
+
+impl Lease {
+    fn new(path: &str, response: &LeaseResponse) -> Self {
+        let duration = Duration::from_secs(response.lease_duration);
+        Self {
+            path: path.to_string(),
+            lease_id: response.lease_id.clone(),
+            renewable: response.renewable,
+            duration,
+            renew_at: (!duration.is_zero()).then(|| Instant::now() + duration * 2 / 3),
+        }
+    }
+}
+
+/// [`SecretProvider`] serving configuration variables from HashiCorp Vault
+///
+/// [`load`](Self::load) reads every path mapped in `VAULT_SECRETS` once
+/// (KV v1 and v2 as well as dynamic secrets engines) and caches the values;
+/// the configuration is then built from the cache. Leases are renewed two
+/// thirds into their duration by [`spawn_renewal`](Self::spawn_renewal), so
+/// dynamic credentials stay valid for as long as the process runs. A lease
+/// that can no longer be renewed is replaced by reading its path again; the
+/// new value only reaches the configuration on the next start, which is
+/// logged.
+pub struct VaultProvider {
+    settings: VaultSettings,
+    values: RwLock<HashMap<String, String>>,
+    leases: Mutex<HashMap<String, Lease>>,
+}
+
+impl VaultProvider {
+    /// Creates a provider; nothing is read before [`load`](Self::load)
+    pub fn new(settings: VaultSettings) -> Self {
+        Self {
+            settings,
+            values: RwLock::new(HashMap::new()),
+            leases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a provider from the `VAULT_*` environment variables; `None`
+    /// when no secrets are mapped
+    ///
+    /// # Errors
+    /// Same as [`VaultSettings::from_lookup`]
+    pub fn from_env() -> AppResult<Option<Self>> {
+        Ok(VaultSettings::from_lookup(|name| env::var(name).ok())?.map(Self::new))
+    }
+
+    /// Reads every mapped secret
+    ///
+    /// # Errors
+    /// Unavailable when Vault cannot be reached or refuses a read, config
+    /// error when a secret lacks a mapped field
+    pub async fn load(&self) -> AppResult<()> {
+        let http = client()?;
+        for path in self.paths() {
+            self.read(&http, &path).await?;
+        }
+        for secret in &self.settings.secrets {
+            if env::var_os(&secret.var).is_some() {
+                warn!("{} is set in the environment too; the value from Vault is used", secret.var);
+            }
+        }
+        info!("Loaded {} secrets from Vault at {}", self.settings.secrets.len(), self.settings.addr);
+        Ok(())
+    }
+
+    /// Renews or re-reads the leases that are due
+    ///
+    /// Returns how long until the next lease is due, `None` when no lease
+    /// expires.
+    ///
+    /// # Errors
+    /// Same as [`load`](Self::load), for secrets that had to be read again
+    pub async fn renew_due(&self) -> AppResult<Option<Duration>> {
+        let now = Instant::now();
+        let due: Vec<Lease> = self
+            .leases
+            .lock()
+            .map(|leases| {
+                leases
+                    .values()
+                    .filter(|lease| lease.renew_at.is_some_and(|at| at <= now))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        if !due.is_empty() {
+            let http = client()?;
+            for lease in due {
+                if lease.renewable && !lease.lease_id.is_empty() {
+                    match self.renew(&http, &lease).await {
+                        Ok(true) => continue,
+                        Ok(false) => info!("Vault lease of {} is reaching its maximum TTL, reading it again", lease.path),
+                        Err(e) => warn!("Renewing the Vault lease of {} failed, reading it again: {}", lease.path, e),
+                    }
+                }
+                if self.read(&http, &lease.path).await? {
+                    warn!("Vault secret {} changed; restart to apply the new value", lease.path);
+                }
+            }
+        }
+        Ok(self
+            .leases
+            .lock()
+            .ok()
+            .and_then(|leases| leases.values().filter_map(|lease| lease.renew_at).min())
+            .map(|at| at.saturating_duration_since(Instant::now())))
+    }
+
+    /// Keeps the leases alive in a supervised background task
+    pub fn spawn_renewal(provider: Arc<Self>, supervisor: &Arc<Supervisor>) {
+        supervisor.spawn("vault_renewal", move || {
+            let provider = provider.clone();
+            async move {
+                while let Some(wait) = provider.renew_due().await? {
+                    actix_web::rt::time::sleep(wait.max(MIN_RENEWAL_INTERVAL)).await;
+                }
+                Ok(())
+            }
+        });
+    }
+
+    /// Distinct mapped paths, in mapping order
+    fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        for secret in &self.settings.secrets {
+            if !paths.contains(&secret.path) {
+                paths.push(secret.path.clone());
+            }
+        }
+        paths
+    }
+
+    /// Reads `path`, caching its mapped fields and lease; returns whether a
+    /// cached value changed
+    async fn read(&self, http: &reqwest::Client, path: &str) -> AppResult<bool> {
+        let url = format!("{}/v1/{}", self.settings.addr, path);
+        let response: LeaseResponse = self.send(http.get(&url), path).await?;
+        let mut data = response.data.clone().unwrap_or_default();
+        // KV v2 nests the fields next to the version metadata
+        if data.get("metadata").is_some() && data.get("data").is_some_and(Value::is_object) {
+            data = data["data"].take();
+        }
+
+        let mut read = Vec::new();
+        for secret in self.settings.secrets.iter().filter(|secret| secret.path == path) {
+            let value = match data.get(&secret.field) {
+                Some(Value::String(value)) => value.clone(),
+                Some(Value::Null) | None => {
+                    return Err(AppError::config(format!(
+                        "Vault secret {} has no field {} (mapped to {})",
+                        path, secret.field, secret.var
+                    )))
+                }
+                Some(other) => other.to_string(),
+            };
+            read.push((secret.var.clone(), value));
+        }
+        let mut changed = false;
+        if let Ok(mut values) = self.values.write() {
+            for (var, value) in read {
+                changed |= values.insert(var, value.clone()).is_some_and(|previous| previous != value);
+            }
+        }
+        if let Ok(mut leases) = self.leases.lock() {
+            leases.insert(path.to_string(), Lease::new(path, &response));
+        }
+        Ok(changed)
+    }
+
+    /// Extends `lease` by its duration; `false` when Vault granted less,
+    /// which means the lease is about to reach its maximum TTL
+    async fn renew(&self, http: &reqwest::Client, lease: &Lease) -> AppResult<bool> {
+        let url = format!("{}/v1/sys/leases/renew", self.settings.addr);
+        let body = json!({ "lease_id": lease.lease_id, "increment": lease.duration.as_secs() });
+        let response: LeaseResponse = self.send(http.put(&url).json(&body), &lease.path).await?;
+        let renewed = Lease::new(&lease.path, &response);
+        let extended = renewed.duration >= lease.duration;
+        if extended {
+            if let Ok(mut leases) = self.leases.lock() {
+                leases.insert(lease.path.clone(), renewed);
+            }
+        }
+        Ok(extended)
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder, path: &str) -> AppResult<T> {
+        let mut request = request.header("X-Vault-Token", &self.settings.token);
+        if let Some(namespace) = &self.settings.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| AppError::unavailable(format!("Vault unreachable: {}", e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(AppError::unavailable(format!("Vault answered {} for {}", status, path)));
+        }
+        response
+            .json::<T>()
+            .await
+            .map_err(|e| AppError::unavailable(format!("Invalid Vault response for {}: {}", path, e)))
+    }
+}
+
+impl SecretProvider for VaultProvider {
+    fn secret(&self, name: &str) -> Option<String> {
+        self.values.read().ok()?.get(name).cloned()
+    }
+}
+
+/// A client per call: startup and renewal run on different runtimes, and
+/// pooled connections must not outlive theirs
+fn client() -> AppResult<reqwest::Client> {
+    reqwest::Client::builder()
+        .timeout(VAULT_TIMEOUT)
+        .build()
+        .map_err(|e| AppError::internal(format!("Failed to build the Vault client: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    /// Fake Vault: a KV v2 secret and renewable database credentials
+    #[derive(Default)]
+    struct FakeVault {
+        reads: AtomicU64,
+        refuse_renewals: AtomicBool,
+        renewed: Mutex<Vec<String>>,
+    }
+
+    fn authorized(req: &HttpRequest) -> bool {
+        req.headers().get("x-vault-token").is_some_and(|token| token == "root-token")
+    }
+
+    async fn start_vault(vault: Arc<FakeVault>) -> String {
+        let data = web::Data::from(vault);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route(
+                    "/v1/secret/data/api",
+                    web::get().to(|req: HttpRequest| async move {
+                        if !authorized(&req) {
+                            return HttpResponse::Forbidden().finish();
+                        }
+                        HttpResponse::Ok().json(json!({
+                            "lease_id": "",
+                            "lease_duration": 0,
+                            "renewable": false,
+                            "data": {
+                                "data": {"jwt_secret": "from-vault-jwt", "stripe": "whsec_vault"},
+                                "metadata": {"version": 3},
+                            },
+                        }))
+                    }),
+                )
+                .route(
+                    "/v1/database/creds/api",
+                    web::get().to(|req: HttpRequest, vault: web::Data<FakeVault>| async move {
+                        if !authorized(&req) {
+                            return HttpResponse::Forbidden().finish();
+                        }
+                        let read = vault.reads.fetch_add(1, Ordering::SeqCst) + 1;
+                        HttpResponse::Ok().json(json!({
+                            "lease_id": format!("database/creds/api/lease-{}", read),
+                            "lease_duration": 60,
+                            "renewable": true,
+                            "data": {"username": "v-api", "password": format!("pw-{}", read)},
+                        }))
+                    }),
+                )
+                .route(
+                    "/v1/sys/leases/renew",
+                    web::put().to(|body: web::Json<Value>, vault: web::Data<FakeVault>| async move {
+                        if vault.refuse_renewals.load(Ordering::SeqCst) {
+                            return HttpResponse::BadRequest().json(json!({"errors": ["lease not found"]}));
+                        }
+                        let lease_id = body["lease_id"].as_str().unwrap_or_default().to_string();
+                        vault.renewed.lock().unwrap().push(lease_id.clone());
+                        HttpResponse::Ok().json(json!({
+                            "lease_id": lease_id,
+                            "lease_duration": body["increment"],
+                            "renewable": true,
+                        }))
+                    }),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        url
+    }
+
+    fn settings(addr: &str, secrets: &str) -> VaultSettings {
+        VaultSettings {
+            addr: addr.to_string(),
+            token: "root-token".to_string(),
+            namespace: None,
+            secrets: VaultSecret::parse_list(secrets).unwrap(),
+        }
+    }
+
+    /// Makes every lease due now
+    fn expire_leases(provider: &VaultProvider) {
+        for lease in provider.leases.lock().unwrap().values_mut() {
+            lease.renew_at = lease.renew_at.map(|_| Instant::now());
+        }
+    }
+
+    #[test]
+    fn test_settings() {
+        let secrets = VaultSecret::parse_list("JWT_SECRET=secret/data/api#jwt, REDIS_PASSWORD=/database/creds/api/").unwrap();
+        assert_eq!(
+            secrets[1],
+            VaultSecret {
+                var: "REDIS_PASSWORD".to_string(),
+                path: "database/creds/api".to_string(),
+                field: "redis_password".to_string(),
+            }
+        );
+        assert_eq!(secrets[0].field, "jwt");
+        assert!(VaultSecret::parse_list("JWT_SECRET").is_err());
+        assert!(VaultSecret::parse_list("=secret/data/api#jwt").is_err());
+
+        let vars = HashMap::from([("VAULT_SECRETS", "JWT_SECRET=secret/data/api#jwt")]);
+        assert!(matches!(
+            VaultSettings::from_lookup(|name| vars.get(name).map(|value| value.to_string())),
+            Err(AppError::Environment { var_name, .. }) if var_name == "VAULT_ADDR"
+        ));
+        let vars = HashMap::from([
+            ("VAULT_SECRETS", "JWT_SECRET=secret/data/api#jwt"),
+            ("VAULT_ADDR", "https://vault.internal:8200/"),
+            ("VAULT_TOKEN", "s.token"),
+        ]);
+        let settings = VaultSettings::from_lookup(|name| vars.get(name).map(|value| value.to_string()))
+            .unwrap()
+            .unwrap();
+        assert_eq!((settings.addr.as_str(), settings.namespace), ("https://vault.internal:8200", None));
+        assert!(VaultSettings::from_lookup(|_| None).unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_load_and_renew() {
+        let vault = Arc::new(FakeVault::default());
+        let addr = start_vault(vault.clone()).await;
+        let provider = VaultProvider::new(settings(
+            &addr,
+            "JWT_SECRET=secret/data/api#jwt_secret,WEBHOOK_STRIPE_SECRET=secret/data/api#stripe,REDIS_PASSWORD=database/creds/api#password",
+        ));
+        provider.load().await.unwrap();
+        assert_eq!(provider.secret("JWT_SECRET").as_deref(), Some("from-vault-jwt"));
+        assert_eq!(provider.secret("WEBHOOK_STRIPE_SECRET").as_deref(), Some("whsec_vault"));
+        assert_eq!(provider.secret("REDIS_PASSWORD").as_deref(), Some("pw-1"));
+        assert_eq!(provider.secret("PORT"), None);
+        // Only the database lease expires: renewal is due two thirds into it
+        let wait = provider.renew_due().await.unwrap().unwrap();
+        assert!(wait > Duration::from_secs(35) && wait <= Duration::from_secs(40), "{:?}", wait);
+
+        expire_leases(&provider);
+        provider.renew_due().await.unwrap();
+        assert_eq!(*vault.renewed.lock().unwrap(), ["database/creds/api/lease-1"]);
+        assert_eq!(vault.reads.load(Ordering::SeqCst), 1);
+
+        // A lease Vault no longer renews is replaced by new credentials
+        vault.refuse_renewals.store(true, Ordering::SeqCst);
+        expire_leases(&provider);
+        provider.renew_due().await.unwrap();
+        assert_eq!(provider.secret("REDIS_PASSWORD").as_deref(), Some("pw-2"));
+        assert_eq!(provider.leases.lock().unwrap()["database/creds/api"].lease_id, "database/creds/api/lease-2");
+    }
+
+    #[actix_web::test]
+    async fn test_load_failures() {
+        let addr = start_vault(Arc::new(FakeVault::default())).await;
+        let provider = VaultProvider::new(settings(&addr, "JWT_SECRET=secret/data/api#missing"));
+        assert!(matches!(provider.load().await, Err(AppError::Config { .. })));
+
+        let mut refused = settings(&addr, "JWT_SECRET=secret/data/api#jwt_secret");
+        refused.token = "wrong".to_string();
+        let err = VaultProvider::new(refused).load().await.unwrap_err();
+        assert!(err.to_string().contains("403"), "{}", err);
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+use std::str::FromStr;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+use crate::config::Config;
+use crate::error::AppError;
+
+/// Request header naming the lowest API version the client can work with
+pub const MIN_API_VERSION_HEADER: &str = "x-min-api-version";
+/// Response header carrying the API version of the instance that answered
+pub const API_VERSION_HEADER: &str = "x-api-version";
+
+/// `major.minor.patch` API version; missing components count as zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ApiVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ApiVersion {
+    /// Version of this build, from `Cargo.toml`
+    pub fn current() -> Self {
+        env!("CARGO_PKG_VERSION").parse().unwrap_or(Self {
+            major: 0,
+            minor: 0,
+            patch: 0,
+        })
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for ApiVersion {
+    type Err = AppError;
+
+    /// Accepts `2`, `2.1`, `2.1.3` and a leading `v`; pre-release and build
+    /// suffixes (`-rc.1`, `+abc`) are ignored
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || AppError::validation(format!("invalid API version '{}'", value));
+        let trimmed = value.trim();
+        let trimmed = trimmed.strip_prefix(['v', 'V']).unwrap_or(trimmed);
+        let core = trimmed.split(['-', '+']).next().unwrap_or_default();
+        let parts = core
+            .split('.')
+            .map(|part| part.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match parts[..] {
+            [major] => Ok(Self { major, minor: 0, patch: 0 }),
+            [major, minor] => Ok(Self { major, minor, patch: 0 }),
+            [major, minor, patch] => Ok(Self { major, minor, patch }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Middleware refusing requests that need a newer API than this instance
+/// serves, for use with `from_fn`
+///
+/// During a rolling deploy old and new instances serve side by side. A
+/// client that relies on new behavior sends `X-Min-Api-Version`; an older
+/// instance answers 426 Upgrade Required instead of silently handling the
+/// request the old way, and the client can retry until it reaches an
+/// upgraded one. Every response carries the instance's `X-Api-Version`.
+pub async fn version_handshake(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let version = req
+        .app_data::<web::Data<Config>>()
+        .map(|config| config.api_version)
+        .unwrap_or_else(ApiVersion::current);
+    let required = req
+        .headers()
+        .get(MIN_API_VERSION_HEADER)
+        .map(|value| value.to_str().unwrap_or_default().parse::<ApiVersion>())
+        .transpose()?;
+    if let Some(required) = required.filter(|required| *required > version) {
+        return Err(AppError::upgrade_required(
+            format!("this instance serves API version {}, the request requires {}", version, required),
+            version.to_string(),
+        )
+        .into());
+    }
+
+    let mut res = next.call(req).await?;
+    if let Ok(value) = HeaderValue::from_str(&version.to_string()) {
+        res.headers_mut().insert(HeaderName::from_static(API_VERSION_HEADER), value);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn version(value: &str) -> ApiVersion {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_version_parsing_and_ordering() {
+        assert_eq!(version("2"), ApiVersion { major: 2, minor: 0, patch: 0 });
+        assert_eq!(version("v1.4"), ApiVersion { major: 1, minor: 4, patch: 0 });
+        assert_eq!(version(" 1.4.2-rc.1+build5 ").to_string(), "1.4.2");
+        assert!(version("1.10.0") > version("1.9.7"));
+        assert!(version("2.0") > version("1.99.99"));
+        for invalid in ["", "1.x", "1.2.3.4", "latest", "-1"] {
+            assert!(invalid.parse::<ApiVersion>().is_err(), "{}", invalid);
+        }
+        assert_eq!(ApiVersion::current().to_string(), env!("CARGO_PKG_VERSION"));
+    }
+
+    #[actix_web::test]
+    async fn test_handshake() {
+        let config = Config {
+            api_version: version("1.4.0"),
+            ..Config::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(version_handshake))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |min: Option<&str>| {
+            let req = TestRequest::get().uri("/");
+            match min {
+                Some(min) => req.insert_header((MIN_API_VERSION_HEADER, min.to_string())),
+                None => req,
+            }
+            .to_request()
+        };
+
+        for accepted in [None, Some("1"), Some("1.4"), Some("1.3.9")] {
+            let res = call_service(&app, request(accepted)).await;
+            assert!(res.status().is_success(), "{:?}", accepted);
+            assert_eq!(res.headers().get(API_VERSION_HEADER).unwrap(), "1.4.0");
+        }
+
+        let err = try_call_service(&app, request(Some("1.5"))).await.err().unwrap();
+        let res = err.error_response();
+        assert_eq!(res.status(), 426);
+        assert_eq!(res.headers().get(API_VERSION_HEADER).unwrap(), "1.4.0");
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["type"], "upgrade_required");
+        assert_eq!(body["error"]["instance_version"], "1.4.0");
+
+        let err = try_call_service(&app, request(Some("next"))).await.err().unwrap();
+        assert_eq!(err.as_response_error().status_code(), 400);
+    }
+}
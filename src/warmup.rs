@@ -0,0 +1,266 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::auth::OidcClient;
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::routes::RouteRegistry;
+
+/// `Accept-Encoding` values each path is requested with, so every
+/// compressed variant is cached
+pub const WARM_ENCODINGS: &[&str] = &["br", "gzip"];
+
+/// Timeout of one warm-up request
+const WARM_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Progress of the cache warm-up, shared with the readiness endpoint
+#[derive(Debug, Default)]
+pub struct WarmupStatus {
+    total: AtomicUsize,
+    done: AtomicUsize,
+    failed: AtomicUsize,
+    complete: AtomicBool,
+}
+
+impl WarmupStatus {
+    /// Status of a warm-up that has not run yet
+    pub fn pending() -> Self {
+        Self::default()
+    }
+
+    /// Status when warm-up is disabled: complete from the start
+    pub fn skipped() -> Self {
+        let status = Self::default();
+        status.complete.store(true, Ordering::SeqCst);
+        status
+    }
+
+    /// Builds the status from `CACHE_WARM_ENABLED`
+    pub fn from_config(config: &Config) -> Self {
+        if config.cache_warm_enabled {
+            Self::pending()
+        } else {
+            Self::skipped()
+        }
+    }
+
+    /// Whether the warm-up finished, successfully or not
+    pub fn is_complete(&self) -> bool {
+        self.complete.load(Ordering::SeqCst)
+    }
+
+    /// Requests made and planned so far
+    pub fn progress(&self) -> (usize, usize) {
+        (self.done.load(Ordering::SeqCst), self.total.load(Ordering::SeqCst))
+    }
+
+    /// Requests that failed
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Marks the warm-up as finished
+    pub fn mark_complete(&self) {
+        self.complete.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Populates response and repository caches right after startup
+///
+/// Requests every path of the manifest from the app server over loopback,
+/// once per [`WARM_ENCODINGS`] entry, and prefetches the OpenID Connect
+/// discovery document and keys. Failures are logged and counted but do
+/// not keep the service unready: the warm-up only delays readiness until
+/// it finished.
+pub struct CacheWarmer {
+    paths: Vec<String>,
+    status: Arc<WarmupStatus>,
+    oidc: Option<Arc<OidcClient>>,
+    http: reqwest::Client,
+}
+
+impl CacheWarmer {
+    /// Creates a warmer requesting `paths` and reporting to `status`
+    pub fn new(paths: Vec<String>, status: Arc<WarmupStatus>) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(WARM_REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self {
+            paths,
+            status,
+            oidc: None,
+            http,
+        }
+    }
+
+    /// Also prefetches the provider metadata of `oidc`
+    pub fn with_oidc(mut self, oidc: Arc<OidcClient>) -> Self {
+        self.oidc = Some(oidc);
+        self
+    }
+
+    /// Paths to warm: `CACHE_WARM_MANIFEST` when set, the routes marked with
+    /// [`RouteSpec::warm_cache`](crate::routes::RouteSpec::warm_cache) otherwise
+    ///
+    /// # Errors
+    /// Returns a config error when the manifest cannot be read or lists
+    /// something other than absolute paths
+    pub fn manifest(config: &Config, routes: &RouteRegistry) -> AppResult<Vec<String>> {
+        let Some(file) = &config.cache_warm_manifest else {
+            return Ok(routes.warm_paths().into_iter().map(str::to_string).collect());
+        };
+        let contents = std::fs::read_to_string(file)
+            .map_err(|e| AppError::config(format!("Failed to read CACHE_WARM_MANIFEST {}: {}", file, e)))?;
+        parse_manifest(&contents).map_err(|e| AppError::config(format!("Invalid CACHE_WARM_MANIFEST {}: {}", file, e)))
+    }
+
+    /// Warms the caches through the server listening on `address`
+    pub async fn run(&self, address: SocketAddr) {
+        let started = Instant::now();
+        let oidc_steps = usize::from(self.oidc.is_some());
+        self.status
+            .total
+            .store(self.paths.len() * WARM_ENCODINGS.len() + oidc_steps, Ordering::SeqCst);
+        info!("Cache warm-up of {} paths started", self.paths.len());
+
+        if let Some(oidc) = &self.oidc {
+            let result = oidc.prefetch().await;
+            self.step("OIDC provider metadata", result.map(|_| "cached".to_string()).map_err(|e| e.to_string()));
+        }
+        for path in &self.paths {
+            for encoding in WARM_ENCODINGS {
+                let result = self
+                    .http
+                    .get(format!("http://{}{}", address, path))
+                    .header(reqwest::header::ACCEPT_ENCODING, *encoding)
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status);
+                let result = match result {
+                    // The body is read so the whole response is produced and cached
+                    Ok(response) => response.bytes().await.map(|body| format!("{} bytes", body.len())),
+                    Err(e) => Err(e),
+                };
+                self.step(&format!("{} ({})", path, encoding), result.map_err(|e| e.to_string()));
+            }
+        }
+
+        self.status.mark_complete();
+        let (done, _) = self.status.progress();
+        info!(
+            "Cache warm-up complete in {:?}: {} requests, {} failed",
+            started.elapsed(),
+            done,
+            self.status.failed()
+        );
+    }
+
+    fn step(&self, target: &str, result: Result<String, String>) {
+        let done = self.status.done.fetch_add(1, Ordering::SeqCst) + 1;
+        let total = self.status.total.load(Ordering::SeqCst);
+        match result {
+            Ok(outcome) => info!("Cache warm-up {}/{}: {} -> {}", done, total, target, outcome),
+            Err(e) => {
+                self.status.failed.fetch_add(1, Ordering::SeqCst);
+                warn!("Cache warm-up {}/{}: {} failed: {}", done, total, target, e);
+            }
+        }
+    }
+}
+
+/// Parses a manifest: one path per line, blank lines and `#` comments ignored
+fn parse_manifest(contents: &str) -> Result<Vec<String>, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            if line.starts_with('/') && !line.contains(char::is_whitespace) {
+                Ok(line.to_string())
+            } else {
+                Err(format!("'{}' is not an absolute path", line))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::{AssetStore, Encoding};
+    use actix_web::{web, App, HttpServer};
+
+    #[test]
+    fn test_manifest_sources() {
+        let routes = RouteRegistry::app_server();
+        let generated = CacheWarmer::manifest(&Config::default(), &routes).unwrap();
+        assert!(generated.contains(&"/docs".to_string()));
+
+        let file = std::env::temp_dir().join(format!("warm-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&file, "# landing pages\n/docs\n\n  /public?page=1\n").unwrap();
+        let config = Config {
+            cache_warm_manifest: Some(file.display().to_string()),
+            ..Config::default()
+        };
+        assert_eq!(CacheWarmer::manifest(&config, &routes).unwrap(), vec!["/docs", "/public?page=1"]);
+
+        std::fs::write(&file, "https://example.com/docs\n").unwrap();
+        let err = CacheWarmer::manifest(&config, &routes).unwrap_err();
+        assert!(err.to_string().contains("not an absolute path"), "{}", err);
+        std::fs::remove_file(&file).unwrap();
+
+        let missing = Config {
+            cache_warm_manifest: Some("/nonexistent/warm.txt".to_string()),
+            ..Config::default()
+        };
+        assert!(matches!(CacheWarmer::manifest(&missing, &routes), Err(AppError::Config { .. })));
+    }
+
+    #[test]
+    fn test_status_from_config() {
+        assert!(WarmupStatus::from_config(&Config::default()).is_complete());
+        let config = Config {
+            cache_warm_enabled: true,
+            ..Config::default()
+        };
+        assert!(!WarmupStatus::from_config(&config).is_complete());
+    }
+
+    #[actix_web::test]
+    async fn test_run_requests_every_path_and_completes() {
+        let assets = web::Data::new(AssetStore::new(None));
+        let server = HttpServer::new({
+            let assets = assets.clone();
+            move || {
+                App::new()
+                    .app_data(assets.clone())
+                    .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+            }
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let status = Arc::new(WarmupStatus::pending());
+        let paths = vec!["/docs".to_string(), "/missing".to_string()];
+        CacheWarmer::new(paths, status.clone()).run(address).await;
+        assert!(status.is_complete());
+        assert_eq!(status.progress(), (4, 4));
+        assert_eq!(status.failed(), 2);
+        // Both compressed variants were produced before any client asked
+        assert!(assets.is_cached("/docs", Encoding::Brotli));
+        assert!(assets.is_cached("/docs", Encoding::Gzip));
+        assert!(!assets.is_cached("/console", Encoding::Gzip));
+
+        handle.stop(true).await;
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use actix_web::http::header::HeaderMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signature scheme used by an inbound webhook provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// `X-Hub-Signature-256: sha256=<hex hmac(body)>`
+    GitHub,
+    /// `Stripe-Signature: t=<unix ts>,v1=<hex hmac("<ts>.<body>")>`
+    Stripe,
+}
+
+/// A configured inbound webhook provider
+#[derive(Debug, Clone)]
+pub struct WebhookProvider {
+    /// How requests from this provider are signed
+    pub scheme: SignatureScheme,
+    /// Shared secret used for the HMAC
+    secret: String,
+}
+
+/// Verifies signatures of inbound webhook deliveries
+///
+/// Only providers with a configured secret are accepted; anything else is
+/// rejected before the payload is looked at.
+#[derive(Debug, Clone, Default)]
+pub struct WebhookVerifier {
+    providers: HashMap<String, WebhookProvider>,
+    tolerance: Duration,
+}
+
+impl WebhookVerifier {
+    /// Creates a verifier accepting timestamps within `tolerance` of now
+    pub fn new(tolerance: Duration) -> Self {
+        Self {
+            providers: HashMap::new(),
+            tolerance,
+        }
+    }
+
+    /// Builds a verifier from the webhook secrets in the configuration
+    pub fn from_config(config: &Config) -> Self {
+        let mut verifier = Self::new(Duration::from_secs(config.webhook_tolerance_secs));
+        if let Some(secret) = &config.webhook_github_secret {
+            verifier = verifier.with_provider("github", SignatureScheme::GitHub, secret);
+        }
+        if let Some(secret) = &config.webhook_stripe_secret {
+            verifier = verifier.with_provider("stripe", SignatureScheme::Stripe, secret);
+        }
+        verifier
+    }
+
+    /// Registers a provider under `name` (the `{provider}` path segment)
+    pub fn with_provider<N: Into<String>, S: Into<String>>(
+        mut self,
+        name: N,
+        scheme: SignatureScheme,
+        secret: S,
+    ) -> Self {
+        self.providers.insert(
+            name.into(),
+            WebhookProvider {
+                scheme,
+                secret: secret.into(),
+            },
+        );
+        self
+    }
+
+    /// Returns whether `provider` is configured
+    pub fn has_provider(&self, provider: &str) -> bool {
+        self.providers.contains_key(provider)
+    }
+
+    /// Verifies the signature of a delivery from `provider`
+    ///
+    /// # Errors
+    /// Returns a not found error for unknown providers and an unauthorized
+    /// error for missing, malformed, stale or mismatching signatures.
+    pub fn verify(&self, provider: &str, headers: &HeaderMap, body: &[u8]) -> AppResult<()> {
+        let config = self
+            .providers
+            .get(provider)
+            .ok_or_else(|| AppError::not_found(format!("unknown webhook provider '{}'", provider)))?;
+
+        match config.scheme {
+            SignatureScheme::GitHub => {
+                let header = header_str(headers, "x-hub-signature-256")?;
+                let signature = header
+                    .strip_prefix("sha256=")
+                    .ok_or_else(|| AppError::unauthorized("signature must start with 'sha256='"))?;
+                verify_hmac(&config.secret, body, signature)
+            }
+            SignatureScheme::Stripe => {
+                let header = header_str(headers, "stripe-signature")?;
+                let (timestamp, signatures) = parse_stripe_header(header)?;
+                self.check_timestamp(timestamp, chrono::Utc::now().timestamp())?;
+
+                let mut signed = format!("{}.", timestamp).into_bytes();
+                signed.extend_from_slice(body);
+                if signatures
+                    .iter()
+                    .any(|signature| verify_hmac(&config.secret, &signed, signature).is_ok())
+                {
+                    Ok(())
+                } else {
+                    Err(AppError::unauthorized("webhook signature mismatch"))
+                }
+            }
+        }
+    }
+
+    fn check_timestamp(&self, timestamp: i64, now: i64) -> AppResult<()> {
+        if now.abs_diff(timestamp) > self.tolerance.as_secs() {
+            return Err(AppError::unauthorized(
+                "webhook timestamp outside the tolerance window",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> AppResult<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AppError::unauthorized(format!("missing {} header", name)))
+}
+
+/// Splits `t=123,v1=abc,v1=def` into the timestamp and the `v1` signatures
+fn parse_stripe_header(header: &str) -> AppResult<(i64, Vec<&str>)> {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.push(value),
+            _ => {}
+        }
+    }
+
+    let timestamp = timestamp.ok_or_else(|| AppError::unauthorized("signature header missing timestamp"))?;
+    if signatures.is_empty() {
+        return Err(AppError::unauthorized("signature header missing v1 signature"));
+    }
+    Ok((timestamp, signatures))
+}
+
+/// Compares a hex HMAC-SHA256 signature in constant time
+fn verify_hmac(secret: &str, message: &[u8], signature_hex: &str) -> AppResult<()> {
+    let expected = hex::decode(signature_hex)
+        .map_err(|_| AppError::unauthorized("signature is not valid hex"))?;
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .map_err(|e| AppError::internal(format!("invalid HMAC key: {}", e)))?;
+    mac.update(message);
+    mac.verify_slice(&expected)
+        .map_err(|_| AppError::unauthorized("webhook signature mismatch"))
+}
+
+/// Computes a hex HMAC-SHA256 signature (used by tests and outbound senders)
+pub fn sign(secret: &str, message: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(message);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+        map
+    }
+
+    fn verifier() -> WebhookVerifier {
+        WebhookVerifier::new(Duration::from_secs(300))
+            .with_provider("github", SignatureScheme::GitHub, "gh-secret")
+            .with_provider("stripe", SignatureScheme::Stripe, "stripe-secret")
+    }
+
+    #[test]
+    fn test_github_signature() {
+        let body = br#"{"action":"opened"}"#;
+        let valid = headers("x-hub-signature-256", &format!("sha256={}", sign("gh-secret", body)));
+        assert!(verifier().verify("github", &valid, body).is_ok());
+
+        let wrong = headers("x-hub-signature-256", &format!("sha256={}", sign("other", body)));
+        assert!(matches!(
+            verifier().verify("github", &wrong, body),
+            Err(AppError::Unauthorized { .. })
+        ));
+
+        assert!(verifier().verify("github", &HeaderMap::new(), body).is_err());
+    }
+
+    #[test]
+    fn test_stripe_signature_and_tolerance() {
+        let body = br#"{"type":"charge.succeeded"}"#;
+        let now = chrono::Utc::now().timestamp();
+        let signed = format!("{}.{}", now, std::str::from_utf8(body).unwrap());
+        let header = format!("t={},v1=deadbeef,v1={}", now, sign("stripe-secret", signed.as_bytes()));
+        assert!(verifier().verify("stripe", &headers("stripe-signature", &header), body).is_ok());
+
+        let old = now - 3600;
+        let signed = format!("{}.{}", old, std::str::from_utf8(body).unwrap());
+        let header = format!("t={},v1={}", old, sign("stripe-secret", signed.as_bytes()));
+        assert!(verifier().verify("stripe", &headers("stripe-signature", &header), body).is_err());
+    }
+
+    #[test]
+    fn test_unknown_provider() {
+        let result = verifier().verify("gitlab", &HeaderMap::new(), b"");
+        assert!(matches!(result, Err(AppError::NotFound { .. })));
+    }
+}
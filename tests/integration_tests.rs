@@ -1,16 +1,29 @@
 use actix_web::{test, web, App, http::StatusCode};
 use simple_api_demo::handlers::{app_server, main_server};
+use simple_api_demo::supervisor::Supervisor;
 use serde_json::Value;
 use std::sync::Mutex;
 
-/// Integration tests for the application endpoints
-/// 
-/// These tests verify the complete behavior of HTTP endpoints
-/// including request/response handling and JSON serialization.
+// Integration tests for the application endpoints
+// 
+// These tests verify the complete behavior of HTTP endpoints
+// including request/response handling and JSON serialization.
 
 // Use a mutex to prevent tests from running concurrently and interfering with env vars
 static TEST_MUTEX: Mutex<()> = Mutex::new(());
 
+/// Signs a token for `subject` with `scopes` and the `roles` claim
+fn token_with_roles(
+    tokens: &simple_api_demo::auth::TokenService,
+    subject: &str,
+    scopes: &[&str],
+    roles: &[&str],
+) -> String {
+    let mut claims = tokens.claims(subject, scopes, std::time::Duration::from_secs(300));
+    claims.extra.insert("roles".to_string(), serde_json::json!(roles));
+    tokens.sign(&claims).unwrap()
+}
+
 #[actix_web::test]
 async fn test_main_server_hello_endpoint() {
     let app = test::init_service(
@@ -33,6 +46,32 @@ async fn test_main_server_hello_endpoint() {
     assert_eq!(resp.status(), StatusCode::OK);
 }
 
+#[actix_web::test]
+async fn test_lb_weight_endpoint() {
+    use simple_api_demo::lb_weight::LoadMonitor;
+    use std::time::Duration;
+
+    let monitor = web::Data::new(LoadMonitor::new(4, 0.5, Duration::from_secs(60), 1));
+    let app = test::init_service(
+        App::new()
+            .app_data(monitor.clone())
+            .route("/lb-weight", web::get().to(main_server::lb_weight))
+    ).await;
+
+    let _busy = monitor.begin();
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/lb-weight").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.headers().get("endpoint-load-metrics").unwrap().to_str().unwrap().starts_with("TEXT application_utilization=0.250"));
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["weight"], 75);
+    assert_eq!(body["in_flight"], 1);
+
+    monitor.set_draining(true);
+    let req = test::TestRequest::get().uri("/lb-weight?format=haproxy").to_request();
+    let body = test::read_body(test::call_service(&app, req).await).await;
+    assert_eq!(body, "drain\n");
+}
+
 #[actix_web::test]
 async fn test_app_server_endpoints() {
     let app = test::init_service(
@@ -75,6 +114,42 @@ async fn test_app_server_endpoints() {
     assert!(body["warning"].is_string());
 }
 
+#[actix_web::test]
+async fn test_version_and_served_by() {
+    use actix_web::middleware::from_fn;
+    use simple_api_demo::config::Config;
+    use simple_api_demo::region::Placement;
+
+    let config = Config {
+        region: Some("eu-west-1".to_string()),
+        zone: Some("eu-west-1a".to_string()),
+        region_affinity_check: true,
+        ..Config::default()
+    };
+    let placement = web::Data::new(Placement::from_config(&config));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(config))
+            .app_data(placement.clone())
+            .wrap(from_fn(simple_api_demo::context::attach_context))
+            .route("/version", web::get().to(app_server::version))
+    ).await;
+
+    let req = test::TestRequest::get()
+        .uri("/version")
+        .insert_header(("X-Expected-Region", "us-east-1"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("x-served-by").unwrap(), "eu-west-1/eu-west-1a");
+
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["region"], "eu-west-1");
+    assert_eq!(body["zone"], "eu-west-1a");
+    assert!(body["api_version"].is_string());
+    assert_eq!(placement.affinity_mismatches(), 1);
+}
+
 #[actix_web::test]
 async fn test_app_server_content_types() {
     let app = test::init_service(
@@ -145,4 +220,1493 @@ async fn test_config_with_custom_env() {
     std::env::remove_var("PORT");
     std::env::remove_var("PORT_APP");
     std::env::remove_var("BIND_ADDRESS");
-} 
\ No newline at end of file
+} 
+#[actix_web::test]
+async fn test_webhook_receiver_verifies_and_accepts() {
+    use simple_api_demo::handlers::hooks;
+    use simple_api_demo::jobs::{JobHandlers, JobQueue};
+    use simple_api_demo::webhooks::{sign, SignatureScheme, WebhookVerifier};
+
+    let verifier = WebhookVerifier::new(std::time::Duration::from_secs(300))
+        .with_provider("github", SignatureScheme::GitHub, "secret");
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(verifier))
+            .app_data(web::Data::new(JobQueue::start(16, JobHandlers::new(), &std::sync::Arc::new(Supervisor::default()))))
+            .route("/hooks/{provider}", web::post().to(hooks::receive))
+    ).await;
+
+    let body = r#"{"zen":"Keep it logically awesome."}"#;
+
+    // Valid signature is accepted and queued
+    let req = test::TestRequest::post()
+        .uri("/hooks/github")
+        .insert_header(("X-Hub-Signature-256", format!("sha256={}", sign("secret", body.as_bytes()))))
+        .insert_header(("X-GitHub-Event", "ping"))
+        .set_payload(body)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    let json: Value = test::read_body_json(resp).await;
+    assert_eq!(json["status"], "accepted");
+    assert!(json["job_id"].is_string());
+
+    // Tampered body is rejected
+    let req = test::TestRequest::post()
+        .uri("/hooks/github")
+        .insert_header(("X-Hub-Signature-256", format!("sha256={}", sign("secret", body.as_bytes()))))
+        .set_payload("{}")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // Unconfigured provider
+    let req = test::TestRequest::post().uri("/hooks/gitlab").set_payload(body).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_token_introspection() {
+    use base64::Engine;
+    use simple_api_demo::auth::{ClientRegistry, TokenDenylist, TokenService};
+    use simple_api_demo::handlers::auth;
+    use simple_api_demo::state::InMemoryStore;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let token = tokens
+        .issue("alice", &["read:private"], std::time::Duration::from_secs(300))
+        .unwrap();
+    let revoked = tokens.claims("alice", &["read:private"], std::time::Duration::from_secs(300));
+    let denylist = TokenDenylist::new(std::sync::Arc::new(InMemoryStore::new()));
+    denylist.revoke_token(&revoked.jti, std::time::Duration::from_secs(300)).unwrap();
+    let revoked = tokens.sign(&revoked).unwrap();
+    let clients = ClientRegistry::new(vec![("gateway".to_string(), "gateway-secret".to_string())]);
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(clients))
+            .app_data(web::Data::new(denylist))
+            .route("/auth/introspect", web::post().to(auth::introspect))
+    ).await;
+    let basic = format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode("gateway:gateway-secret")
+    );
+
+    // Active token reports its claims
+    let req = test::TestRequest::post()
+        .uri("/auth/introspect")
+        .insert_header(("Authorization", basic.clone()))
+        .set_form([("token", token.as_str())])
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["active"], true);
+    assert_eq!(body["sub"], "alice");
+    assert_eq!(body["scope"], "read:private");
+    assert!(body["exp"].is_i64());
+
+    // Garbage and revoked tokens are simply inactive
+    for inactive in ["not-a-token", revoked.as_str()] {
+        let req = test::TestRequest::post()
+            .uri("/auth/introspect")
+            .insert_header(("Authorization", basic.clone()))
+            .set_form([("token", inactive)])
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, serde_json::json!({"active": false}));
+    }
+
+    // Client credentials are required
+    let req = test::TestRequest::post()
+        .uri("/auth/introspect")
+        .set_form([("token", token.as_str())])
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_guest_token_issuance() {
+    use simple_api_demo::auth::challenge::FixedChallengeProvider;
+    use simple_api_demo::auth::{ChallengeGate, GuestTokenIssuer, TokenService};
+    use simple_api_demo::handlers::auth;
+    use simple_api_demo::state::InMemoryStore;
+    use std::sync::Arc;
+
+    let guests = GuestTokenIssuer::new(
+        vec!["read:guest".to_string()],
+        std::time::Duration::from_secs(600),
+        1,
+        Arc::new(InMemoryStore::new()),
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo")))
+            .app_data(web::Data::new(guests))
+            .app_data(web::Data::new(
+                ChallengeGate::new(1, std::time::Duration::from_secs(60), Arc::new(InMemoryStore::new()))
+                    .with_provider(FixedChallengeProvider::new("solved")),
+            ))
+            .route("/auth/guest", web::post().to(auth::guest))
+    ).await;
+    let peer: std::net::SocketAddr = "203.0.113.7:5000".parse().unwrap();
+
+    let req = test::TestRequest::post().uri("/auth/guest").peer_addr(peer).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["token_type"], "Bearer");
+    assert_eq!(body["scope"], "read:guest");
+    assert_eq!(body["expires_in"], 600);
+    assert!(body["access_token"].is_string());
+
+    // Second request from the same address exceeds the hourly allowance
+    let req = test::TestRequest::post().uri("/auth/guest").peer_addr(peer).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(resp.headers().contains_key("retry-after"));
+
+    // That failure crosses the challenge threshold
+    let req = test::TestRequest::post().uri("/auth/guest").peer_addr(peer).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["type"], "challenge_required");
+}
+
+#[actix_web::test]
+async fn test_login_with_two_factor_enrollment() {
+    use simple_api_demo::auth::{ChallengeGate, SessionRegistry, TokenDenylist, TokenService, TwoFactorService};
+    use simple_api_demo::crypto::Cipher;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::{UserRepository, UserService};
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let users = UserService::new(repository.clone(), &tokens, Arc::new(InMemoryStore::new()));
+    let two_factor = TwoFactorService::new(repository, Cipher::new(b"integration-data-key"), "demo", Vec::new());
+    users.register("ann@example.com", "correct horse").await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(users))
+            .app_data(web::Data::new(two_factor))
+            .app_data(web::Data::new(ChallengeGate::new(
+                5,
+                std::time::Duration::from_secs(60),
+                Arc::new(InMemoryStore::new()),
+            )))
+            .app_data(web::Data::new(SessionRegistry::new(
+                Arc::new(InMemoryStore::new()),
+                TokenDenylist::new(Arc::new(InMemoryStore::new())),
+                std::time::Duration::from_secs(3600),
+            )))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    let peer: std::net::SocketAddr = "198.51.100.4:4000".parse().unwrap();
+    let login = |otp: Option<&str>| {
+        let mut body = serde_json::json!({"email": "ann@example.com", "password": "correct horse"});
+        if let Some(otp) = otp {
+            body["otp"] = serde_json::json!(otp);
+        }
+        test::TestRequest::post().uri("/auth/login").peer_addr(peer).set_json(body).to_request()
+    };
+
+    let body: Value = test::call_and_read_body_json(&app, login(None)).await;
+    assert_eq!(body["scope"], "account read:private");
+    let bearer = format!("Bearer {}", body["access_token"].as_str().unwrap());
+
+    let req = test::TestRequest::post()
+        .uri("/auth/2fa/setup")
+        .insert_header(("Authorization", bearer.clone()))
+        .to_request();
+    let provisioning: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(provisioning["otpauth_url"].as_str().unwrap().starts_with("otpauth://totp/"));
+
+    // A deliberately wrong code is refused
+    let req = test::TestRequest::post()
+        .uri("/auth/2fa/confirm")
+        .insert_header(("Authorization", bearer.clone()))
+        .set_json(serde_json::json!({"code": "abcdef"}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let secret = data_encoding::BASE32_NOPAD
+        .decode(provisioning["secret"].as_str().unwrap().as_bytes())
+        .unwrap();
+    let step = simple_api_demo::auth::totp::step_for(chrono::Utc::now().timestamp() as u64);
+    let req = test::TestRequest::post()
+        .uri("/auth/2fa/confirm")
+        .insert_header(("Authorization", bearer))
+        .set_json(serde_json::json!({"code": simple_api_demo::auth::totp::code_at_step(&secret, step)}))
+        .to_request();
+    let confirmed: Value = test::call_and_read_body_json(&app, req).await;
+    let recovery = confirmed["recovery_codes"][0].as_str().unwrap().to_string();
+
+    // Password alone is no longer enough
+    assert_eq!(test::call_service(&app, login(None)).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(test::call_service(&app, login(Some(&recovery))).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, login(Some(&recovery))).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_login_lockout() {
+    use simple_api_demo::auth::{ChallengeGate, LoginLockout, SessionRegistry, TokenDenylist, TokenService, TwoFactorService};
+    use simple_api_demo::crypto::Cipher;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::{UserRepository, UserService};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let users = UserService::new(repository.clone(), &tokens, Arc::new(InMemoryStore::new()));
+    let two_factor = TwoFactorService::new(repository, Cipher::new(b"integration-data-key"), "demo", Vec::new());
+    users.register("ann@example.com", "correct horse").await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(users))
+            .app_data(web::Data::new(two_factor))
+            .app_data(web::Data::new(ChallengeGate::new(5, Duration::from_secs(60), Arc::new(InMemoryStore::new()))))
+            .app_data(web::Data::new(LoginLockout::new(
+                3,
+                Duration::from_secs(60),
+                Duration::from_secs(600),
+                Arc::new(InMemoryStore::new()),
+            )))
+            .app_data(web::Data::new(SessionRegistry::new(
+                Arc::new(InMemoryStore::new()),
+                TokenDenylist::new(Arc::new(InMemoryStore::new())),
+                Duration::from_secs(3600),
+            )))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    let login = |password: &str, peer: &str| {
+        test::TestRequest::post()
+            .uri("/auth/login")
+            .peer_addr(peer.parse().unwrap())
+            .set_json(serde_json::json!({"email": "ann@example.com", "password": password}))
+            .to_request()
+    };
+
+    for peer in ["198.51.100.4:4000", "198.51.100.5:4000", "198.51.100.6:4000"] {
+        assert_eq!(test::call_service(&app, login("guess", peer)).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // The account is locked out, even for the right password from a new address
+    let resp = test::call_service(&app, login("correct horse", "198.51.100.7:4000")).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(resp.headers().contains_key("retry-after"));
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["type"], "locked_out");
+    let locked_until = chrono::DateTime::parse_from_rfc3339(body["error"]["locked_until"].as_str().unwrap()).unwrap();
+    assert!(locked_until > chrono::Utc::now() + chrono::Duration::seconds(590));
+}
+
+#[actix_web::test]
+async fn test_session_listing_and_revocation() {
+    use simple_api_demo::auth::{ChallengeGate, SessionRegistry, TokenDenylist, TokenService, TwoFactorService};
+    use simple_api_demo::crypto::Cipher;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::{UserRepository, UserService};
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let users = UserService::new(repository.clone(), &tokens, Arc::new(InMemoryStore::new()));
+    let two_factor = TwoFactorService::new(repository, Cipher::new(b"integration-data-key"), "demo", Vec::new());
+    users.register("ann@example.com", "correct horse").await.unwrap();
+    let denylist = TokenDenylist::new(Arc::new(InMemoryStore::new()));
+    let sessions = SessionRegistry::new(
+        Arc::new(InMemoryStore::new()),
+        denylist.clone(),
+        std::time::Duration::from_secs(3600),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(users))
+            .app_data(web::Data::new(two_factor))
+            .app_data(web::Data::new(ChallengeGate::new(
+                5,
+                std::time::Duration::from_secs(60),
+                Arc::new(InMemoryStore::new()),
+            )))
+            .app_data(web::Data::new(denylist))
+            .app_data(web::Data::new(sessions))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    let peer: std::net::SocketAddr = "198.51.100.4:4000".parse().unwrap();
+    let login = |device: &str| {
+        test::TestRequest::post()
+            .uri("/auth/login")
+            .peer_addr(peer)
+            .insert_header(("User-Agent", "curl/8.0"))
+            .set_json(serde_json::json!({
+                "email": "ann@example.com",
+                "password": "correct horse",
+                "device_name": device,
+            }))
+            .to_request()
+    };
+
+    let laptop: Value = test::call_and_read_body_json(&app, login("Laptop")).await;
+    let phone: Value = test::call_and_read_body_json(&app, login("Phone")).await;
+    let laptop_bearer = format!("Bearer {}", laptop["access_token"].as_str().unwrap());
+    let phone_bearer = format!("Bearer {}", phone["access_token"].as_str().unwrap());
+
+    let req = test::TestRequest::get()
+        .uri("/me/sessions")
+        .insert_header(("Authorization", phone_bearer.clone()))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let listed = body["sessions"].as_array().unwrap();
+    assert_eq!(listed.len(), 2);
+    let current: Vec<&Value> = listed.iter().filter(|session| session["current"] == true).collect();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0]["device_name"], "Phone");
+    assert_eq!(current[0]["ip"], "198.51.100.4");
+
+    // Revoking the laptop session from the phone locks the laptop token out
+    let req = test::TestRequest::delete()
+        .uri(&format!("/me/sessions/{}", laptop["session_id"].as_str().unwrap()))
+        .insert_header(("Authorization", phone_bearer.clone()))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+
+    let req = test::TestRequest::get()
+        .uri("/me/sessions")
+        .insert_header(("Authorization", laptop_bearer))
+        .to_request();
+    let err = test::try_call_service(&app, req).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::delete()
+        .uri("/me/sessions/unknown")
+        .insert_header(("Authorization", phone_bearer))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_api_key_lifecycle() {
+    use simple_api_demo::auth::{ApiKeyService, TokenService};
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let bearer = format!(
+        "Bearer {}",
+        tokens
+            .issue("ann", &["account", "read:private"], std::time::Duration::from_secs(300))
+            .unwrap()
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(ApiKeyService::new(Arc::new(InMemoryStore::new()))))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+
+    // Keys cannot exceed the caller's scopes
+    let req = test::TestRequest::post()
+        .uri("/me/api-keys")
+        .insert_header(("Authorization", bearer.clone()))
+        .set_json(serde_json::json!({"name": "too much", "scopes": ["admin:impersonate"]}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::post()
+        .uri("/me/api-keys")
+        .insert_header(("Authorization", bearer.clone()))
+        .set_json(serde_json::json!({"name": "CI", "expires_in_days": 30}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let created: Value = test::read_body_json(resp).await;
+    assert_eq!(created["scopes"], serde_json::json!(["read:private"]));
+    let key = format!("Bearer {}", created["key"].as_str().unwrap());
+
+    // The key works where its scopes allow, but cannot manage the account
+    let req = test::TestRequest::get().uri("/private").insert_header(("Authorization", key.clone())).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::get().uri("/me/api-keys").insert_header(("Authorization", key.clone())).to_request();
+    let err = test::try_call_service(&app, req).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::FORBIDDEN);
+
+    // The listing never shows the secret, but shows when the key was last used
+    let req = test::TestRequest::get()
+        .uri("/me/api-keys")
+        .insert_header(("Authorization", bearer.clone()))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    let listed = &body["api_keys"][0];
+    assert_eq!(listed["name"], "CI");
+    assert!(listed.get("key").is_none());
+    assert!(listed["last_used_at"].is_string());
+    assert!(listed["expires_at"].is_string());
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/me/api-keys/{}", created["id"].as_str().unwrap()))
+        .insert_header(("Authorization", bearer))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+    let req = test::TestRequest::get().uri("/private").insert_header(("Authorization", key)).to_request();
+    let err = test::try_call_service(&app, req).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_terms_of_service_gate() {
+    use actix_web::middleware::from_fn;
+    use simple_api_demo::auth::TokenService;
+    use simple_api_demo::consent::{require_consent, ConsentService};
+    use simple_api_demo::rbac::RbacPolicy;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::UserRepository;
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let user = repository.create("ann@example.com", String::new()).unwrap();
+    let bearer = format!("Bearer {}", token_with_roles(&tokens, &user.id, &["account", "admin:terms"], &["operator"]));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(RbacPolicy::default()))
+            .app_data(web::Data::new(ConsentService::new(
+                Arc::new(InMemoryStore::new()),
+                repository,
+                1,
+                Some("https://example.com/tos".to_string()),
+            )))
+            .wrap(from_fn(require_consent))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    let bump = || {
+        test::TestRequest::post()
+            .uri("/admin/tos")
+            .insert_header(("Authorization", bearer.clone()))
+            .to_request()
+    };
+    let accept = |version: u64| {
+        test::TestRequest::post()
+            .uri("/tos/accept")
+            .insert_header(("Authorization", bearer.clone()))
+            .set_json(serde_json::json!({"version": version}))
+            .to_request()
+    };
+
+    let terms: Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/tos").to_request()).await;
+    assert_eq!(terms, serde_json::json!({"version": 1, "url": "https://example.com/tos"}));
+
+    // Mutations are blocked with a pointer to the terms until they are accepted
+    let resp = test::try_call_service(&app, bump()).await.err().unwrap().error_response();
+    assert_eq!(resp.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+    let body: Value = serde_json::from_slice(&actix_web::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["error"]["type"], "consent_required");
+    assert_eq!(body["error"]["terms"], "/tos");
+    assert_eq!(body["error"]["required_version"], 1);
+
+    assert_eq!(test::call_service(&app, accept(1)).await.status(), StatusCode::OK);
+    let bumped: Value = test::call_and_read_body_json(&app, bump()).await;
+    assert_eq!(bumped["version"], 2);
+
+    // The new version has to be accepted again
+    assert!(test::try_call_service(&app, bump()).await.is_err());
+    assert_eq!(test::call_service(&app, accept(1)).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(test::call_service(&app, accept(2)).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_data_export_and_account_erasure() {
+    use simple_api_demo::auth::{ApiKeyService, SessionRegistry, TokenDenylist, TokenService, TwoFactorService};
+    use simple_api_demo::crypto::Cipher;
+    use simple_api_demo::privacy::PrivacyService;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::{UserRepository, UserService};
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let users = UserService::new(repository.clone(), &tokens, Arc::new(InMemoryStore::new()));
+    let user = users.register("ann@example.com", "correct horse").await.unwrap();
+    let two_factor = TwoFactorService::new(repository.clone(), Cipher::new(b"integration-data-key"), "demo", Vec::new());
+    let sessions = Arc::new(SessionRegistry::new(
+        Arc::new(InMemoryStore::new()),
+        TokenDenylist::new(Arc::new(InMemoryStore::new())),
+        std::time::Duration::from_secs(3600),
+    ));
+    let api_keys = Arc::new(ApiKeyService::new(Arc::new(InMemoryStore::new())));
+    let privacy = PrivacyService::new(
+        Arc::new(InMemoryStore::new()),
+        repository,
+        sessions,
+        api_keys,
+        std::time::Duration::from_secs(3600),
+    );
+    let bearer = format!(
+        "Bearer {}",
+        tokens.issue(&user.id, &["account"], std::time::Duration::from_secs(300)).unwrap()
+    );
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(users))
+            .app_data(web::Data::new(two_factor))
+            .app_data(web::Data::new(privacy))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+
+    let req = test::TestRequest::get().uri("/me/export").insert_header(("Authorization", bearer.clone())).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "application/zip");
+    assert!(resp.headers().get("content-disposition").unwrap().to_str().unwrap().starts_with("attachment"));
+    let archive = test::read_body(resp).await;
+    assert!(archive.starts_with(b"PK"));
+
+    let erase = |password: &str| {
+        test::TestRequest::delete()
+            .uri("/me")
+            .insert_header(("Authorization", bearer.clone()))
+            .set_json(serde_json::json!({"password": password}))
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, erase("wrong password")).await.status(), StatusCode::UNAUTHORIZED);
+    let resp = test::call_service(&app, erase("correct horse")).await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["status"], "erasure_scheduled");
+    assert!(body["erase_after"].is_string());
+
+    let req = test::TestRequest::post()
+        .uri("/me/deletion/cancel")
+        .insert_header(("Authorization", bearer))
+        .to_request();
+    let profile: Value = test::call_and_read_body_json(&app, req).await;
+    assert!(profile["erase_after"].is_null());
+}
+
+#[actix_web::test]
+async fn test_data_anonymization_endpoint() {
+    use simple_api_demo::anonymization::AnonymizationJob;
+    use simple_api_demo::auth::{SessionRegistry, TokenDenylist, TokenService};
+    use simple_api_demo::rbac::RbacPolicy;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::UserRepository;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let sessions = Arc::new(SessionRegistry::new(
+        Arc::new(InMemoryStore::new()),
+        TokenDenylist::new(Arc::new(InMemoryStore::new())),
+        Duration::from_secs(3600),
+    ));
+    let user = repository.create("ann@example.com", String::new()).unwrap();
+    let session = sessions.create(&user.id, "Laptop", "192.0.2.1".parse().unwrap()).unwrap();
+    let admin = token_with_roles(&tokens, "admin", &["admin:data"], &["operator"]);
+    let member = tokens.issue(&user.id, &["account"], Duration::from_secs(300)).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(RbacPolicy::default()))
+            .app_data(web::Data::new(AnonymizationJob::new(
+                repository,
+                sessions.clone(),
+                Duration::ZERO,
+                false,
+            )))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    let anonymize = |uri: &str, token: &str| {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request()
+    };
+
+    let resp = test::try_call_service(&app, anonymize("/admin/anonymize", &member)).await;
+    assert_eq!(resp.err().unwrap().error_response().status(), StatusCode::FORBIDDEN);
+
+    // Dry run by default: counts only
+    let report: Value = test::call_and_read_body_json(&app, anonymize("/admin/anonymize", &admin)).await;
+    assert_eq!(report["dry_run"], true);
+    assert_eq!(report["users_scanned"], 1);
+    assert_eq!(report["sessions_anonymized"], 1);
+    assert_eq!(sessions.get(&session.id).unwrap().unwrap().device_name, "Laptop");
+
+    let report: Value =
+        test::call_and_read_body_json(&app, anonymize("/admin/anonymize?dry_run=false", &admin)).await;
+    assert_eq!(report["dry_run"], false);
+    assert_eq!(report["sessions_anonymized"], 1);
+    assert_eq!(sessions.get(&session.id).unwrap().unwrap().device_name, "[redacted]");
+}
+
+#[actix_web::test]
+async fn test_analytics_opt_out() {
+    use actix_web::middleware::from_fn;
+    use simple_api_demo::analytics::{track_usage, AnalyticsPipeline, UsageAggregator};
+    use simple_api_demo::auth::TokenService;
+    use simple_api_demo::rbac::RbacPolicy;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::UserRepository;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let user = repository.create("ann@example.com", String::new()).unwrap();
+    let member = format!("Bearer {}", tokens.issue(&user.id, &["account"], Duration::from_secs(300)).unwrap());
+    let admin = format!("Bearer {}", token_with_roles(&tokens, "support", &["admin:data"], &["viewer"]));
+    let usage = web::Data::new(UsageAggregator::new(Arc::new(InMemoryStore::new())));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(RbacPolicy::default()))
+            .app_data(usage.clone())
+            .app_data(web::Data::new(
+                AnalyticsPipeline::new(Some(repository)).with_sink(usage.clone().into_inner()),
+            ))
+            .wrap(from_fn(track_usage))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    let public = |header: Option<(&str, &str)>| {
+        let request = test::TestRequest::get().uri("/public").insert_header(("Authorization", member.clone()));
+        match header {
+            Some(header) => request.insert_header(header).to_request(),
+            None => request.to_request(),
+        }
+    };
+
+    test::call_service(&app, public(None)).await;
+    test::call_service(&app, public(Some(("DNT", "1")))).await;
+    test::call_service(&app, public(Some(("Sec-GPC", "1")))).await;
+
+    let opt_out = test::TestRequest::put()
+        .uri("/me/privacy")
+        .insert_header(("Authorization", member.clone()))
+        .set_json(serde_json::json!({"analytics_opt_out": true}))
+        .to_request();
+    let profile: Value = test::call_and_read_body_json(&app, opt_out).await;
+    assert_eq!(profile["analytics_opt_out"], true);
+    test::call_service(&app, public(None)).await;
+
+    let export = test::TestRequest::get()
+        .uri("/admin/usage")
+        .insert_header(("Authorization", admin))
+        .insert_header(("DNT", "1"))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, export).await;
+    // Only the request without any opt-out, plus the opt-out request itself
+    let routes = body["usage"]["routes"].as_array().unwrap();
+    assert_eq!(routes.len(), 2, "{}", body);
+    assert!(routes.contains(&serde_json::json!({"route": "GET /public", "requests": 1})));
+    assert!(routes.contains(&serde_json::json!({"route": "PUT /me/privacy", "requests": 1})));
+    assert_eq!(body["usage"]["active_users"], 1);
+    // Operational counters include every request; the export itself is still in flight
+    assert_eq!(body["operational"]["requests_total"], 5);
+    assert_eq!(body["operational"]["analytics_excluded"], 3);
+}
+
+#[actix_web::test]
+async fn test_admin_role_tiers() {
+    use simple_api_demo::auth::TokenService;
+    use simple_api_demo::rbac::RbacPolicy;
+    use simple_api_demo::routes::RouteRegistry;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let scopes = ["admin:data", "admin:dumps", "admin:keys"];
+    let policy = RbacPolicy::parse(r#"{"roles": {"support": {"inherits": ["viewer"]}}}"#).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo")))
+            .app_data(web::Data::new(policy))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    // Status of the request once past the guards; handlers lacking their services answer 500
+    let status = |method: &str, uri: &str, role: &str| {
+        let token = token_with_roles(&tokens, role, &scopes, &[role]);
+        let request = match method {
+            "GET" => test::TestRequest::get(),
+            _ => test::TestRequest::post(),
+        };
+        request.uri(uri).insert_header(("Authorization", format!("Bearer {}", token))).to_request()
+    };
+    let allowed = [
+        ("GET", "/admin/dumps", ["support", "viewer", "operator", "owner", "admin"].as_slice()),
+        ("POST", "/admin/anonymize", ["operator", "owner", "admin"].as_slice()),
+        ("POST", "/admin/jwt/rotate", ["owner", "admin"].as_slice()),
+    ];
+    for (method, uri, roles) in allowed {
+        for role in ["support", "viewer", "operator", "owner", "admin", "member"] {
+            let code = match test::try_call_service(&app, status(method, uri, role)).await {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            };
+            assert_eq!(code == StatusCode::FORBIDDEN, !roles.contains(&role), "{} {} as {}", method, uri, role);
+        }
+    }
+}
+
+#[actix_web::test]
+async fn test_read_only_mode_toggle() {
+    use actix_web::middleware::from_fn;
+    use simple_api_demo::auth::TokenService;
+    use simple_api_demo::change_guard::ChangeGuard;
+    use simple_api_demo::rbac::RbacPolicy;
+    use simple_api_demo::read_only::{reject_writes, ReadOnlyMode, READ_ONLY_HEADER};
+    use simple_api_demo::routes::RouteRegistry;
+    use std::time::Duration;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let operator = format!("Bearer {}", token_with_roles(&tokens, "ops", &["admin:maintenance"], &["operator"]));
+    let viewer = format!("Bearer {}", token_with_roles(&tokens, "support", &["admin:maintenance"], &["viewer"]));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(RbacPolicy::default()))
+            .app_data(web::Data::new(ReadOnlyMode::from_config(&Default::default())))
+            .app_data(web::Data::new(ChangeGuard::new(Vec::new(), Duration::from_secs(60), 10)))
+            .wrap(from_fn(reject_writes))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    let switch = |token: &str, enabled: bool| {
+        test::TestRequest::put()
+            .uri("/admin/read-only")
+            .insert_header(("Authorization", token.to_string()))
+            .set_json(serde_json::json!({ "enabled": enabled, "reason": "migrating users" }))
+            .to_request()
+    };
+    let register = || {
+        test::TestRequest::post()
+            .uri("/auth/register")
+            .set_json(serde_json::json!({ "email": "ann@example.com", "password": "correct horse battery" }))
+            .to_request()
+    };
+
+    let err = test::try_call_service(&app, switch(&viewer, true)).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::FORBIDDEN);
+    let status: Value = test::call_and_read_body_json(&app, switch(&operator, true)).await;
+    assert_eq!((status["enabled"].as_bool(), status["reason"].as_str()), (Some(true), Some("migrating users")));
+
+    let res = test::call_service(&app, register()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers().get(READ_ONLY_HEADER).unwrap(), "on");
+    let res = test::call_service(&app, test::TestRequest::get().uri("/public").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let status = test::TestRequest::get().uri("/admin/read-only").insert_header(("Authorization", viewer)).to_request();
+    let status: Value = test::call_and_read_body_json(&app, status).await;
+    assert_eq!(status["enabled"], true);
+
+    let status: Value = test::call_and_read_body_json(&app, switch(&operator, false)).await;
+    assert_eq!(status["enabled"], false);
+    assert_ne!(test::call_service(&app, register()).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_web::test]
+async fn test_backup_endpoint() {
+    use simple_api_demo::auth::TokenService;
+    use simple_api_demo::backup::{BackupService, Snapshot};
+    use simple_api_demo::rbac::RbacPolicy;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::{InMemoryStore, KeyValueStore};
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let operator = format!("Bearer {}", token_with_roles(&tokens, "ops", &["admin:backup"], &["operator"]));
+    let backup = || {
+        test::TestRequest::post()
+            .uri("/admin/backup")
+            .insert_header(("Authorization", operator.clone()))
+            .to_request()
+    };
+    let app = |backups: Option<BackupService>| {
+        let mut app = App::new()
+            .app_data(web::Data::new(TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo")))
+            .app_data(web::Data::new(RbacPolicy::default()));
+        if let Some(backups) = backups {
+            app = app.app_data(web::Data::new(backups));
+        }
+        app.configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    };
+
+    let disabled = test::init_service(app(None)).await;
+    assert_eq!(test::call_service(&disabled, backup()).await.status(), StatusCode::NOT_FOUND);
+
+    let dir = std::env::temp_dir().join(format!("backup-it-{}", uuid::Uuid::new_v4()));
+    let store = Arc::new(InMemoryStore::new());
+    store.set("sessions:s1", "alice", None).unwrap();
+    let enabled = test::init_service(app(Some(BackupService::open(store, &dir, 3).unwrap()))).await;
+    let res = test::call_service(&enabled, backup()).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let report: Value = test::read_body_json(res).await;
+    assert_eq!(report["entries"], 1);
+
+    let restored = InMemoryStore::new();
+    let snapshot = Snapshot::read(&dir.join(report["file"].as_str().unwrap())).unwrap();
+    assert_eq!(snapshot.restore_into(&restored).unwrap(), 1);
+    assert_eq!(restored.get("sessions:s1").unwrap(), Some("alice".to_string()));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[actix_web::test]
+async fn test_integrity_endpoint() {
+    use simple_api_demo::auth::{ApiKeyService, SessionRegistry, TokenDenylist, TokenService};
+    use simple_api_demo::integrity::IntegrityChecker;
+    use simple_api_demo::rbac::RbacPolicy;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::{InMemoryStore, KeyValueStore};
+    use simple_api_demo::users::UserRepository;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let viewer = format!("Bearer {}", token_with_roles(&tokens, "auditor", &["admin:data"], &["viewer"]));
+    let store = Arc::new(InMemoryStore::new());
+    let repository = UserRepository::new(store.clone());
+    repository.create("ann@example.com", String::new()).unwrap();
+    let checker = IntegrityChecker::new(
+        repository.clone(),
+        Arc::new(SessionRegistry::new(
+            Arc::new(InMemoryStore::new()),
+            TokenDenylist::new(Arc::new(InMemoryStore::new())),
+            Duration::from_secs(3600),
+        )),
+        Arc::new(ApiKeyService::new(Arc::new(InMemoryStore::new()))),
+        true,
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(RbacPolicy::default()))
+            .app_data(web::Data::new(checker))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg)),
+    )
+    .await;
+    let report = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("Authorization", viewer.clone()))
+            .to_request()
+    };
+
+    let res = test::call_service(&app, report("/admin/integrity")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["users_checked"], 1);
+    assert_eq!(body["issues"], serde_json::json!([]));
+
+    // Answered from the latest run until a refresh is asked for
+    store.set("index", &serde_json::json!([repository.ids().unwrap()[0], "gone"]).to_string(), None).unwrap();
+    let body: Value = test::read_body_json(test::call_service(&app, report("/admin/integrity")).await).await;
+    assert_eq!(body["issues"], serde_json::json!([]));
+    let body: Value = test::read_body_json(test::call_service(&app, report("/admin/integrity?refresh=true")).await).await;
+    assert_eq!(body["repair"], false);
+    assert_eq!(body["issues"][0]["kind"], "orphaned_reference");
+    assert_eq!(body["issues"][0]["repaired"], false);
+    assert_eq!(repository.ids().unwrap().len(), 2);
+}
+
+#[actix_web::test]
+async fn test_metrics_snapshot_endpoint() {
+    use simple_api_demo::analytics::AnalyticsPipeline;
+    use simple_api_demo::auth::TokenService;
+    use simple_api_demo::metrics_export::MetricsExporter;
+    use simple_api_demo::rbac::RbacPolicy;
+    use simple_api_demo::routes::RouteRegistry;
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let viewer = format!("Bearer {}", token_with_roles(&tokens, "auditor", &["admin:metrics"], &["viewer"]));
+    let outsider = format!("Bearer {}", token_with_roles(&tokens, "auditor", &["admin:data"], &["viewer"]));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(RbacPolicy::default()))
+            .app_data(web::Data::new(MetricsExporter::new(Arc::new(AnalyticsPipeline::new(None)))))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg)),
+    )
+    .await;
+    let snapshot = |token: &str| {
+        test::TestRequest::get()
+            .uri("/admin/metrics.json")
+            .insert_header(("Authorization", token.to_string()))
+            .to_request()
+    };
+
+    let res = test::call_service(&app, snapshot(&viewer)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = test::read_body_json(res).await;
+    assert_eq!(body["counters"][0]["name"], "http_requests_total");
+    assert_eq!(body["histograms"][0]["name"], "http_request_duration_seconds");
+    assert!(body["taken_at"].is_string());
+
+    let err = test::try_call_service(&app, snapshot(&outsider)).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn test_demo_data_endpoint() {
+    use simple_api_demo::analytics::{AnalyticsPipeline, UsageAggregator};
+    use simple_api_demo::auth::TokenService;
+    use simple_api_demo::config::{AppEnv, Config};
+    use simple_api_demo::rbac::RbacPolicy;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::{UserRepository, UserService};
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let admin = format!("Bearer {}", token_with_roles(&tokens, "admin", &["admin:data"], &["owner"]));
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let app = |config: Config| {
+        App::new()
+            .app_data(web::Data::new(config))
+            .app_data(web::Data::new(RbacPolicy::default()))
+            .app_data(web::Data::new(TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo")))
+            .app_data(web::Data::new(UserService::new(repository.clone(), &tokens, Arc::new(InMemoryStore::new()))))
+            .app_data(web::Data::new(UsageAggregator::new(Arc::new(InMemoryStore::new()))))
+            .app_data(web::Data::new(AnalyticsPipeline::new(None)))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    };
+    let generate = |uri: &str| test::TestRequest::post().uri(uri).insert_header(("Authorization", admin.clone())).to_request();
+
+    let service = test::init_service(app(Config::default())).await;
+    let res = test::call_service(&service, generate("/admin/demo-data?scenario=small&users=12&days=7&seed=3")).await;
+    assert_eq!(res.status(), 201);
+    let report: Value = test::read_body_json(res).await;
+    assert_eq!((report["users"].as_u64(), report["seed"].as_u64()), (Some(12), Some(3)));
+    assert!(report["requests"].as_u64().unwrap() > 0);
+    assert_eq!(repository.ids().unwrap().len(), 12);
+
+    let res = test::call_service(&service, generate("/admin/demo-data?days=365")).await;
+    assert_eq!(res.status(), 400);
+
+    let production = Config {
+        app_env: AppEnv::Production,
+        ..Config::default()
+    };
+    let service = test::init_service(app(production)).await;
+    let res = test::call_service(&service, generate("/admin/demo-data")).await;
+    assert_eq!(res.status(), 403);
+}
+
+#[actix_web::test]
+async fn test_embedded_assets_are_served() {
+    use simple_api_demo::assets::AssetStore;
+    use simple_api_demo::routes::RouteRegistry;
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(AssetStore::new(None)))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+
+    for (path, content_type) in [
+        ("/docs", "text/html; charset=utf-8"),
+        ("/console", "text/html; charset=utf-8"),
+        ("/dashboard", "text/html; charset=utf-8"),
+        ("/favicon.ico", "image/x-icon"),
+    ] {
+        let resp = test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK, "{}", path);
+        assert_eq!(resp.headers().get("content-type").unwrap(), content_type);
+        assert_eq!(resp.headers().get("vary").unwrap(), "Accept-Encoding");
+        assert!(resp.headers().get("content-encoding").is_none());
+    }
+
+    // Clients accepting compression get the smaller pre-compressed body
+    let plain = test::call_service(&app, test::TestRequest::get().uri("/docs").to_request()).await;
+    let plain = test::read_body(plain).await;
+    for (accept, encoding) in [("gzip, deflate, br", "br"), ("gzip", "gzip")] {
+        let req = test::TestRequest::get().uri("/docs").insert_header(("Accept-Encoding", accept)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), encoding);
+        assert_eq!(resp.headers().get("vary").unwrap(), "Accept-Encoding");
+        let body = test::read_body(resp).await;
+        assert!(body.len() < plain.len(), "{}", encoding);
+    }
+}
+
+#[actix_web::test]
+async fn test_key_protected_route() {
+    use simple_api_demo::auth::{InMemoryKeyStore, KeyStore};
+    use simple_api_demo::routes::{RouteRegistry, RouteSpec};
+    use std::sync::Arc;
+
+    let store = InMemoryKeyStore::new();
+    store.insert("reporting", "k-reporting-5f1c2a9e7b3d4c60");
+    let store: Arc<dyn KeyStore> = Arc::new(store);
+    let routes = RouteRegistry::app_server().route(
+        RouteSpec::get("/internal/status", "Internal status", || web::get().to(app_server::root)).require_api_key(),
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::from(store))
+            .configure(|cfg| routes.configure(cfg))
+    ).await;
+
+    let req = test::TestRequest::get().uri("/internal/status").to_request();
+    let err = test::try_call_service(&app, req).await.err().unwrap();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::get()
+        .uri("/internal/status")
+        .insert_header(("X-Api-Key", "k-reporting-5f1c2a9e7b3d4c60"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Routes without the marker stay open
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/public").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_api_key_quotas() {
+    use simple_api_demo::auth::quotas::QuotaLimits;
+    use simple_api_demo::auth::{InMemoryKeyStore, KeyStore, QuotaService};
+    use simple_api_demo::config::Config;
+    use simple_api_demo::routes::{RouteRegistry, RouteSpec};
+    use simple_api_demo::state::InMemoryStore;
+    use std::sync::Arc;
+
+    let store = InMemoryKeyStore::new();
+    store.insert("reporting", "k-reporting-5f1c2a9e7b3d4c60");
+    let store: Arc<dyn KeyStore> = Arc::new(store);
+    let config = Config {
+        api_key_daily_quota: 100,
+        api_key_quotas: vec![("reporting".to_string(), QuotaLimits::new(2, 1000))],
+        ..Config::default()
+    };
+    let quotas = QuotaService::from_config(&config, Arc::new(InMemoryStore::new()));
+    let routes = RouteRegistry::app_server().route(
+        RouteSpec::get("/internal/status", "Internal status", || web::get().to(app_server::root)).require_api_key(),
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::from(store))
+            .app_data(web::Data::new(quotas))
+            .configure(|cfg| routes.configure(cfg))
+    ).await;
+    let request = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("X-Api-Key", "k-reporting-5f1c2a9e7b3d4c60"))
+            .to_request()
+    };
+
+    for remaining in ["1", "0"] {
+        let resp = test::call_service(&app, request("/internal/status")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("x-quota-remaining").unwrap(), remaining);
+    }
+    let err = test::try_call_service(&app, request("/internal/status")).await.err().unwrap();
+    let resp = err.error_response();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(resp.headers().get("retry-after").is_some());
+
+    // The quota stays queryable once exhausted, and querying it is free
+    for _ in 0..2 {
+        let resp = test::call_service(&app, request("/quota")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["key"], "reporting");
+        assert_eq!(body["periods"][0]["period"], "daily");
+        assert_eq!(body["periods"][0]["used"], 2);
+        assert_eq!(body["periods"][0]["remaining"], 0);
+        assert_eq!(body["periods"][1]["limit"], 1000);
+        assert_eq!(body["periods"][1]["remaining"], 998);
+    }
+
+    let err = test::try_call_service(&app, test::TestRequest::get().uri("/quota").to_request()).await.err().unwrap();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_basic_auth_routes() {
+    use base64::Engine;
+    use simple_api_demo::auth::BasicAuthenticator;
+    use simple_api_demo::routes::RouteRegistry;
+
+    let mut authenticator = BasicAuthenticator::new("simple-api-demo");
+    authenticator.insert("operator", "correct horse battery staple");
+    let routes = RouteRegistry::app_server()
+        .require_basic_auth_on(&["/public".to_string()])
+        .unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(authenticator))
+            .configure(|cfg| routes.configure(cfg))
+    ).await;
+
+    let req = test::TestRequest::get().uri("/public").to_request();
+    let err = test::try_call_service(&app, req).await.err().unwrap();
+    let resp = err.error_response();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(resp.headers().get("WWW-Authenticate").unwrap().to_str().unwrap().starts_with("Basic realm="));
+
+    let credentials = base64::engine::general_purpose::STANDARD.encode("operator:correct horse battery staple");
+    let req = test::TestRequest::get()
+        .uri("/public")
+        .insert_header(("Authorization", format!("Basic {}", credentials)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Other routes are unaffected
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_policy_routes() {
+    use simple_api_demo::auth::TokenService;
+    use simple_api_demo::policy::{Authorizer, Enforcer, Model, PolicySet, DEFAULT_MODEL};
+    use simple_api_demo::routes::RouteRegistry;
+    use std::time::Duration;
+
+    let model = Model::parse(DEFAULT_MODEL).unwrap();
+    let policies = PolicySet::parse("p, alice, /public, GET", &model).unwrap();
+    let tokens = TokenService::new(b"policy-routes-key-policy-routes-", "test");
+    let (alice, bob) = (
+        tokens.sign(&tokens.claims("alice", &[], Duration::from_secs(60))).unwrap(),
+        tokens.sign(&tokens.claims("bob", &[], Duration::from_secs(60))).unwrap(),
+    );
+    let routes = RouteRegistry::app_server()
+        .require_policy_on(&["/public".to_string()])
+        .unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(Authorizer::new(Enforcer::new(model, policies))))
+            .configure(|cfg| routes.configure(cfg))
+    ).await;
+    let request = |token: &str| {
+        test::TestRequest::get()
+            .uri("/public")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, request(&alice)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The rules decide, not just a valid token
+    let err = test::try_call_service(&app, request(&bob)).await.err().unwrap();
+    assert_eq!(err.error_response().status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::get().uri("/public").to_request();
+    assert_eq!(test::try_call_service(&app, req).await.err().unwrap().error_response().status(), StatusCode::UNAUTHORIZED);
+
+    // Other routes are unaffected
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_signed_routes() {
+    use simple_api_demo::auth::signatures::signature_header;
+    use simple_api_demo::auth::SignatureVerifier;
+    use simple_api_demo::routes::RouteRegistry;
+    use std::time::Duration;
+
+    let mut verifier = SignatureVerifier::new(Duration::from_secs(300));
+    verifier.insert("billing", "billing-shared-secret");
+    let routes = RouteRegistry::app_server()
+        .require_signature_on(&["/public".to_string()])
+        .unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(verifier))
+            .configure(|cfg| routes.configure(cfg))
+    ).await;
+    let signed = |timestamp: i64| {
+        test::TestRequest::get()
+            .uri("/public?page=2")
+            .insert_header(("X-Signature", signature_header("billing", "billing-shared-secret", timestamp, "GET", "/public?page=2", b"")))
+            .to_request()
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let resp = test::call_service(&app, signed(now)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // A request captured ten minutes ago cannot be replayed
+    let err = test::try_call_service(&app, signed(now - 600)).await.err().unwrap();
+    let resp = err.error_response();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = serde_json::from_slice(&actix_web::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+    assert!(body["error"]["message"].as_str().unwrap().contains("tolerance"), "{}", body);
+
+    let req = test::TestRequest::get().uri("/public?page=2").to_request();
+    assert_eq!(test::try_call_service(&app, req).await.err().unwrap().error_response().status(), StatusCode::UNAUTHORIZED);
+
+    // Other routes are unaffected
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_simulated_failures_in_stub_mode() {
+    use actix_web::middleware::from_fn;
+    use simple_api_demo::config::{AppEnv, Config};
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::simulation::simulate_responses;
+
+    let config = Config {
+        app_env: AppEnv::Staging,
+        stub_dependencies: true,
+        ..Config::default()
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(config))
+            .wrap(from_fn(simulate_responses))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+
+    let req = test::TestRequest::get()
+        .uri("/public")
+        .insert_header(("X-Simulate", "rate_limited"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("Retry-After").unwrap(), "30");
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["error"]["type"], "rate_limited");
+
+    let req = test::TestRequest::get()
+        .uri("/public")
+        .insert_header(("X-Simulate", "maintenance; retry_after=300"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(resp.headers().get("Retry-After").unwrap(), "300");
+    assert_eq!(resp.headers().get("X-Simulated").unwrap(), "maintenance");
+}
+
+#[actix_web::test]
+async fn test_cookie_session_login_and_logout() {
+    use simple_api_demo::auth::{ChallengeGate, CookieSessionManager, InMemorySessionStore, TokenService, TwoFactorService};
+    use simple_api_demo::crypto::Cipher;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::{UserRepository, UserService};
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let users = UserService::new(repository.clone(), &tokens, Arc::new(InMemoryStore::new()));
+    let two_factor = TwoFactorService::new(repository, Cipher::new(b"integration-data-key"), "demo", Vec::new());
+    let user = users.register("ann@example.com", "correct horse").await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(users))
+            .app_data(web::Data::new(two_factor))
+            .app_data(web::Data::new(ChallengeGate::new(
+                5,
+                std::time::Duration::from_secs(60),
+                Arc::new(InMemoryStore::new()),
+            )))
+            .app_data(web::Data::new(CookieSessionManager::new(
+                Arc::new(InMemorySessionStore::new()),
+                b"integration-cookie-key",
+                "session",
+                std::time::Duration::from_secs(3600),
+            )))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    let peer: std::net::SocketAddr = "198.51.100.5:4000".parse().unwrap();
+
+    let req = test::TestRequest::post()
+        .uri("/auth/session/login")
+        .peer_addr(peer)
+        .set_json(serde_json::json!({"email": "ann@example.com", "password": "wrong"}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/session/login")
+        .peer_addr(peer)
+        .set_json(serde_json::json!({"email": "ann@example.com", "password": "correct horse"}))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let cookie = resp.response().cookies().find(|cookie| cookie.name() == "session").unwrap().into_owned();
+    assert_eq!(cookie.http_only(), Some(true));
+    let body: Value = test::read_body_json(resp).await;
+    assert_eq!(body["user_id"], user.id.as_str());
+    assert!(body.get("id").is_none());
+
+    let req = test::TestRequest::get().uri("/auth/session").cookie(cookie.clone()).to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["user_id"], user.id.as_str());
+
+    let req = test::TestRequest::post().uri("/auth/session/logout").cookie(cookie.clone()).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let cleared = resp.response().cookies().find(|cookie| cookie.name() == "session").unwrap();
+    assert_eq!(cleared.value(), "");
+
+    let req = test::TestRequest::get().uri("/auth/session").cookie(cookie).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_token_refresh_rotation_and_revocation() {
+    use simple_api_demo::auth::{
+        ChallengeGate, RefreshTokenService, SessionRegistry, TokenDenylist, TokenService, TwoFactorService,
+    };
+    use simple_api_demo::crypto::Cipher;
+    use simple_api_demo::routes::RouteRegistry;
+    use simple_api_demo::state::InMemoryStore;
+    use simple_api_demo::users::{UserRepository, UserService};
+    use std::sync::Arc;
+
+    let tokens = TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo");
+    let repository = UserRepository::new(Arc::new(InMemoryStore::new()));
+    let users = UserService::new(repository.clone(), &tokens, Arc::new(InMemoryStore::new()));
+    let two_factor = TwoFactorService::new(repository, Cipher::new(b"integration-data-key"), "demo", Vec::new());
+    users.register("ann@example.com", "correct horse").await.unwrap();
+    let denylist = TokenDenylist::new(Arc::new(InMemoryStore::new()));
+    let refresh = RefreshTokenService::new(&tokens, denylist.clone(), std::time::Duration::from_secs(600));
+    let sessions = SessionRegistry::new(
+        Arc::new(InMemoryStore::new()),
+        denylist.clone(),
+        std::time::Duration::from_secs(3600),
+    );
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(tokens))
+            .app_data(web::Data::new(users))
+            .app_data(web::Data::new(two_factor))
+            .app_data(web::Data::new(ChallengeGate::new(
+                5,
+                std::time::Duration::from_secs(60),
+                Arc::new(InMemoryStore::new()),
+            )))
+            .app_data(web::Data::new(denylist))
+            .app_data(web::Data::new(sessions))
+            .app_data(web::Data::new(refresh))
+            .configure(|cfg| RouteRegistry::app_server().configure(cfg))
+    ).await;
+    let peer: std::net::SocketAddr = "198.51.100.6:4000".parse().unwrap();
+    let post = |uri: &str, body: Value| test::TestRequest::post().uri(uri).peer_addr(peer).set_json(body).to_request();
+    let refresh_with = |token: &Value| post("/auth/refresh", serde_json::json!({ "refresh_token": token }));
+    let private = |token: &Value| {
+        test::TestRequest::get()
+            .uri("/private")
+            .insert_header(("Authorization", format!("Bearer {}", token.as_str().unwrap())))
+            .to_request()
+    };
+
+    let login = serde_json::json!({"email": "ann@example.com", "password": "correct horse"});
+    let issued: Value = test::call_and_read_body_json(&app, post("/auth/token", login.clone())).await;
+    assert_eq!(issued["refresh_expires_in"], 600);
+    assert!(test::call_service(&app, private(&issued["access_token"])).await.status().is_success());
+
+    // Refreshing rotates both tokens within the same session
+    let rotated: Value = test::call_and_read_body_json(&app, refresh_with(&issued["refresh_token"])).await;
+    assert_eq!(rotated["session_id"], issued["session_id"]);
+    assert_ne!(rotated["refresh_token"], issued["refresh_token"]);
+    assert!(test::call_service(&app, private(&rotated["access_token"])).await.status().is_success());
+
+    // Replaying the redeemed token is treated as theft and ends the session
+    assert_eq!(test::call_service(&app, refresh_with(&issued["refresh_token"])).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(test::call_service(&app, refresh_with(&rotated["refresh_token"])).await.status(), StatusCode::UNAUTHORIZED);
+    let err = test::try_call_service(&app, private(&rotated["access_token"])).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+
+    // Revocation ends a session on request; access tokens are not refresh tokens
+    let issued: Value = test::call_and_read_body_json(&app, post("/auth/token", login)).await;
+    assert_eq!(test::call_service(&app, refresh_with(&issued["access_token"])).await.status(), StatusCode::UNAUTHORIZED);
+    let body = serde_json::json!({ "refresh_token": issued["refresh_token"] });
+    assert!(test::call_service(&app, post("/auth/revoke", body)).await.status().is_success());
+    let body = serde_json::json!({ "refresh_token": "not-a-token" });
+    assert!(test::call_service(&app, post("/auth/revoke", body)).await.status().is_success());
+    assert_eq!(test::call_service(&app, refresh_with(&issued["refresh_token"])).await.status(), StatusCode::UNAUTHORIZED);
+    let err = test::try_call_service(&app, private(&issued["access_token"])).await.unwrap_err();
+    assert_eq!(err.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn test_whoami_and_guest_limits_resolve_clients_behind_proxies() {
+    use simple_api_demo::auth::{ChallengeGate, GuestTokenIssuer, TokenService};
+    use simple_api_demo::client_info::ClientResolver;
+    use simple_api_demo::handlers::auth;
+    use simple_api_demo::state::InMemoryStore;
+    use std::sync::Arc;
+
+    let guests = GuestTokenIssuer::new(
+        vec!["read:guest".to_string()],
+        std::time::Duration::from_secs(600),
+        1,
+        Arc::new(InMemoryStore::new()),
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(ClientResolver::new(vec!["10.0.0.0/8".parse().unwrap()])))
+            .app_data(web::Data::new(TokenService::new(b"integration-test-signing-key-0123", "simple-api-demo")))
+            .app_data(web::Data::new(guests))
+            .app_data(web::Data::new(ChallengeGate::new(
+                5,
+                std::time::Duration::from_secs(60),
+                Arc::new(InMemoryStore::new()),
+            )))
+            .route("/whoami", web::get().to(app_server::whoami))
+            .route("/auth/guest", web::post().to(auth::guest))
+    ).await;
+    let proxy: std::net::SocketAddr = "10.0.0.2:443".parse().unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/whoami")
+        .peer_addr(proxy)
+        .insert_header(("X-Forwarded-For", "198.51.100.20, 10.0.0.9"))
+        .insert_header(("CF-IPCountry", "NL"))
+        .insert_header(("User-Agent", "Mozilla/5.0 (X11; Linux x86_64) Firefox/128.0"))
+        .insert_header(("X-Request-Id", "whoami-1"))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["ip"], "198.51.100.20");
+    assert_eq!(body["peer_ip"], "10.0.0.2");
+    assert_eq!(body["via_proxy"], true);
+    assert_eq!(body["client_kind"], "browser");
+    assert_eq!(body["geo"]["country"], "NL");
+    assert_eq!(body["request_id"], "whoami-1");
+    assert!(body.get("tls").is_none());
+
+    // Each forwarded client gets its own guest allowance behind the same proxy
+    let guest = |client: &str| {
+        test::TestRequest::post()
+            .uri("/auth/guest")
+            .peer_addr(proxy)
+            .insert_header(("X-Forwarded-For", client.to_string()))
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, guest("198.51.100.20")).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, guest("198.51.100.21")).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, guest("198.51.100.20")).await.status(), StatusCode::TOO_MANY_REQUESTS);
+}